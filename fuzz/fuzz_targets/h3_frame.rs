@@ -0,0 +1,32 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use neqo_http3::hframe::HFrameReader;
+use neqo_transport::StreamType;
+use test_fixture::{connect, now};
+
+// The frame reader takes its input from a stream, so this sends the input
+// on a stream between a connected client and server.
+fuzz_target!(|data: &[u8]| {
+    let (mut client, mut server) = connect();
+    let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+    let _ = server.stream_send(stream_id, data).unwrap();
+    server.stream_close_send(stream_id).unwrap();
+    let dgrams = server.process_multiple_output(now(), usize::max_value());
+    client.process_multiple_input(dgrams, now());
+
+    let mut reader = HFrameReader::new();
+    while let Ok(fin) = reader.receive(&mut client, stream_id) {
+        if !reader.done() || reader.get_frame().is_err() || fin {
+            break;
+        }
+        reader.reset();
+    }
+});
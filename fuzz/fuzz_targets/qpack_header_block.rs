@@ -0,0 +1,16 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use neqo_qpack::decoder::QPackDecoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = QPackDecoder::new(300, 100);
+    let _ = decoder.decode_header_block(data, 0);
+});
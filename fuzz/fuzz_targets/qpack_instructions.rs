@@ -0,0 +1,36 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::encoder::QPackEncoder;
+use neqo_transport::StreamType;
+use test_fixture::{connect, now};
+
+// The input is read both as encoder instructions, by a decoder, and as
+// decoder instructions, by an encoder.  Both read from streams, so the input
+// is sent on two streams between a connected client and server.
+fuzz_target!(|data: &[u8]| {
+    let (mut client, mut server) = connect();
+    let encoder_stream = server.stream_create(StreamType::UniDi).unwrap();
+    let decoder_stream = server.stream_create(StreamType::UniDi).unwrap();
+    for &stream_id in &[encoder_stream, decoder_stream] {
+        let _ = server.stream_send(stream_id, data).unwrap();
+    }
+    let dgrams = server.process_multiple_output(now(), usize::max_value());
+    client.process_multiple_input(dgrams, now());
+
+    let mut decoder = QPackDecoder::new(300, 100);
+    decoder.set_capacity(300).unwrap();
+    let _ = decoder.receive(&mut client, encoder_stream);
+
+    let mut encoder = QPackEncoder::new(true);
+    encoder.add_recv_stream(decoder_stream).unwrap();
+    let _ = encoder.recv_if_encoder_stream(&mut client, decoder_stream);
+});
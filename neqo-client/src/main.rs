@@ -5,15 +5,18 @@
 // except according to those terms.
 
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
-use neqo_common::{matches, Datagram};
-use neqo_crypto::{init, AuthenticationStatus};
+use neqo_common::{hex, matches, Datagram, Recorder};
+use neqo_crypto::{init, AuthenticationStatus, KeyLogFile};
 use neqo_http3::{Header, Http3Client, Http3ClientEvent, Http3State, Output};
-use neqo_transport::FixedConnectionIdManager;
+use neqo_transport::{Connection, FixedConnectionIdManager, QUIC_VERSION};
+use neqo_udp::Socket;
 
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::io::{self, ErrorKind};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
 use std::time::Instant;
@@ -53,6 +56,57 @@ pub struct Args {
     #[structopt(name = "omit-read-data", long)]
     /// Do not print received data
     omit_read_data: bool,
+
+    #[structopt(name = "dg-ping", long)]
+    /// Send QUIC datagrams and report loss and round trip times.
+    ///
+    /// The server needs to echo datagrams back, which neqo-server does.
+    /// Use --alpn to select a protocol that the server accepts.
+    dg_ping: bool,
+
+    #[structopt(name = "dg-ping-rate", long, default_value = "10")]
+    /// The number of datagrams to send each second with --dg-ping.
+    dg_ping_rate: u32,
+
+    #[structopt(name = "dg-ping-count", long, default_value = "100")]
+    /// The number of datagrams to send with --dg-ping.
+    dg_ping_count: u64,
+
+    #[structopt(name = "interactive", long)]
+    /// Read commands from stdin instead of making a single request.
+    ///
+    /// One command per line: `open <path> [method]`, `send <stream> <text>`,
+    /// `fin <stream>`, `cancel <stream>`, `close`.  There is no `migrate` or
+    /// `key-update` yet, as neqo-transport can do neither.
+    interactive: bool,
+
+    #[structopt(name = "fail-on-vn-retry", long)]
+    /// Fail if the server sends Version Negotiation or Retry.
+    ///
+    /// Otherwise, Retry is handled transparently and the client reconnects
+    /// once after Version Negotiation if the server offers our version.
+    fail_on_vn_retry: bool,
+
+    #[structopt(name = "capture", long)]
+    /// Record every datagram sent and received in this file.
+    ///
+    /// The file can be replayed with `test_fixture::replay`.
+    capture: Option<PathBuf>,
+
+    #[structopt(name = "dscp", long, default_value = "0")]
+    /// Mark sent datagrams with this DSCP, from 0 to 63.
+    dscp: u8,
+
+    #[structopt(name = "keylog", long)]
+    /// Add TLS secrets to this file, in the format of SSLKEYLOGFILE.
+    ///
+    /// Wireshark can use the file to decrypt a capture of the connection.
+    keylog: Option<PathBuf>,
+
+    #[structopt(name = "grease", long)]
+    /// Send TLS GREASE values, to find servers and middleboxes that don't
+    /// ignore values they don't know.
+    grease: bool,
 }
 
 impl Args {
@@ -85,28 +139,57 @@ impl ToSocketAddrs for Args {
     }
 }
 
+/// Whether to start over after a Version Negotiation packet.  We only speak
+/// one version, so this only helps if the server says that it supports it.
+fn reconnect_after_vn(args: &Args, conn: &Connection) -> bool {
+    !args.fail_on_vn_retry
+        && conn
+            .vn_versions()
+            .map_or(false, |v| v.contains(&QUIC_VERSION))
+}
+
+/// Print whether Version Negotiation or Retry happened during the handshake.
+/// Exit if the handshake failed, or if either happened and the user asked for
+/// that to be treated as a failure.
+fn report_vn_retry(args: &Args, conn: &Connection, reconnected: bool) {
+    let vn = match conn.vn_versions() {
+        Some(versions) => format!("failed, server offered {:x?}", versions),
+        None if reconnected => String::from("reconnected"),
+        None => String::from("no"),
+    };
+    let retry = conn.retry_token().map_or_else(
+        || String::from("no"),
+        |token| format!("token={}", hex(token)),
+    );
+    println!(
+        "Handshake: version={:#x} vn={} retry={}",
+        conn.version(),
+        vn,
+        retry
+    );
+    if conn.vn_versions().is_some()
+        || (args.fail_on_vn_retry && (reconnected || conn.retry_token().is_some()))
+    {
+        exit(1);
+    }
+}
+
 trait Handler {
     fn handle(&mut self, args: &Args, client: &mut Http3Client) -> bool;
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) {
+fn emit_datagram(socket: &mut Socket, d: Option<Datagram>) {
     if let Some(d) = d {
-        let sent = socket.send(&d[..]).expect("Error sending datagram");
-        if sent != d.len() {
-            eprintln!("Unable to send all {} bytes of datagram", d.len());
-        }
+        socket.send(&[d]).expect("Error sending datagram");
     }
 }
 
 fn process_loop(
-    local_addr: &SocketAddr,
-    remote_addr: &SocketAddr,
-    socket: &UdpSocket,
+    socket: &mut Socket,
     client: &mut Http3Client,
     handler: &mut dyn Handler,
     args: &Args,
 ) -> neqo_http3::Http3State {
-    let buf = &mut [0u8; 2048];
     loop {
         if let Http3State::Closed(..) = client.state() {
             return client.state();
@@ -117,7 +200,7 @@ fn process_loop(
         loop {
             let output = client.process_output(Instant::now());
             match output {
-                Output::Datagram(dgram) => emit_datagram(&socket, Some(dgram)),
+                Output::Datagram(dgram) => emit_datagram(socket, Some(dgram)),
                 Output::Callback(duration) => {
                     socket.set_read_timeout(Some(duration)).unwrap();
                     break;
@@ -136,7 +219,7 @@ fn process_loop(
             return client.state();
         }
 
-        match socket.recv(&mut buf[..]) {
+        match socket.recv() {
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
                 // timer expired
                 client.process_timer(Instant::now());
@@ -145,13 +228,8 @@ fn process_loop(
                 eprintln!("UDP error: {}", err);
                 exit(1)
             }
-            Ok(sz) => {
-                if sz == buf.len() {
-                    eprintln!("Received more than {} bytes", buf.len());
-                    continue;
-                }
-                if sz > 0 {
-                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+            Ok(dgrams) => {
+                for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
                     client.process_input(d, Instant::now());
                     client.process_http3(Instant::now());
                 }
@@ -224,6 +302,31 @@ impl Handler for PostConnectHandler {
     }
 }
 
+/// Record what `c` sends and receives, if --capture was given.
+fn capture(args: &Args, c: &mut Connection) {
+    if let Some(path) = &args.capture {
+        let file = File::create(path).expect("can create the capture file");
+        let recorder = Recorder::new(BufWriter::new(file), None).expect("can write the capture");
+        c.set_capture(Some(Rc::new(RefCell::new(recorder))));
+    }
+}
+
+/// Log the TLS secrets of `c`, if --keylog was given.
+fn keylog(args: &Args, c: &mut Connection) {
+    if let Some(path) = &args.keylog {
+        let log = KeyLogFile::open(path).expect("can open the key log file");
+        c.set_key_log(Rc::new(RefCell::new(log)));
+    }
+}
+
+/// Have `c` send GREASE, if --grease was given.
+fn grease(args: &Args, c: &mut Connection) {
+    if args.grease {
+        c.set_grease(true)
+            .expect("can send GREASE before connecting");
+    }
+}
+
 fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
     values
         .iter()
@@ -239,27 +342,31 @@ fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
         .collect()
 }
 
-fn client(args: Args, socket: UdpSocket, local_addr: SocketAddr, remote_addr: SocketAddr) {
-    let mut client = Http3Client::new(
-        args.url.host_str().unwrap(),
-        &args.alpn,
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
-        local_addr,
-        remote_addr,
-        args.max_table_size,
-        args.max_blocked_streams,
-    )
-    .expect("must succeed");
-    // Temporary here to help out the type inference engine
-    let mut h = PreConnectHandler {};
-    process_loop(
-        &local_addr,
-        &remote_addr,
-        &socket,
-        &mut client,
-        &mut h,
-        &args,
-    );
+fn client(args: Args, mut socket: Socket, local_addr: SocketAddr, remote_addr: SocketAddr) {
+    let mut reconnected = false;
+    let mut client = loop {
+        let mut client = Http3Client::new(
+            args.url.host_str().unwrap(),
+            &args.alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            local_addr,
+            remote_addr,
+            args.max_table_size,
+            args.max_blocked_streams,
+        )
+        .expect("must succeed");
+        capture(&args, client.conn());
+        keylog(&args, client.conn());
+        grease(&args, client.conn());
+        // Temporary here to help out the type inference engine
+        let mut h = PreConnectHandler {};
+        process_loop(&mut socket, &mut client, &mut h, &args);
+        if reconnected || !reconnect_after_vn(&args, client.conn()) {
+            break client;
+        }
+        reconnected = true;
+    };
+    report_vn_retry(&args, client.conn(), reconnected);
 
     let client_stream_id = client.fetch(
         &args.method,
@@ -278,14 +385,7 @@ fn client(args: Args, socket: UdpSocket, local_addr: SocketAddr, remote_addr: So
 
     let mut h2 = PostConnectHandler::default();
     h2.streams.insert(client_stream_id);
-    process_loop(
-        &local_addr,
-        &remote_addr,
-        &socket,
-        &mut client,
-        &mut h2,
-        &args,
-    );
+    process_loop(&mut socket, &mut client, &mut h2, &args);
 }
 
 fn main() {
@@ -299,7 +399,7 @@ fn main() {
         }
         Ok(addr) => addr,
     };
-    let socket = match args.local_addr().and_then(UdpSocket::bind) {
+    let mut socket = match args.local_addr().and_then(Socket::bind) {
         Err(e) => {
             eprintln!("Unable to bind UDP socket: {}", e);
             exit(1)
@@ -307,12 +407,21 @@ fn main() {
         Ok(s) => s,
     };
     socket.connect(&args).expect("Unable to connect UDP socket");
+    if args.dscp >= 64 {
+        eprintln!("DSCP {} doesn't fit in six bits", args.dscp);
+        exit(1)
+    }
+    socket.set_dscp(args.dscp);
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
+    let local_addr = socket.local_addr();
 
     println!("Client connecting: {:?} -> {:?}", local_addr, remote_addr);
 
-    if args.use_old_http {
+    if args.dg_ping {
+        dg_ping::dg_ping_client(args, socket, local_addr, remote_addr)
+    } else if args.interactive {
+        interactive::interactive_client(args, socket, local_addr, remote_addr)
+    } else if args.use_old_http {
         old::old_client(args, socket, local_addr, remote_addr)
     } else {
         client(args, socket, local_addr, remote_addr)
@@ -322,17 +431,18 @@ fn main() {
 mod old {
     use std::cell::RefCell;
     use std::collections::HashSet;
-    use std::net::{SocketAddr, UdpSocket};
+    use std::net::SocketAddr;
     use std::process::exit;
     use std::rc::Rc;
     use std::time::Instant;
 
-    use neqo_common::Datagram;
     use neqo_transport::{
         Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType,
     };
 
-    use super::{emit_datagram, Args};
+    use super::{
+        capture, emit_datagram, grease, keylog, reconnect_after_vn, report_vn_retry, Args, Socket,
+    };
 
     trait HandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> bool;
@@ -394,14 +504,11 @@ mod old {
     }
 
     fn process_loop_old(
-        local_addr: &SocketAddr,
-        remote_addr: &SocketAddr,
-        socket: &UdpSocket,
+        socket: &mut Socket,
         client: &mut Connection,
         handler: &mut dyn HandlerOld,
         args: &Args,
     ) -> State {
-        let buf = &mut [0u8; 2048];
         loop {
             if let State::Closed(..) = client.state() {
                 return client.state().clone();
@@ -410,25 +517,20 @@ mod old {
             let exiting = !handler.handle(args, client);
 
             let out_dgram = client.process_output(Instant::now());
-            emit_datagram(&socket, out_dgram.dgram());
+            emit_datagram(socket, out_dgram.dgram());
 
             if exiting {
                 return client.state().clone();
             }
 
-            let sz = match socket.recv(&mut buf[..]) {
+            let dgrams = match socket.recv() {
                 Err(err) => {
                     eprintln!("UDP error: {}", err);
                     exit(1)
                 }
-                Ok(sz) => sz,
+                Ok(dgrams) => dgrams,
             };
-            if sz == buf.len() {
-                eprintln!("Received more than {} bytes", buf.len());
-                continue;
-            }
-            if sz > 0 {
-                let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+            for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
                 client.process_input(d, Instant::now());
             }
         }
@@ -436,7 +538,7 @@ mod old {
 
     pub fn old_client(
         args: Args,
-        socket: UdpSocket,
+        mut socket: Socket,
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
     ) {
@@ -445,24 +547,28 @@ mod old {
         dbg!(local_addr);
         dbg!(remote_addr);
 
-        let mut client = Connection::new_client(
-            args.url.host_str().unwrap(),
-            &["http/0.9"],
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
-            local_addr,
-            remote_addr,
-        )
-        .expect("must succeed");
-        // Temporary here to help out the type inference engine
-        let mut h = PreConnectHandlerOld {};
-        process_loop_old(
-            &local_addr,
-            &remote_addr,
-            &socket,
-            &mut client,
-            &mut h,
-            &args,
-        );
+        let mut reconnected = false;
+        let mut client = loop {
+            let mut client = Connection::new_client(
+                args.url.host_str().unwrap(),
+                &["http/0.9"],
+                Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+                local_addr,
+                remote_addr,
+            )
+            .expect("must succeed");
+            capture(&args, &mut client);
+            keylog(&args, &mut client);
+            grease(&args, &mut client);
+            // Temporary here to help out the type inference engine
+            let mut h = PreConnectHandlerOld {};
+            process_loop_old(&mut socket, &mut client, &mut h, &args);
+            if reconnected || !reconnect_after_vn(&args, &client) {
+                break client;
+            }
+            reconnected = true;
+        };
+        report_vn_retry(&args, &client, reconnected);
 
         let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
         let req: String = "GET /10\r\n".to_string();
@@ -471,13 +577,522 @@ mod old {
             .unwrap();
         let mut h2 = PostConnectHandlerOld::default();
         h2.streams.insert(client_stream_id);
-        process_loop_old(
-            &local_addr,
-            &remote_addr,
-            &socket,
-            &mut client,
-            &mut h2,
-            &args,
-        );
+        process_loop_old(&mut socket, &mut client, &mut h2, &args);
+    }
+}
+
+mod dg_ping {
+    use std::cell::RefCell;
+    use std::cmp::{max, min};
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+    use std::process::exit;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use neqo_common::{hex, Decoder, Encoder};
+    use neqo_crypto::AuthenticationStatus;
+    use neqo_transport::{
+        tp_constants, Connection, ConnectionEvent, FixedConnectionIdManager, Output, State,
+        TransportParameter,
+    };
+
+    use super::{capture, emit_datagram, grease, keylog, Args, Socket};
+
+    /// The largest DATAGRAM frame that we accept.
+    const DATAGRAM_FRAME_SIZE: u64 = 1200;
+    /// How long to wait for echoes after sending the last ping.
+    const DRAIN_TIME: Duration = Duration::from_secs(2);
+
+    #[derive(Default)]
+    struct PingStats {
+        sent: u64,
+        received: HashSet<u64>,
+        rtts: Vec<Duration>,
+    }
+
+    impl PingStats {
+        /// Each ping carries a sequence number and the time it was sent,
+        /// in microseconds since `start`.
+        fn ping(&self, start: Instant, now: Instant) -> Encoder {
+            let mut enc = Encoder::default();
+            enc.encode_uint(8, self.sent);
+            enc.encode_uint(8, u64::try_from((now - start).as_micros()).unwrap());
+            enc
+        }
+
+        fn echo(&mut self, start: Instant, now: Instant, data: &[u8]) {
+            let mut dec = Decoder::from(data);
+            let (seq, sent_us) = match (dec.decode_uint(8), dec.decode_uint(8)) {
+                (Some(seq), Some(sent_us)) => (seq, sent_us),
+                _ => {
+                    eprintln!("Ignoring malformed echo: {}", hex(data));
+                    return;
+                }
+            };
+            if seq >= self.sent || !self.received.insert(seq) {
+                eprintln!("Ignoring unexpected echo of ping {}", seq);
+                return;
+            }
+            let rtt = (now - start)
+                .checked_sub(Duration::from_micros(sent_us))
+                .unwrap_or_default();
+            self.rtts.push(rtt);
+        }
+
+        fn received(&self) -> u64 {
+            u64::try_from(self.received.len()).unwrap()
+        }
+
+        fn report(&mut self) {
+            let lost = self.sent - self.received();
+            #[allow(clippy::cast_precision_loss)]
+            let loss = if self.sent == 0 {
+                0.0
+            } else {
+                100.0 * lost as f64 / self.sent as f64
+            };
+            println!(
+                "DATAGRAM PING: sent={} received={} lost={} ({:.1}%)",
+                self.sent,
+                self.received(),
+                lost,
+                loss
+            );
+            if self.rtts.is_empty() {
+                return;
+            }
+            self.rtts.sort();
+            let rtts = &self.rtts;
+            let percentile = |p: usize| rtts[(rtts.len() - 1) * p / 100];
+            println!(
+                "RTT: min={:?} p50={:?} p90={:?} p99={:?} max={:?}",
+                rtts[0],
+                percentile(50),
+                percentile(90),
+                percentile(99),
+                rtts[rtts.len() - 1]
+            );
+        }
+    }
+
+    pub fn dg_ping_client(
+        args: Args,
+        mut socket: Socket,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) {
+        if args.dg_ping_rate == 0 {
+            eprintln!("--dg-ping-rate needs to be more than zero");
+            exit(1);
+        }
+        let interval = Duration::from_secs(1) / args.dg_ping_rate;
+
+        let mut client = Connection::new_client(
+            args.url.host_str().unwrap(),
+            &args.alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            local_addr,
+            remote_addr,
+        )
+        .expect("must succeed");
+        capture(&args, &mut client);
+        keylog(&args, &mut client);
+        grease(&args, &mut client);
+        client
+            .set_local_tparam(
+                tp_constants::MAX_DATAGRAM_FRAME_SIZE,
+                TransportParameter::Integer(DATAGRAM_FRAME_SIZE),
+            )
+            .expect("can enable datagrams");
+
+        let start = Instant::now();
+        let mut next_ping = start;
+        let mut drain_until = None;
+        let mut failed = false;
+        let mut stats = PingStats::default();
+        loop {
+            if let State::Closed(e) = client.state() {
+                println!("Connection closed: {:?}", e);
+                break;
+            }
+
+            let now = Instant::now();
+            while let Some(event) = client.next_event() {
+                match event {
+                    ConnectionEvent::AuthenticationNeeded => {
+                        client.authenticated(AuthenticationStatus::Ok, now);
+                    }
+                    ConnectionEvent::StateChange(State::Connected) => next_ping = now,
+                    ConnectionEvent::Datagram(data) => stats.echo(start, now, &data),
+                    _ => {}
+                }
+            }
+
+            if *client.state() == State::Connected {
+                if stats.sent < args.dg_ping_count && now >= next_ping {
+                    let ping = stats.ping(start, now);
+                    if let Err(e) = client.send_datagram(&ping) {
+                        eprintln!("Unable to send datagram, server support needed: {:?}", e);
+                        failed = true;
+                    }
+                    stats.sent += 1;
+                    next_ping += interval;
+                    if stats.sent == args.dg_ping_count {
+                        drain_until = Some(now + DRAIN_TIME);
+                    }
+                }
+                let drained = drain_until.map_or(false, |t| now >= t);
+                if failed || drained || stats.received() == args.dg_ping_count {
+                    client.close(now, 0, "kthxbye!");
+                }
+            }
+
+            let mut timeout = loop {
+                match client.process_output(now) {
+                    Output::Datagram(dgram) => emit_datagram(&mut socket, Some(dgram)),
+                    Output::Callback(duration) => break duration,
+                    Output::None => break DRAIN_TIME,
+                }
+            };
+            if *client.state() == State::Connected {
+                let until_ping = next_ping.saturating_duration_since(now);
+                let until_end =
+                    drain_until.map_or(until_ping, |t| t.saturating_duration_since(now));
+                timeout = min(timeout, min(until_ping, until_end));
+            }
+            // A zero timeout is an error, so wait a little.
+            socket
+                .set_read_timeout(Some(max(timeout, Duration::from_millis(1))))
+                .unwrap();
+
+            match socket.recv() {
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    client.process_timer(Instant::now());
+                }
+                Err(err) => {
+                    eprintln!("UDP error: {}", err);
+                    exit(1)
+                }
+                Ok(dgrams) => {
+                    for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
+                        client.process_input(d, Instant::now());
+                    }
+                }
+            }
+        }
+
+        if !failed {
+            stats.report();
+        }
+    }
+}
+
+mod interactive {
+    use std::cell::RefCell;
+    use std::cmp::{max, min};
+    use std::io::{self, BufRead, ErrorKind};
+    use std::net::SocketAddr;
+    use std::process::exit;
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, Receiver, TryRecvError};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use neqo_crypto::AuthenticationStatus;
+    use neqo_http3::{Error, Http3Client, Http3ClientEvent, Http3State, Output};
+    use neqo_transport::FixedConnectionIdManager;
+
+    use super::{capture, emit_datagram, grease, keylog, to_headers, Args, Socket};
+
+    /// How often to check for new commands while waiting for the network.
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    #[derive(Debug, PartialEq)]
+    enum Command {
+        Open { path: String, method: String },
+        Send { stream_id: u64, data: String },
+        Fin { stream_id: u64 },
+        Cancel { stream_id: u64 },
+        Close,
+    }
+
+    impl Command {
+        fn parse(line: &str) -> Result<Self, String> {
+            let line = line.trim();
+            let (cmd, rest) = match line.find(' ') {
+                Some(i) => (&line[..i], line[i + 1..].trim_start()),
+                None => (line, ""),
+            };
+            let stream_id = |s: &str| {
+                s.split_whitespace()
+                    .next()
+                    .and_then(|id| id.parse::<u64>().ok())
+                    .ok_or_else(|| format!("{} needs a stream ID", cmd))
+            };
+            match cmd {
+                "open" => {
+                    let mut words = rest.split_whitespace();
+                    let path = words.next().unwrap_or("/").to_string();
+                    let method = words.next().unwrap_or("GET").to_string();
+                    Ok(Command::Open { path, method })
+                }
+                "send" => {
+                    let stream_id = stream_id(rest)?;
+                    let data = match rest.find(' ') {
+                        Some(i) => rest[i + 1..].to_string(),
+                        None => String::new(),
+                    };
+                    Ok(Command::Send { stream_id, data })
+                }
+                "fin" => Ok(Command::Fin {
+                    stream_id: stream_id(rest)?,
+                }),
+                "cancel" => Ok(Command::Cancel {
+                    stream_id: stream_id(rest)?,
+                }),
+                "close" => Ok(Command::Close),
+                "migrate" | "key-update" => Err(format!(
+                    "{} isn't supported, as neqo-transport can't do that yet",
+                    cmd
+                )),
+                _ => Err(format!("unknown command: {}", line)),
+            }
+        }
+    }
+
+    /// Read lines from stdin on another thread so that the network does not
+    /// have to wait for the user.  The channel disconnects at end of input.
+    fn read_commands() -> Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        rx
+    }
+
+    fn run_command(args: &Args, client: &mut Http3Client, cmd: Command) {
+        let now = Instant::now();
+        let res = match cmd {
+            Command::Open { path, method } => client
+                .fetch(
+                    &method,
+                    &args.url.scheme(),
+                    &args.url.host_str().unwrap(),
+                    &path,
+                    &to_headers(&args.header),
+                )
+                .map(|stream_id| println!("OPENED {}", stream_id)),
+            Command::Send { stream_id, data } => client
+                .send_request_body(stream_id, data.as_bytes())
+                .map(|sent| println!("SENT[{}]: {} bytes", stream_id, sent)),
+            Command::Fin { stream_id } => client.stream_close_send(stream_id),
+            Command::Cancel { stream_id } => {
+                client.stream_reset(stream_id, Error::HttpRequestCancelled.code())
+            }
+            Command::Close => {
+                client.close(now, 0, "kthxbye!");
+                Ok(())
+            }
+        };
+        if let Err(e) = res {
+            println!("ERROR: {:?}", e);
+        }
+    }
+
+    fn handle_events(args: &Args, client: &mut Http3Client) {
+        let mut data = vec![0; 4000];
+        while let Some(event) = client.next_event() {
+            match event {
+                Http3ClientEvent::AuthenticationNeeded => {
+                    client.authenticated(AuthenticationStatus::Ok, Instant::now());
+                }
+                Http3ClientEvent::StateChange(state) => println!("STATE: {:?}", state),
+                Http3ClientEvent::HeaderReady { stream_id } => {
+                    let headers = client.read_response_headers(stream_id);
+                    println!("READ HEADERS[{}]: {:?}", stream_id, headers);
+                }
+                Http3ClientEvent::DataReadable { stream_id } => {
+                    let (sz, fin) =
+                        match client.read_response_data(Instant::now(), stream_id, &mut data) {
+                            Ok(res) => res,
+                            Err(e) => {
+                                println!("ERROR[{}]: {:?}", stream_id, e);
+                                continue;
+                            }
+                        };
+                    if args.omit_read_data {
+                        println!("READ[{}]: {} bytes", stream_id, sz);
+                    } else {
+                        println!(
+                            "READ[{}]: {}",
+                            stream_id,
+                            String::from_utf8_lossy(&data[..sz])
+                        );
+                    }
+                    if fin {
+                        println!("<FIN[{}]>", stream_id);
+                    }
+                }
+                Http3ClientEvent::Reset { stream_id, error } => {
+                    println!("RESET[{}]: {}", stream_id, error);
+                }
+                Http3ClientEvent::StopSending { stream_id, error } => {
+                    println!("STOP SENDING[{}]: {}", stream_id, error);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn interactive_client(
+        args: Args,
+        mut socket: Socket,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) {
+        let mut client = Http3Client::new(
+            args.url.host_str().unwrap(),
+            &args.alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            local_addr,
+            remote_addr,
+            args.max_table_size,
+            args.max_blocked_streams,
+        )
+        .expect("must succeed");
+        capture(&args, client.conn());
+        keylog(&args, client.conn());
+        grease(&args, client.conn());
+
+        let commands = read_commands();
+        let mut input_done = false;
+        loop {
+            if let Http3State::Closed(e) = client.state() {
+                println!("CLOSED: {:?}", e);
+                break;
+            }
+
+            handle_events(&args, &mut client);
+            // Commands wait until the connection is ready for them.
+            while !input_done && client.state() == Http3State::Connected {
+                match commands.try_recv() {
+                    Ok(line) => match Command::parse(&line) {
+                        Ok(cmd) => run_command(&args, &mut client, cmd),
+                        Err(e) => println!("ERROR: {}", e),
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        input_done = true;
+                        client.close(Instant::now(), 0, "kthxbye!");
+                    }
+                }
+            }
+            client.process_http3(Instant::now());
+
+            let timeout = loop {
+                match client.process_output(Instant::now()) {
+                    Output::Datagram(dgram) => emit_datagram(&mut socket, Some(dgram)),
+                    Output::Callback(duration) => break duration,
+                    Output::None => break POLL_INTERVAL,
+                }
+            };
+            // A zero timeout is an error, so wait a little.
+            let timeout = max(min(timeout, POLL_INTERVAL), Duration::from_millis(1));
+            socket.set_read_timeout(Some(timeout)).unwrap();
+
+            match socket.recv() {
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    client.process_timer(Instant::now());
+                }
+                Err(err) => {
+                    eprintln!("UDP error: {}", err);
+                    exit(1)
+                }
+                Ok(dgrams) => {
+                    for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
+                        client.process_input(d, Instant::now());
+                    }
+                }
+            }
+            client.process_http3(Instant::now());
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::Command;
+
+        #[test]
+        fn parse() {
+            assert_eq!(
+                Command::parse("open"),
+                Ok(Command::Open {
+                    path: "/".to_string(),
+                    method: "GET".to_string(),
+                })
+            );
+            assert_eq!(
+                Command::parse("  open /upload  POST "),
+                Ok(Command::Open {
+                    path: "/upload".to_string(),
+                    method: "POST".to_string(),
+                })
+            );
+            assert_eq!(
+                Command::parse("send 4 hello  world"),
+                Ok(Command::Send {
+                    stream_id: 4,
+                    data: "hello  world".to_string(),
+                })
+            );
+            assert_eq!(
+                Command::parse("send 4"),
+                Ok(Command::Send {
+                    stream_id: 4,
+                    data: String::new(),
+                })
+            );
+            assert_eq!(Command::parse("fin 8"), Ok(Command::Fin { stream_id: 8 }));
+            assert_eq!(
+                Command::parse("cancel 0"),
+                Ok(Command::Cancel { stream_id: 0 })
+            );
+            assert_eq!(Command::parse("close"), Ok(Command::Close));
+        }
+
+        #[test]
+        fn parse_errors() {
+            assert!(Command::parse("send").is_err());
+            assert!(Command::parse("fin x").is_err());
+            assert!(Command::parse("cancel -1").is_err());
+            assert!(Command::parse("").is_err());
+            assert!(Command::parse("ping").unwrap_err().starts_with("unknown"));
+            for cmd in &["migrate", "key-update"] {
+                assert_eq!(
+                    Command::parse(cmd),
+                    Err(format!(
+                        "{} isn't supported, as neqo-transport can't do that yet",
+                        cmd
+                    ))
+                );
+            }
+        }
     }
 }
@@ -0,0 +1,57 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use neqo_common::{Datagram, DatagramPool};
+use std::net::SocketAddr;
+
+/// The number of datagrams in each batch, as a receive loop gets them.
+const BATCH: usize = 32;
+const SIZE: usize = 1252;
+
+fn addr() -> SocketAddr {
+    "[::1]:443".parse().unwrap()
+}
+
+/// Make a batch of datagrams from what the socket received, then drop them,
+/// which is what a receive loop does once a connection has processed them.
+fn receive(c: &mut Criterion) {
+    let payload = [0x5a; SIZE];
+    let mut group = c.benchmark_group("receive");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("allocate", |b| {
+        b.iter(|| {
+            let batch = (0..BATCH)
+                .map(|_| Datagram::new(addr(), addr(), &payload[..]))
+                .collect::<Vec<_>>();
+            criterion::black_box(batch);
+        })
+    });
+
+    let pool = DatagramPool::default();
+    group.bench_function("pool", |b| {
+        b.iter(|| {
+            let batch = (0..BATCH)
+                .map(|_| pool.datagram(addr(), addr(), &payload))
+                .collect::<Vec<_>>();
+            criterion::black_box(batch);
+        })
+    });
+    group.finish();
+
+    // Only the first batch allocates; every other datagram reuses a buffer.
+    let stats = pool.stats();
+    println!(
+        "pool allocated {} buffers for {} datagrams",
+        stats.allocated,
+        stats.allocated + stats.reused
+    );
+}
+
+criterion_group!(benches, receive);
+criterion_main!(benches);
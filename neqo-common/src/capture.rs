@@ -0,0 +1,280 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Captures of the datagrams that an endpoint sends and receives, which can be
+// saved to a corpus and replayed.  A corpus starts with a header:
+//
+//   "NEQOCAP" 0x01, then 0x00, or 0x01 and the seed as 8 bytes
+//
+// The seed is the one that a test gave the transport, if it did, so that a
+// replay can use the same connection IDs.  Then each datagram is:
+//
+//   direction (1 byte, 0 is received and 1 is sent)
+//   time since the first datagram in microseconds (varint)
+//   source and destination (each family (4 or 6), address, port (2 bytes))
+//   payload (varint length and value)
+
+use crate::{Datagram, Decoder, Encoder};
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8] = b"NEQOCAP\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// Something that is told about each datagram that an endpoint sends or
+/// receives.
+pub trait Capture {
+    fn capture(&mut self, direction: Direction, d: &Datagram, now: Instant);
+}
+
+/// One datagram in a corpus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    /// The time, relative to the first datagram in the corpus.
+    pub time: Duration,
+    pub datagram: Datagram,
+}
+
+fn encode_addr(enc: &mut Encoder, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            enc.encode_byte(4).encode(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            enc.encode_byte(6).encode(&ip.octets());
+        }
+    }
+    enc.encode_uint(2, addr.port());
+}
+
+fn decode_addr(dec: &mut Decoder) -> Option<SocketAddr> {
+    let ip = match dec.decode_byte()? {
+        4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(dec.decode(4)?);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(dec.decode(16)?);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    let port = u16::try_from(dec.decode_uint(2)?).unwrap();
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Writes a corpus as datagrams are captured.
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    out: W,
+    start: Option<Instant>,
+    /// The first error from writing, which stops any more writes.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a corpus, noting `seed` if the transport was given one.
+    /// # Errors
+    /// If the header can't be written.
+    pub fn new(mut out: W, seed: Option<u64>) -> io::Result<Self> {
+        let mut enc = Encoder::from(MAGIC);
+        if let Some(seed) = seed {
+            enc.encode_byte(1).encode_uint(8, seed);
+        } else {
+            enc.encode_byte(0);
+        }
+        out.write_all(&enc)?;
+        Ok(Self {
+            out,
+            start: None,
+            error: None,
+        })
+    }
+
+    #[must_use]
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    /// Finish the corpus, and return where it was written.
+    /// # Errors
+    /// If any datagram couldn't be written.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Capture for Recorder<W> {
+    fn capture(&mut self, direction: Direction, d: &Datagram, now: Instant) {
+        if self.error.is_some() {
+            return;
+        }
+        let start = *self.start.get_or_insert(now);
+        let mut enc = Encoder::default();
+        enc.encode_byte(match direction {
+            Direction::Received => 0,
+            Direction::Sent => 1,
+        });
+        let micros = now.saturating_duration_since(start).as_micros();
+        enc.encode_varint(u64::try_from(micros).unwrap());
+        encode_addr(&mut enc, d.source());
+        encode_addr(&mut enc, d.destination());
+        enc.encode_vvec(d);
+        self.error = self.out.write_all(&enc).err();
+    }
+}
+
+/// A corpus that has been read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corpus {
+    seed: Option<u64>,
+    records: Vec<Record>,
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad capture: {}", what))
+}
+
+impl Corpus {
+    /// Decode a corpus.
+    /// # Errors
+    /// If `data` isn't a valid corpus.
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        let mut dec = Decoder::from(data);
+        if dec.decode(MAGIC.len()) != Some(MAGIC) {
+            return Err(invalid("not a capture"));
+        }
+        let seed = match dec.decode_byte() {
+            Some(0) => None,
+            Some(1) => Some(dec.decode_uint(8).ok_or_else(|| invalid("no seed"))?),
+            _ => return Err(invalid("no seed")),
+        };
+        let mut records = Vec::new();
+        while dec.remaining() > 0 {
+            records.push(Self::decode_record(&mut dec).ok_or_else(|| invalid("truncated"))?);
+        }
+        Ok(Self { seed, records })
+    }
+
+    fn decode_record(dec: &mut Decoder) -> Option<Record> {
+        let direction = match dec.decode_byte()? {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            _ => return None,
+        };
+        let time = Duration::from_micros(dec.decode_varint()?);
+        let src = decode_addr(dec)?;
+        let dst = decode_addr(dec)?;
+        let payload = dec.decode_vvec()?;
+        Some(Record {
+            direction,
+            time,
+            datagram: Datagram::new(src, dst, payload),
+        })
+    }
+
+    /// Read a whole corpus.
+    /// # Errors
+    /// If reading fails or what is read isn't a valid corpus.
+    pub fn read(mut r: impl Read) -> io::Result<Self> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        Self::decode(&data)
+    }
+
+    /// The seed that the transport had when the corpus was recorded.
+    #[must_use]
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    #[must_use]
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// The datagrams that were received, which are what a replay feeds in.
+    pub fn received(&self) -> impl Iterator<Item = &Record> {
+        self.records
+            .iter()
+            .filter(|r| r.direction == Direction::Received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let start = Instant::now();
+        let mut rec = Recorder::new(Vec::new(), Some(0x0102_0304_0506_0708)).unwrap();
+        let a = Datagram::new(addr("[::1]:443"), addr("[::1]:9999"), vec![1, 2, 3]);
+        let b = Datagram::new(addr("127.0.0.1:9999"), addr("10.0.0.1:443"), vec![]);
+        rec.capture(Direction::Received, &a, start);
+        rec.capture(Direction::Sent, &b, start + Duration::from_millis(3));
+        let corpus = Corpus::read(&rec.finish().unwrap()[..]).unwrap();
+
+        assert_eq!(corpus.seed(), Some(0x0102_0304_0506_0708));
+        assert_eq!(
+            corpus.records(),
+            &[
+                Record {
+                    direction: Direction::Received,
+                    time: Duration::from_secs(0),
+                    datagram: a.clone(),
+                },
+                Record {
+                    direction: Direction::Sent,
+                    time: Duration::from_millis(3),
+                    datagram: b,
+                },
+            ]
+        );
+        let received = corpus.received().map(|r| &r.datagram).collect::<Vec<_>>();
+        assert_eq!(received, vec![&a]);
+    }
+
+    #[test]
+    fn no_seed() {
+        let rec = Recorder::new(Vec::new(), None).unwrap();
+        let corpus = Corpus::decode(rec.get_ref()).unwrap();
+        assert_eq!(corpus.seed(), None);
+        assert!(corpus.records().is_empty());
+    }
+
+    #[test]
+    fn bad_corpus() {
+        assert!(Corpus::decode(b"NEQOCAP\x02\x00").is_err());
+        assert!(Corpus::decode(b"NEQOCAP\x01").is_err());
+
+        let mut rec = Recorder::new(Vec::new(), None).unwrap();
+        let d = Datagram::new(addr("[::1]:443"), addr("[::1]:443"), vec![1, 2, 3]);
+        rec.capture(Direction::Sent, &d, Instant::now());
+        let data = rec.finish().unwrap();
+        assert!(Corpus::decode(&data).is_ok());
+        assert!(Corpus::decode(&data[..data.len() - 1]).is_err());
+    }
+}
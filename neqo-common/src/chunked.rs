@@ -0,0 +1,224 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A buffer of bytes held in a rope of chunks, so that neither adding bytes at
+// the end nor taking them from the front moves the bytes in between.
+
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+
+/// Small writes are collected into chunks of this size by default.
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+/// Part of the bytes in a `ChunkedBuffer`.  Bytes are dropped from the front
+/// of a chunk by moving `start`, not by moving the rest.
+#[derive(Debug, PartialEq)]
+struct Chunk {
+    data: Vec<u8>,
+    start: usize,
+}
+
+impl Chunk {
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChunkedBuffer {
+    chunks: VecDeque<Chunk>,
+    /// The number of bytes in `chunks`.
+    len: usize,
+    chunk_size: usize,
+}
+
+impl ChunkedBuffer {
+    /// Make a buffer that collects small writes into chunks of `chunk_size`.
+    #[must_use]
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            len: 0,
+            chunk_size,
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of chunks that hold the bytes.
+    #[must_use]
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Add a copy of `buf`.  Small writes go at the end of the last chunk,
+    /// unless that is full.
+    pub fn extend_from_slice(&mut self, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let chunk_size = self.chunk_size;
+        let appended = match self.chunks.back_mut() {
+            Some(Chunk { data, .. }) if data.len() + buf.len() <= chunk_size => {
+                data.extend_from_slice(buf);
+                true
+            }
+            _ => false,
+        };
+        if !appended {
+            let mut data = Vec::with_capacity(max(buf.len(), chunk_size));
+            data.extend_from_slice(buf);
+            self.push(data);
+        }
+        self.len += buf.len();
+    }
+
+    /// Add `data` as a chunk of its own, without copying it.
+    pub fn append(&mut self, data: Vec<u8>) {
+        if !data.is_empty() {
+            self.len += data.len();
+            self.push(data);
+        }
+    }
+
+    fn push(&mut self, data: Vec<u8>) {
+        self.chunks.push_back(Chunk { data, start: 0 });
+    }
+
+    /// The bytes from `offset` to the end of the chunk that holds them, which
+    /// is empty if `offset` is past the end.
+    #[must_use]
+    pub fn chunk_at(&self, mut offset: usize) -> &[u8] {
+        for c in &self.chunks {
+            if offset < c.len() {
+                return &c.as_slice()[offset..];
+            }
+            offset -= c.len();
+        }
+        &[]
+    }
+
+    /// Each chunk, in order.
+    pub fn slices(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks.iter().map(Chunk::as_slice)
+    }
+
+    /// The chunks, for a vectored write.
+    #[must_use]
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.slices().map(IoSlice::new).collect()
+    }
+
+    /// Drop `n` bytes from the front: whole chunks, then the front of the one
+    /// that remains.
+    /// # Panics
+    /// If there are fewer than `n` bytes.
+    pub fn consume(&mut self, mut n: usize) {
+        assert!(n <= self.len, "can't consume more than is buffered");
+        self.len -= n;
+        while n > 0 {
+            let front = self.chunks.front_mut().unwrap();
+            if n < front.len() {
+                front.start += n;
+                break;
+            }
+            n -= front.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    /// Copy bytes from the front into `buf` and drop them.  Returns how many
+    /// were copied.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for s in self.slices() {
+            let n = min(s.len(), buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&s[..n]);
+            copied += n;
+            if copied == buf.len() {
+                break;
+            }
+        }
+        self.consume(copied);
+        copied
+    }
+}
+
+impl Default for ChunkedBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce() {
+        let mut b = ChunkedBuffer::new(8);
+        b.extend_from_slice(&[1, 2, 3]);
+        b.extend_from_slice(&[4, 5]);
+        assert_eq!(b.chunk_count(), 1);
+        // Too much for the last chunk.
+        b.extend_from_slice(&[6; 4]);
+        // A chunk that is appended isn't copied, and isn't added to either.
+        let data = vec![7; 20];
+        let ptr = data.as_ptr();
+        b.append(data);
+        b.extend_from_slice(&[8]);
+        assert_eq!(b.chunk_count(), 4);
+        assert_eq!(b.len(), 30);
+        assert_eq!(b.chunk_at(9).as_ptr(), ptr);
+        assert_eq!(b.chunk_at(3), &[4, 5]);
+        assert_eq!(b.chunk_at(30), &[] as &[u8]);
+
+        let lens = b.io_slices().iter().map(|s| s.len()).collect::<Vec<_>>();
+        assert_eq!(lens, vec![5, 4, 20, 1]);
+    }
+
+    #[test]
+    fn consume_and_read() {
+        let mut b = ChunkedBuffer::new(4);
+        b.extend_from_slice(&[1, 2, 3, 4]);
+        b.extend_from_slice(&[5, 6, 7, 8]);
+        b.extend_from_slice(&[9]);
+        b.consume(2);
+        assert_eq!(b.chunk_at(0), &[3, 4]);
+        b.consume(2);
+        assert_eq!(b.chunk_count(), 2);
+
+        let mut buf = [0; 3];
+        assert_eq!(b.read(&mut buf), 3);
+        assert_eq!(buf, [5, 6, 7]);
+        assert_eq!(b.read(&mut buf), 2);
+        assert_eq!(buf[..2], [8, 9]);
+        assert!(b.is_empty());
+        assert_eq!(b.chunk_count(), 0);
+        assert_eq!(b.read(&mut buf), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't consume more than is buffered")]
+    fn consume_too_much() {
+        let mut b = ChunkedBuffer::default();
+        b.extend_from_slice(&[1]);
+        b.consume(2);
+    }
+}
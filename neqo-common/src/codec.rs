@@ -5,11 +5,33 @@
 // except according to those terms.
 
 use std::convert::TryFrom;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
 use std::ops::{Deref, DerefMut};
 
 use crate::hex;
 
+/// Why a `Decoder` couldn't decode a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoderError {
+    /// The input ended before the value did.
+    Truncated,
+    /// A length is more than a `usize` can hold.
+    Overflow,
+    /// A length is more than the input that is left.
+    InvalidLength,
+}
+
+impl Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decoder error: {:?}", self)
+    }
+}
+
+impl std::error::Error for DecoderError {}
+
+/// The result of a `Decoder` method that says why it failed.
+pub type DecoderResult<T> = Result<T, DecoderError>;
+
 /// Decoder is a view into a byte array that has a read offset.  Use it for parsing.
 pub struct Decoder<'a> {
     buf: &'a [u8],
@@ -56,12 +78,19 @@ impl<'a> Decoder<'a> {
 
     /// Decodes (reads) a single byte.
     pub fn decode_byte(&mut self) -> Option<u8> {
+        self.try_decode_byte().ok()
+    }
+
+    /// Decodes (reads) a single byte, or says why it can't.
+    /// # Errors
+    /// `Truncated` if there are no bytes left.
+    pub fn try_decode_byte(&mut self) -> DecoderResult<u8> {
         if self.remaining() < 1 {
-            return None;
+            return Err(DecoderError::Truncated);
         }
         let b = self.buf[self.offset];
         self.offset += 1;
-        Some(b)
+        Ok(b)
     }
 
     /// Provides the next byte without moving the read position.
@@ -75,19 +104,33 @@ impl<'a> Decoder<'a> {
 
     /// Decodes arbitrary data.
     pub fn decode(&mut self, n: usize) -> Option<&[u8]> {
+        self.try_decode(n).ok()
+    }
+
+    /// Decodes arbitrary data, or says why it can't.
+    /// # Errors
+    /// `Truncated` if there are fewer than `n` bytes left.
+    pub fn try_decode(&mut self, n: usize) -> DecoderResult<&[u8]> {
         if self.remaining() < n {
-            return None;
+            return Err(DecoderError::Truncated);
         }
         let res = &self.buf[self.offset..self.offset + n];
         self.offset += n;
-        Some(res)
+        Ok(res)
     }
 
     /// Decodes an unsigned integer of length 1..8.
     pub fn decode_uint(&mut self, n: usize) -> Option<u64> {
+        self.try_decode_uint(n).ok()
+    }
+
+    /// Decodes an unsigned integer of length 1..8, or says why it can't.
+    /// # Errors
+    /// `Truncated` if there are fewer than `n` bytes left.
+    pub fn try_decode_uint(&mut self, n: usize) -> DecoderResult<u64> {
         assert!(n > 0 && n <= 8);
         if self.remaining() < n {
-            return None;
+            return Err(DecoderError::Truncated);
         }
         let mut v = 0_u64;
         for i in 0..n {
@@ -95,20 +138,24 @@ impl<'a> Decoder<'a> {
             v = v << 8 | u64::from(b);
         }
         self.offset += n;
-        Some(v)
+        Ok(v)
     }
 
     /// Decodes a QUIC varint.
     pub fn decode_varint(&mut self) -> Option<u64> {
-        let b1 = match self.decode_byte() {
-            Some(b) => b,
-            _ => return None,
-        };
+        self.try_decode_varint().ok()
+    }
+
+    /// Decodes a QUIC varint, or says why it can't.
+    /// # Errors
+    /// `Truncated` if the input ends before the varint does.
+    pub fn try_decode_varint(&mut self) -> DecoderResult<u64> {
+        let b1 = self.try_decode_byte()?;
         match b1 >> 6 {
-            0 => Some(u64::from(b1 & 0x3f)),
-            1 => Some((u64::from(b1 & 0x3f) << 8) | self.decode_uint(1)?),
-            2 => Some((u64::from(b1 & 0x3f) << 24) | self.decode_uint(3)?),
-            3 => Some((u64::from(b1 & 0x3f) << 56) | self.decode_uint(7)?),
+            0 => Ok(u64::from(b1 & 0x3f)),
+            1 => Ok((u64::from(b1 & 0x3f) << 8) | self.try_decode_uint(1)?),
+            2 => Ok((u64::from(b1 & 0x3f) << 24) | self.try_decode_uint(3)?),
+            3 => Ok((u64::from(b1 & 0x3f) << 56) | self.try_decode_uint(7)?),
             _ => unreachable!(),
         }
     }
@@ -120,30 +167,41 @@ impl<'a> Decoder<'a> {
         res
     }
 
-    fn decode_checked(&mut self, n: Option<u64>) -> Option<&[u8]> {
-        let len = match n {
-            Some(l) => l,
-            _ => return None,
-        };
+    fn decode_checked(&mut self, len: u64) -> DecoderResult<&[u8]> {
         if let Ok(l) = usize::try_from(len) {
-            self.decode(l)
+            self.try_decode(l).map_err(|_| DecoderError::InvalidLength)
         } else {
             // sizeof(usize) < sizeof(u64) and the value is greater than
             // usize can hold. Throw away the rest of the input.
             self.offset = self.buf.len();
-            None
+            Err(DecoderError::Overflow)
         }
     }
 
     /// Decodes a TLS-style length-prefixed buffer.
     pub fn decode_vec(&mut self, n: usize) -> Option<&[u8]> {
-        let len = self.decode_uint(n);
+        self.try_decode_vec(n).ok()
+    }
+
+    /// Decodes a TLS-style length-prefixed buffer, or says why it can't.
+    /// # Errors
+    /// `Truncated` if the length is cut short, and `InvalidLength` or
+    /// `Overflow` if there isn't as much left as the length says.
+    pub fn try_decode_vec(&mut self, n: usize) -> DecoderResult<&[u8]> {
+        let len = self.try_decode_uint(n)?;
         self.decode_checked(len)
     }
 
     /// Decodes a QUIC varint-length-prefixed buffer.
     pub fn decode_vvec(&mut self) -> Option<&[u8]> {
-        let len = self.decode_varint();
+        self.try_decode_vvec().ok()
+    }
+
+    /// Decodes a QUIC varint-length-prefixed buffer, or says why it can't.
+    /// # Errors
+    /// As for `try_decode_vec`.
+    pub fn try_decode_vvec(&mut self) -> DecoderResult<&[u8]> {
+        let len = self.try_decode_varint()?;
         self.decode_checked(len)
     }
 }
@@ -357,6 +415,14 @@ impl From<&[u8]> for Encoder {
     }
 }
 
+impl From<Vec<u8>> for Encoder {
+    /// Encode onto the end of `buf`, reusing its storage.
+    #[must_use]
+    fn from(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+}
+
 impl Into<Vec<u8>> for Encoder {
     #[must_use]
     fn into(self) -> Vec<u8> {
@@ -378,6 +444,136 @@ impl DerefMut for Encoder {
     }
 }
 
+/// A `SliceEncoder` can't fit what it was asked to encode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferFull;
+
+impl Display for BufferFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Encoder buffer full")
+    }
+}
+
+impl std::error::Error for BufferFull {}
+
+/// `SliceEncoder` encodes into a buffer that it doesn't own, such as one from
+/// a pool, so that the result doesn't have to be copied there.  Unlike
+/// `Encoder`, it can't grow, so each method fails if there isn't room, and
+/// writes nothing when it does.
+pub struct SliceEncoder<'a> {
+    buf: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> SliceEncoder<'a> {
+    /// Make a new encoder that starts at the beginning of `buf`.
+    #[must_use]
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    /// How many more bytes fit.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    /// Give up the buffer, returning the part that was written.
+    #[must_use]
+    pub fn finish(self) -> &'a mut [u8] {
+        &mut self.buf[..self.offset]
+    }
+
+    fn reserve(&mut self, n: usize) -> Result<&mut [u8], BufferFull> {
+        if self.remaining() < n {
+            return Err(BufferFull);
+        }
+        let start = self.offset;
+        self.offset += n;
+        Ok(&mut self.buf[start..self.offset])
+    }
+
+    /// Generic encode routine for arbitrary data.
+    /// # Errors
+    /// `BufferFull` if `data` doesn't fit.
+    pub fn encode(&mut self, data: &[u8]) -> Result<&mut Self, BufferFull> {
+        self.reserve(data.len())?.copy_from_slice(data);
+        Ok(self)
+    }
+
+    /// Encode a single byte.
+    /// # Errors
+    /// `BufferFull` if there is no room left.
+    pub fn encode_byte(&mut self, data: u8) -> Result<&mut Self, BufferFull> {
+        self.reserve(1)?[0] = data;
+        Ok(self)
+    }
+
+    /// Encode an integer of any size up to u64.
+    /// # Errors
+    /// `BufferFull` if there are fewer than `n` bytes left.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn encode_uint<T: Into<u64>>(&mut self, n: usize, v: T) -> Result<&mut Self, BufferFull> {
+        let v = v.into();
+        assert!(n > 0 && n <= 8);
+        for (i, b) in self.reserve(n)?.iter_mut().enumerate() {
+            *b = ((v >> (8 * (n - i - 1))) & 0xff) as u8;
+        }
+        Ok(self)
+    }
+
+    /// Encode a QUIC varint.
+    /// # Errors
+    /// `BufferFull` if the varint doesn't fit.
+    pub fn encode_varint<T: Into<u64>>(&mut self, v: T) -> Result<&mut Self, BufferFull> {
+        let v = v.into();
+        let n = Encoder::varint_len(v);
+        let bits = match n {
+            1 => 0,
+            2 => 1 << 14,
+            4 => 2 << 30,
+            _ => 3 << 62,
+        };
+        self.encode_uint(n, v | bits)
+    }
+
+    /// Encode a vector in TLS style.
+    /// # Errors
+    /// `BufferFull` if the length and `v` don't both fit.
+    pub fn encode_vec(&mut self, n: usize, v: &[u8]) -> Result<&mut Self, BufferFull> {
+        if self.remaining() < n + v.len() {
+            return Err(BufferFull);
+        }
+        self.encode_uint(n, u64::try_from(v.len()).unwrap())?
+            .encode(v)
+    }
+
+    /// Encode a vector with a varint length.
+    /// # Errors
+    /// `BufferFull` if the length and `v` don't both fit.
+    pub fn encode_vvec(&mut self, v: &[u8]) -> Result<&mut Self, BufferFull> {
+        let len = u64::try_from(v.len()).unwrap();
+        if self.remaining() < Encoder::varint_len(len) + v.len() {
+            return Err(BufferFull);
+        }
+        self.encode_varint(len)?.encode(v)
+    }
+}
+
+impl<'a> Deref for SliceEncoder<'a> {
+    type Target = [u8];
+    #[must_use]
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.offset]
+    }
+}
+
+impl<'a> Debug for SliceEncoder<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&hex(self))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -469,6 +665,76 @@ mod tests {
         assert!(dec.decode_vvec().is_none());
     }
 
+    #[test]
+    fn slice_encoder() {
+        let mut buf = [0; 8];
+        let mut enc = SliceEncoder::new(&mut buf);
+        enc.encode_byte(1)
+            .unwrap()
+            .encode_varint(0x1234_u64)
+            .unwrap()
+            .encode_vvec(&[5, 6])
+            .unwrap();
+        assert_eq!(&enc[..], &[1, 0x52, 0x34, 2, 5, 6]);
+        assert_eq!(enc.remaining(), 2);
+        assert_eq!(enc.finish(), &[1, 0x52, 0x34, 2, 5, 6]);
+    }
+
+    #[test]
+    fn slice_encoder_full() {
+        let mut buf = [0; 4];
+        let mut enc = SliceEncoder::new(&mut buf);
+        enc.encode_uint(2, 0xabcd_u16).unwrap();
+        assert_eq!(enc.encode_uint(4, 1_u32).err(), Some(BufferFull));
+        assert_eq!(enc.encode_vec(1, &[1, 2]).err(), Some(BufferFull));
+        assert_eq!(enc.encode_varint(1_u64 << 20).err(), Some(BufferFull));
+        // Nothing was written by the calls that failed.
+        assert_eq!(&enc[..], &[0xab, 0xcd]);
+        enc.encode_vec(1, &[7]).unwrap();
+        assert_eq!(enc.remaining(), 0);
+        assert_eq!(enc.encode_byte(0).err(), Some(BufferFull));
+        assert_eq!(enc.finish(), &[0xab, 0xcd, 1, 7]);
+    }
+
+    #[test]
+    fn slice_encoder_matches_encoder() {
+        let mut enc = Encoder::default();
+        enc.encode_varint(0x3f_u64)
+            .encode_varint(0x3fff_u64)
+            .encode_varint(0x3fff_ffff_u64)
+            .encode_varint(0x3fff_ffff_ffff_ffff_u64)
+            .encode_vec(2, &[1, 2, 3]);
+        let mut buf = [0; 20];
+        let mut senc = SliceEncoder::new(&mut buf);
+        senc.encode_varint(0x3f_u64)
+            .and_then(|e| e.encode_varint(0x3fff_u64))
+            .and_then(|e| e.encode_varint(0x3fff_ffff_u64))
+            .and_then(|e| e.encode_varint(0x3fff_ffff_ffff_ffff_u64))
+            .and_then(|e| e.encode_vec(2, &[1, 2, 3]))
+            .unwrap();
+        assert_eq!(&senc[..], &enc[..]);
+    }
+
+    #[test]
+    fn decoder_errors() {
+        let enc = Encoder::from_hex("ff");
+        let mut dec = enc.as_decoder();
+        assert_eq!(dec.try_decode_varint(), Err(DecoderError::Truncated));
+
+        let enc = Encoder::from_hex("02");
+        let mut dec = enc.as_decoder();
+        assert_eq!(dec.try_decode_vec(2), Err(DecoderError::Truncated));
+
+        let enc = Encoder::from_hex("405500");
+        let mut dec = enc.as_decoder();
+        assert_eq!(dec.try_decode_vvec(), Err(DecoderError::InvalidLength));
+
+        let enc = Encoder::from_hex("0123");
+        let mut dec = enc.as_decoder();
+        assert_eq!(dec.try_decode_vvec(), Ok(&[0x23][..]));
+        assert_eq!(dec.try_decode_byte(), Err(DecoderError::Truncated));
+    }
+
     #[test]
     fn skip() {
         let enc = Encoder::from_hex("ffff");
@@ -4,14 +4,72 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::DatagramPool;
+
+use std::convert::TryFrom;
+use std::mem;
 use std::net::SocketAddr;
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
+
+/// The ECN codepoint in the IP header of a datagram (RFC 3168).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ecn {
+    /// Not ECN-Capable Transport.
+    NotEct,
+    /// ECN-Capable Transport, ECT(1).
+    Ect1,
+    /// ECN-Capable Transport, ECT(0).
+    Ect0,
+    /// Congestion Experienced.
+    Ce,
+}
+
+impl Default for Ecn {
+    fn default() -> Self {
+        Ecn::NotEct
+    }
+}
 
-#[derive(Debug, PartialEq, Clone)]
+impl From<Ecn> for u8 {
+    /// The two bits that go in the IP header.
+    fn from(ecn: Ecn) -> u8 {
+        match ecn {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+impl TryFrom<u8> for Ecn {
+    type Error = ();
+    /// Takes the low two bits of a TOS or traffic class byte; other bits must
+    /// be masked out.
+    fn try_from(v: u8) -> Result<Self, ()> {
+        match v {
+            0b00 => Ok(Ecn::NotEct),
+            0b01 => Ok(Ecn::Ect1),
+            0b10 => Ok(Ecn::Ect0),
+            0b11 => Ok(Ecn::Ce),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Datagram {
     src: SocketAddr,
     dst: SocketAddr,
+    /// The differentiated services codepoint (RFC 2474), which is the six bits
+    /// of the TOS byte or traffic class that come before the ECN codepoint.
+    dscp: u8,
+    ecn: Ecn,
+    /// If this holds several UDP payloads, how long each is.
+    segment_size: Option<usize>,
     d: Vec<u8>,
+    /// Where `d` goes when this is dropped, if it came from a pool.
+    pool: Option<DatagramPool>,
 }
 
 impl Datagram {
@@ -19,10 +77,94 @@ impl Datagram {
         Self {
             src,
             dst,
+            dscp: 0,
+            ecn: Ecn::default(),
+            segment_size: None,
             d: d.into(),
+            pool: None,
         }
     }
 
+    pub(crate) fn with_pool(mut self, pool: DatagramPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Set the ECN codepoint: the one that this was received with, or the one
+    /// to send it with.
+    #[must_use]
+    pub fn with_ecn(mut self, ecn: Ecn) -> Self {
+        self.ecn = ecn;
+        self
+    }
+
+    #[must_use]
+    pub fn ecn(&self) -> Ecn {
+        self.ecn
+    }
+
+    /// Set the DSCP, which marks this for a class of service.
+    /// # Panics
+    /// If `dscp` doesn't fit in six bits.
+    #[must_use]
+    pub fn with_dscp(mut self, dscp: u8) -> Self {
+        assert!(dscp < 64, "DSCP is only six bits");
+        self.dscp = dscp;
+        self
+    }
+
+    #[must_use]
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Set the DSCP and the ECN codepoint from a TOS byte (IPv4) or traffic
+    /// class (IPv6).
+    #[must_use]
+    pub fn with_tos(mut self, tos: u8) -> Self {
+        self.dscp = tos >> 2;
+        // Any two bits are a codepoint.
+        self.ecn = Ecn::try_from(tos & 0b11).unwrap_or_default();
+        self
+    }
+
+    /// The TOS byte (IPv4) or traffic class (IPv6) for this.
+    #[must_use]
+    pub fn tos(&self) -> u8 {
+        self.dscp << 2 | u8::from(self.ecn)
+    }
+
+    /// Say that this holds several UDP payloads, each `size` bytes long except
+    /// for the last, which can be shorter.  This is the form that UDP GSO
+    /// sends, and that GRO receives.
+    /// # Panics
+    /// If `size` is zero.
+    #[must_use]
+    pub fn with_segment_size(mut self, size: usize) -> Self {
+        assert!(size > 0);
+        self.segment_size = Some(size);
+        self
+    }
+
+    #[must_use]
+    pub fn segment_size(&self) -> Option<usize> {
+        self.segment_size
+    }
+
+    /// Split this into one datagram for each UDP payload.
+    pub fn segments(&self) -> impl Iterator<Item = Datagram> + '_ {
+        let size = self.segment_size.unwrap_or_else(|| self.d.len().max(1));
+        self.d.chunks(size).map(move |d| Self {
+            src: self.src,
+            dst: self.dst,
+            dscp: self.dscp,
+            ecn: self.ecn,
+            segment_size: None,
+            d: d.to_vec(),
+            pool: None,
+        })
+    }
+
     #[must_use]
     pub fn source(&self) -> SocketAddr {
         self.src
@@ -34,6 +176,40 @@ impl Datagram {
     }
 }
 
+impl Clone for Datagram {
+    /// A clone has its own buffer, which isn't from the pool.
+    fn clone(&self) -> Self {
+        Self {
+            src: self.src,
+            dst: self.dst,
+            dscp: self.dscp,
+            ecn: self.ecn,
+            segment_size: self.segment_size,
+            d: self.d.clone(),
+            pool: None,
+        }
+    }
+}
+
+impl PartialEq for Datagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.src == other.src
+            && self.dst == other.dst
+            && self.dscp == other.dscp
+            && self.ecn == other.ecn
+            && self.segment_size == other.segment_size
+            && self.d == other.d
+    }
+}
+
+impl Drop for Datagram {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give(mem::take(&mut self.d));
+        }
+    }
+}
+
 impl Deref for Datagram {
     type Target = Vec<u8>;
     #[must_use]
@@ -41,3 +217,9 @@ impl Deref for Datagram {
         &self.d
     }
 }
+
+impl DerefMut for Datagram {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.d
+    }
+}
@@ -0,0 +1,230 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A queue of events for whoever is using a connection.  Events that repeat
+// one that is queued are dropped, and a task that is waiting for an event is
+// woken when one is added.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+/// Something that goes in an `EventQueue`.
+pub trait QueuedEvent {
+    /// Whether this says nothing that `queued`, which is in the queue already,
+    /// doesn't, so that it isn't added.
+    fn coalesces_with(&self, queued: &Self) -> bool;
+}
+
+type Filter<E> = Box<dyn Fn(&E) -> bool>;
+
+struct Inner<E> {
+    events: VecDeque<E>,
+    /// The task that `poll_event` found no events for, if any.
+    waker: Option<Waker>,
+    /// Only events that this accepts are queued.
+    filter: Option<Filter<E>>,
+}
+
+/// Events that have yet to be taken.  Clones share the same queue.
+pub struct EventQueue<E> {
+    inner: Rc<RefCell<Inner<E>>>,
+}
+
+impl<E: QueuedEvent> EventQueue<E> {
+    /// Add an event, unless it coalesces with one that is queued or the filter
+    /// doesn't accept it.
+    pub fn insert(&self, event: E) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(f) = &inner.filter {
+            if !f(&event) {
+                return;
+            }
+        }
+        if inner.events.iter().any(|e| event.coalesces_with(e)) {
+            return;
+        }
+        inner.events.push_back(event);
+        let waker = inner.waker.take();
+        drop(inner);
+        if let Some(w) = waker {
+            w.wake();
+        }
+    }
+}
+
+impl<E> EventQueue<E> {
+    /// Only queue events that `filter` accepts from now on, or all of them with
+    /// `None`.  Events that are already queued stay.
+    pub fn set_filter(&self, filter: Option<Filter<E>>) {
+        self.inner.borrow_mut().filter = filter;
+    }
+
+    /// Drop the events that `f` picks.
+    pub fn remove<F>(&self, f: F)
+    where
+        F: Fn(&E) -> bool,
+    {
+        self.inner.borrow_mut().events.retain(|e| !f(e));
+    }
+
+    pub fn clear(&self) {
+        self.inner.borrow_mut().events.clear();
+    }
+
+    /// Take all of the events.
+    pub fn events(&self) -> impl Iterator<Item = E> {
+        let events = std::mem::take(&mut self.inner.borrow_mut().events);
+        events.into_iter()
+    }
+
+    /// Take the events that `f` picks, and leave the rest.
+    pub fn take_matching<F>(&self, f: F) -> impl Iterator<Item = E>
+    where
+        F: Fn(&E) -> bool,
+    {
+        let mut inner = self.inner.borrow_mut();
+        let (taken, left) = inner.events.drain(..).partition::<VecDeque<_>, _>(|e| f(e));
+        inner.events = left;
+        taken.into_iter()
+    }
+
+    #[must_use]
+    pub fn has_events(&self) -> bool {
+        !self.inner.borrow().events.is_empty()
+    }
+
+    /// The number of events that haven't been taken.
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.inner.borrow().events.len()
+    }
+
+    #[must_use]
+    pub fn next_event(&self) -> Option<E> {
+        self.inner.borrow_mut().events.pop_front()
+    }
+
+    /// Take the next event, or arrange for the task to be woken when there
+    /// is one.  Only the task that called last is woken.
+    pub fn poll_event(&self, cx: &mut Context) -> Poll<E> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(e) = inner.events.pop_front() {
+            Poll::Ready(e)
+        } else {
+            inner.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<E> Default for EventQueue<E> {
+    fn default() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Inner {
+                events: VecDeque::new(),
+                waker: None,
+                filter: None,
+            })),
+        }
+    }
+}
+
+impl<E> Clone for EventQueue<E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<E: Debug> Debug for EventQueue<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.inner.borrow().events.iter())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    #[derive(Debug, PartialEq)]
+    enum Ev {
+        Readable(u64),
+        Data(u8),
+    }
+
+    impl QueuedEvent for Ev {
+        fn coalesces_with(&self, queued: &Self) -> bool {
+            match self {
+                Ev::Data(_) => false,
+                Ev::Readable(_) => self == queued,
+            }
+        }
+    }
+
+    #[test]
+    fn coalesce() {
+        let q = EventQueue::default();
+        q.insert(Ev::Readable(1));
+        q.insert(Ev::Readable(1));
+        q.insert(Ev::Readable(2));
+        q.insert(Ev::Data(1));
+        q.insert(Ev::Data(1));
+        assert_eq!(q.pending(), 4);
+        q.remove(|e| *e == Ev::Readable(2));
+        assert_eq!(
+            q.events().collect::<Vec<_>>(),
+            vec![Ev::Readable(1), Ev::Data(1), Ev::Data(1)]
+        );
+        assert!(!q.has_events());
+    }
+
+    #[test]
+    fn filter_and_take() {
+        let q = EventQueue::default();
+        let other = q.clone();
+        q.insert(Ev::Data(1));
+        q.set_filter(Some(Box::new(|e| matches!(e, Ev::Readable(_)))));
+        other.insert(Ev::Data(2));
+        other.insert(Ev::Readable(3));
+        other.insert(Ev::Readable(4));
+
+        let readable = q
+            .take_matching(|e| matches!(e, Ev::Readable(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(readable, vec![Ev::Readable(3), Ev::Readable(4)]);
+        assert_eq!(q.next_event(), Some(Ev::Data(1)));
+        assert_eq!(q.next_event(), None);
+    }
+
+    struct Count(AtomicUsize);
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn poll() {
+        let q = EventQueue::default();
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&count));
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(q.poll_event(&mut cx), Poll::Pending);
+        q.insert(Ev::Readable(1));
+        q.insert(Ev::Readable(2));
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert_eq!(q.poll_event(&mut cx), Poll::Ready(Ev::Readable(1)));
+    }
+}
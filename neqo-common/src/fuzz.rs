@@ -0,0 +1,163 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Checks that the fuzz targets and property tests run.  Each one reads a list
+// of operations from its input and panics if the code under test does
+// something that a simple model says it shouldn't.
+
+use crate::timer::{Timer, TimerHandle};
+use crate::Decoder;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+// A small wheel, so that items are often past its span and need coarser levels.
+const GRANULARITY: Duration = Duration::from_millis(10);
+const CAPACITY: usize = 8;
+
+/// A delay, from a few milliseconds to a few minutes, from the low 16 bits of
+/// `v`.  The top two of those pick the range, so that every range is common.
+fn delay(v: u64) -> Duration {
+    let n = v & 0x3fff;
+    Duration::from_millis(match (v >> 14) & 3 {
+        0 => n % 20,
+        1 => n % 200,
+        2 => n % 5000,
+        _ => n * 13,
+    })
+}
+
+struct TimerCheck {
+    timer: Timer<usize>,
+    now: Instant,
+    /// What should be in the timer.  Items are numbered in the order they are
+    /// added, which is also the order that items with the same time are taken.
+    model: BTreeMap<(Instant, usize), usize>,
+    /// The handle for each item that has been added, by number.
+    handles: Vec<TimerHandle>,
+}
+
+impl TimerCheck {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            timer: Timer::new(now, GRANULARITY, CAPACITY),
+            now,
+            model: BTreeMap::new(),
+            handles: Vec::new(),
+        }
+    }
+
+    /// An item that was added, which might still be in the timer.
+    fn pick(&self, v: u64) -> Option<(usize, TimerHandle)> {
+        if self.handles.is_empty() {
+            None
+        } else {
+            let i = usize::try_from(v).unwrap() % self.handles.len();
+            Some((i, self.handles[i]))
+        }
+    }
+
+    fn present(&self, i: usize, h: TimerHandle) -> bool {
+        self.model.contains_key(&(h.time(), i))
+    }
+
+    fn step(&mut self, op: u8, arg: u64) {
+        match op % 6 {
+            0 => {
+                let time = self.now + delay(arg);
+                let i = self.handles.len();
+                self.handles.push(self.timer.add(time, i));
+                self.model.insert((time, i), i);
+            }
+            1 => {
+                if let Some((i, h)) = self.pick(arg) {
+                    let expected = self.model.remove(&(h.time(), i));
+                    assert_eq!(expected, self.timer.remove(h.time(), |&x| x == i));
+                }
+            }
+            2 => {
+                if let Some((i, h)) = self.pick(arg) {
+                    let expected = self.model.remove(&(h.time(), i));
+                    assert_eq!(expected, self.timer.cancel(h));
+                }
+            }
+            3 => {
+                if let Some((i, h)) = self.pick(arg >> 16) {
+                    let time = self.now + delay(arg & 0xffff);
+                    let present = self.present(i, h);
+                    let moved = self.timer.reschedule(h, time);
+                    assert_eq!(present, moved.is_some());
+                    if let Some(new) = moved {
+                        assert_eq!(time, new.time());
+                        self.model.remove(&(h.time(), i));
+                        self.model.insert((time, i), i);
+                        self.handles[i] = new;
+                    }
+                }
+            }
+            4 => {
+                let until = self.now + delay(arg);
+                let expected = match self.model.keys().next() {
+                    Some(&k) if k.0 <= until => self.model.remove(&k),
+                    _ => None,
+                };
+                assert_eq!(expected, self.timer.take_next(until));
+                self.now = until;
+            }
+            _ => {
+                let until = self.now + delay(arg);
+                let later = self.model.split_off(&(until + Duration::from_nanos(1), 0));
+                let expected: Vec<_> = self.model.values().copied().collect();
+                self.model = later;
+                assert_eq!(expected, self.timer.take_until(until).collect::<Vec<_>>());
+                self.now = until;
+            }
+        }
+        assert_eq!(
+            self.model.keys().next().map(|k| k.0),
+            self.timer.next_time()
+        );
+        assert_eq!(self.model.len(), self.timer.len());
+    }
+
+    /// Everything that is left comes out, in order.
+    fn finish(mut self) {
+        let expected: Vec<_> = self.model.values().copied().collect();
+        // This is longer than any delay.
+        let until = self.now + Duration::from_secs(1000);
+        assert_eq!(expected, self.timer.take_until(until).collect::<Vec<_>>());
+        assert!(self.timer.is_empty());
+    }
+}
+
+/// Run operations on a `Timer`, and check that it gives back each item
+/// once, in time order, and that it agrees with a model of what it holds.
+/// Each operation is a byte that picks it and a four-byte argument.
+pub fn timer(data: &[u8]) {
+    let mut dec = Decoder::from(data);
+    let mut check = TimerCheck::new();
+    while let (Some(op), Some(arg)) = (dec.decode_byte(), dec.decode_uint(4)) {
+        check.step(op, arg);
+    }
+    check.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1024))]
+
+        #[test]
+        fn timer(data in vec(any::<u8>(), 0..1024)) {
+            super::timer(&data);
+        }
+    }
+}
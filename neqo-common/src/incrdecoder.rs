@@ -27,10 +27,26 @@ use crate::codec::Decoder;
 pub enum IncrementalDecoder {
     Idle,
     BeforeVarint,
-    InUint { v: u64, remaining: usize },
+    InUint {
+        v: u64,
+        remaining: usize,
+    },
     InBufferLen(Box<IncrementalDecoder>),
-    InBuffer { v: Vec<u8>, remaining: usize },
-    Ignoring { remaining: usize },
+    InBuffer {
+        v: Vec<u8>,
+        remaining: usize,
+    },
+    Ignoring {
+        remaining: usize,
+    },
+    BeforePrefixedInt {
+        prefix_len: u8,
+        first_byte: Option<u8>,
+    },
+    InPrefixedInt {
+        v: u64,
+        shift: u32,
+    },
 }
 
 /// `IncrementalDecoderResult` is the result of decoding a partial input.
@@ -84,6 +100,33 @@ impl IncrementalDecoder {
         Self::Ignoring { remaining: n }
     }
 
+    /// Decode an integer with a prefix, as HPACK and QPACK use (RFC 7541,
+    /// Section 5.1).  The top `prefix_len` bits of the first byte hold
+    /// something else, and are ignored.
+    /// # Panics
+    /// If `prefix_len` leaves no bits for the integer.
+    #[must_use]
+    pub fn decode_prefixed_int(prefix_len: u8) -> Self {
+        assert!(prefix_len < 8);
+        Self::BeforePrefixedInt {
+            prefix_len,
+            first_byte: None,
+        }
+    }
+
+    /// Decode an integer with a prefix, when the first byte has been read
+    /// already, usually to find what the other bits in it say.
+    /// # Panics
+    /// If `prefix_len` leaves no bits for the integer.
+    #[must_use]
+    pub fn decode_prefixed_int_from(prefix_len: u8, first_byte: u8) -> Self {
+        assert!(prefix_len < 8);
+        Self::BeforePrefixedInt {
+            prefix_len,
+            first_byte: Some(first_byte),
+        }
+    }
+
     /// For callers that might need to request additional data, provide an indication
     /// of the minimum amount of data that should be requested to make progress.
     /// The guarantee is that this will never return a value larger than a subsequent
@@ -91,7 +134,11 @@ impl IncrementalDecoder {
     #[must_use]
     pub fn min_remaining(&self) -> usize {
         match self {
-            Self::BeforeVarint => 1,
+            Self::BeforeVarint
+            | Self::BeforePrefixedInt {
+                first_byte: None, ..
+            }
+            | Self::InPrefixedInt { .. } => 1,
             Self::InUint { remaining, .. }
             | Self::InBuffer { remaining, .. }
             | Self::Ignoring { remaining } => *remaining,
@@ -152,6 +199,31 @@ impl IncrementalDecoder {
         }
     }
 
+    /// Take the bytes after the prefix of an integer, which each have seven
+    /// bits of it, least significant first, and a bit that says whether more
+    /// follow.
+    fn consume_prefixed_int_remainder(
+        &mut self,
+        mut v: u64,
+        mut shift: u32,
+        dv: &mut Decoder,
+    ) -> IncrementalDecoderResult {
+        while let Some(b) = dv.decode_byte() {
+            let part = u64::from(b & 0x7f);
+            let add = part.checked_shl(shift).filter(|a| a >> shift == part);
+            v = match add.and_then(|a| v.checked_add(a)) {
+                Some(v) => v,
+                None => return IncrementalDecoderResult::Error,
+            };
+            shift += 7;
+            if b & 0x80 == 0 {
+                return IncrementalDecoderResult::Uint(v);
+            }
+        }
+        *self = Self::InPrefixedInt { v, shift };
+        IncrementalDecoderResult::InProgress
+    }
+
     /// Consume data incrementally from |dv|.
     pub fn consume(&mut self, dv: &mut Decoder) -> IncrementalDecoderResult {
         match mem::replace(self, Self::Idle) {
@@ -198,6 +270,29 @@ impl IncrementalDecoder {
 
             Self::InBuffer { v, remaining } => self.consume_buffer_remainder(v, remaining, dv),
 
+            Self::BeforePrefixedInt {
+                prefix_len,
+                first_byte,
+            } => {
+                if let Some(b) = first_byte.or_else(|| dv.decode_byte()) {
+                    let mask = 0xff >> prefix_len;
+                    let v = b & mask;
+                    if v < mask {
+                        IncrementalDecoderResult::Uint(u64::from(v))
+                    } else {
+                        self.consume_prefixed_int_remainder(u64::from(v), 0, dv)
+                    }
+                } else {
+                    *self = Self::BeforePrefixedInt {
+                        prefix_len,
+                        first_byte,
+                    };
+                    IncrementalDecoderResult::InProgress
+                }
+            }
+
+            Self::InPrefixedInt { v, shift } => self.consume_prefixed_int_remainder(v, shift, dv),
+
             Self::Ignoring { remaining } => {
                 if remaining <= dv.remaining() {
                     let _ = dv.decode(remaining);
@@ -396,6 +491,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn prefixed_int() {
+        // Examples from RFC 7541, Appendix C.1, and the top bits are ignored.
+        for (prefix_len, c) in &[
+            (
+                3,
+                uint_tc!["0a" => 10, "ea" => 10, "1f9a0a" => 1337, "1f00" => 31],
+            ),
+            (0, uint_tc!["2a" => 42, "ff00" => 255]),
+            (1, uint_tc!["7f80808080808080808001" => 127 + (1 << 63)]),
+        ] {
+            for t in c {
+                t.run(&IncrementalDecoder::decode_prefixed_int(*prefix_len));
+            }
+        }
+    }
+
+    #[test]
+    fn prefixed_int_from() {
+        let mut dec = IncrementalDecoder::decode_prefixed_int_from(3, 0xea);
+        assert_eq!(
+            dec.consume(&mut Decoder::from(&[][..])),
+            IncrementalDecoderResult::Uint(10)
+        );
+
+        let mut dec = IncrementalDecoder::decode_prefixed_int_from(3, 0x1f);
+        let enc = Encoder::from_hex("9a0aff");
+        let mut dv = Decoder::new(&enc);
+        assert_eq!(dec.consume(&mut dv), IncrementalDecoderResult::Uint(1337));
+        assert_eq!(dv.remaining(), 1);
+    }
+
+    #[test]
+    fn prefixed_int_overflow() {
+        // One more than fits in 64 bits.
+        let enc = Encoder::from_hex("ff80ffffffffffffffff01");
+        let mut dec = IncrementalDecoder::decode_prefixed_int(0);
+        assert_eq!(
+            dec.consume(&mut Decoder::new(&enc)),
+            IncrementalDecoderResult::Error
+        );
+    }
+
     #[test]
     fn zero_len() {
         let enc = Encoder::from_hex("ff");
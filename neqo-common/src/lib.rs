@@ -7,20 +7,49 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::pedantic)]
 
+mod capture;
+mod chunked;
 mod codec;
 mod datagram;
+mod event_queue;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
 mod incrdecoder;
 pub mod log;
+pub mod metrics;
 pub mod once;
+mod pool;
+pub mod qlog;
+mod shared_timer;
+mod time;
 pub mod timer;
+#[cfg(feature = "tokio")]
+mod timer_sleep;
+mod token_bucket;
 
-pub use self::codec::{Decoder, Encoder};
-pub use self::datagram::Datagram;
+pub use self::capture::{Capture, Corpus, Direction, Record, Recorder};
+pub use self::chunked::{ChunkedBuffer, DEFAULT_CHUNK_SIZE};
+pub use self::codec::{BufferFull, Decoder, DecoderError, DecoderResult, Encoder, SliceEncoder};
+pub use self::datagram::{Datagram, Ecn};
+pub use self::event_queue::{EventQueue, QueuedEvent};
 pub use self::incrdecoder::{IncrementalDecoder, IncrementalDecoderResult};
+pub use self::pool::{
+    DatagramPool, DatagramPoolStats, DEFAULT_DATAGRAM_BUFFER_SIZE, DEFAULT_DATAGRAM_POOL_CAPACITY,
+};
+pub use self::shared_timer::{SharedTimer, SharedTimerHandle};
+pub use self::time::{Clock, Epoch, SystemClock, VirtualClock};
+#[cfg(feature = "tokio")]
+pub use self::timer_sleep::TimerSleep;
+pub use self::token_bucket::TokenBucket;
 
 #[macro_use]
 extern crate lazy_static;
 
+// For the log macros, which can't name it otherwise from other crates.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing;
+
 // Cribbed from the |matches| crate, for simplicity.
 #[macro_export]
 macro_rules! matches {
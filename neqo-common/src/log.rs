@@ -37,35 +37,79 @@ pub fn init() {
     });
 }
 
+// The arguments to these macros are only evaluated and formatted if logging is
+// enabled at that level, so they cost nothing otherwise.  Anything that is
+// only built for logging should check `log_enabled!` first.
+//
+// With the `tracing` feature, the macros make `tracing` events instead, with
+// the context in a `ctx` field.  Events from a connection are then in its
+// span, which has the connection ID.
+#[cfg(not(feature = "tracing"))]
 #[macro_export]
 macro_rules! qlog {
     ($lvl:expr, $ctx:expr, $($arg:tt)*) => ( {
         ::neqo_common::log::init();
-        ::log::log!($lvl, "[{}] {}", $ctx, format!($($arg)*));
+        ::log::log!($lvl, "[{}] {}", $ctx, format_args!($($arg)*));
     } )
 }
+#[cfg(not(feature = "tracing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! qlog_plain {
+    ($lvl:expr, $($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!($lvl, $($arg)*); } )
+}
+
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! qlog {
+    ($lvl:expr, $ctx:expr, $($arg:tt)*) => (
+        ::neqo_common::qevent!($lvl, ctx = %$ctx, "{}", format_args!($($arg)*))
+    )
+}
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! qlog_plain {
+    ($lvl:expr, $($arg:tt)*) => (::neqo_common::qevent!($lvl, $($arg)*))
+}
+/// Make a `tracing` event at a `log::Level`, which needn't be a constant.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! qevent {
+    ($lvl:expr, $($arg:tt)*) => (
+        match $lvl {
+            ::log::Level::Error => ::neqo_common::tracing::error!($($arg)*),
+            ::log::Level::Warn => ::neqo_common::tracing::warn!($($arg)*),
+            ::log::Level::Info => ::neqo_common::tracing::info!($($arg)*),
+            ::log::Level::Debug => ::neqo_common::tracing::debug!($($arg)*),
+            ::log::Level::Trace => ::neqo_common::tracing::trace!($($arg)*),
+        }
+    )
+}
+
 #[macro_export]
 macro_rules! qerror {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Error, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Error, $($arg)*); } );
+    ($($arg:tt)*) => (::neqo_common::qlog_plain!(::log::Level::Error, $($arg)*););
 }
 #[macro_export]
 macro_rules! qwarn {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Warn, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Warn, $($arg)*); } );
+    ($($arg:tt)*) => (::neqo_common::qlog_plain!(::log::Level::Warn, $($arg)*););
 }
 #[macro_export]
 macro_rules! qinfo {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Info, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Info, $($arg)*); } );
+    ($($arg:tt)*) => (::neqo_common::qlog_plain!(::log::Level::Info, $($arg)*););
 }
 #[macro_export]
 macro_rules! qdebug {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Debug, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Debug, $($arg)*); } );
+    ($($arg:tt)*) => (::neqo_common::qlog_plain!(::log::Level::Debug, $($arg)*););
 }
 #[macro_export]
 macro_rules! qtrace {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Trace, $ctx, $($arg)*););
-    ($($arg:tt)*) => ( { ::neqo_common::log::init(); ::log::log!(::log::Level::Trace, $($arg)*); } );
+    ($($arg:tt)*) => (::neqo_common::qlog_plain!(::log::Level::Trace, $($arg)*););
 }
@@ -0,0 +1,378 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Counters, gauges and histograms that any crate can update, and that an
+// embedder can take a snapshot of.  A metric that isn't in a registry works
+// the same, but nobody sees it, so code can update metrics without checking
+// whether anyone is collecting them.  Metrics can be updated on one thread
+// and collected on another.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A count that only goes up.  Clones share the same count.
+#[derive(Debug, Default, Clone)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.  Clones share the same value.
+#[derive(Debug, Default, Clone)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a histogram has seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// The upper bound of each bucket, in increasing order.
+    pub bounds: Vec<u64>,
+    /// The number of values in each bucket: those that are no more than its
+    /// bound and more than the one before.  There is one more of these than
+    /// there are bounds, for values above the last bound.
+    pub counts: Vec<u64>,
+    pub sum: u64,
+    pub count: u64,
+}
+
+/// Counts values by range.  Clones share the same counts.
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<Mutex<HistogramSnapshot>>);
+
+impl Histogram {
+    /// Make a histogram with buckets that end at each of `bounds`, and one for
+    /// anything larger.
+    /// # Panics
+    /// If `bounds` isn't in increasing order.
+    #[must_use]
+    pub fn new(bounds: &[u64]) -> Self {
+        assert!(
+            bounds.windows(2).all(|w| w[0] < w[1]),
+            "histogram bounds must increase"
+        );
+        Self(Arc::new(Mutex::new(HistogramSnapshot {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+            count: 0,
+        })))
+    }
+
+    /// # Panics
+    /// If another thread panicked while it was updating the histogram.
+    pub fn observe(&self, v: u64) {
+        let mut h = self.0.lock().unwrap();
+        let i = h
+            .bounds
+            .iter()
+            .position(|&b| v <= b)
+            .unwrap_or(h.bounds.len());
+        h.counts[i] += 1;
+        h.sum = h.sum.saturating_add(v);
+        h.count += 1;
+    }
+
+    /// # Panics
+    /// If another thread panicked while it was updating the histogram.
+    #[must_use]
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+impl Metric {
+    fn value(&self) -> Value {
+        match self {
+            Metric::Counter(c) => Value::Counter(c.get()),
+            Metric::Gauge(g) => Value::Gauge(g.get()),
+            Metric::Histogram(h) => Value::Histogram(h.snapshot()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    help: String,
+    metric: Metric,
+}
+
+/// The value of a metric when a snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(HistogramSnapshot),
+}
+
+/// One metric in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricSnapshot {
+    pub name: String,
+    pub help: String,
+    pub value: Value,
+}
+
+/// Every metric in a registry, in the order that they were registered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub metrics: Vec<MetricSnapshot>,
+}
+
+impl Snapshot {
+    /// The value of the metric called `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.metrics
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| &m.value)
+    }
+
+    /// The metrics in the Prometheus text format.  Histogram buckets are
+    /// cumulative there, so each counts everything up to its bound.
+    #[must_use]
+    pub fn prometheus(&self) -> String {
+        let mut s = String::new();
+        for m in &self.metrics {
+            let kind = match m.value {
+                Value::Counter(_) => "counter",
+                Value::Gauge(_) => "gauge",
+                Value::Histogram(_) => "histogram",
+            };
+            // Writing to a `String` can't fail.
+            writeln!(s, "# HELP {} {}", m.name, m.help).unwrap();
+            writeln!(s, "# TYPE {} {}", m.name, kind).unwrap();
+            match &m.value {
+                Value::Counter(v) => writeln!(s, "{} {}", m.name, v).unwrap(),
+                Value::Gauge(v) => writeln!(s, "{} {}", m.name, v).unwrap(),
+                Value::Histogram(h) => {
+                    let mut total = 0;
+                    for (bound, count) in h.bounds.iter().zip(&h.counts) {
+                        total += count;
+                        writeln!(s, "{}_bucket{{le=\"{}\"}} {}", m.name, bound, total).unwrap();
+                    }
+                    writeln!(s, "{}_bucket{{le=\"+Inf\"}} {}", m.name, h.count).unwrap();
+                    writeln!(s, "{}_sum {}", m.name, h.sum).unwrap();
+                    writeln!(s, "{}_count {}", m.name, h.count).unwrap();
+                }
+            }
+        }
+        s
+    }
+}
+
+/// Metrics by name.  Clones share the same metrics.
+///
+/// Asking for a metric that is already registered returns the one that is
+/// there, so several connections can share a counter.
+#[derive(Debug, Default, Clone)]
+pub struct Registry {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Registry {
+    fn get_or_add(&self, name: &str, help: &str, make: impl FnOnce() -> Metric) -> Metric {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(e) = entries.iter().find(|e| e.name == name) {
+            return e.metric.clone();
+        }
+        let metric = make();
+        entries.push(Entry {
+            name: name.to_string(),
+            help: help.to_string(),
+            metric: metric.clone(),
+        });
+        metric
+    }
+
+    /// # Panics
+    /// If `name` is registered as something other than a counter.  This and
+    /// the other functions here also panic if another thread panicked while it
+    /// was using the registry.
+    #[must_use]
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.get_or_add(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(c) => c,
+            _ => panic!("metric {} isn't a counter", name),
+        }
+    }
+
+    /// # Panics
+    /// If `name` is registered as something other than a gauge.
+    #[must_use]
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.get_or_add(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(g) => g,
+            _ => panic!("metric {} isn't a gauge", name),
+        }
+    }
+
+    /// A histogram with the buckets that `Histogram::new` describes.  If `name`
+    /// is already registered, it keeps its buckets.
+    /// # Panics
+    /// If `name` is registered as something other than a histogram, or if
+    /// `bounds` isn't in increasing order.
+    #[must_use]
+    pub fn histogram(&self, name: &str, help: &str, bounds: &[u64]) -> Histogram {
+        match self.get_or_add(name, help, || Metric::Histogram(Histogram::new(bounds))) {
+            Metric::Histogram(h) => h,
+            _ => panic!("metric {} isn't a histogram", name),
+        }
+    }
+
+    /// # Panics
+    /// If another thread panicked while it was using the registry.
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            metrics: self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|e| MetricSnapshot {
+                    name: e.name.clone(),
+                    help: e.help.clone(),
+                    value: e.metric.value(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared() {
+        let registry = Registry::default();
+        let sent = registry.counter("packets", "Packets sent.");
+        let same = registry.clone().counter("packets", "ignored");
+        sent.inc();
+        same.add(2);
+        let streams = registry.gauge("streams", "Open streams.");
+        streams.inc();
+        streams.inc();
+        streams.dec();
+
+        // Unregistered metrics count too, but they aren't in the snapshot.
+        let detached = Counter::default();
+        detached.inc();
+        assert_eq!(detached.get(), 1);
+
+        let s = registry.snapshot();
+        assert_eq!(s.metrics.len(), 2);
+        assert_eq!(s.metrics[0].help, "Packets sent.");
+        assert_eq!(s.get("packets"), Some(&Value::Counter(3)));
+        assert_eq!(s.get("streams"), Some(&Value::Gauge(1)));
+        assert_eq!(s.get("other"), None);
+    }
+
+    #[test]
+    fn histogram() {
+        let r = Registry::default();
+        let h = r.histogram("rtt", "RTT in ms.", &[10, 100]);
+        for v in &[0, 10, 11, 100, 5000] {
+            h.observe(*v);
+        }
+        assert_eq!(
+            r.snapshot().get("rtt"),
+            Some(&Value::Histogram(HistogramSnapshot {
+                bounds: vec![10, 100],
+                counts: vec![2, 2, 1],
+                sum: 5121,
+                count: 5,
+            }))
+        );
+    }
+
+    #[test]
+    fn prometheus() {
+        let r = Registry::default();
+        r.counter("sent", "Sent.").add(3);
+        r.gauge("open", "Open.").dec();
+        let h = r.histogram("rtt", "RTT.", &[10, 100]);
+        h.observe(5);
+        h.observe(50);
+        assert_eq!(
+            r.snapshot().prometheus(),
+            "# HELP sent Sent.\n# TYPE sent counter\nsent 3\n\
+             # HELP open Open.\n# TYPE open gauge\nopen -1\n\
+             # HELP rtt RTT.\n# TYPE rtt histogram\n\
+             rtt_bucket{le=\"10\"} 1\nrtt_bucket{le=\"100\"} 2\nrtt_bucket{le=\"+Inf\"} 2\n\
+             rtt_sum 55\nrtt_count 2\n"
+        );
+    }
+
+    #[test]
+    fn threads() {
+        let r = Registry::default();
+        let c = r.counter("n", "");
+        let done = std::thread::spawn(move || {
+            for _ in 0..1000 {
+                c.inc();
+            }
+        });
+        let c = r.counter("n", "");
+        for _ in 0..1000 {
+            c.inc();
+        }
+        done.join().unwrap();
+        assert_eq!(r.snapshot().get("n"), Some(&Value::Counter(2000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "metric x isn't a gauge")]
+    fn wrong_kind() {
+        let r = Registry::default();
+        let _c = r.counter("x", "");
+        let _g = r.gauge("x", "");
+    }
+}
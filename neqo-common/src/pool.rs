@@ -0,0 +1,181 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A pool of buffers for received datagrams.  A datagram that is made from
+// the pool gives its buffer back when it is dropped, so a receive loop that
+// is keeping up doesn't allocate for every datagram.
+
+use crate::Datagram;
+
+use std::fmt::{self, Debug};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// The number of buffers that a pool keeps by default.
+pub const DEFAULT_DATAGRAM_POOL_CAPACITY: usize = 64;
+/// The size of each buffer by default, with space for any datagram that a
+/// QUIC endpoint should receive.
+pub const DEFAULT_DATAGRAM_BUFFER_SIZE: usize = 2048;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+/// Datagram pool statistics
+pub struct DatagramPoolStats {
+    /// Buffers that had to be allocated because the pool was empty
+    pub allocated: u64,
+    /// Buffers that were taken from the pool
+    pub reused: u64,
+    /// Buffers that were returned to the pool
+    pub returned: u64,
+    /// Buffers that were dropped because the pool was full or they were too small
+    pub discarded: u64,
+}
+
+struct Inner {
+    buffers: Vec<Vec<u8>>,
+    capacity: usize,
+    buffer_size: usize,
+    stats: DatagramPoolStats,
+}
+
+/// A pool that datagrams borrow buffers from.  Clones share the same buffers,
+/// and datagrams can be dropped on any thread.
+#[derive(Clone)]
+pub struct DatagramPool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl DatagramPool {
+    /// Make a pool that keeps up to `capacity` buffers of at least
+    /// `buffer_size` bytes.  Buffers are only allocated when they are needed.
+    #[must_use]
+    pub fn new(capacity: usize, buffer_size: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                buffers: Vec::with_capacity(capacity),
+                capacity,
+                buffer_size,
+                stats: DatagramPoolStats::default(),
+            })),
+        }
+    }
+
+    /// Make a datagram with a copy of `data`, in a buffer from the pool.  The
+    /// buffer goes back to the pool when the datagram is dropped.
+    /// # Panics
+    /// If another thread panicked while using the pool.
+    #[must_use]
+    pub fn datagram(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Datagram {
+        let mut buf = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(buf) = inner.buffers.pop() {
+                inner.stats.reused += 1;
+                buf
+            } else {
+                inner.stats.allocated += 1;
+                Vec::with_capacity(inner.buffer_size)
+            }
+        };
+        buf.extend_from_slice(data);
+        Datagram::new(src, dst, buf).with_pool(self.clone())
+    }
+
+    /// Return a buffer.  Buffers are dropped if the pool is full or if they
+    /// are too small to be useful.
+    pub(crate) fn give(&self, mut buf: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.buffers.len() >= inner.capacity || buf.capacity() < inner.buffer_size {
+            inner.stats.discarded += 1;
+            return;
+        }
+        buf.clear();
+        inner.buffers.push(buf);
+        inner.stats.returned += 1;
+    }
+
+    /// # Panics
+    /// If another thread panicked while using the pool.
+    #[must_use]
+    pub fn stats(&self) -> DatagramPoolStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+impl Default for DatagramPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_DATAGRAM_POOL_CAPACITY, DEFAULT_DATAGRAM_BUFFER_SIZE)
+    }
+}
+
+impl Debug for DatagramPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DatagramPool {:?}", self.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "[::1]:443".parse().unwrap()
+    }
+
+    #[test]
+    fn reuse() {
+        let pool = DatagramPool::new(2, 100);
+        let d = pool.datagram(addr(), addr(), &[1, 2, 3]);
+        assert_eq!(&d[..], &[1, 2, 3]);
+        let ptr = d.as_ptr();
+        drop(d);
+
+        let d = pool.datagram(addr(), addr(), &[4]);
+        assert_eq!(&d[..], &[4]);
+        assert_eq!(d.as_ptr(), ptr);
+        drop(d);
+        assert_eq!(
+            pool.stats(),
+            DatagramPoolStats {
+                allocated: 1,
+                reused: 1,
+                returned: 2,
+                discarded: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn full() {
+        let pool = DatagramPool::new(1, 100);
+        let a = pool.datagram(addr(), addr(), &[1]);
+        let b = pool.datagram(addr(), addr(), &[2]);
+        drop(a);
+        drop(b);
+        let stats = pool.stats();
+        assert_eq!(stats.allocated, 2);
+        assert_eq!(stats.returned, 1);
+        assert_eq!(stats.discarded, 1);
+    }
+
+    #[test]
+    fn clone_unpooled() {
+        let pool = DatagramPool::new(4, 100);
+        let a = pool.datagram(addr(), addr(), &[1]);
+        let b = a.clone();
+        assert_eq!(a, b);
+        drop(a);
+        drop(b);
+        let stats = pool.stats();
+        assert_eq!(stats.returned, 1);
+        assert_eq!(stats.discarded, 0);
+    }
+
+    #[test]
+    fn unpooled_equal() {
+        let pool = DatagramPool::default();
+        let a = pool.datagram(addr(), addr(), &[1, 2]);
+        assert_eq!(a, Datagram::new(addr(), addr(), vec![1, 2]));
+    }
+}
@@ -0,0 +1,451 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A qlog writer that streams events as they happen.  This uses the JSON-SEQ
+// serialization, where each record is written on its own, so nothing is kept
+// once it is written: a header record that describes the trace, followed by
+// one record for each event.  `Qlog` wraps a writer that might not be there,
+// so that code can report events unconditionally and pay for building them
+// only when a trace is being written.
+
+use crate::Epoch;
+
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+
+const QLOG_VERSION: &str = "0.3";
+/// JSON-SEQ starts each record with this.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// A JSON value for the data of an event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Str(&'a str),
+    String(String),
+    Array(Vec<Value<'a>>),
+    Object(Vec<(&'a str, Value<'a>)>),
+}
+
+impl From<bool> for Value<'_> {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+
+impl From<i64> for Value<'_> {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<u64> for Value<'_> {
+    fn from(v: u64) -> Self {
+        Value::Uint(v)
+    }
+}
+
+impl From<usize> for Value<'_> {
+    fn from(v: usize) -> Self {
+        Value::Uint(v as u64)
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(v: &'a str) -> Self {
+        Value::Str(v)
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(v: String) -> Self {
+        Value::String(v)
+    }
+}
+
+impl Value<'_> {
+    fn write(&self, out: &mut String) {
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(v) => out.push_str(if *v { "true" } else { "false" }),
+            Value::Int(v) => write!(out, "{}", v).unwrap(),
+            Value::Uint(v) => write!(out, "{}", v).unwrap(),
+            // JSON has no infinity or NaN.
+            Value::Float(v) if !v.is_finite() => out.push_str("null"),
+            Value::Float(v) => write!(out, "{}", v).unwrap(),
+            Value::Str(v) => write_str(out, v),
+            Value::String(v) => write_str(out, v),
+            Value::Array(values) => {
+                out.push('[');
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    v.write(out);
+                }
+                out.push(']');
+            }
+            Value::Object(fields) => write_object(out, fields),
+        }
+    }
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_object(out: &mut String, fields: &[(&str, Value)]) {
+    out.push('{');
+    for (i, (k, v)) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_str(out, k);
+        out.push(':');
+        v.write(out);
+    }
+    out.push('}');
+}
+
+/// Which end of a connection a trace is from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VantagePoint {
+    Client,
+    Server,
+}
+
+impl VantagePoint {
+    fn name(self) -> &'static str {
+        match self {
+            VantagePoint::Client => "client",
+            VantagePoint::Server => "server",
+        }
+    }
+}
+
+/// Writes a qlog trace to `W`, one event at a time.  Events are buffered, and
+/// are only sure to reach `W` once `flush` is called, or after each event if
+/// `set_flush_each` is set.  The buffer is flushed when this is dropped, but
+/// errors are lost then.
+pub struct QlogWriter<W: Write> {
+    out: BufWriter<W>,
+    /// Event times are given relative to this.
+    reference: Instant,
+    flush_each: bool,
+    /// Each record is built here before being written.
+    record: String,
+}
+
+impl<W: Write> QlogWriter<W> {
+    /// Start a trace, and write its header.  `reference` is the time that
+    /// event times count from, which is usually when the connection started.
+    /// # Errors
+    /// When `out` can't be written to.
+    pub fn new(
+        out: W,
+        title: &str,
+        vantage_point: VantagePoint,
+        reference: Instant,
+    ) -> io::Result<Self> {
+        Self::start(out, title, vantage_point, reference, None)
+    }
+
+    /// Start a trace whose event times count from the instant that `epoch`
+    /// anchors.  The header gives the wall-clock time of that instant, so
+    /// that traces from different endpoints can be lined up.
+    /// # Errors
+    /// When `out` can't be written to.
+    pub fn with_epoch(
+        out: W,
+        title: &str,
+        vantage_point: VantagePoint,
+        epoch: &Epoch,
+    ) -> io::Result<Self> {
+        Self::start(out, title, vantage_point, epoch.instant(), Some(epoch))
+    }
+
+    fn start(
+        out: W,
+        title: &str,
+        vantage_point: VantagePoint,
+        reference: Instant,
+        epoch: Option<&Epoch>,
+    ) -> io::Result<Self> {
+        let mut common = vec![("time_format", Value::Str("relative"))];
+        if let Some(epoch) = epoch {
+            common.push(("reference_time", Value::Uint(epoch.unix_millis(reference))));
+        }
+        let mut w = Self {
+            out: BufWriter::new(out),
+            reference,
+            flush_each: false,
+            record: String::new(),
+        };
+        write_object(
+            &mut w.record,
+            &[
+                ("qlog_version", Value::Str(QLOG_VERSION)),
+                ("qlog_format", Value::Str("JSON-SEQ")),
+                ("title", Value::Str(title)),
+                (
+                    "trace",
+                    Value::Object(vec![
+                        (
+                            "vantage_point",
+                            Value::Object(vec![("type", Value::Str(vantage_point.name()))]),
+                        ),
+                        ("common_fields", Value::Object(common)),
+                    ]),
+                ),
+            ],
+        );
+        w.write_record()?;
+        Ok(w)
+    }
+
+    /// Whether to flush after each event.  This is off to start with.
+    pub fn set_flush_each(&mut self, flush_each: bool) {
+        self.flush_each = flush_each;
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        self.out.write_all(&[RECORD_SEPARATOR])?;
+        self.out.write_all(self.record.as_bytes())?;
+        self.out.write_all(b"\n")?;
+        self.record.clear();
+        if self.flush_each {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write an event, such as `transport:packet_sent`, that happened at
+    /// `time`, which can't be before the reference time.
+    /// # Errors
+    /// When the event can't be written.
+    pub fn event(&mut self, time: Instant, name: &str, data: &[(&str, Value)]) -> io::Result<()> {
+        let elapsed = time.saturating_duration_since(self.reference);
+        #[allow(clippy::cast_precision_loss)] // Event times don't need nanoseconds.
+        let ms = elapsed.as_nanos() as f64 / 1_000_000.0;
+        self.record.push('{');
+        write_str(&mut self.record, "time");
+        self.record.push(':');
+        Value::Float(ms).write(&mut self.record);
+        self.record.push(',');
+        write_str(&mut self.record, "name");
+        self.record.push(':');
+        write_str(&mut self.record, name);
+        self.record.push(',');
+        write_str(&mut self.record, "data");
+        self.record.push(':');
+        write_object(&mut self.record, data);
+        self.record.push('}');
+        self.write_record()
+    }
+
+    /// Write everything that is buffered.
+    /// # Errors
+    /// When `W` can't be written to.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Flush the buffer, and return `W`.
+    /// # Errors
+    /// When the buffer can't be flushed; the error has the writer.
+    pub fn into_inner(self) -> Result<W, io::IntoInnerError<BufWriter<W>>> {
+        self.out.into_inner()
+    }
+}
+
+/// A trace that might be turned off.  Event data comes from a closure that is
+/// only called if there is a writer, so an event costs a check when there
+/// isn't.  The default has no writer.
+pub struct Qlog<W: Write> {
+    writer: Option<QlogWriter<W>>,
+}
+
+impl<W: Write> Qlog<W> {
+    /// A trace that writes to `writer`.
+    #[must_use]
+    pub fn new(writer: QlogWriter<W>) -> Self {
+        Self {
+            writer: Some(writer),
+        }
+    }
+
+    /// A trace that writes nothing.
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self { writer: None }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Write an event, as `QlogWriter::event` does, if there is a writer.
+    /// `data` builds the data for the event, and is only called then.
+    /// # Errors
+    /// When the event can't be written.
+    pub fn event<'a, F>(&mut self, time: Instant, name: &str, data: F) -> io::Result<()>
+    where
+        F: FnOnce() -> Vec<(&'a str, Value<'a>)>,
+    {
+        match &mut self.writer {
+            Some(w) => w.event(time, name, &data()),
+            None => Ok(()),
+        }
+    }
+
+    /// The writer, if there is one.
+    pub fn writer_mut(&mut self) -> Option<&mut QlogWriter<W>> {
+        self.writer.as_mut()
+    }
+
+    /// Stop the trace, and return the writer, if there was one.
+    #[must_use]
+    pub fn take(&mut self) -> Option<QlogWriter<W>> {
+        self.writer.take()
+    }
+}
+
+impl<W: Write> Default for Qlog<W> {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Qlog, QlogWriter, Value, VantagePoint};
+    use crate::Epoch;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    fn records(out: &[u8]) -> Vec<String> {
+        let s = String::from_utf8(out.to_vec()).unwrap();
+        s.split('\u{1e}')
+            .skip(1)
+            .map(|r| r.strip_suffix('\n').unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn header() {
+        let w = QlogWriter::new(Vec::new(), "test", VantagePoint::Client, Instant::now()).unwrap();
+        assert_eq!(
+            records(&w.into_inner().unwrap()),
+            vec![concat!(
+                r#"{"qlog_version":"0.3","qlog_format":"JSON-SEQ","title":"test","#,
+                r#""trace":{"vantage_point":{"type":"client"},"#,
+                r#""common_fields":{"time_format":"relative"}}}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn reference_time() {
+        let start = Instant::now();
+        let epoch = Epoch::new(start, UNIX_EPOCH + Duration::from_millis(1_583_020_799_500));
+        let mut w = QlogWriter::with_epoch(Vec::new(), "t", VantagePoint::Client, &epoch).unwrap();
+        w.event(start + Duration::from_millis(2), "x", &[]).unwrap();
+        let r = records(&w.into_inner().unwrap());
+        assert!(r[0].ends_with(
+            r#""common_fields":{"time_format":"relative","reference_time":1583020799500}}}"#
+        ));
+        assert_eq!(r[1], r#"{"time":2,"name":"x","data":{}}"#);
+    }
+
+    #[test]
+    fn events() {
+        let start = Instant::now();
+        let mut w = QlogWriter::new(Vec::new(), "t", VantagePoint::Server, start).unwrap();
+        w.event(
+            start + Duration::from_micros(1500),
+            "transport:packet_sent",
+            &[
+                (
+                    "header",
+                    Value::Object(vec![("packet_number", 7_u64.into())]),
+                ),
+                ("frames", Value::Array(vec![Value::Str("ack"), Value::Null])),
+            ],
+        )
+        .unwrap();
+        w.event(start, "quic\"log\"", &[("text", "a\nb\u{1}".into())])
+            .unwrap();
+        let r = records(&w.into_inner().unwrap());
+        assert_eq!(r.len(), 3);
+        assert_eq!(
+            r[1],
+            concat!(
+                r#"{"time":1.5,"name":"transport:packet_sent","#,
+                r#""data":{"header":{"packet_number":7},"frames":["ack",null]}}"#
+            )
+        );
+        assert_eq!(
+            r[2],
+            r#"{"time":0,"name":"quic\"log\"","data":{"text":"a\nb\u0001"}}"#
+        );
+    }
+
+    #[test]
+    fn buffered() {
+        let mut sink = Vec::new();
+        {
+            let mut w =
+                QlogWriter::new(&mut sink, "t", VantagePoint::Client, Instant::now()).unwrap();
+            w.event(Instant::now(), "x", &[]).unwrap();
+            w.flush().unwrap();
+        }
+        assert_eq!(records(&sink).len(), 2);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut q = Qlog::<Vec<u8>>::disabled();
+        assert!(!q.is_enabled());
+        q.event(Instant::now(), "x", || panic!("built an event for nothing"))
+            .unwrap();
+        assert!(q.take().is_none());
+    }
+
+    #[test]
+    fn enabled() {
+        let start = Instant::now();
+        let w = QlogWriter::new(Vec::new(), "t", VantagePoint::Client, start).unwrap();
+        let mut q = Qlog::new(w);
+        assert!(q.is_enabled());
+        q.event(start, "x", || vec![("n", 1_u64.into())]).unwrap();
+        let r = records(&q.take().unwrap().into_inner().unwrap());
+        assert_eq!(r[1], r#"{"time":0,"name":"x","data":{"n":1}}"#);
+        assert!(!q.is_enabled());
+    }
+}
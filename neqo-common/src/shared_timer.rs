@@ -0,0 +1,215 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A timer that can be used from many threads.  Items are spread over several
+// wheels, each with its own lock, so that threads adding and taking items
+// mostly use different locks.
+
+use crate::timer::{Timer, TimerHandle};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A token for an item in a `SharedTimer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedTimerHandle {
+    shard: usize,
+    handle: TimerHandle,
+}
+
+impl SharedTimerHandle {
+    /// The time that the item is set for.
+    #[must_use]
+    pub fn time(&self) -> Instant {
+        self.handle.time()
+    }
+}
+
+/// A `Timer` that can be shared between threads.
+pub struct SharedTimer<T> {
+    shards: Vec<Mutex<Timer<T>>>,
+    /// Items are added to each shard in turn.
+    next_shard: AtomicUsize,
+}
+
+impl<T> SharedTimer<T> {
+    /// Make a timer with `shards` wheels, each of which is made as `Timer::new` does.
+    /// Asserts if `shards` is zero.
+    #[must_use]
+    pub fn new(now: Instant, granularity: Duration, capacity: usize, shards: usize) -> Self {
+        assert!(shards > 0);
+        Self {
+            shards: (0..shards)
+                .map(|_| Mutex::new(Timer::new(now, granularity, capacity)))
+                .collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, i: usize) -> MutexGuard<'_, Timer<T>> {
+        self.shards[i].lock().unwrap()
+    }
+
+    /// The shard with the earliest item, and the time of that item.
+    fn earliest(&self) -> Option<(usize, Instant)> {
+        (0..self.shards.len())
+            .filter_map(|i| self.shard(i).next_time().map(|t| (i, t)))
+            .min_by_key(|&(_, t)| t)
+    }
+
+    /// Add an item, and return a handle that can cancel it.
+    /// Asserts if the time given is in the past.
+    pub fn add(&self, time: Instant, item: T) -> SharedTimerHandle {
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let handle = self.shard(shard).add(time, item);
+        SharedTimerHandle { shard, handle }
+    }
+
+    /// Remove the item that `handle` is for.  This returns `None` if the item has already
+    /// been taken or removed.
+    pub fn cancel(&self, handle: SharedTimerHandle) -> Option<T> {
+        self.shard(handle.shard).cancel(handle.handle)
+    }
+
+    /// Move the item that `handle` is for to `time`, as `Timer::reschedule` does.
+    pub fn reschedule(
+        &self,
+        handle: SharedTimerHandle,
+        time: Instant,
+    ) -> Option<SharedTimerHandle> {
+        let h = self.shard(handle.shard).reschedule(handle.handle, time)?;
+        Some(SharedTimerHandle {
+            handle: h,
+            ..handle
+        })
+    }
+
+    /// The time of the next item.
+    #[must_use]
+    pub fn next_time(&self) -> Option<Instant> {
+        self.earliest().map(|(_, t)| t)
+    }
+
+    /// Take the next item, unless none are due at or before `until`.  Another thread can
+    /// take an item between this finding it and taking it, so this can return an item that
+    /// is not the earliest, but all that it returns are due.
+    pub fn take_next(&self, until: Instant) -> Option<T> {
+        while let Some((i, t)) = self.earliest() {
+            if t > until {
+                break;
+            }
+            if let Some(item) = self.shard(i).take_next(until) {
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    /// Take all items until the given time, in time order.
+    pub fn take_until(&self, until: Instant) -> Vec<T> {
+        let mut taken: Vec<_> = (0..self.shards.len())
+            .flat_map(|i| self.shard(i).take_until_timed(until))
+            .collect();
+        // This sort is stable, so items with the same time stay in their shard's order.
+        taken.sort_by_key(|&(t, _)| t);
+        taken.into_iter().map(|(_, item)| item).collect()
+    }
+
+    /// The number of items, across all shards.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.shard(i).len()).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|i| self.shard(i).is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedTimer;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const GRANULARITY: Duration = Duration::from_millis(10);
+    const CAPACITY: usize = 10;
+    const SHARDS: usize = 4;
+
+    #[test]
+    fn in_order() {
+        let now = Instant::now();
+        let t = SharedTimer::new(now, GRANULARITY, CAPACITY, SHARDS);
+        for (i, ms) in [40, 3, 250, 95, 6, 1200].iter().enumerate() {
+            t.add(now + Duration::from_millis(*ms), i);
+        }
+        assert_eq!(Some(now + Duration::from_millis(3)), t.next_time());
+        assert_eq!(Some(1), t.take_next(now + Duration::from_millis(10)));
+        assert_eq!(Some(4), t.take_next(now + Duration::from_millis(10)));
+        assert_eq!(None, t.take_next(now + Duration::from_millis(10)));
+        let values = t.take_until(now + Duration::from_secs(2));
+        assert_eq!(vec![0, 3, 2, 5], values);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn cancel_and_reschedule() {
+        let now = Instant::now();
+        let t = SharedTimer::new(now, GRANULARITY, CAPACITY, SHARDS);
+        let h1 = t.add(now + Duration::from_millis(20), 1);
+        let h2 = t.add(now + Duration::from_millis(30), 2);
+        let h1 = t.reschedule(h1, now + Duration::from_millis(40)).unwrap();
+        assert_eq!(now + Duration::from_millis(40), h1.time());
+        assert_eq!(Some(2), t.cancel(h2));
+        assert_eq!(None, t.cancel(h2));
+        assert_eq!(Some(now + Duration::from_millis(40)), t.next_time());
+        assert_eq!(1, t.len());
+    }
+
+    #[test]
+    fn threads() {
+        const PER_THREAD: usize = 100;
+        let now = Instant::now();
+        let t = Arc::new(SharedTimer::new(now, GRANULARITY, CAPACITY, SHARDS));
+        let adders: Vec<_> = (0..SHARDS)
+            .map(|n| {
+                let t = Arc::clone(&t);
+                thread::spawn(move || {
+                    for i in 0..PER_THREAD {
+                        let ms = u64::try_from(i).unwrap();
+                        t.add(now + Duration::from_millis(ms), n * PER_THREAD + i);
+                    }
+                })
+            })
+            .collect();
+        for a in adders {
+            a.join().unwrap();
+        }
+        assert_eq!(SHARDS * PER_THREAD, t.len());
+
+        let until = now + Duration::from_secs(1);
+        let takers: Vec<_> = (0..SHARDS)
+            .map(|_| {
+                let t = Arc::clone(&t);
+                thread::spawn(move || {
+                    let mut taken = Vec::new();
+                    while let Some(i) = t.take_next(until) {
+                        taken.push(i);
+                    }
+                    taken
+                })
+            })
+            .collect();
+        let mut taken: Vec<_> = takers.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        taken.sort_unstable();
+        assert_eq!((0..SHARDS * PER_THREAD).collect::<Vec<_>>(), taken);
+        assert!(t.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Sources of time, so that time can be controlled in tests and simulations.
+
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Something that can say what time it is.
+pub trait Clock {
+    /// The time now.  This doesn't go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is told to.  Clones share the same time,
+/// so one can be kept to control the time that another sees.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl VirtualClock {
+    #[must_use]
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Rc::new(Cell::new(start)),
+        }
+    }
+
+    /// Move time forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        self.now.set(self.now.get() + d);
+    }
+
+    /// Move time forward to `t`.
+    /// # Panics
+    /// If `t` is in the past.
+    pub fn set(&self, t: Instant) {
+        assert!(t >= self.now.get(), "time can't go backwards");
+        self.now.set(t);
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Ties an `Instant` to the wall-clock time that it happened at, so that other
+/// instants can be given as wall-clock times.  The wall clock is only read
+/// once, so times stay in order and the same distance apart even if the
+/// system clock is changed later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Epoch {
+    instant: Instant,
+    /// When `instant` was, as a time since the Unix epoch.
+    wall: Duration,
+}
+
+impl Epoch {
+    /// Anchor `instant` to `wall`.  If `wall` is before the Unix epoch,
+    /// the system clock is wrong, and the Unix epoch is used instead.
+    #[must_use]
+    pub fn new(instant: Instant, wall: SystemTime) -> Self {
+        Self {
+            instant,
+            wall: wall.duration_since(UNIX_EPOCH).unwrap_or_default(),
+        }
+    }
+
+    /// Anchor the time now.
+    #[must_use]
+    pub fn now() -> Self {
+        Self::new(Instant::now(), SystemTime::now())
+    }
+
+    /// The instant that is anchored.
+    #[must_use]
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    /// The time since the Unix epoch at `t`.  Times that would be before the
+    /// Unix epoch are given as the Unix epoch.
+    #[must_use]
+    pub fn since_unix_epoch(&self, t: Instant) -> Duration {
+        if t >= self.instant {
+            self.wall + (t - self.instant)
+        } else {
+            self.wall.checked_sub(self.instant - t).unwrap_or_default()
+        }
+    }
+
+    /// The wall-clock time at `t`.
+    #[must_use]
+    pub fn system_time(&self, t: Instant) -> SystemTime {
+        UNIX_EPOCH + self.since_unix_epoch(t)
+    }
+
+    /// Milliseconds since the Unix epoch at `t`.
+    #[must_use]
+    pub fn unix_millis(&self, t: Instant) -> u64 {
+        u64::try_from(self.since_unix_epoch(t).as_millis()).unwrap_or(u64::MAX)
+    }
+
+    /// The time at `t` in RFC 3339 form, in UTC and to the millisecond, for
+    /// example `2020-02-29T23:59:59.999Z`.
+    #[must_use]
+    pub fn rfc3339(&self, t: Instant) -> String {
+        let since = self.since_unix_epoch(t);
+        let secs = since.as_secs();
+        let (year, month, day) = civil_from_days(secs / 86400);
+        let secs = secs % 86400;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            since.subsec_millis()
+        )
+    }
+}
+
+/// The year, month and day that is `days` after 1970-01-01, in the proleptic
+/// Gregorian calendar.  See <http://howardhinnant.github.io/date_algorithms.html>.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01, so that leap days fall at the end of a year.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365; // [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11], with March as 0
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock() {
+        let start = Instant::now();
+        let clock = VirtualClock::new(start);
+        let other = clock.clone();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(other.now(), start + Duration::from_secs(1));
+        other.set(start + Duration::from_secs(3));
+        assert_eq!(clock.now(), start + Duration::from_secs(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "time can't go backwards")]
+    fn virtual_clock_backwards() {
+        let start = Instant::now();
+        let clock = VirtualClock::new(start + Duration::from_secs(1));
+        clock.set(start);
+    }
+
+    #[test]
+    fn epoch() {
+        let start = Instant::now();
+        // 2020-02-29T23:59:59.500Z
+        let wall = UNIX_EPOCH + Duration::from_millis(1_583_020_799_500);
+        let epoch = Epoch::new(start, wall);
+        assert_eq!(epoch.system_time(start), wall);
+        assert_eq!(epoch.unix_millis(start), 1_583_020_799_500);
+        assert_eq!(epoch.rfc3339(start), "2020-02-29T23:59:59.500Z");
+        let later = start + Duration::from_millis(1_250);
+        assert_eq!(epoch.rfc3339(later), "2020-03-01T00:00:00.750Z");
+        assert_eq!(epoch.unix_millis(later), 1_583_020_800_750);
+    }
+
+    #[test]
+    fn epoch_bounds() {
+        let start = Instant::now() + Duration::from_secs(10);
+        let epoch = Epoch::new(start, UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(epoch.rfc3339(start), "1970-01-01T00:00:01.000Z");
+        // Before the epoch, and a system clock that is before it too.
+        let before = start.checked_sub(Duration::from_secs(5)).unwrap();
+        assert_eq!(epoch.unix_millis(before), 0);
+        let broken = Epoch::new(start, UNIX_EPOCH - Duration::from_secs(1));
+        assert_eq!(broken.unix_millis(start), 0);
+    }
+
+    #[test]
+    fn civil() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(10_957), (2000, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn system_clock() {
+        let before = Instant::now();
+        assert!(SystemClock.now() >= before);
+    }
+}
@@ -4,132 +4,466 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::Clock;
+
+use std::cmp::min;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display};
 use std::mem;
 use std::time::{Duration, Instant};
 
 /// Internal structure for a timer item.
 struct TimerItem<T> {
     time: Instant,
+    /// Items with the same time are kept in the order they were added, by this.
+    seq: u64,
     item: T,
+    period: Option<Period<T>>,
+}
+
+/// How a periodic item is added again when it is taken.
+struct Period<T> {
+    interval: Duration,
+    clone: fn(&T) -> T,
+}
+
+impl<T> Period<T> {
+    /// The first time after `after` that an item that was due at `time` is due again.
+    #[allow(clippy::cast_possible_truncation)] // guarded by assertion
+    fn next(&self, time: Instant, after: Instant) -> Instant {
+        let n = (after - time).as_nanos() / self.interval.as_nanos() + 1;
+        assert!(n <= u128::from(u32::MAX));
+        time + self.interval * (n as u32)
+    }
 }
 
 impl<T> TimerItem<T> {
     fn time(ti: &Self) -> Instant {
         ti.time
     }
+
+    fn key(ti: &Self) -> (Instant, u64) {
+        (ti.time, ti.seq)
+    }
+}
+
+/// A slot in the wheel.  This is a deque so that items are taken from the front cheaply.
+type Bucket<T> = VecDeque<TimerItem<T>>;
+
+/// A token for an item in a `Timer`, which `Timer::cancel` uses to find it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    time: Instant,
+    seq: u64,
+    /// For a periodic item, `time` is when it was first due.
+    interval: Option<Duration>,
+}
+
+impl TimerHandle {
+    /// The time that the item is set for.  For a periodic item, this is the first time.
+    #[must_use]
+    pub fn time(&self) -> Instant {
+        self.time
+    }
+}
+
+/// What `Timer::try_add` does with a time that is before the timer's current time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PastTime {
+    /// Give the item back.
+    Reject,
+    /// Add the item for the timer's current time, so that it is taken next.
+    Clamp,
+}
+
+/// The error from `Timer::try_add` when the time is in the past.  This has the item.
+#[derive(Debug)]
+pub struct TimeInPast<T>(pub T);
+
+impl<T> Display for TimeInPast<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timer item is in the past")
+    }
+}
+
+impl<T: Debug> std::error::Error for TimeInPast<T> {}
+
+/// An item in a `FrozenTimer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrozenItem<T> {
+    /// How long after the timer's current time the item is due.
+    pub delay: Duration,
+    /// For a periodic item, how often it is due.
+    pub interval: Option<Duration>,
+    pub item: T,
+}
+
+/// The items of a `Timer`, with times that are relative to its current time rather than
+/// instants, so that they can be saved and put in a new timer with a different time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrozenTimer<T> {
+    pub granularity: Duration,
+    pub capacity: usize,
+    /// The items, in the order that they would be taken.
+    pub items: Vec<FrozenItem<T>>,
+}
+
+impl<T: Clone> FrozenTimer<T> {
+    /// Make a timer that has these items, with its current time at `now`.  Handles for the
+    /// timer that was frozen don't work with this one.
+    #[must_use]
+    pub fn restore(self, now: Instant) -> Timer<T> {
+        let mut t = Timer::new(now, self.granularity, self.capacity);
+        for fi in self.items {
+            let time = now + fi.delay;
+            match fi.interval {
+                Some(interval) => t.add_periodic(time, interval, fi.item),
+                None => t.add(time, fi.item),
+            };
+        }
+        t
+    }
 }
 
 /// A timer queue.
-/// This uses a classic timer wheel arrangement, with some characteristics that might be considered peculiar.
-/// Each slot in the wheel is sorted (complexity O(N) insertions, but O(logN) to find cut points).
-/// Time is relative, the wheel has an origin time and it is unable to represent times that are more than
-/// `granularity * capacity` past that time.
+/// This uses a hierarchical timer wheel, with some characteristics that might be considered peculiar.
+/// Each slot in the wheel is sorted (complexity O(N) insertions, but O(1) to take the first item,
+/// and O(logN) to find cut points).
+/// The first level has `capacity` slots that are each `granularity` wide; each level after that has
+/// `capacity` slots that are each as wide as the whole of the level before.  An item goes in the
+/// first level that reaches its time, and levels are added as they are needed, so any time in the
+/// future can be held.  Because slots are sorted, items in coarse levels are taken at their exact
+/// time and never need to move to a finer level.
 pub struct Timer<T> {
-    items: Vec<Vec<TimerItem<T>>>,
+    /// `levels[n][i]` has the items in the slot whose number, counting from `origin`, is `i`
+    /// modulo `capacity`.
+    levels: Vec<Vec<Bucket<T>>>,
+    origin: Instant,
     now: Instant,
     granularity: Duration,
-    cursor: usize,
+    /// The sequence number of the next item to be added.
+    seq: u64,
+    /// The time of the earliest item, so that `next_time` doesn't have to search for it.
+    next: Option<Instant>,
 }
 
 impl<T> Timer<T> {
     /// Construct a new wheel at the given granularity, starting at the given time.
+    /// `capacity` is the number of slots in each level; it doesn't limit how far ahead items
+    /// can be, as levels are added when they are needed.
     pub fn new(now: Instant, granularity: Duration, capacity: usize) -> Self {
         assert!(u32::try_from(capacity).is_ok());
+        assert!(capacity > 1);
         assert!(granularity.as_nanos() > 0);
-        let mut items = Vec::with_capacity(capacity);
-        items.resize_with(capacity, Default::default);
-        Self {
-            items,
+        let mut t = Self {
+            levels: Vec::new(),
+            origin: now,
             now,
             granularity,
-            cursor: 0,
+            seq: 0,
+            next: None,
+        };
+        t.levels.push(Self::new_level(capacity));
+        t
+    }
+
+    fn new_level(capacity: usize) -> Vec<Bucket<T>> {
+        let mut level = Vec::with_capacity(capacity);
+        level.resize_with(capacity, Default::default);
+        level
+    }
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// The first slot of the level with the earliest item, and where that item is.
+    fn first(&self) -> Option<(usize, usize)> {
+        let mut first: Option<((Instant, u64), usize, usize)> = None;
+        for level in 0..self.levels.len() {
+            let now = self.slot(level, self.now);
+            for s in now..now + self.capacity() {
+                let idx = self.index(s);
+                if let Some(item) = self.levels[level][idx].front() {
+                    // Compare `seq` too, as items with the same time can be on different levels.
+                    let earlier = match first {
+                        Some((k, ..)) => TimerItem::key(item) < k,
+                        None => true,
+                    };
+                    if earlier {
+                        first = Some((TimerItem::key(item), level, idx));
+                    }
+                    break;
+                }
+            }
         }
+        first.map(|(_, level, idx)| (level, idx))
     }
 
     /// Return a reference to the time of the next entry.
     #[must_use]
     pub fn next_time(&self) -> Option<Instant> {
-        for i in 0..self.items.len() {
-            let idx = self.bucket(i);
-            if let Some(t) = self.items[idx].first() {
-                return Some(t.time);
-            }
+        self.next
+    }
+
+    /// How long from the time on `clock` until the next entry is due, which is zero if it
+    /// is already due.
+    #[must_use]
+    pub fn next_delay(&self, clock: &dyn Clock) -> Option<Duration> {
+        let now = clock.now();
+        self.next
+            .map(|t| t.checked_duration_since(now).unwrap_or_default())
+    }
+
+    /// Find the earliest item again, after it might have been removed.
+    fn refresh(&mut self) {
+        self.next = self
+            .first()
+            .map(|(level, idx)| self.levels[level][idx][0].time);
+    }
+
+    /// Update `next` after an item at `time` is removed.
+    fn removed(&mut self, time: Instant) {
+        if self.next == Some(time) {
+            self.refresh();
         }
-        None
     }
 
-    /// Get the full span of time that this can cover.
-    /// Two timers cannot be more than this far apart.
-    /// In practice, this value is less by one amount of the timer granularity.
+    /// Get the span of time that the first level covers.  Items further out than this go to
+    /// coarser levels, which take longer to search.
     #[inline]
     #[allow(clippy::cast_possible_truncation)] // guarded by assertion
     #[must_use]
     pub fn span(&self) -> Duration {
-        self.granularity * (self.items.len() as u32)
+        self.granularity * (self.capacity() as u32)
     }
 
-    /// For the given `time`, get the number of whole buckets in the future that is.
+    /// The number of items, across all buckets.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.levels.iter().flatten().map(VecDeque::len).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().flatten().all(VecDeque::is_empty)
+    }
+
+    /// How wide each slot of `level` is, in nanoseconds.
     #[inline]
     #[allow(clippy::cast_possible_truncation)] // guarded by assertion
-    fn delta(&self, time: Instant) -> usize {
+    fn width(&self, level: usize) -> u128 {
+        self.granularity.as_nanos() * (self.capacity() as u128).pow(level as u32)
+    }
+
+    /// A histogram of how full the buckets are, over all levels.  The first entry counts the
+    /// empty buckets, and entry `n` counts those with between `2^(n-1)` and `2^n - 1` items.
+    /// The last entry is never zero, except when there are no buckets with items.
+    #[must_use]
+    pub fn occupancy(&self) -> Vec<usize> {
+        let mut histogram = vec![0];
+        for bucket in self.levels.iter().flatten() {
+            let n = (usize::BITS - bucket.len().leading_zeros()) as usize;
+            if n >= histogram.len() {
+                histogram.resize(n + 1, 0);
+            }
+            histogram[n] += 1;
+        }
+        histogram
+    }
+
+    /// Iterate over all items, in time order, without taking them.
+    pub fn iter(&self) -> impl Iterator<Item = (Instant, &T)> {
+        let mut items: Vec<_> = self.levels.iter().flatten().flatten().collect();
+        items.sort_by_key(|ti| TimerItem::key(ti));
+        items.into_iter().map(|ti| (ti.time, &ti.item))
+    }
+
+    /// Iterate over the items that are due at or before `until`, in time order, without taking
+    /// them.
+    pub fn iter_until(&self, until: Instant) -> impl Iterator<Item = (Instant, &T)> {
+        self.iter().take_while(move |(t, _)| *t <= until)
+    }
+
+    /// The number of the slot in `level` that `time` falls in, counting from `origin`.
+    #[inline]
+    #[allow(clippy::cast_possible_truncation)] // slot numbers wrap at `capacity`
+    fn slot(&self, level: usize, time: Instant) -> usize {
         // This really should use Instant::div_duration(), but it can't yet.
-        ((time - self.now).as_nanos() / self.granularity.as_nanos()) as usize
+        ((time - self.origin).as_nanos() / self.width(level)) as usize
     }
 
     #[inline]
-    fn time_bucket(&self, time: Instant) -> usize {
-        self.bucket(self.delta(time))
+    fn index(&self, slot: usize) -> usize {
+        slot % self.capacity()
     }
 
+    /// Whether `level` reaches `time`.  Each level holds `capacity` slots, starting with the one
+    /// that `now` is in, so no two times that it holds share a slot unless they share a number.
     #[inline]
-    fn bucket(&self, delta: usize) -> usize {
-        debug_assert!(delta < self.items.len());
-        (self.cursor + delta) % self.items.len()
+    fn reaches(&self, level: usize, time: Instant) -> bool {
+        self.slot(level, time) - self.slot(level, self.now) < self.capacity()
     }
 
-    /// Slide forward in time by `n * self.granularity`.
-    #[allow(clippy::cast_possible_truncation)] // guarded by assertion
-    fn tick(&mut self, n: usize) {
-        let new = self.bucket(n);
-        let iter = if new < self.cursor {
-            (self.cursor..self.items.len()).chain(0..new)
-        } else {
-            (self.cursor..new).chain(0..0)
-        };
-        for i in iter {
-            assert!(self.items[i].is_empty());
+    /// Add an item, and return a handle that can cancel it.
+    /// Asserts if the time given is in the past.
+    pub fn add(&mut self, time: Instant, item: T) -> TimerHandle {
+        assert!(time >= self.now);
+        let seq = self.seq;
+        self.seq += 1;
+        self.insert(TimerItem {
+            time,
+            seq,
+            item,
+            period: None,
+        });
+        TimerHandle {
+            time,
+            seq,
+            interval: None,
         }
-        self.now += self.granularity * (n as u32);
-        self.cursor = new;
     }
 
-    /// Asserts if the time given is in the past or too far in the future.
-    pub fn add(&mut self, time: Instant, item: T) {
-        assert!(time >= self.now);
-        // Skip forward quickly if there is too large a gap.
-        let short_span = self.span() - self.granularity;
-        if time >= (self.now + self.span() + short_span) {
-            // Assert that there aren't any items.
-            for i in &self.items {
-                assert!(i.is_empty());
-            }
-            self.now = time - short_span;
-            self.cursor = 0;
+    /// Add an item, without asserting if `time` is in the past, which can happen when the time
+    /// comes from a clock that was read before the timer was last moved on.  `past` says what
+    /// is done then.
+    /// # Errors
+    /// When `time` is in the past and `past` is `PastTime::Reject`.
+    pub fn try_add(
+        &mut self,
+        time: Instant,
+        item: T,
+        past: PastTime,
+    ) -> Result<TimerHandle, TimeInPast<T>> {
+        if time >= self.now {
+            Ok(self.add(time, item))
+        } else if past == PastTime::Clamp {
+            Ok(self.add(self.now, item))
+        } else {
+            Err(TimeInPast(item))
         }
+    }
 
-        // Adjust time forward the minimum amount necessary.
-        let mut d = self.delta(time);
-        if d >= self.items.len() {
-            self.tick(1 + d - self.items.len());
-            d = self.items.len() - 1;
+    /// The level that an item at `time` goes in, which is added if it isn't there yet.
+    fn level_for(&mut self, time: Instant) -> usize {
+        let mut level = 0;
+        while !self.reaches(level, time) {
+            level += 1;
+            if level == self.levels.len() {
+                self.levels.push(Self::new_level(self.capacity()));
+            }
         }
+        level
+    }
 
-        let bucket = self.bucket(d);
-        let ins = match self.items[bucket].binary_search_by_key(&time, TimerItem::time) {
+    fn insert(&mut self, item: TimerItem<T>) {
+        let level = self.level_for(item.time);
+        let idx = self.index(self.slot(level, item.time));
+        let bucket = &mut self.levels[level][idx];
+        // This is always `Err`, as no two items have the same `seq`.
+        let ins = match bucket.binary_search_by_key(&TimerItem::key(&item), TimerItem::key) {
             Ok(j) | Err(j) => j,
         };
-        self.items[bucket].insert(ins, TimerItem { time, item });
+        self.next = Some(self.next.map_or(item.time, |t| min(t, item.time)));
+        bucket.insert(ins, item);
+    }
+
+    /// Find the item for `handle`: its level, the index of its bucket, and where it is in that.
+    fn find(&self, handle: TimerHandle) -> Option<(usize, usize, usize)> {
+        match handle.interval {
+            None => self.locate(handle.time, handle.seq),
+            Some(interval) => {
+                // A periodic item that has been taken is next due at the first time after the
+                // wheel's `now`; if it hasn't, at `handle.time`.  It is at one of these two.
+                let mut time = handle.time;
+                if self.now > time {
+                    let n = (self.now - time).as_nanos().div_ceil(interval.as_nanos());
+                    time += interval * u32::try_from(n).ok()?;
+                }
+                self.locate(time, handle.seq)
+                    .or_else(|| self.locate(time + interval, handle.seq))
+                    // `take_next` adds it for after the `until` that it was given, which can be
+                    // later than that.
+                    .or_else(|| self.scan(handle.seq))
+            }
+        }
+    }
+
+    /// Find the item with `seq` by looking at every item.
+    fn scan(&self, seq: u64) -> Option<(usize, usize, usize)> {
+        self.levels.iter().enumerate().find_map(|(level, buckets)| {
+            buckets.iter().enumerate().find_map(|(idx, bucket)| {
+                bucket
+                    .iter()
+                    .position(|ti| ti.seq == seq)
+                    .map(|i| (level, idx, i))
+            })
+        })
+    }
+
+    fn locate(&self, time: Instant, seq: u64) -> Option<(usize, usize, usize)> {
+        if time < self.now {
+            return None;
+        }
+        (0..self.levels.len())
+            .filter(|&level| self.reaches(level, time))
+            .find_map(|level| {
+                let idx = self.index(self.slot(level, time));
+                self.levels[level][idx]
+                    .binary_search_by_key(&(time, seq), TimerItem::key)
+                    .ok()
+                    .map(|i| (level, idx, i))
+            })
+    }
+
+    /// Remove the item that `handle` is for.  This returns `None` if the item has already
+    /// been taken or removed.
+    pub fn cancel(&mut self, handle: TimerHandle) -> Option<T> {
+        let (level, idx, i) = self.find(handle)?;
+        let ti = self.levels[level][idx].remove(i)?;
+        self.removed(ti.time);
+        Some(ti.item)
+    }
+
+    /// Move the item that `handle` is for to `time`, and return the handle that is now for it.
+    /// This returns `None` if the item has already been taken or removed.
+    /// Asserts if the time given is in the past.
+    pub fn reschedule(&mut self, handle: TimerHandle, time: Instant) -> Option<TimerHandle> {
+        assert!(time >= self.now);
+        let (level, idx, i) = self.find(handle)?;
+        let new = TimerHandle { time, ..handle };
+        let old = self.levels[level][idx][i].time;
+        let new_level = self.level_for(time);
+        if new_level != level || self.index(self.slot(level, time)) != idx {
+            let mut item = self.levels[level][idx].remove(i)?;
+            item.time = time;
+            self.insert(item);
+            self.removed(old);
+            return Some(new);
+        }
+
+        // The item stays in the same bucket, so it only has to move past the items between
+        // where it is and where it goes.  The bucket is sorted, so this finds where it goes.
+        let bucket = self.levels[level][idx].make_contiguous();
+        let key = (time, handle.seq);
+        let j = bucket.partition_point(|x| TimerItem::key(x) < key);
+        if j > i {
+            bucket[i..j].rotate_left(1);
+            bucket[j - 1].time = time;
+        } else {
+            bucket[j..=i].rotate_right(1);
+            bucket[j].time = time;
+        }
+        if self.next == Some(old) {
+            self.refresh();
+        } else {
+            self.next = self.next.map(|t| min(t, time));
+        }
+        Some(new)
     }
 
     /// Given knowledge of the time an item was added, remove it.
@@ -141,44 +475,100 @@ impl<T> Timer<T> {
         if time < self.now {
             return None;
         }
-        if time > self.now + self.span() {
-            return None;
-        }
-        let bucket = self.time_bucket(time);
-        let start_index = match self.items[bucket].binary_search_by_key(&time, TimerItem::time) {
-            Ok(idx) => idx,
-            _ => return None,
-        };
-        // start_index is just one of potentially many items with the same time.
-        // Search backwards for a match, ...
-        for i in (0..=start_index).rev() {
-            if self.items[bucket][i].time != time {
-                break;
+        // The item is in the first level that reached it when it was added, which might not
+        // be the first that reaches it now.
+        for level in 0..self.levels.len() {
+            if !self.reaches(level, time) {
+                continue;
             }
-            if selector(&self.items[bucket][i].item) {
-                return Some(self.items[bucket].remove(i).item);
-            }
-        }
-        // ... then forwards.
-        for i in (start_index + 1)..self.items[bucket].len() {
-            if self.items[bucket][i].time != time {
-                break;
+            let idx = self.index(self.slot(level, time));
+            let bucket = &mut self.levels[level][idx];
+            let start_index = match bucket.binary_search_by_key(&time, TimerItem::time) {
+                Ok(idx) => idx,
+                _ => continue,
+            };
+            // start_index is just one of potentially many items with the same time.
+            // Search backwards for a match, ...
+            for i in (0..=start_index).rev() {
+                if bucket[i].time != time {
+                    break;
+                }
+                if selector(&bucket[i].item) {
+                    let item = bucket.remove(i)?.item;
+                    self.removed(time);
+                    return Some(item);
+                }
             }
-            if selector(&self.items[bucket][i].item) {
-                return Some(self.items[bucket].remove(i).item);
+            // ... then forwards.
+            for i in (start_index + 1)..bucket.len() {
+                if bucket[i].time != time {
+                    break;
+                }
+                if selector(&bucket[i].item) {
+                    let item = bucket.remove(i)?.item;
+                    self.removed(time);
+                    return Some(item);
+                }
             }
         }
         None
     }
 
+    /// Remove all items that match `f`, and return them in time order.  Periodic items are
+    /// removed for good.  `f` is called once for each item.
+    pub fn drain_where<F>(&mut self, mut f: F) -> Vec<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut drained = Vec::new();
+        for bucket in self.levels.iter_mut().flatten() {
+            if bucket.is_empty() {
+                continue;
+            }
+            let (matched, kept): (Bucket<T>, _) =
+                mem::take(bucket).into_iter().partition(|ti| f(&ti.item));
+            *bucket = kept;
+            drained.extend(matched);
+        }
+        if !drained.is_empty() {
+            self.refresh();
+        }
+        drained.sort_by_key(TimerItem::key);
+        drained.into_iter().map(|ti| ti.item).collect()
+    }
+
+    /// Take the item from the timer, adding it again if it is periodic.  Its next time is the
+    /// first after `after`, so any times that were missed are skipped.
+    fn rearm(&mut self, ti: TimerItem<T>, after: Instant) -> T {
+        if let Some(period) = ti.period {
+            self.insert(TimerItem {
+                time: period.next(ti.time, after),
+                seq: ti.seq,
+                item: (period.clone)(&ti.item),
+                period: Some(period),
+            });
+        }
+        ti.item
+    }
+
     /// Take the next item, unless there are no items with
-    /// a timeout in the past relative to `until`.
+    /// a timeout in the past relative to `until`.  A periodic item is next due after `until`,
+    /// so it is only taken once however many of its times have passed.
     pub fn take_next(&mut self, until: Instant) -> Option<T> {
-        for i in 0..self.items.len() {
-            let idx = self.bucket(i);
-            if !self.items[idx].is_empty() && self.items[idx][0].time <= until {
-                return Some(self.items[idx].remove(0).item);
-            }
+        // Only search for the earliest item when it is due.
+        let due = self.next.filter(|&t| t <= until);
+        let first = due.and_then(|_| self.first());
+        if let Some(ti) = first.and_then(|(level, idx)| self.levels[level][idx].pop_front()) {
+            // Nothing is earlier than this item, so the wheel can move up to it.
+            self.now = ti.time;
+            let time = ti.time;
+            let item = self.rearm(ti, until);
+            self.removed(time);
+            return Some(item);
+        }
+        // Nothing is due before `until`, so the wheel can move up to it.
+        if until > self.now {
+            self.now = until;
         }
         None
     }
@@ -186,50 +576,110 @@ impl<T> Timer<T> {
     /// Create an iterator that takes all items until the given time.
     /// Note: Items might be removed even if the iterator is not fully exhausted.
     pub fn take_until(&mut self, until: Instant) -> impl Iterator<Item = T> {
-        let get_item = move |x: TimerItem<T>| x.item;
-        if until >= self.now + self.span() {
-            // Drain everything, so a clean sweep.
-            let mut empty_items = Vec::with_capacity(self.items.len());
-            empty_items.resize_with(self.items.len(), Vec::default);
-            let mut items = mem::replace(&mut self.items, empty_items);
-            self.now = until;
-            self.cursor = 0;
+        self.take_until_timed(until)
+            .into_iter()
+            .map(|(_, item)| item)
+    }
 
-            let tail = items.split_off(self.cursor);
-            return tail.into_iter().chain(items).flatten().map(get_item);
-        }
+    /// Take all items that are due at the time on `clock`.
+    pub fn take_due(&mut self, clock: &dyn Clock) -> impl Iterator<Item = T> {
+        self.take_until(clock.now())
+    }
 
-        // Only returning a partial span, so do it bucket at a time.
-        let delta = self.delta(until);
-        let mut buckets = Vec::with_capacity(delta + 1);
+    /// Take all items until the given time, in time order, with the time of each.
+    pub(crate) fn take_until_timed(&mut self, until: Instant) -> Vec<(Instant, T)> {
+        let mut taken = Vec::new();
+        if until >= self.now {
+            for level in 0..self.levels.len() {
+                let now = self.slot(level, self.now);
+                let last = self.slot(level, until);
+                // Take whole buckets, up to the one that `until` is in.
+                for s in now..min(last, now + self.capacity()) {
+                    let idx = self.index(s);
+                    taken.extend(self.levels[level][idx].drain(..));
+                }
+                if last >= now + self.capacity() {
+                    continue;
+                }
 
-        // First, the whole buckets.
-        for i in 0..delta {
-            let idx = self.bucket(i);
-            buckets.push(mem::replace(&mut self.items[idx], Vec::default()));
+                // That last bucket might have some items with `item.time > until`.
+                let idx = self.index(last);
+                let bucket = &mut self.levels[level][idx];
+                let last_idx = match bucket.binary_search_by_key(&until, TimerItem::time) {
+                    Ok(mut m) => {
+                        // If there are multiple values, the search will hit any of them.
+                        // Make sure to get them all.
+                        while m < bucket.len() && bucket[m].time == until {
+                            m += 1;
+                        }
+                        m
+                    }
+                    Err(ins) => ins,
+                };
+                let tail = bucket.split_off(last_idx);
+                taken.extend(mem::replace(bucket, tail));
+            }
+            self.now = until;
+        }
+        // Each level is in time order, but the levels are not in order with each other.
+        taken.sort_by_key(TimerItem::key);
+        if !taken.is_empty() {
+            self.refresh();
         }
-        self.tick(delta);
+        taken
+            .into_iter()
+            .map(|ti| (ti.time, self.rearm(ti, until)))
+            .collect()
+    }
+}
 
-        // Now we need to split the last bucket, because there might be
-        // some items with `item.time > until`.
-        let bucket = &mut self.items[self.cursor];
-        let last_idx = match bucket.binary_search_by_key(&until, TimerItem::time) {
-            Ok(mut m) => {
-                // If there are multiple values, the search will hit any of them.
-                // Make sure to get them all.
-                while m < bucket.len() && bucket[m].time == until {
-                    m += 1;
-                }
-                m
-            }
-            Err(ins) => ins,
-        };
-        let tail = bucket.split_off(last_idx);
-        buckets.push(mem::replace(bucket, tail));
-        // This tomfoolery with the empty vector ensures that
-        // the returned type here matches the one above precisely
-        // without having to invoke the `either` crate.
-        buckets.into_iter().chain(vec![]).flatten().map(get_item)
+impl<T: Clone> Timer<T> {
+    /// Take all the items, with times relative to the timer's current time.  This and
+    /// `FrozenTimer::restore` move a timer to another time, such as for a connection that
+    /// is suspended and resumed later.
+    #[must_use]
+    pub fn freeze(mut self) -> FrozenTimer<T> {
+        let now = self.now;
+        let capacity = self.capacity();
+        let mut items: Vec<_> = self.levels.drain(..).flatten().flatten().collect();
+        items.sort_by_key(TimerItem::key);
+        FrozenTimer {
+            granularity: self.granularity,
+            capacity,
+            items: items
+                .into_iter()
+                .map(|ti| FrozenItem {
+                    delay: ti.time - now,
+                    interval: ti.period.map(|p| p.interval),
+                    item: ti.item,
+                })
+                .collect(),
+        }
+    }
+
+    /// Add an item that is due at `start`, then every `interval` after that.  Each time it is
+    /// taken, a clone of it is added for the next time that is after the time it was taken
+    /// for.  It stays until it is cancelled, or removed.
+    /// Asserts if `start` is in the past.
+    pub fn add_periodic(&mut self, start: Instant, interval: Duration, item: T) -> TimerHandle {
+        assert!(start >= self.now);
+        assert!(interval.as_nanos() > 0);
+        let seq = self.seq;
+        self.seq += 1;
+        self.insert(TimerItem {
+            time: start,
+            seq,
+            item,
+            period: Some(Period {
+                interval,
+                clone: T::clone,
+            }),
+        });
+        TimerHandle {
+            time: start,
+            seq,
+            interval: Some(interval),
+        }
     }
 }
 
@@ -260,6 +710,92 @@ mod test {
         assert_eq!(vec![12], values);
     }
 
+    #[test]
+    fn with_clock() {
+        let clock = crate::VirtualClock::new(*NOW);
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        assert_eq!(None, t.next_delay(&clock));
+        t.add(*NOW + Duration::from_millis(35), 1);
+        t.add(*NOW + Duration::from_millis(70), 2);
+        assert_eq!(Some(Duration::from_millis(35)), t.next_delay(&clock));
+        assert_eq!(0, t.take_due(&clock).count());
+
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(vec![1], t.take_due(&clock).collect::<Vec<_>>());
+        assert_eq!(Some(Duration::from_millis(20)), t.next_delay(&clock));
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(Some(Duration::from_millis(0)), t.next_delay(&clock));
+        assert_eq!(vec![2], t.take_due(&clock).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn next_time_cached() {
+        let mut t = with_times();
+        let mut sorted: Vec<_> = TIMES.iter().map(|time| *NOW + *time).collect();
+        sorted.sort();
+        assert_eq!(Some(3), t.remove(*NOW + TIMES[3], |&x| x == 3));
+        assert_eq!(Some(sorted[1]), t.next_time());
+        assert_eq!(Some(2), t.take_next(*NOW + Duration::from_secs(1)));
+        assert_eq!(Some(sorted[2]), t.next_time());
+        let _ = t.take_until(sorted[4]).count();
+        assert_eq!(Some(sorted[5]), t.next_time());
+        let h = t.add(sorted[5] + Duration::from_millis(1), 7);
+        let h = t.reschedule(h, sorted[4]).unwrap();
+        assert_eq!(Some(sorted[4]), t.next_time());
+        assert_eq!(Some(7), t.cancel(h));
+        assert_eq!(Some(sorted[5]), t.next_time());
+    }
+
+    #[test]
+    fn iter() {
+        let t = with_times();
+        let mut expected: Vec<_> = TIMES
+            .iter()
+            .enumerate()
+            .map(|(i, time)| (*NOW + *time, i))
+            .collect();
+        expected.sort();
+        let items: Vec<_> = t.iter().map(|(time, &i)| (time, i)).collect();
+        assert_eq!(expected, items);
+        let until = *NOW + Duration::from_millis(22);
+        let items: Vec<_> = t.iter_until(until).map(|(_, &i)| i).collect();
+        assert_eq!(vec![3, 2, 4], items);
+        assert_eq!(TIMES.len(), t.len());
+    }
+
+    #[test]
+    fn drain_where() {
+        let mut t = with_times();
+        let h = t.add_periodic(*NOW + Duration::from_millis(5), Duration::from_secs(1), 6);
+        assert_eq!(vec![6, 2, 4, 0], t.drain_where(|&x| x % 2 == 0));
+        assert_eq!(3, t.len());
+        assert_eq!(None, t.cancel(h));
+        assert_eq!(Some(*NOW + TIMES[3]), t.next_time());
+        assert!(t.drain_where(|&x| x == 0).is_empty());
+    }
+
+    #[test]
+    fn drain_where_calls_once() {
+        let mut t = with_times();
+        let mut calls = 0;
+        let drained = t.drain_where(|&x| {
+            calls += 1;
+            x == 2
+        });
+        assert_eq!(vec![2], drained);
+        assert_eq!(TIMES.len(), calls);
+        assert_eq!(TIMES.len() - 1, t.len());
+
+        // A predicate that changes its answer gets one say over each item.
+        let mut take = true;
+        let drained = t.drain_where(|_| {
+            take = !take;
+            take
+        });
+        assert_eq!(TIMES.len() - 1, drained.len() + t.len());
+        assert_eq!((TIMES.len() - 1) / 2, drained.len());
+    }
+
     #[test]
     fn same_time() {
         let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
@@ -360,6 +896,53 @@ mod test {
         assert_eq!(None, t.next_time());
     }
 
+    #[test]
+    fn len() {
+        let mut t = with_times();
+        assert_eq!(t.len(), TIMES.len());
+        assert!(!t.is_empty());
+        assert_eq!(Some(0), t.remove(*NOW + TIMES[0], |&x| x == 0));
+        assert_eq!(t.len(), TIMES.len() - 1);
+        let _ = t.take_until(*NOW + Duration::from_secs(100)).count();
+        assert_eq!(t.len(), 0);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        assert_eq!(vec![CAPACITY], t.occupancy());
+        for i in 0..5 {
+            t.add(*NOW, i);
+        }
+        t.add(*NOW + Duration::from_millis(10), 5);
+        assert_eq!(vec![CAPACITY - 2, 1, 0, 1], t.occupancy());
+        t.add(*NOW + Duration::from_millis(500), 6);
+        assert_eq!(vec![2 * CAPACITY - 3, 2, 0, 1], t.occupancy());
+        let _ = t.take_until(*NOW + Duration::from_millis(500)).count();
+        assert_eq!(vec![2 * CAPACITY], t.occupancy());
+    }
+
+    #[test]
+    fn freeze_and_restore() {
+        let mut t = with_times();
+        t.add_periodic(*NOW + Duration::from_millis(5), Duration::from_secs(1), 6);
+        t.add(*NOW + Duration::from_secs(30), 7);
+        assert_eq!(Some(3), t.take_next(*NOW + Duration::from_millis(4)));
+        let frozen = t.freeze();
+        assert_eq!(7, frozen.items.len());
+        assert_eq!(Duration::from_millis(2), frozen.items[0].delay);
+        assert_eq!(Some(Duration::from_secs(1)), frozen.items[0].interval);
+
+        let later = *NOW + Duration::from_secs(45);
+        let mut t = frozen.restore(later);
+        assert_eq!(Some(later + Duration::from_millis(2)), t.next_time());
+        let values: Vec<_> = t.take_until(later + Duration::from_secs(30)).collect();
+        assert_eq!(vec![6, 2, 4, 0, 5, 1, 7], values);
+        // The periodic item is still periodic.
+        assert_eq!(1, t.len());
+    }
+
     #[test]
     fn remove_future() {
         let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
@@ -380,4 +963,207 @@ mod test {
 
         assert_eq!(None, t.remove(too_far_future, |candidate| *candidate == v));
     }
+
+    #[test]
+    fn grow_with_items() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let mut times = Vec::new();
+        let mut far = Duration::from_millis(5);
+        for i in 0..8 {
+            times.push(*NOW + far);
+            t.add(*NOW + far, i);
+            far *= 10;
+        }
+        assert_eq!(8, t.len());
+        for (i, time) in times.iter().enumerate() {
+            assert_eq!(Some(*time), t.next_time());
+            assert_eq!(Some(i), t.take_next(*time));
+        }
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn try_add_past() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let later = *NOW + Duration::from_millis(25);
+        assert_eq!(None, t.take_next(later));
+        match t.try_add(*NOW, 1, PastTime::Reject) {
+            Err(TimeInPast(1)) => {}
+            _ => panic!("a time in the past should be rejected"),
+        }
+        let h = t.try_add(*NOW, 2, PastTime::Clamp).unwrap();
+        assert_eq!(later, h.time());
+        assert!(t.try_add(later, 3, PastTime::Reject).is_ok());
+        assert_eq!(vec![2, 3], t.take_until(later).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn idle_and_pto() {
+        // 30 seconds is many times the span, so this needs several levels.
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let idle = *NOW + Duration::from_secs(30);
+        let pto = *NOW + Duration::from_millis(25);
+        t.add(idle, 1);
+        t.add(pto, 2);
+        assert_eq!(Some(pto), t.next_time());
+        assert_eq!(vec![2], t.take_until(pto).collect::<Vec<_>>());
+        assert_eq!(Some(idle), t.next_time());
+        let values: Vec<_> = t.take_until(idle - Duration::from_millis(1)).collect();
+        assert!(values.is_empty());
+        assert_eq!(vec![1], t.take_until(idle).collect::<Vec<_>>());
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn take_in_order() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let times = [5000, 3, 250, 40_000, 95, 1200];
+        for (i, ms) in times.iter().enumerate() {
+            t.add(*NOW + Duration::from_millis(*ms), i);
+        }
+        let values: Vec<_> = t.take_until(*NOW + Duration::from_secs(100)).collect();
+        assert_eq!(vec![1, 4, 2, 5, 0, 3], values);
+    }
+
+    #[test]
+    fn take_next_in_order() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        t.add(*NOW + Duration::from_secs(20), 0);
+        t.add(*NOW + Duration::from_millis(1500), 1);
+        t.add(*NOW + Duration::from_millis(15), 2);
+        let until = *NOW + Duration::from_secs(20);
+        assert_eq!(Some(2), t.take_next(until));
+        assert_eq!(Some(1), t.take_next(until));
+        assert_eq!(Some(0), t.take_next(until));
+        assert_eq!(None, t.take_next(until));
+    }
+
+    #[test]
+    fn cancel_each() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let handles: Vec<_> = TIMES
+            .iter()
+            .enumerate()
+            .map(|(i, time)| t.add(*NOW + *time, i))
+            .collect();
+        // Items 0 and 5 have the same time, so cancel in reverse to check that the right one goes.
+        for (i, h) in handles.iter().enumerate().rev() {
+            assert_eq!(Some(i), t.cancel(*h));
+            assert_eq!(None, t.cancel(*h));
+        }
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn cancel_after_take() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let near = t.add(*NOW + Duration::from_millis(5), 1);
+        let far = t.add(*NOW + Duration::from_secs(30), 2);
+        assert_eq!(Some(1), t.take_next(*NOW + Duration::from_secs(1)));
+        assert_eq!(None, t.cancel(near));
+        assert_eq!(Some(2), t.cancel(far));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn reschedule_in_bucket() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let h = t.add(*NOW + Duration::from_millis(11), 0);
+        t.add(*NOW + Duration::from_millis(13), 1);
+        t.add(*NOW + Duration::from_millis(15), 2);
+        let h = t.reschedule(h, *NOW + Duration::from_millis(17)).unwrap();
+        assert_eq!(Some(*NOW + Duration::from_millis(13)), t.next_time());
+        let h = t.reschedule(h, *NOW + Duration::from_millis(12)).unwrap();
+        assert_eq!(Some(*NOW + Duration::from_millis(12)), t.next_time());
+        let h = t.reschedule(h, *NOW + Duration::from_millis(14)).unwrap();
+        let values: Vec<_> = t.take_until(*NOW + Duration::from_millis(20)).collect();
+        assert_eq!(vec![1, 0, 2], values);
+        assert_eq!(None, t.reschedule(h, *NOW + Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn reschedule_between_levels() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let pto = *NOW + Duration::from_millis(25);
+        let h = t.add(pto, 1);
+        t.add(*NOW + Duration::from_millis(40), 2);
+        let later = *NOW + Duration::from_secs(10);
+        let h = t.reschedule(h, later).unwrap();
+        assert_eq!(Some(*NOW + Duration::from_millis(40)), t.next_time());
+        let h = t.reschedule(h, pto).unwrap();
+        assert_eq!(Some(pto), t.next_time());
+        assert_eq!(Some(1), t.cancel(h));
+        assert_eq!(1, t.len());
+    }
+
+    #[test]
+    fn periodic() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let interval = Duration::from_millis(15);
+        let h = t.add_periodic(*NOW + interval, interval, 1);
+        t.add(*NOW + Duration::from_millis(40), 2);
+        assert_eq!(None, t.take_next(*NOW + Duration::from_millis(10)));
+        // The times at 30 and 45 have passed too, so they are skipped.
+        assert_eq!(Some(1), t.take_next(*NOW + Duration::from_millis(50)));
+        assert_eq!(Some(2), t.take_next(*NOW + Duration::from_millis(50)));
+        assert_eq!(None, t.take_next(*NOW + Duration::from_millis(50)));
+        assert_eq!(Some(*NOW + Duration::from_millis(60)), t.next_time());
+        assert_eq!(Some(1), t.take_next(*NOW + Duration::from_millis(60)));
+        assert_eq!(Some(*NOW + Duration::from_millis(75)), t.next_time());
+        assert_eq!(Some(1), t.cancel(h));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn periodic_take_next_skips_missed() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let interval = Duration::from_millis(10);
+        let h = t.add_periodic(*NOW, interval, 1);
+        t.add(*NOW + Duration::from_millis(35), 2);
+        // Ten periods pass at once, but the item is only taken once.
+        let until = *NOW + Duration::from_millis(100);
+        assert_eq!(Some(1), t.take_next(until));
+        assert_eq!(Some(*NOW + Duration::from_millis(35)), t.next_time());
+        // The handle still finds it, though it is due well after the item that is next.
+        let h = t.reschedule(h, *NOW + Duration::from_millis(120)).unwrap();
+        assert_eq!(Some(2), t.take_next(until));
+        assert_eq!(None, t.take_next(until));
+        assert_eq!(Some(*NOW + Duration::from_millis(120)), t.next_time());
+        assert_eq!(Some(1), t.cancel(h));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn periodic_skips_missed() {
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let interval = Duration::from_millis(100);
+        let h = t.add_periodic(*NOW, interval, 1);
+        let values: Vec<_> = t.take_until(*NOW + Duration::from_millis(450)).collect();
+        assert_eq!(vec![1], values);
+        assert_eq!(Some(*NOW + Duration::from_millis(500)), t.next_time());
+        let h = t.reschedule(h, *NOW + Duration::from_millis(470)).unwrap();
+        assert_eq!(
+            vec![1],
+            t.take_until(*NOW + Duration::from_millis(480))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(*NOW + Duration::from_millis(570)), t.next_time());
+        assert_eq!(Some(1), t.cancel(h));
+        assert_eq!(None, t.cancel(h));
+    }
+
+    #[test]
+    fn remove_after_moving() {
+        // This goes in a coarse level, ...
+        let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
+        let future = *NOW + Duration::from_millis(2345);
+        t.add(future, 9);
+        // ... which the first level reaches once time moves on.
+        assert_eq!(None, t.take_next(future - Duration::from_millis(50)));
+        t.add(future, 10);
+        assert_eq!(2, t.len());
+        assert_eq!(Some(9), t.remove(future, |&x| x == 9));
+        assert_eq!(Some(10), t.remove(future, |&x| x == 10));
+        assert!(t.is_empty());
+    }
 }
@@ -0,0 +1,92 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Waiting for a `Timer` with tokio, so that an async driver can sleep until
+// the next item is due rather than turning `next_time()` into a sleep itself.
+
+use crate::timer::Timer;
+
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::time::{self, Sleep};
+
+/// Waits for the next item in a `Timer` to be due.  This keeps one tokio
+/// sleep, which is moved whenever the time of the next item changes.
+#[derive(Debug, Default)]
+pub struct TimerSleep {
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TimerSleep {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Poll for the next item in `timer` to be due, and return its time when
+    /// it is.  Only the time of the next item is watched, so poll again after
+    /// changing `timer`.  When `timer` is empty, this is pending until it is
+    /// polled again.
+    pub fn poll_next_time<T>(&mut self, timer: &Timer<T>, cx: &mut Context) -> Poll<Instant> {
+        if let Some(next) = timer.next_time() {
+            let deadline = time::Instant::from_std(next);
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+            if sleep.deadline() != deadline {
+                sleep.as_mut().reset(deadline);
+            }
+            sleep.as_mut().poll(cx).map(|()| next)
+        } else {
+            self.sleep = None;
+            Poll::Pending
+        }
+    }
+
+    /// Wait for the next item in `timer` to be due, as `poll_next_time` does.
+    /// Make a new future after changing `timer`, such as each time around a
+    /// `select!` loop.
+    pub fn wait<'a, T>(&'a mut self, timer: &'a Timer<T>) -> impl Future<Output = Instant> + 'a {
+        future::poll_fn(move |cx| self.poll_next_time(timer, cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimerSleep;
+    use crate::timer::Timer;
+    use std::time::{Duration, Instant};
+
+    const GRANULARITY: Duration = Duration::from_millis(10);
+    const CAPACITY: usize = 10;
+
+    #[tokio::test]
+    async fn wait_for_next() {
+        let now = Instant::now();
+        let mut timer = Timer::new(now, GRANULARITY, CAPACITY);
+        let mut sleep = TimerSleep::new();
+        timer.add(now + Duration::from_millis(50), 1);
+        let first = timer.add(now + Duration::from_millis(500), 2);
+        timer.reschedule(first, now + Duration::from_millis(20));
+        let due = sleep.wait(&timer).await;
+        assert_eq!(now + Duration::from_millis(20), due);
+        assert!(Instant::now() >= due);
+        assert_eq!(Some(2), timer.take_next(due));
+        let due = sleep.wait(&timer).await;
+        assert_eq!(now + Duration::from_millis(50), due);
+        assert_eq!(Some(1), timer.take_next(due));
+    }
+
+    #[tokio::test]
+    async fn empty() {
+        let timer: Timer<()> = Timer::new(Instant::now(), GRANULARITY, CAPACITY);
+        let mut sleep = TimerSleep::new();
+        let waited = tokio::time::timeout(Duration::from_millis(20), sleep.wait(&timer)).await;
+        assert!(waited.is_err());
+    }
+}
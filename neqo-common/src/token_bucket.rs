@@ -0,0 +1,160 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A token bucket, for pacing packets or limiting how fast something is done.
+// Tokens are added at a steady rate, up to a limit, and taken as they are
+// used, so that short bursts are allowed but the rate over time isn't
+// exceeded.
+
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added each second.
+    rate: u64,
+    /// The most tokens that the bucket holds.
+    burst: u64,
+    /// What the bucket holds, in billionths of a token, so that time that is
+    /// too short to add a whole token isn't lost.
+    credit: u128,
+    /// When `credit` was last brought up to date.
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Make a bucket that adds `rate` tokens a second and holds up to `burst`.
+    /// It starts full.
+    #[must_use]
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            credit: u128::from(burst) * NANOS_PER_SEC,
+            updated: now,
+        }
+    }
+
+    #[must_use]
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    #[must_use]
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Change the rate.  Tokens up to `now` are added at the old rate.
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        // Time doesn't go backwards, but don't panic if an `Instant` is stale.
+        if now <= self.updated {
+            return;
+        }
+        let elapsed = now - self.updated;
+        let max = u128::from(self.burst) * NANOS_PER_SEC;
+        self.credit = (self.credit + elapsed.as_nanos() * u128::from(self.rate)).min(max);
+        self.updated = now;
+    }
+
+    /// The number of whole tokens that can be taken at `now`.
+    pub fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        // This is never more than `burst`.
+        u64::try_from(self.credit / NANOS_PER_SEC).unwrap_or(self.burst)
+    }
+
+    /// Take `n` tokens if there are that many, otherwise take none.
+    pub fn try_take(&mut self, n: u64, now: Instant) -> bool {
+        self.refill(now);
+        let need = u128::from(n) * NANOS_PER_SEC;
+        if self.credit >= need {
+            self.credit -= need;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// When there will be `n` tokens, if none are taken in the meantime.  This
+    /// is `now` if they are there already, and `None` if they never will be,
+    /// because `n` is more than the bucket holds or the rate is zero.
+    pub fn next_available(&mut self, n: u64, now: Instant) -> Option<Instant> {
+        self.refill(now);
+        let need = u128::from(n) * NANOS_PER_SEC;
+        if self.credit >= need {
+            return Some(now);
+        }
+        if n > self.burst || self.rate == 0 {
+            return None;
+        }
+        let rate = u128::from(self.rate);
+        // Round up, so that the tokens are there at the time given.
+        let wait = (need - self.credit - 1) / rate + 1;
+        now.checked_add(Duration::from_nanos(u64::try_from(wait).ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(10, 5, now);
+        assert_eq!(b.available(now), 5);
+        assert!(b.try_take(3, now));
+        assert!(!b.try_take(3, now));
+        assert!(b.try_take(2, now));
+        assert_eq!(b.available(now), 0);
+
+        // A long wait only fills the bucket.
+        let later = now + Duration::from_secs(10);
+        assert_eq!(b.available(later), 5);
+        assert_eq!(b.next_available(5, later), Some(later));
+        assert_eq!(b.next_available(6, later), None);
+    }
+
+    #[test]
+    fn steady() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(1000, 2, start);
+        assert!(b.try_take(2, start));
+
+        // A token every millisecond, even when asked for more often than that.
+        let mut taken = 0;
+        for i in 1..=10_000 {
+            let now = start + Duration::from_micros(i * 300);
+            if b.try_take(1, now) {
+                taken += 1;
+            }
+        }
+        assert_eq!(taken, 3000);
+    }
+
+    #[test]
+    fn next() {
+        let now = Instant::now();
+        let mut b = TokenBucket::new(3, 2, now);
+        assert!(b.try_take(2, now));
+        let t = b.next_available(1, now).unwrap();
+        assert_eq!(t, now + Duration::from_nanos(333_333_334));
+        let early = t.checked_sub(Duration::from_nanos(1)).unwrap();
+        assert!(!b.try_take(1, early));
+        assert!(b.try_take(1, t));
+
+        b.set_rate(0, t);
+        assert_eq!(b.next_available(1, t), None);
+    }
+}
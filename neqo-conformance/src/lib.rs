@@ -0,0 +1,18 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A conformance checker.  This runs a neqo client through scripted scenarios
+// against a server, which can be a neqo server in this process or any server
+// that can be reached over UDP, and reports whether the server meets each of
+// a list of requirements.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod requirement;
+mod target;
+
+pub use self::requirement::{Outcome, Requirement, REQUIREMENTS};
+pub use self::target::{LocalTarget, Target, UdpTarget};
@@ -0,0 +1,85 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_common::matches;
+use neqo_conformance::{LocalTarget, Outcome, Target, UdpTarget, REQUIREMENTS};
+use neqo_crypto::{init, init_db};
+
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "neqo-conformance",
+    about = "Checks a QUIC server against a list of requirements."
+)]
+struct Args {
+    /// The server to check, as host:port.  Without this, a neqo server in
+    /// this process is checked.
+    peer: Option<String>,
+
+    #[structopt(short = "n", long)]
+    /// The name of the server, if it isn't the host in `peer`.
+    server_name: Option<String>,
+
+    #[structopt(short = "a", long, default_value = "hq-24")]
+    /// ALPN labels to offer.
+    alpn: Vec<String>,
+
+    #[structopt(short = "d", long, default_value = "./db", parse(from_os_str))]
+    /// NSS database directory, for the neqo server.
+    db: PathBuf,
+    #[structopt(short = "k", long, default_value = "key")]
+    /// Name of keys from NSS database, for the neqo server.
+    key: Vec<String>,
+
+    #[structopt(short = "t", long)]
+    /// Requirements to check, which is all of them by default.
+    include: Vec<String>,
+}
+
+impl Args {
+    fn target(&self) -> Box<dyn Target> {
+        if let Some(peer) = &self.peer {
+            let addr = peer
+                .to_socket_addrs()
+                .expect("Remote address error")
+                .next()
+                .expect("No remote addresses");
+            let host = peer.rsplitn(2, ':').last().unwrap();
+            let name = self.server_name.as_ref().map_or(host, String::as_str);
+            Box::new(UdpTarget::new(name, addr, &self.alpn).expect("Unable to bind UDP socket"))
+        } else {
+            Box::new(LocalTarget::new(&self.key, &self.alpn).expect("Unable to create a server"))
+        }
+    }
+}
+
+fn main() {
+    let args = Args::from_args();
+    if args.peer.is_some() {
+        init();
+    } else {
+        init_db(args.db.clone());
+    }
+
+    let mut failed = false;
+    for r in REQUIREMENTS {
+        if !args.include.is_empty() && !args.include.iter().any(|i| i == r.id) {
+            continue;
+        }
+        let outcome = r.check(&mut *args.target());
+        println!("{} ({}): {}", r.id, r.description, outcome);
+        failed |= matches!(outcome, Outcome::Fail(..));
+    }
+    if failed {
+        process::exit(1);
+    }
+}
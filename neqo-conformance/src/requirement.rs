@@ -0,0 +1,308 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The requirements that are checked, and the scenarios that check them.
+
+use crate::Target;
+use neqo_common::{matches, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{
+    Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager, Output, State,
+    StreamType, QUIC_VERSION,
+};
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long any one exchange can take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// A reserved version, which no server supports.
+const GREASE_VERSION: u32 = 0x1a1a_1a1a;
+
+/// What checking a requirement found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The requirement couldn't be checked.
+    Skip(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "PASS"),
+            Self::Fail(why) => write!(f, "FAIL: {}", why),
+            Self::Skip(why) => write!(f, "SKIP: {}", why),
+        }
+    }
+}
+
+impl From<Result<(), String>> for Outcome {
+    fn from(r: Result<(), String>) -> Self {
+        match r {
+            Ok(()) => Self::Pass,
+            Err(why) => Self::Fail(why),
+        }
+    }
+}
+
+/// Something that a server needs to do, and a scenario that checks it.
+pub struct Requirement {
+    /// A short name, for choosing which requirements to check.
+    pub id: &'static str,
+    /// What the server needs to do.
+    pub description: &'static str,
+    scenario: fn(&mut dyn Target) -> Outcome,
+}
+
+impl Requirement {
+    /// Check this requirement.  Some of the scenarios change how the target
+    /// behaves, so each needs a new target.
+    pub fn check(&self, target: &mut dyn Target) -> Outcome {
+        (self.scenario)(target)
+    }
+}
+
+impl Debug for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Requirement {}", self.id)
+    }
+}
+
+pub const REQUIREMENTS: &[Requirement] = &[
+    Requirement {
+        id: "handshake",
+        description: "Complete a handshake.",
+        scenario: handshake,
+    },
+    Requirement {
+        id: "vn",
+        description: "Send Version Negotiation in response to an unknown version.",
+        scenario: version_negotiation,
+    },
+    Requirement {
+        id: "retry",
+        description: "Complete a handshake after sending Retry.",
+        scenario: retry,
+    },
+    Requirement {
+        id: "resumption",
+        description: "Resume a session using a ticket it sent.",
+        scenario: resumption,
+    },
+    Requirement {
+        id: "0rtt",
+        description: "Accept 0-RTT when resuming a session.",
+        scenario: zero_rtt,
+    },
+    Requirement {
+        id: "keyupdate",
+        description: "Accept a key update.",
+        scenario: unsupported,
+    },
+    Requirement {
+        id: "migration",
+        description: "Continue a connection after the client moves to a new address.",
+        scenario: unsupported,
+    },
+    Requirement {
+        id: "flowcontrol",
+        description: "Close the connection when the client sends more than it allows.",
+        scenario: unsupported,
+    },
+];
+
+fn client(target: &dyn Target) -> Connection {
+    Connection::new_client(
+        target.server_name(),
+        target.alpn(),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+        target.local_addr(),
+        target.remote_addr(),
+    )
+    .expect("can create a client")
+}
+
+/// Exchange datagrams until `done` returns true.  `rewrite` can change what
+/// the client sends.  This accepts any certificate, and fails if the
+/// connection closes first or `done` doesn't return true in time.
+fn exchange(
+    target: &mut dyn Target,
+    client: &mut Connection,
+    rewrite: &mut dyn FnMut(Datagram) -> Datagram,
+    done: &mut dyn FnMut(&Connection) -> bool,
+) -> Result<(), String> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+        if client.events().any(authentication_needed) {
+            client.authenticated(AuthenticationStatus::Ok, Instant::now());
+        }
+        if done(client) {
+            return Ok(());
+        }
+        if let State::Closed(e) = client.state() {
+            return Err(format!("the connection closed: {:?}", e));
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(String::from("timed out"));
+        }
+        let wait = match client.process(None, now) {
+            Output::Datagram(d) => {
+                target.send(rewrite(d));
+                continue;
+            }
+            Output::Callback(delay) => min(delay, deadline - now),
+            Output::None => deadline - now,
+        };
+        for d in target.recv(wait) {
+            client.process_input(d, Instant::now());
+        }
+    }
+}
+
+fn connect(target: &mut dyn Target, client: &mut Connection) -> Result<(), String> {
+    exchange(target, client, &mut |d| d, &mut |c| {
+        *c.state() == State::Connected
+    })
+}
+
+/// Close the connection, without waiting for the server.
+fn close(target: &mut dyn Target, mut client: Connection) {
+    client.close(Instant::now(), 0, "kthxbye!");
+    while let Output::Datagram(d) = client.process(None, Instant::now()) {
+        target.send(d);
+    }
+}
+
+fn handshake(target: &mut dyn Target) -> Outcome {
+    let mut client = client(target);
+    connect(target, &mut client).into()
+}
+
+fn version_negotiation(target: &mut dyn Target) -> Outcome {
+    let mut client = client(target);
+    let mut rewrite = |d: Datagram| {
+        let mut payload = d[..].to_vec();
+        if payload[0] & 0x80 != 0 {
+            payload[1..5].copy_from_slice(&GREASE_VERSION.to_be_bytes());
+        }
+        Datagram::new(d.source(), d.destination(), payload)
+    };
+    let mut closed = |c: &Connection| matches!(c.state(), State::Closed(..));
+    if let Err(why) = exchange(target, &mut client, &mut rewrite, &mut closed) {
+        return Outcome::Fail(why);
+    }
+    match client.state() {
+        State::Closed(ConnectionError::Transport(Error::VersionNegotiation)) => {
+            match client.vn_versions() {
+                Some(v) if v.contains(&QUIC_VERSION) => Outcome::Pass,
+                _ => Outcome::Fail(format!("version {:x} wasn't offered", QUIC_VERSION)),
+            }
+        }
+        st => Outcome::Fail(format!("the connection ended with {:?}", st)),
+    }
+}
+
+fn retry(target: &mut dyn Target) -> Outcome {
+    let required = target.require_retry();
+    let mut client = client(target);
+    if let Err(why) = connect(target, &mut client) {
+        return Outcome::Fail(why);
+    }
+    match (client.retry_token(), required) {
+        (Some(_), _) => Outcome::Pass,
+        (None, true) => Outcome::Fail(String::from("the server didn't send Retry")),
+        (None, false) => Outcome::Skip(String::from(
+            "the server didn't send Retry, and can't be asked to",
+        )),
+    }
+}
+
+/// Connect and wait for a session ticket, then return a token for resuming
+/// the session, if there is one.
+fn resumption_token(target: &mut dyn Target) -> Result<Option<Vec<u8>>, String> {
+    let mut client = client(target);
+    connect(target, &mut client)?;
+    let mut token = None;
+    let waited = exchange(target, &mut client, &mut |d| d, &mut |c| {
+        token = c.resumption_token();
+        token.is_some()
+    });
+    if let Err(why) = waited {
+        if *client.state() != State::Connected {
+            return Err(why);
+        }
+    }
+    close(target, client);
+    Ok(token)
+}
+
+/// Make a client that will resume a session with the server.
+fn resuming_client(target: &mut dyn Target) -> Result<Connection, Outcome> {
+    let token = match resumption_token(target) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            return Err(Outcome::Skip(String::from(
+                "the server didn't send a session ticket",
+            )))
+        }
+        Err(why) => return Err(Outcome::Fail(why)),
+    };
+    let mut client = client(target);
+    client
+        .set_resumption_token(Instant::now(), &token)
+        .expect("can use the token");
+    Ok(client)
+}
+
+fn resumption(target: &mut dyn Target) -> Outcome {
+    let mut client = match resuming_client(target) {
+        Ok(client) => client,
+        Err(outcome) => return outcome,
+    };
+    if let Err(why) = connect(target, &mut client) {
+        return Outcome::Fail(why);
+    }
+    if client.tls_info().map_or(false, |i| i.resumed()) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(String::from("the session wasn't resumed"))
+    }
+}
+
+fn zero_rtt(target: &mut dyn Target) -> Outcome {
+    let mut client = match resuming_client(target) {
+        Ok(client) => client,
+        Err(outcome) => return outcome,
+    };
+    // Send an HTTP/0.9 request in 0-RTT, so that there is something to send.
+    let stream_id = match client.stream_create(StreamType::BiDi) {
+        Ok(stream_id) => stream_id,
+        Err(_) => return Outcome::Skip(String::from("the ticket doesn't allow 0-RTT")),
+    };
+    client
+        .stream_send(stream_id, b"GET /\r\n")
+        .expect("can send a request");
+    if let Err(why) = connect(target, &mut client) {
+        return Outcome::Fail(why);
+    }
+    if client.tls_info().map_or(false, |i| i.early_data_accepted()) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(String::from("0-RTT was rejected"))
+    }
+}
+
+fn unsupported(_target: &mut dyn Target) -> Outcome {
+    Outcome::Skip(String::from("neqo can't do this yet"))
+}
@@ -0,0 +1,227 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The servers that can be checked.
+
+use neqo_common::{matches, qdebug, Datagram};
+use neqo_crypto::AntiReplay;
+use neqo_transport::server::Server;
+use neqo_transport::{ConnectionEvent, FixedConnectionIdManager, Output, Res, State};
+use neqo_udp::Socket;
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server to check, and a way to exchange datagrams with it.
+pub trait Target {
+    /// The name of the server.
+    fn server_name(&self) -> &str;
+    /// The ALPN values to offer.
+    fn alpn(&self) -> &[String];
+    /// The address that datagrams are sent from.
+    fn local_addr(&self) -> SocketAddr;
+    /// The address of the server.
+    fn remote_addr(&self) -> SocketAddr;
+    /// Send a datagram to the server.
+    fn send(&mut self, d: Datagram);
+    /// Wait up to `timeout` for datagrams from the server.  This returns
+    /// early if there are any.
+    fn recv(&mut self, timeout: Duration) -> Vec<Datagram>;
+    /// Ask the server to send Retry in response to new connections.  This
+    /// returns false if that isn't possible.
+    fn require_retry(&mut self) -> bool {
+        false
+    }
+}
+
+/// The anti-replay window for `LocalTarget`.
+const ANTI_REPLAY_WINDOW: Duration = Duration::from_millis(10);
+
+/// A neqo server in this process.  This sends a session ticket on every
+/// connection once the handshake is done, so that resumption can be checked.
+pub struct LocalTarget {
+    server: Server,
+    alpn: Vec<String>,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// What the server has sent that hasn't been received.
+    sent: Vec<Datagram>,
+    /// When the server next needs to run its timers.
+    wake: Option<Instant>,
+}
+
+impl LocalTarget {
+    /// Make a server using the certificates named `certs`, which will
+    /// negotiate one of `alpn`.  NSS needs to be initialized with a database
+    /// that has the certificates first.
+    pub fn new(certs: &[impl AsRef<str>], alpn: &[impl AsRef<str>]) -> Res<Self> {
+        let now = Instant::now();
+        // Start the anti-replay window in the past so that 0-RTT can be
+        // accepted straight away.
+        let anti_replay = AntiReplay::new(now - ANTI_REPLAY_WINDOW, ANTI_REPLAY_WINDOW, 1, 3)?;
+        let localhost = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        Ok(Self {
+            server: Server::new(
+                now,
+                certs,
+                alpn,
+                anti_replay,
+                Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+            )?,
+            alpn: alpn.iter().map(|a| String::from(a.as_ref())).collect(),
+            local: SocketAddr::new(localhost, 44433),
+            remote: SocketAddr::new(localhost, 443),
+            sent: Vec::new(),
+            wake: None,
+        })
+    }
+
+    /// Run the server until it has nothing more to send.
+    fn process(&mut self, mut d: Option<Datagram>, now: Instant) {
+        loop {
+            match self.server.process(d.take(), now) {
+                Output::Datagram(d) => self.sent.push(d),
+                Output::Callback(delay) => {
+                    self.wake = Some(now + delay);
+                    break;
+                }
+                Output::None => {
+                    self.wake = None;
+                    break;
+                }
+            }
+        }
+        if self.send_tickets(now) {
+            self.process(None, now);
+        }
+    }
+
+    /// Send a ticket on any connection that has just connected.
+    fn send_tickets(&mut self, now: Instant) -> bool {
+        let mut sent = false;
+        for mut c in self.server.active_connections() {
+            let mut conn = c.borrow_mut();
+            let connected = |e| matches!(e, ConnectionEvent::StateChange(State::Connected));
+            if conn.events().any(connected) {
+                qdebug!([*conn], "sending a session ticket");
+                sent |= conn.send_ticket(now, &[]).is_ok();
+            }
+        }
+        sent
+    }
+}
+
+impl Target for LocalTarget {
+    fn server_name(&self) -> &str {
+        "localhost"
+    }
+
+    fn alpn(&self) -> &[String] {
+        &self.alpn
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    fn send(&mut self, d: Datagram) {
+        self.process(Some(d), Instant::now());
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Vec<Datagram> {
+        if self.sent.is_empty() {
+            let now = Instant::now();
+            let until = self.wake.map_or(now + timeout, |w| w.min(now + timeout));
+            if until > now {
+                thread::sleep(until - now);
+            }
+            if self.wake.map_or(false, |w| w <= Instant::now()) {
+                self.process(None, Instant::now());
+            }
+        }
+        self.sent.split_off(0)
+    }
+
+    fn require_retry(&mut self) -> bool {
+        self.server.set_retry_required(true);
+        true
+    }
+}
+
+/// A server that is reached over UDP.
+pub struct UdpTarget {
+    socket: Socket,
+    server_name: String,
+    alpn: Vec<String>,
+    remote: SocketAddr,
+}
+
+impl UdpTarget {
+    /// Bind a socket to talk to the server at `remote`.
+    pub fn new(server_name: &str, remote: SocketAddr, alpn: &[String]) -> io::Result<Self> {
+        let bind = match remote {
+            SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
+            SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
+        };
+        let mut socket = Socket::bind(bind)?;
+        socket.connect(remote)?;
+        Ok(Self {
+            socket,
+            server_name: String::from(server_name),
+            alpn: alpn.to_vec(),
+            remote,
+        })
+    }
+}
+
+impl Target for UdpTarget {
+    fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn alpn(&self) -> &[String] {
+        &self.alpn
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr()
+    }
+
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    fn send(&mut self, d: Datagram) {
+        if let Err(e) = self.socket.send(&[d]) {
+            qdebug!("error sending to {}: {}", self.remote, e);
+        }
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Vec<Datagram> {
+        // A zero timeout would mean waiting forever.
+        let timeout = max(timeout, Duration::from_millis(1));
+        self.socket
+            .set_read_timeout(Some(timeout))
+            .expect("can set a read timeout");
+        match self.socket.recv() {
+            Ok(dgrams) => dgrams.into_iter().filter(|d| !d.is_empty()).collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Vec::new(),
+            Err(e) => {
+                qdebug!("error receiving from {}: {}", self.remote, e);
+                Vec::new()
+            }
+        }
+    }
+}
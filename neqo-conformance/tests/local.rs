@@ -0,0 +1,57 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_conformance::{LocalTarget, Outcome, REQUIREMENTS};
+use test_fixture::{fixture_init, DEFAULT_ALPN, DEFAULT_KEYS};
+
+/// Check a neqo server in this process.
+fn check(id: &str) -> Outcome {
+    fixture_init();
+    let r = REQUIREMENTS
+        .iter()
+        .find(|r| r.id == id)
+        .expect("a known requirement");
+    let mut target = LocalTarget::new(DEFAULT_KEYS, DEFAULT_ALPN).expect("create a server");
+    r.check(&mut target)
+}
+
+#[test]
+fn handshake() {
+    assert_eq!(check("handshake"), Outcome::Pass);
+}
+
+#[test]
+fn version_negotiation() {
+    assert_eq!(check("vn"), Outcome::Pass);
+}
+
+#[test]
+fn retry() {
+    assert_eq!(check("retry"), Outcome::Pass);
+}
+
+#[test]
+fn resumption() {
+    assert_eq!(check("resumption"), Outcome::Pass);
+}
+
+#[test]
+fn zero_rtt() {
+    assert_eq!(check("0rtt"), Outcome::Pass);
+}
+
+#[test]
+fn nothing_fails() {
+    for r in REQUIREMENTS {
+        fixture_init();
+        let mut target = LocalTarget::new(DEFAULT_KEYS, DEFAULT_ALPN).expect("create a server");
+        if let Outcome::Fail(why) = r.check(&mut target) {
+            panic!("{} failed: {}", r.id, why);
+        }
+    }
+}
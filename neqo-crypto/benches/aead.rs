@@ -0,0 +1,92 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use neqo_crypto::aead::Aead;
+use neqo_crypto::constants::*;
+use neqo_crypto::hkdf;
+use neqo_crypto::hp::{HpKey, SAMPLE_SIZE};
+use neqo_crypto::SymKey;
+use test_fixture::fixture_init;
+
+/// A typical size for a full packet.
+const PACKET_SIZE: usize = 1200;
+/// How many header protection masks are made at once for the batch case.
+const BATCH_SIZE: usize = 64;
+const AAD: &[u8] = &[
+    0x41, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0x00, 0x01, 0x02,
+];
+
+const CIPHERS: &[(&str, Cipher)] = &[
+    ("aes128gcm", TLS_AES_128_GCM_SHA256),
+    ("chacha20poly1305", TLS_CHACHA20_POLY1305_SHA256),
+];
+
+fn make_secret(cipher: Cipher) -> SymKey {
+    hkdf::import_key(TLS_VERSION_1_3, cipher, &[0x47; 32]).expect("make a secret")
+}
+
+fn aead(c: &mut Criterion) {
+    fixture_init();
+    for (name, cipher) in CIPHERS {
+        let secret = make_secret(*cipher);
+        let aead = Aead::new(TLS_VERSION_1_3, *cipher, &secret, "quic ").expect("make an AEAD");
+        let plaintext = vec![0x61; PACKET_SIZE];
+        let mut ciphertext = vec![0; PACKET_SIZE + aead.expansion()];
+
+        let mut group = c.benchmark_group(format!("aead/{}", name));
+        group.throughput(Throughput::Bytes(PACKET_SIZE as u64));
+        let mut pn = 0;
+        group.bench_function("seal", |b| {
+            b.iter(|| {
+                pn += 1;
+                aead.encrypt(pn, AAD, &plaintext, &mut ciphertext)
+                    .expect("encrypt")
+                    .len()
+            })
+        });
+
+        let sealed = aead
+            .encrypt(1, AAD, &plaintext, &mut ciphertext)
+            .expect("encrypt")
+            .to_vec();
+        let mut opened = vec![0; sealed.len()];
+        group.bench_function("open", |b| {
+            b.iter(|| {
+                aead.decrypt(1, AAD, &sealed, &mut opened)
+                    .expect("decrypt")
+                    .len()
+            })
+        });
+        group.finish();
+    }
+}
+
+fn hp(c: &mut Criterion) {
+    fixture_init();
+    for (name, cipher) in CIPHERS {
+        let secret = make_secret(*cipher);
+        let hp = HpKey::extract(TLS_VERSION_1_3, *cipher, &secret, "quic hp").expect("make a key");
+        let samples = (0..(SAMPLE_SIZE * BATCH_SIZE))
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+
+        let mut group = c.benchmark_group(format!("hp/{}", name));
+        group.bench_function("mask", |b| {
+            b.iter(|| hp.mask(black_box(&samples[..SAMPLE_SIZE])).expect("mask"))
+        });
+        group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+        group.bench_function("mask_batch", |b| {
+            b.iter(|| hp.mask_batch(black_box(&samples)).expect("mask"))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, aead, hp);
+criterion_main!(benches);
@@ -146,6 +146,16 @@ impl Aead {
     }
 }
 
+// SSL_AeadEncrypt and SSL_AeadDecrypt don't write to the SSLAeadContext.  They
+// hand its key to PK11_Encrypt and PK11_Decrypt (or, in NSS 3.52 and later,
+// its PK11Contexts to PK11_AEADOp), and those take the slot or context monitor
+// for anything that they change, which is how NSS makes PKCS#11 objects safe to
+// use from many threads at once.  The only other use of the context is
+// SSL_DestroyAead, from `Drop`, which can't overlap with anything else.  So an
+// `Aead` can move to, and be used from, any thread.
+unsafe impl Send for Aead {}
+unsafe impl Sync for Aead {}
+
 impl fmt::Debug for Aead {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "[AEAD Context]")
@@ -7,12 +7,19 @@
 use crate::agentio::{AgentIo, METHODS};
 pub use crate::agentio::{Record, RecordList};
 use crate::assert_initialized;
-use crate::auth::AuthenticationStatus;
+use crate::auth::{
+    AuthenticationStatus, CertificateVerdict, CertificateVerifier, PendingAuthentication,
+};
 pub use crate::cert::CertificateInfo;
 use crate::constants::*;
+use crate::ct::CtPolicy;
 use crate::err::{is_blocked, secstatus_to_res, Error, PRErrorCode, Res};
 use crate::ext::{ExtensionHandler, ExtensionTracker};
-use crate::p11;
+use crate::grease::{GreaseTracker, PeerGrease};
+use crate::hkdf;
+use crate::keylog::{KeyLog, KeyLogger};
+use crate::p11::{self, PK11SymKey};
+use crate::pem;
 use crate::prio;
 use crate::replay::AntiReplay;
 use crate::secrets::SecretHolder;
@@ -21,15 +28,33 @@ use crate::time::{PRTime, Time};
 
 use neqo_common::{qdebug, qinfo, qwarn};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::ffi::CString;
 use std::mem::{self, MaybeUninit};
 use std::ops::{Deref, DerefMut};
-use std::os::raw::{c_uint, c_void};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
 use std::pin::Pin;
 use std::ptr::{null, null_mut, NonNull};
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+experimental_api!(SSL_AddExternalPsk(
+    fd: *mut ssl::PRFileDesc,
+    psk: *mut PK11SymKey,
+    identity: *const u8,
+    identity_len: c_uint,
+    hash: ssl::SSLHashType::Type,
+));
+experimental_api!(SSL_DelegateCredential(
+    cert: *const p11::CERTCertificate,
+    cert_priv: *const p11::SECKEYPrivateKey,
+    dc_pub: *const p11::SECKEYPublicKey,
+    dc_cert_verify_alg: ssl::SSLSignatureScheme::Type,
+    dc_valid_for: u32,
+    now: PRTime,
+    out: *mut p11::SECItem,
+));
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum HandshakeState {
@@ -146,6 +171,7 @@ pub struct SecretAgentInfo {
     early_data: bool,
     alpn: Option<String>,
     signature_scheme: SignatureScheme,
+    delegated_credential: bool,
 }
 
 impl SecretAgentInfo {
@@ -167,6 +193,7 @@ impl SecretAgentInfo {
             early_data: info.earlyDataAccepted != 0,
             alpn: get_alpn(fd, false)?,
             signature_scheme: SignatureScheme::try_from(info.signatureScheme)?,
+            delegated_credential: info.peerDelegCred != 0,
         })
     }
     #[must_use]
@@ -197,6 +224,12 @@ impl SecretAgentInfo {
     pub fn signature_scheme(&self) -> SignatureScheme {
         self.signature_scheme
     }
+    /// Whether the peer authenticated with a delegated credential rather than
+    /// the key for its certificate.
+    #[must_use]
+    pub fn delegated_credential(&self) -> bool {
+        self.delegated_credential
+    }
 }
 
 /// `SecretAgent` holds the common parts of client and server.
@@ -211,6 +244,14 @@ pub struct SecretAgent {
 
     /// Records whether authentication of certificates is required.
     auth_required: Pin<Box<bool>>,
+    /// Checks the peer's certificates when authentication is required.
+    verifier: Option<Box<dyn CertificateVerifier>>,
+    /// Where the outcome of a pending authentication can be set.
+    pending_auth: Option<PendingAuthentication>,
+    /// Checks the SCTs that the peer presents, before `verifier` is asked.
+    ct_policy: Option<Box<dyn CtPolicy>>,
+    /// The name that a client asked for.
+    server_name: Option<String>,
     /// Records any fatal alert that is sent by the stack.
     alert: Pin<Box<Option<Alert>>>,
     /// The current time.
@@ -221,6 +262,12 @@ pub struct SecretAgent {
 
     /// Whether or not EndOfEarlyData should be suppressed.
     no_eoed: bool,
+    /// Whether this is the server end of a connection.
+    is_server: bool,
+    /// Records secrets, if anything is to be told about them.
+    key_log: Option<KeyLogger>,
+    /// Finds GREASE values in the ClientHello that a server receives.
+    peer_grease: GreaseTracker,
 }
 
 impl SecretAgent {
@@ -233,6 +280,10 @@ impl SecretAgent {
             state: HandshakeState::New,
 
             auth_required: Pin::new(Box::new(false)),
+            verifier: None,
+            pending_auth: None,
+            ct_policy: None,
+            server_name: None,
             alert: Pin::new(Box::new(None)),
             now: Pin::new(Box::new(0)),
 
@@ -240,6 +291,9 @@ impl SecretAgent {
             inf: None,
 
             no_eoed: false,
+            is_server: false,
+            key_log: None,
+            peer_grease: GreaseTracker::default(),
         };
         agent.create_fd()?;
         Ok(agent)
@@ -342,6 +396,7 @@ impl SecretAgent {
         }?;
 
         self.configure()?;
+        self.is_server = is_server;
         secstatus_to_res(unsafe { ssl::SSL_ResetHandshake(self.fd, is_server as ssl::PRBool) })
     }
 
@@ -351,6 +406,7 @@ impl SecretAgent {
         self.set_option(ssl::Opt::Locking, false)?;
         self.set_option(ssl::Opt::Tickets, false)?;
         self.set_option(ssl::Opt::OcspStapling, true)?;
+        self.set_option(ssl::Opt::SignedCertificateTimestamps, true)?;
         Ok(())
     }
 
@@ -393,6 +449,32 @@ impl SecretAgent {
         })
     }
 
+    /// Only use the signature schemes in `schemes`, in order of preference.
+    /// A client only accepts these from the server, and a server only signs
+    /// with these, so a server also needs a certificate that suits one.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Res<()> {
+        // SSLSignatureScheme is a different size to SignatureScheme, so copy one by one.
+        let scheme_vec: Vec<_> = schemes
+            .iter()
+            .map(|&s| ssl::SSLSignatureScheme::Type::from(s))
+            .collect();
+
+        secstatus_to_res(unsafe {
+            ssl::SSL_SignatureSchemePrefSet(
+                self.fd,
+                scheme_vec.as_ptr(),
+                c_uint::try_from(scheme_vec.len())?,
+            )
+        })
+    }
+
+    /// Send key shares for `count` groups as well as the first, so that a
+    /// server that only supports one of those doesn't need to send a
+    /// HelloRetryRequest.  The groups are taken in the order of `set_groups`.
+    pub fn send_additional_key_shares(&mut self, count: usize) -> Res<()> {
+        unsafe { ssl::SSL_SendAdditionalKeyShares(self.fd, c_uint::try_from(count)?) }
+    }
+
     /// Set TLS options.
     pub fn set_option(&mut self, opt: ssl::Opt, value: bool) -> Res<()> {
         secstatus_to_res(unsafe {
@@ -400,6 +482,23 @@ impl SecretAgent {
         })
     }
 
+    /// Send GREASE values (RFC 8701), or not.  A client puts them in the
+    /// cipher suites, extensions, groups, versions and more of its
+    /// ClientHello, and a server in the few places that it can.  NSS doesn't
+    /// send them unless this is enabled.  This has to be set before the
+    /// handshake starts.
+    pub fn set_grease(&mut self, enabled: bool) -> Res<()> {
+        self.set_option(ssl::Opt::Grease, enabled)
+    }
+
+    /// Where the ClientHello that a server received had GREASE values.  NSS
+    /// ignores them, as it should, so this is only for finding out what the
+    /// client sent.  A client always sees nothing here.
+    #[must_use]
+    pub fn peer_grease(&self) -> PeerGrease {
+        self.peer_grease.seen()
+    }
+
     /// Enable 0-RTT.
     pub fn enable_0rtt(&mut self) -> Res<()> {
         self.set_option(ssl::Opt::EarlyData, true)
@@ -454,6 +553,36 @@ impl SecretAgent {
         })
     }
 
+    /// Use an external pre-shared key, which both peers are given out of band,
+    /// called `identity`.  `hash` is the hash function that it is used with,
+    /// which limits the cipher suites to those that use the same hash.
+    ///
+    /// A handshake that uses the key doesn't need certificates, so a server
+    /// can be made without any, and a client won't stop in
+    /// `HandshakeState::AuthenticationPending`.  Only one key can be set.
+    pub fn set_external_psk(
+        &mut self,
+        identity: &[u8],
+        secret: &[u8],
+        hash: HashAlgorithm,
+    ) -> Res<()> {
+        let cipher = match hash {
+            TLS_HASH_SHA256 => TLS_AES_128_GCM_SHA256,
+            TLS_HASH_SHA384 => TLS_AES_256_GCM_SHA384,
+            _ => return Err(Error::UnsupportedHash),
+        };
+        let key = hkdf::import_key(TLS_VERSION_1_3, cipher, secret)?;
+        unsafe {
+            SSL_AddExternalPsk(
+                self.fd,
+                *key.deref(),
+                identity.as_ptr(),
+                c_uint::try_from(identity.len())?,
+                ssl::SSLHashType::Type::from(hash),
+            )
+        }
+    }
+
     /// Install an extension handler.
     ///
     /// This can be called multiple times with different values for `ext`.  The handler is provided as
@@ -469,6 +598,23 @@ impl SecretAgent {
         Ok(())
     }
 
+    /// Tell `log` about each secret as it becomes available, in a form that can
+    /// be written to an SSLKEYLOGFILE.  Set this before the handshake starts,
+    /// so that the client random can be found.
+    pub fn set_key_log(&mut self, log: Rc<RefCell<dyn KeyLog>>) {
+        self.key_log = Some(KeyLogger::new(log));
+    }
+
+    /// Look for a ClientHello in a handshake message, then log any new secrets.
+    fn log_keys(&mut self, msgs: &[&[u8]]) {
+        if let Some(kl) = &mut self.key_log {
+            for m in msgs {
+                kl.handshake_message(m);
+            }
+            kl.log_secrets(&self.secrets, self.is_server);
+        }
+    }
+
     // This function tracks whether handshake() or handshake_raw() was used
     // and prevents the other from being used.
     fn set_raw(&mut self, r: bool) -> Res<()> {
@@ -509,21 +655,144 @@ impl SecretAgent {
         CertificateInfo::new(self.fd)
     }
 
+    /// The DER-encoded certificates that the peer presented, starting with its
+    /// own.  This is empty if it didn't present any.
+    #[must_use]
+    pub fn peer_certificate_chain(&self) -> Vec<Vec<u8>> {
+        match self.peer_certificate() {
+            Some(mut certs) => (&mut certs).map(<[u8]>::to_vec).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Derive `len` bytes from the exporter secret (RFC 8446, Section 7.5),
+    /// for `label` and `context`.  This only works once the handshake is
+    /// complete.
+    pub fn export_keying_material(&self, label: &str, context: &[u8], len: usize) -> Res<Vec<u8>> {
+        let mut out = vec![0; len];
+        secstatus_to_res(unsafe {
+            ssl::SSL_ExportKeyingMaterial(
+                self.fd,
+                label.as_ptr() as *const c_char,
+                c_uint::try_from(label.len())?,
+                // TLS 1.3 doesn't distinguish between no context and an empty
+                // one.
+                true as PRBool,
+                context.as_ptr(),
+                c_uint::try_from(context.len())?,
+                out.as_mut_ptr(),
+                c_uint::try_from(len)?,
+            )
+        })?;
+        Ok(out)
+    }
+
     /// Return any fatal alert that the TLS stack might have sent.
     #[must_use]
     pub fn alert(&self) -> Option<&Alert> {
         (&*self.alert).as_ref()
     }
 
+    /// Have `verifier` check the peer's certificates.  If it decides straight
+    /// away, the handshake continues without stopping in
+    /// `HandshakeState::AuthenticationPending`; if it says that the outcome is
+    /// pending, `authenticated` is called once it is known.
+    pub fn set_certificate_verifier(&mut self, verifier: Box<dyn CertificateVerifier>) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Have `policy` check the signed certificate timestamps that the peer
+    /// presents when its certificates are authenticated.  If it isn't
+    /// satisfied, authentication fails with
+    /// `AuthenticationStatus::PolicyRejection`, which fails the handshake;
+    /// otherwise authentication carries on as it would have.
+    pub fn set_ct_policy(&mut self, policy: Box<dyn CtPolicy>) {
+        self.ct_policy = Some(policy);
+    }
+
+    /// Ask the CT policy and the verifier, if there are any, about the peer's
+    /// certificates.
+    fn verify_certificate(&mut self) -> HandshakeState {
+        if self.verifier.is_none() && self.ct_policy.is_none() {
+            return HandshakeState::AuthenticationPending;
+        }
+        let mut certs = CertificateInfo::new(self.fd);
+        let scts = match certs.as_mut() {
+            Some(c) => c.signed_certificate_timestamps(),
+            None => Vec::new(),
+        };
+        let chain: Vec<&[u8]> = match certs.as_mut() {
+            Some(c) => c.collect(),
+            None => Vec::new(),
+        };
+        if let Some(policy) = self.ct_policy.as_mut() {
+            if !policy.check(&scts, &chain) {
+                *self.auth_required = false;
+                return HandshakeState::Authenticated(AuthenticationStatus::PolicyRejection.into());
+            }
+        }
+        let verifier = match self.verifier.as_mut() {
+            Some(v) => v,
+            None => return HandshakeState::AuthenticationPending,
+        };
+        match verifier.verify(&chain, self.server_name.as_deref()) {
+            CertificateVerdict::Accept => {
+                *self.auth_required = false;
+                HandshakeState::Authenticated(AuthenticationStatus::Ok.into())
+            }
+            CertificateVerdict::Reject(status) => {
+                *self.auth_required = false;
+                HandshakeState::Authenticated(status.into())
+            }
+            CertificateVerdict::Pending => HandshakeState::AuthenticationPending,
+        }
+    }
+
     /// Call this function to mark the peer as authenticated.
     /// Only call this function if `handshake/handshake_raw` returns
     /// `HandshakeState::AuthenticationPending`, or it will panic.
     pub fn authenticated(&mut self, status: AuthenticationStatus) {
         assert_eq!(self.state, HandshakeState::AuthenticationPending);
         *self.auth_required = false;
+        // Anything still holding the old handle no longer counts.
+        self.pending_auth = None;
         self.state = HandshakeState::Authenticated(status.into());
     }
 
+    /// A handle for completing authentication later, in place of calling
+    /// `authenticated`, which can be kept while checks finish elsewhere.  This
+    /// is `None` unless the handshake is in
+    /// `HandshakeState::AuthenticationPending`, and until authentication
+    /// finishes, each call returns a handle to the same outcome.
+    pub fn pending_authentication(&mut self) -> Option<PendingAuthentication> {
+        if self.state == HandshakeState::AuthenticationPending {
+            let pending = self.pending_auth.get_or_insert_with(Default::default);
+            Some(pending.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Take the outcome of a pending authentication, if it has been set.  If
+    /// this returns `true`, the handshake needs to be driven again to carry
+    /// on; `handshake` and `handshake_raw` both do this themselves.
+    pub fn poll_authentication(&mut self) -> bool {
+        if self.state != HandshakeState::AuthenticationPending {
+            return false;
+        }
+        let outcome = self
+            .pending_auth
+            .as_ref()
+            .and_then(PendingAuthentication::outcome);
+        match outcome {
+            Some(status) => {
+                self.authenticated(status);
+                true
+            }
+            None => false,
+        }
+    }
+
     fn capture_error<T>(&mut self, res: Res<T>) -> Res<T> {
         if let Err(e) = &res {
             qwarn!([self], "error: {:?}", e);
@@ -535,7 +804,12 @@ impl SecretAgent {
     fn update_state(&mut self, res: Res<()>) -> Res<()> {
         self.state = if is_blocked(&res) {
             if *self.auth_required {
-                HandshakeState::AuthenticationPending
+                // Only ask the verifier the first time.
+                if self.state == HandshakeState::AuthenticationPending {
+                    HandshakeState::AuthenticationPending
+                } else {
+                    self.verify_certificate()
+                }
             } else {
                 HandshakeState::InProgress
             }
@@ -558,6 +832,7 @@ impl SecretAgent {
     pub fn handshake(&mut self, now: Instant, input: &[u8]) -> Res<Vec<u8>> {
         *self.now = Time::from(now).try_into()?;
         self.set_raw(false)?;
+        self.poll_authentication();
 
         let rv = {
             // Within this scope, _h maintains a mutable reference to self.io.
@@ -571,8 +846,43 @@ impl SecretAgent {
         };
         // Take before updating state so that we leave the output buffer empty
         // even if there is an error.
-        let output = self.io.take_output();
+        let mut output = self.io.take_output();
         self.update_state(secstatus_to_res(rv))?;
+
+        // If a verifier has already decided, carry on.
+        if let HandshakeState::Authenticated(err) = self.state {
+            let rv = {
+                let _h = self.io.wrap(&[]);
+                unsafe { ssl::SSL_AuthCertificateComplete(self.fd, err) }
+            };
+            output.extend(self.io.take_output());
+            self.update_state(secstatus_to_res(rv))?;
+        }
+
+        if self.is_server {
+            // Only whole records are looked at, each with a 5 byte header that
+            // ends with the length.
+            let mut records = input;
+            while records.len() >= 5 {
+                let len = 5 + usize::from(u16::from_be_bytes([records[3], records[4]]));
+                if len > records.len() {
+                    break;
+                }
+                if records[0] == 22 {
+                    self.peer_grease.handshake_data(&records[5..len]);
+                }
+                records = &records[len..];
+            }
+        }
+
+        // Handshake messages follow a 5 byte record header.
+        let msgs = [input, &output[..]]
+            .iter()
+            .copied()
+            .filter(|r| r.len() > 5 && r[0] == 22)
+            .map(|r| &r[5..])
+            .collect::<Vec<_>>();
+        self.log_keys(&msgs);
         Ok(output)
     }
 
@@ -604,6 +914,13 @@ impl SecretAgent {
         Ok(())
     }
 
+    fn complete_authentication(&mut self, err: PRErrorCode) -> Res<()> {
+        let result = secstatus_to_res(unsafe { ssl::SSL_AuthCertificateComplete(self.fd, err) });
+        qdebug!([self], "SSL_AuthCertificateComplete: {:?}", result);
+        // This should return SECSuccess, so don't use update_state().
+        self.capture_error(result)
+    }
+
     // Drive the TLS handshake, but get the raw content of records, not
     // protected records as bytes. This function is incompatible with
     // handshake(); use either this or handshake() exclusively.
@@ -613,16 +930,19 @@ impl SecretAgent {
     pub fn handshake_raw(&mut self, now: Instant, input: Option<Record>) -> Res<RecordList> {
         *self.now = Time::from(now).try_into()?;
         let mut records = self.setup_raw()?;
+        self.poll_authentication();
 
         // Fire off any authentication we might need to complete.
-        if let HandshakeState::Authenticated(ref err) = self.state {
-            let result =
-                secstatus_to_res(unsafe { ssl::SSL_AuthCertificateComplete(self.fd, *err) });
-            qdebug!([self], "SSL_AuthCertificateComplete: {:?}", result);
-            // This should return SECSuccess, so don't use update_state().
-            self.capture_error(result)?;
+        if let HandshakeState::Authenticated(err) = self.state {
+            self.complete_authentication(err)?;
         }
 
+        // A server sees the ClientHello in what is fed in.
+        let client_hello = input
+            .as_ref()
+            .filter(|r| r.epoch == 0 && r.ct == 22)
+            .map(|r| r.data.clone());
+
         // Feed in any records.
         if let Some(rec) = input {
             if rec.epoch == 2 {
@@ -635,10 +955,30 @@ impl SecretAgent {
         let rv = secstatus_to_res(unsafe { ssl::SSL_ForceHandshake(self.fd) });
         self.update_state(rv)?;
 
+        // If a verifier has already decided, carry on.
+        if let HandshakeState::Authenticated(err) = self.state {
+            self.complete_authentication(err)?;
+            let rv = secstatus_to_res(unsafe { ssl::SSL_ForceHandshake(self.fd) });
+            self.update_state(rv)?;
+        }
+
         if self.no_eoed {
             records.remove_eoed();
         }
 
+        if let Some(ch) = &client_hello {
+            if self.is_server {
+                self.peer_grease.handshake_data(ch);
+            }
+        }
+        let mut msgs = records
+            .iter()
+            .filter(|r| r.epoch == 0 && r.ct == 22)
+            .map(|r| &r.data[..])
+            .collect::<Vec<_>>();
+        msgs.extend(client_hello.as_ref().map(|d| &d[..]));
+        self.log_keys(&msgs);
+
         Ok(*Pin::into_inner(records))
     }
 
@@ -663,13 +1003,42 @@ impl ::std::fmt::Display for SecretAgent {
     }
 }
 
+/// A resumption token, as the server provided it, with the time after which
+/// the server won't accept it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumptionToken {
+    token: Vec<u8>,
+    expiration_time: Instant,
+}
+
+impl AsRef<[u8]> for ResumptionToken {
+    fn as_ref(&self) -> &[u8] {
+        &self.token
+    }
+}
+
+impl ResumptionToken {
+    #[must_use]
+    pub fn new(token: Vec<u8>, expiration_time: Instant) -> Self {
+        Self {
+            token,
+            expiration_time,
+        }
+    }
+
+    #[must_use]
+    pub fn expiration_time(&self) -> Instant {
+        self.expiration_time
+    }
+}
+
 /// A TLS Client.
 #[derive(Debug)]
 pub struct Client {
     agent: SecretAgent,
 
     /// Records the last resumption token.
-    resumption: Pin<Box<Option<Vec<u8>>>>,
+    resumption: Pin<Box<Option<ResumptionToken>>>,
 }
 
 impl Client {
@@ -677,6 +1046,7 @@ impl Client {
         let mut agent = SecretAgent::new()?;
         let url = CString::new(server_name)?;
         secstatus_to_res(unsafe { ssl::SSL_SetURL(agent.fd, url.as_ptr()) })?;
+        agent.server_name = Some(server_name.to_string());
         agent.ready(false)?;
         let mut client = Self {
             agent,
@@ -692,12 +1062,28 @@ impl Client {
         len: c_uint,
         arg: *mut c_void,
     ) -> ssl::SECStatus {
-        let resumption_ptr = arg as *mut Option<Vec<u8>>;
+        let resumption_ptr = arg as *mut Option<ResumptionToken>;
         let resumption = resumption_ptr.as_mut().unwrap();
         let mut v = Vec::with_capacity(len as usize);
         v.extend_from_slice(std::slice::from_raw_parts(token, len as usize));
+
+        let mut info: MaybeUninit<ssl::SSLResumptionTokenInfo> = MaybeUninit::uninit();
+        let info_len = c_uint::try_from(mem::size_of::<ssl::SSLResumptionTokenInfo>()).unwrap();
+        if ssl::SSL_GetResumptionTokenInfo(token, len, info.as_mut_ptr(), info_len).is_err() {
+            return ssl::SECFailure;
+        }
+        let mut info = info.assume_init();
+        let expiration = Time::try_from(info.expirationTime);
+        if ssl::SSL_DestroyResumptionTokenInfo(&mut info).is_err() {
+            return ssl::SECFailure;
+        }
+        let expiration_time = match expiration {
+            Ok(t) => t.into(),
+            Err(_) => return ssl::SECFailure,
+        };
+
         qdebug!([format!("{:p}", fd)], "Got resumption token");
-        *resumption = Some(v);
+        *resumption = Some(ResumptionToken::new(v, expiration_time));
         ssl::SECSuccess
     }
 
@@ -706,14 +1092,19 @@ impl Client {
             ssl::SSL_SetResumptionTokenCallback(
                 self.fd,
                 Some(Self::resumption_token_cb),
-                &mut *self.resumption as *mut Option<Vec<u8>> as *mut c_void,
+                &mut *self.resumption as *mut Option<ResumptionToken> as *mut c_void,
             )
         }
     }
 
+    /// Accept delegated credentials from servers.
+    pub fn enable_delegated_credentials(&mut self) -> Res<()> {
+        self.set_option(ssl::Opt::DelegatedCredentials, true)
+    }
+
     /// Return the resumption token.
     #[must_use]
-    pub fn resumption_token(&self) -> Option<&Vec<u8>> {
+    pub fn resumption_token(&self) -> Option<&ResumptionToken> {
         (*self.resumption).as_ref()
     }
 
@@ -776,11 +1167,410 @@ impl ZeroRttCheckState {
     }
 }
 
+/// Chooses an application protocol for a server, in place of the list that
+/// `set_alpn` sets.
+pub trait AlpnSelector: std::fmt::Debug {
+    /// `server_name` is the name that the client sent with SNI, if it sent one,
+    /// and `offered` is what the client offers, most preferred first.  Any
+    /// value that isn't UTF-8 is left out.  Return one of `offered`, or `None`
+    /// if none of them will do, in which case no protocol is negotiated.
+    fn select(&self, server_name: Option<&str>, offered: &[&str]) -> Option<String>;
+}
+
+impl<T: AlpnSelector + ?Sized> AlpnSelector for Rc<T> {
+    fn select(&self, server_name: Option<&str>, offered: &[&str]) -> Option<String> {
+        (**self).select(server_name, offered)
+    }
+}
+
+/// Chooses the certificate for a server from the name that the client asks
+/// for, so that one server can have many names.
+pub trait CertificateSelector: std::fmt::Debug {
+    /// `server_name` is the name that the client sent with SNI, in lower case.
+    /// Return `None` to use the certificates that the server was made with.
+    fn select(&self, server_name: &str) -> Option<ServerCertificate>;
+}
+
+impl<T: CertificateSelector + ?Sized> CertificateSelector for Rc<T> {
+    fn select(&self, server_name: &str) -> Option<ServerCertificate> {
+        (**self).select(server_name)
+    }
+}
+
+/// What depends on the name that the client asks for.  NSS only has the one
+/// SNI callback, so the ALPN callback uses this too.
+#[derive(Debug, Default)]
+struct SniState {
+    /// The name from the ClientHello, which NSS hands over before it asks for
+    /// the protocol.
+    server_name: Option<String>,
+    alpn_selector: Option<Box<dyn AlpnSelector>>,
+    /// Certificates by name, in lower case.
+    certificates: HashMap<String, ServerCertificate>,
+    certificate_selector: Option<Box<dyn CertificateSelector>>,
+}
+
+impl SniState {
+    /// Configure the certificate for `name` on `fd`, if there is one, looking
+    /// for the name itself, then a wildcard, then asking the selector.
+    fn configure_certificate(&self, fd: *mut ssl::PRFileDesc, name: &str) -> Res<bool> {
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.find('.').map(|i| format!("*{}", &name[i..]));
+        let found = self
+            .certificates
+            .get(&name)
+            .or_else(|| wildcard.and_then(|w| self.certificates.get(&w)));
+        if let Some(c) = found {
+            c.configure(fd)?;
+            return Ok(true);
+        }
+        if let Some(c) = self
+            .certificate_selector
+            .as_ref()
+            .and_then(|s| s.select(&name))
+        {
+            c.configure(fd)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// The key usages that are allowed for a private key that is imported.
+const KU_ALL: c_uint = 0xff;
+
+fn sec_item(v: &[u8]) -> Res<p11::SECItem> {
+    Ok(p11::SECItem {
+        type_: p11::SECItemType::siBuffer,
+        // NSS copies what it is given, so it doesn't matter that this isn't
+        // really mutable.
+        data: v.as_ptr() as *mut u8,
+        len: c_uint::try_from(v.len())?,
+    })
+}
+
+/// Import a DER-encoded PKCS#8 PrivateKeyInfo as a session object.
+fn import_private_key(der: &[u8]) -> Res<p11::PrivateKey> {
+    let slot = match NonNull::new(unsafe { p11::PK11_GetInternalSlot() }) {
+        Some(p) => p11::Slot::new(p),
+        None => return Err(Error::InternalError),
+    };
+    let mut item = sec_item(der)?;
+    let mut key_ptr = null_mut();
+    secstatus_to_res(unsafe {
+        p11::PK11_ImportDERPrivateKeyInfoAndReturnKey(
+            *slot.deref(),
+            &mut item,
+            null_mut(),
+            null_mut(),
+            p11::PRBool::from(false),
+            p11::PRBool::from(true),
+            KU_ALL,
+            &mut key_ptr,
+            null_mut(),
+        )
+    })
+    .map_err(|_| Error::CertificateLoading)?;
+    match NonNull::new(key_ptr) {
+        Some(p) => Ok(p11::PrivateKey::new(p)),
+        None => Err(Error::CertificateLoading),
+    }
+}
+
+/// Make a delegated credential, which lets whoever has `dc_key` use `cert`
+/// for `valid_for`, starting at `now`.
+fn delegate_credential(
+    cert: &p11::Certificate,
+    cert_key: &p11::PrivateKey,
+    dc_key: &p11::PrivateKey,
+    scheme: SignatureScheme,
+    valid_for: Duration,
+    now: Instant,
+) -> Res<Vec<u8>> {
+    let dc_pub = match NonNull::new(unsafe { p11::SECKEY_ConvertToPublicKey(*dc_key.deref()) }) {
+        Some(p) => p11::PublicKey::new(p),
+        None => return Err(Error::InternalError),
+    };
+    let mut out = p11::SECItem {
+        type_: p11::SECItemType::siBuffer,
+        data: null_mut(),
+        len: 0,
+    };
+    unsafe {
+        SSL_DelegateCredential(
+            *cert.deref(),
+            *cert_key.deref(),
+            *dc_pub.deref(),
+            ssl::SSLSignatureScheme::Type::from(scheme),
+            u32::try_from(valid_for.as_secs())?,
+            Time::from(now).try_into()?,
+            &mut out,
+        )
+    }?;
+    let dc = unsafe { std::slice::from_raw_parts(out.data, out.len as usize) }.to_vec();
+    unsafe { p11::SECITEM_FreeItem(&mut out, p11::PRBool::from(false)) };
+    Ok(dc)
+}
+
+/// What a server is configured with for a certificate as well as its key.
+#[derive(Debug, Default)]
+struct CertificateExtras {
+    ocsp_responses: Vec<Vec<u8>>,
+    signed_cert_timestamps: Option<Vec<u8>>,
+    /// A delegated credential and its private key.
+    delegated_credential: Option<(Vec<u8>, p11::PrivateKey)>,
+}
+
+impl CertificateExtras {
+    fn configure(
+        &self,
+        fd: *mut ssl::PRFileDesc,
+        cert: &p11::Certificate,
+        key: &p11::PrivateKey,
+    ) -> Res<()> {
+        let mut items = self
+            .ocsp_responses
+            .iter()
+            .map(|r| sec_item(r))
+            .collect::<Res<Vec<_>>>()?;
+        let responses = p11::SECItemArray {
+            items: items.as_mut_ptr(),
+            len: c_uint::try_from(items.len())?,
+        };
+        let scts = match &self.signed_cert_timestamps {
+            Some(s) => Some(sec_item(s)?),
+            None => None,
+        };
+        let dc = match &self.delegated_credential {
+            Some((dc, dc_key)) => Some((sec_item(dc)?, **dc_key)),
+            None => None,
+        };
+        let data = ssl::SSLExtraServerCertData {
+            // Use the type of the certificate.
+            authType: ssl::SSLAuthType::ssl_auth_null,
+            certChain: null(),
+            stapledOCSPResponses: if items.is_empty() {
+                null()
+            } else {
+                &responses as *const p11::SECItemArray as *const ssl::SECItemArray
+            },
+            signedCertTimestamps: match &scts {
+                Some(s) => s as *const p11::SECItem as *const ssl::SECItem,
+                None => null(),
+            },
+            delegCred: match &dc {
+                Some((item, _)) => item as *const p11::SECItem as *const ssl::SECItem,
+                None => null(),
+            },
+            delegCredPrivKey: match &dc {
+                Some((_, dc_key)) => *dc_key as *const ssl::SECKEYPrivateKey,
+                None => null(),
+            },
+        };
+        secstatus_to_res(unsafe {
+            ssl::SSL_ConfigServerCert(
+                fd,
+                *cert.deref(),
+                *key.deref(),
+                &data,
+                c_uint::try_from(mem::size_of::<ssl::SSLExtraServerCertData>())?,
+            )
+        })
+    }
+}
+
+/// Where the private key for a `ServerCertificate` comes from.
+#[derive(Debug, Clone)]
+enum CertificateKey {
+    /// A DER-encoded PKCS#8 PrivateKeyInfo.
+    Der(Vec<u8>),
+    /// A key on a PKCS#11 token, by the name of the token and the `CKA_ID` of
+    /// the key.
+    Token { token: String, id: Vec<u8> },
+}
+
+impl CertificateKey {
+    fn import(&self) -> Res<p11::PrivateKey> {
+        match self {
+            Self::Der(der) => import_private_key(der),
+            Self::Token { token, id } => {
+                let name = CString::new(token.as_str())?;
+                let slot = match NonNull::new(unsafe { p11::PK11_FindSlotByName(name.as_ptr()) }) {
+                    Some(p) => p11::Slot::new(p),
+                    None => return Err(Error::CertificateLoading),
+                };
+                let mut item = sec_item(id)?;
+                let ptr = unsafe { p11::PK11_FindKeyByKeyID(*slot.deref(), &mut item, null_mut()) };
+                match NonNull::new(ptr) {
+                    Some(p) => Ok(p11::PrivateKey::new(p)),
+                    None => Err(Error::CertificateLoading),
+                }
+            }
+        }
+    }
+}
+
+/// A certificate chain and the private key for the first certificate in it,
+/// for a server that doesn't keep them in an NSS database.
+///
+/// Signing is synchronous, wherever the key is: NSS signs the handshake while
+/// `handshake` runs, and has no hook for doing that asynchronously.  A key on
+/// a slow token therefore blocks the connection, and anything else on the
+/// same thread, until the token answers.
+#[derive(Debug, Clone)]
+pub struct ServerCertificate {
+    chain: Vec<Vec<u8>>,
+    key: CertificateKey,
+    /// A delegated credential and its private key, both DER-encoded.
+    delegated_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ServerCertificate {
+    /// `chain` holds DER-encoded certificates, starting with the one for the
+    /// server and followed by any intermediates.  `key` is a DER-encoded
+    /// PKCS#8 PrivateKeyInfo.
+    /// # Errors
+    /// If `chain` is empty.
+    pub fn from_der(chain: &[impl AsRef<[u8]>], key: &[u8]) -> Res<Self> {
+        Self::with_key(chain, CertificateKey::Der(key.to_vec()))
+    }
+
+    /// The same as `from_der`, but the private key stays on a PKCS#11 token,
+    /// such as a hardware security module, and NSS asks the token to sign
+    /// with it.  `token` is the name of the token and `key_id` is the `CKA_ID`
+    /// of the key.  The module for the token has to be loaded already, as it
+    /// is if it is in the NSS database, and the token has to be logged into if
+    /// it needs that.  The key is found when the server is made.
+    ///
+    /// The token signs while `handshake` runs, as described above, so a server
+    /// that uses a slow token should process those connections on a thread
+    /// where the wait doesn't hold up others.
+    /// # Errors
+    /// If `chain` is empty.
+    pub fn from_token(chain: &[impl AsRef<[u8]>], token: &str, key_id: &[u8]) -> Res<Self> {
+        Self::with_key(
+            chain,
+            CertificateKey::Token {
+                token: token.to_string(),
+                id: key_id.to_vec(),
+            },
+        )
+    }
+
+    fn with_key(chain: &[impl AsRef<[u8]>], key: CertificateKey) -> Res<Self> {
+        if chain.is_empty() {
+            return Err(Error::CertificateLoading);
+        }
+        Ok(Self {
+            chain: chain.iter().map(|c| c.as_ref().to_vec()).collect(),
+            key,
+            delegated_credential: None,
+        })
+    }
+
+    /// The same as `from_der`, but from PEM: "CERTIFICATE" blocks in `chain`,
+    /// in the same order, and one "PRIVATE KEY" block in `key`.
+    /// # Errors
+    /// If the PEM is malformed, or doesn't have the blocks that it should.
+    pub fn from_pem(chain: &str, key: &str) -> Res<Self> {
+        let mut keys = pem::decode(key, "PRIVATE KEY")?;
+        if keys.len() != 1 {
+            return Err(Error::CertificateLoading);
+        }
+        Self::from_der(&pem::decode(chain, "CERTIFICATE")?, &keys.remove(0))
+    }
+
+    /// Offer a delegated credential, such as one from `delegate_credential`,
+    /// to clients that support them.  `key` is the DER-encoded PKCS#8 private
+    /// key for the credential.
+    #[must_use]
+    pub fn with_delegated_credential(mut self, credential: &[u8], key: &[u8]) -> Self {
+        self.delegated_credential = Some((credential.to_vec(), key.to_vec()));
+        self
+    }
+
+    /// Make a delegated credential for this certificate, which lets whoever
+    /// has `key`, a DER-encoded PKCS#8 private key, sign handshakes with
+    /// `scheme` for `valid_for` from `now`.  The certificate needs to allow
+    /// this, with the DelegationUsage extension, or clients won't accept it.
+    /// A credential can't be valid for more than seven days.
+    /// # Errors
+    /// If a key or the certificate can't be imported, or NSS can't make the
+    /// credential.
+    pub fn delegate_credential(
+        &self,
+        key: &[u8],
+        scheme: SignatureScheme,
+        valid_for: Duration,
+        now: Instant,
+    ) -> Res<Vec<u8>> {
+        let (chain, cert_key) = self.import()?;
+        let dc_key = import_private_key(key)?;
+        delegate_credential(&chain[0], &cert_key, &dc_key, scheme, valid_for, now)
+    }
+
+    /// Import the chain as temporary certificates, so that NSS can find the
+    /// intermediates when it builds the chain that it sends, and the key as a
+    /// session object, unless it is on a token.  Nothing is saved to the NSS
+    /// database, if there is one.
+    fn import(&self) -> Res<(Vec<p11::Certificate>, p11::PrivateKey)> {
+        let db = unsafe { p11::CERT_GetDefaultCertDB() };
+        let chain = self
+            .chain
+            .iter()
+            .map(|der| {
+                let mut item = sec_item(der)?;
+                let ptr = unsafe {
+                    p11::CERT_NewTempCertificate(
+                        db,
+                        &mut item,
+                        null_mut(),
+                        p11::PRBool::from(false),
+                        p11::PRBool::from(true),
+                    )
+                };
+                match NonNull::new(ptr) {
+                    Some(p) => Ok(p11::Certificate::new(p)),
+                    None => Err(Error::CertificateLoading),
+                }
+            })
+            .collect::<Res<Vec<_>>>()?;
+        Ok((chain, self.key.import()?))
+    }
+
+    /// Configure this on `fd`, in place of any certificate of the same type.
+    fn configure(&self, fd: *mut ssl::PRFileDesc) -> Res<()> {
+        // The intermediates only need to live until this is configured.
+        let (chain, key) = self.import()?;
+        self.extras()?.configure(fd, &chain[0], &key)
+    }
+
+    fn extras(&self) -> Res<CertificateExtras> {
+        let delegated_credential = match &self.delegated_credential {
+            Some((dc, key)) => Some((dc.clone(), import_private_key(key)?)),
+            None => None,
+        };
+        Ok(CertificateExtras {
+            delegated_credential,
+            ..CertificateExtras::default()
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Server {
     agent: SecretAgent,
     /// This holds the HRR callback context.
     zero_rtt_check: Option<Pin<Box<ZeroRttCheckState>>>,
+    /// What has been added to certificates from the NSS database, by name, so
+    /// that setting one thing doesn't lose the others.
+    extras: HashMap<String, CertificateExtras>,
+    /// The most early data that a ticket allows, if 0-RTT is enabled.
+    max_early_data: Option<u32>,
+    /// Whether tickets allow 0-RTT, so that `max_early_data` is used.
+    tickets_allow_0rtt: bool,
+    /// This holds the SNI and ALPN callback context.
+    sni: Option<Pin<Box<SniState>>>,
 }
 
 impl Server {
@@ -788,31 +1578,116 @@ impl Server {
         let mut agent = SecretAgent::new()?;
 
         for n in certificates {
-            let c = CString::new(n.as_ref())?;
-            let cert = match NonNull::new(unsafe {
-                p11::PK11_FindCertFromNickname(c.as_ptr(), null_mut())
-            }) {
-                None => return Err(Error::CertificateLoading),
-                Some(ptr) => p11::Certificate::new(ptr),
-            };
-            let key = match NonNull::new(unsafe {
-                p11::PK11_FindKeyByAnyCert(*cert.deref(), null_mut())
-            }) {
-                None => return Err(Error::CertificateLoading),
-                Some(ptr) => p11::PrivateKey::new(ptr),
-            };
+            let (cert, key) = Self::find_certificate(n.as_ref())?;
             secstatus_to_res(unsafe {
                 ssl::SSL_ConfigServerCert(agent.fd, *cert.deref(), *key.deref(), null(), 0)
             })?;
         }
 
+        Self::from_agent(agent)
+    }
+
+    /// Make a server that uses the certificates and keys that it is given,
+    /// rather than finding them in the NSS database.  NSS still needs to be
+    /// initialized, but `init` is enough.
+    /// # Errors
+    /// If a certificate or key can't be imported.
+    pub fn with_certificates(certificates: &[ServerCertificate]) -> Res<Self> {
+        let agent = SecretAgent::new()?;
+
+        for c in certificates {
+            c.configure(agent.fd)?;
+        }
+
+        Self::from_agent(agent)
+    }
+
+    fn from_agent(mut agent: SecretAgent) -> Res<Self> {
         agent.ready(true)?;
         Ok(Self {
             agent,
             zero_rtt_check: None,
+            extras: HashMap::new(),
+            max_early_data: None,
+            tickets_allow_0rtt: true,
+            sni: None,
+        })
+    }
+
+    fn find_certificate(name: &str) -> Res<(p11::Certificate, p11::PrivateKey)> {
+        let c = CString::new(name)?;
+        let cert =
+            match NonNull::new(unsafe { p11::PK11_FindCertFromNickname(c.as_ptr(), null_mut()) }) {
+                None => return Err(Error::CertificateLoading),
+                Some(ptr) => p11::Certificate::new(ptr),
+            };
+        let key =
+            match NonNull::new(unsafe { p11::PK11_FindKeyByAnyCert(*cert.deref(), null_mut()) }) {
+                None => return Err(Error::CertificateLoading),
+                Some(ptr) => p11::PrivateKey::new(ptr),
+            };
+        Ok((cert, key))
+    }
+
+    /// Change what is configured with the certificate called `certificate`.
+    fn update_extras(
+        &mut self,
+        certificate: &str,
+        f: impl FnOnce(&mut CertificateExtras),
+    ) -> Res<()> {
+        let (cert, key) = Self::find_certificate(certificate)?;
+        let mut extras = self.extras.remove(certificate).unwrap_or_default();
+        f(&mut extras);
+        let res = extras.configure(self.agent.fd, &cert, &key);
+        self.extras.insert(certificate.to_string(), extras);
+        res
+    }
+
+    /// Staple OCSP responses and signed certificate timestamps to the
+    /// certificate called `certificate`, which replaces any that were stapled
+    /// before.  These are only sent to a client that asks for them.
+    pub fn set_stapled_data(
+        &mut self,
+        certificate: &str,
+        ocsp_responses: &[impl AsRef<[u8]>],
+        signed_cert_timestamps: Option<&[u8]>,
+    ) -> Res<()> {
+        let ocsp_responses = ocsp_responses.iter().map(|r| r.as_ref().to_vec()).collect();
+        self.update_extras(certificate, |e| {
+            e.ocsp_responses = ocsp_responses;
+            e.signed_cert_timestamps = signed_cert_timestamps.map(<[u8]>::to_vec);
+        })
+    }
+
+    /// Offer a delegated credential for the certificate called `certificate`
+    /// to clients that support them, in place of any that was offered before.
+    /// `key` is the DER-encoded PKCS#8 private key for the credential.
+    pub fn set_delegated_credential(
+        &mut self,
+        certificate: &str,
+        credential: &[u8],
+        key: &[u8],
+    ) -> Res<()> {
+        let dc_key = import_private_key(key)?;
+        self.update_extras(certificate, |e| {
+            e.delegated_credential = Some((credential.to_vec(), dc_key));
         })
     }
 
+    /// Make a delegated credential for the certificate called `certificate`.
+    /// This is the same as `ServerCertificate::delegate_credential`.
+    pub fn delegate_credential(
+        certificate: &str,
+        key: &[u8],
+        scheme: SignatureScheme,
+        valid_for: Duration,
+        now: Instant,
+    ) -> Res<Vec<u8>> {
+        let (cert, cert_key) = Self::find_certificate(certificate)?;
+        let dc_key = import_private_key(key)?;
+        delegate_credential(&cert, &cert_key, &dc_key, scheme, valid_for, now)
+    }
+
     unsafe extern "C" fn hello_retry_cb(
         first_hello: PRBool,
         client_token: *const u8,
@@ -851,6 +1726,137 @@ impl Server {
         }
     }
 
+    unsafe extern "C" fn sni_cb(
+        fd: *mut ssl::PRFileDesc,
+        names: *const ssl::SECItem,
+        count: c_uint,
+        arg: *mut c_void,
+    ) -> c_int {
+        let p = arg as *mut SniState;
+        let sni = p.as_mut().unwrap();
+        sni.server_name = if names.is_null() || count == 0 {
+            None
+        } else {
+            let name = std::slice::from_raw_parts((*names).data, (*names).len as usize);
+            String::from_utf8(name.to_vec()).ok()
+        };
+        let name = match &sni.server_name {
+            Some(n) => n,
+            None => return ssl::SSL_SNI_CURRENT_CONFIG_IS_USED,
+        };
+        match sni.configure_certificate(fd, name) {
+            // The first name is the one that was used, which tells the client
+            // that it was.
+            Ok(true) => 0,
+            Ok(false) => ssl::SSL_SNI_CURRENT_CONFIG_IS_USED,
+            Err(e) => {
+                qwarn!("Unable to use the certificate for {}: {:?}", name, e);
+                ssl::SSL_SNI_SEND_ALERT
+            }
+        }
+    }
+
+    unsafe extern "C" fn alpn_select_cb(
+        arg: *mut c_void,
+        _fd: *mut ssl::PRFileDesc,
+        protos: *const u8,
+        protos_len: c_uint,
+        proto_out: *mut u8,
+        proto_out_len: *mut c_uint,
+        proto_max_len: c_uint,
+    ) -> ssl::SECStatus {
+        let p = arg as *mut SniState;
+        let sni = p.as_mut().unwrap();
+        let selector = match &sni.alpn_selector {
+            Some(s) => s,
+            None => return ssl::SECFailure,
+        };
+        let mut list = if protos.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(protos, protos_len as usize)
+        };
+        let mut offered = Vec::new();
+        while let Some((&len, rest)) = list.split_first() {
+            let len = usize::from(len);
+            if len > rest.len() {
+                return ssl::SECFailure;
+            }
+            if let Ok(v) = std::str::from_utf8(&rest[..len]) {
+                offered.push(v);
+            }
+            list = &rest[len..];
+        }
+
+        *proto_out_len = 0;
+        let chosen = selector.select(sni.server_name.as_deref(), &offered);
+        if let Some(v) = chosen {
+            if !offered.contains(&v.as_str()) || v.len() > proto_max_len as usize {
+                qwarn!("ALPN selector chose {}, which wasn't offered", v);
+                return ssl::SECFailure;
+            }
+            std::slice::from_raw_parts_mut(proto_out, v.len()).copy_from_slice(v.as_bytes());
+            *proto_out_len = c_uint::try_from(v.len()).expect("ALPN was way too big");
+        }
+        ssl::SECSuccess
+    }
+
+    /// Have `selector` choose the application protocol for each handshake,
+    /// instead of using the list from `set_alpn`.  It is told what the client
+    /// offers and the name that the client asked for, so that one server can
+    /// speak different protocols.
+    /// # Errors
+    /// If NSS won't take the callbacks.
+    pub fn set_alpn_selector(&mut self, selector: Box<dyn AlpnSelector>) -> Res<()> {
+        let sni = self.sni_state()?;
+        sni.alpn_selector = Some(selector);
+        let arg = sni as *mut SniState as *mut c_void;
+        secstatus_to_res(unsafe {
+            ssl::SSL_SetNextProtoCallback(self.agent.fd, Some(Self::alpn_select_cb), arg)
+        })
+    }
+
+    /// Use `certificate` for clients that ask for `server_name`, in place of
+    /// the certificates that the server was made with.  A name like
+    /// "*.example.com" is used for any name with one more label than that,
+    /// unless the name has a certificate of its own.  The certificate is
+    /// imported when a client asks for it.
+    /// # Errors
+    /// If NSS won't take the SNI callback.
+    pub fn set_certificate_for(
+        &mut self,
+        server_name: &str,
+        certificate: ServerCertificate,
+    ) -> Res<()> {
+        self.sni_state()?
+            .certificates
+            .insert(server_name.to_ascii_lowercase(), certificate);
+        Ok(())
+    }
+
+    /// Have `selector` choose the certificate for names that
+    /// `set_certificate_for` doesn't have one for.
+    /// # Errors
+    /// If NSS won't take the SNI callback.
+    pub fn set_certificate_selector(&mut self, selector: Box<dyn CertificateSelector>) -> Res<()> {
+        self.sni_state()?.certificate_selector = Some(selector);
+        Ok(())
+    }
+
+    /// The state that the SNI callback uses, which installs the callback the
+    /// first time that it is needed.
+    fn sni_state(&mut self) -> Res<&mut SniState> {
+        if self.sni.is_none() {
+            let mut sni = Pin::new(Box::new(SniState::default()));
+            let arg = &mut *sni as *mut SniState as *mut c_void;
+            secstatus_to_res(unsafe {
+                ssl::SSL_SNISocketConfigHook(self.agent.fd, Some(Self::sni_cb), arg)
+            })?;
+            self.sni = Some(sni);
+        }
+        Ok(&mut **self.sni.as_mut().unwrap())
+    }
+
     /// Enable 0-RTT.  This shadows the function of the same name that can be accessed
     /// via the Deref implementation on Server.
     pub fn enable_0rtt(
@@ -864,7 +1870,8 @@ impl Server {
         unsafe {
             ssl::SSL_HelloRetryRequestCallback(self.agent.fd, Some(Self::hello_retry_cb), arg)
         }?;
-        unsafe { ssl::SSL_SetMaxEarlyDataSize(self.agent.fd, max_early_data) }?;
+        self.max_early_data = Some(max_early_data);
+        self.configure_max_early_data()?;
         self.zero_rtt_check = Some(check_state);
         self.agent.enable_0rtt()?;
         anti_replay.config_socket(self.fd)?;
@@ -884,6 +1891,34 @@ impl Server {
 
         Ok(*Pin::into_inner(records))
     }
+
+    /// Choose whether the tickets that are sent from now on allow 0-RTT.  This
+    /// does nothing unless 0-RTT is enabled.
+    pub fn set_tickets_allow_0rtt(&mut self, allow: bool) -> Res<()> {
+        self.tickets_allow_0rtt = allow;
+        self.configure_max_early_data()
+    }
+
+    /// Change the most early data that the tickets that are sent from now on
+    /// allow.  This does nothing unless 0-RTT is enabled.
+    pub fn set_max_early_data(&mut self, max_early_data: u32) -> Res<()> {
+        if self.max_early_data.is_some() {
+            self.max_early_data = Some(max_early_data);
+        }
+        self.configure_max_early_data()
+    }
+
+    fn configure_max_early_data(&mut self) -> Res<()> {
+        match self.max_early_data {
+            Some(max) => unsafe {
+                ssl::SSL_SetMaxEarlyDataSize(
+                    self.agent.fd,
+                    if self.tickets_allow_0rtt { max } else { 0 },
+                )
+            },
+            None => Ok(()),
+        }
+    }
 }
 
 impl Deref for Server {
@@ -6,6 +6,9 @@
 
 use crate::err::{mozpkix, sec, ssl, PRErrorCode};
 
+use std::cell::Cell;
+use std::rc::Rc;
+
 /// The outcome of authentication.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AuthenticationStatus {
@@ -65,6 +68,54 @@ impl Into<PRErrorCode> for AuthenticationStatus {
     }
 }
 
+/// What a `CertificateVerifier` makes of the peer's certificates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CertificateVerdict {
+    Accept,
+    Reject(AuthenticationStatus),
+    /// The verifier will decide later.  The handshake waits until
+    /// `SecretAgent::authenticated` is called with the outcome, or until a
+    /// `PendingAuthentication` is completed.
+    Pending,
+}
+
+/// A `CertificateVerifier` is used by the agent to run its own checks on the
+/// peer's certificates, in place of the `AuthenticationPending` state that
+/// the handshake otherwise stops in.
+pub trait CertificateVerifier: std::fmt::Debug {
+    /// `chain` holds the DER encoding of each certificate, starting with the
+    /// end-entity certificate.  `server_name` is the name that a client asked
+    /// for, which is `None` for a server.
+    fn verify(&mut self, chain: &[&[u8]], server_name: Option<&str>) -> CertificateVerdict;
+}
+
+/// An authentication that hasn't finished, for checks like OCSP that take
+/// more than one turn of an event loop.  This can be kept wherever the outcome
+/// will come from, in place of the agent, and the handshake carries on the
+/// next time the agent is driven after `complete` is called.  Clones share the
+/// same outcome.
+#[derive(Clone, Debug, Default)]
+pub struct PendingAuthentication(Rc<Cell<Option<AuthenticationStatus>>>);
+
+impl PendingAuthentication {
+    /// Set the outcome.  Only the first outcome counts, and none do if the
+    /// agent was told the outcome some other way, or has given up.
+    pub fn complete(&self, status: AuthenticationStatus) {
+        if self.0.get().is_none() {
+            self.0.set(Some(status));
+        }
+    }
+
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.0.get().is_some()
+    }
+
+    pub(crate) fn outcome(&self) -> Option<AuthenticationStatus> {
+        self.0.get()
+    }
+}
+
 // Note that this mapping should be removed after gecko eventually learns how to
 // map into the enumerated type.
 impl From<PRErrorCode> for AuthenticationStatus {
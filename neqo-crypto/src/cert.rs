@@ -4,6 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::ct::{self, SctSource, SignedCertificateTimestamp};
 use crate::err::secstatus_to_res;
 use crate::p11::{
     CERTCertList, CERTCertListNode, CERT_GetCertificateDer, CertList, PRCList, SECItem,
@@ -123,4 +124,26 @@ impl CertificateInfo {
     pub fn signed_cert_timestamp(&mut self) -> &Option<Vec<u8>> {
         &self.signed_cert_timestamp
     }
+
+    /// The SCTs for the end-entity certificate: those in the certificate,
+    /// then those in the TLS extension, then those in stapled OCSP responses.
+    /// This doesn't move the iterator.
+    pub fn signed_certificate_timestamps(&mut self) -> Vec<SignedCertificateTimestamp> {
+        let cursor = self.cursor;
+        self.cursor = Self::head(&self.certs);
+        let mut scts = match (&mut *self).next() {
+            Some(der) => ct::certificate_scts(der),
+            None => Vec::new(),
+        };
+        self.cursor = cursor;
+        if let Some(list) = &self.signed_cert_timestamp {
+            scts.extend(ct::decode_sct_list(list, SctSource::TlsExtension));
+        }
+        if let Some(responses) = &self.stapled_ocsp_responses {
+            for r in responses {
+                scts.extend(ct::ocsp_scts(r));
+            }
+        }
+        scts
+    }
 }
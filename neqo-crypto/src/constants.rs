@@ -57,6 +57,19 @@ remap_enum! {
     }
 }
 
+/// The hybrid of X25519 and ML-KEM-768.  Only newer versions of NSS have it, so
+/// it isn't taken from the bindings.  An NSS that doesn't know a group leaves
+/// it out when groups are set, so this can go ahead of groups that any NSS has.
+/// Its key share is large, so a ClientHello with it takes more than one packet.
+pub const TLS_GRP_KEM_MLKEM768X25519: Group = 0x11ec;
+
+remap_enum! {
+    HashAlgorithm: u16 => ssl::SSLHashType {
+        TLS_HASH_SHA256 = ssl_hash_sha256,
+        TLS_HASH_SHA384 = ssl_hash_sha384,
+    }
+}
+
 remap_enum! {
     HandshakeMessage: u8 => ssl::SSLHandshakeType {
         TLS_HS_HELLO_REQUEST = ssl_hs_hello_request,
@@ -0,0 +1,269 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Certificate Transparency (RFC 6962): the signed certificate timestamps that
+// a server presents, from wherever they came, and a hook for deciding whether
+// they are enough.
+
+use neqo_common::Decoder;
+
+use std::convert::TryFrom;
+
+/// The X.509 extension that holds SCTs, 1.3.6.1.4.1.11129.2.4.2.
+const OID_CERTIFICATE_SCTS: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+/// The OCSP extension that holds SCTs, 1.3.6.1.4.1.11129.2.4.5.
+const OID_OCSP_SCTS: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x05];
+
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const CONSTRUCTED: u8 = 0x20;
+
+/// Where an SCT came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SctSource {
+    /// An extension in the certificate.
+    Certificate,
+    /// The `signed_certificate_timestamp` TLS extension.
+    TlsExtension,
+    /// An extension in a stapled OCSP response.
+    Ocsp,
+}
+
+/// A signed certificate timestamp.  Nothing about it is checked, not even
+/// the signature, so that is up to a `CtPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedCertificateTimestamp {
+    pub source: SctSource,
+    pub version: u8,
+    /// The SHA-256 hash of the public key of the log.
+    pub log_id: Vec<u8>,
+    /// When the log saw the certificate, in milliseconds since 1970.
+    pub timestamp: u64,
+    pub extensions: Vec<u8>,
+    /// A TLS `SignatureAndHashAlgorithm`, with the hash in the high byte.
+    pub signature_algorithm: u16,
+    pub signature: Vec<u8>,
+}
+
+/// Decides whether the SCTs that a server presents are enough.
+pub trait CtPolicy: std::fmt::Debug {
+    /// `scts` are those for the end-entity certificate, which is the first in
+    /// `chain`.  Return `false` to fail the handshake.
+    fn check(&mut self, scts: &[SignedCertificateTimestamp], chain: &[&[u8]]) -> bool;
+}
+
+fn decode_sct(sct: &[u8], source: SctSource) -> Option<SignedCertificateTimestamp> {
+    let mut dec = Decoder::from(sct);
+    let version = dec.decode_byte()?;
+    let log_id = dec.decode(32)?.to_vec();
+    let timestamp = dec.decode_uint(8)?;
+    let extensions = dec.decode_vec(2)?.to_vec();
+    let signature_algorithm = u16::try_from(dec.decode_uint(2)?).ok()?;
+    let signature = dec.decode_vec(2)?.to_vec();
+    if dec.remaining() > 0 {
+        return None;
+    }
+    Some(SignedCertificateTimestamp {
+        source,
+        version,
+        log_id,
+        timestamp,
+        extensions,
+        signature_algorithm,
+        signature,
+    })
+}
+
+/// Decode a `SignedCertificateTimestampList`.  Only version 1 is understood,
+/// and RFC 6962 says to ignore others, so they are left out, as is anything
+/// that doesn't decode.
+pub(crate) fn decode_sct_list(list: &[u8], source: SctSource) -> Vec<SignedCertificateTimestamp> {
+    let mut scts = Vec::new();
+    // The list has a length of its own, which has to cover the rest.
+    let mut dec = Decoder::from(list);
+    match dec.decode_uint(2) {
+        Some(len) if usize::try_from(len) == Ok(dec.remaining()) => (),
+        _ => return scts,
+    }
+    let mut entries = Decoder::from(&list[2..]);
+    while let Some(sct) = entries.decode_vec(2) {
+        if let Some(sct) = decode_sct(sct, source) {
+            if sct.version == 0 {
+                scts.push(sct);
+            }
+        }
+    }
+    scts
+}
+
+/// Split a DER item from the front of `der`, into its tag, its contents, and
+/// what follows.  Only the short tags that certificates use are understood.
+fn der_item(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    if tag & 0x1f == 0x1f {
+        return None;
+    }
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let (len, after) = rest.split_at(n);
+        rest = after;
+        len.iter().fold(0, |v, &b| (v << 8) | usize::from(b))
+    };
+    if len > rest.len() {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Each of the DER items in `der`, which has to hold nothing else.
+fn der_items(mut der: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut items = Vec::new();
+    while !der.is_empty() {
+        let (tag, contents, rest) = der_item(der)?;
+        items.push((tag, contents));
+        der = rest;
+    }
+    Some(items)
+}
+
+/// The value of the extension with `oid`, wherever it is in `der`.  This
+/// looks in everything that is constructed, and in OCTET STRINGs that hold
+/// DER, which finds extensions in both certificates and OCSP responses
+/// without having to know how either is put together.
+fn find_extension<'a>(der: &'a [u8], oid: &[u8]) -> Option<&'a [u8]> {
+    let items = der_items(der)?;
+    // An extension is the OID, whether it is critical, and an OCTET STRING.
+    if let Some(&(TAG_OID, id)) = items.first() {
+        if id == oid {
+            return items
+                .iter()
+                .find(|(tag, _)| *tag == TAG_OCTET_STRING)
+                .map(|&(_, v)| v);
+        }
+    }
+    items
+        .iter()
+        .filter(|(tag, _)| tag & CONSTRUCTED != 0 || *tag == TAG_OCTET_STRING)
+        .find_map(|&(_, v)| find_extension(v, oid))
+}
+
+/// The SCTs in the extension with `oid` in `der`.  The list is in an OCTET
+/// STRING of its own, inside the value of the extension.
+fn extension_scts(der: &[u8], oid: &[u8], source: SctSource) -> Vec<SignedCertificateTimestamp> {
+    match find_extension(der, oid).and_then(der_item) {
+        Some((TAG_OCTET_STRING, list, &[])) => decode_sct_list(list, source),
+        _ => Vec::new(),
+    }
+}
+
+pub(crate) fn certificate_scts(certificate: &[u8]) -> Vec<SignedCertificateTimestamp> {
+    extension_scts(certificate, OID_CERTIFICATE_SCTS, SctSource::Certificate)
+}
+
+pub(crate) fn ocsp_scts(response: &[u8]) -> Vec<SignedCertificateTimestamp> {
+    extension_scts(response, OID_OCSP_SCTS, SctSource::Ocsp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sct(version: u8) -> Vec<u8> {
+        let mut v = vec![version];
+        v.extend_from_slice(&[7; 32]);
+        v.extend_from_slice(&[0, 0, 1, 0x70, 0, 0, 0, 1]);
+        v.extend_from_slice(&[0, 0]);
+        v.extend_from_slice(&[4, 3, 0, 2, 0xaa, 0xbb]);
+        v
+    }
+
+    fn sct_list(scts: &[Vec<u8>]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for s in scts {
+            entries.extend_from_slice(&u16::try_from(s.len()).unwrap().to_be_bytes());
+            entries.extend_from_slice(s);
+        }
+        let mut list = u16::try_from(entries.len()).unwrap().to_be_bytes().to_vec();
+        list.extend_from_slice(&entries);
+        list
+    }
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut v = vec![tag];
+        if contents.len() < 0x80 {
+            v.push(u8::try_from(contents.len()).unwrap());
+        } else {
+            v.push(0x82);
+            v.extend_from_slice(&u16::try_from(contents.len()).unwrap().to_be_bytes());
+        }
+        v.extend_from_slice(contents);
+        v
+    }
+
+    #[test]
+    fn list() {
+        let scts = decode_sct_list(&sct_list(&[sct(0), sct(1), vec![0]]), SctSource::Ocsp);
+        assert_eq!(
+            scts,
+            vec![SignedCertificateTimestamp {
+                source: SctSource::Ocsp,
+                version: 0,
+                log_id: vec![7; 32],
+                timestamp: 0x170_0000_0001,
+                extensions: Vec::new(),
+                signature_algorithm: 0x0403,
+                signature: vec![0xaa, 0xbb],
+            }]
+        );
+        assert!(decode_sct_list(&[0, 5, 0], SctSource::Ocsp).is_empty());
+    }
+
+    #[test]
+    fn extension() {
+        let list = sct_list(&[sct(0), sct(0)]);
+        let extension = [
+            der(TAG_OID, OID_CERTIFICATE_SCTS),
+            der(TAG_OCTET_STRING, &der(TAG_OCTET_STRING, &list)),
+        ]
+        .concat();
+        let other = [der(TAG_OID, &[0x55, 0x1d, 0x13]), der(0x01, &[0xff])].concat();
+        let extensions = der(0x30, &[der(0x30, &other), der(0x30, &extension)].concat());
+        // The extensions are deep in the certificate, and explicitly tagged.
+        let certificate = der(
+            0x30,
+            &der(0x30, &[der(0x02, &[1]), der(0xa3, &extensions)].concat()),
+        );
+        let scts = certificate_scts(&certificate);
+        assert_eq!(scts.len(), 2);
+        assert_eq!(scts[0].source, SctSource::Certificate);
+
+        // The OCSP extension is different.
+        assert!(ocsp_scts(&certificate).is_empty());
+        // An OCSP response has its BasicOCSPResponse in an OCTET STRING.
+        let extension = [
+            der(TAG_OID, OID_OCSP_SCTS),
+            der(TAG_OCTET_STRING, &der(TAG_OCTET_STRING, &list)),
+        ]
+        .concat();
+        let basic = der(0x30, &der(0xa1, &der(0x30, &der(0x30, &extension))));
+        let response = der(0x30, &der(0xa0, &der(0x30, &der(TAG_OCTET_STRING, &basic))));
+        assert_eq!(ocsp_scts(&response).len(), 2);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(der_items(&[0x30, 0x05, 0x00]).is_none());
+        assert!(der_items(&[0x1f, 0x00]).is_none());
+        assert!(certificate_scts(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]).is_empty());
+    }
+}
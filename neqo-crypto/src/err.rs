@@ -27,6 +27,7 @@ pub mod nspr {
 pub type Res<T> = Result<T, Error>;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::pub_enum_variant_names)]
 pub enum Error {
     AeadInitFailure,
@@ -38,6 +39,7 @@ pub enum Error {
     IntegerOverflow,
     InvalidEpoch,
     MixedHandshakeMethod,
+    MixedInitMethod,
     NoDataAvailable,
     NssError {
         name: String,
@@ -48,6 +50,7 @@ pub enum Error {
     SelfEncryptFailure,
     TimeTravelError,
     UnsupportedCipher,
+    UnsupportedHash,
     UnsupportedVersion,
 }
 
@@ -0,0 +1,207 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// GREASE (RFC 8701): the reserved values that a ClientHello can include, so
+// that peers which choke on values they don't know are found early.  NSS sends
+// them; this finds them in what a client sends.
+
+use crate::constants::{Extension, TLS_EXT_GROUPS, TLS_EXT_VERSIONS, TLS_HS_CLIENT_HELLO};
+
+use neqo_common::Decoder;
+
+use std::convert::TryFrom;
+
+/// Whether `v` is one of the values that RFC 8701 reserves: 0x0a0a, 0x1a1a,
+/// and so on up to 0xfafa.
+#[must_use]
+pub fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+/// Where a ClientHello had GREASE values.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerGrease {
+    pub cipher_suites: bool,
+    pub extensions: bool,
+    pub groups: bool,
+    pub versions: bool,
+}
+
+impl PeerGrease {
+    /// Whether there were GREASE values anywhere.
+    #[must_use]
+    pub fn any(&self) -> bool {
+        self.cipher_suites || self.extensions || self.groups || self.versions
+    }
+
+    /// Add what is in `msg`, if it is a ClientHello.  Nothing is added if it
+    /// doesn't decode, as NSS will have rejected it anyway.
+    fn client_hello(&mut self, msg: &[u8]) {
+        if let Some(g) = decode_client_hello(msg) {
+            self.cipher_suites |= g.cipher_suites;
+            self.extensions |= g.extensions;
+            self.groups |= g.groups;
+            self.versions |= g.versions;
+        }
+    }
+}
+
+/// Collects the handshake messages that a server receives in the clear until
+/// each ClientHello is whole, because QUIC can split one anywhere.
+#[derive(Debug, Default)]
+pub(crate) struct GreaseTracker {
+    buf: Vec<u8>,
+    /// Set once something other than a ClientHello arrives.
+    done: bool,
+    seen: PeerGrease,
+}
+
+impl GreaseTracker {
+    pub fn handshake_data(&mut self, data: &[u8]) {
+        if self.done {
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= 4 {
+            if self.buf[0] != TLS_HS_CLIENT_HELLO {
+                self.done = true;
+                self.buf = Vec::new();
+                return;
+            }
+            // The type, a 3 byte length, and the body.
+            let len = 4 + self.buf[1..4]
+                .iter()
+                .fold(0, |v, &b| (v << 8) | usize::from(b));
+            if self.buf.len() < len {
+                return;
+            }
+            self.seen.client_hello(&self.buf[..len]);
+            self.buf.drain(..len);
+        }
+    }
+
+    pub fn seen(&self) -> PeerGrease {
+        self.seen
+    }
+}
+
+/// Whether any of the 16-bit values in `list` are GREASE.
+fn any_grease(list: &[u8]) -> bool {
+    list.chunks(2)
+        .any(|v| v.len() == 2 && is_grease(u16::from_be_bytes([v[0], v[1]])))
+}
+
+fn decode_client_hello(msg: &[u8]) -> Option<PeerGrease> {
+    let mut dec = Decoder::from(msg);
+    if dec.decode_byte()? != TLS_HS_CLIENT_HELLO {
+        return None;
+    }
+    // The length, the version and the random.
+    dec.decode(3 + 2 + 32)?;
+    dec.decode_vec(1)?; // legacy_session_id
+    let mut grease = PeerGrease {
+        cipher_suites: any_grease(dec.decode_vec(2)?),
+        ..PeerGrease::default()
+    };
+    dec.decode_vec(1)?; // legacy_compression_methods
+    let mut extensions = Decoder::from(dec.decode_vec(2)?);
+    while extensions.remaining() > 0 {
+        let ext = Extension::try_from(extensions.decode_uint(2)?).ok()?;
+        let mut data = Decoder::from(extensions.decode_vec(2)?);
+        grease.extensions |= is_grease(ext);
+        match ext {
+            TLS_EXT_GROUPS => grease.groups = any_grease(data.decode_vec(2)?),
+            TLS_EXT_VERSIONS => grease.versions = any_grease(data.decode_vec(1)?),
+            _ => (),
+        }
+    }
+    Some(grease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x0b0b));
+        assert!(!is_grease(0x0303));
+    }
+
+    fn client_hello(suites: &[u8], extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, u8::try_from(suites.len()).unwrap()]);
+        body.extend_from_slice(suites);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&[0, u8::try_from(extensions.len()).unwrap()]);
+        body.extend_from_slice(extensions);
+        let mut msg = vec![TLS_HS_CLIENT_HELLO, 0, 0, u8::try_from(body.len()).unwrap()];
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    #[test]
+    fn found() {
+        let extensions = [
+            0x3a, 0x3a, 0, 0, // An empty GREASE extension.
+            0, 10, 0, 6, 0, 4, 0x8a, 0x8a, 0, 0x1d, // supported_groups
+            0, 43, 0, 5, 4, 0xda, 0xda, 3, 4, // supported_versions
+        ];
+        let msg = client_hello(&[0x13, 0x01, 0xca, 0xca], &extensions);
+        let mut grease = GreaseTracker::default();
+        // The ClientHello is split, as it might be in QUIC.
+        grease.handshake_data(&msg[..2]);
+        grease.handshake_data(&msg[2..50]);
+        assert!(!grease.seen().any());
+        grease.handshake_data(&msg[50..]);
+        assert_eq!(
+            grease.seen(),
+            PeerGrease {
+                cipher_suites: true,
+                extensions: true,
+                groups: true,
+                versions: true,
+            }
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        let extensions = [0, 10, 0, 4, 0, 2, 0, 0x1d, 0, 43, 0, 3, 2, 3, 4];
+        let mut grease = GreaseTracker::default();
+        grease.handshake_data(&client_hello(&[0x13, 0x01], &extensions));
+        assert!(!grease.seen().any());
+
+        // Nothing after something that isn't a ClientHello counts.
+        let mut msg = client_hello(&[0xca, 0xca], &[0x3a, 0x3a, 0, 0]);
+        msg[0] = 2;
+        grease.handshake_data(&msg);
+        grease.handshake_data(&client_hello(&[0xca, 0xca], &[]));
+        assert!(!grease.seen().any());
+
+        // Nor does a ClientHello that doesn't decode.
+        let mut msg = client_hello(&[0xca, 0xca], &[0x3a, 0x3a, 0, 0]);
+        msg[3] -= 1;
+        msg.pop();
+        let mut grease = GreaseTracker::default();
+        grease.handshake_data(&msg);
+        assert!(!grease.seen().any());
+    }
+
+    #[test]
+    fn hello_retry() {
+        let mut grease = GreaseTracker::default();
+        grease.handshake_data(&client_hello(&[0x13, 0x01], &[]));
+        grease.handshake_data(&client_hello(&[0x13, 0x01], &[0x3a, 0x3a, 0, 0]));
+        assert!(grease.seen().extensions);
+        assert!(!grease.seen().cipher_suites);
+    }
+}
@@ -0,0 +1,139 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Logging of TLS secrets in the format that SSLKEYLOGFILE uses, so that tools
+// like Wireshark can decrypt a capture.
+
+use crate::constants::*;
+use crate::secrets::Secrets;
+
+use neqo_common::qwarn;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// The length of the random in a ClientHello.
+const CLIENT_RANDOM_LEN: usize = 32;
+/// Where the random starts in a ClientHello: after the message type, a 3 byte
+/// length, and the version.
+const CLIENT_RANDOM_OFFSET: usize = 6;
+
+/// Something that records secrets.  `label` is one of the labels that
+/// SSLKEYLOGFILE uses, such as `CLIENT_HANDSHAKE_TRAFFIC_SECRET`, and
+/// `client_random` is the random from the ClientHello, which identifies the
+/// connection.
+pub trait KeyLog: std::fmt::Debug {
+    fn log(&mut self, label: &str, client_random: &[u8], secret: &[u8]);
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    let mut s = String::with_capacity(buf.len() * 2);
+    for b in buf {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Adds secrets to a file, a line for each.
+#[derive(Debug)]
+pub struct KeyLogFile {
+    file: File,
+}
+
+impl KeyLogFile {
+    /// Open `path` for adding secrets to, creating it if it doesn't exist.
+    /// # Errors
+    /// If the file can't be opened.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&mut self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, to_hex(client_random), to_hex(secret));
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            qwarn!("Unable to write to key log: {}", e);
+        }
+    }
+}
+
+/// The label for the secret for `epoch` that the client uses to protect what
+/// it sends, if `client`, or the server, if not.
+fn label(epoch: Epoch, client: bool) -> Option<&'static str> {
+    match (epoch, client) {
+        (1, true) => Some("CLIENT_EARLY_TRAFFIC_SECRET"),
+        (2, true) => Some("CLIENT_HANDSHAKE_TRAFFIC_SECRET"),
+        (2, false) => Some("SERVER_HANDSHAKE_TRAFFIC_SECRET"),
+        (3, true) => Some("CLIENT_TRAFFIC_SECRET_0"),
+        (3, false) => Some("SERVER_TRAFFIC_SECRET_0"),
+        _ => None,
+    }
+}
+
+/// Tracks what a `SecretAgent` needs to log secrets: the client random, which
+/// is taken from the ClientHello, and which secrets have been logged already.
+#[derive(Debug)]
+pub(crate) struct KeyLogger {
+    log: Rc<RefCell<dyn KeyLog>>,
+    client_random: Option<Vec<u8>>,
+    /// The secrets that have been logged, with read secrets first.
+    logged: [[bool; 3]; 2],
+}
+
+impl KeyLogger {
+    pub fn new(log: Rc<RefCell<dyn KeyLog>>) -> Self {
+        Self {
+            log,
+            client_random: None,
+            logged: [[false; 3]; 2],
+        }
+    }
+
+    /// Look at a handshake message, to find the client random if it is a
+    /// ClientHello.  Only the first ClientHello counts, though the one that
+    /// follows a HelloRetryRequest has the same random anyway.
+    pub fn handshake_message(&mut self, msg: &[u8]) {
+        if self.client_random.is_none()
+            && msg.len() >= CLIENT_RANDOM_OFFSET + CLIENT_RANDOM_LEN
+            && msg[0] == TLS_HS_CLIENT_HELLO
+        {
+            self.client_random =
+                Some(msg[CLIENT_RANDOM_OFFSET..CLIENT_RANDOM_OFFSET + CLIENT_RANDOM_LEN].to_vec());
+        }
+    }
+
+    /// Log any secrets in `secrets` that haven't been logged.  Nothing is logged
+    /// until the client random is known.
+    pub fn log_secrets(&mut self, secrets: &Secrets, is_server: bool) {
+        let random = match &self.client_random {
+            Some(r) => r,
+            None => return,
+        };
+        for (i, (dir, read)) in [(secrets.read(), true), (secrets.write(), false)]
+            .iter()
+            .enumerate()
+        {
+            for epoch in 1..=3 {
+                let logged = &mut self.logged[i][usize::from(epoch - 1)];
+                if *logged {
+                    continue;
+                }
+                // A server reads what the client writes.
+                let name = label(epoch, is_server == *read);
+                if let (Some(name), Some(key)) = (name, dir.get(epoch)) {
+                    if let Ok(secret) = key.as_bytes() {
+                        self.log.borrow_mut().log(name, random, secret);
+                    }
+                    *logged = true;
+                }
+            }
+        }
+    }
+}
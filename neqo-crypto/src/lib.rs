@@ -23,10 +23,14 @@ mod agentio;
 mod auth;
 mod cert;
 pub mod constants;
+mod ct;
 mod err;
 pub mod ext;
+mod grease;
 pub mod hkdf;
 pub mod hp;
+mod keylog;
+mod pem;
 mod prio;
 mod replay;
 mod secrets;
@@ -35,23 +39,31 @@ mod ssl;
 mod time;
 
 pub use self::agent::{
-    Agent, Client, HandshakeState, Record, RecordList, SecretAgent, SecretAgentInfo,
-    SecretAgentPreInfo, Server, ZeroRttCheckResult, ZeroRttChecker,
+    Agent, AlpnSelector, CertificateSelector, Client, HandshakeState, Record, RecordList,
+    ResumptionToken, SecretAgent, SecretAgentInfo, SecretAgentPreInfo, Server, ServerCertificate,
+    ZeroRttCheckResult, ZeroRttChecker,
 };
 pub use self::constants::*;
+pub use self::ct::{CtPolicy, SctSource, SignedCertificateTimestamp};
 pub use self::err::{Error, PRErrorCode, Res};
 pub use self::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
+pub use self::grease::{is_grease, PeerGrease};
+pub use self::keylog::{KeyLog, KeyLogFile};
 pub use self::p11::SymKey;
 pub use self::replay::AntiReplay;
 pub use self::secrets::SecretDirection;
-pub use auth::AuthenticationStatus;
+pub use auth::{
+    AuthenticationStatus, CertificateVerdict, CertificateVerifier, PendingAuthentication,
+};
 
 use neqo_common::once::OnceResult;
+use neqo_common::qerror;
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::path::{Path, PathBuf};
-use std::ptr::null;
+use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 mod nss {
     #![allow(clippy::redundant_static_lifetimes, non_upper_case_globals)]
@@ -81,13 +93,31 @@ impl Drop for NssLoaded {
 }
 
 static mut INITIALIZED: OnceResult<NssLoaded> = OnceResult::new();
+/// The server session cache can only be configured once, and it is kept for
+/// as long as the process runs.
+static mut SESSION_CACHE: OnceResult<ssl::SECStatus> = OnceResult::new();
 
 fn already_initialized() -> bool {
     unsafe { nss::NSS_IsInitialized() != 0 }
 }
 
+/// Whether `init` or `init_db` has been called, after which `NssContext` can't
+/// be used.
+static INIT_CALLED: AtomicBool = AtomicBool::new(false);
+
+/// Note that `init` or `init_db` is being called.
+fn init_called() {
+    INIT_CALLED.store(true, Ordering::SeqCst);
+    assert_eq!(
+        CONTEXTS.load(Ordering::SeqCst),
+        0,
+        "NSS is already initialized with NssContext"
+    );
+}
+
 /// Initialize NSS.  This only executes the initialization routines once, so if there is any chance that
 pub fn init() {
+    init_called();
     // Set time zero.
     time::init();
     unsafe {
@@ -105,6 +135,7 @@ pub fn init() {
 }
 
 pub fn init_db<P: Into<PathBuf>>(dir: P) {
+    init_called();
     time::init();
     unsafe {
         INITIALIZED.call_once(|| {
@@ -127,24 +158,119 @@ pub fn init_db<P: Into<PathBuf>>(dir: P) {
             .expect("NSS_Initialize failed");
 
             secstatus_to_res(nss::NSS_SetDomesticPolicy()).expect("NSS_SetDomesticPolicy failed");
-            secstatus_to_res(ssl::SSL_ConfigServerSessionIDCache(
-                1024,
-                0,
-                0,
-                dircstr.as_ptr(),
-            ))
-            .expect("SSL_ConfigServerSessionIDCache failed");
+            let rv = *SESSION_CACHE
+                .call_once(|| ssl::SSL_ConfigServerSessionIDCache(1024, 0, 0, dircstr.as_ptr()));
+            crate::err::secstatus_to_res(rv).expect("SSL_ConfigServerSessionIDCache failed");
 
             NssLoaded::Db(path.into_boxed_path())
         });
     }
 }
 
-/// Panic if NSS isn't initialized.
+/// The number of `NssContext` instances that are alive.
+static CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Panic if NSS isn't initialized, either by `init` or `init_db`, or because
+/// an `NssContext` is alive.
 pub fn assert_initialized() {
+    if CONTEXTS.load(Ordering::SeqCst) > 0 {
+        return;
+    }
     unsafe {
         INITIALIZED.call_once(|| {
             panic!("NSS not initialized with init or init_db");
         });
     }
 }
+
+/// A reference to NSS that is shut down when it is dropped, unlike `init` and
+/// `init_db`, which last for the life of the process.
+///
+/// These are not independent.  There is only one NSS in a process, and every
+/// context shares it: the database that each opens is added to it, so the
+/// certificates and keys in every database that is open can be found through
+/// any of them.  NSS shuts down when the last context is dropped, and until
+/// then `assert_initialized` passes for everything in the process.
+///
+/// Contexts can't be mixed with `init` or `init_db`.  Making a context after
+/// either has been called is an error, and calling either while a context is
+/// alive panics.
+#[derive(Debug)]
+pub struct NssContext {
+    ctx: *mut nss::NSSInitContext,
+    db: Option<PathBuf>,
+}
+
+impl NssContext {
+    /// Initialize NSS without a database.
+    /// # Errors
+    /// If NSS can't be initialized.
+    pub fn new() -> Res<Self> {
+        let empty = CString::new("")?;
+        let flags = nss::NSS_INIT_READONLY
+            | nss::NSS_INIT_NOCERTDB
+            | nss::NSS_INIT_NOMODDB
+            | nss::NSS_INIT_FORCEOPEN
+            | nss::NSS_INIT_NOROOTINIT
+            | nss::NSS_INIT_OPTIMIZESPACE;
+        Self::init(&empty, flags, None)
+    }
+
+    /// Initialize NSS with the database in `dir`, which is opened read-only.
+    /// # Errors
+    /// If `dir` isn't a directory or the database can't be opened.
+    pub fn with_db<P: Into<PathBuf>>(dir: P) -> Res<Self> {
+        let path = dir.into();
+        if !path.is_dir() {
+            return Err(Error::InternalError);
+        }
+        let dircstr = CString::new(path.to_str().ok_or(Error::InternalError)?)?;
+        Self::init(&dircstr, nss::NSS_INIT_READONLY, Some(path))
+    }
+
+    fn init(dir: &CStr, flags: u32, db: Option<PathBuf>) -> Res<Self> {
+        if INIT_CALLED.load(Ordering::SeqCst) {
+            return Err(Error::MixedInitMethod);
+        }
+        time::init();
+        let empty = CString::new("")?;
+        let ctx = unsafe {
+            nss::NSS_InitContext(
+                dir.as_ptr(),
+                empty.as_ptr(),
+                empty.as_ptr(),
+                nss::SECMOD_DB.as_ptr() as *const c_char,
+                null_mut(),
+                flags,
+            )
+        };
+        if ctx.is_null() {
+            crate::err::secstatus_to_res(ssl::SECFailure)?;
+            return Err(Error::InternalError);
+        }
+        // From here, dropping `cx` shuts the context down.
+        let cx = Self { ctx, db };
+        CONTEXTS.fetch_add(1, Ordering::SeqCst);
+        secstatus_to_res(unsafe { nss::NSS_SetDomesticPolicy() })?;
+        let rv = unsafe {
+            *SESSION_CACHE.call_once(|| ssl::SSL_ConfigServerSessionIDCache(1024, 0, 0, null()))
+        };
+        crate::err::secstatus_to_res(rv)?;
+        Ok(cx)
+    }
+
+    /// The directory that the database was opened from, if there is one.
+    #[must_use]
+    pub fn db(&self) -> Option<&Path> {
+        self.db.as_ref().map(PathBuf::as_path)
+    }
+}
+
+impl Drop for NssContext {
+    fn drop(&mut self) {
+        CONTEXTS.fetch_sub(1, Ordering::SeqCst);
+        if let Err(e) = secstatus_to_res(unsafe { nss::NSS_ShutdownContext(self.ctx) }) {
+            qerror!("NSS_ShutdownContext failed: {}", e);
+        }
+    }
+}
@@ -62,6 +62,7 @@ macro_rules! scoped_ptr {
 scoped_ptr!(Certificate, CERTCertificate, CERT_DestroyCertificate);
 scoped_ptr!(CertList, CERTCertList, CERT_DestroyCertList);
 scoped_ptr!(PrivateKey, SECKEYPrivateKey, SECKEY_DestroyPrivateKey);
+scoped_ptr!(PublicKey, SECKEYPublicKey, SECKEY_DestroyPublicKey);
 scoped_ptr!(SymKey, PK11SymKey, PK11_FreeSymKey);
 scoped_ptr!(Slot, PK11SlotInfo, PK11_FreeSlot);
 
@@ -89,6 +90,12 @@ impl std::fmt::Debug for SymKey {
     }
 }
 
+impl std::fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Opaque PrivateKey")
+    }
+}
+
 /// Generate a randomized buffer.
 pub fn random(size: usize) -> Res<Vec<u8>> {
     let mut buf = vec![0; size];
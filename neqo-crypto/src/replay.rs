@@ -41,15 +41,36 @@ scoped_ptr!(
 /// It limits the exposure of servers to replay attack by rejecting 0-RTT
 /// if it appears to be a replay.  There is a false-positive rate that can be
 /// managed by tuning the parameters used to create the context.
+///
+/// The context remembers the ClientHello messages that it has seen using a
+/// pair of Bloom filters, which take turns to be cleared each `window`.
+/// Each filter has `2^bits` bits, and `k` bits are set for each ClientHello.
 #[allow(clippy::module_name_repetitions)]
 pub struct AntiReplay {
     ctx: AntiReplayContext,
+    window: Duration,
+    k: usize,
+    bits: usize,
 }
 
 impl AntiReplay {
     /// Make a new anti-replay context.
     /// See the documentation in NSS for advice on how to set these values.
     pub fn new(now: Instant, window: Duration, k: usize, bits: usize) -> Res<Self> {
+        Ok(Self {
+            ctx: Self::create_context(now, window, k, bits)?,
+            window,
+            k,
+            bits,
+        })
+    }
+
+    fn create_context(
+        now: Instant,
+        window: Duration,
+        k: usize,
+        bits: usize,
+    ) -> Res<AntiReplayContext> {
         let mut ctx: *mut SSLAntiReplayContext = null_mut();
         unsafe {
             SSL_CreateAntiReplayContext(
@@ -62,13 +83,57 @@ impl AntiReplay {
         }?;
 
         match NonNull::new(ctx) {
-            Some(ctx_nn) => Ok(Self {
-                ctx: AntiReplayContext::new(ctx_nn),
-            }),
+            Some(ctx_nn) => Ok(AntiReplayContext::new(ctx_nn)),
             None => Err(Error::InternalError),
         }
     }
 
+    /// The time that each filter covers.
+    #[must_use]
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// The number of bits that are set for each ClientHello.
+    #[must_use]
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// The base 2 logarithm of the size of each filter.
+    #[must_use]
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Start again with new parameters.  This only affects connections that are
+    /// configured after this is called; those that already use this context
+    /// keep the old one.
+    ///
+    /// Until `window` has passed from `now`, the new context can't tell if
+    /// anything is a replay, so it rejects all 0-RTT.  `now` can be set that
+    /// far in the past to avoid this, but only by a server that knows that no
+    /// server using the same tickets has accepted 0-RTT in that time.
+    pub fn reconfigure(
+        &mut self,
+        now: Instant,
+        window: Duration,
+        k: usize,
+        bits: usize,
+    ) -> Res<()> {
+        self.ctx = Self::create_context(now, window, k, bits)?;
+        self.window = window;
+        self.k = k;
+        self.bits = bits;
+        Ok(())
+    }
+
+    /// Start again with the same parameters, forgetting what has been seen.
+    /// As with `reconfigure`, 0-RTT is rejected until a `window` after `now`.
+    pub fn reset(&mut self, now: Instant) -> Res<()> {
+        self.reconfigure(now, self.window, self.k, self.bits)
+    }
+
     /// Configure the provided socket with this anti-replay context.
     pub(crate) fn config_socket(&self, fd: *mut PRFileDesc) -> Res<()> {
         unsafe { SSL_SetAntiReplayContext(fd, *self.ctx) }
@@ -40,6 +40,8 @@ pub enum Opt {
     RecordSizeLimit,
     Tls13CompatMode,
     HelloDowngradeCheck,
+    DelegatedCredentials,
+    Grease,
 }
 
 impl Opt {
@@ -57,6 +59,8 @@ impl Opt {
             Self::RecordSizeLimit => SSLOption::SSL_RECORD_SIZE_LIMIT,
             Self::Tls13CompatMode => SSLOption::SSL_ENABLE_TLS13_COMPAT_MODE,
             Self::HelloDowngradeCheck => SSLOption::SSL_ENABLE_HELLO_DOWNGRADE_CHECK,
+            Self::DelegatedCredentials => SSLOption::SSL_ENABLE_DELEGATED_CREDENTIALS,
+            Self::Grease => SSLOption::SSL_ENABLE_GREASE,
         };
         i as PRInt32
     }
@@ -93,11 +97,24 @@ experimental_api!(SSL_RecordLayerData(
     data: *const u8,
     len: c_uint,
 ));
+experimental_api!(SSL_SendAdditionalKeyShares(
+    fd: *mut PRFileDesc,
+    count: c_uint,
+));
 experimental_api!(SSL_SendSessionTicket(
     fd: *mut PRFileDesc,
     extra: *const u8,
     len: c_uint,
 ));
+experimental_api!(SSL_GetResumptionTokenInfo(
+    token: *const u8,
+    len: c_uint,
+    info: *mut SSLResumptionTokenInfo,
+    infoLen: c_uint,
+));
+experimental_api!(SSL_DestroyResumptionTokenInfo(
+    info: *mut SSLResumptionTokenInfo,
+));
 experimental_api!(SSL_SetMaxEarlyDataSize(fd: *mut PRFileDesc, size: u32));
 experimental_api!(SSL_SetResumptionToken(
     fd: *mut PRFileDesc,
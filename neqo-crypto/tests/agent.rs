@@ -3,10 +3,16 @@
 use neqo_crypto::*;
 
 use std::boxed::Box;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
 
 mod handshake;
 use crate::handshake::*;
-use test_fixture::{fixture_init, now};
+use test_fixture::{
+    fixture_init, now, ANTI_REPLAY_WINDOW, DC_PRIVATE_KEY, DER_CERTIFICATE, DER_PRIVATE_KEY,
+    PEM_CERTIFICATE, PEM_PRIVATE_KEY,
+};
 
 #[test]
 fn make_client() {
@@ -125,6 +131,214 @@ fn raw() {
 
     // The server shouldn't have a client certificate.
     assert!(server.peer_certificate().is_none());
+    assert!(server.peer_certificate_chain().is_empty());
+}
+
+#[test]
+fn stapled_data() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .set_stapled_data("key", &[b"ocsp response"], Some(&b"timestamps"[..]))
+        .expect("should staple");
+    connect(&mut client, &mut server);
+
+    let mut certs = client.peer_certificate().unwrap();
+    assert_eq!(
+        certs.stapled_ocsp_responses(),
+        &Some(vec![b"ocsp response".to_vec()])
+    );
+    assert_eq!(certs.signed_cert_timestamp(), &Some(b"timestamps".to_vec()));
+}
+
+#[test]
+fn stapled_data_none() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    connect(&mut client, &mut server);
+
+    let mut certs = client.peer_certificate().unwrap();
+    assert_eq!(certs.stapled_ocsp_responses(), &None);
+    assert_eq!(certs.signed_cert_timestamp(), &None);
+}
+
+#[test]
+fn stapled_data_unknown_certificate() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert_eq!(
+        server.set_stapled_data("nothing", &[b"ocsp"], None),
+        Err(Error::CertificateLoading)
+    );
+}
+
+fn connect_with_certificate(certificate: ServerCertificate) {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::with_certificates(&[certificate]).expect("should create server");
+    connect(&mut client, &mut server);
+
+    assert_eq!(
+        client.peer_certificate_chain(),
+        vec![DER_CERTIFICATE.to_vec()]
+    );
+}
+
+#[test]
+fn der_certificate() {
+    let certificate = ServerCertificate::from_der(&[DER_CERTIFICATE], DER_PRIVATE_KEY).unwrap();
+    connect_with_certificate(certificate);
+}
+
+#[test]
+fn pem_certificate() {
+    let certificate = ServerCertificate::from_pem(PEM_CERTIFICATE, PEM_PRIVATE_KEY).unwrap();
+    connect_with_certificate(certificate);
+}
+
+#[test]
+fn bad_certificate() {
+    fixture_init();
+    assert_eq!(
+        ServerCertificate::from_pem(PEM_PRIVATE_KEY, PEM_PRIVATE_KEY).unwrap_err(),
+        Error::CertificateLoading
+    );
+    let certificate = ServerCertificate::from_der(&[DER_PRIVATE_KEY], DER_PRIVATE_KEY).unwrap();
+    assert_eq!(
+        Server::with_certificates(&[certificate]).unwrap_err(),
+        Error::CertificateLoading
+    );
+}
+
+#[test]
+fn token_key_not_found() {
+    fixture_init();
+    // No token has this name, and the database has no key with this ID.
+    for token in &["no such token", "NSS Certificate DB"] {
+        let certificate =
+            ServerCertificate::from_token(&[DER_CERTIFICATE], token, &[1; 20]).unwrap();
+        assert_eq!(
+            Server::with_certificates(&[certificate]).unwrap_err(),
+            Error::CertificateLoading
+        );
+    }
+}
+
+fn delegated_credential_server() -> Server {
+    let certificate = ServerCertificate::from_der(&[DER_CERTIFICATE], DER_PRIVATE_KEY).unwrap();
+    let dc = certificate
+        .delegate_credential(
+            DC_PRIVATE_KEY,
+            TLS_SIG_ECDSA_SECP256R1_SHA256,
+            Duration::from_secs(3600),
+            now(),
+        )
+        .expect("should delegate");
+    let certificate = certificate.with_delegated_credential(&dc, DC_PRIVATE_KEY);
+    Server::with_certificates(&[certificate]).expect("should create server")
+}
+
+#[test]
+fn delegated_credential() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.enable_delegated_credentials().unwrap();
+    let mut server = delegated_credential_server();
+    connect(&mut client, &mut server);
+    assert!(client.info().unwrap().delegated_credential());
+    assert!(!server.info().unwrap().delegated_credential());
+}
+
+#[test]
+fn delegated_credential_not_enabled() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = delegated_credential_server();
+    connect(&mut client, &mut server);
+    assert!(!client.info().unwrap().delegated_credential());
+}
+
+#[test]
+fn delegated_credential_unknown_certificate() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert_eq!(
+        server.set_delegated_credential("nothing", &[1, 2, 3], DC_PRIVATE_KEY),
+        Err(Error::CertificateLoading)
+    );
+}
+
+fn pem_server() -> Server {
+    let certificate = ServerCertificate::from_pem(PEM_CERTIFICATE, PEM_PRIVATE_KEY).unwrap();
+    Server::with_certificates(&[certificate]).expect("should create server")
+}
+
+#[test]
+fn signature_schemes() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_signature_schemes(&[TLS_SIG_RSA_PSS_RSAE_SHA256, TLS_SIG_ECDSA_SECP256R1_SHA256])
+        .unwrap();
+    let mut server = pem_server();
+    connect(&mut client, &mut server);
+
+    assert_eq!(
+        client.info().unwrap().signature_scheme(),
+        TLS_SIG_ECDSA_SECP256R1_SHA256
+    );
+    assert_eq!(
+        server.info().unwrap().signature_scheme(),
+        TLS_SIG_ECDSA_SECP256R1_SHA256
+    );
+}
+
+#[test]
+fn signature_schemes_mismatch() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    // The server has an ECDSA certificate.
+    client
+        .set_signature_schemes(&[TLS_SIG_RSA_PSS_RSAE_SHA256])
+        .unwrap();
+    let mut server = pem_server();
+    connect_fail(&mut client, &mut server);
+}
+
+#[test]
+fn export_keying_material() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert!(client
+        .export_keying_material("EXPORTER-test", &[], 32)
+        .is_err());
+    connect(&mut client, &mut server);
+
+    let exported = client
+        .export_keying_material("EXPORTER-test", b"context", 32)
+        .unwrap();
+    assert_eq!(exported.len(), 32);
+    assert_eq!(
+        exported,
+        server
+            .export_keying_material("EXPORTER-test", b"context", 32)
+            .unwrap()
+    );
+    assert_ne!(
+        exported,
+        client
+            .export_keying_material("EXPORTER-other", b"context", 32)
+            .unwrap()
+    );
+    assert_ne!(
+        exported,
+        client
+            .export_keying_material("EXPORTER-test", &[], 32)
+            .unwrap()
+    );
 }
 
 #[test]
@@ -163,6 +377,76 @@ fn p256_server() {
     assert_eq!(server.info().unwrap().key_exchange(), TLS_GRP_EC_SECP256R1);
 }
 
+#[test]
+fn mlkem_group() {
+    fixture_init();
+    let groups = [TLS_GRP_KEM_MLKEM768X25519, TLS_GRP_EC_X25519];
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_groups(&groups).expect("groups set");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server.set_groups(&groups).expect("groups set");
+
+    connect(&mut client, &mut server);
+
+    // Which of these is used depends on the version of NSS.
+    let group = client.info().unwrap().key_exchange();
+    assert!(groups.contains(&group));
+    assert_eq!(server.info().unwrap().key_exchange(), group);
+}
+
+/// Whether the server answers the first ClientHello without a
+/// HelloRetryRequest, which it does if it sends handshake records.
+fn no_hello_retry(additional_key_shares: usize) -> bool {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_groups(&[TLS_GRP_EC_X25519, TLS_GRP_EC_SECP256R1])
+        .expect("groups set");
+    client
+        .send_additional_key_shares(additional_key_shares)
+        .expect("key shares set");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .set_groups(&[TLS_GRP_EC_SECP256R1])
+        .expect("groups set");
+
+    let records = client.handshake_raw(now(), None).expect("send CH");
+    let records = forward_records(now(), &mut server, records).expect("read CH");
+    records.iter().any(|r| r.epoch == 2)
+}
+
+#[test]
+fn additional_key_share() {
+    assert!(!no_hello_retry(0));
+    assert!(no_hello_retry(1));
+}
+
+#[test]
+fn grease() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client.set_grease(true).expect("should enable GREASE");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server.set_grease(true).expect("should enable GREASE");
+    connect(&mut client, &mut server);
+
+    let grease = server.peer_grease();
+    assert!(grease.cipher_suites);
+    assert!(grease.extensions);
+    assert!(grease.groups);
+    assert!(grease.versions);
+    assert!(!client.peer_grease().any());
+}
+
+#[test]
+fn no_grease() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    connect(&mut client, &mut server);
+    assert!(!server.peer_grease().any());
+}
+
 #[test]
 fn alpn() {
     fixture_init();
@@ -216,6 +500,87 @@ fn alpn_server_pref() {
     assert_eq!(expected.as_ref(), server.info().unwrap().alpn());
 }
 
+/// Picks "custom" for one name and the first thing offered otherwise.
+#[derive(Debug)]
+struct ByName;
+
+impl AlpnSelector for ByName {
+    fn select(&self, server_name: Option<&str>, offered: &[&str]) -> Option<String> {
+        if server_name == Some("custom.example") {
+            offered
+                .iter()
+                .find(|&&v| v == "custom")
+                .map(|v| v.to_string())
+        } else {
+            offered.first().map(|v| v.to_string())
+        }
+    }
+}
+
+fn alpn_selected(server_name: &str) -> Option<String> {
+    let mut client = Client::new(server_name).expect("should create client");
+    client.set_alpn(&["h3", "custom"]).expect("should set ALPN");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .set_alpn_selector(Box::new(ByName))
+        .expect("should set ALPN selector");
+
+    // The certificate isn't for custom.example, but `connect` doesn't check.
+    connect(&mut client, &mut server);
+    assert_eq!(client.info().unwrap().alpn(), server.info().unwrap().alpn());
+    server.info().unwrap().alpn().cloned()
+}
+
+#[test]
+fn alpn_selector() {
+    fixture_init();
+    assert_eq!(alpn_selected("server.example"), Some(String::from("h3")));
+    assert_eq!(
+        alpn_selected("custom.example"),
+        Some(String::from("custom"))
+    );
+}
+
+/// Has the certificate from the fixture for one name.
+#[derive(Debug)]
+struct OneName;
+
+impl CertificateSelector for OneName {
+    fn select(&self, server_name: &str) -> Option<ServerCertificate> {
+        if server_name == "picked.example" {
+            Some(ServerCertificate::from_der(&[DER_CERTIFICATE], DER_PRIVATE_KEY).unwrap())
+        } else {
+            None
+        }
+    }
+}
+
+fn certificate_selected(server_name: &str) -> Vec<u8> {
+    let mut client = Client::new(server_name).expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let certificate = ServerCertificate::from_der(&[DER_CERTIFICATE], DER_PRIVATE_KEY).unwrap();
+    server
+        .set_certificate_for("*.wild.example", certificate)
+        .expect("should set certificate");
+    server
+        .set_certificate_selector(Box::new(OneName))
+        .expect("should set certificate selector");
+
+    // The certificates aren't for these names, but `connect` doesn't check.
+    connect(&mut client, &mut server);
+    client.peer_certificate_chain().remove(0)
+}
+
+#[test]
+fn certificate_selector() {
+    fixture_init();
+    let chosen = DER_CERTIFICATE.to_vec();
+    assert_eq!(certificate_selected("a.wild.example"), chosen);
+    assert_eq!(certificate_selected("Picked.Example"), chosen);
+    assert_ne!(certificate_selected("server.example"), chosen);
+    assert_ne!(certificate_selected("a.b.wild.example"), chosen);
+}
+
 #[test]
 fn alpn_no_protocol() {
     fixture_init();
@@ -258,12 +623,13 @@ fn alpn_server_only() {
 #[test]
 fn resume() {
     let (_, token) = resumption_setup(Resumption::WithoutZeroRtt);
+    assert!(token.expiration_time() > now());
 
     let mut client = Client::new("server.example").expect("should create second client");
     let mut server = Server::new(&["key"]).expect("should create second server");
 
     client
-        .set_resumption_token(&token[..])
+        .set_resumption_token(token.as_ref())
         .expect("should accept token");
     connect(&mut client, &mut server);
 
@@ -279,7 +645,7 @@ fn zero_rtt() {
     let mut client = Client::new("server.example").expect("should create client");
     let mut server = Server::new(&["key"]).expect("should create server");
     client
-        .set_resumption_token(&token[..])
+        .set_resumption_token(token.as_ref())
         .expect("should accept token");
     client.enable_0rtt().expect("should enable 0-RTT");
     server
@@ -295,6 +661,83 @@ fn zero_rtt() {
     assert!(server.info().unwrap().early_data_accepted());
 }
 
+#[test]
+fn max_early_data() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let anti_replay = test_fixture::anti_replay();
+    client.enable_0rtt().expect("should enable 0-RTT");
+    server
+        .enable_0rtt(
+            &anti_replay,
+            0xffff_ffff,
+            Box::new(PermissiveZeroRttChecker::default()),
+        )
+        .expect("should enable 0-RTT");
+    connect(&mut client, &mut server);
+
+    // The limit applies to the tickets that are sent after it is set.
+    server.set_max_early_data(1000).expect("should set limit");
+    let records = server
+        .send_ticket(now(), ZERO_RTT_TOKEN_DATA)
+        .expect("ticket sent");
+    client
+        .handshake_raw(now(), records.into_iter().next())
+        .expect("records ingested");
+    let token = client.resumption_token().expect("token is present").clone();
+
+    // A client that resumes with that ticket sees the limit.
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_resumption_token(token.as_ref())
+        .expect("should accept token");
+    client.enable_0rtt().expect("should enable 0-RTT");
+    client.handshake_raw(now(), None).expect("send CH");
+    assert_eq!(client.preinfo().unwrap().max_early_data(), 1000);
+}
+
+/// Attempt 0-RTT using `anti_replay`, and report whether it was accepted.
+fn zero_rtt_with(anti_replay: &AntiReplay, token: &ResumptionToken) -> bool {
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_resumption_token(token.as_ref())
+        .expect("should accept token");
+    client.enable_0rtt().expect("should enable 0-RTT");
+    server
+        .enable_0rtt(
+            anti_replay,
+            0xffff_ffff,
+            Box::new(PermissiveZeroRttChecker::default()),
+        )
+        .expect("should enable 0-RTT");
+
+    connect(&mut client, &mut server);
+    server.info().unwrap().early_data_accepted()
+}
+
+#[test]
+fn anti_replay_reconfigure() {
+    let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
+    let mut anti_replay = anti_replay.unwrap();
+
+    // A new context rejects 0-RTT for a whole window.
+    anti_replay
+        .reconfigure(now(), ANTI_REPLAY_WINDOW, 2, 4)
+        .expect("should reconfigure");
+    assert_eq!(anti_replay.window(), ANTI_REPLAY_WINDOW);
+    assert_eq!(anti_replay.k(), 2);
+    assert_eq!(anti_replay.bits(), 4);
+    assert!(!zero_rtt_with(&anti_replay, &token));
+
+    // Unless it starts that far in the past.
+    anti_replay
+        .reset(now() - ANTI_REPLAY_WINDOW)
+        .expect("should reset");
+    assert!(zero_rtt_with(&anti_replay, &token));
+}
+
 #[test]
 fn zero_rtt_no_eoed() {
     let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
@@ -303,7 +746,7 @@ fn zero_rtt_no_eoed() {
     let mut client = Client::new("server.example").expect("should create client");
     let mut server = Server::new(&["key"]).expect("should create server");
     client
-        .set_resumption_token(&token[..])
+        .set_resumption_token(token.as_ref())
         .expect("should accept token");
     client.enable_0rtt().expect("should enable 0-RTT");
     client.disable_end_of_early_data();
@@ -338,7 +781,7 @@ fn reject_zero_rtt() {
     let mut client = Client::new("server.example").expect("should create client");
     let mut server = Server::new(&["key"]).expect("should create server");
     client
-        .set_resumption_token(&token[..])
+        .set_resumption_token(token.as_ref())
         .expect("should accept token");
     client.enable_0rtt().expect("should enable 0-RTT");
     server
@@ -353,3 +796,322 @@ fn reject_zero_rtt() {
     assert!(!client.info().unwrap().early_data_accepted());
     assert!(!server.info().unwrap().early_data_accepted());
 }
+
+#[derive(Debug)]
+struct FixedVerifier {
+    verdict: CertificateVerdict,
+    calls: Rc<RefCell<usize>>,
+}
+impl CertificateVerifier for FixedVerifier {
+    fn verify(&mut self, chain: &[&[u8]], server_name: Option<&str>) -> CertificateVerdict {
+        assert_eq!(chain.len(), 1);
+        assert_eq!(server_name, Some("server.example"));
+        *self.calls.borrow_mut() += 1;
+        self.verdict
+    }
+}
+
+fn client_with_verifier(verdict: CertificateVerdict) -> (Client, Rc<RefCell<usize>>) {
+    let mut client = Client::new("server.example").expect("should create client");
+    let calls = Rc::new(RefCell::new(0));
+    client.set_certificate_verifier(Box::new(FixedVerifier {
+        verdict,
+        calls: Rc::clone(&calls),
+    }));
+    (client, calls)
+}
+
+#[test]
+fn verifier_accept() {
+    fixture_init();
+    let (mut client, calls) = client_with_verifier(CertificateVerdict::Accept);
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let client_records = client.handshake_raw(now(), None).expect("send CH");
+    let server_records =
+        forward_records(now(), &mut server, client_records).expect("read CH, send SH");
+    // The client doesn't stop for authentication, and sends its Finished.
+    let client_records = forward_records(now(), &mut client, server_records).expect("send CF");
+    assert!(!client_records.is_empty());
+    assert!(client.state().connected());
+    assert_eq!(*calls.borrow(), 1);
+
+    forward_records(now(), &mut server, client_records).expect("finish");
+    assert!(server.state().connected());
+}
+
+#[test]
+fn verifier_reject() {
+    fixture_init();
+    let (mut client, calls) = client_with_verifier(CertificateVerdict::Reject(
+        AuthenticationStatus::CertRevoked,
+    ));
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let bytes = client.handshake(now(), &[]).expect("send CH");
+    let bytes = server.handshake(now(), &bytes).expect("read CH, send SH");
+    assert!(client.handshake(now(), &bytes).is_err());
+    assert!(!client.state().connected());
+    assert!(client.alert().is_some());
+    assert_eq!(*calls.borrow(), 1);
+}
+
+#[test]
+fn verifier_pending() {
+    fixture_init();
+    let (mut client, calls) = client_with_verifier(CertificateVerdict::Pending);
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let bytes = client.handshake(now(), &[]).expect("send CH");
+    let bytes = server.handshake(now(), &bytes).expect("read CH, send SH");
+    let bytes = client.handshake(now(), &bytes).expect("wait for verifier");
+    assert!(bytes.is_empty());
+    assert_eq!(*client.state(), HandshakeState::AuthenticationPending);
+
+    // Driving the handshake while waiting doesn't ask again.
+    let bytes = client.handshake(now(), &[]).expect("still waiting");
+    assert!(bytes.is_empty());
+    assert_eq!(*client.state(), HandshakeState::AuthenticationPending);
+    assert_eq!(*calls.borrow(), 1);
+
+    client.authenticated(AuthenticationStatus::Ok);
+    let bytes = client.handshake(now(), &[]).expect("send CF");
+    assert!(client.state().connected());
+    server.handshake(now(), &bytes).expect("finish");
+    assert!(server.state().connected());
+}
+
+#[derive(Debug)]
+struct RecordingCtPolicy {
+    satisfied: bool,
+    scts: Rc<RefCell<Vec<SignedCertificateTimestamp>>>,
+}
+impl CtPolicy for RecordingCtPolicy {
+    fn check(&mut self, scts: &[SignedCertificateTimestamp], chain: &[&[u8]]) -> bool {
+        assert_eq!(chain.len(), 1);
+        *self.scts.borrow_mut() = scts.to_vec();
+        self.satisfied
+    }
+}
+
+/// Connect, with the server sending an SCT in the TLS extension.
+fn connect_with_ct_policy(satisfied: bool) -> (Client, Vec<SignedCertificateTimestamp>) {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let scts = Rc::new(RefCell::new(Vec::new()));
+    client.set_ct_policy(Box::new(RecordingCtPolicy {
+        satisfied,
+        scts: Rc::clone(&scts),
+    }));
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let mut list = vec![0, 50, 0, 48, 0];
+    list.extend_from_slice(&[1; 32]);
+    list.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 4, 3, 0, 1, 0xaa]);
+    server
+        .set_stapled_data("key", &[] as &[&[u8]], Some(&list))
+        .expect("should staple");
+    if satisfied {
+        connect(&mut client, &mut server);
+    } else {
+        connect_fail(&mut client, &mut server);
+    }
+    let scts = scts.borrow().clone();
+    (client, scts)
+}
+
+#[test]
+fn ct_policy_satisfied() {
+    let (client, scts) = connect_with_ct_policy(true);
+    assert!(client.state().connected());
+    assert_eq!(
+        scts,
+        vec![SignedCertificateTimestamp {
+            source: SctSource::TlsExtension,
+            version: 0,
+            log_id: vec![1; 32],
+            timestamp: 42,
+            extensions: Vec::new(),
+            signature_algorithm: 0x0403,
+            signature: vec![0xaa],
+        }]
+    );
+}
+
+#[test]
+fn ct_policy_unsatisfied() {
+    let (client, scts) = connect_with_ct_policy(false);
+    assert_eq!(scts.len(), 1);
+    assert!(client.alert().is_some());
+}
+
+#[test]
+fn verifier_pending_completed_later() {
+    fixture_init();
+    let (mut client, _) = client_with_verifier(CertificateVerdict::Pending);
+    let mut server = Server::new(&["key"]).expect("should create server");
+    assert!(client.pending_authentication().is_none());
+
+    let bytes = client.handshake(now(), &[]).expect("send CH");
+    let bytes = server.handshake(now(), &bytes).expect("read CH, send SH");
+    client.handshake(now(), &bytes).expect("wait for verifier");
+    let pending = client.pending_authentication().expect("should be pending");
+    assert!(!client.poll_authentication());
+
+    // Nothing changes until the outcome is known, however often the
+    // handshake is driven.
+    let bytes = client.handshake(now(), &[]).expect("still waiting");
+    assert!(bytes.is_empty());
+    assert_eq!(*client.state(), HandshakeState::AuthenticationPending);
+
+    // Any handle will do.
+    let again = client.pending_authentication().unwrap();
+    again.complete(AuthenticationStatus::Ok);
+    assert!(pending.is_complete());
+    pending.complete(AuthenticationStatus::CertRevoked);
+    let bytes = client.handshake(now(), &[]).expect("send CF");
+    assert!(client.state().connected());
+    server.handshake(now(), &bytes).expect("finish");
+    assert!(server.state().connected());
+    assert!(client.pending_authentication().is_none());
+}
+
+#[test]
+fn pending_authentication_superseded() {
+    fixture_init();
+    let (mut client, _) = client_with_verifier(CertificateVerdict::Pending);
+    let mut server = Server::new(&["key"]).expect("should create server");
+
+    let bytes = client.handshake(now(), &[]).expect("send CH");
+    let bytes = server.handshake(now(), &bytes).expect("read CH, send SH");
+    client.handshake(now(), &bytes).expect("wait for verifier");
+    let pending = client.pending_authentication().unwrap();
+
+    // Once `authenticated` is called, what the handle says doesn't count.
+    client.authenticated(AuthenticationStatus::CertRevoked);
+    pending.complete(AuthenticationStatus::Ok);
+    assert!(!client.poll_authentication());
+    assert!(client.handshake(now(), &[]).is_err());
+    assert!(!client.state().connected());
+}
+
+const PSK_ID: &[u8] = b"external-psk";
+const PSK: &[u8] = &[0x42; 32];
+
+fn psk_agents() -> (Client, Server) {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let server = Server::new(&[] as &[&str]).expect("should create server");
+    client
+        .set_external_psk(PSK_ID, PSK, TLS_HASH_SHA256)
+        .expect("client PSK");
+    (client, server)
+}
+
+#[test]
+fn external_psk() {
+    let (mut client, mut server) = psk_agents();
+    server
+        .set_external_psk(PSK_ID, PSK, TLS_HASH_SHA256)
+        .expect("server PSK");
+    connect(&mut client, &mut server);
+    // Neither side used a certificate.
+    assert!(client.peer_certificate().is_none());
+    assert!(server.peer_certificate().is_none());
+}
+
+#[test]
+fn external_psk_mismatch() {
+    let (mut client, mut server) = psk_agents();
+    server
+        .set_external_psk(PSK_ID, &[0x24; 32], TLS_HASH_SHA256)
+        .expect("server PSK");
+    connect_fail(&mut client, &mut server);
+}
+
+#[test]
+fn external_psk_bad_hash() {
+    let (mut client, _) = psk_agents();
+    assert_eq!(
+        client.set_external_psk(PSK_ID, PSK, 0),
+        Err(Error::UnsupportedHash)
+    );
+}
+
+#[derive(Debug, Default)]
+struct RecordingKeyLog {
+    lines: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+impl KeyLog for RecordingKeyLog {
+    fn log(&mut self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.lines
+            .push((label.to_string(), client_random.to_vec(), secret.to_vec()));
+    }
+}
+
+fn set_key_log(agent: &mut SecretAgent) -> Rc<RefCell<RecordingKeyLog>> {
+    let log = Rc::new(RefCell::new(RecordingKeyLog::default()));
+    agent.set_key_log(log.clone());
+    log
+}
+
+fn sorted_lines(log: &Rc<RefCell<RecordingKeyLog>>) -> Vec<(String, Vec<u8>, Vec<u8>)> {
+    let mut lines = log.borrow().lines.clone();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn key_log() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let client_log = set_key_log(&mut client);
+    let server_log = set_key_log(&mut server);
+    connect(&mut client, &mut server);
+
+    // Both ends log the same secrets, for the same client random.
+    let lines = sorted_lines(&client_log);
+    assert_eq!(lines, sorted_lines(&server_log));
+    let labels = lines.iter().map(|(l, _, _)| &l[..]).collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        vec![
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_TRAFFIC_SECRET_0",
+        ]
+    );
+    assert!(lines
+        .iter()
+        .all(|(_, r, _)| r.len() == 32 && r == &lines[0].1));
+    assert!(lines.iter().all(|(_, _, s)| s.len() == 32));
+}
+
+#[test]
+fn key_log_zero_rtt() {
+    let (anti_replay, token) = resumption_setup(Resumption::WithZeroRtt);
+
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    client
+        .set_resumption_token(token.as_ref())
+        .expect("should accept token");
+    client.enable_0rtt().expect("should enable 0-RTT");
+    server
+        .enable_0rtt(
+            anti_replay.as_ref().unwrap(),
+            0xffff_ffff,
+            Box::new(PermissiveZeroRttChecker::default()),
+        )
+        .expect("should enable 0-RTT");
+    let client_log = set_key_log(&mut client);
+    let server_log = set_key_log(&mut server);
+    connect(&mut client, &mut server);
+
+    let lines = sorted_lines(&client_log);
+    assert_eq!(lines, sorted_lines(&server_log));
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0].0, "CLIENT_EARLY_TRAFFIC_SECRET");
+}
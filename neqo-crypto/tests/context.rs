@@ -0,0 +1,40 @@
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+// These are in a file of their own, so that nothing else in the process uses
+// `init` or `init_db`.
+use neqo_crypto::{assert_initialized, NssContext};
+use std::path::Path;
+
+mod nss {
+    #![allow(clippy::redundant_static_lifetimes, dead_code, non_upper_case_globals)]
+    include!(concat!(env!("OUT_DIR"), "/nss_init.rs"));
+}
+
+fn is_initialized() -> bool {
+    unsafe { nss::NSS_IsInitialized() != 0 }
+}
+
+#[test]
+fn contexts() {
+    let db = NssContext::with_db(::test_fixture::NSS_DB_PATH).expect("open the database");
+    assert_eq!(db.db(), Some(Path::new(::test_fixture::NSS_DB_PATH)));
+    let nodb = NssContext::new().expect("initialize without a database");
+    assert_eq!(nodb.db(), None);
+    assert_initialized();
+
+    // NSS stays up until both are gone, and it can be started again after.
+    drop(db);
+    assert!(is_initialized());
+    drop(nodb);
+    assert!(!is_initialized());
+
+    let again = NssContext::new().expect("initialize again");
+    assert!(is_initialized());
+    drop(again);
+    assert!(!is_initialized());
+}
+
+#[test]
+fn missing_db() {
+    assert!(NssContext::with_db("/no/such/directory").is_err());
+}
@@ -122,7 +122,7 @@ fn zero_rtt_setup(
     }
 }
 
-pub fn resumption_setup(mode: Resumption) -> (Option<AntiReplay>, Vec<u8>) {
+pub fn resumption_setup(mode: Resumption) -> (Option<AntiReplay>, ResumptionToken) {
     fixture_init();
 
     let mut client = Client::new("server.example").expect("should create client");
@@ -2,7 +2,7 @@
 
 use neqo_crypto::constants::*;
 use neqo_crypto::hkdf;
-use neqo_crypto::hp::HpKey;
+use neqo_crypto::hp::{HpKey, SAMPLE_SIZE};
 use test_fixture::fixture_init;
 
 fn make_hp(cipher: Cipher) -> HpKey {
@@ -53,3 +53,35 @@ fn chacha20_ctr() {
     ];
     assert_eq!(mask, EXPECTED);
 }
+
+fn check_batch(cipher: Cipher) {
+    let hp = make_hp(cipher);
+    let samples = (0..4_u8)
+        .flat_map(|i| vec![i; SAMPLE_SIZE])
+        .collect::<Vec<_>>();
+    let masks = hp.mask_batch(&samples).expect("should produce masks");
+    let singles = samples
+        .chunks(SAMPLE_SIZE)
+        .flat_map(|s| hp.mask(s).expect("should produce a mask"))
+        .collect::<Vec<_>>();
+    assert_eq!(masks, singles);
+}
+
+#[test]
+fn aes128_batch() {
+    fixture_init();
+    check_batch(TLS_AES_128_GCM_SHA256);
+}
+
+#[test]
+fn aes256_batch() {
+    fixture_init();
+    check_batch(TLS_AES_256_GCM_SHA384);
+}
+
+#[cfg(feature = "chacha")]
+#[test]
+fn chacha20_ctr_batch() {
+    fixture_init();
+    check_batch(TLS_CHACHA20_POLY1305_SHA256);
+}
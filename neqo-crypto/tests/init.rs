@@ -35,3 +35,11 @@ fn init_withdb() {
         assert!(nss::NSS_IsInitialized() != 0);
     }
 }
+
+#[test]
+fn no_context_after_init() {
+    ::test_fixture::fixture_init();
+    assert_eq!(NssContext::new().unwrap_err(), Error::MixedInitMethod);
+    // NSS is still up.
+    assert_initialized();
+}
@@ -0,0 +1,554 @@
+#ifndef NEQO_H
+#define NEQO_H
+
+/* This file is generated by cbindgen from neqo-ffi.  Do not edit it by hand. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define NEQO_AF_INET 4
+
+#define NEQO_AF_INET6 6
+
+/**
+ * The result of a call.
+ */
+typedef enum {
+  NEQO_STATUS_OK = 0,
+  /**
+   * A pointer was null, or a string or address was not valid.
+   */
+  NEQO_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The buffer was too small; the length that is needed has been written.
+   */
+  NEQO_STATUS_BUFFER_TOO_SMALL = 2,
+  /**
+   * The stream does not exist.
+   */
+  NEQO_STATUS_INVALID_STREAM = 3,
+  /**
+   * The stream can't be used in that way.
+   */
+  NEQO_STATUS_STREAM_STATE = 4,
+  /**
+   * The connection is not in a state that allows this.
+   */
+  NEQO_STATUS_CONNECTION_STATE = 5,
+  /**
+   * The call could not complete now; try again after more input.
+   */
+  NEQO_STATUS_UNAVAILABLE = 6,
+  /**
+   * Any other error.
+   */
+  NEQO_STATUS_FAILED = 7,
+  /**
+   * neqo panicked.  The handle should be freed and not used again.
+   */
+  NEQO_STATUS_PANIC = 8,
+} NeqoStatus;
+
+/**
+ * What a connection wants the caller to do next.
+ */
+typedef enum {
+  /**
+   * Nothing, until more input arrives.
+   */
+  NEQO_OUTPUT_KIND_NONE = 0,
+  /**
+   * Send the datagram that was written to the buffer.
+   */
+  NEQO_OUTPUT_KIND_DATAGRAM = 1,
+  /**
+   * Ask for output again after `timeout_us` microseconds.
+   */
+  NEQO_OUTPUT_KIND_CALLBACK = 2,
+} NeqoOutputKind;
+
+typedef enum {
+  /**
+   * The server certificate has to be checked, and the result passed to
+   * `neqo_connection_authenticated`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_AUTHENTICATION_NEEDED = 0,
+  /**
+   * The peer opened `stream_id`, which is of type `stream_type`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_NEW_STREAM = 1,
+  NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_WRITABLE = 2,
+  NEQO_CONNECTION_EVENT_KIND_RECV_STREAM_READABLE = 3,
+  /**
+   * The peer reset `stream_id` with `app_error`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_RECV_STREAM_RESET = 4,
+  /**
+   * The peer asked that `stream_id` stop sending, with `app_error`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_STOP_SENDING = 5,
+  NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_COMPLETE = 6,
+  /**
+   * Another stream of type `stream_type` can be created.
+   */
+  NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_CREATABLE = 7,
+  /**
+   * The connection is now in `state`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_STATE_CHANGE = 8,
+  NEQO_CONNECTION_EVENT_KIND_ZERO_RTT_REJECTED = 9,
+  /**
+   * A datagram of `len` bytes arrived, which
+   * `neqo_connection_recv_datagram` returns.
+   */
+  NEQO_CONNECTION_EVENT_KIND_DATAGRAM = 10,
+  /**
+   * The server didn't accept the resumption token.
+   */
+  NEQO_CONNECTION_EVENT_KIND_RESUMPTION_REJECTED = 11,
+  /**
+   * 0-RTT was rejected, for `reason`.
+   */
+  NEQO_CONNECTION_EVENT_KIND_ZERO_RTT_REJECT_REASON = 12,
+} NeqoConnectionEventKind;
+
+typedef enum {
+  NEQO_STREAM_TYPE_BIDI = 0,
+  NEQO_STREAM_TYPE_UNI = 1,
+} NeqoStreamType;
+
+typedef enum {
+  NEQO_STATE_INIT = 0,
+  NEQO_STATE_WAIT_INITIAL = 1,
+  NEQO_STATE_HANDSHAKING = 2,
+  NEQO_STATE_CONNECTED = 3,
+  NEQO_STATE_CLOSING = 4,
+  NEQO_STATE_CLOSED = 5,
+} NeqoState;
+
+/**
+ * Why the server rejected 0-RTT; see `ZeroRttRejectReason`.
+ */
+typedef enum {
+  NEQO_ZERO_RTT_REJECT_REASON_TICKET_REJECTED = 0,
+  NEQO_ZERO_RTT_REJECT_REASON_ALPN = 1,
+  NEQO_ZERO_RTT_REJECT_REASON_TRANSPORT_PARAMETERS = 2,
+  NEQO_ZERO_RTT_REJECT_REASON_DECLINED = 3,
+} NeqoZeroRttRejectReason;
+
+/**
+ * Where an error that closed a connection came from.
+ */
+typedef enum {
+  /**
+   * The connection is not closing.
+   */
+  NEQO_CLOSE_ERROR_KIND_NONE = 0,
+  NEQO_CLOSE_ERROR_KIND_TRANSPORT = 1,
+  NEQO_CLOSE_ERROR_KIND_APPLICATION = 2,
+} NeqoCloseErrorKind;
+
+typedef enum {
+  /**
+   * The response headers for `stream_id` can be read.
+   */
+  NEQO_HTTP3_EVENT_KIND_HEADER_READY = 0,
+  /**
+   * More of the request body for `stream_id` can be sent.
+   */
+  NEQO_HTTP3_EVENT_KIND_DATA_WRITABLE = 1,
+  /**
+   * More of the response body for `stream_id` can be read.
+   */
+  NEQO_HTTP3_EVENT_KIND_DATA_READABLE = 2,
+  /**
+   * The server reset `stream_id` with `app_error`.
+   */
+  NEQO_HTTP3_EVENT_KIND_RESET = 3,
+  /**
+   * The server asked that the request on `stream_id` stop, with
+   * `app_error`.
+   */
+  NEQO_HTTP3_EVENT_KIND_STOP_SENDING = 4,
+  NEQO_HTTP3_EVENT_KIND_NEW_PUSH_STREAM = 5,
+  /**
+   * Another request can be made.
+   */
+  NEQO_HTTP3_EVENT_KIND_REQUESTS_CREATABLE = 6,
+  /**
+   * The server certificate has to be checked, and the result passed to
+   * `neqo_http3_client_authenticated`.
+   */
+  NEQO_HTTP3_EVENT_KIND_AUTHENTICATION_NEEDED = 7,
+  NEQO_HTTP3_EVENT_KIND_ZERO_RTT_REJECTED = 8,
+  NEQO_HTTP3_EVENT_KIND_GOAWAY_RECEIVED = 9,
+  /**
+   * The client is now in `state`.
+   */
+  NEQO_HTTP3_EVENT_KIND_STATE_CHANGE = 10,
+  /**
+   * The server didn't accept the resumption token.
+   */
+  NEQO_HTTP3_EVENT_KIND_RESUMPTION_REJECTED = 11,
+  /**
+   * 0-RTT was rejected, for `reason`.
+   */
+  NEQO_HTTP3_EVENT_KIND_ZERO_RTT_REJECT_REASON = 12,
+} NeqoHttp3EventKind;
+
+typedef enum {
+  NEQO_HTTP3_STATE_INITIALIZING = 0,
+  NEQO_HTTP3_STATE_ZERO_RTT = 1,
+  NEQO_HTTP3_STATE_CONNECTED = 2,
+  NEQO_HTTP3_STATE_GOING_AWAY = 3,
+  NEQO_HTTP3_STATE_CLOSING = 4,
+  NEQO_HTTP3_STATE_CLOSED = 5,
+} NeqoHttp3State;
+
+/**
+ * A QUIC connection.
+ */
+typedef struct NeqoConnection NeqoConnection;
+
+/**
+ * The headers of a response.
+ */
+typedef struct NeqoHeaders NeqoHeaders;
+
+/**
+ * An HTTP/3 client.
+ */
+typedef struct NeqoHttp3Client NeqoHttp3Client;
+
+/**
+ * A socket address.  `family` is `NEQO_AF_INET` or `NEQO_AF_INET6`.  An IPv4
+ * address is in the first four bytes of `ip`.  Both `ip` and `port` are in
+ * network byte order, as they would be on the wire.
+ */
+typedef struct {
+  uint8_t family;
+  uint8_t port[2];
+  uint8_t ip[16];
+} NeqoAddr;
+
+typedef struct {
+  NeqoOutputKind kind;
+  /**
+   * The length of the datagram.
+   */
+  uintptr_t len;
+  NeqoAddr source;
+  NeqoAddr destination;
+  uint64_t timeout_us;
+} NeqoOutput;
+
+/**
+ * An event.  Only the fields that the comment on `kind` names are set.
+ */
+typedef struct {
+  NeqoConnectionEventKind kind;
+  uint64_t stream_id;
+  NeqoStreamType stream_type;
+  uint64_t app_error;
+  NeqoState state;
+  uintptr_t len;
+  NeqoZeroRttRejectReason reason;
+} NeqoConnectionEvent;
+
+/**
+ * The error that closed a connection.
+ */
+typedef struct {
+  NeqoCloseErrorKind kind;
+  uint64_t code;
+} NeqoCloseError;
+
+/**
+ * An event.  Only the fields that the comment on `kind` names are set.
+ */
+typedef struct {
+  NeqoHttp3EventKind kind;
+  uint64_t stream_id;
+  uint64_t app_error;
+  NeqoHttp3State state;
+  NeqoZeroRttRejectReason reason;
+} NeqoHttp3Event;
+
+/**
+ * A header for a request; both strings are NUL-terminated.
+ */
+typedef struct {
+  const char *name;
+  const char *value;
+} NeqoHeader;
+
+/**
+ * Initialize NSS.  This has to be called once, before any connection is
+ * made.  `db_dir` is the NSS database directory; if it is null, NSS is
+ * started without a database, which is enough for a client.
+ */
+NeqoStatus neqo_init(const char *db_dir);
+
+/**
+ * Create a client connection to `server_name` at `remote`, from `local`,
+ * offering the `alpn_len` ALPN labels in `alpn`.  On success, `*conn` is set
+ * to a connection that has to be freed with `neqo_connection_free`.
+ */
+NeqoStatus neqo_connection_new_client(const char *server_name,
+                                      const char *const *alpn,
+                                      uintptr_t alpn_len,
+                                      const NeqoAddr *local,
+                                      const NeqoAddr *remote,
+                                      NeqoConnection **conn);
+
+/**
+ * Free a connection.  This doesn't send anything, so call
+ * `neqo_connection_close` first and keep it until it is closed to close
+ * cleanly.
+ */
+void neqo_connection_free(NeqoConnection *conn);
+
+/**
+ * Pass a datagram of `len` bytes that arrived at `destination` from
+ * `source` to the connection.
+ */
+NeqoStatus neqo_connection_process_input(NeqoConnection *conn,
+                                         const uint8_t *data,
+                                         uintptr_t len,
+                                         const NeqoAddr *source,
+                                         const NeqoAddr *destination);
+
+/**
+ * Ask the connection what to do next.  Call this after input, after the
+ * timeout that it last gave, and after using streams, until the kind of
+ * `*output` isn't `NEQO_OUTPUT_KIND_DATAGRAM`.  A datagram is written to the
+ * `len` bytes at `buf`.  If it doesn't fit, this fails with
+ * `NEQO_STATUS_BUFFER_TOO_SMALL`, `output->len` says how big it is, and the
+ * next call returns the same datagram.
+ */
+NeqoStatus neqo_connection_process_output(NeqoConnection *conn,
+                                          uint8_t *buf,
+                                          uintptr_t len,
+                                          NeqoOutput *output);
+
+/**
+ * Take the next event.  This returns false if there are none.
+ */
+bool neqo_connection_next_event(NeqoConnection *conn, NeqoConnectionEvent *event);
+
+/**
+ * Report whether the server certificate was acceptable, after an
+ * `NEQO_CONNECTION_EVENT_KIND_AUTHENTICATION_NEEDED` event.
+ */
+NeqoStatus neqo_connection_authenticated(NeqoConnection *conn, bool ok);
+
+/**
+ * The state of the connection.  If `error` isn't null and the connection is
+ * closing or closed, the reason is written there.
+ */
+NeqoState neqo_connection_state(const NeqoConnection *conn, NeqoCloseError *error);
+
+/**
+ * Close the connection with an application error code and an optional
+ * reason.  Keep calling `neqo_connection_process_output` until the state is
+ * `NEQO_STATE_CLOSED`.
+ */
+NeqoStatus neqo_connection_close(NeqoConnection *conn, uint64_t error, const char *reason);
+
+/**
+ * Open a stream, and write its ID to `*stream_id`.
+ */
+NeqoStatus neqo_connection_stream_create(NeqoConnection *conn,
+                                         NeqoStreamType stream_type,
+                                         uint64_t *stream_id);
+
+/**
+ * Send up to `len` bytes from `data` on a stream.  How many were taken is
+ * written to `*written`; that can be fewer than `len` when flow control
+ * doesn't allow more, in which case wait for
+ * `NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_WRITABLE`.
+ */
+NeqoStatus neqo_connection_stream_send(NeqoConnection *conn,
+                                       uint64_t stream_id,
+                                       const uint8_t *data,
+                                       uintptr_t len,
+                                       uintptr_t *written);
+
+/**
+ * Say that nothing more will be sent on a stream.
+ */
+NeqoStatus neqo_connection_stream_close_send(NeqoConnection *conn, uint64_t stream_id);
+
+/**
+ * Read up to `len` bytes from a stream into `buf`.  How many were read is
+ * written to `*read`, and `*fin` is set once the end of the stream has been
+ * read.
+ */
+NeqoStatus neqo_connection_stream_recv(NeqoConnection *conn,
+                                       uint64_t stream_id,
+                                       uint8_t *buf,
+                                       uintptr_t len,
+                                       uintptr_t *read,
+                                       bool *fin);
+
+/**
+ * Send an unreliable datagram, if the peer allows them.
+ */
+NeqoStatus neqo_connection_send_datagram(NeqoConnection *conn, const uint8_t *data, uintptr_t len);
+
+/**
+ * Take the oldest datagram that an `NEQO_CONNECTION_EVENT_KIND_DATAGRAM`
+ * event announced, writing it to the `len` bytes at `buf` and its length to
+ * `*read`.  This fails with `NEQO_STATUS_UNAVAILABLE` if there are none, and
+ * with `NEQO_STATUS_BUFFER_TOO_SMALL`, keeping the datagram, if it doesn't
+ * fit.
+ */
+NeqoStatus neqo_connection_recv_datagram(NeqoConnection *conn,
+                                         uint8_t *buf,
+                                         uintptr_t len,
+                                         uintptr_t *read);
+
+/**
+ * Create an HTTP/3 client connection to `server_name` at `remote`, from
+ * `local`, offering the `alpn_len` ALPN labels in `alpn`.  The QPACK
+ * dynamic table can hold `max_table_size` bytes, and `max_blocked_streams`
+ * streams can wait for it.  On success, `*client` is set to a client that
+ * has to be freed with `neqo_http3_client_free`.
+ */
+NeqoStatus neqo_http3_client_new(const char *server_name,
+                                 const char *const *alpn,
+                                 uintptr_t alpn_len,
+                                 const NeqoAddr *local,
+                                 const NeqoAddr *remote,
+                                 uint32_t max_table_size,
+                                 uint16_t max_blocked_streams,
+                                 NeqoHttp3Client **client);
+
+/**
+ * Free a client, as `neqo_connection_free` does a connection.
+ */
+void neqo_http3_client_free(NeqoHttp3Client *client);
+
+/**
+ * Pass a datagram of `len` bytes that arrived at `destination` from
+ * `source` to the client.
+ */
+NeqoStatus neqo_http3_client_process_input(NeqoHttp3Client *client,
+                                           const uint8_t *data,
+                                           uintptr_t len,
+                                           const NeqoAddr *source,
+                                           const NeqoAddr *destination);
+
+/**
+ * As `neqo_connection_process_output`.
+ */
+NeqoStatus neqo_http3_client_process_output(NeqoHttp3Client *client,
+                                            uint8_t *buf,
+                                            uintptr_t len,
+                                            NeqoOutput *output);
+
+/**
+ * Take the next event.  This returns false if there are none.
+ */
+bool neqo_http3_client_next_event(NeqoHttp3Client *client, NeqoHttp3Event *event);
+
+/**
+ * Report whether the server certificate was acceptable, after an
+ * `NEQO_HTTP3_EVENT_KIND_AUTHENTICATION_NEEDED` event.
+ */
+NeqoStatus neqo_http3_client_authenticated(NeqoHttp3Client *client, bool ok);
+
+/**
+ * The state of the client.  If `error` isn't null and the client is closing
+ * or closed, the reason is written there.
+ */
+NeqoHttp3State neqo_http3_client_state(const NeqoHttp3Client *client, NeqoCloseError *error);
+
+/**
+ * Close the client with an application error code and an optional reason.
+ */
+NeqoStatus neqo_http3_client_close(NeqoHttp3Client *client, uint64_t error, const char *reason);
+
+/**
+ * Make a request, with the `headers_len` extra headers in `headers`, and
+ * write the ID of its stream to `*stream_id`.  The request body, if there
+ * is one, is sent with `neqo_http3_client_send_request_body`; either way
+ * `neqo_http3_client_stream_close_send` has to be called to finish the
+ * request.
+ */
+NeqoStatus neqo_http3_client_fetch(NeqoHttp3Client *client,
+                                   const char *method,
+                                   const char *scheme,
+                                   const char *host,
+                                   const char *path,
+                                   const NeqoHeader *headers,
+                                   uintptr_t headers_len,
+                                   uint64_t *stream_id);
+
+/**
+ * Send up to `len` bytes of request body from `data`.  How many were taken
+ * is written to `*written`.
+ */
+NeqoStatus neqo_http3_client_send_request_body(NeqoHttp3Client *client,
+                                               uint64_t stream_id,
+                                               const uint8_t *data,
+                                               uintptr_t len,
+                                               uintptr_t *written);
+
+/**
+ * Finish sending a request.
+ */
+NeqoStatus neqo_http3_client_stream_close_send(NeqoHttp3Client *client, uint64_t stream_id);
+
+/**
+ * Cancel a request with an application error code.
+ */
+NeqoStatus neqo_http3_client_stream_reset(NeqoHttp3Client *client,
+                                          uint64_t stream_id,
+                                          uint64_t error);
+
+/**
+ * Read the response headers, after an `NEQO_HTTP3_EVENT_KIND_HEADER_READY`
+ * event.  On success, `*headers` is set to headers that have to be freed
+ * with `neqo_headers_free`, and `*fin` is set if there is no body.
+ */
+NeqoStatus neqo_http3_client_read_response_headers(NeqoHttp3Client *client,
+                                                   uint64_t stream_id,
+                                                   NeqoHeaders **headers,
+                                                   bool *fin);
+
+/**
+ * Read up to `len` bytes of the response body into `buf`.  How many were
+ * read is written to `*read`, and `*fin` is set once all of it has been.
+ */
+NeqoStatus neqo_http3_client_read_response_data(NeqoHttp3Client *client,
+                                                uint64_t stream_id,
+                                                uint8_t *buf,
+                                                uintptr_t len,
+                                                uintptr_t *read,
+                                                bool *fin);
+
+/**
+ * How many headers there are.
+ */
+uintptr_t neqo_headers_len(const NeqoHeaders *headers);
+
+/**
+ * Point `*name` and `*value` at the header at `index`, and write their
+ * lengths.  These are not NUL-terminated, and are valid until the headers
+ * are freed.
+ */
+NeqoStatus neqo_headers_get(const NeqoHeaders *headers,
+                            uintptr_t index,
+                            const uint8_t **name,
+                            uintptr_t *name_len,
+                            const uint8_t **value,
+                            uintptr_t *value_len);
+
+/**
+ * Free headers that `neqo_http3_client_read_response_headers` returned.
+ */
+void neqo_headers_free(NeqoHeaders *headers);
+
+#endif  /* NEQO_H */
@@ -0,0 +1,66 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{NeqoStatus, Res};
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const NEQO_AF_INET: u8 = 4;
+pub const NEQO_AF_INET6: u8 = 6;
+
+/// A socket address.  `family` is `NEQO_AF_INET` or `NEQO_AF_INET6`.  An IPv4
+/// address is in the first four bytes of `ip`.  Both `ip` and `port` are in
+/// network byte order, as they would be on the wire.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NeqoAddr {
+    pub family: u8,
+    pub port: [u8; 2],
+    pub ip: [u8; 16],
+}
+
+impl From<SocketAddr> for NeqoAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let mut ip = [0; 16];
+        let family = match addr.ip() {
+            IpAddr::V4(v4) => {
+                ip[..4].copy_from_slice(&v4.octets());
+                NEQO_AF_INET
+            }
+            IpAddr::V6(v6) => {
+                ip.copy_from_slice(&v6.octets());
+                NEQO_AF_INET6
+            }
+        };
+        NeqoAddr {
+            family,
+            port: addr.port().to_be_bytes(),
+            ip,
+        }
+    }
+}
+
+impl TryFrom<&NeqoAddr> for SocketAddr {
+    type Error = NeqoStatus;
+    fn try_from(addr: &NeqoAddr) -> Res<Self> {
+        let ip = match addr.family {
+            NEQO_AF_INET => {
+                let mut v4 = [0; 4];
+                v4.copy_from_slice(&addr.ip[..4]);
+                IpAddr::V4(Ipv4Addr::from(v4))
+            }
+            NEQO_AF_INET6 => IpAddr::V6(Ipv6Addr::from(addr.ip)),
+            _ => return Err(NeqoStatus::InvalidArgument),
+        };
+        Ok(SocketAddr::new(ip, u16::from_be_bytes(addr.port)))
+    }
+}
+
+/// Read the address at `addr`, which can't be null.
+pub(crate) unsafe fn socket_addr(addr: *const NeqoAddr) -> Res<SocketAddr> {
+    SocketAddr::try_from(addr.as_ref().ok_or(NeqoStatus::InvalidArgument)?)
+}
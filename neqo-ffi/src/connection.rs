@@ -0,0 +1,508 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The transport: a QUIC connection with streams and nothing on top.
+
+use crate::addr::socket_addr;
+use crate::output::Outbox;
+use crate::{
+    bytes, bytes_mut, guard, mut_ref, protocols, string, NeqoAddr, NeqoCloseError, NeqoOutput,
+    NeqoStatus, CID_LEN,
+};
+use neqo_common::Datagram;
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{
+    Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType, ZeroRttRejectReason,
+};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::os::raw::c_char;
+use std::ptr;
+use std::rc::Rc;
+use std::time::Instant;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoStreamType {
+    Bidi = 0,
+    Uni = 1,
+}
+
+impl From<NeqoStreamType> for StreamType {
+    fn from(st: NeqoStreamType) -> Self {
+        match st {
+            NeqoStreamType::Bidi => StreamType::BiDi,
+            NeqoStreamType::Uni => StreamType::UniDi,
+        }
+    }
+}
+
+impl From<StreamType> for NeqoStreamType {
+    fn from(st: StreamType) -> Self {
+        match st {
+            StreamType::BiDi => NeqoStreamType::Bidi,
+            StreamType::UniDi => NeqoStreamType::Uni,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoState {
+    Init = 0,
+    WaitInitial = 1,
+    Handshaking = 2,
+    Connected = 3,
+    Closing = 4,
+    Closed = 5,
+}
+
+impl From<&State> for NeqoState {
+    fn from(state: &State) -> Self {
+        match state {
+            State::Init => NeqoState::Init,
+            State::WaitInitial => NeqoState::WaitInitial,
+            State::Handshaking => NeqoState::Handshaking,
+            State::Connected => NeqoState::Connected,
+            State::Closing { .. } => NeqoState::Closing,
+            State::Closed(_) => NeqoState::Closed,
+        }
+    }
+}
+
+/// Why the server rejected 0-RTT; see `ZeroRttRejectReason`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoZeroRttRejectReason {
+    TicketRejected = 0,
+    Alpn = 1,
+    TransportParameters = 2,
+    Declined = 3,
+}
+
+impl From<ZeroRttRejectReason> for NeqoZeroRttRejectReason {
+    fn from(reason: ZeroRttRejectReason) -> Self {
+        match reason {
+            ZeroRttRejectReason::TicketRejected => NeqoZeroRttRejectReason::TicketRejected,
+            ZeroRttRejectReason::Alpn => NeqoZeroRttRejectReason::Alpn,
+            ZeroRttRejectReason::TransportParameters => {
+                NeqoZeroRttRejectReason::TransportParameters
+            }
+            ZeroRttRejectReason::Declined => NeqoZeroRttRejectReason::Declined,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoConnectionEventKind {
+    /// The server certificate has to be checked, and the result passed to
+    /// `neqo_connection_authenticated`.
+    AuthenticationNeeded = 0,
+    /// The peer opened `stream_id`, which is of type `stream_type`.
+    NewStream = 1,
+    SendStreamWritable = 2,
+    RecvStreamReadable = 3,
+    /// The peer reset `stream_id` with `app_error`.
+    RecvStreamReset = 4,
+    /// The peer asked that `stream_id` stop sending, with `app_error`.
+    SendStreamStopSending = 5,
+    SendStreamComplete = 6,
+    /// Another stream of type `stream_type` can be created.
+    SendStreamCreatable = 7,
+    /// The connection is now in `state`.
+    StateChange = 8,
+    ZeroRttRejected = 9,
+    /// A datagram of `len` bytes arrived, which
+    /// `neqo_connection_recv_datagram` returns.
+    Datagram = 10,
+    /// The server didn't accept the resumption token.
+    ResumptionRejected = 11,
+    /// 0-RTT was rejected, for `reason`.
+    ZeroRttRejectReason = 12,
+}
+
+/// An event.  Only the fields that the comment on `kind` names are set.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeqoConnectionEvent {
+    pub kind: NeqoConnectionEventKind,
+    pub stream_id: u64,
+    pub stream_type: NeqoStreamType,
+    pub app_error: u64,
+    pub state: NeqoState,
+    pub len: usize,
+    pub reason: NeqoZeroRttRejectReason,
+}
+
+impl NeqoConnectionEvent {
+    fn new(kind: NeqoConnectionEventKind) -> Self {
+        NeqoConnectionEvent {
+            kind,
+            stream_id: 0,
+            stream_type: NeqoStreamType::Bidi,
+            app_error: 0,
+            state: NeqoState::Init,
+            len: 0,
+            reason: NeqoZeroRttRejectReason::TicketRejected,
+        }
+    }
+
+    fn stream(kind: NeqoConnectionEventKind, stream_id: u64) -> Self {
+        NeqoConnectionEvent {
+            stream_id,
+            ..Self::new(kind)
+        }
+    }
+}
+
+/// A QUIC connection.
+pub struct NeqoConnection {
+    conn: Connection,
+    outbox: Outbox,
+    datagrams: VecDeque<Vec<u8>>,
+}
+
+impl NeqoConnection {
+    fn event(&mut self, e: ConnectionEvent) -> NeqoConnectionEvent {
+        use NeqoConnectionEventKind as Kind;
+        match e {
+            ConnectionEvent::AuthenticationNeeded => {
+                NeqoConnectionEvent::new(Kind::AuthenticationNeeded)
+            }
+            ConnectionEvent::NewStream {
+                stream_id,
+                stream_type,
+            } => NeqoConnectionEvent {
+                stream_type: stream_type.into(),
+                ..NeqoConnectionEvent::stream(Kind::NewStream, stream_id)
+            },
+            ConnectionEvent::SendStreamWritable { stream_id } => {
+                NeqoConnectionEvent::stream(Kind::SendStreamWritable, stream_id)
+            }
+            ConnectionEvent::RecvStreamReadable { stream_id } => {
+                NeqoConnectionEvent::stream(Kind::RecvStreamReadable, stream_id)
+            }
+            ConnectionEvent::RecvStreamReset {
+                stream_id,
+                app_error,
+            } => NeqoConnectionEvent {
+                app_error,
+                ..NeqoConnectionEvent::stream(Kind::RecvStreamReset, stream_id)
+            },
+            ConnectionEvent::SendStreamStopSending {
+                stream_id,
+                app_error,
+            } => NeqoConnectionEvent {
+                app_error,
+                ..NeqoConnectionEvent::stream(Kind::SendStreamStopSending, stream_id)
+            },
+            ConnectionEvent::SendStreamComplete { stream_id } => {
+                NeqoConnectionEvent::stream(Kind::SendStreamComplete, stream_id)
+            }
+            ConnectionEvent::SendStreamCreatable { stream_type } => NeqoConnectionEvent {
+                stream_type: stream_type.into(),
+                ..NeqoConnectionEvent::new(Kind::SendStreamCreatable)
+            },
+            ConnectionEvent::StateChange(state) => NeqoConnectionEvent {
+                state: NeqoState::from(&state),
+                ..NeqoConnectionEvent::new(Kind::StateChange)
+            },
+            ConnectionEvent::ZeroRttRejected => NeqoConnectionEvent::new(Kind::ZeroRttRejected),
+            ConnectionEvent::ZeroRttRejectReason(reason) => NeqoConnectionEvent {
+                reason: NeqoZeroRttRejectReason::from(reason),
+                ..NeqoConnectionEvent::new(Kind::ZeroRttRejectReason)
+            },
+            ConnectionEvent::ResumptionRejected => {
+                NeqoConnectionEvent::new(Kind::ResumptionRejected)
+            }
+            ConnectionEvent::Datagram(d) => {
+                let len = d.len();
+                self.datagrams.push_back(d);
+                NeqoConnectionEvent {
+                    len,
+                    ..NeqoConnectionEvent::new(Kind::Datagram)
+                }
+            }
+        }
+    }
+}
+
+/// Create a client connection to `server_name` at `remote`, from `local`,
+/// offering the `alpn_len` ALPN labels in `alpn`.  On success, `*conn` is set
+/// to a connection that has to be freed with `neqo_connection_free`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_new_client(
+    server_name: *const c_char,
+    alpn: *const *const c_char,
+    alpn_len: usize,
+    local: *const NeqoAddr,
+    remote: *const NeqoAddr,
+    conn: *mut *mut NeqoConnection,
+) -> NeqoStatus {
+    guard(|| {
+        let conn = mut_ref(conn)?;
+        *conn = ptr::null_mut();
+        let c = Connection::new_client(
+            string(server_name)?,
+            &protocols(alpn, alpn_len)?,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(CID_LEN))),
+            socket_addr(local)?,
+            socket_addr(remote)?,
+        )?;
+        *conn = Box::into_raw(Box::new(NeqoConnection {
+            conn: c,
+            outbox: Outbox::default(),
+            datagrams: VecDeque::new(),
+        }));
+        Ok(())
+    })
+}
+
+/// Free a connection.  This doesn't send anything, so call
+/// `neqo_connection_close` first and keep it until it is closed to close
+/// cleanly.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_free(conn: *mut NeqoConnection) {
+    if !conn.is_null() {
+        drop(Box::from_raw(conn));
+    }
+}
+
+/// Pass a datagram of `len` bytes that arrived at `destination` from
+/// `source` to the connection.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_process_input(
+    conn: *mut NeqoConnection,
+    data: *const u8,
+    len: usize,
+    source: *const NeqoAddr,
+    destination: *const NeqoAddr,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let d = Datagram::new(
+            socket_addr(source)?,
+            socket_addr(destination)?,
+            bytes(data, len)?,
+        );
+        c.conn.process_input(d, Instant::now());
+        Ok(())
+    })
+}
+
+/// Ask the connection what to do next.  Call this after input, after the
+/// timeout that it last gave, and after using streams, until the kind of
+/// `*output` isn't `NEQO_OUTPUT_KIND_DATAGRAM`.  A datagram is written to the
+/// `len` bytes at `buf`.  If it doesn't fit, this fails with
+/// `NEQO_STATUS_BUFFER_TOO_SMALL`, `output->len` says how big it is, and the
+/// next call returns the same datagram.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_process_output(
+    conn: *mut NeqoConnection,
+    buf: *mut u8,
+    len: usize,
+    output: *mut NeqoOutput,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let conn = &mut c.conn;
+        c.outbox.write(
+            || conn.process(None, Instant::now()),
+            bytes_mut(buf, len)?,
+            mut_ref(output)?,
+        )
+    })
+}
+
+/// Take the next event.  This returns false if there are none.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_next_event(
+    conn: *mut NeqoConnection,
+    event: *mut NeqoConnectionEvent,
+) -> bool {
+    let mut found = false;
+    let _ = guard(|| {
+        let c = mut_ref(conn)?;
+        let event = mut_ref(event)?;
+        if let Some(e) = c.conn.next_event() {
+            *event = c.event(e);
+            found = true;
+        }
+        Ok(())
+    });
+    found
+}
+
+/// Report whether the server certificate was acceptable, after an
+/// `NEQO_CONNECTION_EVENT_KIND_AUTHENTICATION_NEEDED` event.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_authenticated(
+    conn: *mut NeqoConnection,
+    ok: bool,
+) -> NeqoStatus {
+    guard(|| {
+        let status = if ok {
+            AuthenticationStatus::Ok
+        } else {
+            AuthenticationStatus::Unknown
+        };
+        mut_ref(conn)?.conn.authenticated(status, Instant::now());
+        Ok(())
+    })
+}
+
+/// The state of the connection.  If `error` isn't null and the connection is
+/// closing or closed, the reason is written there.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_state(
+    conn: *const NeqoConnection,
+    error: *mut NeqoCloseError,
+) -> NeqoState {
+    let state = match conn.as_ref() {
+        Some(c) => c.conn.state(),
+        None => return NeqoState::Closed,
+    };
+    if let Some(error) = error.as_mut() {
+        *error = match state {
+            State::Closing { error, .. } | State::Closed(error) => NeqoCloseError::from(error),
+            _ => NeqoCloseError::default(),
+        };
+    }
+    NeqoState::from(state)
+}
+
+/// Close the connection with an application error code and an optional
+/// reason.  Keep calling `neqo_connection_process_output` until the state is
+/// `NEQO_STATE_CLOSED`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_close(
+    conn: *mut NeqoConnection,
+    error: u64,
+    reason: *const c_char,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let reason = if reason.is_null() {
+            ""
+        } else {
+            string(reason)?
+        };
+        c.conn.close(Instant::now(), error, reason);
+        Ok(())
+    })
+}
+
+/// Open a stream, and write its ID to `*stream_id`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_create(
+    conn: *mut NeqoConnection,
+    stream_type: NeqoStreamType,
+    stream_id: *mut u64,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let stream_id = mut_ref(stream_id)?;
+        *stream_id = c.conn.stream_create(stream_type.into())?;
+        Ok(())
+    })
+}
+
+/// Send up to `len` bytes from `data` on a stream.  How many were taken is
+/// written to `*written`; that can be fewer than `len` when flow control
+/// doesn't allow more, in which case wait for
+/// `NEQO_CONNECTION_EVENT_KIND_SEND_STREAM_WRITABLE`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_send(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    data: *const u8,
+    len: usize,
+    written: *mut usize,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let written = mut_ref(written)?;
+        *written = c.conn.stream_send(stream_id, bytes(data, len)?)?;
+        Ok(())
+    })
+}
+
+/// Say that nothing more will be sent on a stream.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_close_send(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+) -> NeqoStatus {
+    guard(|| {
+        mut_ref(conn)?.conn.stream_close_send(stream_id)?;
+        Ok(())
+    })
+}
+
+/// Read up to `len` bytes from a stream into `buf`.  How many were read is
+/// written to `*read`, and `*fin` is set once the end of the stream has been
+/// read.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_recv(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    buf: *mut u8,
+    len: usize,
+    read: *mut usize,
+    fin: *mut bool,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let read = mut_ref(read)?;
+        let fin = mut_ref(fin)?;
+        let (r, f) = c.conn.stream_recv(stream_id, bytes_mut(buf, len)?)?;
+        *read = r;
+        *fin = f;
+        Ok(())
+    })
+}
+
+/// Send an unreliable datagram, if the peer allows them.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_send_datagram(
+    conn: *mut NeqoConnection,
+    data: *const u8,
+    len: usize,
+) -> NeqoStatus {
+    guard(|| {
+        mut_ref(conn)?.conn.send_datagram(bytes(data, len)?)?;
+        Ok(())
+    })
+}
+
+/// Take the oldest datagram that an `NEQO_CONNECTION_EVENT_KIND_DATAGRAM`
+/// event announced, writing it to the `len` bytes at `buf` and its length to
+/// `*read`.  This fails with `NEQO_STATUS_UNAVAILABLE` if there are none, and
+/// with `NEQO_STATUS_BUFFER_TOO_SMALL`, keeping the datagram, if it doesn't
+/// fit.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_recv_datagram(
+    conn: *mut NeqoConnection,
+    buf: *mut u8,
+    len: usize,
+    read: *mut usize,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(conn)?;
+        let read = mut_ref(read)?;
+        let buf = bytes_mut(buf, len)?;
+        let d = c.datagrams.front().ok_or(NeqoStatus::Unavailable)?;
+        *read = d.len();
+        if d.len() > buf.len() {
+            return Err(NeqoStatus::BufferTooSmall);
+        }
+        buf[..d.len()].copy_from_slice(d);
+        c.datagrams.pop_front();
+        Ok(())
+    })
+}
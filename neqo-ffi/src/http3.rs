@@ -0,0 +1,509 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An HTTP/3 client.
+
+use crate::addr::socket_addr;
+use crate::output::Outbox;
+use crate::{
+    bytes, bytes_mut, guard, mut_ref, protocols, string, NeqoAddr, NeqoCloseError, NeqoOutput,
+    NeqoStatus, NeqoZeroRttRejectReason, Res, CID_LEN,
+};
+use neqo_common::Datagram;
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Header, Http3Client, Http3ClientEvent, Http3State};
+use neqo_transport::FixedConnectionIdManager;
+
+use std::cell::RefCell;
+use std::os::raw::c_char;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::time::Instant;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoHttp3State {
+    Initializing = 0,
+    ZeroRtt = 1,
+    Connected = 2,
+    GoingAway = 3,
+    Closing = 4,
+    Closed = 5,
+}
+
+impl From<&Http3State> for NeqoHttp3State {
+    fn from(state: &Http3State) -> Self {
+        match state {
+            Http3State::Initializing => NeqoHttp3State::Initializing,
+            Http3State::ZeroRtt => NeqoHttp3State::ZeroRtt,
+            Http3State::Connected => NeqoHttp3State::Connected,
+            Http3State::GoingAway => NeqoHttp3State::GoingAway,
+            Http3State::Closing(_) => NeqoHttp3State::Closing,
+            Http3State::Closed(_) => NeqoHttp3State::Closed,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoHttp3EventKind {
+    /// The response headers for `stream_id` can be read.
+    HeaderReady = 0,
+    /// More of the request body for `stream_id` can be sent.
+    DataWritable = 1,
+    /// More of the response body for `stream_id` can be read.
+    DataReadable = 2,
+    /// The server reset `stream_id` with `app_error`.
+    Reset = 3,
+    /// The server asked that the request on `stream_id` stop, with
+    /// `app_error`.
+    StopSending = 4,
+    NewPushStream = 5,
+    /// Another request can be made.
+    RequestsCreatable = 6,
+    /// The server certificate has to be checked, and the result passed to
+    /// `neqo_http3_client_authenticated`.
+    AuthenticationNeeded = 7,
+    ZeroRttRejected = 8,
+    GoawayReceived = 9,
+    /// The client is now in `state`.
+    StateChange = 10,
+    /// The server didn't accept the resumption token.
+    ResumptionRejected = 11,
+    /// 0-RTT was rejected, for `reason`.
+    ZeroRttRejectReason = 12,
+}
+
+/// An event.  Only the fields that the comment on `kind` names are set.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeqoHttp3Event {
+    pub kind: NeqoHttp3EventKind,
+    pub stream_id: u64,
+    pub app_error: u64,
+    pub state: NeqoHttp3State,
+    pub reason: NeqoZeroRttRejectReason,
+}
+
+impl NeqoHttp3Event {
+    fn new(kind: NeqoHttp3EventKind) -> Self {
+        NeqoHttp3Event {
+            kind,
+            stream_id: 0,
+            app_error: 0,
+            state: NeqoHttp3State::Initializing,
+            reason: NeqoZeroRttRejectReason::TicketRejected,
+        }
+    }
+
+    fn stream(kind: NeqoHttp3EventKind, stream_id: u64) -> Self {
+        NeqoHttp3Event {
+            stream_id,
+            ..Self::new(kind)
+        }
+    }
+}
+
+impl From<Http3ClientEvent> for NeqoHttp3Event {
+    fn from(e: Http3ClientEvent) -> Self {
+        use NeqoHttp3EventKind as Kind;
+        match e {
+            Http3ClientEvent::HeaderReady { stream_id } => {
+                Self::stream(Kind::HeaderReady, stream_id)
+            }
+            Http3ClientEvent::DataWritable { stream_id } => {
+                Self::stream(Kind::DataWritable, stream_id)
+            }
+            Http3ClientEvent::DataReadable { stream_id } => {
+                Self::stream(Kind::DataReadable, stream_id)
+            }
+            Http3ClientEvent::Reset { stream_id, error } => NeqoHttp3Event {
+                app_error: error,
+                ..Self::stream(Kind::Reset, stream_id)
+            },
+            Http3ClientEvent::StopSending { stream_id, error } => NeqoHttp3Event {
+                app_error: error,
+                ..Self::stream(Kind::StopSending, stream_id)
+            },
+            Http3ClientEvent::NewPushStream { stream_id } => {
+                Self::stream(Kind::NewPushStream, stream_id)
+            }
+            Http3ClientEvent::RequestsCreatable => Self::new(Kind::RequestsCreatable),
+            Http3ClientEvent::AuthenticationNeeded => Self::new(Kind::AuthenticationNeeded),
+            Http3ClientEvent::ZeroRttRejected => Self::new(Kind::ZeroRttRejected),
+            Http3ClientEvent::ZeroRttRejectReason(reason) => NeqoHttp3Event {
+                reason: NeqoZeroRttRejectReason::from(reason),
+                ..Self::new(Kind::ZeroRttRejectReason)
+            },
+            Http3ClientEvent::ResumptionRejected => Self::new(Kind::ResumptionRejected),
+            Http3ClientEvent::GoawayReceived => Self::new(Kind::GoawayReceived),
+            Http3ClientEvent::StateChange(state) => NeqoHttp3Event {
+                state: NeqoHttp3State::from(&state),
+                ..Self::new(Kind::StateChange)
+            },
+        }
+    }
+}
+
+/// A header for a request; both strings are NUL-terminated.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct NeqoHeader {
+    pub name: *const c_char,
+    pub value: *const c_char,
+}
+
+/// The headers of a response.
+pub struct NeqoHeaders {
+    headers: Vec<Header>,
+}
+
+/// An HTTP/3 client.
+pub struct NeqoHttp3Client {
+    client: Http3Client,
+    outbox: Outbox,
+}
+
+/// Create an HTTP/3 client connection to `server_name` at `remote`, from
+/// `local`, offering the `alpn_len` ALPN labels in `alpn`.  The QPACK
+/// dynamic table can hold `max_table_size` bytes, and `max_blocked_streams`
+/// streams can wait for it.  On success, `*client` is set to a client that
+/// has to be freed with `neqo_http3_client_free`.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn neqo_http3_client_new(
+    server_name: *const c_char,
+    alpn: *const *const c_char,
+    alpn_len: usize,
+    local: *const NeqoAddr,
+    remote: *const NeqoAddr,
+    max_table_size: u32,
+    max_blocked_streams: u16,
+    client: *mut *mut NeqoHttp3Client,
+) -> NeqoStatus {
+    guard(|| {
+        let client = mut_ref(client)?;
+        *client = ptr::null_mut();
+        let c = Http3Client::new(
+            string(server_name)?,
+            &protocols(alpn, alpn_len)?,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(CID_LEN))),
+            socket_addr(local)?,
+            socket_addr(remote)?,
+            max_table_size,
+            max_blocked_streams,
+        )?;
+        *client = Box::into_raw(Box::new(NeqoHttp3Client {
+            client: c,
+            outbox: Outbox::default(),
+        }));
+        Ok(())
+    })
+}
+
+/// Free a client, as `neqo_connection_free` does a connection.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_free(client: *mut NeqoHttp3Client) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Pass a datagram of `len` bytes that arrived at `destination` from
+/// `source` to the client.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_process_input(
+    client: *mut NeqoHttp3Client,
+    data: *const u8,
+    len: usize,
+    source: *const NeqoAddr,
+    destination: *const NeqoAddr,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let d = Datagram::new(
+            socket_addr(source)?,
+            socket_addr(destination)?,
+            bytes(data, len)?,
+        );
+        let now = Instant::now();
+        c.client.process_input(d, now);
+        c.client.process_http3(now);
+        Ok(())
+    })
+}
+
+/// As `neqo_connection_process_output`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_process_output(
+    client: *mut NeqoHttp3Client,
+    buf: *mut u8,
+    len: usize,
+    output: *mut NeqoOutput,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let client = &mut c.client;
+        c.outbox.write(
+            || {
+                let now = Instant::now();
+                client.process_timer(now);
+                client.process(None, now)
+            },
+            bytes_mut(buf, len)?,
+            mut_ref(output)?,
+        )
+    })
+}
+
+/// Take the next event.  This returns false if there are none.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_next_event(
+    client: *mut NeqoHttp3Client,
+    event: *mut NeqoHttp3Event,
+) -> bool {
+    let mut found = false;
+    let _ = guard(|| {
+        let c = mut_ref(client)?;
+        let event = mut_ref(event)?;
+        if let Some(e) = c.client.next_event() {
+            *event = NeqoHttp3Event::from(e);
+            found = true;
+        }
+        Ok(())
+    });
+    found
+}
+
+/// Report whether the server certificate was acceptable, after an
+/// `NEQO_HTTP3_EVENT_KIND_AUTHENTICATION_NEEDED` event.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_authenticated(
+    client: *mut NeqoHttp3Client,
+    ok: bool,
+) -> NeqoStatus {
+    guard(|| {
+        let status = if ok {
+            AuthenticationStatus::Ok
+        } else {
+            AuthenticationStatus::Unknown
+        };
+        mut_ref(client)?
+            .client
+            .authenticated(status, Instant::now());
+        Ok(())
+    })
+}
+
+/// The state of the client.  If `error` isn't null and the client is closing
+/// or closed, the reason is written there.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_state(
+    client: *const NeqoHttp3Client,
+    error: *mut NeqoCloseError,
+) -> NeqoHttp3State {
+    let state = match client.as_ref() {
+        Some(c) => c.client.state(),
+        None => return NeqoHttp3State::Closed,
+    };
+    if let Some(error) = error.as_mut() {
+        *error = match &state {
+            Http3State::Closing(e) | Http3State::Closed(e) => NeqoCloseError::from(*e),
+            _ => NeqoCloseError::default(),
+        };
+    }
+    NeqoHttp3State::from(&state)
+}
+
+/// Close the client with an application error code and an optional reason.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_close(
+    client: *mut NeqoHttp3Client,
+    error: u64,
+    reason: *const c_char,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let reason = if reason.is_null() {
+            ""
+        } else {
+            string(reason)?
+        };
+        c.client.close(Instant::now(), error, reason);
+        Ok(())
+    })
+}
+
+/// Make a request, with the `headers_len` extra headers in `headers`, and
+/// write the ID of its stream to `*stream_id`.  The request body, if there
+/// is one, is sent with `neqo_http3_client_send_request_body`; either way
+/// `neqo_http3_client_stream_close_send` has to be called to finish the
+/// request.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn neqo_http3_client_fetch(
+    client: *mut NeqoHttp3Client,
+    method: *const c_char,
+    scheme: *const c_char,
+    host: *const c_char,
+    path: *const c_char,
+    headers: *const NeqoHeader,
+    headers_len: usize,
+    stream_id: *mut u64,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let stream_id = mut_ref(stream_id)?;
+        let headers = if headers_len == 0 {
+            Vec::new()
+        } else if headers.is_null() {
+            return Err(NeqoStatus::InvalidArgument);
+        } else {
+            slice::from_raw_parts(headers, headers_len)
+                .iter()
+                .map(|h| Ok((string(h.name)?.to_string(), string(h.value)?.to_string())))
+                .collect::<Res<Vec<_>>>()?
+        };
+        *stream_id = c.client.fetch(
+            string(method)?,
+            string(scheme)?,
+            string(host)?,
+            string(path)?,
+            &headers,
+        )?;
+        Ok(())
+    })
+}
+
+/// Send up to `len` bytes of request body from `data`.  How many were taken
+/// is written to `*written`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_send_request_body(
+    client: *mut NeqoHttp3Client,
+    stream_id: u64,
+    data: *const u8,
+    len: usize,
+    written: *mut usize,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let written = mut_ref(written)?;
+        *written = c.client.send_request_body(stream_id, bytes(data, len)?)?;
+        Ok(())
+    })
+}
+
+/// Finish sending a request.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_stream_close_send(
+    client: *mut NeqoHttp3Client,
+    stream_id: u64,
+) -> NeqoStatus {
+    guard(|| {
+        mut_ref(client)?.client.stream_close_send(stream_id)?;
+        Ok(())
+    })
+}
+
+/// Cancel a request with an application error code.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_stream_reset(
+    client: *mut NeqoHttp3Client,
+    stream_id: u64,
+    error: u64,
+) -> NeqoStatus {
+    guard(|| {
+        mut_ref(client)?.client.stream_reset(stream_id, error)?;
+        Ok(())
+    })
+}
+
+/// Read the response headers, after an `NEQO_HTTP3_EVENT_KIND_HEADER_READY`
+/// event.  On success, `*headers` is set to headers that have to be freed
+/// with `neqo_headers_free`, and `*fin` is set if there is no body.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_read_response_headers(
+    client: *mut NeqoHttp3Client,
+    stream_id: u64,
+    headers: *mut *mut NeqoHeaders,
+    fin: *mut bool,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let headers = mut_ref(headers)?;
+        let fin = mut_ref(fin)?;
+        *headers = ptr::null_mut();
+        let (h, f) = c.client.read_response_headers(stream_id)?;
+        *headers = Box::into_raw(Box::new(NeqoHeaders { headers: h }));
+        *fin = f;
+        Ok(())
+    })
+}
+
+/// Read up to `len` bytes of the response body into `buf`.  How many were
+/// read is written to `*read`, and `*fin` is set once all of it has been.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_http3_client_read_response_data(
+    client: *mut NeqoHttp3Client,
+    stream_id: u64,
+    buf: *mut u8,
+    len: usize,
+    read: *mut usize,
+    fin: *mut bool,
+) -> NeqoStatus {
+    guard(|| {
+        let c = mut_ref(client)?;
+        let read = mut_ref(read)?;
+        let fin = mut_ref(fin)?;
+        let (r, f) =
+            c.client
+                .read_response_data(Instant::now(), stream_id, bytes_mut(buf, len)?)?;
+        *read = r;
+        *fin = f;
+        Ok(())
+    })
+}
+
+/// How many headers there are.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_headers_len(headers: *const NeqoHeaders) -> usize {
+    headers.as_ref().map_or(0, |h| h.headers.len())
+}
+
+/// Point `*name` and `*value` at the header at `index`, and write their
+/// lengths.  These are not NUL-terminated, and are valid until the headers
+/// are freed.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_headers_get(
+    headers: *const NeqoHeaders,
+    index: usize,
+    name: *mut *const u8,
+    name_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> NeqoStatus {
+    guard(|| {
+        let headers = headers.as_ref().ok_or(NeqoStatus::InvalidArgument)?;
+        let (n, v) = headers
+            .headers
+            .get(index)
+            .ok_or(NeqoStatus::InvalidArgument)?;
+        *mut_ref(name)? = n.as_ptr();
+        *mut_ref(name_len)? = n.len();
+        *mut_ref(value)? = v.as_ptr();
+        *mut_ref(value_len)? = v.len();
+        Ok(())
+    })
+}
+
+/// Free headers that `neqo_http3_client_read_response_headers` returned.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_headers_free(headers: *mut NeqoHeaders) {
+    if !headers.is_null() {
+        drop(Box::from_raw(headers));
+    }
+}
@@ -0,0 +1,225 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A C interface to neqo, so that applications that aren't written in Rust,
+// and bindings for other languages, can use it.  The header that goes with
+// this is in include/neqo.h.
+//
+// Connections are opaque handles that the caller owns and has to free.  The
+// caller owns the socket too: it passes every datagram it receives in, and
+// asks for datagrams to send until it is told to wait.  Time is taken from
+// the system clock on each call, and timeouts are given in microseconds.
+//
+// # Safety
+//
+// Every pointer that is passed in has to be either null or valid for the
+// length that goes with it, for the duration of the call.  Strings are
+// UTF-8 and NUL-terminated.  A handle can't be used from more than one
+// thread at a time, or after it has been freed.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod addr;
+mod connection;
+mod http3;
+mod output;
+
+pub use self::addr::{NeqoAddr, NEQO_AF_INET, NEQO_AF_INET6};
+pub use self::connection::*;
+pub use self::http3::*;
+pub use self::output::{NeqoOutput, NeqoOutputKind};
+
+use neqo_transport::{CloseError, ConnectionError};
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+
+/// The result of a call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoStatus {
+    Ok = 0,
+    /// A pointer was null, or a string or address was not valid.
+    InvalidArgument = 1,
+    /// The buffer was too small; the length that is needed has been written.
+    BufferTooSmall = 2,
+    /// The stream does not exist.
+    InvalidStream = 3,
+    /// The stream can't be used in that way.
+    StreamState = 4,
+    /// The connection is not in a state that allows this.
+    ConnectionState = 5,
+    /// The call could not complete now; try again after more input.
+    Unavailable = 6,
+    /// Any other error.
+    Failed = 7,
+    /// neqo panicked.  The handle should be freed and not used again.
+    Panic = 8,
+}
+
+impl From<neqo_transport::Error> for NeqoStatus {
+    fn from(e: neqo_transport::Error) -> Self {
+        match e {
+            neqo_transport::Error::InvalidStreamId => NeqoStatus::InvalidStream,
+            neqo_transport::Error::StreamStateError | neqo_transport::Error::FinalSizeError => {
+                NeqoStatus::StreamState
+            }
+            neqo_transport::Error::ConnectionState => NeqoStatus::ConnectionState,
+            neqo_transport::Error::InvalidInput => NeqoStatus::InvalidArgument,
+            _ => NeqoStatus::Failed,
+        }
+    }
+}
+
+impl From<neqo_http3::Error> for NeqoStatus {
+    fn from(e: neqo_http3::Error) -> Self {
+        match e {
+            neqo_http3::Error::InvalidStreamId => NeqoStatus::InvalidStream,
+            neqo_http3::Error::AlreadyClosed => NeqoStatus::ConnectionState,
+            neqo_http3::Error::Unavailable => NeqoStatus::Unavailable,
+            neqo_http3::Error::TransportError(e) => NeqoStatus::from(e),
+            _ => NeqoStatus::Failed,
+        }
+    }
+}
+
+/// Where an error that closed a connection came from.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoCloseErrorKind {
+    /// The connection is not closing.
+    None = 0,
+    Transport = 1,
+    Application = 2,
+}
+
+/// The error that closed a connection.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeqoCloseError {
+    pub kind: NeqoCloseErrorKind,
+    pub code: u64,
+}
+
+impl Default for NeqoCloseError {
+    fn default() -> Self {
+        NeqoCloseError {
+            kind: NeqoCloseErrorKind::None,
+            code: 0,
+        }
+    }
+}
+
+impl From<&ConnectionError> for NeqoCloseError {
+    fn from(e: &ConnectionError) -> Self {
+        match e {
+            ConnectionError::Transport(e) => NeqoCloseError {
+                kind: NeqoCloseErrorKind::Transport,
+                code: e.code(),
+            },
+            ConnectionError::Application(code) => NeqoCloseError {
+                kind: NeqoCloseErrorKind::Application,
+                code: *code,
+            },
+        }
+    }
+}
+
+impl From<CloseError> for NeqoCloseError {
+    fn from(e: CloseError) -> Self {
+        match e {
+            CloseError::Transport(code) => NeqoCloseError {
+                kind: NeqoCloseErrorKind::Transport,
+                code,
+            },
+            CloseError::Application(code) => NeqoCloseError {
+                kind: NeqoCloseErrorKind::Application,
+                code,
+            },
+        }
+    }
+}
+
+type Res<T> = Result<T, NeqoStatus>;
+
+/// The length of the connection IDs that connections made here use.
+const CID_LEN: usize = 8;
+
+/// Run `f`, making sure that a panic doesn't unwind into the caller.
+fn guard<F: FnOnce() -> Res<()>>(f: F) -> NeqoStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => NeqoStatus::Ok,
+        Ok(Err(e)) => e,
+        Err(_) => NeqoStatus::Panic,
+    }
+}
+
+/// A handle or an out parameter, which can't be null.
+unsafe fn mut_ref<'a, T>(p: *mut T) -> Res<&'a mut T> {
+    p.as_mut().ok_or(NeqoStatus::InvalidArgument)
+}
+
+unsafe fn string<'a>(p: *const c_char) -> Res<&'a str> {
+    if p.is_null() {
+        return Err(NeqoStatus::InvalidArgument);
+    }
+    CStr::from_ptr(p)
+        .to_str()
+        .map_err(|_| NeqoStatus::InvalidArgument)
+}
+
+unsafe fn bytes<'a>(p: *const u8, len: usize) -> Res<&'a [u8]> {
+    if len == 0 {
+        Ok(&[])
+    } else if p.is_null() {
+        Err(NeqoStatus::InvalidArgument)
+    } else {
+        Ok(slice::from_raw_parts(p, len))
+    }
+}
+
+unsafe fn bytes_mut<'a>(p: *mut u8, len: usize) -> Res<&'a mut [u8]> {
+    if len == 0 {
+        Ok(&mut [])
+    } else if p.is_null() {
+        Err(NeqoStatus::InvalidArgument)
+    } else {
+        Ok(slice::from_raw_parts_mut(p, len))
+    }
+}
+
+/// The ALPN labels in `alpn`, which holds `count` strings.
+unsafe fn protocols<'a>(alpn: *const *const c_char, count: usize) -> Res<Vec<&'a str>> {
+    if count == 0 || alpn.is_null() {
+        return Err(NeqoStatus::InvalidArgument);
+    }
+    slice::from_raw_parts(alpn, count)
+        .iter()
+        .map(|&p| string(p))
+        .collect()
+}
+
+/// Initialize NSS.  This has to be called once, before any connection is
+/// made.  `db_dir` is the NSS database directory; if it is null, NSS is
+/// started without a database, which is enough for a client.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_init(db_dir: *const c_char) -> NeqoStatus {
+    guard(|| {
+        if db_dir.is_null() {
+            neqo_crypto::init();
+        } else {
+            let dir = Path::new(string(db_dir)?);
+            if !dir.is_dir() {
+                return Err(NeqoStatus::InvalidArgument);
+            }
+            neqo_crypto::init_db(dir);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,90 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{NeqoAddr, NeqoStatus, Res};
+use neqo_common::Datagram;
+use neqo_transport::Output;
+
+use std::convert::TryFrom;
+
+/// What a connection wants the caller to do next.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoOutputKind {
+    /// Nothing, until more input arrives.
+    None = 0,
+    /// Send the datagram that was written to the buffer.
+    Datagram = 1,
+    /// Ask for output again after `timeout_us` microseconds.
+    Callback = 2,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeqoOutput {
+    pub kind: NeqoOutputKind,
+    /// The length of the datagram.
+    pub len: usize,
+    pub source: NeqoAddr,
+    pub destination: NeqoAddr,
+    pub timeout_us: u64,
+}
+
+impl Default for NeqoOutput {
+    fn default() -> Self {
+        NeqoOutput {
+            kind: NeqoOutputKind::None,
+            len: 0,
+            source: NeqoAddr::default(),
+            destination: NeqoAddr::default(),
+            timeout_us: 0,
+        }
+    }
+}
+
+/// Holds on to a datagram that didn't fit in the caller's buffer, so that it
+/// can be collected with a bigger one.
+#[derive(Debug, Default)]
+pub(crate) struct Outbox {
+    pending: Option<Datagram>,
+}
+
+impl Outbox {
+    /// Write the output from `produce`, or the datagram that is waiting, to
+    /// `buf` and `out`.
+    pub fn write(
+        &mut self,
+        produce: impl FnOnce() -> Output,
+        buf: &mut [u8],
+        out: &mut NeqoOutput,
+    ) -> Res<()> {
+        let output = match self.pending.take() {
+            Some(d) => Output::Datagram(d),
+            None => produce(),
+        };
+        *out = NeqoOutput::default();
+        match output {
+            Output::None => Ok(()),
+            Output::Callback(t) => {
+                out.kind = NeqoOutputKind::Callback;
+                out.timeout_us = u64::try_from(t.as_micros()).unwrap_or(u64::max_value());
+                Ok(())
+            }
+            Output::Datagram(d) => {
+                out.kind = NeqoOutputKind::Datagram;
+                out.len = d.len();
+                out.source = NeqoAddr::from(d.source());
+                out.destination = NeqoAddr::from(d.destination());
+                if d.len() > buf.len() {
+                    self.pending = Some(d);
+                    return Err(NeqoStatus::BufferTooSmall);
+                }
+                buf[..d.len()].copy_from_slice(&d);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_common::Datagram;
+use neqo_ffi::*;
+use neqo_http3::{Http3Server, Http3ServerEvent};
+use neqo_transport::{Connection, Output, State};
+use test_fixture::*;
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::ptr;
+
+const BUF_SIZE: usize = 2048;
+
+/// The ALPN labels and server name as C strings, which have to be held while
+/// the pointers to them are used.
+struct Names {
+    server_name: CString,
+    alpn: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl Names {
+    fn new() -> Self {
+        let alpn: Vec<_> = DEFAULT_ALPN
+            .iter()
+            .map(|a| CString::new(*a).unwrap())
+            .collect();
+        let ptrs = alpn.iter().map(|a| a.as_ptr()).collect();
+        Names {
+            server_name: CString::new(DEFAULT_SERVER_NAME).unwrap(),
+            alpn,
+            ptrs,
+        }
+    }
+}
+
+fn init() {
+    let db = CString::new(NSS_DB_PATH).unwrap();
+    assert_eq!(unsafe { neqo_init(db.as_ptr()) }, NeqoStatus::Ok);
+}
+
+fn addr() -> NeqoAddr {
+    NeqoAddr::from(loopback())
+}
+
+fn new_client() -> *mut NeqoConnection {
+    init();
+    let names = Names::new();
+    let mut conn = ptr::null_mut();
+    let status = unsafe {
+        neqo_connection_new_client(
+            names.server_name.as_ptr(),
+            names.ptrs.as_ptr(),
+            names.alpn.len(),
+            &addr(),
+            &addr(),
+            &mut conn,
+        )
+    };
+    assert_eq!(status, NeqoStatus::Ok);
+    assert!(!conn.is_null());
+    conn
+}
+
+/// Take datagrams from `output` until it has no more, and give them to
+/// `server`.  Return what the server sent back.
+fn exchange(
+    output: impl Fn(&mut [u8], &mut NeqoOutput) -> NeqoStatus,
+    mut server: impl FnMut(Option<Datagram>) -> Output,
+) -> Vec<Datagram> {
+    let mut buf = [0; BUF_SIZE];
+    let mut out = NeqoOutput::default();
+    let mut replies = Vec::new();
+    loop {
+        assert_eq!(output(&mut buf, &mut out), NeqoStatus::Ok);
+        if out.kind != NeqoOutputKind::Datagram {
+            break;
+        }
+        let d = Datagram::new(loopback(), loopback(), &buf[..out.len]);
+        replies.extend(server(Some(d)).dgram());
+    }
+    replies.extend(server(None).dgram());
+    replies
+}
+
+fn connect_transport(client: *mut NeqoConnection, server: &mut Connection) {
+    let mut event = NeqoConnectionEvent {
+        kind: NeqoConnectionEventKind::ZeroRttRejected,
+        stream_id: 0,
+        stream_type: NeqoStreamType::Bidi,
+        app_error: 0,
+        state: NeqoState::Init,
+        len: 0,
+        reason: NeqoZeroRttRejectReason::TicketRejected,
+    };
+    for _ in 0..10 {
+        let replies = exchange(
+            |buf, out| unsafe {
+                neqo_connection_process_output(client, buf.as_mut_ptr(), buf.len(), out)
+            },
+            |d| server.process(d, now()),
+        );
+        for d in replies {
+            let status = unsafe {
+                neqo_connection_process_input(client, d.as_ptr(), d.len(), &addr(), &addr())
+            };
+            assert_eq!(status, NeqoStatus::Ok);
+        }
+        while unsafe { neqo_connection_next_event(client, &mut event) } {
+            if event.kind == NeqoConnectionEventKind::AuthenticationNeeded {
+                assert_eq!(
+                    unsafe { neqo_connection_authenticated(client, true) },
+                    NeqoStatus::Ok
+                );
+            }
+        }
+        let state = unsafe { neqo_connection_state(client, ptr::null_mut()) };
+        if state == NeqoState::Connected && *server.state() == State::Connected {
+            return;
+        }
+    }
+    panic!("the connection was not established");
+}
+
+#[test]
+fn transport_stream() {
+    let client = new_client();
+    let mut server = default_server();
+    connect_transport(client, &mut server);
+
+    let mut stream_id = 0;
+    let mut written = 0;
+    let request = b"hello";
+    unsafe {
+        assert_eq!(
+            neqo_connection_stream_create(client, NeqoStreamType::Bidi, &mut stream_id),
+            NeqoStatus::Ok
+        );
+        assert_eq!(
+            neqo_connection_stream_send(
+                client,
+                stream_id,
+                request.as_ptr(),
+                request.len(),
+                &mut written
+            ),
+            NeqoStatus::Ok
+        );
+        assert_eq!(written, request.len());
+        assert_eq!(
+            neqo_connection_stream_close_send(client, stream_id),
+            NeqoStatus::Ok
+        );
+    }
+
+    let replies = exchange(
+        |buf, out| unsafe {
+            neqo_connection_process_output(client, buf.as_mut_ptr(), buf.len(), out)
+        },
+        |d| server.process(d, now()),
+    );
+    let mut buf = [0; 16];
+    let (len, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+    assert_eq!(&buf[..len], request);
+    assert!(fin);
+
+    // Answer, and check that the client sees the answer.
+    server.stream_send(stream_id, b"world").unwrap();
+    server.stream_close_send(stream_id).unwrap();
+    for d in replies
+        .into_iter()
+        .chain(server.process(None, now()).dgram())
+    {
+        unsafe { neqo_connection_process_input(client, d.as_ptr(), d.len(), &addr(), &addr()) };
+    }
+    let mut read = 0;
+    let mut fin = false;
+    let status = unsafe {
+        neqo_connection_stream_recv(
+            client,
+            stream_id,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut read,
+            &mut fin,
+        )
+    };
+    assert_eq!(status, NeqoStatus::Ok);
+    assert_eq!(&buf[..read], b"world");
+    assert!(fin);
+
+    unsafe { neqo_connection_free(client) };
+}
+
+#[test]
+fn small_buffer() {
+    let client = new_client();
+    let mut out = NeqoOutput::default();
+    let mut small = [0; 10];
+    let status = unsafe {
+        neqo_connection_process_output(client, small.as_mut_ptr(), small.len(), &mut out)
+    };
+    assert_eq!(status, NeqoStatus::BufferTooSmall);
+    assert_eq!(out.kind, NeqoOutputKind::Datagram);
+    let needed = out.len;
+    assert!(needed > small.len());
+
+    // The same datagram comes out when there is space for it.
+    let mut buf = vec![0; needed];
+    let status =
+        unsafe { neqo_connection_process_output(client, buf.as_mut_ptr(), buf.len(), &mut out) };
+    assert_eq!(status, NeqoStatus::Ok);
+    assert_eq!(out.len, needed);
+    assert_eq!(SocketAddr::try_from(&out.destination), Ok(loopback()));
+
+    // Now the client waits for the server.
+    let status =
+        unsafe { neqo_connection_process_output(client, buf.as_mut_ptr(), buf.len(), &mut out) };
+    assert_eq!(status, NeqoStatus::Ok);
+    assert_eq!(out.kind, NeqoOutputKind::Callback);
+    assert!(out.timeout_us > 0);
+
+    unsafe { neqo_connection_free(client) };
+}
+
+#[test]
+fn bad_arguments() {
+    init();
+    let names = Names::new();
+    let mut conn = ptr::null_mut();
+    let bad = NeqoAddr {
+        family: 5,
+        ..addr()
+    };
+    let status = unsafe {
+        neqo_connection_new_client(
+            names.server_name.as_ptr(),
+            names.ptrs.as_ptr(),
+            names.alpn.len(),
+            &bad,
+            &addr(),
+            &mut conn,
+        )
+    };
+    assert_eq!(status, NeqoStatus::InvalidArgument);
+    assert!(conn.is_null());
+
+    let status = unsafe {
+        neqo_connection_new_client(
+            ptr::null(),
+            names.ptrs.as_ptr(),
+            names.alpn.len(),
+            &addr(),
+            &addr(),
+            &mut conn,
+        )
+    };
+    assert_eq!(status, NeqoStatus::InvalidArgument);
+
+    let client = new_client();
+    let mut read = 0;
+    let mut fin = false;
+    let mut buf = [0; 16];
+    let status = unsafe {
+        neqo_connection_stream_recv(client, 4, buf.as_mut_ptr(), buf.len(), &mut read, &mut fin)
+    };
+    assert_eq!(status, NeqoStatus::InvalidStream);
+    unsafe { neqo_connection_free(client) };
+}
+
+fn connect_http3(client: *mut NeqoHttp3Client, server: &mut Http3Server) {
+    let mut event = NeqoHttp3Event {
+        kind: NeqoHttp3EventKind::ZeroRttRejected,
+        stream_id: 0,
+        app_error: 0,
+        state: NeqoHttp3State::Initializing,
+        reason: NeqoZeroRttRejectReason::TicketRejected,
+    };
+    for _ in 0..10 {
+        let replies = exchange(
+            |buf, out| unsafe {
+                neqo_http3_client_process_output(client, buf.as_mut_ptr(), buf.len(), out)
+            },
+            |d| server.process(d, now()),
+        );
+        for d in replies {
+            unsafe {
+                neqo_http3_client_process_input(client, d.as_ptr(), d.len(), &addr(), &addr())
+            };
+        }
+        while unsafe { neqo_http3_client_next_event(client, &mut event) } {
+            if event.kind == NeqoHttp3EventKind::AuthenticationNeeded {
+                unsafe { neqo_http3_client_authenticated(client, true) };
+            }
+        }
+        let state = unsafe { neqo_http3_client_state(client, ptr::null_mut()) };
+        if state == NeqoHttp3State::Connected {
+            return;
+        }
+    }
+    panic!("the client did not connect");
+}
+
+#[test]
+fn http3_fetch() {
+    init();
+    let names = Names::new();
+    let mut client = ptr::null_mut();
+    let status = unsafe {
+        neqo_http3_client_new(
+            names.server_name.as_ptr(),
+            names.ptrs.as_ptr(),
+            names.alpn.len(),
+            &addr(),
+            &addr(),
+            100,
+            100,
+            &mut client,
+        )
+    };
+    assert_eq!(status, NeqoStatus::Ok);
+    let mut server = default_http3_server();
+    connect_http3(client, &mut server);
+
+    let method = CString::new("GET").unwrap();
+    let scheme = CString::new("https").unwrap();
+    let host = CString::new("something.com").unwrap();
+    let path = CString::new("/").unwrap();
+    let mut stream_id = 0;
+    unsafe {
+        assert_eq!(
+            neqo_http3_client_fetch(
+                client,
+                method.as_ptr(),
+                scheme.as_ptr(),
+                host.as_ptr(),
+                path.as_ptr(),
+                ptr::null(),
+                0,
+                &mut stream_id,
+            ),
+            NeqoStatus::Ok
+        );
+        assert_eq!(
+            neqo_http3_client_stream_close_send(client, stream_id),
+            NeqoStatus::Ok
+        );
+    }
+
+    let mut replies = exchange(
+        |buf, out| unsafe {
+            neqo_http3_client_process_output(client, buf.as_mut_ptr(), buf.len(), out)
+        },
+        |d| server.process(d, now()),
+    );
+    while let Some(event) = server.next_event() {
+        if let Http3ServerEvent::Headers { mut request, .. } = event {
+            request
+                .set_response(
+                    &[(String::from(":status"), String::from("200"))],
+                    b"abc".to_vec(),
+                )
+                .unwrap();
+        }
+    }
+    replies.extend(server.process(None, now()).dgram());
+    for d in replies {
+        unsafe { neqo_http3_client_process_input(client, d.as_ptr(), d.len(), &addr(), &addr()) };
+    }
+
+    let mut event = NeqoHttp3Event {
+        kind: NeqoHttp3EventKind::ZeroRttRejected,
+        stream_id: 0,
+        app_error: 0,
+        state: NeqoHttp3State::Initializing,
+        reason: NeqoZeroRttRejectReason::TicketRejected,
+    };
+    let mut status_found = false;
+    let mut body = Vec::new();
+    while unsafe { neqo_http3_client_next_event(client, &mut event) } {
+        match event.kind {
+            NeqoHttp3EventKind::HeaderReady => {
+                let mut headers = ptr::null_mut();
+                let mut fin = true;
+                unsafe {
+                    assert_eq!(
+                        neqo_http3_client_read_response_headers(
+                            client,
+                            stream_id,
+                            &mut headers,
+                            &mut fin
+                        ),
+                        NeqoStatus::Ok
+                    );
+                    assert!(!fin);
+                    assert_eq!(neqo_headers_len(headers), 1);
+                    let (mut name, mut name_len) = (ptr::null(), 0);
+                    let (mut value, mut value_len) = (ptr::null(), 0);
+                    assert_eq!(
+                        neqo_headers_get(
+                            headers,
+                            0,
+                            &mut name,
+                            &mut name_len,
+                            &mut value,
+                            &mut value_len
+                        ),
+                        NeqoStatus::Ok
+                    );
+                    assert_eq!(std::slice::from_raw_parts(name, name_len), b":status");
+                    assert_eq!(std::slice::from_raw_parts(value, value_len), b"200");
+                    neqo_headers_free(headers);
+                }
+                status_found = true;
+            }
+            NeqoHttp3EventKind::DataReadable => {
+                let mut buf = [0; 16];
+                let mut read = 0;
+                let mut fin = false;
+                unsafe {
+                    assert_eq!(
+                        neqo_http3_client_read_response_data(
+                            client,
+                            stream_id,
+                            buf.as_mut_ptr(),
+                            buf.len(),
+                            &mut read,
+                            &mut fin
+                        ),
+                        NeqoStatus::Ok
+                    );
+                }
+                body.extend_from_slice(&buf[..read]);
+                assert!(fin);
+            }
+            _ => {}
+        }
+    }
+    assert!(status_found);
+    assert_eq!(body, b"abc");
+
+    unsafe { neqo_http3_client_free(client) };
+}
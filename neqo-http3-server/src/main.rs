@@ -8,8 +8,11 @@
 
 use neqo_common::{qdebug, qinfo, Datagram};
 use neqo_crypto::{init_db, AntiReplay};
-use neqo_http3::{Http3Server, Http3ServerEvent};
+use neqo_http3::{
+    ClientRequestStream, FieldSource, Header, HeaderIndexing, Http3Server, Http3ServerEvent,
+};
 use neqo_transport::{FixedConnectionIdManager, Output};
+use neqo_udp::Socket;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -22,11 +25,13 @@ use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
-use mio::net::UdpSocket;
+use mio::unix::EventedFd;
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::{Builder, Timeout, Timer};
 
 const TIMER_TOKEN: Token = Token(0xffff_ffff);
+/// Requests for this path get a JSON description of the request in response.
+const ECHO_PATH: &str = "/echo";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neqo-http3-server", about = "A basic HTTP3 server.")]
@@ -54,6 +59,15 @@ struct Args {
     ///
     /// This server still only does HTTP3 no matter what the ALPN says.
     alpn: String,
+
+    #[structopt(name = "batch-size", long, default_value = "32")]
+    /// The number of datagrams to send or receive with one system call,
+    /// if segmentation offload is not available.
+    batch_size: usize,
+
+    #[structopt(name = "io-uring", long)]
+    /// Send and receive with io_uring.  This needs the io-uring feature.
+    io_uring: bool,
 }
 
 impl Args {
@@ -66,6 +80,97 @@ impl Args {
     }
 }
 
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(v: Option<String>) -> String {
+    v.map_or_else(|| String::from("null"), |v| json_string(&v))
+}
+
+fn json_source(source: FieldSource) -> String {
+    match source {
+        FieldSource::Static(i) => format!("{{\"static\": {}}}", i),
+        FieldSource::Dynamic(i) => format!("{{\"dynamic\": {}}}", i),
+        FieldSource::Literal { huffman } => {
+            format!("{{\"literal\": {{\"huffman\": {}}}}}", huffman)
+        }
+    }
+}
+
+/// Each header, with how it was represented if that is known.
+fn json_headers(headers: &[Header], indexing: &[HeaderIndexing]) -> String {
+    headers
+        .iter()
+        .enumerate()
+        .map(|(i, (k, v))| {
+            let indexing = indexing.get(i).map_or_else(
+                || String::from("null"),
+                |h| {
+                    format!(
+                        "{{\"name\": {}, \"value\": {}, \"never_indexed\": {}}}",
+                        json_source(h.name),
+                        json_source(h.value),
+                        h.never_indexed
+                    )
+                },
+            );
+            format!(
+                "{{\"name\": {}, \"value\": {}, \"qpack\": {}}}",
+                json_string(k),
+                json_string(v),
+                indexing
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describe a request: the headers that were received and how QPACK
+/// represented each, who sent them, and the state of the connection they
+/// arrived on.
+fn echo_response(request: &ClientRequestStream, headers: &[Header]) -> Vec<u8> {
+    let headers = json_headers(headers, &request.header_indexing());
+    let qpack = request.qpack_info();
+    format!(
+        concat!(
+            "{{\"headers\": [{}], ",
+            "\"peer\": {}, ",
+            "\"alpn\": {}, ",
+            "\"quic_version\": \"{:#x}\", ",
+            "\"tls_version\": {}, ",
+            "\"qpack\": {{\"max_table_size\": {}, \"max_blocked_streams\": {}, ",
+            "\"decoder_capacity\": {}, \"decoder_insert_count\": {}, ",
+            "\"encoder_capacity\": {}}}}}\n"
+        ),
+        headers,
+        json_option(request.remote_address().map(|a| a.to_string())),
+        json_option(request.alpn()),
+        request.quic_version(),
+        json_option(request.tls_version().map(|v| format!("{:#x}", v))),
+        qpack.max_table_size,
+        qpack.max_blocked_streams,
+        qpack.decoder_capacity,
+        qpack.decoder_insert_count,
+        qpack.encoder_capacity,
+    )
+    .into_bytes()
+}
+
 fn process_events(server: &mut Http3Server) {
     while let Some(event) = server.next_event() {
         eprintln!("Event: {:?}", event);
@@ -79,7 +184,12 @@ fn process_events(server: &mut Http3Server) {
 
                 let default_ret = b"Hello World".to_vec();
 
+                let mut content_type = "text/plain";
                 let response = match headers.iter().find(|&(k, _)| k == ":path") {
+                    Some((_, path)) if path == ECHO_PATH => {
+                        content_type = "application/json";
+                        echo_response(&request, &headers)
+                    }
                     Some((_, path)) if !path.is_empty() => {
                         match path.trim_matches(|p| p == '/').parse::<usize>() {
                             Ok(v) => vec![b'a'; v],
@@ -93,6 +203,7 @@ fn process_events(server: &mut Http3Server) {
                     .set_response(
                         &[
                             (String::from(":status"), String::from("200")),
+                            (String::from("content-type"), String::from(content_type)),
                             (String::from("content-length"), response.len().to_string()),
                         ],
                         response,
@@ -107,17 +218,10 @@ fn process_events(server: &mut Http3Server) {
     }
 }
 
-fn emit_packets(sockets: &mut Vec<UdpSocket>, out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
+fn emit_packets(sockets: &mut [Socket], out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
     for s in sockets {
-        if let Some(dgrams) = out_dgrams.get(&s.local_addr().unwrap()) {
-            for d in dgrams {
-                let sent = s
-                    .send_to(d, &d.destination())
-                    .expect("Error sending datagram");
-                if sent != d.len() {
-                    eprintln!("Unable to send all {} bytes of datagram", d.len());
-                }
-            }
+        if let Some(dgrams) = out_dgrams.get(&s.local_addr()) {
+            s.send(dgrams).expect("Error sending datagrams");
         }
     }
 }
@@ -171,21 +275,23 @@ fn main() -> Result<(), io::Error> {
     poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
 
     for (i, host) in hosts.iter().enumerate() {
-        let socket = match UdpSocket::bind(&host) {
+        let mut socket = match Socket::bind(&host) {
             Err(err) => {
                 eprintln!("Unable to bind UDP socket: {}", err);
                 exit(1)
             }
             Ok(s) => s,
         };
-
-        let local_addr = match socket.local_addr() {
-            Err(err) => {
-                eprintln!("Socket local address not bound: {}", err);
+        socket.set_nonblocking(true)?;
+        socket.set_batch_size(args.batch_size);
+        if args.io_uring {
+            if let Err(err) = socket.use_io_uring() {
+                eprintln!("Unable to use io_uring: {}", err);
                 exit(1)
             }
-            Ok(s) => s,
-        };
+        }
+
+        let local_addr = socket.local_addr();
 
         let res = socket.only_v6();
         let also_v4 = if res.is_ok() && !res.unwrap() {
@@ -199,7 +305,7 @@ fn main() -> Result<(), io::Error> {
         );
 
         poll.register(
-            &socket,
+            &EventedFd(&socket.poll_fd()),
             Token(i),
             Ready::readable() | Ready::writable(),
             PollOpt::edge(),
@@ -224,8 +330,6 @@ fn main() -> Result<(), io::Error> {
         );
     }
 
-    let buf = &mut [0u8; 2048];
-
     let mut events = Events::with_capacity(1024);
 
     loop {
@@ -236,31 +340,28 @@ fn main() -> Result<(), io::Error> {
                 while let Some(inx) = timer.poll() {
                     if let Some(socket) = sockets.get(inx) {
                         qinfo!("Timer expired for {:?}", socket);
-                        if let Some((server, svr_timeout)) =
-                            servers.get_mut(&socket.local_addr().unwrap())
-                        {
+                        if let Some((server, svr_timeout)) = servers.get_mut(&socket.local_addr()) {
                             process(
                                 server,
                                 svr_timeout,
                                 inx,
                                 None,
                                 &mut out_dgrams
-                                    .entry(socket.local_addr().unwrap())
+                                    .entry(socket.local_addr())
                                     .or_insert_with(Vec::new),
                                 &mut timer,
                             );
                         }
                     }
                 }
-            } else if let Some(socket) = sockets.get(event.token().0) {
-                let local_addr = hosts[event.token().0];
-
+            } else if let Some(socket) = sockets.get_mut(event.token().0) {
                 if !event.readiness().is_readable() {
                     continue;
                 }
 
+                let local_addr = socket.local_addr();
                 loop {
-                    let (sz, remote_addr) = match socket.recv_from(&mut buf[..]) {
+                    let dgrams = match socket.recv() {
                         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
                         Err(err) => {
                             eprintln!("UDP recv error: {:?}", err);
@@ -269,28 +370,22 @@ fn main() -> Result<(), io::Error> {
                         Ok(res) => res,
                     };
 
-                    if sz == buf.len() {
-                        eprintln!("Might have received more than {} bytes", buf.len());
-                    }
-
-                    if sz == 0 {
-                        eprintln!("zero length datagram received?");
-                    } else if let Some((server, svr_timeout)) =
-                        servers.get_mut(&socket.local_addr().unwrap())
-                    {
-                        let out = out_dgrams
-                            .entry(socket.local_addr().unwrap())
-                            .or_insert_with(Vec::new);
-                        process(
-                            server,
-                            svr_timeout,
-                            event.token().0,
-                            Some(Datagram::new(remote_addr, local_addr, &buf[..sz])),
-                            out,
-                            &mut timer,
-                        );
-                        process_events(server);
-                        process(server, svr_timeout, event.token().0, None, out, &mut timer);
+                    for dgram in dgrams {
+                        if dgram.is_empty() {
+                            eprintln!("zero length datagram received?");
+                        } else if let Some((server, svr_timeout)) = servers.get_mut(&local_addr) {
+                            let out = out_dgrams.entry(local_addr).or_insert_with(Vec::new);
+                            process(
+                                server,
+                                svr_timeout,
+                                event.token().0,
+                                Some(dgram),
+                                out,
+                                &mut timer,
+                            );
+                            process_events(server);
+                            process(server, svr_timeout, event.token().0, None, out, &mut timer);
+                        }
                     }
                 }
             }
@@ -299,3 +394,52 @@ fn main() -> Result<(), io::Error> {
         emit_packets(&mut sockets, &out_dgrams);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("\n\r\t"), "\"\\n\\r\\t\"");
+        assert_eq!(
+            json_string("\u{0}\u{1f}\u{7f}"),
+            "\"\\u0000\\u001f\\u007f\""
+        );
+        assert_eq!(json_string("caf\u{e9}"), "\"caf\u{e9}\"");
+    }
+
+    #[test]
+    fn json_headers_indexing() {
+        let headers = [
+            (String::from(":method"), String::from("GET")),
+            (String::from("x-\"quoted\""), String::from("v")),
+            (String::from("late"), String::new()),
+        ];
+        let indexing = [
+            HeaderIndexing {
+                name: FieldSource::Static(17),
+                value: FieldSource::Static(17),
+                never_indexed: false,
+            },
+            HeaderIndexing {
+                name: FieldSource::Dynamic(3),
+                value: FieldSource::Literal { huffman: true },
+                never_indexed: true,
+            },
+        ];
+        assert_eq!(
+            json_headers(&headers, &indexing),
+            concat!(
+                r#"{"name": ":method", "value": "GET", "qpack": "#,
+                r#"{"name": {"static": 17}, "value": {"static": 17}, "never_indexed": false}}, "#,
+                r#"{"name": "x-\"quoted\"", "value": "v", "qpack": "#,
+                r#"{"name": {"dynamic": 3}, "value": {"literal": {"huffman": true}}, "#,
+                r#""never_indexed": true}}, "#,
+                r#"{"name": "late", "value": "", "qpack": null}"#
+            )
+        );
+    }
+}
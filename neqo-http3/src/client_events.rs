@@ -5,12 +5,10 @@
 // except according to those terms.
 
 use crate::connection::Http3State;
-use neqo_common::matches;
-use neqo_transport::{AppError, StreamType};
+use neqo_common::{matches, EventQueue, QueuedEvent};
+use neqo_transport::{AppError, StreamType, ZeroRttRejectReason};
 
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::rc::Rc;
+use std::task::{Context, Poll};
 
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub enum Http3ClientEvent {
@@ -32,15 +30,25 @@ pub enum Http3ClientEvent {
     AuthenticationNeeded,
     /// Zero Rtt has been rejected.
     ZeroRttRejected,
+    /// Why Zero Rtt was rejected.
+    ZeroRttRejectReason(ZeroRttRejectReason),
+    /// The server didn't accept the resumption token.
+    ResumptionRejected,
     /// Client has received a GOAWAY frame
     GoawayReceived,
     /// Connection state change.
     StateChange(Http3State),
 }
 
+impl QueuedEvent for Http3ClientEvent {
+    fn coalesces_with(&self, _queued: &Self) -> bool {
+        false
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Http3ClientEvents {
-    events: Rc<RefCell<VecDeque<Http3ClientEvent>>>,
+    events: EventQueue<Http3ClientEvent>,
 }
 
 impl Http3ClientEvents {
@@ -84,32 +92,46 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::ZeroRttRejected);
     }
 
+    pub fn zero_rtt_reject_reason(&self, reason: ZeroRttRejectReason) {
+        self.insert(Http3ClientEvent::ZeroRttRejectReason(reason));
+    }
+
+    pub fn resumption_rejected(&self) {
+        self.insert(Http3ClientEvent::ResumptionRejected);
+    }
+
     pub fn goaway_received(&self) {
         self.remove(|evt| matches!(evt, Http3ClientEvent::RequestsCreatable));
         self.insert(Http3ClientEvent::GoawayReceived);
     }
 
     pub fn events(&self) -> impl Iterator<Item = Http3ClientEvent> {
-        self.events.replace(VecDeque::new()).into_iter()
+        self.events.events()
     }
 
     pub fn has_events(&self) -> bool {
-        !self.events.borrow().is_empty()
+        self.events.has_events()
     }
 
     pub fn next_event(&self) -> Option<Http3ClientEvent> {
-        self.events.borrow_mut().pop_front()
+        self.events.next_event()
+    }
+
+    /// Take the next event, or arrange for the task to be woken when there
+    /// is one.  Only the task that called last is woken.
+    pub fn poll_event(&self, cx: &mut Context) -> Poll<Http3ClientEvent> {
+        self.events.poll_event(cx)
     }
 
     fn insert(&self, event: Http3ClientEvent) {
-        self.events.borrow_mut().push_back(event);
+        self.events.insert(event);
     }
 
     fn remove<F>(&self, f: F)
     where
         F: Fn(&Http3ClientEvent) -> bool,
     {
-        self.events.borrow_mut().retain(|evt| !f(evt))
+        self.events.remove(f)
     }
 
     pub fn reset(&self, stream_id: u64, error: AppError) {
@@ -120,7 +142,7 @@ impl Http3ClientEvents {
     pub fn connection_state_change(&self, state: Http3State) {
         // If closing, existing events no longer relevant.
         match state {
-            Http3State::Closing { .. } | Http3State::Closed(_) => self.events.borrow_mut().clear(),
+            Http3State::Closing { .. } | Http3State::Closed(_) => self.events.clear(),
             _ => (),
         }
         self.insert(Http3ClientEvent::StateChange(state));
@@ -14,6 +14,7 @@ use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
 use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
 use neqo_transport::{AppError, CloseError, Connection, State, StreamType};
 use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::mem;
 
@@ -51,6 +52,7 @@ struct LocalSettings {
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Http3State {
     Initializing,
     ZeroRtt,
@@ -159,9 +161,16 @@ impl<T: Http3Transaction> Http3Connection<T> {
         }
         self.qpack_decoder.send(conn)?;
         self.qpack_encoder.send(conn)?;
+        self.report_memory(conn);
         Ok(())
     }
 
+    /// The dynamic tables count toward the memory budget of the connection.
+    fn report_memory(&self, conn: &mut Connection) {
+        let tables = self.qpack_encoder.table_used() + self.qpack_decoder.table_used();
+        conn.set_app_memory(usize::try_from(tables).unwrap_or(usize::max_value()));
+    }
+
     pub fn set_resumption_settings(
         &mut self,
         conn: &mut Connection,
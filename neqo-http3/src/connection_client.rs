@@ -6,19 +6,25 @@
 
 use crate::client_events::{Http3ClientEvent, Http3ClientEvents};
 use crate::connection::{HandleReadableOutput, Http3Connection, Http3State, Http3Transaction};
+#[cfg(feature = "futures")]
+use crate::event_stream::Http3ClientEventStream;
 use crate::hframe::HFrame;
 use crate::hsettings_frame::HSettings;
 use crate::transaction_client::TransactionClient;
 use crate::Header;
-use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Datagram, Decoder, Encoder};
-use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus, SecretAgentInfo};
+use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Clock, Datagram, Decoder, Encoder};
+use neqo_crypto::{
+    agent::CertificateInfo, AuthenticationStatus, CertificateVerifier, CtPolicy, KeyLog,
+    PendingAuthentication, SecretAgentInfo,
+};
 use neqo_transport::{
-    AppError, Connection, ConnectionEvent, ConnectionIdManager, Output, Role, StreamType,
+    AppError, Connection, ConnectionEvent, ConnectionIdManager, HandshakeInfo, Output, Role,
+    StreamType,
 };
 use std::cell::RefCell;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
@@ -71,15 +77,69 @@ impl Http3Client {
         self.conn.tls_info()
     }
 
+    /// What the TLS handshake settled on, once it is complete.
+    pub fn handshake_info(&self) -> Option<HandshakeInfo> {
+        self.conn.handshake_info()
+    }
+
+    /// How many more bytes of stream data can be sent in 0-RTT, which
+    /// includes the encoded headers of requests as well as their bodies.  What
+    /// doesn't fit is sent once the handshake completes.
+    pub fn early_data_remaining(&self) -> usize {
+        self.conn.early_data_remaining()
+    }
+
     /// Get the peer's certificate.
     pub fn peer_certificate(&self) -> Option<CertificateInfo> {
         self.conn.peer_certificate()
     }
 
+    /// The DER-encoded certificates that the server presented, starting with
+    /// its own.
+    pub fn peer_certificate_chain(&self) -> Vec<Vec<u8>> {
+        self.conn.peer_certificate_chain()
+    }
+
+    /// Have `verifier` check the server's certificates.
+    pub fn set_certificate_verifier(&mut self, verifier: Box<dyn CertificateVerifier>) {
+        self.conn.set_certificate_verifier(verifier);
+    }
+
+    /// Have `policy` check the signed certificate timestamps that the server
+    /// presents.
+    pub fn set_ct_policy(&mut self, policy: Box<dyn CtPolicy>) {
+        self.conn.set_ct_policy(policy);
+    }
+
+    /// Accept a delegated credential from the server.  This has to be set
+    /// before the handshake starts.
+    pub fn enable_delegated_credentials(&mut self) -> Res<()> {
+        self.conn.enable_delegated_credentials()?;
+        Ok(())
+    }
+
+    /// Send TLS GREASE values, or not.  This has to be set before the
+    /// handshake starts.
+    pub fn set_grease(&mut self, enabled: bool) -> Res<()> {
+        self.conn.set_grease(enabled)?;
+        Ok(())
+    }
+
+    /// Tell `log` about the TLS secrets.
+    pub fn set_key_log(&mut self, log: Rc<RefCell<dyn KeyLog>>) {
+        self.conn.set_key_log(log);
+    }
+
     pub fn authenticated(&mut self, status: AuthenticationStatus, now: Instant) {
         self.conn.authenticated(status, now);
     }
 
+    /// A handle for completing authentication later, in place of calling
+    /// `authenticated`.
+    pub fn pending_authentication(&mut self) -> Option<PendingAuthentication> {
+        self.conn.pending_authentication()
+    }
+
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if let Some(token) = self.conn.resumption_token() {
             if let Some(settings) = self.base_handler.get_settings() {
@@ -246,6 +306,12 @@ impl Http3Client {
         self.events.next_event()
     }
 
+    /// The events of this client as a `Stream`.
+    #[cfg(feature = "futures")]
+    pub fn event_stream(&self) -> Http3ClientEventStream {
+        Http3ClientEventStream::new(self.events.clone())
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         if let Some(d) = dgram {
@@ -269,6 +335,33 @@ impl Http3Client {
         &mut self.conn
     }
 
+    /// Set the clock that `wakeup` uses, which is the connection's clock.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.conn.set_clock(clock);
+    }
+
+    /// Handle `dgrams`, any timer that has expired, and anything that is
+    /// waiting to be sent, all at the time on the clock.  Datagrams to send
+    /// are added to `out`.  This is `Connection::wakeup` with the HTTP/3
+    /// processing in between.
+    pub fn wakeup(
+        &mut self,
+        dgrams: impl IntoIterator<Item = Datagram>,
+        out: &mut Vec<Datagram>,
+    ) -> Option<Duration> {
+        let now = self.conn.now();
+        self.conn.process_multiple_input(dgrams, now);
+        self.conn.process_timer(now);
+        self.process_http3(now);
+        loop {
+            match self.process_output(now) {
+                Output::Datagram(d) => out.push(d),
+                Output::Callback(delay) => return Some(delay),
+                Output::None => return None,
+            }
+        }
+    }
+
     pub fn process_http3(&mut self, now: Instant) {
         qtrace!([self], "Process http3 internal.");
         match self.base_handler.state() {
@@ -371,6 +464,12 @@ impl Http3Client {
                     self.base_handler.handle_zero_rtt_rejected()?;
                     self.events.zero_rtt_rejected();
                 }
+                ConnectionEvent::ZeroRttRejectReason(reason) => {
+                    self.events.zero_rtt_reject_reason(reason);
+                }
+                ConnectionEvent::ResumptionRejected => self.events.resumption_rejected(),
+                // HTTP/3 does not enable QUIC datagrams.
+                ConnectionEvent::Datagram(_) => return Err(Error::HttpInternalError),
             }
         }
         Ok(())
@@ -462,7 +561,7 @@ mod tests {
     use super::*;
     use crate::hframe::HFrame;
     use crate::hsettings_frame::{HSetting, HSettingType};
-    use neqo_common::{matches, Encoder};
+    use neqo_common::{matches, Encoder, VirtualClock};
     use neqo_crypto::AntiReplay;
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_transport::{CloseError, ConnectionEvent, FixedConnectionIdManager, State};
@@ -631,6 +730,19 @@ mod tests {
         (client, server)
     }
 
+    #[test]
+    fn wakeup_until_idle() {
+        let (mut client, _server) = connect();
+        let clock = VirtualClock::new(now());
+        client.set_clock(Rc::new(clock.clone()));
+        let mut out = Vec::new();
+        while let Some(delay) = client.wakeup(None, &mut out) {
+            clock.advance(delay);
+        }
+        assert!(matches!(client.state(), Http3State::Closed(_)));
+        assert!(clock.now() > now());
+    }
+
     fn read_and_check_stream_data(
         server: &mut Connection,
         stream_id: u64,
@@ -2486,6 +2598,9 @@ mod tests {
 
         assert!(client.tls_info().unwrap().resumed());
         assert!(server.conn.tls_info().unwrap().resumed());
+        let info = client.handshake_info().unwrap();
+        assert!(info.resumed);
+        assert!(info.early_data_accepted);
     }
 
     #[test]
@@ -7,8 +7,9 @@
 use crate::connection::{HandleReadableOutput, Http3Connection, Http3State, Http3Transaction};
 use crate::hframe::HFrame;
 use crate::server_connection_events::{Http3ServerConnEvent, Http3ServerConnEvents};
+use crate::server_events::QPackInfo;
 use crate::transaction_server::TransactionServer;
-use crate::{Error, Header, Res};
+use crate::{Error, Header, HeaderIndexing, Res};
 use neqo_common::{qdebug, qinfo, qtrace};
 use neqo_transport::{AppError, Connection, ConnectionEvent, StreamType};
 use std::time::Instant;
@@ -73,6 +74,27 @@ impl Http3ServerHandler {
         }
     }
 
+    pub fn qpack_info(&self) -> QPackInfo {
+        let decoder = &self.base_handler.qpack_decoder;
+        QPackInfo {
+            max_table_size: decoder.get_max_table_size(),
+            max_blocked_streams: decoder.get_blocked_streams(),
+            decoder_capacity: decoder.capacity(),
+            decoder_insert_count: decoder.insert_count(),
+            encoder_capacity: self.base_handler.qpack_encoder.capacity(),
+        }
+    }
+
+    /// How each of the headers of a request was represented.  This is empty
+    /// until they have been received, and once the request is done.
+    pub fn header_indexing(&self, stream_id: u64) -> Vec<HeaderIndexing> {
+        self.base_handler
+            .transactions
+            .get(&stream_id)
+            .map(|t| t.header_indexing().to_vec())
+            .unwrap_or_default()
+    }
+
     pub fn next_event(&mut self) -> Option<Http3ServerConnEvent> {
         self.events.next_event()
     }
@@ -142,7 +164,10 @@ impl Http3ServerHandler {
                             .connection_state_change(self.base_handler.state());
                     }
                 }
-                ConnectionEvent::ZeroRttRejected => return Err(Error::HttpInternalError),
+                ConnectionEvent::ZeroRttRejected
+                | ConnectionEvent::ZeroRttRejectReason(_)
+                | ConnectionEvent::ResumptionRejected => return Err(Error::HttpInternalError),
+                ConnectionEvent::Datagram(_) => return Err(Error::HttpInternalError),
             }
         }
         Ok(())
@@ -0,0 +1,94 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Client and server events as `futures::Stream`s.  These don't drive
+// anything: whatever calls `process` still has to, but it shouldn't take
+// events itself.
+
+use crate::client_events::{Http3ClientEvent, Http3ClientEvents};
+use crate::connection::Http3State;
+use crate::server_events::{Http3ServerEvent, Http3ServerEvents};
+
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The events of an `Http3Client`, from `Http3Client::event_stream`.  This
+/// ends after the event for the client being closed.
+#[derive(Debug)]
+pub struct Http3ClientEventStream {
+    events: Http3ClientEvents,
+    done: bool,
+}
+
+impl Http3ClientEventStream {
+    pub(crate) fn new(events: Http3ClientEvents) -> Self {
+        Self {
+            events,
+            done: false,
+        }
+    }
+}
+
+impl Stream for Http3ClientEventStream {
+    type Item = Http3ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let e = futures::ready!(self.events.poll_event(cx));
+        if let Http3ClientEvent::StateChange(Http3State::Closed(_)) = e {
+            self.done = true;
+        }
+        Poll::Ready(Some(e))
+    }
+}
+
+/// The events of an `Http3Server`, from `Http3Server::event_stream`.  This
+/// doesn't end.
+#[derive(Debug)]
+pub struct Http3ServerEventStream {
+    events: Http3ServerEvents,
+}
+
+impl Http3ServerEventStream {
+    pub(crate) fn new(events: Http3ServerEvents) -> Self {
+        Self { events }
+    }
+}
+
+impl Stream for Http3ServerEventStream {
+    type Item = Http3ServerEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.events.poll_event(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use neqo_transport::{CloseError, StreamType};
+
+    #[test]
+    fn client_stream_ends_when_closed() {
+        let events = Http3ClientEvents::default();
+        let mut stream = Http3ClientEventStream::new(events.clone());
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+
+        events.new_requests_creatable(StreamType::BiDi);
+        let closed = Http3State::Closed(CloseError::Application(1));
+        events.connection_state_change(closed.clone());
+        assert_eq!(
+            block_on(stream.collect::<Vec<_>>()),
+            vec![Http3ClientEvent::StateChange(closed)]
+        );
+    }
+}
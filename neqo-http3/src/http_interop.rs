@@ -0,0 +1,464 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Conversions between the header lists that neqo uses and the types in the
+// `http` crate, so that requests and responses can be passed to and from
+// code that uses hyper or tower without working on headers by hand.  The
+// pseudo-headers are mapped to the parts of a request or response that they
+// stand for, and both directions check what HTTP/3 requires of fields.
+
+use crate::{Error, Header, Http3Client, Res};
+use http::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use http::{Method, Request, Response, Uri};
+
+/// Fields that only mean something on one HTTP/1.1 connection, which HTTP/3
+/// doesn't allow.
+const CONNECTION_SPECIFIC: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn check_field(name: &str, value: &str) -> Res<()> {
+    if name.is_empty()
+        || name.bytes().any(|c| c.is_ascii_uppercase())
+        || CONNECTION_SPECIFIC.contains(&name)
+        || (name == "te" && value != "trailers")
+    {
+        return Err(Error::InvalidHeader);
+    }
+    Ok(())
+}
+
+/// The fields in `map`, as neqo takes them.  This fails if a value isn't
+/// visible ASCII or a field isn't allowed in HTTP/3.
+pub fn headers_from_map(map: &HeaderMap) -> Res<Vec<Header>> {
+    map.iter()
+        .map(|(name, value)| {
+            let value = value.to_str().map_err(|_| Error::InvalidHeader)?;
+            check_field(name.as_str(), value)?;
+            Ok((name.as_str().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// The fields in `headers` as a `HeaderMap`.  This fails if there are
+/// pseudo-headers, or a field isn't valid or allowed in HTTP/3.
+pub fn header_map(headers: &[Header]) -> Res<HeaderMap> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        check_field(name, value)?;
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| Error::InvalidHeader)?;
+        let value = HeaderValue::from_str(value).map_err(|_| Error::InvalidHeader)?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// The pseudo-headers at the start of a header list.
+#[derive(Debug, Default)]
+struct Pseudo<'a> {
+    method: Option<&'a str>,
+    scheme: Option<&'a str>,
+    authority: Option<&'a str>,
+    path: Option<&'a str>,
+    status: Option<&'a str>,
+}
+
+impl<'a> Pseudo<'a> {
+    /// Take the pseudo-headers from `headers`, and return them with the
+    /// fields that follow.  Each can only appear once, and all of them have
+    /// to come before any other field.
+    fn parse(headers: &'a [Header]) -> Res<(Self, &'a [Header])> {
+        let mut pseudo = Self::default();
+        let count = headers
+            .iter()
+            .take_while(|(name, _)| name.starts_with(':'))
+            .count();
+        for (name, value) in &headers[..count] {
+            let slot = match name.as_str() {
+                ":method" => &mut pseudo.method,
+                ":scheme" => &mut pseudo.scheme,
+                ":authority" => &mut pseudo.authority,
+                ":path" => &mut pseudo.path,
+                ":status" => &mut pseudo.status,
+                _ => return Err(Error::InvalidHeader),
+            };
+            if slot.replace(value.as_str()).is_some() {
+                return Err(Error::InvalidHeader);
+            }
+        }
+        Ok((pseudo, &headers[count..]))
+    }
+
+    fn is_request(&self) -> bool {
+        self.method.is_some()
+            || self.scheme.is_some()
+            || self.authority.is_some()
+            || self.path.is_some()
+    }
+}
+
+/// Make the `http::Request` that `headers` describe, without a body.  If
+/// there is no `:authority`, the URI only has the path.
+pub fn request_from_headers(headers: &[Header]) -> Res<Request<()>> {
+    let (pseudo, fields) = Pseudo::parse(headers)?;
+    if pseudo.status.is_some() {
+        return Err(Error::InvalidHeader);
+    }
+    let method = pseudo.method.ok_or(Error::InvalidHeader)?;
+    let uri = if method == Method::CONNECT.as_str() {
+        // A CONNECT request names only the place to connect to.
+        if pseudo.scheme.is_some() || pseudo.path.is_some() {
+            return Err(Error::InvalidHeader);
+        }
+        pseudo
+            .authority
+            .ok_or(Error::InvalidHeader)?
+            .parse::<Uri>()
+            .map_err(|_| Error::InvalidHeader)?
+    } else {
+        let scheme = pseudo.scheme.ok_or(Error::InvalidHeader)?;
+        let path = pseudo
+            .path
+            .filter(|p| !p.is_empty())
+            .ok_or(Error::InvalidHeader)?;
+        match pseudo.authority {
+            Some(authority) => Uri::builder()
+                .scheme(scheme)
+                .authority(authority)
+                .path_and_query(path)
+                .build(),
+            None => Uri::builder().path_and_query(path).build(),
+        }
+        .map_err(|_| Error::InvalidHeader)?
+    };
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(())
+        .map_err(|_| Error::InvalidHeader)?;
+    *request.headers_mut() = header_map(fields)?;
+    Ok(request)
+}
+
+/// Make the `http::Response` that `headers` describe, without a body.
+pub fn response_from_headers(headers: &[Header]) -> Res<Response<()>> {
+    let (pseudo, fields) = Pseudo::parse(headers)?;
+    if pseudo.is_request() {
+        return Err(Error::InvalidHeader);
+    }
+    let status = pseudo
+        .status
+        .filter(|s| s.len() == 3)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or(Error::InvalidHeader)?;
+    let mut response = Response::builder()
+        .status(status)
+        .body(())
+        .map_err(|_| Error::InvalidHeader)?;
+    *response.headers_mut() = header_map(fields)?;
+    Ok(response)
+}
+
+/// What `Http3Client::fetch` needs to make a request.
+struct Target {
+    method: String,
+    scheme: String,
+    authority: String,
+    path: String,
+    headers: Vec<Header>,
+}
+
+impl Target {
+    /// The authority comes from the URI, or failing that from the `Host`
+    /// field, which is then left out.  The scheme is https unless the URI
+    /// says otherwise.
+    fn new<B>(request: &Request<B>) -> Res<Self> {
+        let uri = request.uri();
+        let authority = match uri.authority() {
+            Some(a) => a.as_str(),
+            None => request
+                .headers()
+                .get(HOST)
+                .ok_or(Error::InvalidHeader)?
+                .to_str()
+                .map_err(|_| Error::InvalidHeader)?,
+        };
+        let headers = headers_from_map(request.headers())?
+            .into_iter()
+            .filter(|(name, _)| name != HOST.as_str())
+            .collect();
+        Ok(Target {
+            method: request.method().as_str().to_string(),
+            scheme: uri.scheme_str().unwrap_or("https").to_string(),
+            authority: authority.to_string(),
+            path: uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            headers,
+        })
+    }
+}
+
+/// The header list for `request`, pseudo-headers first.
+pub fn request_headers<B>(request: &Request<B>) -> Res<Vec<Header>> {
+    let target = Target::new(request)?;
+    let connect = request.method() == Method::CONNECT;
+    let mut headers = vec![(String::from(":method"), target.method)];
+    if !connect {
+        headers.push((String::from(":scheme"), target.scheme));
+    }
+    headers.push((String::from(":authority"), target.authority));
+    if !connect {
+        headers.push((String::from(":path"), target.path));
+    }
+    headers.extend(target.headers);
+    Ok(headers)
+}
+
+/// The header list for `response`, starting with `:status`.
+pub fn response_headers<B>(response: &Response<B>) -> Res<Vec<Header>> {
+    let mut headers = vec![(
+        String::from(":status"),
+        response.status().as_str().to_string(),
+    )];
+    headers.extend(headers_from_map(response.headers())?);
+    Ok(headers)
+}
+
+impl Http3Client {
+    /// Make a request from an `http::Request`, as `fetch` does.  The body of
+    /// `request` isn't sent; use `send_request_body` for that.  CONNECT
+    /// requests aren't supported.
+    pub fn fetch_request<B>(&mut self, request: &Request<B>) -> Res<u64> {
+        if request.method() == Method::CONNECT {
+            return Err(Error::InvalidHeader);
+        }
+        let target = Target::new(request)?;
+        self.fetch(
+            &target.method,
+            &target.scheme,
+            &target.authority,
+            &target.path,
+            &target.headers,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn h(name: &str, value: &str) -> Header {
+        (String::from(name), String::from(value))
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://example.com:8443/upload?x=1")
+            .header("content-type", "text/plain")
+            .header("accept", "*/*")
+            .body(())
+            .unwrap();
+        let headers = request_headers(&request).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                h(":method", "POST"),
+                h(":scheme", "https"),
+                h(":authority", "example.com:8443"),
+                h(":path", "/upload?x=1"),
+                h("content-type", "text/plain"),
+                h("accept", "*/*"),
+            ]
+        );
+
+        let back = request_from_headers(&headers).unwrap();
+        assert_eq!(back.method(), Method::POST);
+        assert_eq!(back.uri(), request.uri());
+        assert_eq!(back.headers(), request.headers());
+    }
+
+    #[test]
+    fn request_host() {
+        let request = Request::builder()
+            .uri("/index.html")
+            .header("host", "example.com")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            request_headers(&request).unwrap(),
+            vec![
+                h(":method", "GET"),
+                h(":scheme", "https"),
+                h(":authority", "example.com"),
+                h(":path", "/index.html"),
+            ]
+        );
+
+        let no_authority = Request::builder().uri("/").body(()).unwrap();
+        assert_eq!(request_headers(&no_authority), Err(Error::InvalidHeader));
+    }
+
+    #[test]
+    fn connect() {
+        let request = Request::builder()
+            .method("CONNECT")
+            .uri("example.com:443")
+            .body(())
+            .unwrap();
+        let headers = request_headers(&request).unwrap();
+        assert_eq!(
+            headers,
+            vec![h(":method", "CONNECT"), h(":authority", "example.com:443")]
+        );
+        let back = request_from_headers(&headers).unwrap();
+        assert_eq!(back.method(), Method::CONNECT);
+        assert_eq!(back.uri(), request.uri());
+
+        let with_path = [
+            h(":method", "CONNECT"),
+            h(":authority", "example.com:443"),
+            h(":path", "/"),
+        ];
+        assert_eq!(
+            request_from_headers(&with_path).unwrap_err(),
+            Error::InvalidHeader
+        );
+    }
+
+    #[test]
+    fn bad_request_pseudo_headers() {
+        let bad: &[&[Header]] = &[
+            // No :method, :scheme, or :path.
+            &[h(":scheme", "https"), h(":path", "/")],
+            &[h(":method", "GET"), h(":path", "/")],
+            &[h(":method", "GET"), h(":scheme", "https")],
+            &[h(":method", "GET"), h(":scheme", "https"), h(":path", "")],
+            // Twice.
+            &[
+                h(":method", "GET"),
+                h(":method", "GET"),
+                h(":scheme", "https"),
+                h(":path", "/"),
+            ],
+            // Unknown, or only for responses.
+            &[
+                h(":method", "GET"),
+                h(":scheme", "https"),
+                h(":path", "/"),
+                h(":protocol", "websocket"),
+            ],
+            &[
+                h(":method", "GET"),
+                h(":scheme", "https"),
+                h(":path", "/"),
+                h(":status", "200"),
+            ],
+            // After a regular field.
+            &[
+                h(":method", "GET"),
+                h(":scheme", "https"),
+                h("accept", "*/*"),
+                h(":path", "/"),
+            ],
+        ];
+        for headers in bad {
+            assert_eq!(
+                request_from_headers(headers).unwrap_err(),
+                Error::InvalidHeader,
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn bad_fields() {
+        let base = || vec![h(":method", "GET"), h(":scheme", "https"), h(":path", "/")];
+        for field in &[
+            h("Accept", "*/*"),
+            h("connection", "close"),
+            h("keep-alive", "timeout=5"),
+            h("transfer-encoding", "chunked"),
+            h("upgrade", "h2c"),
+            h("te", "gzip"),
+            h("bad name", "x"),
+            h("x-newline", "a\nb"),
+        ] {
+            let mut headers = base();
+            headers.push(field.clone());
+            assert_eq!(
+                request_from_headers(&headers).unwrap_err(),
+                Error::InvalidHeader,
+                "{:?}",
+                field
+            );
+        }
+
+        let mut headers = base();
+        headers.push(h("te", "trailers"));
+        assert!(request_from_headers(&headers).is_ok());
+
+        let request = Request::builder()
+            .uri("https://example.com/")
+            .header("connection", "keep-alive")
+            .body(())
+            .unwrap();
+        assert_eq!(request_headers(&request), Err(Error::InvalidHeader));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let response = Response::builder()
+            .status(404)
+            .header("content-length", "3")
+            .body(())
+            .unwrap();
+        let headers = response_headers(&response).unwrap();
+        assert_eq!(headers, vec![h(":status", "404"), h("content-length", "3")]);
+        let back = response_from_headers(&headers).unwrap();
+        assert_eq!(back.status(), 404);
+        assert_eq!(back.headers(), response.headers());
+    }
+
+    #[test]
+    fn bad_responses() {
+        let bad: &[&[Header]] = &[
+            &[],
+            &[h(":status", "20")],
+            &[h(":status", "2000")],
+            &[h(":status", "abc")],
+            &[h(":status", "200"), h(":path", "/")],
+            &[h(":status", "200"), h(":status", "200")],
+            &[h("server", "neqo"), h(":status", "200")],
+        ];
+        for headers in bad {
+            assert_eq!(
+                response_from_headers(headers).unwrap_err(),
+                Error::InvalidHeader,
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn header_maps() {
+        let headers = vec![h("accept", "a"), h("accept", "b"), h("x-thing", "1")];
+        let map = header_map(&headers).unwrap();
+        assert_eq!(map.get_all("accept").iter().count(), 2);
+        assert_eq!(headers_from_map(&map).unwrap(), headers);
+        assert_eq!(
+            header_map(&[h(":path", "/")]).unwrap_err(),
+            Error::InvalidHeader
+        );
+    }
+}
@@ -6,14 +6,34 @@
 
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 
+// Emit a `tracing` event at `$lvl`, if the feature is on.
+macro_rules! tracing_event {
+    ($lvl:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$lvl!($($arg)*);
+    };
+}
+
+// Enter `$span` until the end of the enclosing block, if the feature is on.
+macro_rules! enter_span {
+    ($span:expr) => {
+        #[cfg(feature = "tracing")]
+        let _entered = $span.clone().entered();
+    };
+}
+
 mod client_events;
 mod connection;
 pub mod connection_client;
 mod connection_server;
 mod control_stream_local;
 mod control_stream_remote;
+#[cfg(feature = "futures")]
+mod event_stream;
 pub mod hframe;
 mod hsettings_frame;
+#[cfg(feature = "http")]
+mod http_interop;
 pub mod server;
 mod server_connection_events;
 mod server_events;
@@ -29,9 +49,16 @@ pub use neqo_transport::Output;
 pub use client_events::Http3ClientEvent;
 pub use connection::Http3State;
 pub use connection_client::Http3Client;
-pub use neqo_qpack::Header;
+#[cfg(feature = "futures")]
+pub use event_stream::{Http3ClientEventStream, Http3ServerEventStream};
+#[cfg(feature = "http")]
+pub use http_interop::{
+    header_map, headers_from_map, request_from_headers, request_headers, response_from_headers,
+    response_headers,
+};
+pub use neqo_qpack::{FieldSource, Header, HeaderIndexing};
 pub use server::Http3Server;
-pub use server_events::Http3ServerEvent;
+pub use server_events::{ClientRequestStream, Http3ServerEvent, QPackInfo};
 pub use transaction_server::TransactionServer;
 
 type Res<T> = Result<T, Error>;
@@ -60,6 +87,7 @@ pub enum Error {
     // Internal errors from here.
     AlreadyClosed,
     DecodingFrame,
+    InvalidHeader,
     InvalidStreamId,
     NoMoreData,
     NotEnoughData,
@@ -6,11 +6,13 @@
 
 use crate::connection::Http3State;
 use crate::connection_server::Http3ServerHandler;
+#[cfg(feature = "futures")]
+use crate::event_stream::Http3ServerEventStream;
 use crate::server_connection_events::Http3ServerConnEvent;
 use crate::server_events::{ClientRequestStream, Http3ServerEvent, Http3ServerEvents};
 use crate::Res;
 use neqo_common::{qtrace, Datagram};
-use neqo_crypto::AntiReplay;
+use neqo_crypto::{AntiReplay, KeyLog};
 use neqo_transport::server::{ActiveConnectionRef, Server};
 use neqo_transport::{ConnectionIdManager, Output};
 use std::cell::RefCell;
@@ -53,6 +55,11 @@ impl Http3Server {
         })
     }
 
+    /// Have each new connection tell `log` about its TLS secrets.
+    pub fn set_key_log(&mut self, log: Rc<RefCell<dyn KeyLog>>) {
+        self.server.set_key_log(log);
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         let out = self.server.process(dgram, now);
@@ -155,6 +162,12 @@ impl Http3Server {
     pub fn next_event(&mut self) -> Option<Http3ServerEvent> {
         self.events.next_event()
     }
+
+    /// The events of this server as a `Stream`.
+    #[cfg(feature = "futures")]
+    pub fn event_stream(&self) -> Http3ServerEventStream {
+        Http3ServerEventStream::new(self.events.clone())
+    }
 }
 
 #[cfg(test)]
@@ -6,14 +6,32 @@
 
 use crate::connection::Http3State;
 use crate::connection_server::Http3ServerHandler;
-use crate::{Header, Res};
+use crate::{Header, HeaderIndexing, Res};
 use neqo_common::{qdebug, qinfo};
+use neqo_crypto::Version;
 use neqo_transport::server::ActiveConnectionRef;
 use neqo_transport::{AppError, Connection};
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+/// The QPACK state of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QPackInfo {
+    /// The dynamic table size that the server allows the client to use.
+    pub max_table_size: u32,
+    /// The number of streams that the server allows to be blocked.
+    pub max_blocked_streams: u16,
+    /// The dynamic table capacity that the client's encoder is using.
+    pub decoder_capacity: u64,
+    /// The number of entries that the client has inserted into the dynamic table.
+    pub decoder_insert_count: u64,
+    /// The dynamic table capacity that the server's encoder is using.
+    pub encoder_capacity: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct ClientRequestStream {
@@ -52,6 +70,39 @@ impl ClientRequestStream {
             .set_response(self.stream_id, headers, data)
     }
 
+    /// Get the address of the client.
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.conn.borrow().remote_address()
+    }
+
+    /// Get the QUIC version of the connection.
+    pub fn quic_version(&self) -> u32 {
+        self.conn.borrow().version()
+    }
+
+    /// Get the ALPN that was negotiated for the connection.
+    pub fn alpn(&self) -> Option<String> {
+        self.conn
+            .borrow()
+            .tls_info()
+            .and_then(|info| info.alpn().cloned())
+    }
+
+    /// Get the TLS version that was negotiated for the connection.
+    pub fn tls_version(&self) -> Option<Version> {
+        self.conn.borrow().tls_info().map(|info| info.version())
+    }
+
+    /// Get the QPACK state of the connection.
+    pub fn qpack_info(&self) -> QPackInfo {
+        self.handler.borrow().qpack_info()
+    }
+
+    /// Get how each of the request headers was represented, in the same order.
+    pub fn header_indexing(&self) -> Vec<HeaderIndexing> {
+        self.handler.borrow().header_indexing(self.stream_id)
+    }
+
     pub fn stream_stop_sending(&mut self, app_error: AppError) -> Res<()> {
         qdebug!(
             [self],
@@ -99,11 +150,16 @@ pub enum Http3ServerEvent {
 #[derive(Debug, Default, Clone)]
 pub struct Http3ServerEvents {
     events: Rc<RefCell<VecDeque<Http3ServerEvent>>>,
+    /// The task that `poll_event` found no events for, if any.
+    waker: Rc<RefCell<Option<Waker>>>,
 }
 
 impl Http3ServerEvents {
     fn insert(&self, event: Http3ServerEvent) {
         self.events.borrow_mut().push_back(event);
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake();
+        }
     }
 
     pub fn events(&self) -> impl Iterator<Item = Http3ServerEvent> {
@@ -118,6 +174,18 @@ impl Http3ServerEvents {
         self.events.borrow_mut().pop_front()
     }
 
+    /// Take the next event, or arrange for the task to be woken when there
+    /// is one.  Only the task that called last is woken.
+    pub fn poll_event(&self, cx: &mut Context) -> Poll<Http3ServerEvent> {
+        match self.next_event() {
+            Some(e) => Poll::Ready(e),
+            None => {
+                self.waker.replace(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+
     pub fn headers(&self, request: ClientRequestStream, headers: Vec<Header>, fin: bool) {
         self.insert(Http3ServerEvent::Headers {
             request,
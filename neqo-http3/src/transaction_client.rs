@@ -169,6 +169,9 @@ pub struct TransactionClient {
     frame_reader: HFrameReader,
     response_headers_state: ResponseHeadersState,
     conn_events: Http3ClientEvents,
+    /// This span lasts as long as the request does.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl TransactionClient {
@@ -183,6 +186,8 @@ impl TransactionClient {
     ) -> TransactionClient {
         qinfo!("Create a request stream_id={}", stream_id);
         TransactionClient {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("request", stream_id, method, host, path),
             send_state: TransactionSendState::SendingHeaders {
                 request: Request::new(method, scheme, host, path, headers),
                 fin: false,
@@ -314,6 +319,7 @@ impl TransactionClient {
             return Err(Error::HttpInternalError);
         }
         self.response_headers_state = ResponseHeadersState::Ready(headers);
+        tracing_event!(debug, "response headers received");
         self.conn_events.header_ready(self.stream_id);
         self.recv_state = TransactionRecvState::WaitingForData;
         Ok(())
@@ -327,6 +333,7 @@ impl TransactionClient {
             "set_state_to_close_pending:  response_headers_state={:?}",
             self.response_headers_state
         );
+        tracing_event!(debug, "response received");
         match self.response_headers_state {
             ResponseHeadersState::NoHeaders => {
                 self.conn_events.header_ready(self.stream_id);
@@ -486,6 +493,7 @@ impl ::std::fmt::Display for TransactionClient {
 
 impl Http3Transaction for TransactionClient {
     fn send(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
+        enter_span!(self.span);
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
         } else {
@@ -501,10 +509,12 @@ impl Http3Transaction for TransactionClient {
                     conn.stream_close_send(self.stream_id)?;
                     self.send_state = TransactionSendState::Closed;
                     qinfo!([label], "done sending request");
+                    tracing_event!(debug, "request sent");
                 } else {
                     self.send_state = TransactionSendState::SendingData;
                     self.conn_events.data_writable(self.stream_id);
                     qinfo!([label], "change to state SendingData");
+                    tracing_event!(debug, "request headers sent");
                 }
             }
         }
@@ -512,6 +522,7 @@ impl Http3Transaction for TransactionClient {
     }
 
     fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
+        enter_span!(self.span);
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
         } else {
@@ -593,10 +604,12 @@ impl Http3Transaction for TransactionClient {
     }
 
     fn reset_receiving_side(&mut self) {
+        tracing_event!(debug, parent: &self.span, "response reset");
         self.recv_state = TransactionRecvState::Closed;
     }
 
     fn stop_sending(&mut self) {
+        tracing_event!(debug, parent: &self.span, "request stopped");
         self.send_state = TransactionSendState::Closed;
     }
 
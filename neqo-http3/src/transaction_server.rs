@@ -7,8 +7,7 @@
 use crate::connection::Http3Transaction;
 use crate::hframe::{HFrame, HFrameReader};
 use crate::server_connection_events::Http3ServerConnEvents;
-use crate::Header;
-use crate::{Error, Res};
+use crate::{Error, Header, HeaderIndexing, Res};
 use neqo_common::{matches, qdebug, qinfo, qtrace, Encoder};
 use neqo_qpack::decoder::QPackDecoder;
 use neqo_qpack::encoder::QPackEncoder;
@@ -39,6 +38,11 @@ pub struct TransactionServer {
     stream_id: u64,
     frame_reader: HFrameReader,
     conn_events: Http3ServerConnEvents,
+    /// How each of the request headers was represented.
+    header_indexing: Vec<HeaderIndexing>,
+    /// This span lasts as long as the request does.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl TransactionServer {
@@ -50,10 +54,14 @@ impl TransactionServer {
             stream_id,
             frame_reader: HFrameReader::new(),
             conn_events,
+            header_indexing: Vec::new(),
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("request", stream_id),
         }
     }
 
     pub fn set_response(&mut self, headers: &[Header], data: Vec<u8>, encoder: &mut QPackEncoder) {
+        tracing_event!(debug, parent: &self.span, len = data.len(), "response set");
         qdebug!([self], "Encoding headers");
         let encoded_headers = encoder.encode_header_block(&headers, self.stream_id);
         let hframe = HFrame::Headers {
@@ -74,6 +82,10 @@ impl TransactionServer {
         self.send_state = TransactionSendState::SendingResponse { buf: d.into() };
     }
 
+    pub fn header_indexing(&self) -> &[HeaderIndexing] {
+        &self.header_indexing
+    }
+
     fn recv_frame_header(&mut self, conn: &mut Connection) -> Res<(Option<HFrame>, bool)> {
         qtrace!([self], "receiving frame header");
         let fin = self.frame_reader.receive(conn, self.stream_id)?;
@@ -116,8 +128,11 @@ impl TransactionServer {
                 [label],
                 "read_headers: read all headers, try decoding them."
             );
-            match decoder.decode_header_block(buf, self.stream_id)? {
+            match decoder.decode_header_block_with_indexing(buf, self.stream_id)? {
                 Some(headers) => {
+                    tracing_event!(debug, fin, "request headers received");
+                    let (headers, indexing) = headers.into_iter().unzip();
+                    self.header_indexing = indexing;
                     self.conn_events.headers(self.stream_id, headers, fin);
                     if fin {
                         self.recv_state = TransactionRecvState::Closed;
@@ -196,6 +211,7 @@ impl ::std::fmt::Display for TransactionServer {
 
 impl Http3Transaction for TransactionServer {
     fn send(&mut self, conn: &mut Connection, _encoder: &mut QPackEncoder) -> Res<()> {
+        enter_span!(self.span);
         qtrace!([self], "Sending response.");
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
@@ -209,6 +225,7 @@ impl Http3Transaction for TransactionServer {
                 conn.stream_close_send(self.stream_id)?;
                 self.send_state = TransactionSendState::Closed;
                 qinfo!([label], "done sending request");
+                tracing_event!(debug, "response sent");
             } else {
                 let mut b = buf.split_off(sent);
                 mem::swap(buf, &mut b);
@@ -219,6 +236,7 @@ impl Http3Transaction for TransactionServer {
     }
 
     fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
+        enter_span!(self.span);
         let label = if ::log::log_enabled!(::log::Level::Debug) {
             format!("{}", self)
         } else {
@@ -257,8 +275,11 @@ impl Http3Transaction for TransactionServer {
                     }
                 }
                 TransactionRecvState::BlockedDecodingHeaders { ref mut buf, fin } => {
-                    match decoder.decode_header_block(buf, self.stream_id)? {
+                    match decoder.decode_header_block_with_indexing(buf, self.stream_id)? {
                         Some(headers) => {
+                            tracing_event!(debug, fin, "request headers received");
+                            let (headers, indexing) = headers.into_iter().unzip();
+                            self.header_indexing = indexing;
                             self.conn_events.headers(self.stream_id, headers, fin);
                             if fin {
                                 return Ok(());
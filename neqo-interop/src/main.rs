@@ -13,10 +13,11 @@ use neqo_transport::{
     Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager, State,
     StreamType,
 };
+use neqo_udp::Socket;
 
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::rc::Rc;
 // use std::path::PathBuf;
 use std::str::FromStr;
@@ -49,11 +50,8 @@ trait Handler {
     }
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Datagram) {
-    let sent = socket.send(&d[..]).expect("Error sending datagram");
-    if sent != d.len() {
-        eprintln!("Unable to send all {} bytes of datagram", d.len());
-    }
+fn emit_datagram(socket: &mut Socket, d: Datagram) {
+    socket.send(&[d]).expect("Error sending datagram");
 }
 
 struct Timer {
@@ -77,12 +75,11 @@ impl Timer {
 }
 
 fn process_loop(
-    nctx: &NetworkCtx,
+    nctx: &mut NetworkCtx,
     client: &mut Connection,
     handler: &mut dyn Handler,
     timeout: Duration,
 ) -> Result<State, String> {
-    let buf = &mut [0u8; 2048];
     let timer = Timer::new(timeout);
 
     loop {
@@ -94,7 +91,7 @@ fn process_loop(
         let out_dgram = client.process_output(Instant::now());
         if let Some(dgram) = out_dgram.dgram() {
             let dgram = handler.rewrite_out(&dgram).unwrap_or(dgram);
-            emit_datagram(&nctx.socket, dgram);
+            emit_datagram(&mut nctx.socket, dgram);
         }
 
         if exiting {
@@ -106,8 +103,8 @@ fn process_loop(
         nctx.socket
             .set_read_timeout(Some(time_remaining))
             .expect("Read timeout");
-        let sz = match nctx.socket.recv(&mut buf[..]) {
-            Ok(sz) => sz,
+        let dgrams = match nctx.socket.recv() {
+            Ok(dgrams) => dgrams,
             Err(e) => {
                 return Err(String::from(match e.kind() {
                     std::io::ErrorKind::WouldBlock => "Timed out",
@@ -116,12 +113,7 @@ fn process_loop(
             }
         };
 
-        if sz == buf.len() {
-            eprintln!("Received more than {} bytes", buf.len());
-            continue;
-        }
-        if sz > 0 {
-            let received = Datagram::new(nctx.remote_addr, nctx.local_addr, &buf[..sz]);
+        for received in dgrams.into_iter().filter(|d| !d.is_empty()) {
             client.process_input(received, Instant::now());
         }
     }
@@ -234,11 +226,10 @@ struct H3Handler {
 
 // TODO(ekr@rtfm.com): Figure out how to merge this.
 fn process_loop_h3(
-    nctx: &NetworkCtx,
+    nctx: &mut NetworkCtx,
     handler: &mut H3Handler,
     timeout: Duration,
 ) -> Result<State, String> {
-    let buf = &mut [0u8; 2048];
     let timer = Timer::new(timeout);
 
     loop {
@@ -249,7 +240,7 @@ fn process_loop_h3(
         let exiting = !handler.handle();
         let out_dgram = handler.h3.conn().process_output(Instant::now());
         if let Some(dgram) = out_dgram.dgram() {
-            emit_datagram(&nctx.socket, dgram);
+            emit_datagram(&mut nctx.socket, dgram);
         }
 
         if exiting {
@@ -260,8 +251,8 @@ fn process_loop_h3(
         nctx.socket
             .set_read_timeout(Some(remaining_time))
             .expect("Read timeout");
-        let sz = match nctx.socket.recv(&mut buf[..]) {
-            Ok(sz) => sz,
+        let dgrams = match nctx.socket.recv() {
+            Ok(dgrams) => dgrams,
             Err(e) => {
                 return Err(String::from(match e.kind() {
                     std::io::ErrorKind::WouldBlock => "Timed out",
@@ -270,12 +261,7 @@ fn process_loop_h3(
             }
         };
 
-        if sz == buf.len() {
-            eprintln!("Received more than {} bytes", buf.len());
-            continue;
-        }
-        if sz > 0 {
-            let received = Datagram::new(nctx.remote_addr, nctx.local_addr, &buf[..sz]);
+        for received in dgrams.into_iter().filter(|d| !d.is_empty()) {
             handler.h3.process_input(received, Instant::now());
         }
     }
@@ -399,10 +385,10 @@ impl Test {
 struct NetworkCtx {
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    socket: UdpSocket,
+    socket: Socket,
 }
 
-fn test_connect(nctx: &NetworkCtx, test: &Test, peer: &Peer) -> Result<Connection, String> {
+fn test_connect(nctx: &mut NetworkCtx, test: &Test, peer: &Peer) -> Result<Connection, String> {
     let mut client = Connection::new_client(
         peer.host,
         &test.alpn(),
@@ -428,7 +414,7 @@ fn test_connect(nctx: &NetworkCtx, test: &Test, peer: &Peer) -> Result<Connectio
     }
 }
 
-fn test_h9(nctx: &NetworkCtx, client: &mut Connection) -> Result<(), String> {
+fn test_h9(nctx: &mut NetworkCtx, client: &mut Connection) -> Result<(), String> {
     let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
     let req: String = "GET /10\r\n".to_string();
     client
@@ -450,7 +436,7 @@ fn test_h9(nctx: &NetworkCtx, client: &mut Connection) -> Result<(), String> {
     Ok(())
 }
 
-fn test_h3(nctx: &NetworkCtx, peer: &Peer, client: Connection) -> Result<(), String> {
+fn test_h3(nctx: &mut NetworkCtx, peer: &Peer, client: Connection) -> Result<(), String> {
     let mut hc = H3Handler {
         streams: HashSet::new(),
         h3: Http3Client::new_with_conn(client, 128, 128),
@@ -489,7 +475,7 @@ impl Handler for VnHandler {
         Some(Datagram::new(d.source(), d.destination(), payload))
     }
 }
-fn test_vn(nctx: &NetworkCtx, peer: &Peer) -> Result<Connection, String> {
+fn test_vn(nctx: &mut NetworkCtx, peer: &Peer) -> Result<Connection, String> {
     let mut client = Connection::new_client(
         peer.host,
         &["hq-22"],
@@ -506,20 +492,20 @@ fn test_vn(nctx: &NetworkCtx, peer: &Peer) -> Result<Connection, String> {
 }
 
 fn run_test<'t>(peer: &Peer, test: &'t Test) -> (&'t Test, String) {
-    let socket = UdpSocket::bind(peer.bind()).expect("Unable to bind UDP socket");
+    let mut socket = Socket::bind(peer.bind()).expect("Unable to bind UDP socket");
     socket.connect(&peer).expect("Unable to connect UDP socket");
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
+    let local_addr = socket.local_addr();
     let remote_addr = peer.addr();
 
-    let nctx = NetworkCtx {
+    let mut nctx = NetworkCtx {
         socket,
         local_addr,
         remote_addr,
     };
 
     if let Test::VN = test {
-        let res = test_vn(&nctx, peer);
+        let res = test_vn(&mut nctx, peer);
         return match res {
             Err(e) => (test, format!("ERROR: {}", e)),
             Ok(client) => match client.state() {
@@ -531,7 +517,7 @@ fn run_test<'t>(peer: &Peer, test: &'t Test) -> (&'t Test, String) {
         };
     }
 
-    let mut client = match test_connect(&nctx, test, peer) {
+    let mut client = match test_connect(&mut nctx, test, peer) {
         Ok(client) => client,
         Err(e) => return (test, e),
     };
@@ -540,8 +526,8 @@ fn run_test<'t>(peer: &Peer, test: &'t Test) -> (&'t Test, String) {
         Test::Connect => {
             return (test, String::from("OK"));
         }
-        Test::H9 => test_h9(&nctx, &mut client),
-        Test::H3 => test_h3(&nctx, peer, client),
+        Test::H9 => test_h9(&mut nctx, &mut client),
+        Test::H3 => test_h3(&mut nctx, peer, client),
         Test::VN => unimplemented!(),
     };
 
@@ -0,0 +1,448 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::event::{state_name, Event};
+use crate::{neqo_error, NeqoError};
+use neqo_common::matches;
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Header, Http3Client, Http3ClientEvent, Http3State, Output};
+use neqo_transport::FixedConnectionIdManager;
+use neqo_udp::Socket;
+use pyo3::exceptions::{PyOSError, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How much is read from a stream at once.
+const READ_SIZE: usize = 0x1_0000;
+/// How long to wait when there is no deadline and nothing to wait for, so
+/// that signals are noticed.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+fn os_error(e: std::io::Error) -> PyErr {
+    PyOSError::new_err(e.to_string())
+}
+
+fn deadline(timeout: Option<f64>) -> Option<Instant> {
+    timeout.map(|t| Instant::now() + Duration::from_secs_f64(t.max(0.0)))
+}
+
+/// A complete response to a request.
+#[pyclass]
+#[derive(Clone, Debug, Default)]
+pub struct Response {
+    #[pyo3(get)]
+    pub status: u16,
+    #[pyo3(get)]
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+#[pymethods]
+impl Response {
+    #[getter]
+    fn body<'py>(&self, py: Python<'py>) -> &'py PyBytes {
+        PyBytes::new(py, &self.body)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Response(status={}, {} headers, {} bytes)",
+            self.status,
+            self.headers.len(),
+            self.body.len()
+        )
+    }
+}
+
+/// An HTTP/3 client connection to one server.
+///
+/// `Client(host, port, alpn=["h3-24"], local=None)` resolves `host`, binds a
+/// UDP socket, and starts the handshake.  `host` is also the server name that
+/// the certificate is checked against and the authority of requests.
+#[pyclass(unsendable)]
+pub struct Client {
+    client: Http3Client,
+    socket: Socket,
+    host: String,
+    /// Events that `fetch` took for streams other than its own, which are
+    /// given out before any others.
+    deferred: VecDeque<Http3ClientEvent>,
+}
+
+impl Client {
+    /// Send whatever there is to send, then wait for input until the next
+    /// timer or until `limit`, whichever is sooner.
+    fn drive(&mut self, py: Python, limit: Duration) -> PyResult<()> {
+        let wait = loop {
+            match self.client.process_output(Instant::now()) {
+                Output::Datagram(d) => self.socket.send(&[d]).map_err(os_error)?,
+                Output::Callback(t) => break min(t, limit),
+                Output::None => break limit,
+            }
+        };
+        self.client.process_http3(Instant::now());
+
+        self.socket
+            .set_read_timeout(Some(max(wait, Duration::from_millis(1))))
+            .map_err(os_error)?;
+        let socket = &mut self.socket;
+        match py.allow_threads(|| socket.recv()) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                self.client.process_timer(Instant::now());
+            }
+            Err(e) => return Err(os_error(e)),
+            Ok(dgrams) => {
+                for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
+                    self.client.process_input(d, Instant::now());
+                }
+            }
+        }
+        self.client.process_http3(Instant::now());
+        py.check_signals()
+    }
+
+    /// Drive the connection until `done` says to stop, or `deadline` passes.
+    fn drive_until(
+        &mut self,
+        py: Python,
+        deadline: Option<Instant>,
+        mut done: impl FnMut(&mut Self) -> PyResult<bool>,
+    ) -> PyResult<()> {
+        loop {
+            if done(self)? {
+                return Ok(());
+            }
+            if let Http3State::Closed(e) = self.client.state() {
+                return Err(NeqoError::new_err(format!("connection closed: {:?}", e)));
+            }
+            let limit = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return Err(PyTimeoutError::new_err("timed out"));
+                    }
+                    d - now
+                }
+                None => IDLE_WAIT,
+            };
+            self.drive(py, limit)?;
+        }
+    }
+
+    fn take_event(&mut self) -> Option<Http3ClientEvent> {
+        self.deferred
+            .pop_front()
+            .or_else(|| self.client.next_event())
+    }
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[pyo3(signature = (host, port, alpn = vec![String::from("h3-24")], local = None, max_table_size = 128, max_blocked_streams = 128))]
+    fn new(
+        host: String,
+        port: u16,
+        alpn: Vec<String>,
+        local: Option<&str>,
+        max_table_size: u32,
+        max_blocked_streams: u16,
+    ) -> PyResult<Self> {
+        let remote = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(os_error)?
+            .next()
+            .ok_or_else(|| PyValueError::new_err(format!("can't resolve {}", host)))?;
+        let local = match local {
+            Some(l) => l
+                .parse::<SocketAddr>()
+                .map_err(|e| PyValueError::new_err(e.to_string()))?,
+            None if remote.is_ipv4() => SocketAddr::from(([0; 4], 0)),
+            None => SocketAddr::from(([0; 16], 0)),
+        };
+        let mut socket = Socket::bind(local).map_err(os_error)?;
+        socket.connect(remote).map_err(os_error)?;
+        let client = Http3Client::new(
+            &host,
+            &alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            socket.local_addr(),
+            remote,
+            max_table_size,
+            max_blocked_streams,
+        )
+        .map_err(neqo_error)?;
+        Ok(Client {
+            client,
+            socket,
+            host,
+            deferred: VecDeque::new(),
+        })
+    }
+
+    /// The state of the connection, such as "connected" or "closed".
+    #[getter]
+    fn state(&self) -> &'static str {
+        state_name(&self.client.state())
+    }
+
+    /// Finish the handshake, accepting the server certificate as neqo-client
+    /// does.  This doesn't return the events that it sees.
+    #[pyo3(signature = (timeout = Some(10.0)))]
+    fn connect(&mut self, py: Python, timeout: Option<f64>) -> PyResult<()> {
+        self.drive_until(py, deadline(timeout), |c| {
+            while let Some(e) = c.take_event() {
+                if let Http3ClientEvent::AuthenticationNeeded = e {
+                    c.client
+                        .authenticated(AuthenticationStatus::Ok, Instant::now());
+                }
+            }
+            Ok(c.client.state() == Http3State::Connected)
+        })
+    }
+
+    /// Say whether the server certificate is acceptable, after an
+    /// "authentication_needed" event.
+    fn authenticated(&mut self, ok: bool) {
+        let status = if ok {
+            AuthenticationStatus::Ok
+        } else {
+            AuthenticationStatus::Unknown
+        };
+        self.client.authenticated(status, Instant::now());
+    }
+
+    /// Make a request, and return the stream ID for it.  Send the body, if
+    /// there is one, with `send_body`, then call `close_send`.
+    #[pyo3(signature = (method, path, headers = Vec::new(), scheme = "https"))]
+    fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: Vec<Header>,
+        scheme: &str,
+    ) -> PyResult<u64> {
+        self.client
+            .fetch(method, scheme, &self.host, path, &headers)
+            .map_err(neqo_error)
+    }
+
+    /// Send some of a request body, and return how much was taken.
+    fn send_body(&mut self, stream_id: u64, data: &[u8]) -> PyResult<usize> {
+        self.client
+            .send_request_body(stream_id, data)
+            .map_err(neqo_error)
+    }
+
+    /// Finish sending a request.
+    fn close_send(&mut self, stream_id: u64) -> PyResult<()> {
+        self.client.stream_close_send(stream_id).map_err(neqo_error)
+    }
+
+    /// Cancel a request.
+    fn reset(&mut self, stream_id: u64, error: u64) -> PyResult<()> {
+        self.client
+            .stream_reset(stream_id, error)
+            .map_err(neqo_error)
+    }
+
+    /// Read the response headers, after a "header_ready" event.  This
+    /// returns the headers and whether the response ends there.
+    fn read_headers(&mut self, stream_id: u64) -> PyResult<(Vec<Header>, bool)> {
+        self.client
+            .read_response_headers(stream_id)
+            .map_err(neqo_error)
+    }
+
+    /// Read what there is of the response body, after a "data_readable"
+    /// event.  This returns the data and whether the response ends there.
+    #[pyo3(signature = (stream_id, max = READ_SIZE))]
+    fn read_data<'py>(
+        &mut self,
+        py: Python<'py>,
+        stream_id: u64,
+        max: usize,
+    ) -> PyResult<(&'py PyBytes, bool)> {
+        let mut buf = vec![0; max];
+        let (len, fin) = self
+            .client
+            .read_response_data(Instant::now(), stream_id, &mut buf)
+            .map_err(neqo_error)?;
+        Ok((PyBytes::new(py, &buf[..len]), fin))
+    }
+
+    /// Make a request and wait for the whole response.  This connects first
+    /// if that isn't done.  Events for other streams that arrive in the
+    /// meantime are kept for `next_event` and `events`.
+    #[pyo3(signature = (method, path, headers = Vec::new(), body = None, timeout = Some(10.0)))]
+    fn fetch(
+        &mut self,
+        py: Python,
+        method: &str,
+        path: &str,
+        headers: Vec<Header>,
+        body: Option<&[u8]>,
+        timeout: Option<f64>,
+    ) -> PyResult<Response> {
+        let deadline = deadline(timeout);
+        if self.client.state() != Http3State::Connected {
+            self.connect(py, timeout)?;
+        }
+        let stream_id = self.request(method, path, headers, "https")?;
+
+        let mut body = body.unwrap_or(&[]);
+        self.drive_until(py, deadline, |c| {
+            if !body.is_empty() {
+                let sent = c
+                    .client
+                    .send_request_body(stream_id, body)
+                    .map_err(neqo_error)?;
+                body = &body[sent..];
+            }
+            Ok(body.is_empty())
+        })?;
+        self.close_send(stream_id)?;
+
+        let mut response = Response::default();
+        let mut deferred = VecDeque::new();
+        let result = self.drive_until(py, deadline, |c| {
+            while let Some(e) = c.client.next_event() {
+                match e {
+                    Http3ClientEvent::HeaderReady { stream_id: id } if id == stream_id => {
+                        let (headers, fin) = c
+                            .client
+                            .read_response_headers(stream_id)
+                            .map_err(neqo_error)?;
+                        response.status = headers
+                            .iter()
+                            .find(|(n, _)| n == ":status")
+                            .and_then(|(_, v)| v.parse().ok())
+                            .ok_or_else(|| NeqoError::new_err("no valid :status"))?;
+                        response.headers = headers;
+                        if fin {
+                            return Ok(true);
+                        }
+                    }
+                    Http3ClientEvent::DataReadable { stream_id: id } if id == stream_id => {
+                        let mut buf = vec![0; READ_SIZE];
+                        loop {
+                            let (len, fin) = c
+                                .client
+                                .read_response_data(Instant::now(), stream_id, &mut buf)
+                                .map_err(neqo_error)?;
+                            response.body.extend_from_slice(&buf[..len]);
+                            if fin {
+                                return Ok(true);
+                            }
+                            if len == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    Http3ClientEvent::Reset {
+                        stream_id: id,
+                        error,
+                    } if id == stream_id => {
+                        return Err(NeqoError::new_err(format!("reset with error {}", error)));
+                    }
+                    e => deferred.push_back(e),
+                }
+            }
+            Ok(false)
+        });
+        self.deferred.extend(deferred);
+        result.map(|()| response)
+    }
+
+    /// Take the next event that has arrived, or None.  This doesn't wait.
+    fn next_event(&mut self) -> Option<Event> {
+        self.take_event().map(|e| Event::from(&e))
+    }
+
+    /// Iterate over events as they arrive, sending and receiving as needed,
+    /// until the connection closes or `timeout` seconds pass.
+    #[pyo3(signature = (timeout = None))]
+    fn events(slf: Py<Self>, timeout: Option<f64>) -> EventIter {
+        EventIter {
+            client: slf,
+            deadline: deadline(timeout),
+        }
+    }
+
+    /// Send and receive for up to `timeout` seconds, or until the next timer.
+    #[pyo3(signature = (timeout = 0.0))]
+    fn process(&mut self, py: Python, timeout: f64) -> PyResult<()> {
+        self.drive(py, Duration::from_secs_f64(timeout.max(0.0)))
+    }
+
+    /// Close the connection, and wait up to `timeout` seconds for that to
+    /// finish.
+    #[pyo3(signature = (error = 0, reason = "", timeout = Some(1.0)))]
+    fn close(
+        &mut self,
+        py: Python,
+        error: u64,
+        reason: &str,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        self.client.close(Instant::now(), error, reason);
+        let deadline = deadline(timeout);
+        while !matches!(self.client.state(), Http3State::Closed(_)) {
+            let now = Instant::now();
+            match deadline {
+                Some(d) if now >= d => break,
+                Some(d) => self.drive(py, d - now)?,
+                None => self.drive(py, IDLE_WAIT)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The iterator that `Client.events` returns.
+#[pyclass(unsendable)]
+pub struct EventIter {
+    client: Py<Client>,
+    deadline: Option<Instant>,
+}
+
+#[pymethods]
+impl EventIter {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<Event>> {
+        let mut client = self.client.borrow_mut(py);
+        loop {
+            if let Some(e) = client.take_event() {
+                return Ok(Some(Event::from(&e)));
+            }
+            if let Http3State::Closed(_) = client.client.state() {
+                return Ok(None);
+            }
+            let limit = match self.deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return Ok(None);
+                    }
+                    d - now
+                }
+                None => IDLE_WAIT,
+            };
+            client.drive(py, limit)?;
+        }
+    }
+}
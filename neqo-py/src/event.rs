@@ -0,0 +1,161 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_http3::{Http3ClientEvent, Http3State};
+use neqo_transport::ZeroRttRejectReason;
+use pyo3::prelude::*;
+
+/// The name that Python sees for a state.
+pub fn state_name(state: &Http3State) -> &'static str {
+    match state {
+        Http3State::Initializing => "initializing",
+        Http3State::ZeroRtt => "zero_rtt",
+        Http3State::Connected => "connected",
+        Http3State::GoingAway => "going_away",
+        Http3State::Closing(_) => "closing",
+        Http3State::Closed(_) => "closed",
+    }
+}
+
+/// The name that Python sees for why 0-RTT was rejected.
+pub fn reason_name(reason: ZeroRttRejectReason) -> &'static str {
+    match reason {
+        ZeroRttRejectReason::TicketRejected => "ticket_rejected",
+        ZeroRttRejectReason::Alpn => "alpn",
+        ZeroRttRejectReason::TransportParameters => "transport_parameters",
+        ZeroRttRejectReason::Declined => "declined",
+    }
+}
+
+/// Something that happened on a connection.  `kind` is the name of the
+/// event, such as "header_ready" or "state_change".  The other attributes
+/// are None unless the event has them.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct Event {
+    #[pyo3(get)]
+    pub kind: &'static str,
+    #[pyo3(get)]
+    pub stream_id: Option<u64>,
+    #[pyo3(get)]
+    pub error: Option<u64>,
+    #[pyo3(get)]
+    pub state: Option<&'static str>,
+    #[pyo3(get)]
+    pub reason: Option<&'static str>,
+}
+
+impl Event {
+    fn new(kind: &'static str) -> Self {
+        Event {
+            kind,
+            stream_id: None,
+            error: None,
+            state: None,
+            reason: None,
+        }
+    }
+
+    fn stream(kind: &'static str, stream_id: u64) -> Self {
+        Event {
+            stream_id: Some(stream_id),
+            ..Self::new(kind)
+        }
+    }
+}
+
+impl From<&Http3ClientEvent> for Event {
+    fn from(e: &Http3ClientEvent) -> Self {
+        match e {
+            Http3ClientEvent::HeaderReady { stream_id } => Self::stream("header_ready", *stream_id),
+            Http3ClientEvent::DataWritable { stream_id } => {
+                Self::stream("data_writable", *stream_id)
+            }
+            Http3ClientEvent::DataReadable { stream_id } => {
+                Self::stream("data_readable", *stream_id)
+            }
+            Http3ClientEvent::Reset { stream_id, error } => Event {
+                error: Some(*error),
+                ..Self::stream("reset", *stream_id)
+            },
+            Http3ClientEvent::StopSending { stream_id, error } => Event {
+                error: Some(*error),
+                ..Self::stream("stop_sending", *stream_id)
+            },
+            Http3ClientEvent::NewPushStream { stream_id } => {
+                Self::stream("new_push_stream", *stream_id)
+            }
+            Http3ClientEvent::RequestsCreatable => Self::new("requests_creatable"),
+            Http3ClientEvent::AuthenticationNeeded => Self::new("authentication_needed"),
+            Http3ClientEvent::ZeroRttRejected => Self::new("zero_rtt_rejected"),
+            Http3ClientEvent::ZeroRttRejectReason(reason) => Event {
+                reason: Some(reason_name(*reason)),
+                ..Self::new("zero_rtt_reject_reason")
+            },
+            Http3ClientEvent::ResumptionRejected => Self::new("resumption_rejected"),
+            Http3ClientEvent::GoawayReceived => Self::new("goaway_received"),
+            Http3ClientEvent::StateChange(state) => Event {
+                state: Some(state_name(state)),
+                ..Self::new("state_change")
+            },
+        }
+    }
+}
+
+#[pymethods]
+impl Event {
+    fn __repr__(&self) -> String {
+        let mut s = format!("Event({}", self.kind);
+        if let Some(id) = self.stream_id {
+            s.push_str(&format!(", stream_id={}", id));
+        }
+        if let Some(error) = self.error {
+            s.push_str(&format!(", error={}", error));
+        }
+        if let Some(state) = self.state {
+            s.push_str(&format!(", state={}", state));
+        }
+        if let Some(reason) = self.reason {
+            s.push_str(&format!(", reason={}", reason));
+        }
+        s.push(')');
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neqo_transport::CloseError;
+
+    #[test]
+    fn events() {
+        let e = Event::from(&Http3ClientEvent::Reset {
+            stream_id: 4,
+            error: 7,
+        });
+        assert_eq!(e.kind, "reset");
+        assert_eq!(e.stream_id, Some(4));
+        assert_eq!(e.error, Some(7));
+        assert_eq!(e.__repr__(), "Event(reset, stream_id=4, error=7)");
+
+        let e = Event::from(&Http3ClientEvent::StateChange(Http3State::Closed(
+            CloseError::Application(0),
+        )));
+        assert_eq!(e.state, Some("closed"));
+        assert_eq!(e.stream_id, None);
+        assert_eq!(e.__repr__(), "Event(state_change, state=closed)");
+
+        let e = Event::from(&Http3ClientEvent::ZeroRttRejectReason(
+            ZeroRttRejectReason::Alpn,
+        ));
+        assert_eq!(e.reason, Some("alpn"));
+        assert_eq!(
+            Event::from(&Http3ClientEvent::RequestsCreatable).kind,
+            "requests_creatable"
+        );
+    }
+}
@@ -0,0 +1,58 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Python bindings for the HTTP/3 client.  A `Client` owns a UDP socket and a
+// connection to one server, and does its own I/O: `fetch` makes a request and
+// waits for the whole response, and `events` gives the connection's events
+// as they happen, for scripts that want to see more of what goes on.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod client;
+mod event;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+use std::path::Path;
+
+pub use self::client::{Client, EventIter, Response};
+pub use self::event::Event;
+
+create_exception!(neqo, NeqoError, PyException);
+
+fn neqo_error(e: neqo_http3::Error) -> PyErr {
+    NeqoError::new_err(format!("{:?}", e))
+}
+
+/// Initialize NSS, with the database in `db` if it is given.  This has to be
+/// called once, before any client is made.
+#[pyfunction]
+#[pyo3(signature = (db = None))]
+fn init(db: Option<&str>) -> PyResult<()> {
+    match db {
+        None => neqo_crypto::init(),
+        Some(dir) => {
+            if !Path::new(dir).is_dir() {
+                return Err(PyValueError::new_err(format!("{} is not a directory", dir)));
+            }
+            neqo_crypto::init_db(dir);
+        }
+    }
+    Ok(())
+}
+
+#[pymodule]
+fn neqo(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(init, m)?)?;
+    m.add_class::<Client>()?;
+    m.add_class::<Event>()?;
+    m.add_class::<EventIter>()?;
+    m.add_class::<Response>()?;
+    m.add("NeqoError", py.get_type::<NeqoError>())?;
+    Ok(())
+}
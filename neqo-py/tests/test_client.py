@@ -0,0 +1,91 @@
+# Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+# http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+# <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+# option. This file may not be copied, modified, or distributed
+# except according to those terms.
+
+# Run the module against neqo-http3-server, from the workspace's target
+# directory unless NEQO_HTTP3_SERVER says where it is.
+
+import os
+import socket
+import subprocess
+import time
+
+import pytest
+
+import neqo
+
+ROOT = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "..")
+DB = os.path.join(ROOT, "test-fixture", "db")
+SERVER = os.environ.get(
+    "NEQO_HTTP3_SERVER", os.path.join(ROOT, "target", "debug", "neqo-http3-server")
+)
+
+
+def unused_port():
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+@pytest.fixture(scope="module")
+def server():
+    neqo.init(DB)
+    port = unused_port()
+    proc = subprocess.Popen(
+        [SERVER, "-d", DB, "127.0.0.1:{}".format(port)],
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    # Give it time to bind.
+    time.sleep(0.5)
+    yield port
+    proc.kill()
+    proc.wait()
+
+
+def test_fetch(server):
+    client = neqo.Client("127.0.0.1", server)
+    response = client.fetch("GET", "/100")
+    assert response.status == 200
+    assert ("content-length", "100") in response.headers
+    assert response.body == b"a" * 100
+    assert client.state == "connected"
+    client.close()
+    assert client.state in ("closing", "closed")
+
+
+def test_events(server):
+    client = neqo.Client("127.0.0.1", server)
+    client.connect()
+    stream_id = client.request("GET", "/")
+    client.close_send(stream_id)
+    body = b""
+    for event in client.events(timeout=10):
+        if event.stream_id != stream_id:
+            continue
+        if event.kind == "header_ready":
+            headers, fin = client.read_headers(stream_id)
+            assert (":status", "200") in headers
+            assert not fin
+        elif event.kind == "data_readable":
+            data, fin = client.read_data(stream_id)
+            body += data
+            if fin:
+                break
+    assert body == b"Hello World"
+    client.close()
+
+
+def test_init_needs_a_directory():
+    with pytest.raises(ValueError):
+        neqo.init(os.path.join(DB, "nonexistent"))
+
+
+def test_unreachable():
+    # Nothing answers, so connecting either times out or finds that the port
+    # is unreachable.  TimeoutError is an OSError too.
+    client = neqo.Client("127.0.0.1", unused_port())
+    with pytest.raises(OSError):
+        client.connect(timeout=0.5)
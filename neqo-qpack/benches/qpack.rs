@@ -0,0 +1,72 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::encoder::QPackEncoder;
+use neqo_qpack::Header;
+
+const STREAM_ID: u64 = 0;
+
+/// The headers of a typical request.
+fn request_headers() -> Vec<Header> {
+    [
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":authority", "www.example.com"),
+        (":path", "/images/logo.png?size=large&format=webp"),
+        (
+            "user-agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:72.0) Gecko/20100101",
+        ),
+        ("accept", "image/webp,*/*"),
+        ("accept-language", "en-US,en;q=0.5"),
+        ("accept-encoding", "gzip, deflate, br"),
+        ("referer", "https://www.example.com/"),
+        ("cookie", "session=0123456789abcdef0123456789abcdef"),
+    ]
+    .iter()
+    .map(|(n, v)| (String::from(*n), String::from(*v)))
+    .collect()
+}
+
+fn headers_size(headers: &[Header]) -> u64 {
+    headers
+        .iter()
+        .map(|(n, v)| (n.len() + v.len()) as u64)
+        .sum()
+}
+
+fn qpack(c: &mut Criterion) {
+    let headers = request_headers();
+    let mut group = c.benchmark_group("qpack");
+    group.throughput(Throughput::Bytes(headers_size(&headers)));
+
+    for &huffman in &[false, true] {
+        let suffix = if huffman { "huffman" } else { "literal" };
+        let mut encoder = QPackEncoder::new(huffman);
+        group.bench_function(format!("encode/{}", suffix), |b| {
+            b.iter(|| encoder.encode_header_block(black_box(&headers), STREAM_ID))
+        });
+
+        let encoded = encoder.encode_header_block(&headers, STREAM_ID).to_vec();
+        let mut decoder = QPackDecoder::new(0, 0);
+        group.bench_function(format!("decode/{}", suffix), |b| {
+            b.iter(|| {
+                decoder
+                    .decode_header_block(black_box(&encoded), STREAM_ID)
+                    .expect("decode")
+                    .expect("not blocked")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, qpack);
+criterion_main!(benches);
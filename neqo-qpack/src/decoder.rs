@@ -7,13 +7,12 @@
 #![allow(unused_variables, dead_code)]
 use crate::huffman::Huffman;
 use crate::qpack_helper::{
-    read_prefixed_encoded_int_slice, read_prefixed_encoded_int_with_connection, BufWrapper,
+    read_prefixed_encoded_int_slice, read_prefixed_int_from_stream, BufWrapper,
 };
 use crate::qpack_send_buf::QPData;
 use crate::table::HeaderTable;
-use crate::Header;
-use crate::{Error, Res};
-use neqo_common::qdebug;
+use crate::{Error, FieldSource, Header, HeaderIndexing, Res};
+use neqo_common::{qdebug, IncrementalDecoder};
 use neqo_transport::Connection;
 use std::{mem, str};
 
@@ -29,18 +28,35 @@ fn to_string(v: &[u8]) -> Res<String> {
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 enum QPackWithRefState {
-    GetName { cnt: u8 },
-    GetValueLength { len: u64, cnt: u8 },
-    GetValue { offset: usize },
+    GetName {
+        decoder: IncrementalDecoder,
+    },
+    /// The decoder is `None` until the first byte, which also says whether the
+    /// value uses Huffman coding, is read.
+    GetValueLength {
+        decoder: Option<IncrementalDecoder>,
+    },
+    GetValue {
+        offset: usize,
+    },
 }
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 enum QPackWithoutRefState {
-    GetNameLength { len: u64, cnt: u8 },
-    GetName { offset: usize },
-    GetValueLength { len: u64, cnt: u8 },
-    GetValue { offset: usize },
+    GetNameLength {
+        decoder: IncrementalDecoder,
+    },
+    GetName {
+        offset: usize,
+    },
+    /// As for `QPackWithRefState::GetValueLength`.
+    GetValueLength {
+        decoder: Option<IncrementalDecoder>,
+    },
+    GetValue {
+        offset: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -61,12 +77,10 @@ enum QPackDecoderState {
         state: QPackWithoutRefState,
     },
     Duplicate {
-        index: u64,
-        cnt: u8,
+        decoder: IncrementalDecoder,
     },
     Capacity {
-        capacity: u64,
-        cnt: u8,
+        decoder: IncrementalDecoder,
     },
 }
 
@@ -107,6 +121,16 @@ impl QPackDecoder {
         self.table.capacity()
     }
 
+    /// How much of the dynamic table capacity is in use.
+    pub fn table_used(&self) -> u64 {
+        self.table.used()
+    }
+
+    /// The number of entries that the peer's encoder has inserted so far.
+    pub fn insert_count(&self) -> u64 {
+        self.table.base()
+    }
+
     pub fn get_max_table_size(&self) -> u32 {
         self.max_table_size
     }
@@ -130,7 +154,6 @@ impl QPackDecoder {
     }
 
     #[allow(clippy::cognitive_complexity)]
-    #[allow(clippy::useless_let_if_seq)]
     fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
         let label = self.to_string();
         qdebug!([self], "reading instructions");
@@ -154,83 +177,74 @@ impl QPackDecoder {
                     if (b[0] & 0x80) != 0 {
                         // Insert With Name Reference
                         let static_t = (b[0] & 0x40) != 0;
-                        let mut v: u64 = 0;
-                        let mut cnt: u8 = 0;
-                        let name_done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 2, b[0], true,
-                        )?;
+                        let mut decoder = IncrementalDecoder::decode_prefixed_int_from(2, b[0]);
+                        let name_index =
+                            read_prefixed_int_from_stream_wrap(&mut decoder, conn, stream_id)?;
                         self.state = QPackDecoderState::InsertWithNameRef {
-                            name_index: v,
+                            name_index: name_index.unwrap_or(0),
                             name_static_table: static_t,
                             value: Vec::new(),
                             value_is_huffman: false,
-                            state: if name_done {
-                                QPackWithRefState::GetValueLength { len: 0, cnt: 0 }
+                            state: if name_index.is_some() {
+                                QPackWithRefState::GetValueLength { decoder: None }
                             } else {
-                                QPackWithRefState::GetName { cnt }
+                                QPackWithRefState::GetName { decoder }
                             },
                         };
-                        if !name_done {
+                        if name_index.is_none() {
                             // wait for more data
                             break Ok(());
                         }
                     } else if (b[0] & 0x40) != 0 {
                         // Insert Without Name Reference
                         let huffman = (b[0] & 0x20) != 0;
-                        let mut v: u64 = 0;
-                        let mut cnt: u8 = 0;
-                        let name_done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
-                        )?;
+                        let mut decoder = IncrementalDecoder::decode_prefixed_int_from(3, b[0]);
+                        let name_len =
+                            read_prefixed_int_from_stream_wrap(&mut decoder, conn, stream_id)?;
                         self.state = QPackDecoderState::InsertWithoutNameRef {
-                            name: if name_done {
-                                vec![0; v as usize]
-                            } else {
-                                Vec::new()
+                            name: match name_len {
+                                Some(len) => vec![0; len as usize],
+                                None => Vec::new(),
                             },
                             name_is_huffman: huffman,
                             value: Vec::new(),
                             value_is_huffman: false,
-                            state: if name_done {
+                            state: if name_len.is_some() {
                                 QPackWithoutRefState::GetName { offset: 0 }
                             } else {
-                                QPackWithoutRefState::GetNameLength { len: v, cnt }
+                                QPackWithoutRefState::GetNameLength { decoder }
                             },
                         };
-                        if !name_done {
+                        if name_len.is_none() {
                             // wait for more data
                             break Ok(());
                         }
                     } else if (b[0] & 0x20) == 0 {
                         // Duplicate
-                        let mut v: u64 = 0;
-                        let mut cnt: u8 = 0;
-                        let done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
-                        )?;
-                        if done {
-                            qdebug!([label], "received instruction - duplicate index={}", v);
-                            self.table.duplicate(v)?;
+                        let mut decoder = IncrementalDecoder::decode_prefixed_int_from(3, b[0]);
+                        if let Some(index) =
+                            read_prefixed_int_from_stream_wrap(&mut decoder, conn, stream_id)?
+                        {
+                            qdebug!([label], "received instruction - duplicate index={}", index);
+                            self.table.duplicate(index)?;
                             self.total_num_of_inserts += 1;
                             self.increment += 1;
                             self.state = QPackDecoderState::ReadInstruction;
                         } else {
-                            self.state = QPackDecoderState::Duplicate { index: v, cnt };
+                            self.state = QPackDecoderState::Duplicate { decoder };
                             // wait for more data
                             break Ok(());
                         }
                     } else {
                         // Set Dynamic Table Capacity
-                        let mut v: u64 = 0;
-                        let mut cnt: u8 = 0;
-                        let done = read_prefixed_encoded_int_with_connection_wrap(
-                            conn, stream_id, &mut v, &mut cnt, 3, b[0], true,
-                        )?;
-                        if done {
-                            self.set_capacity(v)?;
+                        let mut decoder = IncrementalDecoder::decode_prefixed_int_from(3, b[0]);
+                        if let Some(capacity) =
+                            read_prefixed_int_from_stream_wrap(&mut decoder, conn, stream_id)?
+                        {
+                            self.set_capacity(capacity)?;
                             self.state = QPackDecoderState::ReadInstruction;
                         } else {
-                            self.state = QPackDecoderState::Capacity { capacity: v, cnt };
+                            self.state = QPackDecoderState::Capacity { decoder };
                             // wait for more data
                             break Ok(());
                         }
@@ -244,23 +258,19 @@ impl QPackDecoder {
                     ref mut state,
                 } => {
                     match state {
-                        QPackWithRefState::GetName { ref mut cnt } => {
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn, stream_id, name_index, cnt, 0, 0x0, false,
-                            )?;
-                            if !done {
-                                // waiting for more data
-                                break Ok(());
+                        QPackWithRefState::GetName { ref mut decoder } => {
+                            match read_prefixed_int_from_stream_wrap(decoder, conn, stream_id)? {
+                                Some(index) => *name_index = index,
+                                None => {
+                                    // waiting for more data
+                                    break Ok(());
+                                }
                             }
-                            *state = QPackWithRefState::GetValueLength { len: 0, cnt: 0 };
+                            *state = QPackWithRefState::GetValueLength { decoder: None };
                         }
-                        QPackWithRefState::GetValueLength {
-                            ref mut len,
-                            ref mut cnt,
-                        } => {
-                            let mut b = [0; 1];
-                            let mut prefix_len = 0;
-                            if *cnt == 0 {
+                        QPackWithRefState::GetValueLength { ref mut decoder } => {
+                            if decoder.is_none() {
+                                let mut b = [0; 1];
                                 match conn.stream_recv(stream_id, &mut b) {
                                     Err(_) => break Err(Error::DecoderStreamError),
                                     Ok((amount, fin)) => {
@@ -273,23 +283,22 @@ impl QPackDecoder {
                                         }
                                     }
                                 }
-                                prefix_len = 1;
                                 *value_is_huffman = b[0] & 0x80 != 0;
+                                *decoder =
+                                    Some(IncrementalDecoder::decode_prefixed_int_from(1, b[0]));
                             }
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
+                            let len = match read_prefixed_int_from_stream_wrap(
+                                decoder.as_mut().unwrap(),
                                 conn,
                                 stream_id,
-                                len,
-                                cnt,
-                                prefix_len,
-                                b[0],
-                                prefix_len > 0,
-                            )?;
-                            if !done {
-                                // waiting for more data
-                                break Ok(());
-                            }
-                            *value = vec![0; *len as usize];
+                            )? {
+                                Some(len) => len,
+                                None => {
+                                    // waiting for more data
+                                    break Ok(());
+                                }
+                            };
+                            *value = vec![0; len as usize];
                             *state = QPackWithRefState::GetValue { offset: 0 };
                         }
                         QPackWithRefState::GetValue { ref mut offset } => {
@@ -335,18 +344,14 @@ impl QPackDecoder {
                     ref mut state,
                 } => {
                     match state {
-                        QPackWithoutRefState::GetNameLength {
-                            ref mut len,
-                            ref mut cnt,
-                        } => {
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
-                                conn, stream_id, len, cnt, 0, 0x0, false,
-                            )?;
-                            if !done {
-                                // waiting for more data
-                                break Ok(());
+                        QPackWithoutRefState::GetNameLength { ref mut decoder } => {
+                            match read_prefixed_int_from_stream_wrap(decoder, conn, stream_id)? {
+                                Some(len) => *name = vec![0; len as usize],
+                                None => {
+                                    // waiting for more data
+                                    break Ok(());
+                                }
                             }
-                            *name = vec![0; *len as usize];
                             *state = QPackWithoutRefState::GetName { offset: 0 };
                         }
                         QPackWithoutRefState::GetName { offset } => {
@@ -361,19 +366,15 @@ impl QPackDecoder {
                             }
 
                             if name.len() == *offset {
-                                *state = QPackWithoutRefState::GetValueLength { len: 0, cnt: 0 };
+                                *state = QPackWithoutRefState::GetValueLength { decoder: None };
                             } else {
                                 // waiting for more data
                                 break Ok(());
                             }
                         }
-                        QPackWithoutRefState::GetValueLength {
-                            ref mut len,
-                            ref mut cnt,
-                        } => {
-                            let mut b = [0; 1];
-                            let mut prefix_len = 0;
-                            if *cnt == 0 {
+                        QPackWithoutRefState::GetValueLength { ref mut decoder } => {
+                            if decoder.is_none() {
+                                let mut b = [0; 1];
                                 match conn.stream_recv(stream_id, &mut b) {
                                     Err(_) => break Err(Error::DecoderStreamError),
                                     Ok((amount, fin)) => {
@@ -386,23 +387,22 @@ impl QPackDecoder {
                                         }
                                     }
                                 }
-                                prefix_len = 1;
                                 *value_is_huffman = b[0] & 0x80 != 0;
+                                *decoder =
+                                    Some(IncrementalDecoder::decode_prefixed_int_from(1, b[0]));
                             }
-                            let done = read_prefixed_encoded_int_with_connection_wrap(
+                            let len = match read_prefixed_int_from_stream_wrap(
+                                decoder.as_mut().unwrap(),
                                 conn,
                                 stream_id,
-                                len,
-                                cnt,
-                                prefix_len,
-                                b[0],
-                                prefix_len > 0,
-                            )?;
-                            if !done {
-                                // waiting for more data
-                                break Ok(());
-                            }
-                            *value = vec![0; *len as usize];
+                            )? {
+                                Some(len) => len,
+                                None => {
+                                    // waiting for more data
+                                    break Ok(());
+                                }
+                            };
+                            *value = vec![0; len as usize];
                             *state = QPackWithoutRefState::GetValue { offset: 0 };
                         }
                         QPackWithoutRefState::GetValue { ref mut offset } => {
@@ -442,16 +442,12 @@ impl QPackDecoder {
                         }
                     }
                 }
-                QPackDecoderState::Duplicate {
-                    ref mut index,
-                    ref mut cnt,
-                } => {
-                    let done = read_prefixed_encoded_int_with_connection_wrap(
-                        conn, stream_id, index, cnt, 0, 0x0, false,
-                    )?;
-                    if done {
+                QPackDecoderState::Duplicate { ref mut decoder } => {
+                    if let Some(index) =
+                        read_prefixed_int_from_stream_wrap(decoder, conn, stream_id)?
+                    {
                         qdebug!([label], "received instruction - duplicate index={}", index);
-                        self.table.duplicate(*index)?;
+                        self.table.duplicate(index)?;
                         self.total_num_of_inserts += 1;
                         self.increment += 1;
                         self.state = QPackDecoderState::ReadInstruction;
@@ -460,16 +456,11 @@ impl QPackDecoder {
                         break Ok(());
                     }
                 }
-                QPackDecoderState::Capacity {
-                    ref mut capacity,
-                    ref mut cnt,
-                } => {
-                    let done = read_prefixed_encoded_int_with_connection_wrap(
-                        conn, stream_id, capacity, cnt, 0, 0x0, false,
-                    )?;
-                    if done {
-                        let v = *capacity;
-                        self.set_capacity(v)?;
+                QPackDecoderState::Capacity { ref mut decoder } => {
+                    if let Some(capacity) =
+                        read_prefixed_int_from_stream_wrap(decoder, conn, stream_id)?
+                    {
+                        self.set_capacity(capacity)?;
                         self.state = QPackDecoderState::ReadInstruction;
                     } else {
                         // waiting for more data
@@ -524,6 +515,17 @@ impl QPackDecoder {
 
     // this function returns None if the stream is blocked waiting for table insertions.
     pub fn decode_header_block(&mut self, buf: &[u8], stream_id: u64) -> Res<Option<Vec<Header>>> {
+        Ok(self
+            .decode_header_block_with_indexing(buf, stream_id)?
+            .map(|h| h.into_iter().map(|(header, _)| header).collect()))
+    }
+
+    /// As `decode_header_block`, but also say how each header was represented.
+    pub fn decode_header_block_with_indexing(
+        &mut self,
+        buf: &[u8],
+        stream_id: u64,
+    ) -> Res<Option<Vec<(Header, HeaderIndexing)>>> {
         qdebug!([self], "decode header block.");
         let mut reader = BufWrapper { buf, offset: 0 };
 
@@ -547,7 +549,7 @@ impl QPackDecoder {
             }
             return Ok(None);
         }
-        let mut h: Vec<Header> = Vec::new();
+        let mut h: Vec<(Header, HeaderIndexing)> = Vec::new();
 
         loop {
             if reader.done() {
@@ -591,35 +593,54 @@ impl QPackDecoder {
         Ok((req_insert_cnt, base))
     }
 
-    fn read_indexed(&self, buf: &mut BufWrapper, base: u64) -> Res<Header> {
+    fn read_indexed(&self, buf: &mut BufWrapper, base: u64) -> Res<(Header, HeaderIndexing)> {
         let static_table = buf.peek()? & 0x40 != 0;
         let index = read_prefixed_encoded_int_slice(buf, 2)?;
         qdebug!([self], "decoder indexed {} static={}.", index, static_table);
-        if static_table {
+        let (header, source) = if static_table {
             match self.table.get_static(index) {
-                Ok(entry) => Ok((to_string(entry.name())?, to_string(entry.value())?)),
-                Err(_) => Err(Error::DecompressionFailed),
+                Ok(entry) => (
+                    (to_string(entry.name())?, to_string(entry.value())?),
+                    FieldSource::Static(index),
+                ),
+                Err(_) => return Err(Error::DecompressionFailed),
             }
         } else if let Ok(entry) = self.table.get_dynamic(index, base, false) {
-            Ok((to_string(entry.name())?, to_string(entry.value())?))
+            (
+                (to_string(entry.name())?, to_string(entry.value())?),
+                FieldSource::Dynamic(base - index - 1),
+            )
         } else {
-            Err(Error::DecompressionFailed)
-        }
+            return Err(Error::DecompressionFailed);
+        };
+        Ok((header, indexing(source, source, false)))
     }
 
-    fn read_post_base_index(&self, buf: &mut BufWrapper, base: u64) -> Res<Header> {
+    fn read_post_base_index(
+        &self,
+        buf: &mut BufWrapper,
+        base: u64,
+    ) -> Res<(Header, HeaderIndexing)> {
         let index = read_prefixed_encoded_int_slice(buf, 4)?;
         qdebug!([self], "decode post-based {}.", index);
         if let Ok(entry) = self.table.get_dynamic(index, base, true) {
-            Ok((to_string(entry.name())?, to_string(entry.value())?))
+            let source = FieldSource::Dynamic(base + index);
+            Ok((
+                (to_string(entry.name())?, to_string(entry.value())?),
+                indexing(source, source, false),
+            ))
         } else {
             Err(Error::DecompressionFailed)
         }
     }
 
-    fn read_literal_with_name_ref(&self, buf: &mut BufWrapper, base: u64) -> Res<Header> {
+    fn read_literal_with_name_ref(
+        &self,
+        buf: &mut BufWrapper,
+        base: u64,
+    ) -> Res<(Header, HeaderIndexing)> {
         qdebug!([self], "read literal with name reference.");
-        // ignore n bit.
+        let never_indexed = buf.peek()? & 0x20 != 0;
         let static_table = buf.peek()? & 0x10 != 0;
         let index = read_prefixed_encoded_int_slice(buf, 4)?;
 
@@ -651,12 +672,30 @@ impl QPackDecoder {
             static_table,
             value
         );
-        Ok((to_string(&name)?, to_string(&value)?))
+        let name_source = if static_table {
+            FieldSource::Static(index)
+        } else {
+            FieldSource::Dynamic(base - index - 1)
+        };
+        Ok((
+            (to_string(&name)?, to_string(&value)?),
+            indexing(
+                name_source,
+                FieldSource::Literal {
+                    huffman: value_is_huffman,
+                },
+                never_indexed,
+            ),
+        ))
     }
 
-    fn read_literal_with_post_base_name_ref(&self, buf: &mut BufWrapper, base: u64) -> Res<Header> {
+    fn read_literal_with_post_base_name_ref(
+        &self,
+        buf: &mut BufWrapper,
+        base: u64,
+    ) -> Res<(Header, HeaderIndexing)> {
         qdebug!([self], "decoder literal with post-based index.");
-        // ignore n bit.
+        let never_indexed = buf.peek()? & 0x08 != 0;
         let index = read_prefixed_encoded_int_slice(buf, 5)?;
         let name: Vec<u8>;
         if let Ok(entry) = self.table.get_dynamic(index, base, true) {
@@ -675,13 +714,24 @@ impl QPackDecoder {
         };
 
         qdebug!([self], "name={:x?} value={:x?}.", name, value);
-        Ok((to_string(&name)?, to_string(&value)?))
+        Ok((
+            (to_string(&name)?, to_string(&value)?),
+            indexing(
+                FieldSource::Dynamic(base + index),
+                FieldSource::Literal {
+                    huffman: value_is_huffman,
+                },
+                never_indexed,
+            ),
+        ))
     }
 
-    fn read_literal_with_name_literal(&self, buf: &mut BufWrapper) -> Res<Header> {
+    fn read_literal_with_name_literal(
+        &self,
+        buf: &mut BufWrapper,
+    ) -> Res<(Header, HeaderIndexing)> {
         qdebug!([self], "decode literal with name literal.");
-        // ignore n bit.
-
+        let never_indexed = buf.peek()? & 0x10 != 0;
         let name_is_huffman = buf.peek()? & 0x08 != 0;
         let name_len = read_prefixed_encoded_int_slice(buf, 5)? as usize;
 
@@ -701,7 +751,18 @@ impl QPackDecoder {
         };
 
         qdebug!([self], "name={:x?} value={:x?}.", name, value);
-        Ok((to_string(&name)?, to_string(&value)?))
+        Ok((
+            (to_string(&name)?, to_string(&value)?),
+            indexing(
+                FieldSource::Literal {
+                    huffman: name_is_huffman,
+                },
+                FieldSource::Literal {
+                    huffman: value_is_huffman,
+                },
+                never_indexed,
+            ),
+        ))
     }
 
     fn calc_req_insert_cnt(&self, encoded: u64) -> Res<u64> {
@@ -761,28 +822,24 @@ impl ::std::fmt::Display for QPackDecoder {
     }
 }
 
-// this wraps read_prefixed_encoded_int_with_connection to return proper error.
-fn read_prefixed_encoded_int_with_connection_wrap(
+fn indexing(name: FieldSource, value: FieldSource, never_indexed: bool) -> HeaderIndexing {
+    HeaderIndexing {
+        name,
+        value,
+        never_indexed,
+    }
+}
+
+// this wraps read_prefixed_int_from_stream to return proper error.
+fn read_prefixed_int_from_stream_wrap(
+    decoder: &mut IncrementalDecoder,
     conn: &mut Connection,
     stream_id: u64,
-    val: &mut u64,
-    cnt: &mut u8,
-    prefix_len: u8,
-    first_byte: u8,
-    have_first_byte: bool,
-) -> Res<bool> {
-    match read_prefixed_encoded_int_with_connection(
-        conn,
-        stream_id,
-        val,
-        cnt,
-        prefix_len,
-        first_byte,
-        have_first_byte,
-    ) {
+) -> Res<Option<u64>> {
+    match read_prefixed_int_from_stream(decoder, conn, stream_id) {
         Err(Error::ClosedCriticalStream) => Err(Error::ClosedCriticalStream),
         Err(_) => Err(Error::DecoderStreamError),
-        Ok(done) => Ok(done),
+        Ok(v) => Ok(v),
     }
 }
 
@@ -906,6 +963,29 @@ mod tests {
         );
     }
 
+    // Each byte of these instructions arrives on its own, so every integer is
+    // read over several calls.
+    #[test]
+    fn test_recv_instructions_split() {
+        let (mut decoder, mut conn_c, mut conn_s, recv_stream_id, _) = connect();
+        let instructions = [
+            0x3f, 0xa9, 0x01, // Set capacity to 200.
+            0x4e, 0x63, 0x6f, 0x6e, 0x74, 0x65, 0x6e, 0x74, 0x2d, 0x6c, 0x65, 0x6e, 0x67, 0x74,
+            0x68, 0x04, 0x31, 0x32, 0x33, 0x34, // Insert with a name literal.
+            0x00, // Duplicate it.
+        ];
+        for b in &instructions {
+            let _ = conn_s.stream_send(recv_stream_id, &[*b]);
+            let out = conn_s.process(None, now());
+            conn_c.process(out.dgram(), now());
+            decoder
+                .read_instructions(&mut conn_c, recv_stream_id)
+                .unwrap();
+        }
+        assert_eq!(decoder.capacity(), 200);
+        assert_eq!(decoder.insert_count(), 2);
+    }
+
     // this test tests header decoding, the header acks command and the insert count increment command.
     #[test]
     fn test_duplicate() {
@@ -1150,4 +1230,62 @@ mod tests {
         }
         assert!(found_instruction);
     }
+
+    #[test]
+    fn test_header_block_indexing() {
+        let (mut decoder, mut conn_c, mut conn_s, recv_stream_id, _) = connect();
+        assert!(decoder.set_capacity(200).is_ok());
+        // Insert my-header: my-value.
+        let _ = conn_s.stream_send(
+            recv_stream_id,
+            &[
+                0x49, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x08, 0x6d, 0x79, 0x2d,
+                0x76, 0x61, 0x6c, 0x75, 0x65,
+            ],
+        );
+        let out = conn_s.process(None, now());
+        conn_c.process(out.dgram(), now());
+        assert!(decoder
+            .read_instructions(&mut conn_c, recv_stream_id)
+            .is_ok());
+
+        let mut decode = |block: &[u8], stream_id| {
+            decoder
+                .decode_header_block_with_indexing(block, stream_id)
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .map(|(_, i)| i)
+                .collect::<Vec<_>>()
+        };
+        let literal = FieldSource::Literal { huffman: false };
+        assert_eq!(
+            decode(
+                &[0x00, 0x00, 0xd1, 0x71, 0x01, 0x2f, 0x21, 0x61, 0x81, 0x07, 0x50, 0x00],
+                0
+            ),
+            vec![
+                indexing(FieldSource::Static(17), FieldSource::Static(17), false),
+                indexing(FieldSource::Static(1), literal, true),
+                indexing(literal, FieldSource::Literal { huffman: true }, false),
+                indexing(FieldSource::Static(0), literal, false),
+            ]
+        );
+        // The one entry in the dynamic table, referred to before and after the base.
+        let dynamic = FieldSource::Dynamic(0);
+        assert_eq!(
+            decode(&[0x02, 0x00, 0x80, 0x40, 0x00], 4),
+            vec![
+                indexing(dynamic, dynamic, false),
+                indexing(dynamic, literal, false),
+            ]
+        );
+        assert_eq!(
+            decode(&[0x02, 0x80, 0x10, 0x08, 0x00], 8),
+            vec![
+                indexing(dynamic, dynamic, false),
+                indexing(dynamic, literal, true),
+            ]
+        );
+    }
 }
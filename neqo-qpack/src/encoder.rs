@@ -7,12 +7,12 @@
 #![allow(unused_variables, dead_code)]
 
 use crate::huffman::encode_huffman;
-use crate::qpack_helper::read_prefixed_encoded_int_with_connection;
+use crate::qpack_helper::read_prefixed_int_from_stream;
 use crate::qpack_send_buf::QPData;
 use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::{qdebug, qtrace};
+use neqo_common::{qdebug, qtrace, IncrementalDecoder};
 use neqo_transport::Connection;
 
 pub const QPACK_UNI_STREAM_TYPE_ENCODER: u64 = 0x2;
@@ -40,8 +40,7 @@ pub struct QPackEncoder {
     send_buf: QPData,
     max_entries: u64,
     instruction_reader_current_inst: Option<DecoderInstructions>,
-    instruction_reader_value: u64, // this is instrunction dependent value.
-    instruction_reader_cnt: u8, // this is helper variable for reading a prefixed integer encoded value
+    instruction_reader: IncrementalDecoder, // reads the instruction dependent value.
     local_stream_id: Option<u64>,
     remote_stream_id: Option<u64>,
     max_blocked_streams: u16,
@@ -57,8 +56,7 @@ impl QPackEncoder {
             send_buf: QPData::default(),
             max_entries: 0,
             instruction_reader_current_inst: None,
-            instruction_reader_value: 0,
-            instruction_reader_cnt: 0,
+            instruction_reader: IncrementalDecoder::default(),
             local_stream_id: None,
             remote_stream_id: None,
             max_blocked_streams: 0,
@@ -79,6 +77,15 @@ impl QPackEncoder {
         Ok(())
     }
 
+    pub fn capacity(&self) -> u64 {
+        self.table.capacity()
+    }
+
+    /// How much of the dynamic table capacity is in use.
+    pub fn table_used(&self) -> u64 {
+        self.table.used()
+    }
+
     pub fn set_max_blocked_streams(&mut self, blocked_streams: u64) -> Res<()> {
         if blocked_streams > (1 << 16) - 1 {
             return Err(Error::EncoderStreamError);
@@ -105,92 +112,53 @@ impl QPackEncoder {
     fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
         qdebug!([self], "read a new instraction");
         loop {
-            match self.instruction_reader_current_inst {
-                None => {
-                    // get new instruction
-                    let mut b = [0];
-                    match conn.stream_recv(stream_id, &mut b) {
-                        Err(_) => break Err(Error::EncoderStreamError),
-                        Ok((amount, fin)) => {
-                            if fin {
-                                break Err(Error::ClosedCriticalStream);
-                            }
-                            if amount != 1 {
-                                // wait for more data.
-                                break Ok(());
-                            }
+            if self.instruction_reader_current_inst.is_none() {
+                // get new instruction
+                let mut b = [0];
+                match conn.stream_recv(stream_id, &mut b) {
+                    Err(_) => break Err(Error::EncoderStreamError),
+                    Ok((amount, fin)) => {
+                        if fin {
+                            break Err(Error::ClosedCriticalStream);
                         }
-                    }
-                    self.instruction_reader_current_inst = Some(get_instruction(b[0]));
-
-                    // try to read data
-                    let prefix_len = if (b[0] & 0x80) != 0 { 1 } else { 2 };
-                    match read_prefixed_encoded_int_with_connection(
-                        conn,
-                        stream_id,
-                        &mut self.instruction_reader_value,
-                        &mut self.instruction_reader_cnt,
-                        prefix_len,
-                        b[0],
-                        true,
-                    ) {
-                        Ok(done) => {
-                            if done {
-                                self.call_instruction();
-                            } else {
-                                // wait for more data.
-                                break Ok(());
-                            }
+                        if amount != 1 {
+                            // wait for more data.
+                            break Ok(());
                         }
-                        Err(Error::ClosedCriticalStream) => break Err(Error::ClosedCriticalStream),
-                        Err(_) => break Err(Error::EncoderStreamError),
                     }
                 }
-                Some(_) => {
-                    match read_prefixed_encoded_int_with_connection(
-                        conn,
-                        stream_id,
-                        &mut self.instruction_reader_value,
-                        &mut self.instruction_reader_cnt,
-                        0,
-                        0x0,
-                        false,
-                    ) {
-                        Ok(done) => {
-                            if done {
-                                self.call_instruction();
-                            } else {
-                                // wait for more data.
-                                break Ok(());
-                            }
-                        }
-                        Err(Error::ClosedCriticalStream) => break Err(Error::ClosedCriticalStream),
-                        Err(_) => break Err(Error::EncoderStreamError),
-                    }
+                self.instruction_reader_current_inst = Some(get_instruction(b[0]));
+
+                // try to read data
+                let prefix_len = if (b[0] & 0x80) != 0 { 1 } else { 2 };
+                self.instruction_reader =
+                    IncrementalDecoder::decode_prefixed_int_from(prefix_len, b[0]);
+            }
+            match read_prefixed_int_from_stream(&mut self.instruction_reader, conn, stream_id) {
+                Ok(Some(v)) => self.call_instruction(v),
+                Ok(None) => {
+                    // wait for more data.
+                    break Ok(());
                 }
+                Err(Error::ClosedCriticalStream) => break Err(Error::ClosedCriticalStream),
+                Err(_) => break Err(Error::EncoderStreamError),
             }
         }
     }
 
-    fn call_instruction(&mut self) {
+    fn call_instruction(&mut self, value: u64) {
         if let Some(inst) = &self.instruction_reader_current_inst {
             qdebug!([self], "call intruction {:?}", inst);
             match inst {
                 DecoderInstructions::InsertCountIncrement => {
-                    self.table.increment_acked(self.instruction_reader_value);
+                    self.table.increment_acked(value);
                     let inserts = self.table.get_acked_inserts_cnt();
                     self.blocked_streams.retain(|req| *req <= inserts);
                 }
-                DecoderInstructions::HeaderAck => {
-                    self.table.header_ack(self.instruction_reader_value)
-                }
-                DecoderInstructions::StreamCancellation => {
-                    self.table.header_ack(self.instruction_reader_value)
-                }
+                DecoderInstructions::HeaderAck => self.table.header_ack(value),
+                DecoderInstructions::StreamCancellation => self.table.header_ack(value),
             }
             self.instruction_reader_current_inst = None;
-            self.instruction_reader_value = 0;
-            self.instruction_reader_cnt = 0;
         } else {
             panic!("We must have a instruction decoded beforewe call call_instruction");
         }
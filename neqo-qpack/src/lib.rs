@@ -17,6 +17,26 @@ mod static_table;
 mod table;
 
 pub type Header = (String, String);
+
+/// Where the name or the value of a header field came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldSource {
+    /// The entry in the static table at this index.
+    Static(u64),
+    /// The entry in the dynamic table with this absolute index.
+    Dynamic(u64),
+    /// A string literal, which might have been Huffman encoded.
+    Literal { huffman: bool },
+}
+
+/// How a header field was represented in a header block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderIndexing {
+    pub name: FieldSource,
+    pub value: FieldSource,
+    /// Whether the encoder asked that the field never be added to a table.
+    pub never_indexed: bool,
+}
 type Res<T> = Result<T, Error>;
 
 #[derive(Debug)]
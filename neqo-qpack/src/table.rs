@@ -84,6 +84,10 @@ impl HeaderTable {
         self.capacity
     }
 
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
     pub fn set_capacity(&mut self, c: u64) {
         self.evict_to(c);
         self.capacity = c;
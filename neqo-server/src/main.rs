@@ -6,17 +6,26 @@
 
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 
+use neqo_common::metrics::Registry;
 use neqo_common::Datagram;
-use neqo_crypto::{init_db, AntiReplay};
-use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State};
+use neqo_crypto::{init_db, AntiReplay, KeyLog, KeyLogFile, ServerCertificate};
+use neqo_transport::{
+    tp_constants, Connection, ConnectionEvent, FixedConnectionIdManager, State, TransportParameter,
+};
+use neqo_udp::Socket;
 use regex::Regex;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
 use std::path::PathBuf;
+use std::process::exit;
 use std::rc::Rc;
+use std::thread;
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
@@ -38,6 +47,13 @@ struct Args {
     #[structopt(short = "k", long, default_value = "key")]
     /// Name of keys from NSS database.
     key: Vec<String>,
+    #[structopt(long)]
+    /// A certificate for clients that ask for a particular name, as
+    /// NAME=CERT,KEY where CERT and KEY are PEM files.
+    ///
+    /// A NAME like "*.example.com" is for any name with one more label.
+    /// Clients that ask for other names get the keys from the database.
+    sni: Vec<String>,
 
     #[structopt(short = "a", long, default_value = "http/0.9")]
     /// ALPN labels to negotiate.
@@ -51,6 +67,18 @@ struct Args {
     #[structopt(short = "6", long)]
     /// Restrict to IPv6.
     ipv6: bool,
+
+    #[structopt(long)]
+    /// Serve metrics in the Prometheus text format on this address.
+    metrics: Option<SocketAddr>,
+
+    #[structopt(long, default_value = "0")]
+    /// Mark sent datagrams with this DSCP, from 0 to 63.
+    dscp: u8,
+
+    #[structopt(long)]
+    /// Add TLS secrets to this file, in the format of SSLKEYLOGFILE.
+    keylog: Option<PathBuf>,
 }
 
 impl Args {
@@ -115,13 +143,54 @@ fn http_serve(server: &mut Connection, stream: u64) {
     server.stream_close_send(stream).expect("Stream closed");
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Datagram) {
-    let sent = socket
-        .send_to(&d[..], d.destination())
-        .expect("Error sending datagram");
-    if sent != d.len() {
-        eprintln!("Unable to send all {} bytes of datagram", d.len());
-    }
+/// Load a certificate for `--sni`, from NAME=CERT,KEY.
+fn load_sni_certificate(spec: &str) -> (String, ServerCertificate) {
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().unwrap();
+    let files = parts.next().expect("--sni needs NAME=CERT,KEY");
+    let mut files = files.splitn(2, ',');
+    let cert = files.next().unwrap();
+    let key = files.next().expect("--sni needs NAME=CERT,KEY");
+    let cert = fs::read_to_string(cert).expect("can read the certificate");
+    let key = fs::read_to_string(key).expect("can read the key");
+    let certificate = ServerCertificate::from_pem(&cert, &key).expect("can decode the PEM");
+    (name.to_string(), certificate)
+}
+
+/// The largest DATAGRAM frame that is accepted, and echoed.
+const DATAGRAM_FRAME_SIZE: u64 = 1200;
+
+fn emit_datagram(socket: &mut Socket, d: Datagram) {
+    socket.send(&[d]).expect("Error sending datagram");
+}
+
+/// Answer every request on `addr` with the metrics in `registry`, in the
+/// Prometheus text format.  Requests aren't parsed: any path works.
+fn serve_metrics(addr: SocketAddr, registry: Registry) {
+    let listener = TcpListener::bind(addr).expect("Unable to bind metrics address");
+    println!("Metrics on: {:?}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Metrics connection failed: {}", e);
+                    continue;
+                }
+            };
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let body = registry.snapshot().prometheus();
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                eprintln!("Unable to send metrics: {}", e);
+            }
+        }
+    });
 }
 
 fn main() {
@@ -129,65 +198,132 @@ fn main() {
     assert!(!args.key.is_empty(), "Need at least one key");
 
     init_db(args.db.clone());
+    let sni_certificates = args
+        .sni
+        .iter()
+        .map(|s| load_sni_certificate(s))
+        .collect::<Vec<_>>();
     let anti_replay = AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
         .expect("unable to setup anti-replay");
 
     // TODO(mt): listen on both v4 and v6.
-    let socket = UdpSocket::bind(args.bind()).expect("Unable to bind UDP socket");
+    let mut socket = Socket::bind(args.bind()).expect("Unable to bind UDP socket");
+    if args.dscp >= 64 {
+        eprintln!("DSCP {} doesn't fit in six bits", args.dscp);
+        exit(1)
+    }
+    socket.set_dscp(args.dscp);
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
+    let local_addr = socket.local_addr();
 
     println!("Server waiting for connection on: {:?}", local_addr);
 
-    let buf = &mut [0u8; 2048];
+    // Every connection adds to the same file.
+    let key_log = args.keylog.as_ref().map(|path| {
+        let log = KeyLogFile::open(path).expect("can open the key log file");
+        Rc::new(RefCell::new(log)) as Rc<RefCell<dyn KeyLog>>
+    });
+
+    // Connections add their packet counts to this as well.
+    let registry = Registry::default();
+    let handshakes = registry.counter(
+        "neqo_handshakes_total",
+        "Connections that finished the handshake.",
+    );
+    let open = registry.gauge("neqo_connections", "Connections that are open.");
+    let cwnd = registry.gauge(
+        "neqo_cwnd_bytes",
+        "The congestion windows of open connections, added together.",
+    );
+    let rtt = registry.histogram(
+        "neqo_rtt_milliseconds",
+        "The final RTT of connections that closed.",
+        &[1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500],
+    );
+    if let Some(addr) = args.metrics {
+        serve_metrics(addr, registry.clone());
+    }
+
     let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
     loop {
         // TODO use timer to set socket.set_read_timeout.
-        let (sz, remote_addr) = socket.recv_from(&mut buf[..]).expect("UDP error");
-        if sz == buf.len() {
-            eprintln!("Discarding packet that might be truncated");
-            continue;
-        }
+        let dgrams = socket.recv().expect("UDP error");
+        // Everything that is done for this batch uses the same time.
+        let now = Instant::now();
+        for dgram in dgrams {
+            let remote_addr = dgram.source();
+            let mut server = connections.entry(remote_addr).or_insert_with(|| {
+                println!("New connection from {:?}", remote_addr);
+                let mut c = Connection::new_server(
+                    &args.key,
+                    &args.alpn,
+                    &anti_replay,
+                    Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+                )
+                .expect("can't create connection");
+                c.set_metrics(&registry);
+                // Enable QUIC datagrams so that they can be echoed.
+                c.set_local_tparam(
+                    tp_constants::MAX_DATAGRAM_FRAME_SIZE,
+                    TransportParameter::Integer(DATAGRAM_FRAME_SIZE),
+                )
+                .expect("can set datagram frame size");
+                if let Some(log) = &key_log {
+                    c.set_key_log(Rc::clone(log));
+                }
+                for (name, certificate) in &sni_certificates {
+                    c.set_certificate_for(name, certificate.clone())
+                        .expect("can set the certificate");
+                }
+                c
+            });
 
-        let mut server = connections.entry(remote_addr).or_insert_with(|| {
-            println!("New connection from {:?}", remote_addr);
-            Connection::new_server(
-                &args.key,
-                &args.alpn,
-                &anti_replay,
-                Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
-            )
-            .expect("can't create connection")
-        });
+            if !dgram.is_empty() {
+                server.process_input(dgram, now);
+            }
+            if let State::Closed(e) = server.state() {
+                eprintln!("Closed connection from {:?}: {:?}", remote_addr, e);
+                rtt.observe(u64::try_from(server.stats().rtt.as_millis()).unwrap_or(u64::MAX));
+                connections.remove(&remote_addr);
+                continue;
+            }
+            if let State::Closing { error, .. } = server.state() {
+                eprintln!("Closing connection from {:?}: {:?}", remote_addr, error);
+                // TOOD(ekr@rtfm.com): Do I need to remove?
+                continue;
+            }
+            let mut streams = Vec::new();
+            let mut datagrams = Vec::new();
+            while let Some(event) = server.next_event() {
+                match event {
+                    ConnectionEvent::RecvStreamReadable { stream_id } => streams.push(stream_id),
+                    ConnectionEvent::Datagram(data) => datagrams.push(data),
+                    ConnectionEvent::StateChange(State::Connected) => handshakes.inc(),
+                    _ => {}
+                }
+            }
 
-        if sz > 0 {
-            let dgram = Datagram::new(remote_addr, local_addr, &buf[..sz]);
-            server.process_input(dgram, Instant::now());
-        }
-        if let State::Closed(e) = server.state() {
-            eprintln!("Closed connection from {:?}: {:?}", remote_addr, e);
-            connections.remove(&remote_addr);
-            continue;
-        }
-        if let State::Closing { error, .. } = server.state() {
-            eprintln!("Closing connection from {:?}: {:?}", remote_addr, error);
-            // TOOD(ekr@rtfm.com): Do I need to remove?
-            continue;
-        }
-        let mut streams = Vec::new();
-        while let Some(event) = server.next_event() {
-            if let ConnectionEvent::RecvStreamReadable { stream_id } = event {
-                streams.push(stream_id)
+            for data in datagrams {
+                if let Err(e) = server.send_datagram(&data) {
+                    eprintln!("Unable to echo datagram: {:?}", e);
+                }
             }
-        }
 
-        for stream_id in streams {
-            http_serve(&mut server, stream_id);
-        }
+            for stream_id in streams {
+                http_serve(&mut server, stream_id);
+            }
 
-        let out = server.process_output(Instant::now());
-        if let Some(dgram) = out.dgram() {
-            emit_datagram(&socket, dgram);
+            let out = server.process_output(now);
+            if let Some(dgram) = out.dgram() {
+                emit_datagram(&mut socket, dgram);
+            }
         }
+        open.set(i64::try_from(connections.len()).unwrap_or(i64::MAX));
+        cwnd.set(
+            connections
+                .values()
+                .map(|c| i64::try_from(c.stats().cwnd).unwrap_or(i64::MAX))
+                .sum(),
+        );
     }
 }
@@ -0,0 +1,139 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A simulated endpoint built on a transport connection.
+
+use crate::Node;
+use neqo_common::{qdebug, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{Connection, ConnectionEvent, Output, State, StreamType};
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::time::Instant;
+
+const CHUNK: [u8; 4096] = [0x73; 4096];
+
+#[derive(Debug)]
+enum Goal {
+    /// Send this many bytes on a stream, once connected.
+    Send {
+        stream_id: Option<u64>,
+        remaining: usize,
+    },
+    /// Receive this many bytes, on any streams.
+    Receive { remaining: usize },
+}
+
+/// A node that connects and then either sends or receives data.
+#[derive(Debug)]
+pub struct ConnectionNode {
+    c: Connection,
+    goal: Goal,
+}
+
+impl ConnectionNode {
+    /// A node that sends `bytes` on a unidirectional stream once connected.
+    pub fn sender(c: Connection, bytes: usize) -> Self {
+        Self {
+            c,
+            goal: Goal::Send {
+                stream_id: None,
+                remaining: bytes,
+            },
+        }
+    }
+
+    /// A node that reads until it has received `bytes`.
+    pub fn receiver(c: Connection, bytes: usize) -> Self {
+        Self {
+            c,
+            goal: Goal::Receive { remaining: bytes },
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.c
+    }
+
+    fn send(&mut self) {
+        if let Goal::Send {
+            stream_id: Some(stream_id),
+            remaining,
+        } = &mut self.goal
+        {
+            while *remaining > 0 {
+                let sent = self
+                    .c
+                    .stream_send(*stream_id, &CHUNK[..min(*remaining, CHUNK.len())])
+                    .expect("the stream is open");
+                if sent == 0 {
+                    return;
+                }
+                *remaining -= sent;
+            }
+            self.c.stream_close_send(*stream_id).expect("close");
+        }
+    }
+
+    fn recv(&mut self, stream_id: u64) {
+        if let Goal::Receive { remaining } = &mut self.goal {
+            let mut buf = [0; 4096];
+            loop {
+                let (n, _) = self
+                    .c
+                    .stream_recv(stream_id, &mut buf)
+                    .expect("the stream is readable");
+                if n == 0 {
+                    break;
+                }
+                *remaining = remaining.saturating_sub(n);
+            }
+        }
+    }
+
+    fn handle_events(&mut self, now: Instant) {
+        while let Some(e) = self.c.next_event() {
+            qdebug!([self], "event {:?}", e);
+            match e {
+                ConnectionEvent::AuthenticationNeeded => {
+                    self.c.authenticated(AuthenticationStatus::Ok, now);
+                }
+                ConnectionEvent::StateChange(State::Connected) => {
+                    if let Goal::Send { stream_id, .. } = &mut self.goal {
+                        *stream_id = Some(self.c.stream_create(StreamType::UniDi).unwrap());
+                    }
+                    self.send();
+                }
+                ConnectionEvent::SendStreamWritable { .. } => self.send(),
+                ConnectionEvent::RecvStreamReadable { stream_id } => self.recv(stream_id),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Node for ConnectionNode {
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = d {
+            self.c.process_input(d, now);
+        }
+        self.handle_events(now);
+        self.c.process(None, now)
+    }
+
+    fn done(&self) -> bool {
+        match self.goal {
+            Goal::Send { remaining, .. } | Goal::Receive { remaining } => remaining == 0,
+        }
+    }
+}
+
+impl Display for ConnectionNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node {}", self.c)
+    }
+}
@@ -0,0 +1,143 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A network simulator.  This connects two endpoints with links that model
+// delay, jitter, loss, reordering, and a bottleneck with a fixed rate or one
+// that replays a recorded trace, all using virtual time.  The link models take their random choices from a seeded
+// generator, so a run can be repeated exactly as long as the endpoints
+// don't use randomness of their own.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod connection;
+mod link;
+mod trace;
+
+pub use self::connection::ConnectionNode;
+pub use self::link::{Link, LinkConfig, LinkStats, Queue};
+pub use self::trace::{Step, Trace, TraceError};
+
+use neqo_common::{qdebug, Datagram};
+use neqo_transport::Output;
+
+use std::time::{Duration, Instant};
+
+/// Something that can be connected to the simulated network.
+pub trait Node {
+    /// Process a datagram, or just timers if there isn't one, and return
+    /// what to send or how long to wait.
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output;
+    /// Whether this node has finished what it was asked to do.
+    fn done(&self) -> bool;
+}
+
+/// The default limit on how long a simulation can run, in virtual time.
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(600);
+
+/// Two nodes, each with a link that carries what it sends to the other.
+pub struct Simulator {
+    nodes: [Box<dyn Node>; 2],
+    links: [Link; 2],
+    seed: u64,
+    start: Instant,
+    time_limit: Duration,
+}
+
+impl Simulator {
+    /// Connect `a` and `b` with links that deliver everything immediately.
+    /// `seed` determines all the random choices that the links make.
+    pub fn new(a: impl Node + 'static, b: impl Node + 'static, seed: u64) -> Self {
+        let start = test_fixture::now();
+        Self {
+            nodes: [Box::new(a), Box::new(b)],
+            links: [
+                Link::new(LinkConfig::default(), seed, start),
+                Link::new(LinkConfig::default(), seed.wrapping_add(1), start),
+            ],
+            seed,
+            start,
+            time_limit: DEFAULT_TIME_LIMIT,
+        }
+    }
+
+    /// Set the link from `a` to `b` and the link from `b` to `a`.
+    pub fn set_links(&mut self, forward: LinkConfig, back: LinkConfig) {
+        self.links = [
+            Link::new(forward, self.seed, self.start),
+            Link::new(back, self.seed.wrapping_add(1), self.start),
+        ];
+    }
+
+    /// Replay `trace` on link `i`, in place of its rate.  This needs to be
+    /// done after `set_links`.
+    pub fn set_trace(&mut self, i: usize, trace: Trace) {
+        self.links[i].set_trace(trace);
+    }
+
+    /// Set how long the simulation can run before it fails.
+    pub fn set_time_limit(&mut self, limit: Duration) {
+        self.time_limit = limit;
+    }
+
+    /// The link from `a` to `b` (0) or from `b` to `a` (1).
+    pub fn link(&self, i: usize) -> &Link {
+        &self.links[i]
+    }
+
+    /// Send everything that node `i` has and return when it next wants to
+    /// be woken.
+    fn output(&mut self, i: usize, mut out: Output, now: Instant) -> Option<Instant> {
+        loop {
+            match out {
+                Output::Datagram(d) => {
+                    self.links[i].send(d, now);
+                    out = self.nodes[i].process(None, now);
+                }
+                Output::Callback(delay) => return Some(now + delay),
+                Output::None => return None,
+            }
+        }
+    }
+
+    /// Run until both nodes are done, and return how long that took.
+    /// # Panics
+    /// If nothing is left to happen before the nodes are done, or if the
+    /// time limit is reached.
+    pub fn run(&mut self) -> Duration {
+        let mut now = self.start;
+        let mut wake = [Some(now); 2];
+        loop {
+            if self.nodes.iter().all(|n| n.done()) {
+                let elapsed = now - self.start;
+                qdebug!("simulation done after {:?}", elapsed);
+                return elapsed;
+            }
+            assert!(
+                now - self.start <= self.time_limit,
+                "simulation reached the time limit"
+            );
+
+            for i in 0..2 {
+                while let Some(d) = self.links[1 - i].receive(now) {
+                    let out = self.nodes[i].process(Some(d), now);
+                    wake[i] = self.output(i, out, now);
+                }
+                if wake[i].map_or(false, |t| t <= now) {
+                    let out = self.nodes[i].process(None, now);
+                    wake[i] = self.output(i, out, now);
+                }
+            }
+
+            now = wake
+                .iter()
+                .copied()
+                .chain(self.links.iter().map(Link::next_arrival))
+                .flatten()
+                .min()
+                .expect("the simulation stalled");
+        }
+    }
+}
@@ -0,0 +1,495 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A model of a network path in one direction.
+
+use crate::trace::{Trace, TraceState};
+use neqo_common::{qtrace, Datagram};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::cmp::max;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+/// How the queue in front of the bottleneck decides to drop packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Queue {
+    /// Drop packets that don't fit in `limit` bytes.
+    DropTail { limit: usize },
+    /// Random early detection.  Packets are dropped with a probability that
+    /// rises from 0 to `max_p` as the average queue size goes from `min` to
+    /// `max` bytes.  Above `max`, everything is dropped.  The average is an
+    /// exponentially weighted moving average, updated with `weight` for each
+    /// packet.  Packets that don't fit in `limit` bytes are always dropped.
+    Red {
+        min: usize,
+        max: usize,
+        max_p: f64,
+        weight: f64,
+        limit: usize,
+    },
+}
+
+impl Queue {
+    fn limit(&self) -> usize {
+        match self {
+            Self::DropTail { limit } | Self::Red { limit, .. } => *limit,
+        }
+    }
+}
+
+/// The configuration of a link.  The default is a link that delivers
+/// everything immediately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    /// The time it takes for a packet to cross the link.
+    pub delay: Duration,
+    /// Each packet is delayed by a random extra amount up to this much.
+    /// Jitter alone doesn't reorder packets.
+    pub jitter: Duration,
+    /// The probability that a packet is lost.
+    pub loss: f64,
+    /// The probability that a packet is held back by `reorder_delay`,
+    /// so that packets sent after it can overtake it.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// The rate of the bottleneck, in bytes per second.  Without this or a
+    /// trace, packets don't queue.
+    pub rate: Option<u64>,
+    /// The queue in front of the bottleneck.
+    pub queue: Queue,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_secs(0),
+            rate: None,
+            queue: Queue::DropTail { limit: 64 * 1024 },
+        }
+    }
+}
+
+/// What happened to packets that were sent on a link.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    pub sent: usize,
+    pub delivered: usize,
+    /// Packets dropped by random loss.
+    pub lost: usize,
+    /// Packets dropped by the queue.
+    pub dropped: usize,
+    pub reordered: usize,
+}
+
+/// A link carries datagrams in one direction.  All of the random choices it
+/// makes come from a generator with a fixed seed, so the same packets sent at
+/// the same times always have the same fate.
+#[derive(Debug)]
+pub struct Link {
+    config: LinkConfig,
+    rng: StdRng,
+    start: Instant,
+    /// A trace that replaces the rate of the bottleneck.
+    trace: Option<TraceState>,
+    /// When the bottleneck finishes sending everything that is queued.
+    busy_until: Instant,
+    /// The times that queued packets leave the queue, and their sizes.
+    queued: VecDeque<(Instant, usize)>,
+    queued_bytes: usize,
+    /// The average queue size, for RED.
+    avg_queue: f64,
+    /// When the last packet that wasn't reordered arrives.
+    last_arrival: Instant,
+    /// Packets in flight, by arrival time and then the order they were sent.
+    in_flight: BTreeMap<(Instant, u64), Datagram>,
+    next_seq: u64,
+    stats: LinkStats,
+}
+
+impl Link {
+    pub fn new(config: LinkConfig, seed: u64, now: Instant) -> Self {
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            start: now,
+            trace: None,
+            busy_until: now,
+            queued: VecDeque::new(),
+            queued_bytes: 0,
+            avg_queue: 0.0,
+            last_arrival: now,
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Replay `trace` in place of the configured rate, starting from when
+    /// the link was made.  The delay from a CSV trace is added to the
+    /// configured delay.
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(TraceState::new(trace, self.start));
+    }
+
+    pub fn stats(&self) -> &LinkStats {
+        &self.stats
+    }
+
+    /// The number of bytes waiting at the bottleneck.
+    pub fn queued(&self) -> usize {
+        self.queued_bytes
+    }
+
+    fn dequeue(&mut self, now: Instant) {
+        while let Some((t, len)) = self.queued.front() {
+            if *t > now {
+                break;
+            }
+            self.queued_bytes -= len;
+            self.queued.pop_front();
+        }
+    }
+
+    /// Decide whether the queue drops a packet of `len` bytes.
+    fn queue_drop(&mut self, len: usize) -> bool {
+        if self.queued_bytes + len > self.config.queue.limit() {
+            return true;
+        }
+        if let Queue::Red {
+            min,
+            max,
+            max_p,
+            weight,
+            ..
+        } = self.config.queue
+        {
+            self.avg_queue = (1.0 - weight) * self.avg_queue + weight * self.queued_bytes as f64;
+            if self.avg_queue < min as f64 {
+                false
+            } else if self.avg_queue >= max as f64 {
+                true
+            } else {
+                let p = max_p * (self.avg_queue - min as f64) / (max - min) as f64;
+                self.rng.gen::<f64>() < p
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Start sending `len` bytes at the bottleneck and return when that is
+    /// done.
+    fn transmit(&mut self, len: usize, now: Instant) -> Instant {
+        if let Some(trace) = &mut self.trace {
+            return trace.transmit(len, now);
+        }
+        let rate = self.config.rate.expect("the link has a bottleneck");
+        let tx_time =
+            Duration::from_nanos(u64::try_from(len).unwrap() * 1_000_000_000 / max(rate, 1));
+        self.busy_until = max(now, self.busy_until) + tx_time;
+        self.busy_until
+    }
+
+    fn random_delay(&mut self, limit: Duration) -> Duration {
+        let nanos = u64::try_from(limit.as_nanos()).unwrap();
+        if nanos == 0 {
+            limit
+        } else {
+            Duration::from_nanos(self.rng.gen_range(0, nanos + 1))
+        }
+    }
+
+    /// Send a datagram at `now`.
+    pub fn send(&mut self, d: Datagram, now: Instant) {
+        self.stats.sent += 1;
+
+        let departure = if self.trace.is_some() || self.config.rate.is_some() {
+            self.dequeue(now);
+            if self.queue_drop(d.len()) {
+                qtrace!("link queue drop len={}", d.len());
+                self.stats.dropped += 1;
+                return;
+            }
+            let departure = self.transmit(d.len(), now);
+            self.queued.push_back((departure, d.len()));
+            self.queued_bytes += d.len();
+            departure
+        } else {
+            now
+        };
+
+        if self.config.loss > 0.0 && self.rng.gen::<f64>() < self.config.loss {
+            qtrace!("link loss len={}", d.len());
+            self.stats.lost += 1;
+            return;
+        }
+
+        let jitter = self.random_delay(self.config.jitter);
+        let delay = self.config.delay
+            + self
+                .trace
+                .as_ref()
+                .map_or(Duration::from_secs(0), |t| t.delay(departure));
+        let mut arrival = departure + delay + jitter;
+        if self.config.reorder > 0.0 && self.rng.gen::<f64>() < self.config.reorder {
+            self.stats.reordered += 1;
+            arrival += self.config.reorder_delay;
+        } else {
+            arrival = max(arrival, self.last_arrival);
+            self.last_arrival = arrival;
+        }
+
+        self.in_flight.insert((arrival, self.next_seq), d);
+        self.next_seq += 1;
+    }
+
+    /// When the next packet arrives, if there is one in flight.
+    pub fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.keys().next().map(|(t, _)| *t)
+    }
+
+    /// Take a datagram that has arrived by `now`.
+    pub fn receive(&mut self, now: Instant) -> Option<Datagram> {
+        let key = *self.in_flight.keys().next()?;
+        if key.0 > now {
+            return None;
+        }
+        self.stats.delivered += 1;
+        self.in_flight.remove(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_fixture::{loopback, now};
+
+    fn dgram(len: usize) -> Datagram {
+        Datagram::new(loopback(), loopback(), vec![0; len])
+    }
+
+    /// Send `count` packets, one every `interval`, and return when each
+    /// arrived, or `None` for those that didn't.
+    fn run(config: LinkConfig, seed: u64, count: usize, interval: Duration) -> Vec<Option<u32>> {
+        let start = now();
+        let mut link = Link::new(config, seed, start);
+        for i in 0..count {
+            let mut d = dgram(1000);
+            d[..2].copy_from_slice(&u16::try_from(i).unwrap().to_be_bytes());
+            link.send(d, start + interval * u32::try_from(i).unwrap());
+        }
+        let mut arrivals = vec![None; count];
+        while let Some(t) = link.next_arrival() {
+            let d = link.receive(t).unwrap();
+            let elapsed = u32::try_from((t - start).as_micros()).unwrap();
+            arrivals[usize::from(u16::from_be_bytes([d[0], d[1]]))] = Some(elapsed);
+        }
+        arrivals
+    }
+
+    #[test]
+    fn delay() {
+        let config = LinkConfig {
+            delay: Duration::from_millis(10),
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 0, 3, Duration::from_millis(1));
+        assert_eq!(arrivals, vec![Some(10_000), Some(11_000), Some(12_000)]);
+    }
+
+    #[test]
+    fn not_yet() {
+        let config = LinkConfig {
+            delay: Duration::from_millis(10),
+            ..LinkConfig::default()
+        };
+        let start = now();
+        let mut link = Link::new(config, 0, start);
+        link.send(dgram(10), start);
+        assert!(link.receive(start + Duration::from_millis(9)).is_none());
+        assert!(link.receive(start + Duration::from_millis(10)).is_some());
+        assert_eq!(link.stats().delivered, 1);
+    }
+
+    #[test]
+    fn rate() {
+        // 1000 byte packets at 100kB/s take 10ms each.
+        let config = LinkConfig {
+            rate: Some(100_000),
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 0, 3, Duration::from_secs(0));
+        assert_eq!(arrivals, vec![Some(10_000), Some(20_000), Some(30_000)]);
+    }
+
+    fn mahimahi_link(text: &str) -> Link {
+        let mut link = Link::new(LinkConfig::default(), 0, now());
+        link.set_trace(Trace::mahimahi(text).unwrap());
+        link
+    }
+
+    /// Send packets of the given sizes at the given times, in milliseconds,
+    /// and return when each arrived.
+    fn arrivals(mut link: Link, packets: &[(u64, usize)]) -> Vec<u32> {
+        let start = now();
+        for (t, len) in packets {
+            link.send(dgram(*len), start + Duration::from_millis(*t));
+        }
+        let mut arrivals = Vec::new();
+        while let Some(t) = link.next_arrival() {
+            link.receive(t).unwrap();
+            arrivals.push(u32::try_from((t - start).as_millis()).unwrap());
+        }
+        arrivals
+    }
+
+    #[test]
+    fn mahimahi() {
+        // The trace repeats every 10ms, with opportunities at 5ms and 10ms.
+        let link = mahimahi_link("5\n10\n");
+        let packets = [(0, 1500); 6];
+        assert_eq!(arrivals(link, &packets), vec![5, 10, 15, 20, 25, 30]);
+    }
+
+    #[test]
+    fn mahimahi_shared() {
+        // Small packets share an opportunity, and a large packet can need
+        // more than one.
+        let link = mahimahi_link("5\n10\n");
+        let packets = [(0, 1000), (0, 1000), (0, 1000), (0, 2000)];
+        assert_eq!(arrivals(link, &packets), vec![5, 10, 10, 20]);
+    }
+
+    #[test]
+    fn mahimahi_idle() {
+        // Opportunities that pass without a packet are wasted.
+        let link = mahimahi_link("5\n10\n");
+        let packets = [(0, 1000), (12, 1000), (12, 1000), (40, 1)];
+        assert_eq!(arrivals(link, &packets), vec![5, 15, 20, 40]);
+    }
+
+    #[test]
+    fn csv() {
+        // 800kbit/s is 100kB/s, so 1000 bytes takes 10ms.
+        let mut link = Link::new(LinkConfig::default(), 0, now());
+        link.set_trace(Trace::csv("0,800,10\n100,0,10\n200,8000,20\n").unwrap());
+        let packets = [(0, 1000), (95, 1000), (150, 1000)];
+        // The second packet starts before the outage, and the third waits
+        // for it to end.
+        assert_eq!(arrivals(link, &packets), vec![20, 115, 221]);
+    }
+
+    #[test]
+    fn trace_queue() {
+        let config = LinkConfig {
+            queue: Queue::DropTail { limit: 2500 },
+            ..LinkConfig::default()
+        };
+        let mut link = Link::new(config, 0, now());
+        link.set_trace(Trace::mahimahi("5\n10\n").unwrap());
+        let packets = [(0, 1000); 4];
+        assert_eq!(arrivals(link, &packets), vec![5, 10]);
+    }
+
+    #[test]
+    fn drop_tail() {
+        let config = LinkConfig {
+            rate: Some(100_000),
+            queue: Queue::DropTail { limit: 2500 },
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 0, 4, Duration::from_secs(0));
+        assert_eq!(arrivals, vec![Some(10_000), Some(20_000), None, None]);
+
+        // Once the queue drains, there is space again.
+        let arrivals = run(config, 0, 4, Duration::from_millis(10));
+        assert!(arrivals.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn red() {
+        let config = LinkConfig {
+            rate: Some(100_000),
+            queue: Queue::Red {
+                min: 2000,
+                max: 10_000,
+                max_p: 0.5,
+                weight: 1.0,
+                limit: 20_000,
+            },
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 1, 20, Duration::from_secs(0));
+        // Nothing is dropped until the queue is above the minimum,
+        // and everything is dropped above the maximum.
+        assert!(arrivals[..3].iter().all(Option::is_some));
+        assert!(arrivals.iter().any(Option::is_none));
+        assert!(arrivals.iter().filter(|a| a.is_some()).count() <= 11);
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let config = LinkConfig {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 2, 50, Duration::from_millis(1));
+        let times = arrivals.iter().map(|a| a.unwrap()).collect::<Vec<_>>();
+        let mut sorted = times.clone();
+        sorted.sort();
+        assert_eq!(times, sorted);
+        assert!(times.iter().zip(0..).all(|(t, i)| *t >= 10_000 + i * 1000));
+    }
+
+    #[test]
+    fn reorder() {
+        let config = LinkConfig {
+            delay: Duration::from_millis(10),
+            reorder: 0.2,
+            reorder_delay: Duration::from_millis(5),
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 3, 50, Duration::from_millis(1));
+        let times = arrivals.iter().map(|a| a.unwrap()).collect::<Vec<_>>();
+        let mut sorted = times.clone();
+        sorted.sort();
+        assert_ne!(times, sorted);
+    }
+
+    #[test]
+    fn loss() {
+        let config = LinkConfig {
+            loss: 0.1,
+            ..LinkConfig::default()
+        };
+        let arrivals = run(config, 4, 1000, Duration::from_millis(1));
+        let lost = arrivals.iter().filter(|a| a.is_none()).count();
+        assert!(lost > 50 && lost < 150);
+    }
+
+    #[test]
+    fn deterministic() {
+        let config = LinkConfig {
+            delay: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            loss: 0.1,
+            reorder: 0.1,
+            reorder_delay: Duration::from_millis(3),
+            ..LinkConfig::default()
+        };
+        let a = run(config, 5, 100, Duration::from_millis(1));
+        assert_eq!(a, run(config, 5, 100, Duration::from_millis(1)));
+        assert_ne!(a, run(config, 6, 100, Duration::from_millis(1)));
+    }
+}
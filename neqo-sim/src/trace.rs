@@ -0,0 +1,367 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Recorded bandwidth and delay, for replaying on a link.  Two formats are
+// understood:
+//
+// * mahimahi traces, where each line is a time in milliseconds at which the
+//   link can deliver one packet of up to 1500 bytes.  The same time can
+//   appear on several lines.  The trace repeats, with the time on the last
+//   line as its period.
+//
+// * CSV, where each line is `time,bandwidth,delay`: from `time`
+//   milliseconds on, the link runs at `bandwidth` kbit/s and takes `delay`
+//   milliseconds to cross.  A bandwidth of 0 is an outage.  The first line
+//   has to start at 0, and the last line lasts forever.
+//
+// In both, empty lines and lines that start with `#` are ignored.
+
+use std::cmp::max;
+use std::cmp::Ordering::{Greater, Less};
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+/// How many bytes a mahimahi delivery opportunity can carry.
+const OPPORTUNITY_SIZE: usize = 1500;
+
+/// Why a trace couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceError {
+    /// The line with the problem, counting from 1, or 0 if the problem is
+    /// with the whole trace.
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "bad trace: {}", self.reason)
+        } else {
+            write!(f, "bad trace at line {}: {}", self.line, self.reason)
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// One line of a CSV trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// When this step starts, from the start of the trace.
+    pub start: Duration,
+    /// The rate of the link, in bytes per second.
+    pub rate: u64,
+    pub delay: Duration,
+}
+
+/// A recorded trace of what a link could carry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trace {
+    /// The times, from the start of each period, at which a packet can be
+    /// delivered.
+    Opportunities {
+        times: Vec<Duration>,
+        period: Duration,
+    },
+    /// A rate and delay that changes in steps.
+    Steps(Vec<Step>),
+}
+
+/// The lines of a trace that aren't empty or comments, numbered from 1.
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .map(|(i, l)| (i + 1, l))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+}
+
+fn millis(line: usize, v: &str) -> Result<Duration, TraceError> {
+    v.trim()
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| TraceError {
+            line,
+            reason: "a time isn't a number of milliseconds",
+        })
+}
+
+fn nanos(d: Duration) -> u64 {
+    u64::try_from(d.as_nanos()).unwrap()
+}
+
+impl Trace {
+    /// Parse a mahimahi trace.
+    pub fn mahimahi(text: &str) -> Result<Self, TraceError> {
+        let mut times = Vec::new();
+        for (line, l) in lines(text) {
+            let t = millis(line, l)?;
+            if times.last().map_or(false, |last| t < *last) {
+                return Err(TraceError {
+                    line,
+                    reason: "the times go backwards",
+                });
+            }
+            times.push(t);
+        }
+        let period = *times.last().ok_or(TraceError {
+            line: 0,
+            reason: "it is empty",
+        })?;
+        if period == Duration::from_secs(0) {
+            return Err(TraceError {
+                line: 0,
+                reason: "it ends at 0",
+            });
+        }
+        Ok(Self::Opportunities { times, period })
+    }
+
+    /// Parse a CSV trace.
+    pub fn csv(text: &str) -> Result<Self, TraceError> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (line, l) in lines(text) {
+            let fields = l.split(',').collect::<Vec<_>>();
+            if fields.len() != 3 {
+                return Err(TraceError {
+                    line,
+                    reason: "it doesn't have three fields",
+                });
+            }
+            let start = millis(line, fields[0])?;
+            let kbps = fields[1].trim().parse::<u64>().map_err(|_| TraceError {
+                line,
+                reason: "the bandwidth isn't a number of kbit/s",
+            })?;
+            let delay = millis(line, fields[2])?;
+            let ok = steps
+                .last()
+                .map_or(start == Duration::from_secs(0), |s| start > s.start);
+            if !ok {
+                return Err(TraceError {
+                    line,
+                    reason: "the times don't start at 0 and go forwards",
+                });
+            }
+            steps.push(Step {
+                start,
+                rate: kbps * 1000 / 8,
+                delay,
+            });
+        }
+        match steps.last() {
+            None => Err(TraceError {
+                line: 0,
+                reason: "it is empty",
+            }),
+            Some(s) if s.rate == 0 => Err(TraceError {
+                line: 0,
+                reason: "it ends with an outage",
+            }),
+            _ => Ok(Self::Steps(steps)),
+        }
+    }
+}
+
+/// Replays a trace on a link, keeping track of what has been used.
+#[derive(Debug)]
+pub(crate) struct TraceState {
+    trace: Trace,
+    start: Instant,
+    /// The index of the next delivery opportunity, counting every repeat
+    /// of the trace.
+    next: u64,
+    /// The bytes left in the last opportunity that was used, and when it
+    /// was.  What isn't used by then is wasted.
+    left: usize,
+    left_at: Instant,
+    /// When the link finishes sending everything that is queued, for steps.
+    busy_until: Instant,
+}
+
+impl TraceState {
+    pub fn new(trace: Trace, start: Instant) -> Self {
+        Self {
+            trace,
+            start,
+            next: 0,
+            left: 0,
+            left_at: start,
+            busy_until: start,
+        }
+    }
+
+    /// When opportunity `i` is.
+    fn opportunity(times: &[Duration], period: Duration, i: u64) -> Duration {
+        let n = u64::try_from(times.len()).unwrap();
+        period * u32::try_from(i / n).unwrap() + times[usize::try_from(i % n).unwrap()]
+    }
+
+    /// The index of the first opportunity at or after `t`.
+    fn first_opportunity(times: &[Duration], period: Duration, t: Duration) -> u64 {
+        let n = u64::try_from(times.len()).unwrap();
+        let cycle = nanos(t) / nanos(period);
+        let offset = t - period * u32::try_from(cycle).unwrap();
+        // This never finds a match, so it finds where the times stop being
+        // before `offset`.
+        let i = match times.binary_search_by(|x| if *x < offset { Less } else { Greater }) {
+            Ok(i) | Err(i) => u64::try_from(i).unwrap(),
+        };
+        let mut first = cycle * n + i;
+        // Times at the end of the previous repeat can be at `t` too.
+        while first > 0 && Self::opportunity(times, period, first - 1) >= t {
+            first -= 1;
+        }
+        first
+    }
+
+    /// The step that applies at `t`.
+    fn step(steps: &[Step], t: Duration) -> usize {
+        match steps.binary_search_by(|s| s.start.cmp(&t)) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    /// Send `len` bytes at `now`, and return when they have been sent.
+    pub fn transmit(&mut self, len: usize, now: Instant) -> Instant {
+        match &self.trace {
+            Trace::Opportunities { times, period } => {
+                let mut remaining = len;
+                let mut departure = now;
+                if self.left > 0 && self.left_at >= now {
+                    let used = remaining.min(self.left);
+                    self.left -= used;
+                    remaining -= used;
+                    departure = self.left_at;
+                }
+                let first = Self::first_opportunity(times, *period, now - self.start);
+                self.next = max(self.next, first);
+                while remaining > 0 {
+                    departure = self.start + Self::opportunity(times, *period, self.next);
+                    self.next += 1;
+                    let used = remaining.min(OPPORTUNITY_SIZE);
+                    remaining -= used;
+                    self.left = OPPORTUNITY_SIZE - used;
+                    self.left_at = departure;
+                }
+                departure
+            }
+            Trace::Steps(steps) => {
+                let mut t = max(now, self.busy_until);
+                let mut i = Self::step(steps, t - self.start);
+                // Wait out any outage.  The last step isn't an outage.
+                while steps[i].rate == 0 {
+                    i += 1;
+                    t = self.start + steps[i].start;
+                }
+                let tx_time = Duration::from_nanos(
+                    u64::try_from(len).unwrap() * 1_000_000_000 / steps[i].rate,
+                );
+                self.busy_until = t + tx_time;
+                self.busy_until
+            }
+        }
+    }
+
+    /// How long a packet that leaves at `departure` takes to cross.
+    pub fn delay(&self, departure: Instant) -> Duration {
+        match &self.trace {
+            Trace::Opportunities { .. } => Duration::from_secs(0),
+            Trace::Steps(steps) => steps[Self::step(steps, departure - self.start)].delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mahimahi() {
+        let trace = Trace::mahimahi("# a comment\n1\n1\n\n3\n").unwrap();
+        assert_eq!(
+            trace,
+            Trace::Opportunities {
+                times: vec![
+                    Duration::from_millis(1),
+                    Duration::from_millis(1),
+                    Duration::from_millis(3)
+                ],
+                period: Duration::from_millis(3),
+            }
+        );
+    }
+
+    #[test]
+    fn mahimahi_errors() {
+        let err = |text, line, reason| {
+            assert_eq!(Trace::mahimahi(text), Err(TraceError { line, reason }));
+        };
+        err("", 0, "it is empty");
+        err("0\n0\n", 0, "it ends at 0");
+        err("2\n1\n", 2, "the times go backwards");
+        err("1\nx\n", 2, "a time isn't a number of milliseconds");
+    }
+
+    #[test]
+    fn csv() {
+        let trace = Trace::csv("0,800,10\n100, 0, 10\n").unwrap_err();
+        assert_eq!(trace.reason, "it ends with an outage");
+        let trace = Trace::csv("0,800,10\n100, 8000, 20\n").unwrap();
+        assert_eq!(
+            trace,
+            Trace::Steps(vec![
+                Step {
+                    start: Duration::from_millis(0),
+                    rate: 100_000,
+                    delay: Duration::from_millis(10),
+                },
+                Step {
+                    start: Duration::from_millis(100),
+                    rate: 1_000_000,
+                    delay: Duration::from_millis(20),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn csv_errors() {
+        let err = |text, line, reason| {
+            assert_eq!(Trace::csv(text), Err(TraceError { line, reason }));
+        };
+        err("", 0, "it is empty");
+        err("0,1\n", 1, "it doesn't have three fields");
+        err("0,x,1\n", 1, "the bandwidth isn't a number of kbit/s");
+        err("10,1,1\n", 1, "the times don't start at 0 and go forwards");
+        err(
+            "0,1,1\n0,1,1\n",
+            2,
+            "the times don't start at 0 and go forwards",
+        );
+    }
+
+    #[test]
+    fn opportunities() {
+        let times = [Duration::from_millis(5), Duration::from_millis(10)];
+        let period = Duration::from_millis(10);
+        let first = |ms| TraceState::first_opportunity(&times, period, Duration::from_millis(ms));
+        assert_eq!(first(0), 0);
+        assert_eq!(first(5), 0);
+        assert_eq!(first(6), 1);
+        assert_eq!(first(10), 1);
+        assert_eq!(first(11), 2);
+        assert_eq!(first(20), 3);
+        assert_eq!(first(25), 4);
+        for i in 0..6 {
+            let t = TraceState::opportunity(&times, period, i);
+            assert_eq!(t, Duration::from_millis(5 * (i + 1)));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_sim::{ConnectionNode, LinkConfig, Queue, Simulator, Trace};
+use std::time::Duration;
+use test_fixture::{default_client, default_server};
+
+const TRANSFER: usize = 200_000;
+
+fn transfer(seed: u64, forward: LinkConfig, back: LinkConfig) -> (Simulator, Duration) {
+    let mut sim = Simulator::new(
+        ConnectionNode::sender(default_client(), TRANSFER),
+        ConnectionNode::receiver(default_server(), TRANSFER),
+        seed,
+    );
+    sim.set_links(forward, back);
+    let elapsed = sim.run();
+    (sim, elapsed)
+}
+
+#[test]
+fn ideal() {
+    let (sim, _) = transfer(0, LinkConfig::default(), LinkConfig::default());
+    assert_eq!(sim.link(0).stats().lost, 0);
+    assert_eq!(sim.link(0).stats().sent, sim.link(0).stats().delivered);
+}
+
+#[test]
+fn delay() {
+    let link = LinkConfig {
+        delay: Duration::from_millis(25),
+        ..LinkConfig::default()
+    };
+    let (_, elapsed) = transfer(0, link, link);
+    // The handshake alone takes more than one round trip.
+    assert!(elapsed > Duration::from_millis(100));
+}
+
+#[test]
+fn bottleneck() {
+    let forward = LinkConfig {
+        delay: Duration::from_millis(10),
+        rate: Some(1_000_000),
+        queue: Queue::DropTail { limit: 20_000 },
+        ..LinkConfig::default()
+    };
+    let back = LinkConfig {
+        delay: Duration::from_millis(10),
+        ..LinkConfig::default()
+    };
+    let (sim, elapsed) = transfer(1, forward, back);
+    // It can't go faster than the bottleneck.
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(sim.link(0).stats().delivered > TRANSFER / 1500);
+}
+
+#[test]
+fn impaired() {
+    let forward = LinkConfig {
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(5),
+        loss: 0.02,
+        reorder: 0.02,
+        reorder_delay: Duration::from_millis(10),
+        rate: Some(2_000_000),
+        queue: Queue::Red {
+            min: 10_000,
+            max: 40_000,
+            max_p: 0.1,
+            weight: 0.1,
+            limit: 60_000,
+        },
+    };
+    let back = LinkConfig {
+        delay: Duration::from_millis(20),
+        loss: 0.02,
+        ..LinkConfig::default()
+    };
+    let (sim, _) = transfer(2, forward, back);
+    let stats = sim.link(0).stats();
+    assert!(stats.lost > 0);
+    assert!(stats.reordered > 0);
+}
+
+#[test]
+fn trace() {
+    // An opportunity every millisecond is 1.5MB/s, with a burst of ten
+    // at the end of each 100ms.
+    let mut text = (1..=100).map(|t| format!("{}\n", t)).collect::<String>();
+    text.push_str(&"100\n".repeat(10));
+    let forward = LinkConfig {
+        delay: Duration::from_millis(10),
+        queue: Queue::DropTail { limit: 30_000 },
+        ..LinkConfig::default()
+    };
+    let back = LinkConfig {
+        delay: Duration::from_millis(10),
+        ..LinkConfig::default()
+    };
+    let mut sim = Simulator::new(
+        ConnectionNode::sender(default_client(), TRANSFER),
+        ConnectionNode::receiver(default_server(), TRANSFER),
+        3,
+    );
+    sim.set_links(forward, back);
+    sim.set_trace(0, Trace::mahimahi(&text).unwrap());
+    let elapsed = sim.run();
+    // It can't go faster than the trace allows.
+    assert!(elapsed >= Duration::from_millis(120));
+    assert!(sim.link(0).stats().delivered > TRANSFER / 1500);
+}
@@ -0,0 +1,20 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A soak test.  This keeps many client connections open to a neqo server in
+// the same process, for as long as it is asked to, with a mix of workloads.
+// Connections close after a while and others take their place.  Regular
+// reports say what the server and the process are holding, so that memory
+// that grows while the number of connections doesn't, or timers that run
+// later and later, can be caught before a release.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod report;
+mod soak;
+
+pub use self::report::{resident_memory, Report};
+pub use self::soak::{Config, Soak, Workload};
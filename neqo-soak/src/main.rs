@@ -0,0 +1,125 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_crypto::init_db;
+use neqo_soak::{Config, Report, Soak};
+
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "neqo-soak",
+    about = "Keeps many connections to a neqo server busy, and watches for leaks and timer drift."
+)]
+struct Args {
+    #[structopt(short = "d", long, default_value = "./db", parse(from_os_str))]
+    /// NSS database directory.
+    db: PathBuf,
+    #[structopt(short = "k", long, default_value = "key")]
+    /// Name of keys from NSS database.
+    key: Vec<String>,
+    #[structopt(short = "a", long, default_value = "hq-24")]
+    /// ALPN labels to use.
+    alpn: Vec<String>,
+
+    #[structopt(short = "c", long, default_value = "1000")]
+    /// How many connections to keep open.
+    connections: usize,
+    #[structopt(short = "t", long, default_value = "3600")]
+    /// How long to run, in seconds.
+    duration: u64,
+    #[structopt(short = "i", long, default_value = "60")]
+    /// How often to report, in seconds.
+    interval: u64,
+    #[structopt(long, default_value = "300")]
+    /// How long to run before the memory that is in use is taken as the
+    /// baseline, in seconds.
+    warmup: u64,
+
+    #[structopt(long, default_value = "100000")]
+    /// How many bytes a bulk connection asks for each time.
+    bulk_size: u64,
+    #[structopt(long, default_value = "1000")]
+    /// How many bytes a request asks for.
+    request_size: u64,
+    #[structopt(long, default_value = "1000")]
+    /// How long connections wait between requests, in milliseconds.
+    request_interval: u64,
+    #[structopt(long, default_value = "100")]
+    /// How many responses a connection gets before it is replaced.
+    responses: usize,
+
+    #[structopt(long, default_value = "20")]
+    /// How much the memory for each connection can grow after warmup, as a
+    /// percentage, before the test fails.
+    max_growth: usize,
+    #[structopt(long, default_value = "1000")]
+    /// How late a timer can run before the test fails, in milliseconds.
+    max_lateness: u64,
+}
+
+fn main() {
+    let args = Args::from_args();
+    init_db(args.db.clone());
+
+    let config = Config {
+        connections: args.connections,
+        bulk_size: args.bulk_size,
+        request_size: args.request_size,
+        request_interval: Duration::from_millis(args.request_interval),
+        responses_per_connection: args.responses,
+    };
+    let mut soak = Soak::new(config, &args.key, &args.alpn).expect("Unable to start the soak");
+
+    let warmup = Duration::from_secs(args.warmup);
+    let max_lateness = Duration::from_millis(args.max_lateness);
+    let mut baseline: Option<usize> = None;
+    let mut last: Option<Report> = None;
+    let mut failed = false;
+    soak.run(
+        Duration::from_secs(args.duration),
+        Duration::from_secs(args.interval),
+        |r| {
+            println!("{}", r);
+            if r.lateness > max_lateness {
+                println!("Timers are running late: {:?}", r.lateness);
+                failed = true;
+            }
+            if baseline.is_none() && r.elapsed >= warmup {
+                baseline = r.memory_per_connection();
+            }
+            last = Some(r.clone());
+        },
+    );
+
+    let end = last.as_ref().and_then(Report::memory_per_connection);
+    if let (Some(base), Some(end)) = (baseline, end) {
+        let limit = base + base * args.max_growth / 100;
+        println!(
+            "Memory per connection: {} after warmup, {} at the end",
+            base, end
+        );
+        if end > limit {
+            println!(
+                "Memory per connection grew by more than {}%",
+                args.max_growth
+            );
+            failed = true;
+        }
+    }
+    if last.map_or(false, |r| r.failed > 0) {
+        println!("Some connections failed");
+        failed = true;
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_transport::server::ServerStats;
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::time::Duration;
+
+/// The size of a page, which `/proc/self/statm` counts in.  This is right for
+/// the machines that soak tests run on.
+const PAGE_SIZE: usize = 4096;
+
+/// The memory that this process has resident, in bytes, if the system says.
+pub fn resident_memory() -> Option<usize> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(pages * PAGE_SIZE)
+}
+
+/// What a soak test is doing at one time.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// How long the test has been running.
+    pub elapsed: Duration,
+    /// Client connections that are open.
+    pub clients: usize,
+    /// Client connections that have been opened, and of those, how many
+    /// have closed, and how many closed with an error.
+    pub opened: u64,
+    pub closed: u64,
+    pub failed: u64,
+    /// Responses that clients have received in full, and the bytes in them.
+    pub responses: u64,
+    pub bytes: u64,
+    pub server: ServerStats,
+    /// The most events that any one client connection has waiting.
+    pub client_events: usize,
+    /// The memory that the process has resident, if it is known.
+    pub resident: Option<usize>,
+    /// How late the latest timer ran since the last report.
+    pub lateness: Duration,
+}
+
+impl Report {
+    /// The resident memory for each connection, counting both ends.
+    pub fn memory_per_connection(&self) -> Option<usize> {
+        let connections = self.clients + self.server.connections;
+        self.resident.map(|r| r / connections.max(1))
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>8.1}s clients={} opened={} closed={} failed={} responses={} bytes={} \
+             server: connections={} active={} waiting={} timers={} events={} memory={} \
+             client events={} late={:?}",
+            self.elapsed.as_secs_f64(),
+            self.clients,
+            self.opened,
+            self.closed,
+            self.failed,
+            self.responses,
+            self.bytes,
+            self.server.connections,
+            self.server.active,
+            self.server.waiting,
+            self.server.timers,
+            self.server.events,
+            self.server.memory,
+            self.client_events,
+            self.lateness,
+        )?;
+        if let (Some(r), Some(per)) = (self.resident, self.memory_per_connection()) {
+            write!(f, " resident={} per connection={}", r, per)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,543 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The connections in a soak test, and the server that they connect to.
+// Datagrams go straight from one to the other, without sockets.  A request
+// is a bidirectional stream on which the client sends the number of bytes
+// it wants, as 8 bytes, and the server sends that many bytes back.
+
+use crate::report::{resident_memory, Report};
+
+use neqo_common::{matches, qdebug, qinfo, timer::Timer, Datagram};
+use neqo_crypto::{AntiReplay, AuthenticationStatus};
+use neqo_transport::server::{ActiveConnectionRef, Server};
+use neqo_transport::{
+    Connection, ConnectionError, ConnectionEvent, Error, FixedConnectionIdManager, Output, Res,
+    State, StreamType,
+};
+
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ANTI_REPLAY_WINDOW: Duration = Duration::from_millis(10);
+const SERVER_NAME: &str = "example.com";
+/// What the server sends in responses.
+const ZEROS: [u8; 4096] = [0; 4096];
+/// The timers for clients are kept in a wheel like the server's.  The first
+/// level reaches ten seconds ahead, which covers most timers other than the
+/// idle timeout.
+const WAKE_GRANULARITY: Duration = Duration::from_millis(10);
+const WAKE_CAPACITY: usize = 1024;
+
+/// What a client connection does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Ask for a large response as soon as the last one is done.
+    Bulk,
+    /// Ask for a small response at intervals.
+    Requests,
+    /// Ask for nothing, until the connection times out.
+    Idle,
+}
+
+/// Clients are given these workloads in turn.
+const WORKLOADS: &[Workload] = &[
+    Workload::Requests,
+    Workload::Requests,
+    Workload::Bulk,
+    Workload::Idle,
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How many client connections to keep open.
+    pub connections: usize,
+    /// How many bytes a `Bulk` connection asks for each time.
+    pub bulk_size: u64,
+    /// How many bytes a `Requests` connection asks for each time, and how
+    /// long it waits after each response.
+    pub request_size: u64,
+    pub request_interval: Duration,
+    /// How many responses a connection receives before it closes and
+    /// another takes its place.
+    pub responses_per_connection: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            connections: 1000,
+            bulk_size: 100_000,
+            request_size: 1000,
+            request_interval: Duration::from_secs(1),
+            responses_per_connection: 100,
+        }
+    }
+}
+
+struct Client {
+    c: Connection,
+    workload: Workload,
+    /// When to send the next request, if one isn't outstanding.
+    next_request: Option<Instant>,
+    responses: usize,
+    /// When the connection next wants `process` to be called.
+    wake: Option<Instant>,
+    /// When this client is in `Soak::wakes`.
+    scheduled: Option<Instant>,
+}
+
+impl Client {
+    /// When to drive this client next.
+    fn next_wake(&self) -> Option<Instant> {
+        match (self.wake, self.next_request) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// A request that the server is responding to.
+#[derive(Default)]
+struct Response {
+    /// The request, until all of it has arrived.
+    request: Vec<u8>,
+    /// How much is left to send.
+    remaining: u64,
+}
+
+pub struct Soak {
+    config: Config,
+    alpn: Vec<String>,
+    server: Server,
+    server_addr: SocketAddr,
+    server_wake: Option<Instant>,
+    clients: Vec<Client>,
+    /// When each client wants to be driven.
+    wakes: Timer<usize>,
+    to_server: VecDeque<Datagram>,
+    responses: HashMap<ActiveConnectionRef, HashMap<u64, Response>>,
+    start: Instant,
+    opened: u64,
+    closed: u64,
+    failed: u64,
+    responses_received: u64,
+    bytes: u64,
+    lateness: Duration,
+}
+
+impl Soak {
+    /// Make a server with the keys in `certs`, and the clients for it.
+    /// # Errors
+    /// If the server or any client can't be made.
+    pub fn new(config: Config, certs: &[impl AsRef<str>], alpn: &[impl AsRef<str>]) -> Res<Self> {
+        let now = Instant::now();
+        let anti_replay = AntiReplay::new(now - ANTI_REPLAY_WINDOW, ANTI_REPLAY_WINDOW, 1, 3)?;
+        let server = Server::new(
+            now,
+            certs,
+            alpn,
+            anti_replay,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+        )?;
+        let mut soak = Self {
+            config,
+            alpn: alpn.iter().map(|a| String::from(a.as_ref())).collect(),
+            server,
+            server_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443),
+            server_wake: None,
+            clients: Vec::new(),
+            wakes: Timer::new(now, WAKE_GRANULARITY, WAKE_CAPACITY),
+            to_server: VecDeque::new(),
+            responses: HashMap::new(),
+            start: now,
+            opened: 0,
+            closed: 0,
+            failed: 0,
+            responses_received: 0,
+            bytes: 0,
+            lateness: Duration::from_secs(0),
+        };
+        for i in 0..soak.config.connections {
+            let c = soak.client(i)?;
+            soak.clients.push(c);
+        }
+        Ok(soak)
+    }
+
+    /// The address of client `i`.  Each client has its own.
+    fn client_addr(i: usize) -> SocketAddr {
+        let i = u32::try_from(i).unwrap();
+        let ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, (i >> 16) as u16, i as u16);
+        SocketAddr::new(IpAddr::V6(ip), 4433)
+    }
+
+    fn client_index(addr: SocketAddr) -> Option<usize> {
+        if let IpAddr::V6(ip) = addr.ip() {
+            let s = ip.segments();
+            Some((usize::from(s[6]) << 16) | usize::from(s[7]))
+        } else {
+            None
+        }
+    }
+
+    fn client(&mut self, i: usize) -> Res<Client> {
+        let c = Connection::new_client(
+            SERVER_NAME,
+            &self.alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(8))),
+            Self::client_addr(i),
+            self.server_addr,
+        )?;
+        self.opened += 1;
+        Ok(Client {
+            c,
+            workload: WORKLOADS[i % WORKLOADS.len()],
+            next_request: None,
+            responses: 0,
+            wake: None,
+            scheduled: None,
+        })
+    }
+
+    /// Run for `duration`, calling `report` every `interval`.
+    pub fn run(&mut self, duration: Duration, interval: Duration, mut report: impl FnMut(&Report)) {
+        self.start = Instant::now();
+        for i in 0..self.clients.len() {
+            self.drive_client(i, None, self.start);
+        }
+        let end = self.start + duration;
+        let mut next_report = self.start + interval;
+        loop {
+            let now = Instant::now();
+            self.step(now);
+            if now >= next_report {
+                report(&self.report(now));
+                next_report += interval;
+            }
+            if now >= end {
+                break;
+            }
+
+            let mut next = min(next_report, end);
+            if let Some(t) = self.wakes.next_time() {
+                next = min(next, t);
+            }
+            if let Some(t) = self.server_wake {
+                next = min(next, t);
+            }
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+        }
+    }
+
+    fn late(&mut self, due: Instant, now: Instant) {
+        self.lateness = max(self.lateness, now.saturating_duration_since(due));
+    }
+
+    /// Run everything that is due.
+    fn step(&mut self, now: Instant) {
+        while let Some(i) = self.wakes.take_next(now) {
+            let t = self.clients[i].scheduled.take().unwrap();
+            self.late(t, now);
+            self.drive_client(i, None, now);
+        }
+        if let Some(t) = self.server_wake {
+            if t <= now {
+                self.late(t, now);
+                self.drive_server(None, now);
+            }
+        }
+        while let Some(d) = self.to_server.pop_front() {
+            self.drive_server(Some(d), now);
+        }
+    }
+
+    fn drive_server(&mut self, d: Option<Datagram>, now: Instant) {
+        let mut out = self.server.process(d, now);
+        loop {
+            self.serve();
+            match out {
+                Output::Datagram(d) => {
+                    if let Some(i) = Self::client_index(d.destination()) {
+                        self.drive_client(i, Some(d), now);
+                    }
+                }
+                Output::Callback(delay) => {
+                    self.server_wake = Some(now + delay);
+                    return;
+                }
+                Output::None => {
+                    self.server_wake = None;
+                    return;
+                }
+            }
+            out = self.server.process(None, now);
+        }
+    }
+
+    /// Handle events on the server connections.
+    fn serve(&mut self) {
+        for mut c in self.server.active_connections() {
+            let mut sent = false;
+            loop {
+                let e = c.borrow_mut().next_event();
+                match e {
+                    Some(ConnectionEvent::RecvStreamReadable { stream_id }) => {
+                        sent |= self.read_request(&mut c, stream_id);
+                    }
+                    Some(ConnectionEvent::SendStreamWritable { stream_id }) => {
+                        sent |= self.respond(&mut c, stream_id);
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            if sent {
+                self.server.add_to_waiting(c);
+            }
+        }
+    }
+
+    fn read_request(&mut self, c: &mut ActiveConnectionRef, stream_id: u64) -> bool {
+        let mut buf = [0; 8];
+        let mut request = Vec::new();
+        let mut fin = false;
+        while let Ok((n, f)) = c.borrow_mut().stream_recv(stream_id, &mut buf) {
+            request.extend_from_slice(&buf[..n]);
+            fin = f;
+            if fin || n == 0 {
+                break;
+            }
+        }
+        let r = self
+            .responses
+            .entry(c.clone())
+            .or_default()
+            .entry(stream_id)
+            .or_default();
+        r.request.extend(request);
+        if !fin {
+            return false;
+        }
+        let mut size = [0; 8];
+        let len = min(r.request.len(), size.len());
+        size[..len].copy_from_slice(&r.request[..len]);
+        r.remaining = u64::from_be_bytes(size);
+        self.respond(c, stream_id)
+    }
+
+    /// Send what can be sent of a response, and return whether anything was.
+    fn respond(&mut self, c: &mut ActiveConnectionRef, stream_id: u64) -> bool {
+        let streams = match self.responses.get_mut(&*c) {
+            Some(s) => s,
+            None => return false,
+        };
+        let r = match streams.get_mut(&stream_id) {
+            Some(r) => r,
+            None => return false,
+        };
+        let mut sent = false;
+        let mut conn = c.borrow_mut();
+        while r.remaining > 0 {
+            let len = usize::try_from(min(r.remaining, ZEROS.len() as u64)).unwrap();
+            match conn.stream_send(stream_id, &ZEROS[..len]) {
+                Ok(n) if n > 0 => {
+                    r.remaining -= n as u64;
+                    sent = true;
+                }
+                _ => return sent,
+            }
+        }
+        let _ = conn.stream_close_send(stream_id);
+        drop(conn);
+        streams.remove(&stream_id);
+        if streams.is_empty() {
+            self.responses.remove(&*c);
+        }
+        true
+    }
+
+    fn drive_client(&mut self, i: usize, mut d: Option<Datagram>, now: Instant) {
+        loop {
+            self.maybe_request(i, now);
+            let client = &mut self.clients[i];
+            let mut out = client.c.process(d.take(), now);
+            client.wake = loop {
+                match out {
+                    Output::Datagram(dgram) => self.to_server.push_back(dgram),
+                    Output::Callback(delay) => break Some(now + delay),
+                    Output::None => break None,
+                }
+                out = client.c.process(None, now);
+            };
+            if !self.client_events(i, now) {
+                break;
+            }
+        }
+
+        if matches!(self.clients[i].c.state(), State::Closed(..)) {
+            self.replace_client(i, now);
+            return;
+        }
+        self.schedule(i);
+    }
+
+    /// Put client `i` in the wheel for when it next needs to be driven.
+    fn schedule(&mut self, i: usize) {
+        let client = &mut self.clients[i];
+        let next = client.next_wake();
+        if client.scheduled == next {
+            return;
+        }
+        if let Some(t) = client.scheduled.take() {
+            self.wakes.remove(t, |&x| x == i);
+        }
+        if let Some(t) = next {
+            self.wakes.add(t, i);
+        }
+        client.scheduled = next;
+    }
+
+    /// Send a request, or close the connection, if it is time.
+    fn maybe_request(&mut self, i: usize, now: Instant) {
+        let config = &self.config;
+        let client = &mut self.clients[i];
+        if client.next_request.map_or(true, |t| t > now) {
+            return;
+        }
+        client.next_request = None;
+        if client.responses >= config.responses_per_connection {
+            qdebug!("client {} closing after {} responses", i, client.responses);
+            client.c.close(now, 0, "");
+            return;
+        }
+        let size = match client.workload {
+            Workload::Bulk => config.bulk_size,
+            Workload::Requests => config.request_size,
+            Workload::Idle => return,
+        };
+        let sent = client.c.stream_create(StreamType::BiDi).and_then(|id| {
+            client.c.stream_send(id, &size.to_be_bytes())?;
+            client.c.stream_close_send(id)
+        });
+        if sent.is_err() {
+            // Wait for the server to allow more streams.
+            client.next_request = Some(now + config.request_interval);
+        }
+    }
+
+    /// Handle a client's events, and return whether there were any.
+    fn client_events(&mut self, i: usize, now: Instant) -> bool {
+        let client = &mut self.clients[i];
+        let mut any = false;
+        while let Some(e) = client.c.next_event() {
+            any = true;
+            match e {
+                ConnectionEvent::AuthenticationNeeded => {
+                    client.c.authenticated(AuthenticationStatus::Ok, now);
+                }
+                ConnectionEvent::StateChange(State::Connected) => {
+                    if client.workload != Workload::Idle {
+                        client.next_request = Some(now);
+                    }
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    let mut buf = [0; 4096];
+                    while let Ok((n, fin)) = client.c.stream_recv(stream_id, &mut buf) {
+                        self.bytes += n as u64;
+                        if fin {
+                            self.responses_received += 1;
+                            client.responses += 1;
+                            client.next_request = Some(match client.workload {
+                                Workload::Requests => now + self.config.request_interval,
+                                _ => now,
+                            });
+                            break;
+                        }
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        any
+    }
+
+    /// Count a client that has closed, and start another in its place.
+    fn replace_client(&mut self, i: usize, now: Instant) {
+        self.closed += 1;
+        if let State::Closed(e) = self.clients[i].c.state() {
+            let clean = match e {
+                ConnectionError::Application(0) => true,
+                ConnectionError::Transport(Error::IdleTimeout) => {
+                    self.clients[i].workload == Workload::Idle
+                }
+                _ => false,
+            };
+            if !clean {
+                qinfo!("client {} failed: {:?}", i, e);
+                self.failed += 1;
+            }
+        }
+        if let Some(t) = self.clients[i].scheduled.take() {
+            self.wakes.remove(t, |&x| x == i);
+        }
+        match self.client(i) {
+            Ok(c) => {
+                self.clients[i] = c;
+                self.drive_client(i, None, now);
+            }
+            Err(e) => panic!("unable to make a client: {:?}", e),
+        }
+    }
+
+    /// Report on how things are, and start counting lateness again.
+    pub fn report(&mut self, now: Instant) -> Report {
+        // Responses to connections that closed before they were done.
+        self.responses.retain(|c, _| {
+            !matches!(
+                c.borrow().state(),
+                State::Closing { .. } | State::Closed(..)
+            )
+        });
+        let open = self
+            .clients
+            .iter()
+            .filter(|c| !matches!(c.c.state(), State::Closing { .. } | State::Closed(..)))
+            .count();
+        let report = Report {
+            elapsed: now - self.start,
+            clients: open,
+            opened: self.opened,
+            closed: self.closed,
+            failed: self.failed,
+            responses: self.responses_received,
+            bytes: self.bytes,
+            server: self.server.stats(),
+            client_events: self
+                .clients
+                .iter()
+                .map(|c| c.c.pending_events())
+                .max()
+                .unwrap_or(0),
+            resident: resident_memory(),
+            lateness: self.lateness,
+        };
+        self.lateness = Duration::from_secs(0);
+        report
+    }
+}
@@ -0,0 +1,40 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_soak::{Config, Soak};
+use test_fixture::{fixture_init, DEFAULT_ALPN, DEFAULT_KEYS};
+
+use std::time::Duration;
+
+#[test]
+fn short_soak() {
+    fixture_init();
+    let config = Config {
+        connections: 12,
+        bulk_size: 20_000,
+        request_interval: Duration::from_millis(50),
+        responses_per_connection: 5,
+        ..Config::default()
+    };
+    let mut soak = Soak::new(config, DEFAULT_KEYS, DEFAULT_ALPN).expect("start the soak");
+    let mut reports = Vec::new();
+    soak.run(Duration::from_secs(2), Duration::from_millis(500), |r| {
+        reports.push(r.clone())
+    });
+
+    assert!(reports.len() >= 3);
+    let last = reports.last().unwrap();
+    assert!(last.responses > 0);
+    assert!(last.bytes >= last.responses * 1000);
+    // Connections that made all their requests were replaced.
+    assert!(last.closed > 0);
+    assert_eq!(last.opened, last.closed + 12);
+    assert_eq!(last.failed, 0);
+    assert!(last.server.connections > 0);
+    assert!(last.server.timers > 0);
+}
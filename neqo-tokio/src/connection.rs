@@ -0,0 +1,203 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The handle for a connection.
+
+use crate::driver::{self, Shared};
+use crate::{Datagrams, Res, Stream};
+use neqo_transport::{AppError, ConnectionError, FixedConnectionIdManager, State, StreamType};
+use neqo_udp::Socket;
+
+use std::cell::RefCell;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// A connection that a task drives.  The connection stays open while this
+/// or any of its streams exist, or until it is closed.
+///
+/// The server certificate is accepted as it is in neqo-client.
+pub struct Connection {
+    shared: Rc<Shared>,
+}
+
+impl Connection {
+    /// Start a task that drives `conn` on `socket`.  `conn` can be a
+    /// client or a server, but a server connection can only talk to the one
+    /// client that sends to it first.
+    /// # Errors
+    /// When the socket can't be registered with the runtime.
+    /// # Panics
+    /// When called outside of a `tokio::task::LocalSet`.
+    pub fn new(conn: neqo_transport::Connection, mut socket: Socket) -> Res<Self> {
+        socket.set_nonblocking(true)?;
+        let socket = AsyncFd::with_interest(socket, Interest::READABLE)?;
+        let shared = Rc::new(Shared::new(conn));
+        tokio::task::spawn_local(driver::drive(Rc::clone(&shared), socket));
+        Ok(Self { shared })
+    }
+
+    /// Connect to `remote`, which has the certificate for `server_name`,
+    /// using one of the `alpn` protocols, and wait for the handshake to
+    /// finish.
+    /// # Errors
+    /// When the socket can't be made, or the handshake fails.
+    pub async fn connect(
+        server_name: &str,
+        alpn: &[impl AsRef<str>],
+        remote: SocketAddr,
+    ) -> Res<Self> {
+        let local = if remote.is_ipv4() {
+            SocketAddr::from(([0; 4], 0))
+        } else {
+            SocketAddr::from(([0; 16], 0))
+        };
+        let mut socket = Socket::bind(local)?;
+        socket.connect(remote)?;
+        let conn = neqo_transport::Connection::new_client(
+            server_name,
+            alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            socket.local_addr(),
+            remote,
+        )?;
+        let c = Self::new(conn, socket)?;
+        c.connected().await?;
+        Ok(c)
+    }
+
+    /// Wait for the handshake to finish.
+    /// # Errors
+    /// When the connection closes first.
+    pub async fn connected(&self) -> Res<()> {
+        poll_fn(|cx| {
+            let mut inner = self.shared.inner.borrow_mut();
+            if let State::Connected = inner.conn.state() {
+                return Poll::Ready(Ok(()));
+            }
+            if let Err(e) = inner.check() {
+                return Poll::Ready(Err(e));
+            }
+            inner.wait(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Open a bidirectional stream, waiting until the peer allows it.
+    /// # Errors
+    /// When the connection closes first.
+    pub async fn open_bi(&self) -> Res<Stream> {
+        self.open(StreamType::BiDi).await
+    }
+
+    /// Open a unidirectional stream, waiting until the peer allows it.
+    /// # Errors
+    /// When the connection closes first.
+    pub async fn open_uni(&self) -> Res<Stream> {
+        self.open(StreamType::UniDi).await
+    }
+
+    async fn open(&self, st: StreamType) -> Res<Stream> {
+        poll_fn(|cx| {
+            let mut inner = self.shared.inner.borrow_mut();
+            match inner.conn.stream_create(st) {
+                Ok(id) => {
+                    inner.add_stream(id);
+                    Poll::Ready(Ok(Stream::new(Rc::clone(&self.shared), id)))
+                }
+                // Wait for more streams, or for the handshake.
+                Err(neqo_transport::Error::StreamLimitError)
+                | Err(neqo_transport::Error::ConnectionState)
+                    if inner.error().is_none() =>
+                {
+                    inner.wait(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(inner.fail(e))),
+            }
+        })
+        .await
+    }
+
+    /// Wait for the peer to open a stream.
+    /// # Errors
+    /// When the connection closes first.
+    pub async fn accept(&self) -> Res<Stream> {
+        poll_fn(|cx| {
+            let mut inner = self.shared.inner.borrow_mut();
+            if let Some(id) = inner.next_incoming() {
+                return Poll::Ready(Ok(Stream::new(Rc::clone(&self.shared), id)));
+            }
+            if let Err(e) = inner.check() {
+                return Poll::Ready(Err(e));
+            }
+            inner.wait(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Send and receive DATAGRAM frames.  The extension has to be enabled
+    /// with `tp_constants::MAX_DATAGRAM_FRAME_SIZE` on both ends.
+    #[must_use]
+    pub fn datagrams(&self) -> Datagrams {
+        Datagrams::new(Rc::clone(&self.shared))
+    }
+
+    /// Close the connection with `error`.  Streams fail after this.
+    pub fn close(&self, error: AppError, msg: &str) {
+        self.shared
+            .inner
+            .borrow_mut()
+            .conn
+            .close(Instant::now(), error, msg);
+        self.shared.notify.notify_one();
+    }
+
+    /// Wait for the connection to close, and return the reason.
+    pub async fn closed(&self) -> ConnectionError {
+        poll_fn(|cx| {
+            let mut inner = self.shared.inner.borrow_mut();
+            match inner.error() {
+                Some(e) => Poll::Ready(e),
+                None => {
+                    inner.wait(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Use the underlying connection, for anything that this doesn't cover.
+    /// Events that `f` takes from the connection won't reach the driver.
+    pub fn with_connection<T>(&self, f: impl FnOnce(&mut neqo_transport::Connection) -> T) -> T {
+        let res = f(&mut self.shared.inner.borrow_mut().conn);
+        self.shared.notify.notify_one();
+        res
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The driver closes the connection if this is the last handle.
+        self.shared.notify.notify_one();
+    }
+}
+
+impl ::std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.shared.inner.try_borrow() {
+            Ok(inner) => write!(f, "Connection {:?}", inner.conn.state()),
+            Err(_) => write!(f, "Connection"),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// DATAGRAM frames as a `Sink` and a `Stream`.
+
+use crate::driver::{Shared, DATAGRAM_QUEUE_LIMIT};
+use crate::{Error, Res};
+use neqo_transport::State;
+
+use futures::{Sink, Stream};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// The DATAGRAM frames of a `Connection`, from `Connection::datagrams`.
+/// As a `Sink`, this takes frames to send, and waits while too many are
+/// queued; flushing waits for the queue to be empty.  As a `Stream`, this
+/// gives the frames that the peer sent, and ends when the connection
+/// closes.  If frames from the peer aren't taken, the oldest are dropped.
+///
+/// Only one task should use each half at a time.
+pub struct Datagrams {
+    shared: Rc<Shared>,
+}
+
+impl Datagrams {
+    pub(crate) fn new(shared: Rc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    fn poll_queued(&self, cx: &mut Context, limit: usize) -> Poll<Res<()>> {
+        let mut inner = self.shared.inner.borrow_mut();
+        if let Err(e) = inner.check() {
+            return Poll::Ready(Err(e));
+        }
+        if let State::Connected = inner.conn.state() {
+            if inner.conn.datagrams_queued() <= limit {
+                return Poll::Ready(Ok(()));
+            }
+            inner.wait_datagram_send(cx.waker());
+        } else {
+            inner.wait(cx.waker());
+        }
+        Poll::Pending
+    }
+}
+
+impl Sink<Vec<u8>> for Datagrams {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Res<()>> {
+        self.poll_queued(cx, DATAGRAM_QUEUE_LIMIT - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Res<()> {
+        let mut inner = self.shared.inner.borrow_mut();
+        if let Err(e) = inner.conn.send_datagram(&item) {
+            return Err(inner.fail(e));
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Res<()>> {
+        self.poll_queued(cx, 0)
+    }
+
+    /// This only flushes: the connection stays open.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Res<()>> {
+        self.poll_queued(cx, 0)
+    }
+}
+
+impl Stream for Datagrams {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        let mut inner = self.shared.inner.borrow_mut();
+        if let Some(d) = inner.next_datagram() {
+            return Poll::Ready(Some(d));
+        }
+        if inner.error().is_some() {
+            return Poll::Ready(None);
+        }
+        inner.wait_datagram_recv(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl ::std::fmt::Debug for Datagrams {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Datagrams")
+    }
+}
@@ -0,0 +1,342 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The task that drives a connection, and the state that it shares with the
+// handles for the connection and its streams.
+
+use crate::{Error, Res};
+use neqo_common::{qdebug, qwarn, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{AppError, Connection, ConnectionError, ConnectionEvent, Output, State};
+use neqo_udp::Socket;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future;
+use std::rc::Rc;
+use std::task::Waker;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Notify;
+
+/// The most DATAGRAM frames that wait to be sent before `Datagrams` stops
+/// taking more, and that wait to be received before the oldest is dropped.
+pub(crate) const DATAGRAM_QUEUE_LIMIT: usize = 32;
+
+/// What the driver knows about a stream that has a handle.
+#[derive(Debug, Default)]
+struct StreamEntry {
+    read: Option<Waker>,
+    write: Option<Waker>,
+    /// The error that the peer reset the stream with.
+    reset: Option<AppError>,
+    /// The error that the peer asked for sending to stop with.
+    stopped: Option<AppError>,
+}
+
+impl StreamEntry {
+    fn wake(&mut self) {
+        if let Some(w) = self.read.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write.take() {
+            w.wake();
+        }
+    }
+}
+
+pub(crate) struct Inner {
+    pub(crate) conn: Connection,
+    streams: HashMap<u64, StreamEntry>,
+    /// Streams that the peer opened, which haven't been accepted.
+    incoming: VecDeque<u64>,
+    /// Tasks that are waiting for the connection state to change, for a
+    /// stream from the peer, or for permission to open a stream.
+    waiting: Vec<Waker>,
+    /// DATAGRAM frames from the peer that haven't been taken.
+    received: VecDeque<Vec<u8>>,
+    /// The task waiting for a DATAGRAM frame from the peer.
+    datagram_recv: Option<Waker>,
+    /// The task waiting for DATAGRAM frames to be sent.
+    datagram_send: Option<Waker>,
+    /// Set if the driver stops before the connection is closed, which only
+    /// happens if the socket fails.
+    driver_stopped: bool,
+}
+
+impl Inner {
+    fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            streams: HashMap::new(),
+            incoming: VecDeque::new(),
+            waiting: Vec::new(),
+            received: VecDeque::new(),
+            datagram_recv: None,
+            datagram_send: None,
+            driver_stopped: false,
+        }
+    }
+
+    /// The error that the connection is closing with, if it is closing.
+    pub(crate) fn error(&self) -> Option<ConnectionError> {
+        match self.conn.state() {
+            State::Closing { error, .. } | State::Closed(error) => Some(error.clone()),
+            _ if self.driver_stopped => Some(ConnectionError::Transport(
+                neqo_transport::Error::InternalError,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Fail if the connection is closing.
+    pub(crate) fn check(&self) -> Res<()> {
+        self.error().map_or(Ok(()), |e| Err(Error::Closed(e)))
+    }
+
+    /// Turn an error from the connection into the error that the caller
+    /// should see.  Once the connection is closing, that is the reason.
+    pub(crate) fn fail(&self, err: neqo_transport::Error) -> Error {
+        self.error().map_or(Error::Transport(err), Error::Closed)
+    }
+
+    pub(crate) fn wait(&mut self, waker: &Waker) {
+        if !self.waiting.iter().any(|w| w.will_wake(waker)) {
+            self.waiting.push(waker.clone());
+        }
+    }
+
+    pub(crate) fn wait_read(&mut self, stream_id: u64, waker: &Waker) {
+        self.streams.entry(stream_id).or_default().read = Some(waker.clone());
+    }
+
+    pub(crate) fn wait_write(&mut self, stream_id: u64, waker: &Waker) {
+        self.streams.entry(stream_id).or_default().write = Some(waker.clone());
+    }
+
+    pub(crate) fn reset(&self, stream_id: u64) -> Option<AppError> {
+        self.streams.get(&stream_id).and_then(|s| s.reset)
+    }
+
+    pub(crate) fn stopped(&self, stream_id: u64) -> Option<AppError> {
+        self.streams.get(&stream_id).and_then(|s| s.stopped)
+    }
+
+    /// Start tracking a stream that now has a handle.
+    pub(crate) fn add_stream(&mut self, stream_id: u64) {
+        self.streams.entry(stream_id).or_default();
+    }
+
+    /// Stop tracking a stream when its handle goes away.
+    pub(crate) fn remove_stream(&mut self, stream_id: u64) {
+        self.streams.remove(&stream_id);
+    }
+
+    pub(crate) fn next_incoming(&mut self) -> Option<u64> {
+        self.incoming.pop_front()
+    }
+
+    pub(crate) fn next_datagram(&mut self) -> Option<Vec<u8>> {
+        self.received.pop_front()
+    }
+
+    pub(crate) fn wait_datagram_recv(&mut self, waker: &Waker) {
+        self.datagram_recv = Some(waker.clone());
+    }
+
+    pub(crate) fn wait_datagram_send(&mut self, waker: &Waker) {
+        self.datagram_send = Some(waker.clone());
+    }
+
+    fn wake_waiting(&mut self) {
+        for w in self.waiting.drain(..) {
+            w.wake();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.wake_waiting();
+        if let Some(w) = self.datagram_recv.take() {
+            w.wake();
+        }
+        if let Some(w) = self.datagram_send.take() {
+            w.wake();
+        }
+        for s in self.streams.values_mut() {
+            s.wake();
+        }
+    }
+
+    /// Handle the events that the connection has, waking the tasks that
+    /// they concern.
+    fn dispatch(&mut self, now: Instant) {
+        while let Some(e) = self.conn.next_event() {
+            qdebug!("Event {:?}", e);
+            match e {
+                ConnectionEvent::AuthenticationNeeded => {
+                    self.conn.authenticated(AuthenticationStatus::Ok, now);
+                }
+                ConnectionEvent::NewStream { stream_id, .. } => {
+                    self.add_stream(stream_id);
+                    self.incoming.push_back(stream_id);
+                    self.wake_waiting();
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id } => {
+                    if let Some(w) = self.streams.get_mut(&stream_id).and_then(|s| s.read.take()) {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::SendStreamWritable { stream_id } => {
+                    if let Some(w) = self
+                        .streams
+                        .get_mut(&stream_id)
+                        .and_then(|s| s.write.take())
+                    {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::RecvStreamReset {
+                    stream_id,
+                    app_error,
+                } => {
+                    if let Some(s) = self.streams.get_mut(&stream_id) {
+                        s.reset = Some(app_error);
+                        s.wake();
+                    }
+                }
+                ConnectionEvent::SendStreamStopSending {
+                    stream_id,
+                    app_error,
+                } => {
+                    if let Some(s) = self.streams.get_mut(&stream_id) {
+                        s.stopped = Some(app_error);
+                        s.wake();
+                    }
+                }
+                ConnectionEvent::StateChange(State::Closing { .. })
+                | ConnectionEvent::StateChange(State::Closed(_)) => self.wake_all(),
+                ConnectionEvent::StateChange(_) | ConnectionEvent::SendStreamCreatable { .. } => {
+                    self.wake_waiting()
+                }
+                ConnectionEvent::Datagram(data) => {
+                    if self.received.len() == DATAGRAM_QUEUE_LIMIT {
+                        qdebug!("Dropping a DATAGRAM frame that wasn't taken");
+                        self.received.pop_front();
+                    }
+                    self.received.push_back(data);
+                    if let Some(w) = self.datagram_recv.take() {
+                        w.wake();
+                    }
+                }
+                ConnectionEvent::SendStreamComplete { .. }
+                | ConnectionEvent::ZeroRttRejected
+                | ConnectionEvent::ZeroRttRejectReason(_)
+                | ConnectionEvent::ResumptionRejected => {}
+            }
+        }
+    }
+
+    /// Collect the datagrams that the connection wants to send, and the time
+    /// until it next needs to be called.
+    fn output(&mut self, now: Instant) -> (Vec<Datagram>, Option<Duration>) {
+        let mut dgrams = Vec::new();
+        loop {
+            match self.conn.process_output(now) {
+                Output::Datagram(d) => dgrams.push(d),
+                Output::Callback(t) => return (dgrams, Some(t)),
+                Output::None => return (dgrams, None),
+            }
+        }
+    }
+
+    /// Wake the task that is waiting to send DATAGRAM frames if what it
+    /// waits for might have happened.
+    fn wake_datagram_send(&mut self) {
+        if self.conn.datagrams_queued() < DATAGRAM_QUEUE_LIMIT {
+            if let Some(w) = self.datagram_send.take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+/// The state that the driver shares with handles.
+pub(crate) struct Shared {
+    pub(crate) inner: RefCell<Inner>,
+    /// Handles use this to wake the driver after they change the connection.
+    pub(crate) notify: Notify,
+}
+
+impl Shared {
+    pub(crate) fn new(conn: Connection) -> Self {
+        Self {
+            inner: RefCell::new(Inner::new(conn)),
+            notify: Notify::new(),
+        }
+    }
+}
+
+async fn sleep(timeout: Option<Duration>) {
+    match timeout {
+        Some(t) => tokio::time::sleep(t).await,
+        None => future::pending().await,
+    }
+}
+
+/// Run the connection until it is closed.  Once no handles remain, the
+/// connection is closed without an error.
+pub(crate) async fn drive(shared: Rc<Shared>, mut socket: AsyncFd<Socket>) {
+    loop {
+        let (dgrams, timeout) = {
+            let mut inner = shared.inner.borrow_mut();
+            let now = Instant::now();
+            if Rc::strong_count(&shared) == 1 && inner.error().is_none() {
+                qdebug!("No handles remain, closing");
+                inner.conn.close(now, 0, "");
+            }
+            inner.conn.process_timer(now);
+            inner.dispatch(now);
+            let output = inner.output(now);
+            inner.wake_datagram_send();
+            output
+        };
+        if !dgrams.is_empty() {
+            if let Err(e) = socket.get_mut().send(&dgrams) {
+                qwarn!("Failed to send {} datagrams: {}", dgrams.len(), e);
+            }
+        }
+        if let State::Closed(_) = shared.inner.borrow().conn.state() {
+            break;
+        }
+
+        tokio::select! {
+            ready = socket.readable_mut() => {
+                let mut guard = match ready {
+                    Ok(g) => g,
+                    Err(e) => {
+                        qwarn!("Socket failed: {}", e);
+                        break;
+                    }
+                };
+                match guard.try_io(|s| s.get_mut().recv()) {
+                    Ok(Ok(dgrams)) => shared
+                        .inner
+                        .borrow_mut()
+                        .conn
+                        .process_multiple_input(dgrams, Instant::now()),
+                    Ok(Err(e)) => qwarn!("Failed to receive: {}", e),
+                    // Nothing there after all, and readiness has been cleared.
+                    Err(_) => {}
+                }
+            }
+            _ = sleep(timeout) => {}
+            _ = shared.notify.notified() => {}
+        }
+    }
+    let mut inner = shared.inner.borrow_mut();
+    inner.driver_stopped = true;
+    inner.wake_all();
+}
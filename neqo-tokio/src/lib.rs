@@ -0,0 +1,85 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Tokio integration for neqo.  A `Connection` owns a UDP socket and a task
+// that drives a transport connection: it reads from the socket, runs the
+// timers, sends what the connection produces, and wakes the streams that
+// are waiting on the connection.  Streams are `AsyncRead` and `AsyncWrite`,
+// and DATAGRAM frames are a `futures::Sink` and `futures::Stream`.
+//
+// neqo connections are not `Send`, so the driver is started with
+// `tokio::task::spawn_local`.  Connections have to be made inside a
+// `LocalSet`, and the handles stay on that thread.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![cfg(unix)]
+
+mod connection;
+mod datagram;
+mod driver;
+mod stream;
+
+pub use self::connection::Connection;
+pub use self::datagram::Datagrams;
+pub use self::stream::Stream;
+
+use neqo_transport::ConnectionError;
+use std::fmt;
+use std::io;
+
+type Res<T> = Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The socket failed.
+    Io(io::Error),
+    /// The connection refused an operation.
+    Transport(neqo_transport::Error),
+    /// The connection is closed, or closing, because of this error.
+    Closed(ConnectionError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<neqo_transport::Error> for Error {
+    fn from(err: neqo_transport::Error) -> Self {
+        Error::Transport(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(e) => e,
+            Error::Closed(_) => io::Error::new(io::ErrorKind::NotConnected, err),
+            Error::Transport(_) => io::Error::other(err),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Transport(e) => Some(e),
+            Error::Closed(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Socket error: {}", e),
+            Error::Transport(e) => write!(f, "{}", e),
+            Error::Closed(e) => write!(f, "Connection closed: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The handle for a stream.
+
+use crate::driver::Shared;
+use crate::Res;
+use neqo_transport::AppError;
+
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A stream on a `Connection`.  Reading from a stream that this end opened
+/// as unidirectional fails, as does writing to one that the peer opened as
+/// unidirectional.  Shutting down the writing half sends a FIN.
+pub struct Stream {
+    shared: Rc<Shared>,
+    id: u64,
+    /// Whether the FIN has been read.
+    fin_read: bool,
+    /// Whether the FIN has been sent.
+    fin_sent: bool,
+}
+
+impl Stream {
+    pub(crate) fn new(shared: Rc<Shared>, id: u64) -> Self {
+        Self {
+            shared,
+            id,
+            fin_read: false,
+            fin_sent: false,
+        }
+    }
+
+    /// The stream ID.
+    #[must_use]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Abandon sending, and tell the peer with `error`.
+    /// # Errors
+    /// When the stream can't be reset.
+    pub fn reset(&mut self, error: AppError) -> Res<()> {
+        let mut inner = self.shared.inner.borrow_mut();
+        if let Err(e) = inner.conn.stream_reset_send(self.id, error) {
+            return Err(inner.fail(e));
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Ask the peer to stop sending, with `error`.
+    /// # Errors
+    /// When the stream can't be stopped.
+    pub fn stop_sending(&mut self, error: AppError) -> Res<()> {
+        let mut inner = self.shared.inner.borrow_mut();
+        if let Err(e) = inner.conn.stream_stop_sending(self.id, error) {
+            return Err(inner.fail(e));
+        }
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.fin_read || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut inner = this.shared.inner.borrow_mut();
+        if let Some(error) = inner.reset(this.id) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                format!("Stream reset with error {}", error),
+            )));
+        }
+        match inner.conn.stream_recv(this.id, buf.initialize_unfilled()) {
+            Ok((0, false)) => {
+                inner.check()?;
+                inner.wait_read(this.id, cx.waker());
+                Poll::Pending
+            }
+            Ok((n, fin)) => {
+                buf.advance(n);
+                this.fin_read = fin;
+                // Reading can let the connection send more flow control credit.
+                this.shared.notify.notify_one();
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => Poll::Ready(Err(inner.fail(e).into())),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut inner = this.shared.inner.borrow_mut();
+        if let Some(error) = inner.stopped(this.id) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("Peer stopped the stream with error {}", error),
+            )));
+        }
+        match inner.conn.stream_send(this.id, buf) {
+            Ok(0) => {
+                inner.check()?;
+                inner.wait_write(this.id, cx.waker());
+                Poll::Pending
+            }
+            Ok(n) => {
+                this.shared.notify.notify_one();
+                Poll::Ready(Ok(n))
+            }
+            Err(e) => Poll::Ready(Err(inner.fail(e).into())),
+        }
+    }
+
+    /// Data is sent as soon as the connection is able to, so this has
+    /// nothing to wait for.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Ok(()));
+        }
+        let mut inner = this.shared.inner.borrow_mut();
+        if let Err(e) = inner.conn.stream_close_send(this.id) {
+            return Poll::Ready(Err(inner.fail(e).into()));
+        }
+        this.fin_sent = true;
+        this.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.inner.borrow_mut().remove_stream(self.id);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl ::std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Stream {}", self.id)
+    }
+}
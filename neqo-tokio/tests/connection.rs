@@ -0,0 +1,147 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use futures::{SinkExt, StreamExt};
+use neqo_tokio::{Connection, Error};
+use neqo_transport::{tp_constants, ConnectionError, FixedConnectionIdManager, TransportParameter};
+use neqo_udp::Socket;
+use test_fixture::{default_server, DEFAULT_ALPN, DEFAULT_SERVER_NAME};
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::{self, LocalSet};
+
+fn run(f: impl Future<Output = ()>) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("make a runtime");
+    LocalSet::new().block_on(&rt, f);
+}
+
+fn server() -> (Connection, SocketAddr) {
+    let socket = Socket::bind("[::1]:0").expect("bind");
+    let addr = socket.local_addr();
+    let server = Connection::new(default_server(), socket).expect("start the server");
+    (server, addr)
+}
+
+async fn connect(addr: SocketAddr) -> Connection {
+    Connection::connect(DEFAULT_SERVER_NAME, DEFAULT_ALPN, addr)
+        .await
+        .expect("connect")
+}
+
+#[test]
+fn echo() {
+    run(async {
+        let (server, addr) = server();
+        let echo = task::spawn_local(async move {
+            let mut stream = server.accept().await.expect("accept");
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.expect("read");
+            stream.write_all(&buf).await.expect("write");
+            stream.shutdown().await.expect("shutdown");
+            server.closed().await
+        });
+
+        let client = connect(addr).await;
+        let mut stream = client.open_bi().await.expect("open");
+        let data = vec![7; 100_000];
+        stream.write_all(&data).await.expect("write");
+        stream.shutdown().await.expect("shutdown");
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.expect("read");
+        assert_eq!(echoed, data);
+
+        client.close(3, "done");
+        assert_eq!(echo.await.unwrap(), ConnectionError::Application(3));
+        match client.open_uni().await {
+            Err(Error::Closed(ConnectionError::Application(3))) => {}
+            r => panic!("opened a stream after closing: {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn reset() {
+    run(async {
+        let (server, addr) = server();
+        let peer = task::spawn_local(async move {
+            let mut stream = server.accept().await.expect("accept");
+            stream.reset(9).expect("reset");
+            server.closed().await
+        });
+
+        let client = connect(addr).await;
+        let mut stream = client.open_bi().await.expect("open");
+        stream.write_all(b"hello").await.expect("write");
+        let mut buf = [0; 10];
+        let err = stream.read(&mut buf).await.expect_err("read after reset");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        // Dropping every handle closes the connection without an error.
+        drop(stream);
+        drop(client);
+        assert_eq!(peer.await.unwrap(), ConnectionError::Application(0));
+    });
+}
+
+fn enable_datagrams(c: &neqo_transport::Connection) {
+    c.set_local_tparam(
+        tp_constants::MAX_DATAGRAM_FRAME_SIZE,
+        TransportParameter::Integer(1200),
+    )
+    .unwrap();
+}
+
+#[test]
+fn datagrams() {
+    run(async {
+        let socket = Socket::bind("[::1]:0").expect("bind");
+        let addr = socket.local_addr();
+        let server = default_server();
+        enable_datagrams(&server);
+        let server = Connection::new(server, socket).expect("start the server");
+        let echo = task::spawn_local(async move {
+            let mut datagrams = server.datagrams();
+            while let Some(d) = datagrams.next().await {
+                datagrams.send(d).await.expect("echo");
+            }
+        });
+
+        let mut socket = Socket::bind("[::1]:0").expect("bind");
+        socket.connect(addr).expect("connect");
+        let client = neqo_transport::Connection::new_client(
+            DEFAULT_SERVER_NAME,
+            DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            socket.local_addr(),
+            addr,
+        )
+        .expect("make a client");
+        enable_datagrams(&client);
+        let client = Connection::new(client, socket).expect("start the client");
+        client.connected().await.expect("connect");
+
+        let mut datagrams = client.datagrams();
+        for i in 0..10 {
+            datagrams.send(vec![i; 100]).await.expect("send");
+        }
+        for i in 0..10 {
+            assert_eq!(datagrams.next().await, Some(vec![i; 100]));
+        }
+
+        client.close(0, "");
+        echo.await.unwrap();
+    });
+}
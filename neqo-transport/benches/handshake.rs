@@ -0,0 +1,41 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use test_fixture::{self, default_client, default_server, now};
+
+/// How many connections a server can set up.  Each iteration is a
+/// connection, so the throughput reported is in connections per second.
+fn setup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("setup");
+    group.throughput(Throughput::Elements(1));
+
+    // The server side of a connection, up to sending its first flight.
+    // This includes making Initial keys from the client's connection ID.
+    group.bench_function("accept", |b| {
+        b.iter_batched(
+            || {
+                let mut client = default_client();
+                let initial = client.process(None, now()).dgram();
+                (default_server(), initial)
+            },
+            |(mut server, initial)| {
+                let out = server.process(initial, now()).dgram();
+                assert!(out.is_some());
+                server
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("connect", |b| b.iter(test_fixture::connect));
+    group.finish();
+}
+
+criterion_group!(benches, setup);
+criterion_main!(benches);
@@ -0,0 +1,168 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use neqo_common::Datagram;
+use neqo_transport::{Connection, Output, StreamType};
+use std::cmp::min;
+use std::env;
+use std::time::{Duration, Instant};
+use test_fixture::{self, now};
+
+/// How much stream data each packet benchmark sends.
+const PACKET_DATA: usize = 10_000;
+/// The default size of the transfer benchmark, which `NEQO_BENCH_TRANSFER`
+/// can change.
+const TRANSFER_SIZE: usize = 1 << 30;
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// A connected client and server, with stream data that the client has
+/// packaged and not yet sent.
+fn pending_packets() -> (Connection, Connection, u64, Vec<Datagram>) {
+    let (mut client, server) = test_fixture::connect();
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    assert_eq!(
+        client.stream_send(stream_id, &[0x62; PACKET_DATA]).unwrap(),
+        PACKET_DATA
+    );
+    let dgrams = client.process_multiple_output(now(), usize::max_value());
+    assert!(!dgrams.is_empty());
+    (client, server, stream_id, dgrams)
+}
+
+fn read_all(server: &mut Connection, stream_id: u64, buf: &mut [u8]) -> usize {
+    let mut total = 0;
+    loop {
+        let (n, _) = server.stream_recv(stream_id, buf).unwrap();
+        if n == 0 {
+            return total;
+        }
+        total += n;
+    }
+}
+
+fn packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packets");
+    group.throughput(Throughput::Bytes(PACKET_DATA as u64));
+
+    group.bench_function("encode", |b| {
+        b.iter_batched(
+            || {
+                let (mut client, server) = test_fixture::connect();
+                let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+                client.stream_send(stream_id, &[0x62; PACKET_DATA]).unwrap();
+                (client, server)
+            },
+            |(mut client, server)| {
+                let dgrams = client.process_multiple_output(now(), usize::max_value());
+                (client, server, dgrams)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("decode", |b| {
+        b.iter_batched(
+            pending_packets,
+            |(client, mut server, _, dgrams)| {
+                server.process_multiple_input(dgrams, now());
+                (client, server)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // The same packets, but in reverse order, so that the receiver has
+    // to put the stream back together.
+    group.bench_function("reassemble", |b| {
+        let mut buf = vec![0; PACKET_DATA];
+        b.iter_batched(
+            || {
+                let (client, server, stream_id, mut dgrams) = pending_packets();
+                dgrams.reverse();
+                (client, server, stream_id, dgrams)
+            },
+            |(client, mut server, stream_id, dgrams)| {
+                for d in dgrams {
+                    server.process_input(d, now());
+                }
+                assert!(read_all(&mut server, stream_id, &mut buf) > 0);
+                (client, server)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// Send everything that `from` has to `to`.  This returns whether anything
+/// was sent, and how long `from` wants to wait before it is called again.
+fn deliver(from: &mut Connection, to: &mut Connection, now: Instant) -> (bool, Duration) {
+    let mut sent = false;
+    loop {
+        match from.process_output(now) {
+            Output::Datagram(d) => {
+                to.process_input(d, now);
+                sent = true;
+            }
+            Output::Callback(delay) => return (sent, delay),
+            Output::None => panic!("connection closed"),
+        }
+    }
+}
+
+/// Move `size` bytes from client to server on one stream.
+fn transfer(size: usize) {
+    let (mut client, mut server) = test_fixture::connect();
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    let chunk = vec![0x63; CHUNK_SIZE];
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut now = now();
+    let mut sent = 0;
+    let mut received = 0;
+    while received < size {
+        while sent < size {
+            let n = client
+                .stream_send(stream_id, &chunk[..min(CHUNK_SIZE, size - sent)])
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            sent += n;
+        }
+        let (client_sent, client_wait) = deliver(&mut client, &mut server, now);
+        received += read_all(&mut server, stream_id, &mut buf);
+        let (server_sent, server_wait) = deliver(&mut server, &mut client, now);
+        if !client_sent && !server_sent {
+            // Nothing moved, so let time pass until something will.
+            now += min(client_wait, server_wait);
+            client.process_timer(now);
+            server.process_timer(now);
+        }
+    }
+    assert_eq!(received, size);
+}
+
+fn transfer_size() -> usize {
+    env::var("NEQO_BENCH_TRANSFER")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(TRANSFER_SIZE)
+}
+
+fn transfers(c: &mut Criterion) {
+    let size = transfer_size();
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function(size.to_string(), |b| b.iter(|| transfer(size)));
+    group.finish();
+}
+
+criterion_group!(benches, packets, transfers);
+criterion_main!(benches);
@@ -8,38 +8,51 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt::{self, Debug};
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use smallvec::SmallVec;
 
-use neqo_common::{hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Datagram, Decoder, Encoder};
+use neqo_common::metrics::Registry;
+use neqo_common::{
+    hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Capture, Clock, Datagram, Decoder,
+    Direction, Ecn, Encoder, SystemClock,
+};
 use neqo_crypto::agent::CertificateInfo;
 use neqo_crypto::{
-    Agent, AntiReplay, AuthenticationStatus, Client, Epoch, HandshakeState, Record,
-    SecretAgentInfo, Server,
+    Agent, AlpnSelector, AntiReplay, AuthenticationStatus, CertificateSelector,
+    CertificateVerifier, Cipher, Client, CtPolicy, Epoch, Group, HandshakeState, HashAlgorithm,
+    KeyLog, PeerGrease, PendingAuthentication, Record, SecretAgentInfo, Server, ServerCertificate,
+    SignatureScheme, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256,
 };
 
 use crate::crypto::{Crypto, CryptoDxDirection, CryptoDxState, CryptoState};
+use crate::decrypt_pool::{DecryptJob, DecryptPool};
 use crate::dump::*;
-use crate::events::{ConnectionEvent, ConnectionEvents};
+#[cfg(feature = "futures")]
+use crate::event_stream::EventStream;
+use crate::events::{ConnectionEvent, ConnectionEvents, ZeroRttRejectReason};
 use crate::flow_mgr::FlowMgr;
-use crate::frame::{decode_frame, AckRange, Frame, FrameType, StreamType, TxMode};
+use crate::frame::{decode_frame, AckRanges, Frame, FrameType, StreamType, TxFrame, TxMode};
 use crate::packet::{
-    decode_packet_hdr, decrypt_packet, encode_packet, ConnectionId, ConnectionIdDecoder, PacketHdr,
-    PacketNumberDecoder, PacketType,
+    decode_packet_hdr, decrypt_packet, decrypt_packet_with_mask, encode_packet, hp_sample,
+    protect_packets, seal_packet_short, unmask_header, ConnectionId, ConnectionIdDecoder,
+    CryptoCtx, PacketHdr, PacketNumberDecoder, PacketType, MASK_LEN,
 };
+use crate::pool::{BufferPool, PoolStats};
 use crate::recovery::{
-    LossRecovery, LossRecoveryMode, LossRecoveryState, RecoveryToken, SentPacket,
+    LossRecovery, LossRecoveryMode, LossRecoveryState, RecoveryToken, RecoveryTokens, SentPacket,
 };
 use crate::recv_stream::{RecvStream, RecvStreams, RX_STREAM_DATA_WINDOW};
 use crate::send_stream::{SendStream, SendStreams};
-use crate::stats::Stats;
+use crate::stats::{ConnectionMetrics, EcnCount, MemoryBudget, MemoryUsage, Stats};
 use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
 use crate::tparams::{
     tp_constants, TransportParameter, TransportParameters, TransportParametersHandler,
@@ -60,6 +73,15 @@ const LOCAL_MAX_DATA: u64 = 0x3FFF_FFFF_FFFF_FFFF; // 2^62-1
 
 const LOCAL_IDLE_TIMEOUT: Duration = Duration::from_secs(60); // 1 minute
 
+/// A generous estimate of what a short header packet and AEAD add to a DATAGRAM frame.
+const DATAGRAM_PACKET_OVERHEAD: usize = 64;
+
+/// The size of a DATAGRAM frame, including type and length, which is what
+/// `tp_constants::MAX_DATAGRAM_FRAME_SIZE` limits.
+fn datagram_frame_size(len: usize) -> usize {
+    1 + Encoder::varint_len(u64::try_from(len).unwrap()) + len
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 /// Client or Server.
 pub enum Role {
@@ -228,6 +250,16 @@ struct RetryInfo {
     odcid: ConnectionId,
 }
 
+/// Work that was done on the first packet of a datagram before `input`.
+enum Prepared<'a> {
+    Nothing,
+    /// The header protection mask for a short header packet.
+    Mask(&'a [u8]),
+    /// A decrypted short header packet, or `None` for the body if
+    /// decryption failed.
+    Decrypted(PacketHdr, Option<Vec<u8>>),
+}
+
 #[derive(Debug, Clone)]
 /// There's a little bit of different behavior for resetting idle timeout. See
 /// -transport 10.2 ("Idle Timeout").
@@ -275,6 +307,62 @@ impl IdleTimeout {
     }
 }
 
+/// What the TLS handshake settled on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeInfo {
+    pub version: neqo_crypto::Version,
+    pub cipher_suite: Cipher,
+    /// The group used for key exchange.
+    pub group: Group,
+    pub signature_scheme: SignatureScheme,
+    pub alpn: Option<String>,
+    pub resumed: bool,
+    pub early_data_accepted: bool,
+    /// Whether the server authenticated with a delegated credential.  This is
+    /// only ever true for a client.
+    pub delegated_credential: bool,
+}
+
+impl From<&SecretAgentInfo> for HandshakeInfo {
+    fn from(info: &SecretAgentInfo) -> Self {
+        Self {
+            version: info.version(),
+            cipher_suite: info.cipher_suite(),
+            group: info.key_exchange(),
+            signature_scheme: info.signature_scheme(),
+            alpn: info.alpn().cloned(),
+            resumed: info.resumed(),
+            early_data_accepted: info.early_data_accepted(),
+            delegated_credential: info.delegated_credential(),
+        }
+    }
+}
+
+/// How a server sends session tickets.  The lifetime of tickets isn't here,
+/// because NSS sets that for all connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TicketPolicy {
+    /// The number of tickets to send when the handshake completes.  More can
+    /// be sent at any time with `send_ticket`.
+    pub count: usize,
+    /// Whether tickets allow the client to send 0-RTT when it resumes.
+    pub allow_0rtt: bool,
+    /// The most stream data that a client can send in 0-RTT packets when it
+    /// resumes with a ticket.  A server closes the connection if a client
+    /// sends more.
+    pub max_early_data: u32,
+}
+
+impl Default for TicketPolicy {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            allow_0rtt: true,
+            max_early_data: 0xffff_ffff,
+        }
+    }
+}
+
 /// A QUIC Connection
 ///
 /// First, create a new connection using `new_client()` or `new_server()`.
@@ -297,6 +385,16 @@ pub struct Connection {
     tps: Rc<RefCell<TransportParametersHandler>>,
     /// What we are doing with 0-RTT.
     zero_rtt_state: ZeroRttState,
+    /// The stream data that 0-RTT packets can still carry: what a client can
+    /// send, or what a server accepts.
+    early_data: usize,
+    /// How tickets are sent, for a server.
+    ticket_policy: TicketPolicy,
+    /// The application protocols that we offer, which a resumption token has
+    /// to have one of.
+    alpn: Vec<String>,
+    /// The application protocol in the resumption token, if there is one.
+    resumption_alpn: Option<Vec<u8>>,
     /// This object will generate connection IDs for the connection.
     cid_manager: CidMgr,
     /// Network paths.  Right now, this tracks at most one path, so it uses `Option`.
@@ -306,6 +404,8 @@ pub struct Connection {
     /// During the handshake at the server, it also includes the randomized DCID pick by the client.
     valid_cids: Vec<ConnectionId>,
     retry_info: Option<RetryInfo>,
+    /// The versions that the server offered in a Version Negotiation packet.
+    vn_versions: Option<Vec<crate::packet::Version>>,
     pub(crate) crypto: Crypto,
     pub(crate) acks: AckTracker,
     idle_timeout: IdleTimeout,
@@ -319,7 +419,34 @@ pub struct Connection {
     events: ConnectionEvents,
     token: Option<Vec<u8>>,
     stats: Stats,
+    /// What `stats` counts, for a registry that is collecting it.
+    metrics: ConnectionMetrics,
+    /// How much memory the connection can use, if there is a limit.
+    memory_budget: Option<MemoryBudget>,
+    /// What `set_app_memory` reported.
+    app_memory: usize,
+    /// The ECN codepoint that datagrams are sent with.
+    ecn: Ecn,
+    /// The most datagrams that `process_output` puts in one.
+    max_segments: usize,
+    /// Buffers for protecting and unprotecting packets.
+    pool: BufferPool,
+    /// Threads for decrypting batches of packets, if enabled.
+    decrypt_pool: Option<DecryptPool>,
+    /// Where `wakeup` gets the time from.
+    clock: Rc<dyn Clock>,
+    /// Where every datagram that is sent and received is recorded, if set.
+    capture: Option<Rc<RefCell<dyn Capture>>>,
     tx_mode: TxMode,
+    /// DATAGRAM frames that are waiting to be sent.
+    datagrams: VecDeque<Vec<u8>>,
+    /// The span that everything this connection does is traced in.  Its
+    /// `odcid` field is the connection ID that the client first picked.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    /// Frames that a test wants sent as they are, and in which epoch.
+    #[cfg(feature = "adversarial")]
+    injected: Vec<(Epoch, Vec<u8>)>,
 }
 
 impl Debug for Connection {
@@ -356,6 +483,8 @@ impl Connection {
             }),
         );
         c.crypto.create_initial_state(Role::Client, &dcid);
+        #[cfg(feature = "tracing")]
+        c.record_odcid(&dcid);
         Ok(c)
     }
 
@@ -430,7 +559,12 @@ impl Connection {
             valid_cids: Vec::new(),
             tps: tphandler,
             zero_rtt_state: ZeroRttState::Init,
+            early_data: 0,
+            ticket_policy: TicketPolicy::default(),
+            alpn: protocols.iter().map(|p| p.as_ref().to_string()).collect(),
+            resumption_alpn: None,
             retry_info: None,
+            vn_versions: None,
             crypto,
             acks: AckTracker::default(),
             idle_timeout: IdleTimeout::default(),
@@ -444,10 +578,29 @@ impl Connection {
             events: ConnectionEvents::default(),
             token: None,
             stats: Stats::default(),
+            metrics: ConnectionMetrics::default(),
+            memory_budget: None,
+            app_memory: 0,
+            ecn: Ecn::default(),
+            max_segments: 1,
+            pool: BufferPool::default(),
+            decrypt_pool: None,
+            clock: Rc::new(SystemClock),
+            capture: None,
             tx_mode: TxMode::Normal,
+            datagrams: VecDeque::new(),
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("connection", role = ?r, odcid = tracing::field::Empty),
+            #[cfg(feature = "adversarial")]
+            injected: Vec::new(),
         }
     }
 
+    #[cfg(feature = "tracing")]
+    fn record_odcid(&self, odcid: &ConnectionId) {
+        self.span.record("odcid", &tracing::field::display(odcid));
+    }
+
     /// Set a local transport parameter, possibly overriding a default value.
     pub fn set_local_tparam(&self, key: u16, value: TransportParameter) -> Res<()> {
         if matches!(
@@ -476,10 +629,15 @@ impl Connection {
     /// higher preference.
     pub fn set_alpn(&mut self, protocols: &[impl AsRef<str>]) -> Res<()> {
         self.crypto.tls.set_alpn(protocols)?;
+        self.alpn = protocols.iter().map(|p| p.as_ref().to_string()).collect();
         Ok(())
     }
 
     /// Access the latest resumption token on the connection.
+    ///
+    /// The token holds the time that it expires, in milliseconds since the
+    /// Unix epoch, the application protocol that was used, and the server's
+    /// transport parameters, so that it can be saved and used after a restart.
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if self.state != State::Connected {
             return None;
@@ -487,8 +645,16 @@ impl Connection {
         match self.crypto.tls {
             Agent::Client(ref c) => match c.resumption_token() {
                 Some(ref t) => {
-                    qtrace!("TLS token {}", hex(&t));
+                    qtrace!("TLS token {}", hex(t.as_ref()));
                     let mut enc = Encoder::default();
+                    enc.encode_varint(neqo_common::Epoch::now().unix_millis(t.expiration_time()));
+                    let alpn = self
+                        .crypto
+                        .tls
+                        .info()
+                        .and_then(SecretAgentInfo::alpn)
+                        .expect("should have ALPN");
+                    enc.encode_vvec(alpn.as_bytes());
                     enc.encode_vvec_with(|enc_inner| {
                         self.tps
                             .borrow()
@@ -497,7 +663,7 @@ impl Connection {
                             .expect("should have transport parameters")
                             .encode(enc_inner);
                     });
-                    enc.encode(&t[..]);
+                    enc.encode(t.as_ref());
                     qinfo!("resumption token {}", hex(&enc[..]));
                     Some(enc.into())
                 }
@@ -518,6 +684,23 @@ impl Connection {
         }
         qinfo!([self], "resumption token {}", hex(token));
         let mut dec = Decoder::from(token);
+        let expiration = match dec.decode_varint() {
+            Some(v) => v,
+            _ => return Err(Error::InvalidResumptionToken),
+        };
+        if neqo_common::Epoch::now().unix_millis(now) >= expiration {
+            qinfo!([self], "resumption token expired");
+            return Err(Error::InvalidResumptionToken);
+        }
+        let alpn = match dec.decode_vvec() {
+            Some(v) => v,
+            _ => return Err(Error::InvalidResumptionToken),
+        };
+        if !self.alpn.iter().any(|a| a.as_bytes() == alpn) {
+            qinfo!([self], "resumption token ALPN {} not offered", hex(alpn));
+            return Err(Error::InvalidResumptionToken);
+        }
+        self.resumption_alpn = Some(alpn.to_vec());
         let tp_slice = match dec.decode_vvec() {
             Some(v) => v,
             _ => return Err(Error::InvalidResumptionToken),
@@ -559,15 +742,211 @@ impl Connection {
         }
     }
 
+    /// Change how this server sends session tickets.  The tickets for a
+    /// handshake are only sent if this is set before the handshake completes.
+    pub fn set_ticket_policy(&mut self, policy: TicketPolicy) -> Res<()> {
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => {
+                s.set_max_early_data(policy.max_early_data)?;
+                s.set_tickets_allow_0rtt(policy.allow_0rtt)?;
+            }
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        self.ticket_policy = policy;
+        Ok(())
+    }
+
+    /// Have `selector` choose the application protocol, rather than the
+    /// protocols that the server was made with.
+    pub fn set_alpn_selector(&mut self, selector: Box<dyn AlpnSelector>) -> Res<()> {
+        self.check_tls_config()?;
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => s.set_alpn_selector(selector)?,
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
+    /// Use `certificate` for clients that ask for `server_name`, rather than
+    /// the certificates that the server was made with.  See
+    /// `Server::set_certificate_for` in neqo-crypto for the names that match.
+    pub fn set_certificate_for(
+        &mut self,
+        server_name: &str,
+        certificate: ServerCertificate,
+    ) -> Res<()> {
+        self.check_tls_config()?;
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => s.set_certificate_for(server_name, certificate)?,
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
+    /// Have `selector` choose the certificate from the name that the client
+    /// asks for, when `set_certificate_for` doesn't have one for it.
+    pub fn set_certificate_selector(&mut self, selector: Box<dyn CertificateSelector>) -> Res<()> {
+        self.check_tls_config()?;
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => s.set_certificate_selector(selector)?,
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
     pub fn tls_info(&self) -> Option<&SecretAgentInfo> {
         self.crypto.tls.info()
     }
 
+    /// What the handshake negotiated, once it is complete.
+    pub fn handshake_info(&self) -> Option<HandshakeInfo> {
+        self.crypto.tls.info().map(HandshakeInfo::from)
+    }
+
+    /// How much more stream data a client can send in 0-RTT, or a server will
+    /// take.  Stream data beyond this waits for the handshake.  This is zero
+    /// unless 0-RTT is in use.
+    pub fn early_data_remaining(&self) -> usize {
+        match self.zero_rtt_state {
+            ZeroRttState::Sending(..) | ZeroRttState::AcceptedServer(..) => self.early_data,
+            _ => 0,
+        }
+    }
+
+    fn check_tls_config(&self) -> Res<()> {
+        if self.state == State::Init || self.state == State::WaitInitial {
+            Ok(())
+        } else {
+            qerror!([self], "configure TLS in state {:?}", self.state);
+            Err(Error::ConnectionState)
+        }
+    }
+
+    /// Only use the cipher suites in `ciphers`.  These need to be TLS 1.3
+    /// cipher suites that QUIC can use.  This has to be set before the
+    /// handshake starts.
+    pub fn set_ciphers(&mut self, ciphers: &[Cipher]) -> Res<()> {
+        self.check_tls_config()?;
+        let usable = |c: &Cipher| {
+            matches!(
+                *c,
+                TLS_AES_128_GCM_SHA256 | TLS_AES_256_GCM_SHA384 | TLS_CHACHA20_POLY1305_SHA256
+            )
+        };
+        if ciphers.is_empty() || !ciphers.iter().all(usable) {
+            return Err(Error::InvalidInput);
+        }
+        self.crypto.tls.enable_ciphers(ciphers)?;
+        Ok(())
+    }
+
+    /// Use the groups in `groups` for key exchange, in order of preference.
+    /// This has to be set before the handshake starts.
+    pub fn set_groups(&mut self, groups: &[Group]) -> Res<()> {
+        self.check_tls_config()?;
+        if groups.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+        self.crypto.tls.set_groups(groups)?;
+        Ok(())
+    }
+
+    /// Use the signature schemes in `schemes`, in order of preference, so that
+    /// schemes like RSA PKCS#1 can be left out.  This has to be set before the
+    /// handshake starts.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Res<()> {
+        self.check_tls_config()?;
+        if schemes.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+        self.crypto.tls.set_signature_schemes(schemes)?;
+        Ok(())
+    }
+
+    /// Send key shares for `count` groups as well as the first.  This has to be
+    /// set before the handshake starts.
+    pub fn send_additional_key_shares(&mut self, count: usize) -> Res<()> {
+        self.check_tls_config()?;
+        self.crypto.tls.send_additional_key_shares(count)?;
+        Ok(())
+    }
+
+    /// Send TLS GREASE values (RFC 8701), or not, which is the default.  This
+    /// has to be set before the handshake starts.
+    pub fn set_grease(&mut self, enabled: bool) -> Res<()> {
+        self.check_tls_config()?;
+        self.crypto.tls.set_grease(enabled)?;
+        Ok(())
+    }
+
+    /// Where the ClientHello had GREASE values, for a server.
+    pub fn peer_grease(&self) -> PeerGrease {
+        self.crypto.tls.peer_grease()
+    }
+
+    /// Accept a delegated credential from the server in place of the key for
+    /// its certificate.  This has to be set before the handshake starts.
+    pub fn enable_delegated_credentials(&mut self) -> Res<()> {
+        self.check_tls_config()?;
+        match self.crypto.tls {
+            Agent::Client(ref mut c) => c.enable_delegated_credentials()?,
+            Agent::Server(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
     /// Get the peer's certificate chain and other info.
     pub fn peer_certificate(&self) -> Option<CertificateInfo> {
         self.crypto.tls.peer_certificate()
     }
 
+    /// The DER-encoded certificates that the peer presented, starting with its
+    /// own.
+    pub fn peer_certificate_chain(&self) -> Vec<Vec<u8>> {
+        self.crypto.tls.peer_certificate_chain()
+    }
+
+    /// Derive `len` bytes from the TLS exporter secret, for `label` and
+    /// `context`.  Both ends get the same bytes, once the handshake is done.
+    pub fn export_keying_material(&self, label: &str, context: &[u8], len: usize) -> Res<Vec<u8>> {
+        Ok(self
+            .crypto
+            .tls
+            .export_keying_material(label, context, len)?)
+    }
+
+    /// Use an external pre-shared key, so that the handshake doesn't need
+    /// certificates.  This has to be set before the handshake starts.
+    pub fn set_external_psk(
+        &mut self,
+        identity: &[u8],
+        secret: &[u8],
+        hash: HashAlgorithm,
+    ) -> Res<()> {
+        self.check_tls_config()?;
+        self.crypto.tls.set_external_psk(identity, secret, hash)?;
+        Ok(())
+    }
+
+    /// Have `verifier` check the peer's certificates during the handshake.
+    /// If it can't decide straight away, `ConnectionEvent::AuthenticationNeeded`
+    /// is still delivered, and `authenticated` is called once it has.
+    pub fn set_certificate_verifier(&mut self, verifier: Box<dyn CertificateVerifier>) {
+        self.crypto.tls.set_certificate_verifier(verifier);
+    }
+
+    /// Have `policy` check the signed certificate timestamps that the peer
+    /// presents.  If it isn't satisfied, the handshake fails.
+    pub fn set_ct_policy(&mut self, policy: Box<dyn CtPolicy>) {
+        self.crypto.tls.set_ct_policy(policy);
+    }
+
+    /// Tell `log` about the TLS secrets, so that packets can be decrypted by
+    /// something like Wireshark.  Set this before the handshake starts.
+    pub fn set_key_log(&mut self, log: Rc<RefCell<dyn KeyLog>>) {
+        self.crypto.tls.set_key_log(log);
+    }
+
     /// Call by application when the peer cert has been verified
     pub fn authenticated(&mut self, status: AuthenticationStatus, now: Instant) {
         self.crypto.tls.authenticated(status);
@@ -575,11 +954,42 @@ impl Connection {
         self.absorb_error(now, res);
     }
 
+    /// A handle for completing authentication later, in place of calling
+    /// `authenticated`, for when checking the peer's certificates takes more
+    /// than one turn of the event loop.  This is only available after
+    /// `ConnectionEvent::AuthenticationNeeded`, and until the outcome is
+    /// known.  The handshake carries on the next time that `process_output`
+    /// is called after the handle is completed.
+    pub fn pending_authentication(&mut self) -> Option<PendingAuthentication> {
+        self.crypto.tls.pending_authentication()
+    }
+
     /// Get the role of the connection.
     pub fn role(&self) -> Role {
         self.role
     }
 
+    /// Get the QUIC version in use.
+    pub fn version(&self) -> crate::packet::Version {
+        self.version
+    }
+
+    /// Get the token from a Retry packet, if the server sent one.
+    pub fn retry_token(&self) -> Option<&[u8]> {
+        self.retry_info.as_ref().map(|r| &r.token[..])
+    }
+
+    /// Get the versions that the server offered, if the connection failed
+    /// because of a Version Negotiation packet.
+    pub fn vn_versions(&self) -> Option<&[crate::packet::Version]> {
+        self.vn_versions.as_ref().map(|v| &v[..])
+    }
+
+    /// Get the address of the peer, if a path has been established.
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.path.as_ref().map(|p| p.remote)
+    }
+
     /// Get the state of the connection.
     pub fn state(&self) -> &State {
         &self.state
@@ -590,6 +1000,81 @@ impl Connection {
         &self.stats
     }
 
+    /// Measure the memory that the connection is using.  This looks at every
+    /// stream and every packet in flight.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (crypto_tx, crypto_rx) = self.crypto.streams.buffered();
+        let recv_buffers = self
+            .recv_streams
+            .values()
+            .map(|rs| usize::try_from(rs.buffered()).unwrap())
+            .sum::<usize>();
+        MemoryUsage {
+            send_buffers: self.send_streams.buffered() + crypto_tx,
+            recv_buffers: recv_buffers + crypto_rx,
+            sent_packets: self.loss_recovery.sent_packets_memory(),
+            app: self.app_memory,
+        }
+    }
+
+    /// Limit how much memory the connection uses, or remove the limit with
+    /// `None`.  Use is measured each time packets are received or sent, but
+    /// only while there is a limit.
+    pub fn set_memory_budget(&mut self, budget: Option<MemoryBudget>) {
+        self.memory_budget = budget;
+    }
+
+    /// Add what this connection sends, receives and loses to the counters in
+    /// `registry`, from now on.
+    pub fn set_metrics(&mut self, registry: &Registry) {
+        self.metrics = ConnectionMetrics::register(registry);
+    }
+
+    /// Mark the datagrams that are sent with `ecn`.  The default is
+    /// `Ecn::NotEct`.  What each packet that is received was marked with is
+    /// counted in `Stats::ecn_rx` and reported to the peer in ACK frames, and
+    /// more CE marks reported by the peer reduce the congestion window.
+    pub fn set_ecn(&mut self, ecn: Ecn) {
+        self.ecn = ecn;
+    }
+
+    /// Let `process_output` put up to `segments` full-sized datagrams in one,
+    /// with a segment size, so that they can be sent with one UDP GSO call.
+    /// The default is 1, which doesn't.
+    pub fn set_max_segments(&mut self, segments: usize) {
+        self.max_segments = max(segments, 1);
+    }
+
+    /// Report the memory that the application protocol uses for this
+    /// connection, so that it counts toward the memory budget.
+    pub fn set_app_memory(&mut self, bytes: usize) {
+        self.app_memory = bytes;
+    }
+
+    /// Set how many buffers are kept for protecting and unprotecting packets,
+    /// and how large each is.  Packets that don't fit in a buffer still work,
+    /// but they cause allocations.
+    pub fn set_buffer_pool(&mut self, capacity: usize, buffer_size: usize) {
+        self.pool.configure(capacity, buffer_size);
+    }
+
+    /// Get statistics on how buffers for packet protection are reused.
+    pub fn buffer_pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Use `threads` threads to decrypt the short header packets that are
+    /// passed to `process_multiple_input`.  Zero, the default, decrypts
+    /// everything on the calling thread.  WASM has no threads, so there this
+    /// always decrypts on the calling thread.
+    pub fn set_decrypt_threads(&mut self, threads: usize) {
+        self.decrypt_pool = if threads > 0 && !cfg!(target_arch = "wasm32") {
+            Some(DecryptPool::new(threads))
+        } else {
+            None
+        };
+    }
+
     // This function wraps a call to another function and sets the connection state
     // properly if that call fails.
     fn capture_error<T>(&mut self, now: Instant, frame_type: FrameType, res: Res<T>) -> Res<T> {
@@ -619,6 +1104,7 @@ impl Connection {
     }
 
     pub fn process_timer(&mut self, now: Instant) {
+        enter_span!(self.span);
         if matches!(self.state(), State::Closing{..} | State::Closed{..}) {
             qinfo!("Timer fired while closing/closed");
             return;
@@ -637,15 +1123,74 @@ impl Connection {
     /// Call in to process activity on the connection. Either new packets have
     /// arrived or a timeout has expired (or both).
     pub fn process_input(&mut self, dgram: Datagram, now: Instant) {
-        let res = self.input(dgram, now);
+        if dgram.segment_size().is_some() {
+            self.process_multiple_input(Some(dgram), now);
+            return;
+        }
+        enter_span!(self.span);
+        self.capture(Direction::Received, &dgram, now);
+        let res = self.input(dgram, now, Prepared::Nothing);
         self.absorb_error(now, res);
         self.cleanup_streams();
+        self.check_memory_budget(now);
+    }
+
+    /// Process a batch of datagrams.  This does the work that follows each
+    /// call to `process_input` once for the whole batch.  When to send an
+    /// ACK is decided once, for all of the packets in the batch, and each
+    /// stream that becomes readable or writable gets one event, after the
+    /// other events from the batch.  Once the connection is established,
+    /// header protection is removed from all of the short header packets in
+    /// the batch in one pass.  If `set_decrypt_threads` was used, those
+    /// packets are then decrypted in parallel before any are processed.
+    pub fn process_multiple_input(
+        &mut self,
+        dgrams: impl IntoIterator<Item = Datagram>,
+        now: Instant,
+    ) {
+        enter_span!(self.span);
+        let dgrams = dgrams
+            .into_iter()
+            .flat_map(|d| {
+                if d.segment_size().is_some() {
+                    d.segments().collect()
+                } else {
+                    vec![d]
+                }
+            })
+            .collect::<Vec<_>>();
+        for d in &dgrams {
+            self.capture(Direction::Received, d, now);
+        }
+        let (indices, masks) = self.short_header_masks(&dgrams);
+        let prepared = if self.decrypt_pool.is_some() {
+            self.decrypt_in_parallel(dgrams, &indices, &masks)
+        } else {
+            dgrams
+                .into_iter()
+                .zip(indices)
+                .map(|(d, i)| match i {
+                    Some(i) => (d, Prepared::Mask(&masks[i * MASK_LEN..(i + 1) * MASK_LEN])),
+                    None => (d, Prepared::Nothing),
+                })
+                .collect()
+        };
+        self.acks.start_batch();
+        self.events.start_batch();
+        for (dgram, prepared) in prepared {
+            let res = self.input(dgram, now, prepared);
+            self.absorb_error(now, res);
+        }
+        self.acks.end_batch(now);
+        self.events.end_batch();
+        self.cleanup_streams();
+        self.check_memory_budget(now);
     }
 
     /// Just like above but returns frames parsed from the datagram
     #[cfg(test)]
     pub fn test_process_input(&mut self, dgram: Datagram, now: Instant) -> Vec<(Frame, Epoch)> {
-        let res = self.input(dgram, now);
+        let res = self.input(dgram, now, Prepared::Nothing);
         let frames = self.absorb_error(now, res).unwrap_or_default();
         self.cleanup_streams();
         frames
@@ -683,15 +1228,23 @@ impl Connection {
     /// Returns datagrams to send, and how long to wait before calling again
     /// even if no incoming packets.
     pub fn process_output(&mut self, now: Instant) -> Output {
+        enter_span!(self.span);
+        self.check_memory_budget(now);
+        if matches!(self.state, State::WaitInitial | State::Handshaking)
+            && self.crypto.tls.poll_authentication()
+        {
+            let res = self.handshake(now, 0, None);
+            self.absorb_error(now, res);
+        }
         let pkt = match &self.state {
             State::Init => {
                 let res = self.client_start(now);
                 self.absorb_error(now, res);
-                self.output(now)
+                self.output(now, false)
             }
             State::Closing { error, timeout, .. } => {
                 if *timeout > now {
-                    self.output(now)
+                    self.output(now, false)
                 } else {
                     // Close timeout expired, move to Closed
                     let st = State::Closed(error.clone());
@@ -700,11 +1253,15 @@ impl Connection {
                 }
             }
             State::Closed(..) => None,
-            _ => self.output(now),
+            _ => self.output(now, false),
         };
 
         match pkt {
-            Some(pkt) => Output::Datagram(pkt),
+            Some((pkt, _)) => {
+                let pkt = self.coalesce(pkt, now);
+                self.capture(Direction::Sent, &pkt, now);
+                Output::Datagram(pkt)
+            }
             None => match self.state {
                 State::Closed(_) => Output::None,
                 State::Closing { timeout, .. } => Output::Callback(timeout - now),
@@ -713,6 +1270,134 @@ impl Connection {
         }
     }
 
+    /// Add more datagrams to `first`, up to `max_segments` in all.  Only
+    /// full-sized datagrams are followed by more, so each datagram fits the
+    /// segment size, and only the last can be shorter.
+    fn coalesce(&mut self, mut first: Datagram, now: Instant) -> Datagram {
+        let size = first.len();
+        let full = match &self.path {
+            Some(path) => size == path.mtu(),
+            None => false,
+        };
+        if self.max_segments == 1 || self.state != State::Connected || !full {
+            return first;
+        }
+        let mut count = 1;
+        while count < self.max_segments {
+            let d = match self.output(now, false) {
+                Some((d, _)) => d,
+                None => break,
+            };
+            first.extend_from_slice(&d);
+            count += 1;
+            if d.len() < size {
+                break;
+            }
+        }
+        if count > 1 {
+            first.with_segment_size(size)
+        } else {
+            first
+        }
+    }
+
+    /// Build up to `max` datagrams at once.  Header protection is applied to
+    /// all of the short header packets in one pass, which is cheaper than
+    /// calling `process_output` for each datagram.  This only sends once the
+    /// connection is established, so call `process_output` afterwards to send
+    /// anything else and to learn when to call again.
+    pub fn process_multiple_output(&mut self, now: Instant, max: usize) -> Vec<Datagram> {
+        let mut out = Vec::new();
+        if self.state != State::Connected {
+            return out;
+        }
+        while out.len() < max {
+            match self.output(now, true) {
+                Some(d) => out.push(d),
+                None => break,
+            }
+        }
+
+        let mut packets = Vec::new();
+        for (d, unprotected) in &mut out {
+            if let Some((start, pn_start)) = *unprotected {
+                packets.push((&mut d[start..], pn_start));
+            }
+        }
+        if !packets.is_empty() {
+            let tx = match self.crypto.states.obtain(self.role, 3, &self.crypto.tls) {
+                Ok(CryptoState { tx: Some(tx), .. }) => tx,
+                _ => unreachable!("short header packets were built with these keys"),
+            };
+            protect_packets(tx, &mut packets).unwrap();
+        }
+        for (d, _) in &out {
+            self.capture(Direction::Sent, d, now);
+        }
+        out.into_iter().map(|(d, _)| d).collect()
+    }
+
+    /// Set the clock that `wakeup` uses.  The default is the system clock,
+    /// but tests and simulations can use a different one.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The time on the clock that `wakeup` uses.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Record every datagram that is sent and received from now on.  Tests
+    /// that use `seed_random` can record the seed in the capture too, so that
+    /// a replay sees the same connection IDs.
+    pub fn set_capture(&mut self, capture: Option<Rc<RefCell<dyn Capture>>>) {
+        self.capture = capture;
+    }
+
+    fn capture(&self, direction: Direction, d: &Datagram, now: Instant) {
+        if let Some(c) = &self.capture {
+            if d.segment_size().is_some() {
+                for s in d.segments() {
+                    c.borrow_mut().capture(direction, &s, now);
+                }
+            } else {
+                c.borrow_mut().capture(direction, d, now);
+            }
+        }
+    }
+
+    /// Send `frames` at the start of the next packet for `epoch`, without
+    /// checking them, so that tests can see what a peer does when the
+    /// protocol is broken.  `frames` has to be encoded already, and has to
+    /// fit in a packet.  Nothing is sent until there are keys for `epoch`.
+    #[cfg(feature = "adversarial")]
+    pub fn inject_frames(&mut self, epoch: Epoch, frames: Vec<u8>) {
+        self.injected.push((epoch, frames));
+    }
+
+    /// Handle a wakeup.  This processes `dgrams` and any timers that have
+    /// expired, and then adds everything there is to send to `out`.  All of
+    /// that uses the same time, which is read from the clock once.
+    /// Returns how long to wait before calling again, or `None` once the
+    /// connection is closed.
+    pub fn wakeup(
+        &mut self,
+        dgrams: impl IntoIterator<Item = Datagram>,
+        out: &mut Vec<Datagram>,
+    ) -> Option<Duration> {
+        let now = self.now();
+        self.process_multiple_input(dgrams, now);
+        self.process_timer(now);
+        loop {
+            match self.process_output(now) {
+                Output::Datagram(d) => out.push(d),
+                Output::Callback(delay) => return Some(delay),
+                Output::None => return None,
+            }
+        }
+    }
+
     /// Process input and generate output.
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         if let Some(d) = dgram {
@@ -784,7 +1469,14 @@ impl Connection {
         Ok(())
     }
 
-    fn input(&mut self, d: Datagram, now: Instant) -> Res<Vec<(Frame, Epoch)>> {
+    /// `prepared` is anything that has already been done to the packet
+    /// that `d` starts with.
+    fn input(
+        &mut self,
+        d: Datagram,
+        now: Instant,
+        mut prepared: Prepared,
+    ) -> Res<Vec<(Frame, Epoch)>> {
         let mut slc = &d[..];
         let mut frames = Vec::new();
 
@@ -806,8 +1498,10 @@ impl Connection {
                 }
             };
             self.stats.packets_rx += 1;
+            self.metrics.packets_rx.inc();
             match (&hdr.tipe, &self.state, &self.role) {
-                (PacketType::VN(_), State::WaitInitial, Role::Client) => {
+                (PacketType::VN(versions), State::WaitInitial, Role::Client) => {
+                    self.vn_versions = Some(versions.clone());
                     self.set_state(State::Closed(ConnectionError::Transport(
                         Error::VersionNegotiation,
                     )));
@@ -847,6 +1541,8 @@ impl Connection {
                             return Ok(frames);
                         }
                         self.crypto.create_initial_state(self.role, &hdr.dcid);
+                        #[cfg(feature = "tracing")]
+                        self.record_odcid(&hdr.dcid);
                     }
                 }
                 State::Handshaking | State::Connected => {
@@ -869,7 +1565,14 @@ impl Connection {
 
             qdebug!([self], "Received unverified packet {:?}", hdr);
 
-            let body = self.decrypt_body(&mut hdr, slc);
+            let body = match mem::replace(&mut prepared, Prepared::Nothing) {
+                Prepared::Nothing => self.decrypt_body(&mut hdr, slc, None),
+                Prepared::Mask(mask) => self.decrypt_body(&mut hdr, slc, Some(mask)),
+                Prepared::Decrypted(decrypted, body) => {
+                    hdr = decrypted;
+                    body
+                }
+            };
             slc = &slc[hdr.hdr_len + hdr.body_len()..];
             if let Some(body) = body {
                 // TODO(ekr@rtfm.com): Have the server blow away the initial
@@ -878,7 +1581,10 @@ impl Connection {
                 // OK, we have a valid packet.
                 self.idle_timeout.on_packet_received(now);
                 dump_packet(self, "-> RX", &hdr, &body);
-                frames.extend(self.process_packet(&hdr, body, now)?);
+                tracing_event!(trace, pn = hdr.pn, epoch = hdr.epoch, "packet received");
+                let res = self.process_packet(&hdr, &body, d.ecn(), now);
+                self.pool.give(body);
+                frames.extend(res?);
                 if matches!(self.state, State::WaitInitial) {
                     self.start_handshake(hdr, &d)?;
                 }
@@ -914,27 +1620,131 @@ impl Connection {
         }
     }
 
-    fn decrypt_body(&mut self, mut hdr: &mut PacketHdr, slc: &[u8]) -> Option<Vec<u8>> {
+    fn decrypt_body(
+        &mut self,
+        mut hdr: &mut PacketHdr,
+        slc: &[u8],
+        mask: Option<&[u8]>,
+    ) -> Option<Vec<u8>> {
         // Decryption failure, or not having keys is not fatal.
         // If the state isn't available, or we can't decrypt the packet, drop
         // the rest of the datagram on the floor, but don't generate an error.
         let largest_acknowledged = self
             .loss_recovery
             .largest_acknowledged_pn(PNSpace::from(hdr.epoch));
-        match self.obtain_epoch_rx_crypto_state(hdr.epoch) {
+        // The crypto state borrows all of `self`, so move the pool out.
+        let mut pool = mem::take(&mut self.pool);
+        let body = match self.obtain_epoch_rx_crypto_state(hdr.epoch) {
             Some(rx) => {
                 let pn_decoder = PacketNumberDecoder::new(largest_acknowledged);
-                decrypt_packet(rx, pn_decoder, &mut hdr, slc).ok()
+                let res = match mask {
+                    Some(mask) => {
+                        decrypt_packet_with_mask(rx, mask, pn_decoder, &mut hdr, slc, &mut pool)
+                    }
+                    None => decrypt_packet(rx, pn_decoder, &mut hdr, slc, &mut pool),
+                };
+                res.ok()
             }
             _ => None,
+        };
+        self.pool = pool;
+        body
+    }
+
+    /// Compute header protection masks for the datagrams in `dgrams` that
+    /// carry a short header packet, all at once.  This returns where the
+    /// mask for each datagram is, in units of `MASK_LEN`, and the masks.
+    fn short_header_masks(&mut self, dgrams: &[Datagram]) -> (Vec<Option<usize>>, Vec<u8>) {
+        let mut indices = vec![None; dgrams.len()];
+        if self.state != State::Connected {
+            return (indices, Vec::new());
+        }
+        let mut samples = Vec::new();
+        let mut count = 0;
+        for (d, index) in dgrams.iter().zip(indices.iter_mut()) {
+            let res = decode_packet_hdr(self.cid_manager.borrow().as_decoder(), d);
+            if let Ok(hdr) = res {
+                if hdr.tipe == PacketType::Short {
+                    if let Ok(sample) = hp_sample(&hdr, d) {
+                        samples.extend_from_slice(sample);
+                        *index = Some(count);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        if count == 0 {
+            return (indices, Vec::new());
+        }
+        let masks = self
+            .obtain_epoch_rx_crypto_state(3)
+            .and_then(|rx| rx.compute_masks(&samples).ok());
+        match masks {
+            Some(masks) => (indices, masks),
+            None => (vec![None; dgrams.len()], Vec::new()),
+        }
+    }
+
+    /// Remove header protection from the short header packets in `dgrams`
+    /// with `masks` from `short_header_masks`, then decrypt them all with
+    /// the decryption threads.  Packet numbers are recovered using what
+    /// was known before the batch arrived.
+    fn decrypt_in_parallel<'a>(
+        &mut self,
+        dgrams: Vec<Datagram>,
+        indices: &[Option<usize>],
+        masks: &'a [u8],
+    ) -> Vec<(Datagram, Prepared<'a>)> {
+        let largest_acknowledged = self
+            .loss_recovery
+            .largest_acknowledged_pn(PNSpace::ApplicationData);
+        let aead = self
+            .obtain_epoch_rx_crypto_state(3)
+            .map(|rx| Arc::clone(&rx.aead));
+        let mut prepared = Vec::with_capacity(dgrams.len());
+        let mut jobs = Vec::new();
+        for (index, (d, i)) in dgrams.into_iter().zip(indices).enumerate() {
+            let hdr = decode_packet_hdr(self.cid_manager.borrow().as_decoder(), &d);
+            let (mut hdr, i, aead) = match (hdr, i, &aead) {
+                (Ok(hdr), Some(i), Some(aead)) => (hdr, i, aead),
+                _ => {
+                    prepared.push(Some((d, Prepared::Nothing)));
+                    continue;
+                }
+            };
+            let mask = &masks[i * MASK_LEN..(i + 1) * MASK_LEN];
+            let pn_decoder = PacketNumberDecoder::new(largest_acknowledged);
+            let aad = unmask_header(mask, pn_decoder, &mut hdr, &d, &mut self.pool);
+            let body = hdr.hdr_len..hdr.hdr_len + hdr.body_len();
+            jobs.push(DecryptJob {
+                index,
+                aead: Arc::clone(aead),
+                dgram: d,
+                hdr,
+                aad,
+                body,
+                out: self.pool.take(),
+            });
+            prepared.push(None);
+        }
+
+        if !jobs.is_empty() {
+            let decrypt_pool = self.decrypt_pool.as_ref().unwrap();
+            for done in decrypt_pool.run(jobs) {
+                self.pool.give(done.aad);
+                let body = done.body.map_err(|out| self.pool.give(out)).ok();
+                prepared[done.index] = Some((done.dgram, Prepared::Decrypted(done.hdr, body)));
+            }
         }
+        prepared.into_iter().map(Option::unwrap).collect()
     }
 
     /// Ok(true) if the packet is a duplicate
     fn process_packet(
         &mut self,
         hdr: &PacketHdr,
-        body: Vec<u8>,
+        body: &[u8],
+        ecn: Ecn,
         now: Instant,
     ) -> Res<Vec<(Frame, Epoch)>> {
         // TODO(ekr@rtfm.com): Have the server blow away the initial
@@ -953,15 +1763,21 @@ impl Connection {
                 hdr.pn
             );
             self.stats.dups_rx += 1;
+            self.metrics.dups_rx.inc();
             return Ok(vec![]);
         }
 
         let mut ack_eliciting = false;
-        let mut d = Decoder::from(&body[..]);
+        let mut d = Decoder::from(body);
         #[allow(unused_mut)]
         let mut frames = Vec::new();
         while d.remaining() > 0 {
-            let f = decode_frame(&mut d)?;
+            // A frame that can't be decoded, including one of an unknown
+            // type, is a FRAME_ENCODING_ERROR (-transport 12.4).
+            let f = decode_frame(&mut d).map_err(|e| match e {
+                Error::NoMoreData | Error::UnknownFrameType => Error::FrameEncodingError,
+                e => e,
+            })?;
             if cfg!(test) {
                 frames.push((f.clone(), hdr.epoch));
             }
@@ -971,6 +1787,8 @@ impl Connection {
             self.capture_error(now, t, res)?;
         }
         self.acks[space].set_received(now, hdr.pn, ack_eliciting);
+        self.acks[space].count_ecn(now, ecn);
+        self.stats.ecn_rx.add(ecn);
 
         Ok(frames)
     }
@@ -1010,7 +1828,8 @@ impl Connection {
 
             // SecretAgentPreinfo::early_data() always returns false for a server,
             // but a non-zero maximum tells us if we are accepting 0-RTT.
-            self.zero_rtt_state = if self.crypto.tls.preinfo()?.max_early_data() > 0 {
+            self.early_data = self.crypto.tls.preinfo()?.max_early_data();
+            self.zero_rtt_state = if self.early_data > 0 {
                 match self.get_zero_rtt_crypto() {
                     Some(cs) => ZeroRttState::AcceptedServer(cs),
                     None => {
@@ -1048,10 +1867,16 @@ impl Connection {
         }
     }
 
-    fn output(&mut self, now: Instant) -> Option<Datagram> {
+    /// With `defer_hp`, a short header packet is left without header
+    /// protection, and its location in the datagram is returned.
+    fn output(
+        &mut self,
+        now: Instant,
+        defer_hp: bool,
+    ) -> Option<(Datagram, Option<(usize, usize)>)> {
         let mut out = None;
         if self.path.is_some() {
-            match self.output_pkt_for_path(now) {
+            match self.output_pkt_for_path(now, defer_hp) {
                 Ok(res) => {
                     out = res;
                 }
@@ -1061,7 +1886,7 @@ impl Connection {
                         let err: Result<Option<Datagram>, Error> = Err(e);
                         self.absorb_error(now, err);
                         // Rerun to give a chance to send a CONNECTION_CLOSE.
-                        out = match self.output_pkt_for_path(now) {
+                        out = match self.output_pkt_for_path(now, defer_hp) {
                             Ok(x) => x,
                             Err(e) => {
                                 qwarn!([self], "two output_path errors in a row: {:?}", e);
@@ -1079,8 +1904,15 @@ impl Connection {
     #[allow(clippy::useless_let_if_seq)]
     /// Build a datagram, possibly from multiple packets (for different PN
     /// spaces) and each containing 1+ frames.
-    fn output_pkt_for_path(&mut self, now: Instant) -> Res<Option<Datagram>> {
+    /// With `defer_hp`, this also returns the offsets of a short header packet
+    /// without header protection and of its packet number.
+    fn output_pkt_for_path(
+        &mut self,
+        now: Instant,
+        defer_hp: bool,
+    ) -> Res<Option<(Datagram, Option<(usize, usize)>)>> {
         let mut out_bytes = Vec::new();
+        let mut unprotected = None;
         let mut needs_padding = false;
         let mut close_sent = false;
         let path = self
@@ -1092,8 +1924,6 @@ impl Connection {
         // packets can go in a single datagram
         for epoch in 0..NUM_EPOCHS {
             let space = PNSpace::from(epoch);
-            let mut encoder = Encoder::default();
-            let mut tokens = Vec::new();
 
             // Ensure we have tx crypto state for this epoch, or skip it.
             let tx = if epoch == 1 && self.role == Role::Server {
@@ -1114,6 +1944,11 @@ impl Connection {
                 }
             };
 
+            // Frames are written into a buffer from the pool and their tokens
+            // are kept inline, so that building a packet doesn't allocate.
+            let mut encoder = Encoder::from(self.pool.take());
+            let mut tokens = RecoveryTokens::new();
+
             let hdr = PacketHdr::new(
                 0,
                 match epoch {
@@ -1146,6 +1981,18 @@ impl Connection {
 
             match &self.state {
                 State::Init | State::WaitInitial | State::Handshaking | State::Connected => {
+                    #[cfg(feature = "adversarial")]
+                    {
+                        let (injected, rest): (Vec<_>, Vec<_>) =
+                            mem::replace(&mut self.injected, Vec::new())
+                                .into_iter()
+                                .partition(|(e, _)| *e == epoch);
+                        self.injected = rest;
+                        for (_, frames) in injected {
+                            encoder.encode(&frames);
+                            ack_eliciting = true;
+                        }
+                    }
                     loop {
                         let used =
                             out_bytes.len() + encoder.len() + hdr.overhead(&tx.aead, path.mtu());
@@ -1161,24 +2008,37 @@ impl Connection {
                         // Try to get a frame from frame sources
                         let mut frame = None;
                         if self.tx_mode == TxMode::Normal {
-                            frame = self.acks.get_frame(now, epoch);
+                            frame = self.acks.get_frame(now, epoch).map(|(f, t)| (f.into(), t));
                         }
                         if frame.is_none() {
                             frame = self.crypto.streams.get_frame(epoch, tx_mode, remaining)
                         }
                         if frame.is_none() && self.tx_mode == TxMode::Normal {
-                            frame = self.flow_mgr.borrow_mut().get_frame(epoch, remaining);
+                            frame = self
+                                .flow_mgr
+                                .borrow_mut()
+                                .get_frame(epoch, remaining)
+                                .map(|(f, t)| (f.into(), t));
+                        }
+                        if frame.is_none() && self.tx_mode == TxMode::Normal {
+                            frame = Self::get_datagram_frame(&mut self.datagrams, epoch, remaining)
+                                .map(|f| (f.into(), None));
                         }
                         if frame.is_none() {
-                            frame = self.send_streams.get_frame(epoch, tx_mode, remaining)
+                            let space = if epoch == 1 {
+                                min(remaining, self.early_data)
+                            } else {
+                                remaining
+                            };
+                            frame = self.send_streams.get_frame(epoch, tx_mode, space)
                         }
                         if frame.is_none() && self.tx_mode == TxMode::Pto {
-                            frame = Some((Frame::Ping, None));
+                            frame = Some((Frame::Ping.into(), None));
                         }
 
                         if let Some((frame, token)) = frame {
                             ack_eliciting |= frame.ack_eliciting();
-                            if let Frame::Padding = frame {
+                            if let TxFrame::Frame(Frame::Padding) = frame {
                                 has_padding |= true;
                             }
                             frame.marshal(&mut encoder);
@@ -1186,6 +2046,15 @@ impl Connection {
                                 tokens.push(t);
                             }
 
+                            if let (1, TxFrame::Stream { data, fill, .. }) = (epoch, &frame) {
+                                self.early_data -= data.len();
+                                // A frame that fills what early data allows
+                                // has no length, so it has to be the last.
+                                if *fill {
+                                    break;
+                                }
+                            }
+
                             // Pto only ever sends one frame, but it ALWAYS
                             // sends one
                             if self.tx_mode == TxMode::Pto {
@@ -1207,10 +2076,12 @@ impl Connection {
                     if self.flow_mgr.borrow().need_close_frame() {
                         // ConnectionClose frame not allowed for 0RTT
                         if epoch == 1 {
+                            self.pool.give(encoder.into());
                             continue;
                         }
                         // ConnectionError::Application only allowed at 1RTT
                         if epoch != 3 && matches!(error, ConnectionError::Application(_)) {
+                            self.pool.give(encoder.into());
                             continue;
                         }
                         let frame = Frame::ConnectionClose {
@@ -1227,6 +2098,7 @@ impl Connection {
 
             assert!(encoder.len() <= path.mtu());
             if encoder.len() == 0 {
+                self.pool.give(encoder.into());
                 continue;
             }
 
@@ -1240,9 +2112,17 @@ impl Connection {
             }
 
             self.stats.packets_tx += 1;
+            self.metrics.packets_tx.inc();
+            tracing_event!(trace, pn = hdr.pn, epoch, "packet sent");
             self.loss_recovery.inc_pn(space);
 
-            let mut packet = encode_packet(tx, &hdr, &encoder);
+            let mut packet = if defer_hp && hdr.tipe == PacketType::Short {
+                let (packet, pn_start) = seal_packet_short(tx, &hdr, &encoder, &mut self.pool);
+                unprotected = Some((out_bytes.len(), pn_start));
+                packet
+            } else {
+                encode_packet(tx, &hdr, &encoder, &mut self.pool)
+            };
 
             if self.tx_mode != TxMode::Pto && ack_eliciting {
                 self.idle_timeout.on_packet_sent(now);
@@ -1260,8 +2140,10 @@ impl Connection {
             );
 
             dump_packet(self, "TX ->", &hdr, &encoder);
+            self.pool.give(encoder.into());
 
             out_bytes.append(&mut packet);
+            self.pool.give(packet);
         }
 
         if close_sent {
@@ -1284,7 +2166,8 @@ impl Connection {
                 qdebug!([self], "pad Initial to max_datagram_size");
                 out_bytes.resize(path.mtu(), 0);
             }
-            let ret = Ok(Some(Datagram::new(path.local, path.remote, out_bytes)));
+            let dgram = Datagram::new(path.local, path.remote, out_bytes).with_ecn(self.ecn);
+            let ret = Ok(Some((dgram, unprotected)));
             self.path = Some(path);
             ret
         }
@@ -1294,8 +2177,10 @@ impl Connection {
         qinfo!([self], "client_start");
         self.handshake(now, 0, None)?;
         self.set_state(State::WaitInitial);
-        if self.crypto.tls.preinfo()?.early_data() {
+        let preinfo = self.crypto.tls.preinfo()?;
+        if preinfo.early_data() {
             qdebug!([self], "Enabling 0-RTT");
+            self.early_data = preinfo.max_early_data();
             self.zero_rtt_state = match self.get_zero_rtt_crypto() {
                 Some(cs) => ZeroRttState::Sending(cs),
                 None => {
@@ -1391,6 +2276,11 @@ impl Connection {
             self.validate_odcid()?;
             self.set_state(State::Connected);
             self.set_initial_limits();
+            if self.role == Role::Server {
+                for _ in 0..self.ticket_policy.count {
+                    self.send_ticket(now, &[])?;
+                }
+            }
         }
         Ok(())
     }
@@ -1429,6 +2319,7 @@ impl Connection {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
+                ecn_count,
             } => {
                 self.handle_ack(
                     epoch,
@@ -1436,23 +2327,36 @@ impl Connection {
                     ack_delay,
                     first_ack_range,
                     ack_ranges,
+                    ecn_count,
                     now,
                 )?;
             }
             Frame::ResetStream {
                 stream_id,
                 application_error_code,
-                ..
+                final_size,
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if send-only
+                // stream (-transport 19.4)
+                if stream_id.is_send_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
                 // TODO(agrover@mozilla.com): use final_size for connection MaxData calc
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
-                    rs.reset(application_error_code);
+                    rs.reset(application_error_code, final_size)?;
                 }
             }
             Frame::StopSending {
                 stream_id,
                 application_error_code,
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if receive-only
+                // stream (-transport 19.5)
+                if stream_id.is_recv_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
                 self.events
                     .send_stream_stop_sending(stream_id, application_error_code);
                 if let (Some(ss), _) = self.obtain_stream(stream_id)? {
@@ -1483,6 +2387,12 @@ impl Connection {
                 data,
                 ..
             } => {
+                if epoch == 1 {
+                    self.early_data = self
+                        .early_data
+                        .checked_sub(data.len())
+                        .ok_or(Error::ProtocolViolation)?;
+                }
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
                     rs.inbound_stream_frame(fin, offset, data)?;
                 }
@@ -1546,10 +2456,17 @@ impl Connection {
             }
             Frame::NewConnectionId {
                 sequence_number,
+                retire_prior,
                 connection_id,
                 stateless_reset_token,
-                ..
             } => {
+                // -transport 19.15
+                if !matches!(connection_id.len(), 1..=20) {
+                    return Err(Error::ProtocolViolation);
+                }
+                if retire_prior > sequence_number {
+                    return Err(Error::FrameEncodingError);
+                }
                 self.connection_ids
                     .insert(sequence_number, (connection_id, stateless_reset_token));
             }
@@ -1577,12 +2494,35 @@ impl Connection {
                 );
                 self.set_state(State::Closed(error_code.into()));
             }
+            Frame::Datagram { data } => {
+                // is_allowed() has rejected this unless it came in 0-RTT or 1-RTT.
+                let limit = self
+                    .tps
+                    .borrow()
+                    .local
+                    .get_integer(tp_constants::MAX_DATAGRAM_FRAME_SIZE);
+                if u64::try_from(datagram_frame_size(data.len()))? > limit {
+                    return Err(Error::ProtocolViolation);
+                }
+                self.events.datagram(data);
+            }
         };
 
         Ok(())
     }
 
     fn handle_lost_packets(&mut self, lost_packets: &[SentPacket]) {
+        self.stats.lost += lost_packets.len() as u64;
+        self.metrics.lost.add(lost_packets.len() as u64);
+        self.stats.cwnd = self.loss_recovery.cwnd();
+        if !lost_packets.is_empty() {
+            tracing_event!(
+                debug,
+                lost = lost_packets.len(),
+                cwnd = self.stats.cwnd,
+                "packets lost"
+            );
+        }
         for lost in lost_packets {
             for token in &lost.tokens {
                 qdebug!([self], "Lost: {:?}", token);
@@ -1607,7 +2547,8 @@ impl Connection {
         largest_acknowledged: u64,
         ack_delay: u64,
         first_ack_range: u64,
-        ack_ranges: Vec<AckRange>,
+        ack_ranges: AckRanges,
+        ecn_count: Option<EcnCount>,
         now: Instant,
     ) -> Res<()> {
         qinfo!(
@@ -1620,14 +2561,22 @@ impl Connection {
         );
 
         let acked_ranges =
-            Frame::decode_ack_frame(largest_acknowledged, first_ack_range, ack_ranges)?;
+            Frame::decode_ack_frame(largest_acknowledged, first_ack_range, &ack_ranges)?;
         let (acked_packets, lost_packets) = self.loss_recovery.on_ack_received(
             PNSpace::from(epoch),
             largest_acknowledged,
             acked_ranges,
             Duration::from_millis(ack_delay),
+            ecn_count,
             now,
         );
+        self.stats.rtt = self.loss_recovery.rtt();
+        tracing_event!(
+            trace,
+            largest_acknowledged,
+            rtt = ?self.stats.rtt,
+            "ACK received"
+        );
         for acked in acked_packets {
             for token in acked.tokens {
                 match token {
@@ -1671,12 +2620,34 @@ impl Connection {
         self.send_streams.clear();
         self.recv_streams.clear();
         self.indexes = StreamIndexes::new();
+        let reason = self.zero_rtt_reject_reason();
+        qinfo!([self], "0-RTT rejected: {:?}", reason);
         self.events.client_0rtt_rejected();
+        self.events.zero_rtt_reject_reason(reason);
+    }
+
+    /// Why the server rejected 0-RTT, as far as the client can tell.
+    fn zero_rtt_reject_reason(&self) -> ZeroRttRejectReason {
+        let info = self.crypto.tls.info().unwrap();
+        if !info.resumed() {
+            return ZeroRttRejectReason::TicketRejected;
+        }
+        if info.alpn().map(String::as_bytes) != self.resumption_alpn.as_deref() {
+            return ZeroRttRejectReason::Alpn;
+        }
+        let tps = self.tps.borrow();
+        if let (Some(remote), Some(remembered)) = (&tps.remote, &tps.remote_0rtt) {
+            if !remote.ok_for_0rtt(remembered) {
+                return ZeroRttRejectReason::TransportParameters;
+            }
+        }
+        ZeroRttRejectReason::Declined
     }
 
     fn set_state(&mut self, state: State) {
         if state > self.state {
             qinfo!([self], "State change from {:?} -> {:?}", self.state, state);
+            tracing_event!(info, from = ?self.state, to = ?state, "state change");
             self.state = state.clone();
             match &self.state {
                 State::Connected => {
@@ -1691,18 +2662,24 @@ impl Connection {
                             } else {
                                 self.client_0rtt_rejected();
                                 ZeroRttState::Rejected
-                            }
+                            };
+                        let resumed = self.crypto.tls.info().unwrap().resumed();
+                        if self.tps.borrow().remote_0rtt.is_some() && !resumed {
+                            self.events.resumption_rejected();
+                        }
                     }
                 }
                 State::Closing { .. } => {
                     self.send_streams.clear();
                     self.recv_streams.clear();
+                    self.datagrams.clear();
                     self.flow_mgr.borrow_mut().set_need_close_frame(true);
                 }
                 State::Closed(..) => {
                     // Equivalent to spec's "draining" state -- never send anything.
                     self.send_streams.clear();
                     self.recv_streams.clear();
+                    self.datagrams.clear();
                 }
                 _ => {}
             }
@@ -1750,6 +2727,40 @@ impl Connection {
         self.send_streams.clear_terminal();
     }
 
+    /// Close the connection or hold back flow control credit if it is using
+    /// more memory than its budget.
+    fn check_memory_budget(&mut self, now: Instant) {
+        if self.memory_budget.is_none()
+            || matches!(self.state, State::Closing { .. } | State::Closed(..))
+        {
+            return;
+        }
+        let used = self.memory_usage().total();
+        let clamp = match self.memory_budget {
+            Some(MemoryBudget::Close(limit)) if used > limit => {
+                qwarn!([self], "Memory use {} exceeds budget {}", used, limit);
+                self.absorb_error::<()>(now, Err(Error::MemoryBudgetExceeded));
+                return;
+            }
+            Some(MemoryBudget::ClampFlowControl(limit)) => used > limit,
+            _ => false,
+        };
+        if clamp != self.flow_mgr.borrow().rx_clamped() {
+            qinfo!(
+                [self],
+                "Memory use {}, flow control clamped: {}",
+                used,
+                clamp
+            );
+            self.flow_mgr.borrow_mut().set_rx_clamped(clamp);
+            if !clamp {
+                for rs in self.recv_streams.values_mut() {
+                    rs.maybe_send_flowc_update();
+                }
+            }
+        }
+    }
+
     /// Get or make a stream, and implicitly open additional streams as
     /// indicated by its stream id.
     fn obtain_stream(
@@ -1998,6 +3009,54 @@ impl Connection {
         Ok(())
     }
 
+    /// The number of DATAGRAM frames that are waiting to be sent.
+    pub fn datagrams_queued(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Queue an unreliable DATAGRAM frame for sending.
+    /// These are never retransmitted.  The peer has to support the extension,
+    /// which is enabled locally by setting `tp_constants::MAX_DATAGRAM_FRAME_SIZE`.
+    pub fn send_datagram(&mut self, data: &[u8]) -> Res<()> {
+        if self.state != State::Connected {
+            return Err(Error::ConnectionState);
+        }
+        let limit = self
+            .tps
+            .borrow()
+            .remote()
+            .get_integer(tp_constants::MAX_DATAGRAM_FRAME_SIZE);
+        if limit == 0 {
+            // The peer didn't enable the extension.
+            return Err(Error::ConnectionState);
+        }
+        let frame_size = datagram_frame_size(data.len());
+        let mtu = self.path.as_ref().map_or(0, Path::mtu);
+        if u64::try_from(frame_size)? > limit || frame_size + DATAGRAM_PACKET_OVERHEAD > mtu {
+            return Err(Error::TooMuchData);
+        }
+        self.datagrams.push_back(data.to_vec());
+        Ok(())
+    }
+
+    fn get_datagram_frame(
+        datagrams: &mut VecDeque<Vec<u8>>,
+        epoch: Epoch,
+        remaining: usize,
+    ) -> Option<Frame> {
+        if epoch != 3 {
+            return None;
+        }
+        let fits = datagrams
+            .front()
+            .map_or(false, |d| datagram_frame_size(d.len()) <= remaining);
+        if fits {
+            datagrams.pop_front().map(|data| Frame::Datagram { data })
+        } else {
+            None
+        }
+    }
+
     /// Read buffered data from stream. bool says whether read bytes includes
     /// the final data on stream.
     pub fn stream_recv(&mut self, stream_id: u64, data: &mut [u8]) -> Res<(usize, bool)> {
@@ -2032,6 +3091,11 @@ impl Connection {
         self.events.has_events()
     }
 
+    /// The number of events that haven't been taken yet.
+    pub fn pending_events(&self) -> usize {
+        self.events.pending()
+    }
+
     /// Get events that indicate state changes on the connection. This method
     /// correctly handles cases where handling one event can obsolete
     /// previously-queued events, or cause new events to be generated.
@@ -2039,6 +3103,12 @@ impl Connection {
         self.events.next_event()
     }
 
+    /// The events of this connection as a `Stream`.
+    #[cfg(feature = "futures")]
+    pub fn event_stream(&self) -> EventStream {
+        EventStream::new(self.events.clone())
+    }
+
     fn check_loss_detection_timeout(&mut self, now: Instant) {
         qdebug!([self], "check_loss_timeouts");
 
@@ -2064,21 +3134,7 @@ impl Connection {
                 let packets = self.loss_recovery.detect_lost_packets(pn_space, now);
 
                 qinfo!("lost packets: {}", packets.len());
-                for lost in packets {
-                    for token in lost.tokens {
-                        match token {
-                            RecoveryToken::Ack(_) => {} // Do nothing
-                            RecoveryToken::Stream(st) => self.send_streams.lost(&st),
-                            RecoveryToken::Crypto(ct) => self.crypto.lost(&ct),
-                            RecoveryToken::Flow(ft) => self.flow_mgr.borrow_mut().lost(
-                                &ft,
-                                &mut self.send_streams,
-                                &mut self.recv_streams,
-                                &mut self.indexes,
-                            ),
-                        }
-                    }
-                }
+                self.handle_lost_packets(&packets);
             }
             LossRecoveryMode::PTO => {
                 qinfo!(
@@ -2086,6 +3142,7 @@ impl Connection {
                     "check_loss_detection_timeout -send_one_or_two_packets"
                 );
                 self.loss_recovery.increment_pto_count();
+                tracing_event!(debug, "probe timeout");
                 // TODO
                 // if (has unacknowledged crypto data):
                 //   RetransmitUnackedCryptoData()
@@ -2125,8 +3182,9 @@ mod tests {
     use super::*;
     use crate::frame::{CloseError, StreamType};
     use crate::recovery::{INITIAL_CWND_PKTS, MAX_DATAGRAM_SIZE, MIN_CONG_WINDOW};
-    use neqo_common::matches;
-    use std::mem;
+    use neqo_common::metrics::Value;
+    use neqo_common::{matches, VirtualClock};
+    use neqo_crypto::CertificateVerdict;
     use test_fixture::{self, assertions, fixture_init, loopback, now};
 
     // This is fabulous: because test_fixture uses the public API for Connection,
@@ -2283,6 +3341,15 @@ mod tests {
 
         assert_eq!(*client.state(), State::Connected);
         assert_eq!(*server.state(), State::Connected);
+        assert_eq!(client.peer_certificate_chain().len(), 1);
+        assert!(server.peer_certificate_chain().is_empty());
+        let exported = client
+            .export_keying_material("EXPORTER-test", &[], 16)
+            .unwrap();
+        assert_eq!(
+            server.export_keying_material("EXPORTER-test", &[], 16),
+            Ok(exported)
+        );
     }
 
     #[test]
@@ -2326,6 +3393,152 @@ mod tests {
         assert_error(&server, ConnectionError::Transport(Error::PeerError(300)));
     }
 
+    #[derive(Debug)]
+    struct AcceptAll;
+    impl CertificateVerifier for AcceptAll {
+        fn verify(&mut self, chain: &[&[u8]], server_name: Option<&str>) -> CertificateVerdict {
+            assert!(!chain.is_empty());
+            assert_eq!(server_name, Some(test_fixture::DEFAULT_SERVER_NAME));
+            CertificateVerdict::Accept
+        }
+    }
+
+    #[test]
+    fn certificate_verifier() {
+        let mut client = default_client();
+        client.set_certificate_verifier(Box::new(AcceptAll));
+        let mut server = default_server();
+
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        // The client finishes without waiting for authenticated().
+        let out = client.process(out.dgram(), now());
+        assert!(out.as_dgram_ref().is_some());
+        let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+        assert!(!client.events().any(authentication_needed));
+        assert_eq!(*client.state(), State::Connected);
+
+        server.process(out.dgram(), now());
+        assert_eq!(*server.state(), State::Connected);
+    }
+
+    #[test]
+    fn pending_authentication() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert!(client.pending_authentication().is_none());
+
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let out = client.process(out.dgram(), now());
+        let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+        assert!(client.events().any(authentication_needed));
+        let pending = client.pending_authentication().unwrap();
+
+        // The connection carries on while the outcome isn't known.
+        server.process(out.dgram(), now());
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_none());
+        assert_eq!(*client.state(), State::Handshaking);
+
+        pending.complete(AuthenticationStatus::Ok);
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_some());
+        assert_eq!(*client.state(), State::Connected);
+        assert!(client.pending_authentication().is_none());
+        server.process(out.dgram(), now());
+        assert_eq!(*server.state(), State::Connected);
+    }
+
+    #[test]
+    fn external_psk() {
+        const PSK_ID: &[u8] = b"external-psk";
+        let mut client = default_client();
+        client
+            .set_external_psk(PSK_ID, &[1; 32], neqo_crypto::TLS_HASH_SHA256)
+            .unwrap();
+        let mut server = Connection::new_server(
+            &[] as &[&str],
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+        )
+        .unwrap();
+        server
+            .set_external_psk(PSK_ID, &[1; 32], neqo_crypto::TLS_HASH_SHA256)
+            .unwrap();
+        connect(&mut client, &mut server);
+        assert!(client.peer_certificate().is_none());
+        assert!(client.peer_certificate_chain().is_empty());
+    }
+
+    #[test]
+    fn tls_config() {
+        let mut client = default_client();
+        client
+            .set_ciphers(&[neqo_crypto::TLS_CHACHA20_POLY1305_SHA256])
+            .unwrap();
+        client
+            .set_groups(&[
+                neqo_crypto::TLS_GRP_EC_X25519,
+                neqo_crypto::TLS_GRP_EC_SECP256R1,
+            ])
+            .unwrap();
+        client.send_additional_key_shares(1).unwrap();
+        client
+            .set_signature_schemes(&[neqo_crypto::TLS_SIG_ECDSA_SECP256R1_SHA256])
+            .unwrap();
+        let mut server = default_server();
+        server
+            .set_ciphers(&[
+                neqo_crypto::TLS_AES_128_GCM_SHA256,
+                neqo_crypto::TLS_CHACHA20_POLY1305_SHA256,
+            ])
+            .unwrap();
+        server
+            .set_groups(&[neqo_crypto::TLS_GRP_EC_SECP256R1])
+            .unwrap();
+        assert_eq!(client.handshake_info(), None);
+        connect(&mut client, &mut server);
+
+        let info = client.handshake_info().unwrap();
+        assert_eq!(info.cipher_suite, neqo_crypto::TLS_CHACHA20_POLY1305_SHA256);
+        assert_eq!(info.group, neqo_crypto::TLS_GRP_EC_SECP256R1);
+        assert_eq!(
+            info.signature_scheme,
+            neqo_crypto::TLS_SIG_ECDSA_SECP256R1_SHA256
+        );
+        assert_eq!(Some(info), server.handshake_info());
+
+        assert_eq!(
+            client.set_groups(&[neqo_crypto::TLS_GRP_EC_X25519]),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    #[test]
+    fn grease() {
+        let mut client = default_client();
+        client.set_grease(true).unwrap();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(server.peer_grease().any());
+        assert!(!client.peer_grease().any());
+        assert_eq!(client.set_grease(false), Err(Error::ConnectionState));
+    }
+
+    #[test]
+    fn tls_config_invalid() {
+        let mut client = default_client();
+        assert_eq!(client.set_ciphers(&[]), Err(Error::InvalidInput));
+        // TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256 is only for TLS 1.2.
+        assert_eq!(client.set_ciphers(&[0xc02b]), Err(Error::InvalidInput));
+        assert_eq!(client.set_groups(&[]), Err(Error::InvalidInput));
+        assert_eq!(client.set_signature_schemes(&[]), Err(Error::InvalidInput));
+        let mut server = default_server();
+        assert_eq!(server.enable_delegated_credentials(), Err(Error::WrongRole));
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     // tests stream send/recv after connection is established.
@@ -2523,6 +3736,80 @@ mod tests {
         assert_eq!(2, client.stats().dups_rx);
     }
 
+    #[test]
+    fn ecn_marking() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_ecn(Ecn::Ect0);
+
+        let out = client.process(None, now()).dgram().unwrap();
+        assert_eq!(out.ecn(), Ecn::Ect0);
+        let out = server.process(Some(out), now()).dgram().unwrap();
+        assert_eq!(out.ecn(), Ecn::NotEct);
+        assert_eq!(server.stats().ecn_rx.ect0, 1);
+
+        let out = out.with_ecn(Ecn::Ce);
+        client.process_input(out.clone(), now());
+        let ce = client.stats().ecn_rx.ce;
+        assert!(ce > 0);
+        assert_eq!(client.stats().ecn_rx.ect0, 0);
+
+        // Packets that are duplicates, or can't be decrypted, aren't counted.
+        client.process_input(out, now());
+        assert!(client.stats().packets_rx > ce);
+        assert_eq!(client.stats().ecn_rx.ce, ce);
+    }
+
+    #[test]
+    fn ecn_ce_reduces_cwnd() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.set_ecn(Ecn::Ect0);
+        let cwnd = client.loss_recovery.cwnd();
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0; 100]).unwrap();
+        let out = client.process(None, now()).dgram().unwrap();
+        assert_eq!(out.ecn(), Ecn::Ect0);
+        let frames = server.test_process_input(out.with_ecn(Ecn::Ce), now());
+        assert!(!frames.is_empty());
+        assert_eq!(server.stats().ecn_rx.ce, 1);
+
+        // The CE mark is acknowledged without delay, and the client reacts.
+        let ack = server.process_output(now()).dgram().unwrap();
+        let frames = client.test_process_input(ack, now());
+        assert!(frames.iter().any(|(f, _)| matches!(
+            f,
+            Frame::Ack {
+                ecn_count: Some(EcnCount { ce: 1, .. }),
+                ..
+            }
+        )));
+        assert!(client.loss_recovery.cwnd() < cwnd);
+    }
+
+    #[test]
+    fn metrics() {
+        let registry = Registry::default();
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_metrics(&registry);
+        server.set_metrics(&registry);
+        connect(&mut client, &mut server);
+
+        let snapshot = registry.snapshot();
+        let count = |name| match snapshot.get(name) {
+            Some(Value::Counter(v)) => *v,
+            _ => panic!("no counter {}", name),
+        };
+        let sent = client.stats().packets_tx + server.stats().packets_tx;
+        let received = client.stats().packets_rx + server.stats().packets_rx;
+        assert_eq!(count("neqo_packets_tx_total"), sent);
+        assert_eq!(count("neqo_packets_rx_total"), received);
+        assert_eq!(count("neqo_lost_total"), 0);
+    }
+
     fn exchange_ticket(client: &mut Connection, server: &mut Connection) -> Vec<u8> {
         server.send_ticket(now(), &[]).expect("can send ticket");
         let out = server.process_output(now());
@@ -2575,6 +3862,36 @@ mod tests {
         assert!(server.crypto.tls.info().unwrap().resumed());
     }
 
+    #[test]
+    fn resume_expired() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
+        let mut client = default_client();
+        let later = now() + Duration::from_secs(30 * 24 * 60 * 60);
+        assert_eq!(
+            client.set_resumption_token(later, &token[..]),
+            Err(Error::InvalidResumptionToken)
+        );
+    }
+
+    #[test]
+    fn resume_other_alpn() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server);
+        let mut client = default_client();
+        client.set_alpn(&["other"]).expect("should set ALPN");
+        assert_eq!(
+            client.set_resumption_token(now(), &token[..]),
+            Err(Error::InvalidResumptionToken)
+        );
+    }
+
     #[test]
     fn zero_rtt_negotiate() {
         // Note that the two servers in this test will get different anti-replay filters.
@@ -2720,8 +4037,12 @@ mod tests {
 
         // Client should get a rejection.
         let client_fin = client.process(server_hs.dgram(), now());
-        let recvd_0rtt_reject = |e| e == ConnectionEvent::ZeroRttRejected;
-        assert!(client.events().any(recvd_0rtt_reject));
+        let events = client.events().collect::<Vec<_>>();
+        assert!(events.contains(&ConnectionEvent::ZeroRttRejected));
+        // The server resumed, so the fresh anti-replay context is to blame.
+        assert!(events.contains(&ConnectionEvent::ZeroRttRejectReason(
+            ZeroRttRejectReason::Declined
+        )));
 
         // Server consume client_fin
         let server_ack = server.process(client_fin.dgram(), now());
@@ -2930,6 +4251,72 @@ mod tests {
         assert!(matches!(evts[0], ConnectionEvent::SendStreamWritable{..}));
     }
 
+    #[test]
+    fn segmented_output() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.set_max_segments(4);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0x63; 10_000]).unwrap();
+        let d = client.process_output(now()).dgram().unwrap();
+        let size = d.segment_size().expect("datagrams were coalesced");
+        assert_eq!(d.segments().count(), 4);
+        assert!(d.segments().all(|s| s.len() == size));
+
+        let received = server.stats().packets_rx;
+        server.process_input(d, now());
+        assert_eq!(server.stats().packets_rx, received + 4);
+        let mut buf = [0; 10_000];
+        let (len, _) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert!(len > size * 3);
+    }
+
+    #[test]
+    fn memory_budget_clamp() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        server.set_memory_budget(Some(MemoryBudget::ClampFlowControl(5000)));
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0x62; 10_000]).unwrap();
+        assert!(client.memory_usage().send_buffers >= 10_000);
+        let dgrams = client.process_multiple_output(now(), usize::max_value());
+        assert!(client.memory_usage().sent_packets > 0);
+        server.process_multiple_input(dgrams, now());
+        assert_eq!(server.memory_usage().recv_buffers, 10_000);
+        assert!(server.flow_mgr.borrow().rx_clamped());
+
+        // Reading the data releases the memory, which lifts the clamp.
+        let mut buf = vec![0; 10_000];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (10_000, false)
+        );
+        let _ = server.process_output(now());
+        assert_eq!(server.memory_usage().recv_buffers, 0);
+        assert!(!server.flow_mgr.borrow().rx_clamped());
+    }
+
+    #[test]
+    fn memory_budget_close() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        server.set_memory_budget(Some(MemoryBudget::Close(5000)));
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0x62; 10_000]).unwrap();
+        let dgrams = client.process_multiple_output(now(), usize::max_value());
+        server.process_multiple_input(dgrams, now());
+        assert_error(
+            &server,
+            ConnectionError::Transport(Error::MemoryBudgetExceeded),
+        );
+    }
+
     // Test that we split crypto data if they cannot fit into one packet.
     // To test this we will use a long server certificate.
     #[test]
@@ -2998,6 +4385,171 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn datagram() {
+        let mut client = default_client();
+        let mut server = default_server();
+        for c in &[&client, &server] {
+            c.set_local_tparam(
+                tp_constants::MAX_DATAGRAM_FRAME_SIZE,
+                TransportParameter::Integer(1200),
+            )
+            .unwrap();
+        }
+        connect(&mut client, &mut server);
+
+        client.send_datagram(&[1, 2, 3]).unwrap();
+        let out = client.process(None, now());
+        let frames = server.test_process_input(out.dgram().unwrap(), now());
+        assert!(frames
+            .iter()
+            .any(|(f, _)| matches!(f, Frame::Datagram { .. })));
+        let datagram = |e| matches!(e, ConnectionEvent::Datagram(ref d) if d[..] == [1, 2, 3]);
+        assert!(server.events().any(datagram));
+
+        // Too large for the peer's limit.
+        assert_eq!(client.send_datagram(&[0; 1200]), Err(Error::TooMuchData));
+
+        // Only allowed in 0-RTT and 1-RTT.
+        let frame = Frame::Datagram { data: vec![1] };
+        assert_eq!(
+            server.input_frame(2, frame, now()),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn buffer_pool_reuse() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let allocated = client.buffer_pool_stats().allocated;
+        for _ in 0..10 {
+            client.stream_send(stream_id, &[6; 100]).unwrap();
+            let out = client.process(None, now());
+            let out = server.process(out.dgram(), now());
+            let _ = client.process(out.dgram(), now());
+        }
+        // Once connected, packets are protected using buffers from the pool.
+        assert_eq!(client.buffer_pool_stats().allocated, allocated);
+        assert!(client.buffer_pool_stats().reused > 0);
+    }
+
+    #[test]
+    fn multiple_output_input() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[7; 5000]).unwrap(), 5000);
+        let dgrams = client.process_multiple_output(now(), 10);
+        assert!(dgrams.len() > 1);
+        server.process_multiple_input(dgrams, now());
+
+        let stream_readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable { .. });
+        assert_eq!(server.events().filter(stream_readable).count(), 1);
+        let mut buf = vec![0; 6000];
+        let (received, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(received, 5000);
+        assert!(!fin);
+    }
+
+    #[test]
+    fn multiple_input_events() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let first = client.stream_create(StreamType::UniDi).unwrap();
+        let second = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(first, &[7; 3000]).unwrap(), 3000);
+        assert_eq!(client.stream_send(second, &[8; 3000]).unwrap(), 3000);
+        let dgrams = client.process_multiple_output(now(), 10);
+        assert!(dgrams.len() > 2);
+        server.process_multiple_input(dgrams, now());
+
+        // Each stream is readable once, after both are new.
+        let events = server
+            .events()
+            .filter(|e| {
+                matches!(
+                    e,
+                    ConnectionEvent::NewStream { .. } | ConnectionEvent::RecvStreamReadable { .. }
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEvent::NewStream {
+                    stream_id: first,
+                    stream_type: StreamType::UniDi,
+                },
+                ConnectionEvent::NewStream {
+                    stream_id: second,
+                    stream_type: StreamType::UniDi,
+                },
+                ConnectionEvent::RecvStreamReadable { stream_id: first },
+                ConnectionEvent::RecvStreamReadable { stream_id: second },
+            ]
+        );
+    }
+
+    #[test]
+    fn wakeup_idle_timeout() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let clock = VirtualClock::new(now());
+        client.set_clock(Rc::new(clock.clone()));
+        let mut out = Vec::new();
+        while let Some(delay) = client.wakeup(None, &mut out) {
+            clock.advance(delay);
+        }
+        assert!(matches!(
+            client.state(),
+            State::Closed(ConnectionError::Transport(Error::IdleTimeout))
+        ));
+        assert!(clock.now() >= now() + LOCAL_IDLE_TIMEOUT);
+    }
+
+    #[test]
+    fn parallel_decrypt() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_decrypt_threads(2);
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(client.stream_send(stream_id, &[7; 5000]).unwrap(), 5000);
+        let mut dgrams = client.process_multiple_output(now(), 10);
+        assert!(dgrams.len() > 1);
+        // Processing still follows the order the datagrams arrived in.
+        dgrams.reverse();
+        server.process_multiple_input(dgrams, now());
+
+        let mut buf = vec![0; 6000];
+        let (received, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(received, 5000);
+        assert!(!fin);
+        assert_eq!(server.stats().dups_rx, 0);
+    }
+
+    #[test]
+    fn datagram_not_negotiated() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            client.send_datagram(&[1, 2, 3]),
+            Err(Error::ConnectionState)
+        );
+    }
+
     #[test]
     // If we send a stop_sending to the peer, we should not accept more data from the peer.
     fn do_not_accept_data_after_stop_sending() {
@@ -3570,6 +5122,9 @@ mod tests {
         client.test_process_input(s_tx_dgram, now);
 
         assert_eq!(client.loss_recovery.cwnd(), MIN_CONG_WINDOW);
+        assert!(client.stats().lost > 0);
+        assert_eq!(client.stats().cwnd, MIN_CONG_WINDOW);
+        assert!(client.stats().rtt > Duration::from_secs(0));
     }
 
     #[test]
@@ -6,6 +6,7 @@
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use neqo_common::{hex, qdebug, qinfo, qtrace};
 use neqo_crypto::aead::Aead;
@@ -16,15 +17,28 @@ use neqo_crypto::{
 };
 
 use crate::connection::Role;
-use crate::frame::{Frame, TxMode};
-use crate::packet::{CryptoCtx, PacketNumber};
+use crate::frame::{TxFrame, TxMode};
+use crate::packet::{CryptoCtx, PacketNumber, MASK_LEN, SAMPLE_SIZE};
 use crate::recovery::RecoveryToken;
 use crate::recv_stream::RxStreamOrderer;
 use crate::send_stream::TxBuffer;
 use crate::tparams::{TpZeroRttChecker, TransportParametersHandler};
 use crate::{Error, Res};
 
-const MAX_AUTH_TAG: usize = 32;
+const INITIAL_SALT: &[u8] = &[
+    0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4, 0x63, 0x65,
+    0xbe, 0xf9, 0xf5, 0x02,
+];
+const INITIAL_CIPHER: Cipher = TLS_AES_128_GCM_SHA256;
+
+thread_local! {
+    // The salt for Initial secrets depends only on the version, so it is only
+    // imported once on each thread, rather than for every connection.
+    // `SymKey` isn't `Send` or `Sync` here, unlike `Aead`, so each thread
+    // needs its own.
+    static INITIAL_SALT_KEY: SymKey =
+        hkdf::import_key(TLS_VERSION_1_3, INITIAL_CIPHER, INITIAL_SALT).unwrap();
+}
 
 #[derive(Debug)]
 pub struct Crypto {
@@ -129,7 +143,7 @@ pub enum CryptoDxDirection {
 pub struct CryptoDxState {
     pub(crate) direction: CryptoDxDirection,
     pub(crate) epoch: Epoch,
-    pub(crate) aead: Aead,
+    pub(crate) aead: Arc<Aead>,
     pub(crate) hpkey: HpKey,
 }
 
@@ -149,7 +163,7 @@ impl CryptoDxState {
         CryptoDxState {
             direction,
             epoch,
-            aead: Aead::new(TLS_VERSION_1_3, cipher, secret, "quic ").unwrap(),
+            aead: Arc::new(Aead::new(TLS_VERSION_1_3, cipher, secret, "quic ").unwrap()),
             hpkey: HpKey::extract(TLS_VERSION_1_3, cipher, secret, "quic hp").unwrap(),
         }
     }
@@ -159,24 +173,11 @@ impl CryptoDxState {
         label: &str,
         dcid: &[u8],
     ) -> Option<CryptoDxState> {
-        const INITIAL_SALT: &[u8] = &[
-            0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4,
-            0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
-        ];
-        let cipher = TLS_AES_128_GCM_SHA256;
-        let initial_secret = hkdf::extract(
-            TLS_VERSION_1_3,
-            cipher,
-            Some(
-                hkdf::import_key(TLS_VERSION_1_3, cipher, INITIAL_SALT)
-                    .as_ref()
-                    .unwrap(),
-            ),
-            hkdf::import_key(TLS_VERSION_1_3, cipher, dcid)
-                .as_ref()
-                .unwrap(),
-        )
-        .unwrap();
+        let cipher = INITIAL_CIPHER;
+        let ikm = hkdf::import_key(TLS_VERSION_1_3, cipher, dcid).unwrap();
+        let initial_secret = INITIAL_SALT_KEY
+            .with(|salt| hkdf::extract(TLS_VERSION_1_3, cipher, Some(salt), &ikm))
+            .unwrap();
 
         let secret =
             hkdf::expand_label(TLS_VERSION_1_3, cipher, &initial_secret, &[], label).unwrap();
@@ -192,7 +193,27 @@ impl CryptoCtx for CryptoDxState {
         Ok(mask)
     }
 
-    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+    fn compute_masks(&self, samples: &[u8]) -> Res<Vec<u8>> {
+        let count = samples.len() / SAMPLE_SIZE;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let masks = self.hpkey.mask_batch(samples)?;
+        qdebug!("HP {} samples masks={}", count, hex(&masks));
+        Ok(masks
+            .chunks(masks.len() / count)
+            .flat_map(|m| &m[..MASK_LEN])
+            .copied()
+            .collect())
+    }
+
+    fn aead_decrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        hdr: &[u8],
+        body: &[u8],
+        output: &'a mut [u8],
+    ) -> Res<&'a [u8]> {
         qinfo!(
             [self],
             "aead_decrypt pn={} hdr={} body={}",
@@ -200,12 +221,16 @@ impl CryptoCtx for CryptoDxState {
             hex(hdr),
             hex(body)
         );
-        let mut out = vec![0; body.len()];
-        let res = self.aead.decrypt(pn, hdr, body, &mut out)?;
-        Ok(res.to_vec())
+        Ok(self.aead.decrypt(pn, hdr, body, output)?)
     }
 
-    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+    fn aead_encrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        hdr: &[u8],
+        body: &[u8],
+        output: &'a mut [u8],
+    ) -> Res<&'a [u8]> {
         qdebug!(
             [self],
             "aead_encrypt pn={} hdr={} body={}",
@@ -214,13 +239,11 @@ impl CryptoCtx for CryptoDxState {
             hex(body)
         );
 
-        let size = body.len() + MAX_AUTH_TAG;
-        let mut out = vec![0; size];
-        let res = self.aead.encrypt(pn, hdr, body, &mut out)?;
+        let res = self.aead.encrypt(pn, hdr, body, output)?;
 
         qdebug!([self], "aead_encrypt ct={}", hex(res),);
 
-        Ok(res.to_vec())
+        Ok(res)
     }
 }
 
@@ -242,22 +265,28 @@ pub struct CryptoStates {
 }
 
 impl std::fmt::Display for CryptoStates {
+    /// This lists the epochs that have keys, with `r` for reading and `w` for
+    /// writing.
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "CryptoStates")
+        write!(f, "CryptoStates")?;
+        for (epoch, cs) in self.states.iter().enumerate() {
+            if let Some(cs) = cs {
+                let rx = if cs.rx.is_some() { "r" } else { "" };
+                let tx = if cs.tx.is_some() { "w" } else { "" };
+                write!(f, " {}:{}{}", epoch, rx, tx)?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl CryptoStates {
     // Get a crypto state, making it if necessary, otherwise return an error.
     pub fn obtain(&mut self, role: Role, epoch: Epoch, tls: &Agent) -> Res<&mut CryptoState> {
-        #[cfg(debug_assertions)]
-        let label = format!("{}", self);
-        #[cfg(not(debug_assertions))]
-        let label = "";
-
-        let cs = &mut self.states[epoch as usize];
-        if cs.is_none() {
-            qtrace!([label], "Build crypto state for epoch {}", epoch);
+        // This is called for every packet, so `self` is only formatted by the
+        // q* macros, which only do that when they log.
+        if self.states[epoch as usize].is_none() {
+            qtrace!([self], "Build crypto state for epoch {}", epoch);
             assert!(epoch != 0); // This state is made directly.
 
             let cipher = match (epoch, tls.info()) {
@@ -266,7 +295,7 @@ impl CryptoStates {
                 (_, Some(info)) => Some(info.cipher_suite()),
             }
             .ok_or_else(|| {
-                qdebug!([label], "cipher info not available yet");
+                qdebug!([self], "cipher info not available yet");
                 Error::KeysNotFound
             })?;
 
@@ -283,16 +312,16 @@ impl CryptoStates {
                 | (Some(_), None, Role::Server, 1)
                 | (Some(_), Some(_), _, _) => {}
                 (None, None, _, _) => {
-                    qdebug!([label], "Keying material not available for epoch {}", epoch);
+                    qdebug!([self], "Keying material not available for epoch {}", epoch);
                     return Err(Error::KeysNotFound);
                 }
                 _ => panic!("bad configuration of keys"),
             }
 
-            *cs = Some(CryptoState { rx, tx });
+            self.states[epoch as usize] = Some(CryptoState { rx, tx });
         }
 
-        Ok(cs.as_mut().unwrap())
+        Ok(self.states[epoch as usize].as_mut().unwrap())
     }
 }
 
@@ -344,33 +373,40 @@ impl CryptoStreams {
         self.streams[epoch as usize].tx.next_bytes(mode)
     }
 
+    /// Bytes buffered for sending and for receiving, over all epochs.
+    pub fn buffered(&self) -> (usize, usize) {
+        self.streams.iter().fold((0, 0), |(tx, rx), cs| {
+            (tx + cs.tx.buffered(), rx + cs.rx.buffered() as usize)
+        })
+    }
+
     pub fn get_frame(
         &mut self,
         epoch: u16,
         mode: TxMode,
         remaining: usize,
-    ) -> Option<(Frame, Option<RecoveryToken>)> {
-        if let Some((offset, data)) = self.next_bytes(epoch, mode) {
-            let (frame, length) = Frame::new_crypto(offset, data, remaining);
-            self.sent(epoch, offset, length);
+    ) -> Option<(TxFrame, Option<RecoveryToken>)> {
+        let (offset, length) = {
+            let (offset, data) = self.next_bytes(epoch, mode)?;
+            (offset, TxFrame::new_crypto(offset, data, remaining).1)
+        };
+        self.sent(epoch, offset, length);
 
-            qdebug!(
-                "Emitting crypto frame epoch={}, offset={}, len={}",
+        qdebug!(
+            "Emitting crypto frame epoch={}, offset={}, len={}",
+            epoch,
+            offset,
+            length
+        );
+        let data = self.streams[epoch as usize].tx.bytes(offset, length);
+        Some((
+            TxFrame::Crypto { offset, data },
+            Some(RecoveryToken::Crypto(CryptoRecoveryToken {
                 epoch,
                 offset,
-                length
-            );
-            Some((
-                frame,
-                Some(RecoveryToken::Crypto(CryptoRecoveryToken {
-                    epoch,
-                    offset,
-                    length,
-                })),
-            ))
-        } else {
-            None
-        }
+                length,
+            })),
+        ))
     }
 }
 
@@ -0,0 +1,230 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A pool of threads that decrypt packets, so that a connection that receives
+// packets faster than one core can decrypt them can use more cores.
+
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use neqo_common::{qwarn, Datagram};
+use neqo_crypto::aead::Aead;
+
+use crate::packet::{PacketHdr, PacketNumber};
+
+/// What a `DecryptJob` decrypts with.  This is an `Aead`, except in tests.
+pub trait PacketDecrypt: Send + Sync {
+    fn decrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        aad: &[u8],
+        input: &[u8],
+        output: &'a mut [u8],
+    ) -> neqo_crypto::Res<&'a [u8]>;
+}
+
+impl PacketDecrypt for Aead {
+    fn decrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        aad: &[u8],
+        input: &[u8],
+        output: &'a mut [u8],
+    ) -> neqo_crypto::Res<&'a [u8]> {
+        Aead::decrypt(self, pn, aad, input, output)
+    }
+}
+
+/// A packet that has had header protection removed and is ready to decrypt.
+pub struct DecryptJob {
+    /// Where the datagram was in its batch.
+    pub index: usize,
+    pub aead: Arc<dyn PacketDecrypt>,
+    pub dgram: Datagram,
+    /// The header, which has the packet number.
+    pub hdr: PacketHdr,
+    /// The unprotected header bytes.
+    pub aad: Vec<u8>,
+    /// Where the ciphertext is in `dgram`.
+    pub body: Range<usize>,
+    /// A buffer for the plaintext.
+    pub out: Vec<u8>,
+}
+
+impl DecryptJob {
+    /// A panic while decrypting counts as a failure, so that a result for
+    /// every job reaches `DecryptPool::run`, which waits for them all.
+    fn run(mut self) -> Decrypted {
+        self.out.resize(self.body.len(), 0);
+        // The closure borrows the fields, as it can't borrow all of `self`.
+        let (aead, pn, aad, out) = (&self.aead, self.hdr.pn, &self.aad, &mut self.out);
+        let input = &self.dgram[self.body.clone()];
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            aead.decrypt(pn, aad, input, out).map(<[u8]>::len)
+        }));
+        let body = match res {
+            Ok(Ok(len)) => {
+                self.out.truncate(len);
+                Ok(self.out)
+            }
+            Ok(Err(_)) => Err(self.out),
+            Err(_) => {
+                qwarn!("Decryption of packet {} panicked", self.hdr.pn);
+                Err(self.out)
+            }
+        };
+        Decrypted {
+            index: self.index,
+            dgram: self.dgram,
+            hdr: self.hdr,
+            aad: self.aad,
+            body,
+        }
+    }
+}
+
+/// The outcome of a `DecryptJob`.  If decryption failed, `body` has the
+/// buffer that was meant for the plaintext, so it can be reused.
+pub struct Decrypted {
+    pub index: usize,
+    pub dgram: Datagram,
+    pub hdr: PacketHdr,
+    pub aad: Vec<u8>,
+    pub body: Result<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct DecryptPool {
+    jobs: Option<Sender<DecryptJob>>,
+    results: Receiver<Decrypted>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl DecryptPool {
+    /// Start `threads` threads.
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0);
+        let (jobs, job_rx) = channel::<DecryptJob>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, results) = channel();
+        let threads = (0..threads)
+            .map(|i| {
+                let job_rx = Arc::clone(&job_rx);
+                let result_tx = result_tx.clone();
+                thread::Builder::new()
+                    .name(format!("neqo-decrypt-{}", i))
+                    .spawn(move || loop {
+                        let job = match job_rx.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break, // The pool is gone.
+                        };
+                        if result_tx.send(job.run()).is_err() {
+                            break;
+                        }
+                    })
+                    .expect("unable to start decryption thread")
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            results,
+            threads,
+        }
+    }
+
+    /// Decrypt all of `jobs`.  This waits until they are all done and
+    /// returns the results in the order of `DecryptJob::index`.
+    pub fn run(&self, jobs: Vec<DecryptJob>) -> Vec<Decrypted> {
+        let count = jobs.len();
+        let sender = self.jobs.as_ref().unwrap();
+        for job in jobs {
+            sender.send(job).expect("decryption threads are running");
+        }
+        let mut done = self.results.iter().take(count).collect::<Vec<_>>();
+        done.sort_by_key(|d| d.index);
+        done
+    }
+}
+
+impl Drop for DecryptPool {
+    fn drop(&mut self) {
+        // Closing the channel stops the threads.
+        self.jobs.take();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ConnectionId, PacketType};
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+    /// Copies the input, but panics for packet 1.
+    struct Fragile;
+
+    impl PacketDecrypt for Fragile {
+        fn decrypt<'a>(
+            &self,
+            pn: PacketNumber,
+            _aad: &[u8],
+            input: &[u8],
+            output: &'a mut [u8],
+        ) -> neqo_crypto::Res<&'a [u8]> {
+            assert_ne!(pn, 1, "can't decrypt packet 1");
+            output[..input.len()].copy_from_slice(input);
+            Ok(&output[..input.len()])
+        }
+    }
+
+    fn job(aead: &Arc<dyn PacketDecrypt>, index: u8) -> DecryptJob {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443);
+        let hdr = PacketHdr::new(
+            0,
+            PacketType::Short,
+            None,
+            ConnectionId(Vec::new()),
+            None,
+            index.into(),
+            3,
+        );
+        DecryptJob {
+            index: index.into(),
+            aead: Arc::clone(aead),
+            dgram: Datagram::new(addr, addr, vec![index; 10]),
+            hdr,
+            aad: Vec::new(),
+            body: 2..10,
+            out: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn panic_in_job() {
+        let pool = DecryptPool::new(2);
+        let aead: Arc<dyn PacketDecrypt> = Arc::new(Fragile);
+        let done = pool.run((0..4).map(|i| job(&aead, i)).collect());
+        assert_eq!(4, done.len());
+        for (i, d) in done.iter().enumerate() {
+            assert_eq!(i, d.index);
+            match &d.body {
+                Ok(body) => assert_eq!(&vec![d.dgram[0]; 8], body),
+                Err(_) => assert_eq!(1, i),
+            }
+        }
+        assert!(done[1].body.is_err());
+
+        // Every thread is still there to take the next batch.
+        let done = pool.run((0..4).map(|i| job(&aead, i + 2)).collect());
+        assert_eq!(4, done.len());
+        assert!(done.iter().all(|d| d.body.is_ok()));
+    }
+}
@@ -14,6 +14,13 @@ use neqo_common::{qdebug, Decoder};
 
 #[allow(clippy::module_name_repetitions)]
 pub fn dump_packet(conn: &Connection, dir: &str, hdr: &PacketHdr, payload: &[u8]) {
+    // Decoding the frames is much more work than logging them, so only do
+    // that if the packets are going to be logged.
+    neqo_common::log::init();
+    if !::log::log_enabled!(::log::Level::Debug) {
+        return;
+    }
+
     let mut s = String::from("");
     let mut d = Decoder::from(payload);
     while d.remaining() > 0 {
@@ -0,0 +1,82 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The events of a connection as a `futures::Stream`.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+
+use crate::connection::State;
+use crate::events::{ConnectionEvent, ConnectionEvents};
+
+/// A `Stream` of the events of a `Connection`, from `Connection::event_stream`.
+/// This doesn't drive the connection: whatever does that still has to call
+/// `process`, but it shouldn't take events itself.  The stream ends after the
+/// event for the connection being closed.
+#[derive(Debug)]
+pub struct EventStream {
+    events: ConnectionEvents,
+    done: bool,
+}
+
+impl EventStream {
+    pub(crate) fn new(events: ConnectionEvents) -> Self {
+        Self {
+            events,
+            done: false,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let e = futures::ready!(self.events.poll_event(cx));
+        if let ConnectionEvent::StateChange(State::Closed(_)) = e {
+            self.done = true;
+        }
+        Poll::Ready(Some(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::StreamType;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    #[test]
+    fn wakes_on_insert() {
+        let events = ConnectionEvents::default();
+        let mut stream = EventStream::new(events.clone());
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+
+        events.send_stream_creatable(StreamType::BiDi);
+        assert_eq!(
+            block_on(stream.next()),
+            Some(ConnectionEvent::SendStreamCreatable {
+                stream_type: StreamType::BiDi
+            })
+        );
+    }
+
+    #[test]
+    fn ends_when_closed() {
+        let events = ConnectionEvents::default();
+        let closed = State::Closed(crate::ConnectionError::Application(1));
+        events.connection_state_change(closed.clone());
+        let all = block_on(EventStream::new(events.clone()).collect::<Vec<_>>());
+        assert_eq!(all, vec![ConnectionEvent::StateChange(closed)]);
+    }
+}
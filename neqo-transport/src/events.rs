@@ -7,16 +7,35 @@
 // Collecting a list of events relevant to whoever is using the Connection.
 
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::BTreeSet;
 use std::rc::Rc;
+use std::task::{Context, Poll};
 
-use neqo_common::matches;
+use neqo_common::{matches, EventQueue, QueuedEvent};
 
 use crate::connection::State;
 use crate::frame::StreamType;
 use crate::stream_id::StreamId;
 use crate::AppError;
 
+/// Why the server rejected 0-RTT, as far as the client can tell.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone, Copy)]
+pub enum ZeroRttRejectReason {
+    /// The server didn't accept the resumption token, perhaps because it had
+    /// expired or the server no longer has the key for it.  The handshake was a
+    /// full one.
+    TicketRejected,
+    /// The server chose a different application protocol to the one in the
+    /// resumption token.
+    Alpn,
+    /// The server lowered limits in its transport parameters, so that what the
+    /// client sent in 0-RTT might have exceeded them.
+    TransportParameters,
+    /// The server resumed, but didn't say why it wouldn't take 0-RTT.  It
+    /// might not allow 0-RTT any more, or it might suspect a replay.
+    Declined,
+}
+
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 pub enum ConnectionEvent {
     /// Cert authentication needed
@@ -44,12 +63,41 @@ pub enum ConnectionEvent {
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// Why 0-RTT was rejected.  This follows `ZeroRttRejected`, so that an
+    /// application can decide whether to try 0-RTT again.
+    ZeroRttRejectReason(ZeroRttRejectReason),
+    /// The server didn't accept the resumption token, so the handshake was a
+    /// full one.  The token shouldn't be used again.
+    ResumptionRejected,
+    /// The peer sent a DATAGRAM frame.
+    Datagram(Vec<u8>),
+}
+
+impl QueuedEvent for ConnectionEvent {
+    fn coalesces_with(&self, queued: &Self) -> bool {
+        // Special-case two enums that are not strictly PartialEq equal but that
+        // we wish to avoid inserting duplicates.
+        match self {
+            ConnectionEvent::SendStreamStopSending { stream_id, .. } => matches!(
+                queued, ConnectionEvent::SendStreamStopSending { stream_id: x, .. }
+                if x == stream_id),
+            ConnectionEvent::RecvStreamReset { stream_id, .. } => matches!(
+                queued, ConnectionEvent::RecvStreamReset { stream_id: x, .. }
+                if x == stream_id),
+            // Every datagram is delivered, even if it repeats an earlier one.
+            ConnectionEvent::Datagram(_) => false,
+            _ => self == queued,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ConnectionEvents {
-    events: Rc<RefCell<VecDeque<ConnectionEvent>>>,
+    events: EventQueue<ConnectionEvent>,
+    /// While a batch is in progress, the streams that became readable or
+    /// writable in it.
+    held: Rc<RefCell<Option<BTreeSet<ConnectionEvent>>>>,
 }
 
 impl ConnectionEvents {
@@ -65,13 +113,16 @@ impl ConnectionEvents {
     }
 
     pub fn recv_stream_readable(&self, stream_id: StreamId) {
-        self.insert(ConnectionEvent::RecvStreamReadable {
+        self.insert_or_hold(ConnectionEvent::RecvStreamReadable {
             stream_id: stream_id.as_u64(),
         });
     }
 
     pub fn recv_stream_reset(&self, stream_id: StreamId, app_error: AppError) {
         // If reset, no longer readable.
+        self.unhold(&ConnectionEvent::RecvStreamReadable {
+            stream_id: stream_id.as_u64(),
+        });
         self.remove(|evt| matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x } if *x == stream_id.as_u64()));
 
         self.insert(ConnectionEvent::RecvStreamReset {
@@ -81,13 +132,16 @@ impl ConnectionEvents {
     }
 
     pub fn send_stream_writable(&self, stream_id: StreamId) {
-        self.insert(ConnectionEvent::SendStreamWritable {
+        self.insert_or_hold(ConnectionEvent::SendStreamWritable {
             stream_id: stream_id.as_u64(),
         });
     }
 
     pub fn send_stream_stop_sending(&self, stream_id: StreamId, app_error: AppError) {
         // If stopped, no longer writable.
+        self.unhold(&ConnectionEvent::SendStreamWritable {
+            stream_id: stream_id.as_u64(),
+        });
         self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamWritable { stream_id: x } if *x == stream_id.as_u64()));
 
         self.insert(ConnectionEvent::SendStreamStopSending {
@@ -97,6 +151,9 @@ impl ConnectionEvents {
     }
 
     pub fn send_stream_complete(&self, stream_id: StreamId) {
+        self.unhold(&ConnectionEvent::SendStreamWritable {
+            stream_id: stream_id.as_u64(),
+        });
         self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamWritable { stream_id: x } if *x == stream_id.as_u64()));
 
         self.remove(|evt| matches!(evt, ConnectionEvent::SendStreamStopSending { stream_id: x, .. } if *x == stream_id.as_u64()));
@@ -113,7 +170,7 @@ impl ConnectionEvents {
     pub fn connection_state_change(&self, state: State) {
         // If closing, existing events no longer relevant.
         match state {
-            State::Closing { .. } | State::Closed(_) => self.events.borrow_mut().clear(),
+            State::Closing { .. } | State::Closed(_) => self.clear(),
             _ => (),
         }
         self.insert(ConnectionEvent::StateChange(state));
@@ -122,53 +179,91 @@ impl ConnectionEvents {
     pub fn client_0rtt_rejected(&self) {
         // If 0rtt rejected, must start over and existing events are no longer
         // relevant.
-        self.events.borrow_mut().clear();
+        self.clear();
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
+    pub fn zero_rtt_reject_reason(&self, reason: ZeroRttRejectReason) {
+        self.insert(ConnectionEvent::ZeroRttRejectReason(reason));
+    }
+
+    pub fn resumption_rejected(&self) {
+        self.insert(ConnectionEvent::ResumptionRejected);
+    }
+
+    pub fn datagram(&self, data: Vec<u8>) {
+        self.insert(ConnectionEvent::Datagram(data));
+    }
+
+    /// Start a batch.  Until `end_batch`, streams that become readable or
+    /// writable are only noted, and each gets one event when the batch ends.
+    pub fn start_batch(&self) {
+        self.held.replace(Some(BTreeSet::new()));
+    }
+
+    /// Add the events for streams that became readable or writable during
+    /// the batch.
+    pub fn end_batch(&self) {
+        if let Some(held) = self.held.replace(None) {
+            for event in held {
+                self.insert(event);
+            }
+        }
+    }
+
     pub fn events(&self) -> impl Iterator<Item = ConnectionEvent> {
-        self.events.replace(VecDeque::new()).into_iter()
+        self.events.events()
     }
 
     pub fn has_events(&self) -> bool {
-        !self.events.borrow().is_empty()
+        self.events.has_events()
+    }
+
+    /// The number of events that haven't been taken.
+    pub fn pending(&self) -> usize {
+        self.events.pending()
     }
 
     pub fn next_event(&self) -> Option<ConnectionEvent> {
-        self.events.borrow_mut().pop_front()
+        self.events.next_event()
+    }
+
+    /// Take the next event, or arrange for the task to be woken when there
+    /// is one.  Only the task that called last is woken.
+    pub fn poll_event(&self, cx: &mut Context) -> Poll<ConnectionEvent> {
+        self.events.poll_event(cx)
     }
 
-    #[allow(clippy::block_in_if_condition_stmt)]
     fn insert(&self, event: ConnectionEvent) {
-        let mut q = self.events.borrow_mut();
+        self.events.insert(event);
+    }
 
-        // Special-case two enums that are not strictly PartialEq equal but that
-        // we wish to avoid inserting duplicates.
-        if match &event {
-            ConnectionEvent::SendStreamStopSending { stream_id, .. } => q.iter().any(|evt| {
-                matches!(
-		    evt, ConnectionEvent::SendStreamStopSending { stream_id: x, .. }
-		    if *x == *stream_id)
-            }),
-            ConnectionEvent::RecvStreamReset { stream_id, .. } => q.iter().any(|evt| {
-                matches!(
-		    evt, ConnectionEvent::RecvStreamReset { stream_id: x, .. }
-		    if *x == *stream_id)
-            }),
-            _ => q.contains(&event),
-        } {
-            // Already in event list.
+    fn insert_or_hold(&self, event: ConnectionEvent) {
+        if let Some(held) = self.held.borrow_mut().as_mut() {
+            held.insert(event);
             return;
         }
+        self.insert(event);
+    }
+
+    fn unhold(&self, event: &ConnectionEvent) {
+        if let Some(held) = self.held.borrow_mut().as_mut() {
+            held.remove(event);
+        }
+    }
 
-        q.push_back(event);
+    fn clear(&self) {
+        self.events.clear();
+        if let Some(held) = self.held.borrow_mut().as_mut() {
+            held.clear();
+        }
     }
 
     fn remove<F>(&self, f: F)
     where
         F: Fn(&ConnectionEvent) -> bool,
     {
-        self.events.borrow_mut().retain(|evt| !f(evt))
+        self.events.remove(f)
     }
 }
 
@@ -231,6 +326,10 @@ mod tests {
         evts.client_0rtt_rejected();
         assert_eq!(evts.events().count(), 1);
 
+        evts.datagram(vec![1, 2, 3]);
+        evts.datagram(vec![1, 2, 3]);
+        assert_eq!(evts.events().count(), 2);
+
         evts.send_stream_writable(9.into());
         evts.send_stream_stop_sending(10.into(), 55);
         evts.connection_state_change(State::Closed(ConnectionError::Transport(
@@ -238,4 +337,40 @@ mod tests {
         )));
         assert_eq!(evts.events().count(), 1);
     }
+
+    #[test]
+    fn batch() {
+        let evts = ConnectionEvents::default();
+
+        evts.start_batch();
+        evts.recv_stream_readable(4.into());
+        evts.new_stream(8.into());
+        evts.recv_stream_readable(8.into());
+        evts.recv_stream_readable(4.into());
+        evts.recv_stream_readable(12.into());
+        evts.recv_stream_reset(12.into(), 1);
+        evts.send_stream_writable(2.into());
+        evts.send_stream_writable(2.into());
+        evts.send_stream_writable(6.into());
+        evts.send_stream_complete(6.into());
+        // Readable and writable events wait for the end of the batch.
+        let events = evts.events().collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert!(!evts.has_events());
+
+        evts.end_batch();
+        let events = evts.events().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEvent::SendStreamWritable { stream_id: 2 },
+                ConnectionEvent::RecvStreamReadable { stream_id: 4 },
+                ConnectionEvent::RecvStreamReadable { stream_id: 8 },
+            ]
+        );
+
+        // Without a batch, events are added straight away.
+        evts.recv_stream_readable(4.into());
+        assert_eq!(evts.events().count(), 1);
+    }
 }
@@ -39,6 +39,9 @@ pub struct FlowMgr {
     max_data: u64,
 
     need_close_frame: bool,
+
+    // Set when no more receive credit should be given out.
+    rx_clamped: bool,
 }
 
 impl FlowMgr {
@@ -67,6 +70,15 @@ impl FlowMgr {
         }
     }
 
+    /// Whether receive streams are holding back flow control credit.
+    pub fn rx_clamped(&self) -> bool {
+        self.rx_clamped
+    }
+
+    pub fn set_rx_clamped(&mut self, clamped: bool) {
+        self.rx_clamped = clamped;
+    }
+
     // -- frames scoped on connection --
 
     pub fn data_blocked(&mut self) {
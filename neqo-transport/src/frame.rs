@@ -9,10 +9,12 @@
 use neqo_common::{matches, qdebug, Decoder, Encoder};
 use neqo_crypto::Epoch;
 
+use crate::stats::EcnCount;
 use crate::stream_id::{StreamId, StreamIndex};
 use crate::{AppError, TransportError};
 use crate::{ConnectionError, Error, Res};
 
+use smallvec::SmallVec;
 use std::cmp::{min, Ordering};
 use std::convert::TryFrom;
 
@@ -43,6 +45,8 @@ const FRAME_TYPE_PATH_CHALLENGE: FrameType = 0x1a;
 const FRAME_TYPE_PATH_RESPONSE: FrameType = 0x1b;
 const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
 const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
+const FRAME_TYPE_DATAGRAM: FrameType = 0x30;
+const FRAME_TYPE_DATAGRAM_WITH_LEN: FrameType = 0x31;
 
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
@@ -72,6 +76,7 @@ impl StreamType {
 }
 
 #[derive(PartialEq, Eq, Debug, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CloseError {
     Transport(TransportError),
     Application(AppError),
@@ -115,6 +120,10 @@ pub struct AckRange {
     pub(crate) range: u64,
 }
 
+/// The ranges of an ACK frame.  Most frames only have a few of these, so
+/// they are kept inline.
+pub type AckRanges = SmallVec<[AckRange; 8]>;
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum Frame {
     Padding,
@@ -123,7 +132,9 @@ pub enum Frame {
         largest_acknowledged: u64,
         ack_delay: u64,
         first_ack_range: u64,
-        ack_ranges: Vec<AckRange>,
+        ack_ranges: AckRanges,
+        /// Present in an ACK_ECN frame.
+        ecn_count: Option<EcnCount>,
     },
     ResetStream {
         stream_id: StreamId,
@@ -190,6 +201,9 @@ pub enum Frame {
         frame_type: u64,
         reason_phrase: Vec<u8>,
     },
+    Datagram {
+        data: Vec<u8>,
+    },
 }
 
 impl Frame {
@@ -197,26 +211,17 @@ impl Frame {
         match self {
             Frame::Padding => FRAME_TYPE_PADDING,
             Frame::Ping => FRAME_TYPE_PING,
-            Frame::Ack { .. } => FRAME_TYPE_ACK, // We don't do ACK ECN.
+            Frame::Ack {
+                ecn_count: None, ..
+            } => FRAME_TYPE_ACK,
+            Frame::Ack { .. } => FRAME_TYPE_ACK_ECN,
             Frame::ResetStream { .. } => FRAME_TYPE_RST_STREAM,
             Frame::StopSending { .. } => FRAME_TYPE_STOP_SENDING,
             Frame::Crypto { .. } => FRAME_TYPE_CRYPTO,
             Frame::NewToken { .. } => FRAME_TYPE_NEW_TOKEN,
             Frame::Stream {
                 fin, offset, fill, ..
-            } => {
-                let mut t = FRAME_TYPE_STREAM;
-                if *fin {
-                    t |= STREAM_FRAME_BIT_FIN;
-                }
-                if *offset > 0 {
-                    t |= STREAM_FRAME_BIT_OFF;
-                }
-                if !*fill {
-                    t |= STREAM_FRAME_BIT_LEN;
-                }
-                t
-            }
+            } => stream_frame_type(*fin, *offset, *fill),
             Frame::MaxData { .. } => FRAME_TYPE_MAX_DATA,
             Frame::MaxStreamData { .. } => FRAME_TYPE_MAX_STREAM_DATA,
             Frame::MaxStreams { stream_type, .. } => {
@@ -234,92 +239,11 @@ impl Frame {
             Frame::ConnectionClose { error_code, .. } => {
                 FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT + error_code.frame_type_bit()
             }
+            // We always include a length, so that DATAGRAM can be followed by other frames.
+            Frame::Datagram { .. } => FRAME_TYPE_DATAGRAM_WITH_LEN,
         }
     }
 
-    /// Create a CRYPTO frame that fits the available space and its length.
-    pub fn new_crypto(offset: u64, data: &[u8], space: usize) -> (Frame, usize) {
-        // Subtract the frame type and offset from available space.
-        let mut remaining = space - 1 - Encoder::varint_len(offset);
-        // Then subtract space for the length field.
-        let data_len = min(remaining - 1, data.len());
-        remaining -= Encoder::varint_len(u64::try_from(data_len).unwrap());
-        remaining = min(data.len(), remaining);
-        (
-            Frame::Crypto {
-                offset,
-                data: data[..remaining].to_vec(),
-            },
-            remaining,
-        )
-    }
-
-    /// Create a STREAM frame that fits the available space.
-    /// Return a tuple of a frame and the amount of data it carries.
-    pub fn new_stream(
-        stream_id: u64,
-        offset: u64,
-        data: &[u8],
-        fin: bool,
-        space: usize,
-    ) -> Option<(Frame, usize)> {
-        let mut overhead = 1 + Encoder::varint_len(stream_id);
-        if offset > 0 {
-            overhead += Encoder::varint_len(offset);
-        }
-
-        let (fin, fill) = match (data.len() + overhead).cmp(&space) {
-            // More data than fits, fill the packet and negate |fin|.
-            Ordering::Greater => (false, true),
-            // Exact fit, fill the packet, keep |fin|.
-            Ordering::Equal => (fin, true),
-            // Too small, so include a length.
-            Ordering::Less => {
-                let data_len = min(space.saturating_sub(overhead + 1), data.len());
-                overhead += Encoder::varint_len(u64::try_from(data_len).unwrap());
-
-                // If all data isn't going to make it in the frame, don't keep fin.
-                let keep_fin = data.len() + overhead <= space;
-                (fin && keep_fin, false)
-            }
-        };
-
-        if overhead > space {
-            qdebug!(
-                "Frame::new_stream -> None; ovr {} > space {}",
-                overhead,
-                space
-            );
-            return None;
-        }
-
-        let data_len = min(data.len(), space - overhead);
-        if data_len == 0 && !fin {
-            qdebug!("Frame::new_stream -> None; no data, no fin");
-            return None;
-        }
-
-        qdebug!(
-            "Frame::new_stream fill {} fin {} data {} space {} ovr {}",
-            fill,
-            fin,
-            data_len,
-            space,
-            overhead
-        );
-
-        Some((
-            Frame::Stream {
-                stream_id: stream_id.into(),
-                offset,
-                data: data[..data_len].to_vec(),
-                fin,
-                fill,
-            },
-            data_len,
-        ))
-    }
-
     pub fn marshal(&self, enc: &mut Encoder) {
         enc.encode_varint(self.get_type());
 
@@ -330,6 +254,7 @@ impl Frame {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
+                ecn_count,
             } => {
                 enc.encode_varint(*largest_acknowledged);
                 enc.encode_varint(*ack_delay);
@@ -339,6 +264,11 @@ impl Frame {
                     enc.encode_varint(r.gap);
                     enc.encode_varint(r.range);
                 }
+                if let Some(c) = ecn_count {
+                    enc.encode_varint(c.ect0);
+                    enc.encode_varint(c.ect1);
+                    enc.encode_varint(c.ce);
+                }
             }
             Frame::ResetStream {
                 stream_id,
@@ -358,7 +288,7 @@ impl Frame {
             }
             Frame::Crypto { offset, data } => {
                 enc.encode_varint(*offset);
-                enc.encode_vvec(&data);
+                enc.encode_vvec(data);
             }
             Frame::NewToken { token } => {
                 enc.encode_vvec(token);
@@ -369,17 +299,7 @@ impl Frame {
                 data,
                 fill,
                 ..
-            } => {
-                enc.encode_varint(stream_id.as_u64());
-                if *offset > 0 {
-                    enc.encode_varint(*offset);
-                }
-                if *fill {
-                    enc.encode(&data);
-                } else {
-                    enc.encode_vvec(&data);
-                }
-            }
+            } => encode_stream(enc, *stream_id, *offset, data, *fill),
             Frame::MaxData { maximum_data } => {
                 enc.encode_varint(*maximum_data);
             }
@@ -438,6 +358,9 @@ impl Frame {
                 enc.encode_varint(*frame_type);
                 enc.encode_vvec(reason_phrase);
             }
+            Frame::Datagram { data } => {
+                enc.encode_vvec(data);
+            }
         }
     }
 
@@ -451,7 +374,7 @@ impl Frame {
     pub fn decode_ack_frame(
         largest_acked: u64,
         first_ack_range: u64,
-        ack_ranges: Vec<AckRange>,
+        ack_ranges: &[AckRange],
     ) -> Res<Vec<(u64, u64)>> {
         let mut acked_ranges = Vec::new();
 
@@ -509,6 +432,7 @@ impl Frame {
                 data.len(),
                 fin,
             )),
+            Frame::Datagram { data } => Some(format!("Datagram {{ len: {} }}", data.len())),
             Frame::Padding => None,
             _ => Some(format!("{:?}", self)),
         }
@@ -523,92 +447,249 @@ impl Frame {
             epoch != 1
         } else if matches!(self, Frame::NewToken {..} | Frame::ConnectionClose {..}) {
             epoch >= 3
+        } else if matches!(self, Frame::Datagram { .. }) {
+            // Only in 0-RTT and 1-RTT packets.
+            epoch == 1 || epoch >= 3
         } else {
             epoch == 1 || epoch >= 3 // Application data
         }
     }
 }
 
-#[allow(clippy::module_name_repetitions)]
-pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
-    macro_rules! d {
-        ($d:expr) => {
-            match $d {
-                Some(v) => v,
-                _ => return Err(Error::NoMoreData),
+fn stream_frame_type(fin: bool, offset: u64, fill: bool) -> FrameType {
+    let mut t = FRAME_TYPE_STREAM;
+    if fin {
+        t |= STREAM_FRAME_BIT_FIN;
+    }
+    if offset > 0 {
+        t |= STREAM_FRAME_BIT_OFF;
+    }
+    if !fill {
+        t |= STREAM_FRAME_BIT_LEN;
+    }
+    t
+}
+
+/// Encode the body of a STREAM frame, everything after the type.
+fn encode_stream(enc: &mut Encoder, stream_id: StreamId, offset: u64, data: &[u8], fill: bool) {
+    enc.encode_varint(stream_id.as_u64());
+    if offset > 0 {
+        enc.encode_varint(offset);
+    }
+    if fill {
+        enc.encode(data);
+    } else {
+        enc.encode_vvec(data);
+    }
+}
+
+/// A frame that is being written into a packet.  CRYPTO and STREAM frames
+/// refer to the data in the send buffer rather than holding a copy, so that
+/// building a packet doesn't allocate.  Their encoding is the same as the
+/// equivalent `Frame`.
+#[derive(PartialEq, Debug)]
+pub enum TxFrame<'a> {
+    Crypto {
+        offset: u64,
+        data: &'a [u8],
+    },
+    Stream {
+        fin: bool,
+        stream_id: StreamId,
+        offset: u64,
+        data: &'a [u8],
+        fill: bool,
+    },
+    Frame(Frame),
+}
+
+impl<'a> TxFrame<'a> {
+    /// Create a CRYPTO frame that fits the available space and its length.
+    pub fn new_crypto(offset: u64, data: &'a [u8], space: usize) -> (Self, usize) {
+        // Subtract the frame type and offset from available space.
+        let mut remaining = space - 1 - Encoder::varint_len(offset);
+        // Then subtract space for the length field.
+        let data_len = min(remaining - 1, data.len());
+        remaining -= Encoder::varint_len(u64::try_from(data_len).unwrap());
+        remaining = min(data.len(), remaining);
+        (
+            TxFrame::Crypto {
+                offset,
+                data: &data[..remaining],
+            },
+            remaining,
+        )
+    }
+
+    /// Create a STREAM frame that fits the available space.
+    /// Return a tuple of a frame and the amount of data it carries.
+    pub fn new_stream(
+        stream_id: u64,
+        offset: u64,
+        data: &'a [u8],
+        fin: bool,
+        space: usize,
+    ) -> Option<(Self, usize)> {
+        let mut overhead = 1 + Encoder::varint_len(stream_id);
+        if offset > 0 {
+            overhead += Encoder::varint_len(offset);
+        }
+
+        let (fin, fill) = match (data.len() + overhead).cmp(&space) {
+            // More data than fits, fill the packet and negate |fin|.
+            Ordering::Greater => (false, true),
+            // Exact fit, fill the packet, keep |fin|.
+            Ordering::Equal => (fin, true),
+            // Too small, so include a length.
+            Ordering::Less => {
+                let data_len = min(space.saturating_sub(overhead + 1), data.len());
+                overhead += Encoder::varint_len(u64::try_from(data_len).unwrap());
+
+                // If all data isn't going to make it in the frame, don't keep fin.
+                let keep_fin = data.len() + overhead <= space;
+                (fin && keep_fin, false)
             }
         };
+
+        if overhead > space {
+            qdebug!(
+                "TxFrame::new_stream -> None; ovr {} > space {}",
+                overhead,
+                space
+            );
+            return None;
+        }
+
+        let data_len = min(data.len(), space - overhead);
+        if data_len == 0 && !fin {
+            qdebug!("TxFrame::new_stream -> None; no data, no fin");
+            return None;
+        }
+
+        qdebug!(
+            "TxFrame::new_stream fill {} fin {} data {} space {} ovr {}",
+            fill,
+            fin,
+            data_len,
+            space,
+            overhead
+        );
+
+        Some((
+            TxFrame::Stream {
+                stream_id: stream_id.into(),
+                offset,
+                data: &data[..data_len],
+                fin,
+                fill,
+            },
+            data_len,
+        ))
     }
-    macro_rules! dv {
-        ($d:expr) => {
-            d!($d.decode_varint())
-        };
+
+    pub fn ack_eliciting(&self) -> bool {
+        match self {
+            TxFrame::Frame(f) => f.ack_eliciting(),
+            _ => true,
+        }
     }
 
+    pub fn marshal(&self, enc: &mut Encoder) {
+        match self {
+            TxFrame::Crypto { offset, data } => {
+                enc.encode_varint(FRAME_TYPE_CRYPTO);
+                enc.encode_varint(*offset);
+                enc.encode_vvec(data);
+            }
+            TxFrame::Stream {
+                fin,
+                stream_id,
+                offset,
+                data,
+                fill,
+            } => {
+                enc.encode_varint(stream_frame_type(*fin, *offset, *fill));
+                encode_stream(enc, *stream_id, *offset, data, *fill);
+            }
+            TxFrame::Frame(f) => f.marshal(enc),
+        }
+    }
+}
+
+impl From<Frame> for TxFrame<'_> {
+    fn from(f: Frame) -> Self {
+        TxFrame::Frame(f)
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
     // TODO(ekr@rtfm.com): check for minimal encoding
-    let t = d!(dec.decode_varint());
+    let t = dec.try_decode_varint()?;
     qdebug!("Frame type byte={:0x}", t);
     match t {
         FRAME_TYPE_PADDING => Ok(Frame::Padding),
         FRAME_TYPE_PING => Ok(Frame::Ping),
         FRAME_TYPE_RST_STREAM => Ok(Frame::ResetStream {
-            stream_id: dv!(dec).into(),
-            application_error_code: d!(dec.decode_varint()),
-            final_size: match dec.decode_varint() {
-                Some(v) => v,
-                _ => return Err(Error::NoMoreData),
-            },
+            stream_id: dec.try_decode_varint()?.into(),
+            application_error_code: dec.try_decode_varint()?,
+            final_size: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_ACK | FRAME_TYPE_ACK_ECN => {
-            let la = dv!(dec);
-            let ad = dv!(dec);
-            let nr = dv!(dec);
-            let fa = dv!(dec);
-            let mut arr: Vec<AckRange> = Vec::with_capacity(nr as usize);
+            let la = dec.try_decode_varint()?;
+            let ad = dec.try_decode_varint()?;
+            let nr = dec.try_decode_varint()?;
+            let fa = dec.try_decode_varint()?;
+            let mut arr = AckRanges::with_capacity(nr as usize);
             for _ in 0..nr {
                 let ar = AckRange {
-                    gap: dv!(dec),
-                    range: dv!(dec),
+                    gap: dec.try_decode_varint()?,
+                    range: dec.try_decode_varint()?,
                 };
                 arr.push(ar);
             }
 
             // Now check for the values for ACK_ECN.
-            if t == FRAME_TYPE_ACK_ECN {
-                dv!(dec);
-                dv!(dec);
-                dv!(dec);
-            }
+            let ecn_count = if t == FRAME_TYPE_ACK_ECN {
+                Some(EcnCount {
+                    ect0: dec.try_decode_varint()?,
+                    ect1: dec.try_decode_varint()?,
+                    ce: dec.try_decode_varint()?,
+                })
+            } else {
+                None
+            };
 
             Ok(Frame::Ack {
                 largest_acknowledged: la,
                 ack_delay: ad,
                 first_ack_range: fa,
                 ack_ranges: arr,
+                ecn_count,
             })
         }
         FRAME_TYPE_STOP_SENDING => Ok(Frame::StopSending {
-            stream_id: dv!(dec).into(),
-            application_error_code: d!(dec.decode_varint()),
+            stream_id: dec.try_decode_varint()?.into(),
+            application_error_code: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_CRYPTO => {
-            let o = dv!(dec);
+            let o = dec.try_decode_varint()?;
             Ok(Frame::Crypto {
                 offset: o,
-                data: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
+                data: dec.try_decode_vvec()?.to_vec(), // TODO(mt) unnecessary copy
             })
         }
         FRAME_TYPE_NEW_TOKEN => {
             Ok(Frame::NewToken {
-                token: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
+                token: dec.try_decode_vvec()?.to_vec(), // TODO(mt) unnecessary copy
             })
         }
         FRAME_TYPE_STREAM..=FRAME_TYPE_STREAM_MAX => {
-            let s = dv!(dec);
+            let s = dec.try_decode_varint()?;
             let o = if t & STREAM_FRAME_BIT_OFF == 0 {
                 0
             } else {
-                dv!(dec)
+                dec.try_decode_varint()?
             };
             qdebug!("STREAM {}", t);
             let fill = (t & STREAM_FRAME_BIT_LEN) == 0;
@@ -617,7 +698,7 @@ pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
                 dec.decode_remainder()
             } else {
                 qdebug!("STREAM frame has a length");
-                d!(dec.decode_vvec())
+                dec.try_decode_vvec()?
             };
             Ok(Frame::Stream {
                 fin: (t & STREAM_FRAME_BIT_FIN) != 0,
@@ -628,35 +709,35 @@ pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
             })
         }
         FRAME_TYPE_MAX_DATA => Ok(Frame::MaxData {
-            maximum_data: dv!(dec),
+            maximum_data: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_MAX_STREAM_DATA => Ok(Frame::MaxStreamData {
-            stream_id: dv!(dec).into(),
-            maximum_stream_data: dv!(dec),
+            stream_id: dec.try_decode_varint()?.into(),
+            maximum_stream_data: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_MAX_STREAMS_BIDI | FRAME_TYPE_MAX_STREAMS_UNIDI => Ok(Frame::MaxStreams {
             stream_type: StreamType::from_type_bit(t),
-            maximum_streams: StreamIndex::new(dv!(dec)),
+            maximum_streams: StreamIndex::new(dec.try_decode_varint()?),
         }),
 
         FRAME_TYPE_DATA_BLOCKED => Ok(Frame::DataBlocked {
-            data_limit: dv!(dec),
+            data_limit: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_STREAM_DATA_BLOCKED => Ok(Frame::StreamDataBlocked {
-            stream_id: dv!(dec).into(),
-            stream_data_limit: dv!(dec),
+            stream_id: dec.try_decode_varint()?.into(),
+            stream_data_limit: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_STREAMS_BLOCKED_BIDI | FRAME_TYPE_STREAMS_BLOCKED_UNIDI => {
             Ok(Frame::StreamsBlocked {
                 stream_type: StreamType::from_type_bit(t),
-                stream_limit: StreamIndex::new(dv!(dec)),
+                stream_limit: StreamIndex::new(dec.try_decode_varint()?),
             })
         }
         FRAME_TYPE_NEW_CONNECTION_ID => {
-            let s = dv!(dec);
-            let retire_prior = dv!(dec);
-            let cid = d!(dec.decode_vec(1)).to_vec(); // TODO(mt) unnecessary copy
-            let srt = d!(dec.decode(16));
+            let s = dec.try_decode_varint()?;
+            let retire_prior = dec.try_decode_varint()?;
+            let cid = dec.try_decode_vec(1)?.to_vec(); // TODO(mt) unnecessary copy
+            let srt = dec.try_decode(16)?;
             let mut srtv: [u8; 16] = [0; 16];
             srtv.copy_from_slice(&srt);
 
@@ -668,25 +749,35 @@ pub fn decode_frame(dec: &mut Decoder) -> Res<Frame> {
             })
         }
         FRAME_TYPE_RETIRE_CONNECTION_ID => Ok(Frame::RetireConnectionId {
-            sequence_number: dv!(dec),
+            sequence_number: dec.try_decode_varint()?,
         }),
         FRAME_TYPE_PATH_CHALLENGE => {
-            let data = d!(dec.decode(8));
+            let data = dec.try_decode(8)?;
             let mut datav: [u8; 8] = [0; 8];
             datav.copy_from_slice(&data);
             Ok(Frame::PathChallenge { data: datav })
         }
         FRAME_TYPE_PATH_RESPONSE => {
-            let data = d!(dec.decode(8));
+            let data = dec.try_decode(8)?;
             let mut datav: [u8; 8] = [0; 8];
             datav.copy_from_slice(&data);
             Ok(Frame::PathResponse { data: datav })
         }
         FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT | FRAME_TYPE_CONNECTION_CLOSE_APPLICATION => {
             Ok(Frame::ConnectionClose {
-                error_code: CloseError::from_type_bit(t, d!(dec.decode_varint())),
-                frame_type: dv!(dec),
-                reason_phrase: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
+                error_code: CloseError::from_type_bit(t, dec.try_decode_varint()?),
+                frame_type: dec.try_decode_varint()?,
+                reason_phrase: dec.try_decode_vvec()?.to_vec(), // TODO(mt) unnecessary copy
+            })
+        }
+        FRAME_TYPE_DATAGRAM | FRAME_TYPE_DATAGRAM_WITH_LEN => {
+            let data = if t == FRAME_TYPE_DATAGRAM {
+                dec.decode_remainder()
+            } else {
+                dec.try_decode_vvec()?
+            };
+            Ok(Frame::Datagram {
+                data: data.to_vec(), // TODO(mt) unnecessary copy
             })
         }
         _ => Err(Error::UnknownFrameType),
@@ -703,6 +794,7 @@ pub enum TxMode {
 mod tests {
     use super::*;
     use neqo_common::hex;
+    use smallvec::smallvec;
 
     fn enc_dec(f: &Frame, s: &str) {
         let mut d = Encoder::default();
@@ -728,13 +820,14 @@ mod tests {
 
     #[test]
     fn test_ack() {
-        let ar = vec![AckRange { gap: 1, range: 2 }, AckRange { gap: 3, range: 4 }];
+        let ar = smallvec![AckRange { gap: 1, range: 2 }, AckRange { gap: 3, range: 4 }];
 
         let f = Frame::Ack {
             largest_acknowledged: 0x1234,
             ack_delay: 0x1235,
             first_ack_range: 0x1236,
-            ack_ranges: ar,
+            ack_ranges: ar.clone(),
+            ecn_count: None,
         };
 
         enc_dec(&f, "025234523502523601020304");
@@ -744,10 +837,19 @@ mod tests {
         let mut dec = enc.as_decoder();
         assert_eq!(decode_frame(&mut dec).unwrap_err(), Error::NoMoreData);
 
-        // Try to parse ACK_ECN without ECN values
-        let enc = Encoder::from_hex("035234523502523601020304010203");
-        let mut dec = enc.as_decoder();
-        assert_eq!(decode_frame(&mut dec).unwrap(), f);
+        // Parse ACK_ECN with ECN values
+        let f = Frame::Ack {
+            largest_acknowledged: 0x1234,
+            ack_delay: 0x1235,
+            first_ack_range: 0x1236,
+            ack_ranges: ar,
+            ecn_count: Some(EcnCount {
+                ect0: 1,
+                ect1: 2,
+                ce: 3,
+            }),
+        };
+        enc_dec(&f, "035234523502523601020304010203");
     }
 
     #[test]
@@ -822,6 +924,17 @@ mod tests {
             fill: true,
         };
         enc_dec(&f, "0905010203");
+
+        // A length that is cut short, then one longer than what remains.
+        let enc = Encoder::from_hex("0a05");
+        let mut dec = enc.as_decoder();
+        assert_eq!(decode_frame(&mut dec).unwrap_err(), Error::NoMoreData);
+        let enc = Encoder::from_hex("0a05040102");
+        let mut dec = enc.as_decoder();
+        assert_eq!(
+            decode_frame(&mut dec).unwrap_err(),
+            Error::FrameEncodingError
+        );
     }
 
     #[test]
@@ -948,6 +1061,25 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn test_datagram() {
+        let f = Frame::Datagram {
+            data: vec![1, 2, 3],
+        };
+        enc_dec(&f, "3103010203");
+
+        // Without a length, the frame extends to the end of the packet.
+        let enc = Encoder::from_hex("30010203");
+        let mut dec = enc.as_decoder();
+        assert_eq!(decode_frame(&mut dec).unwrap(), f);
+        assert_eq!(dec.remaining(), 0);
+
+        assert!(!f.is_allowed(0));
+        assert!(f.is_allowed(1));
+        assert!(!f.is_allowed(2));
+        assert!(f.is_allowed(3));
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
@@ -982,10 +1114,11 @@ mod tests {
             largest_acknowledged: 7,
             ack_delay: 12_000,
             first_ack_range: 2, // [7], 6, 5
-            ack_ranges: vec![AckRange {
+            ack_ranges: smallvec![AckRange {
                 gap: 0,   // 4
                 range: 1, // 3, 2
             }],
+            ecn_count: None,
         };
         let mut enc = Encoder::default();
         ack_frame.marshal(&mut enc);
@@ -997,6 +1130,7 @@ mod tests {
             ack_delay,
             first_ack_range,
             ack_ranges,
+            ..
         } = f
         {
             assert_eq!(largest_acknowledged, 7);
@@ -1010,7 +1144,7 @@ mod tests {
 
     #[test]
     fn test_decode_ack_frame() {
-        let res = Frame::decode_ack_frame(7, 2, vec![AckRange { gap: 0, range: 3 }]);
+        let res = Frame::decode_ack_frame(7, 2, &[AckRange { gap: 0, range: 3 }]);
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), vec![(7, 5), (3, 0)]);
     }
@@ -1018,49 +1152,49 @@ mod tests {
     #[test]
     fn new_stream_empty() {
         // Stream frames with empty data and no fin never work.
-        assert!(Frame::new_stream(0, 10, &[], false, 2).is_none());
-        assert!(Frame::new_stream(0, 10, &[], false, 3).is_none());
-        assert!(Frame::new_stream(0, 10, &[], false, 4).is_none());
-        assert!(Frame::new_stream(0, 10, &[], false, 5).is_none());
-        assert!(Frame::new_stream(0, 10, &[], false, 100).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], false, 2).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], false, 3).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], false, 4).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], false, 5).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], false, 100).is_none());
 
         // Empty data with fin is only a problem if there is no space.
-        assert!(Frame::new_stream(0, 0, &[], true, 1).is_none());
-        assert!(Frame::new_stream(0, 0, &[], true, 2).is_some());
-        assert!(Frame::new_stream(0, 10, &[], true, 2).is_none());
-        assert!(Frame::new_stream(0, 10, &[], true, 3).is_some());
-        assert!(Frame::new_stream(0, 10, &[], true, 4).is_some());
-        assert!(Frame::new_stream(0, 10, &[], true, 5).is_some());
-        assert!(Frame::new_stream(0, 10, &[], true, 100).is_some());
+        assert!(TxFrame::new_stream(0, 0, &[], true, 1).is_none());
+        assert!(TxFrame::new_stream(0, 0, &[], true, 2).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[], true, 2).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[], true, 3).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[], true, 4).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[], true, 5).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[], true, 100).is_some());
     }
 
     #[test]
     fn new_stream_minimum() {
         // Add minimum data
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], false, 3).is_none());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], true, 3).is_none());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], false, 4).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], true, 4).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], false, 5).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], true, 5).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], false, 100).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 1], true, 100).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], false, 3).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], true, 3).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], false, 4).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], true, 4).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], false, 5).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], true, 5).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], false, 100).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 1], true, 100).is_some());
     }
 
     #[test]
     fn new_stream_more() {
         // Try more data
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], false, 3).is_none());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], true, 3).is_none());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], false, 4).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], true, 4).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], false, 5).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], true, 5).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], false, 100).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], true, 100).is_some());
-
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], false, 1000).is_some());
-        assert!(Frame::new_stream(0, 10, &[0x42; 100], true, 1000).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], false, 3).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], true, 3).is_none());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], false, 4).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], true, 4).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], false, 5).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], true, 5).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], false, 100).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], true, 100).is_some());
+
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], false, 1000).is_some());
+        assert!(TxFrame::new_stream(0, 10, &[0x42; 100], true, 1000).is_some());
     }
 
     #[test]
@@ -1068,21 +1202,21 @@ mod tests {
         // A value that encodes to the largest varint.
         const BIG: u64 = 1 << 30;
 
-        assert!(Frame::new_stream(BIG, BIG, &[], false, 16).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[], true, 16).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[], false, 17).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[], true, 17).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[], false, 18).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[], true, 18).is_some());
-
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], false, 17).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], true, 17).is_none());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], false, 18).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], true, 18).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], false, 19).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], true, 19).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], false, 100).is_some());
-        assert!(Frame::new_stream(BIG, BIG, &[0x42; 1], true, 100).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], false, 16).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], true, 16).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], false, 17).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], true, 17).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], false, 18).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[], true, 18).is_some());
+
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], false, 17).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], true, 17).is_none());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], false, 18).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], true, 18).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], false, 19).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], true, 19).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], false, 100).is_some());
+        assert!(TxFrame::new_stream(BIG, BIG, &[0x42; 1], true, 100).is_some());
     }
 
     #[test]
@@ -1090,10 +1224,10 @@ mod tests {
         // 16383/16384 is an odd boundary in STREAM frame construction.
         // That is the boundary where a length goes from 2 bytes to 4 bytes.
         // If the data fits in the available space, then it is simple:
-        let r = Frame::new_stream(0, 0, &[0x43; 16384], true, 16386);
+        let r = TxFrame::new_stream(0, 0, &[0x43; 16384], true, 16386);
         let (f, used) = r.expect("Fit frame");
         assert_eq!(used, 16384);
-        if let Frame::Stream {
+        if let TxFrame::Stream {
             fin, fill, data, ..
         } = f
         {
@@ -1108,10 +1242,10 @@ mod tests {
         // That length will then make the frame to be too large and the data will be
         // truncated.  The frame could carry one more byte of data, but it's a corner
         // case we don't want to address as it should be rare (if not impossible).
-        let r = Frame::new_stream(0, 0, &[0x43; 16384], true, 16387);
+        let r = TxFrame::new_stream(0, 0, &[0x43; 16384], true, 16387);
         let (f, used) = r.expect("a frame");
         assert_eq!(used, 16381);
-        if let Frame::Stream {
+        if let TxFrame::Stream {
             fin, fill, data, ..
         } = f
         {
@@ -1127,10 +1261,10 @@ mod tests {
     fn new_stream_64() {
         // Unlike 16383/16384, the boundary at 63/64 is easy because the difference
         // is just one byte.  We lose just the last byte when there is more space.
-        let r = Frame::new_stream(0, 0, &[0x43; 64], true, 66);
+        let r = TxFrame::new_stream(0, 0, &[0x43; 64], true, 66);
         let (f, used) = r.expect("Fit frame");
         assert_eq!(used, 64);
-        if let Frame::Stream {
+        if let TxFrame::Stream {
             fin, fill, data, ..
         } = f
         {
@@ -1141,10 +1275,10 @@ mod tests {
             panic!("Wrong frame type");
         }
 
-        let r = Frame::new_stream(0, 0, &[0x43; 64], true, 67);
+        let r = TxFrame::new_stream(0, 0, &[0x43; 64], true, 67);
         let (f, used) = r.expect("a frame");
         assert_eq!(used, 63);
-        if let Frame::Stream {
+        if let TxFrame::Stream {
             fin, fill, data, ..
         } = f
         {
@@ -1155,4 +1289,51 @@ mod tests {
             panic!("Wrong frame type");
         }
     }
+
+    #[test]
+    fn tx_frame_encoding() {
+        let data = [0x44; 20];
+        let (crypto, _) = TxFrame::new_crypto(7, &data, 100);
+        let mut enc = Encoder::default();
+        crypto.marshal(&mut enc);
+        assert_eq!(
+            decode_frame(&mut enc.as_decoder()).unwrap(),
+            Frame::Crypto {
+                offset: 7,
+                data: data.to_vec(),
+            }
+        );
+
+        let (stream, _) = TxFrame::new_stream(4, 3, &data, true, 100).expect("a frame");
+        let mut enc = Encoder::default();
+        stream.marshal(&mut enc);
+        assert_eq!(
+            decode_frame(&mut enc.as_decoder()).unwrap(),
+            Frame::Stream {
+                fin: true,
+                stream_id: 4.into(),
+                offset: 3,
+                data: data.to_vec(),
+                fill: false,
+            }
+        );
+
+        let ping = TxFrame::from(Frame::Ping);
+        assert!(ping.ack_eliciting());
+        let mut enc = Encoder::default();
+        ping.marshal(&mut enc);
+        assert_eq!(enc, Encoder::from_hex("01"));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn decode_encode(frames in test_fixture::prop::frames()) {
+            let mut dec = Decoder::from(&frames[..]);
+            let mut enc = Encoder::default();
+            while dec.remaining() > 0 {
+                decode_frame(&mut dec).unwrap().marshal(&mut enc);
+            }
+            proptest::prop_assert_eq!(&enc[..], &frames[..]);
+        }
+    }
 }
@@ -0,0 +1,42 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Entry points for the fuzz targets, which can't otherwise reach the parsers
+// that are internal to this crate.
+
+use neqo_common::{Decoder, Encoder};
+
+use crate::connection::FixedConnectionIdManager;
+use crate::frame::decode_frame;
+use crate::packet::decode_packet_hdr;
+use crate::tparams::TransportParameters;
+
+/// The length of the connection IDs in short headers.
+const CID_LEN: usize = 8;
+
+/// Decode a packet header.
+pub fn packet_header(data: &[u8]) {
+    let _ = decode_packet_hdr(&FixedConnectionIdManager::new(CID_LEN), data);
+}
+
+/// Decode frames until there are none left or one doesn't decode.  Each frame
+/// that decodes is encoded again.
+pub fn frames(data: &[u8]) {
+    let mut dec = Decoder::from(data);
+    while dec.remaining() > 0 {
+        match decode_frame(&mut dec) {
+            Ok(f) => f.marshal(&mut Encoder::default()),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Decode transport parameters, and encode them again if that works.
+pub fn transport_parameters(data: &[u8]) {
+    if let Ok(tps) = TransportParameters::decode(&mut Decoder::from(data)) {
+        tps.encode(&mut Encoder::default());
+    }
+}
@@ -9,13 +9,36 @@
 use neqo_common::qinfo;
 use neqo_crypto;
 
+// Emit a `tracing` event at `$lvl`, if the feature is on.
+macro_rules! tracing_event {
+    ($lvl:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$lvl!($($arg)*);
+    };
+}
+
+// Enter `$span` until the end of the enclosing block, if the feature is on.
+macro_rules! enter_span {
+    ($span:expr) => {
+        #[cfg(feature = "tracing")]
+        let _entered = $span.clone().entered();
+    };
+}
+
 mod connection;
 mod crypto;
+mod decrypt_pool;
 mod dump;
+#[cfg(feature = "futures")]
+mod event_stream;
 mod events;
 mod flow_mgr;
 mod frame;
+#[cfg(feature = "test-crypto")]
+pub mod fuzz;
 mod packet;
+mod pool;
+mod random;
 mod recovery;
 mod recv_stream;
 mod send_stream;
@@ -26,11 +49,17 @@ mod tparams;
 mod tracking;
 
 pub use self::connection::{
-    Connection, ConnectionIdManager, FixedConnectionIdManager, Output, Role, State,
+    Connection, ConnectionIdManager, FixedConnectionIdManager, HandshakeInfo, Output, Role, State,
+    TicketPolicy,
 };
-pub use self::events::{ConnectionEvent, ConnectionEvents};
+#[cfg(feature = "futures")]
+pub use self::event_stream::EventStream;
+pub use self::events::{ConnectionEvent, ConnectionEvents, ZeroRttRejectReason};
 pub use self::frame::CloseError;
 pub use self::frame::StreamType;
+pub use self::pool::PoolStats;
+pub use self::random::seed_random;
+pub use self::stats::{ConnectionMetrics, EcnCount, MemoryBudget, MemoryUsage, Stats};
 pub use self::tparams::{tp_constants, TransportParameter};
 
 /// The supported version of the QUIC protocol.
@@ -39,6 +68,7 @@ pub const QUIC_VERSION: u32 = 0xff00_0018;
 type TransportError = u64;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::pub_enum_variant_names)]
 pub enum Error {
     NoError,
@@ -69,6 +99,7 @@ pub enum Error {
     InvalidRetry,
     InvalidStreamId,
     KeysNotFound,
+    MemoryBudgetExceeded,
     NoMoreData,
     PeerError(TransportError),
     TooMuchData,
@@ -112,6 +143,17 @@ impl From<std::num::TryFromIntError> for Error {
     }
 }
 
+impl From<neqo_common::DecoderError> for Error {
+    fn from(err: neqo_common::DecoderError) -> Self {
+        match err {
+            neqo_common::DecoderError::Truncated => Error::NoMoreData,
+            neqo_common::DecoderError::Overflow | neqo_common::DecoderError::InvalidLength => {
+                Error::FrameEncodingError
+            }
+        }
+    }
+}
+
 impl ::std::error::Error for Error {
     fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
         match self {
@@ -130,6 +172,7 @@ impl ::std::fmt::Display for Error {
 pub type AppError = u64;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectionError {
     Transport(Error),
     Application(AppError),
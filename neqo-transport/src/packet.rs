@@ -9,14 +9,14 @@
 // A lot of methods and types contain the word Packet
 #![allow(clippy::module_name_repetitions)]
 
-use rand::Rng;
-
-use neqo_common::{hex, matches, qtrace, Decoder, Encoder};
+use neqo_common::{hex, matches, qtrace, BufferFull, Decoder, Encoder, SliceEncoder};
 use neqo_crypto::aead::Aead;
 use neqo_crypto::Epoch;
 
 use std::convert::{TryFrom, TryInto};
 
+use crate::pool::BufferPool;
+use crate::random::random_fill;
 use crate::{Error, Res};
 
 const PACKET_TYPE_INITIAL: u8 = 0x0;
@@ -28,7 +28,9 @@ const PACKET_BIT_LONG: u8 = 0x80;
 const PACKET_BIT_SHORT: u8 = 0x00;
 const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
 
-const SAMPLE_SIZE: usize = 16;
+pub const SAMPLE_SIZE: usize = 16;
+/// The number of bytes from each mask that header protection uses.
+pub const MASK_LEN: usize = 5;
 
 const AUTH_TAG_LEN: usize = 16;
 
@@ -78,14 +80,14 @@ impl ConnectionId {
     pub fn generate(len: usize) -> Self {
         assert!(matches!(len, 0..=20));
         let mut v = vec![0; len];
-        rand::thread_rng().fill(&mut v[..]);
+        random_fill(&mut v[..]);
         Self(v)
     }
 
     // Apply a wee bit of greasing here in picking a length between 8 and 20 bytes long.
     pub fn generate_initial() -> ConnectionId {
         let mut v = [0u8; 1];
-        rand::thread_rng().fill(&mut v[..]);
+        random_fill(&mut v[..]);
         // Bias selection toward picking 8 (>50% of the time).
         let len: usize = ::std::cmp::max(8, 5 + (v[0] & (v[0] >> 4))).into();
         ConnectionId::generate(len)
@@ -196,8 +198,31 @@ impl PacketHdr {
 
 pub trait CryptoCtx {
     fn compute_mask(&self, sample: &[u8]) -> Res<Vec<u8>>;
-    fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>>;
-    fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>>;
+    /// Compute masks for `samples`, which are `SAMPLE_SIZE` bytes each.
+    /// This produces `MASK_LEN` bytes for each sample.
+    fn compute_masks(&self, samples: &[u8]) -> Res<Vec<u8>> {
+        let mut masks = Vec::with_capacity(samples.len() / SAMPLE_SIZE * MASK_LEN);
+        for sample in samples.chunks(SAMPLE_SIZE) {
+            masks.extend_from_slice(&self.compute_mask(sample)?[..MASK_LEN]);
+        }
+        Ok(masks)
+    }
+    /// Decrypt `body` into `output`, which needs to be as long as `body`.
+    fn aead_decrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        hdr: &[u8],
+        body: &[u8],
+        output: &'a mut [u8],
+    ) -> Res<&'a [u8]>;
+    /// Encrypt `body` into `output`, which needs space for the authentication tag.
+    fn aead_encrypt<'a>(
+        &self,
+        pn: PacketNumber,
+        hdr: &[u8],
+        body: &[u8],
+        output: &'a mut [u8],
+    ) -> Res<&'a [u8]>;
 }
 
 pub struct PacketNumberDecoder {
@@ -210,21 +235,15 @@ impl PacketNumberDecoder {
         }
     }
 
-    // TODO(mt) test this.  It's a strict implementation of the spec,
-    // but that doesn't mean we shouldn't test it.
+    // This is the algorithm from the spec, but it picks the window to use
+    // with arithmetic rather than branches, as this runs for every packet.
     fn decode_pn(&self, pn: u64, w: usize) -> PacketNumber {
         let window = 1_u64 << (w * 8);
+        let half = window / 2;
         let candidate = (self.expected & !(window - 1)) | pn;
-        if candidate + (window / 2) <= self.expected {
-            candidate + window
-        } else if candidate > self.expected + (window / 2) {
-            match candidate.checked_sub(window) {
-                Some(pn_sub) => pn_sub,
-                None => candidate,
-            }
-        } else {
-            candidate
-        }
+        let up = u64::from(candidate + half <= self.expected);
+        let down = u64::from(candidate > self.expected + half && candidate >= window);
+        candidate + up * window - down * window
     }
 }
 
@@ -339,8 +358,7 @@ pub fn decode_packet_hdr(cid_parser: &dyn ConnectionIdDecoder, pd: &[u8]) -> Res
 
         // Short Header.
         p.tipe = PacketType::Short;
-        let cid = d!(cid_parser.decode_cid(&mut d));
-        p.dcid = ConnectionId(cid.to_vec()); // TODO(mt) unnecessary copy
+        p.dcid = d!(cid_parser.decode_cid(&mut d));
         p.hdr_len = pd.len() - d.remaining();
         p.body_len = d.remaining();
         p.epoch = 3; // TODO(ekr@rtfm.com): Decode key phase bits.
@@ -399,28 +417,83 @@ pub fn decode_packet_hdr(cid_parser: &dyn ConnectionIdDecoder, pd: &[u8]) -> Res
     Ok(p)
 }
 
+/// Get the sample that header protection uses from `pkt`, which has the
+/// header `hdr`.
+pub fn hp_sample<'a>(hdr: &PacketHdr, pkt: &'a [u8]) -> Res<&'a [u8]> {
+    let payload = &pkt[hdr.hdr_len..];
+
+    if payload.len() < (4 + SAMPLE_SIZE) {
+        return Err(Error::NoMoreData);
+    }
+    Ok(&payload[4..(SAMPLE_SIZE + 4)])
+}
+
+/// Remove packet protection.  The plaintext is in a buffer from `pool`,
+/// which can be given back once it has been processed.
 pub fn decrypt_packet(
     crypto: &dyn CryptoCtx,
     pn: PacketNumberDecoder,
     hdr: &mut PacketHdr,
     pkt: &[u8],
+    pool: &mut BufferPool,
 ) -> Res<Vec<u8>> {
+    // First remove the header protection.
+    let mask = crypto.compute_mask(hp_sample(hdr, pkt)?)?;
+    decrypt_packet_with_mask(crypto, &mask, pn, hdr, pkt, pool)
+}
+
+/// Remove packet protection, using a header protection mask that was
+/// computed in advance.
+pub fn decrypt_packet_with_mask(
+    crypto: &dyn CryptoCtx,
+    mask: &[u8],
+    pn: PacketNumberDecoder,
+    hdr: &mut PacketHdr,
+    pkt: &[u8],
+    pool: &mut BufferPool,
+) -> Res<Vec<u8>> {
+    let hdrbytes = unmask_header(mask, pn, hdr, pkt, pool);
+
+    // Finally, decrypt.
+    let body = &pkt[hdr.hdr_len..hdr.hdr_len + hdr.body_len()];
+    let mut out = pool.take();
+    out.resize(body.len(), 0);
+    let res = crypto
+        .aead_decrypt(hdr.pn, &hdrbytes, body, &mut out)
+        .map(<[u8]>::len);
+    pool.give(hdrbytes);
+    match res {
+        Ok(len) => {
+            out.truncate(len);
+            Ok(out)
+        }
+        Err(e) => {
+            pool.give(out);
+            Err(e)
+        }
+    }
+}
+
+/// Remove header protection using `mask`.  This sets the packet number in
+/// `hdr`, and returns the header with protection removed in a buffer from `pool`.
+/// After this the ciphertext is at `hdr.hdr_len..hdr.hdr_len + hdr.body_len()`.
+/// `pkt` has to be long enough for `hp_sample` to succeed.
+pub fn unmask_header(
+    mask: &[u8],
+    pn: PacketNumberDecoder,
+    hdr: &mut PacketHdr,
+    pkt: &[u8],
+    pool: &mut BufferPool,
+) -> Vec<u8> {
     assert!(!matches!(
         hdr.tipe,
         PacketType::Retry{..} | PacketType::VN(_)
     ));
 
-    // First remove the header protection.
-    let payload = &pkt[hdr.hdr_len..];
-
-    if payload.len() < (4 + SAMPLE_SIZE) {
-        return Err(Error::NoMoreData);
-    }
-    let mask = crypto.compute_mask(&payload[4..(SAMPLE_SIZE + 4)])?;
-
     // Now put together a raw header to work on.
     let pn_len = decode_pnl((hdr.tbyte ^ mask[0]) & 0x3);
-    let mut hdrbytes = pkt[0..(hdr.hdr_len + pn_len)].to_vec();
+    let mut hdrbytes = pool.take();
+    hdrbytes.extend_from_slice(&pkt[0..(hdr.hdr_len + pn_len)]);
 
     qtrace!("unmask hdr={}", hex(&hdrbytes));
     // Un-mask the leading byte.
@@ -430,43 +503,50 @@ pub fn decrypt_packet(
             _ => 0x0f,
         };
 
-    // Now unmask the PN.
-    let mut pn_encoded: u64 = 0;
-    for i in 0..pn_len {
-        hdrbytes[hdr.hdr_len + i] ^= mask[1 + i];
-        pn_encoded <<= 8;
-        pn_encoded += u64::from(hdrbytes[hdr.hdr_len + i]);
-    }
+    // Now unmask the PN.  There are always four bytes after the header (the
+    // sample starts after them), so unmask four and keep the ones needed.
+    let mut pn_bytes = [0; 4];
+    pn_bytes.copy_from_slice(&pkt[hdr.hdr_len..hdr.hdr_len + 4]);
+    let mut pn_mask = [0; 4];
+    pn_mask.copy_from_slice(&mask[1..5]);
+    let pn_unmasked = (u32::from_be_bytes(pn_bytes) ^ u32::from_be_bytes(pn_mask)).to_be_bytes();
+    hdrbytes[hdr.hdr_len..].copy_from_slice(&pn_unmasked[..pn_len]);
+    let pn_encoded = u64::from(u32::from_be_bytes(pn_unmasked) >> (8 * (4 - pn_len)));
     qtrace!("unmasked hdr={}", hex(&hdrbytes));
     hdr.hdr_len += pn_len;
     hdr.body_len -= pn_len;
 
     // Now call out to expand the PN.
     hdr.pn = pn.decode_pn(pn_encoded, pn_len);
-
-    // Finally, decrypt.
-    Ok(crypto.aead_decrypt(
-        hdr.pn,
-        &hdrbytes,
-        &pkt[hdr.hdr_len..hdr.hdr_len + hdr.body_len()],
-    )?)
+    hdrbytes
 }
 
-fn encode_packet_short(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
-    let mut enc = Encoder::default();
+fn encode_short_header(hdr: &PacketHdr, enc: &mut SliceEncoder) -> Result<(), BufferFull> {
     // Leading byte.
     let pnl = pn_length(hdr.pn);
-    enc.encode_byte(PACKET_BIT_SHORT | PACKET_BIT_FIXED_QUIC | encode_pnl(pnl));
-    enc.encode(&hdr.dcid.0);
-    enc.encode_uint(pnl, hdr.pn);
+    enc.encode_byte(PACKET_BIT_SHORT | PACKET_BIT_FIXED_QUIC | encode_pnl(pnl))?;
+    enc.encode(&hdr.dcid.0)?;
+    enc.encode_uint(pnl, hdr.pn)?;
+    Ok(())
+}
 
-    encrypt_packet(crypto, hdr, enc, body)
+/// Build a short header packet without header protection, which
+/// `protect_packets` can add later.  This returns the packet and the offset
+/// of its packet number.
+pub fn seal_packet_short(
+    crypto: &dyn CryptoCtx,
+    hdr: &PacketHdr,
+    body: &[u8],
+    pool: &mut BufferPool,
+) -> (Vec<u8>, usize) {
+    debug_assert_eq!(hdr.tipe, PacketType::Short);
+    seal_packet(crypto, hdr, body, pool)
 }
 
 pub fn encode_packet_vn(hdr: &PacketHdr) -> Vec<u8> {
     let mut d = Encoder::default();
     let mut rand_byte: [u8; 1] = [0; 1];
-    rand::thread_rng().fill(&mut rand_byte);
+    random_fill(&mut rand_byte);
     d.encode_byte(PACKET_BIT_LONG | rand_byte[0]);
     d.encode_uint(4, 0_u64); // version
     d.encode_vec(1, &hdr.dcid);
@@ -482,51 +562,115 @@ pub fn encode_packet_vn(hdr: &PacketHdr) -> Vec<u8> {
 }
 
 /* Handle Initial, 0-RTT, Handshake. */
-fn encode_packet_long(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
-    let mut enc = Encoder::default();
-
+fn encode_long_header(
+    hdr: &PacketHdr,
+    body_len: usize,
+    enc: &mut SliceEncoder,
+) -> Result<(), BufferFull> {
     let pnl = pn_length(hdr.pn);
     enc.encode_byte(
         PACKET_BIT_LONG | PACKET_BIT_FIXED_QUIC | hdr.tipe.code() << 4 | encode_pnl(pnl),
-    );
-    enc.encode_uint(4, hdr.version.unwrap());
-    enc.encode_vec(1, &*hdr.dcid);
-    enc.encode_vec(1, &*hdr.scid.as_ref().unwrap());
+    )?;
+    enc.encode_uint(4, hdr.version.unwrap())?;
+    enc.encode_vec(1, &*hdr.dcid)?;
+    enc.encode_vec(1, &*hdr.scid.as_ref().unwrap())?;
 
     if let PacketType::Initial(token) = &hdr.tipe {
-        enc.encode_vvec(&token);
+        enc.encode_vvec(&token)?;
     }
-    enc.encode_varint((pnl + body.len() + AUTH_TAG_LEN) as u64);
-    enc.encode_uint(pnl, hdr.pn);
+    enc.encode_varint((pnl + body_len + AUTH_TAG_LEN) as u64)?;
+    enc.encode_uint(pnl, hdr.pn)?;
+    Ok(())
+}
 
-    encrypt_packet(crypto, hdr, enc, body)
+/// The most that the header for `hdr` can take, counting each varint as
+/// the longest that it could be.
+fn header_len_max(hdr: &PacketHdr) -> usize {
+    let pnl = pn_length(hdr.pn);
+    match &hdr.tipe {
+        PacketType::Short => 1 + hdr.dcid.len() + pnl,
+        tipe => {
+            let token = match tipe {
+                PacketType::Initial(token) => 8 + token.len(),
+                _ => 0,
+            };
+            let scid = hdr.scid.as_ref().map_or(0, |c| c.len());
+            1 + 4 + 1 + hdr.dcid.len() + 1 + scid + token + 8 + pnl
+        }
+    }
 }
 
 fn encrypt_packet(
     crypto: &dyn CryptoCtx,
     hdr: &PacketHdr,
-    mut enc: Encoder,
     body: &[u8],
+    pool: &mut BufferPool,
 ) -> Vec<u8> {
-    let hdr_len = enc.len();
-    // Encrypt the packet. This has too many copies.
-    let ct = crypto.aead_encrypt(hdr.pn, &enc, body).unwrap();
-    enc.encode(&ct);
-    qtrace!("mask hdr={}", hex(&enc[0..hdr_len]));
-    let pn_start = hdr_len - pn_length(hdr.pn);
+    let (mut pkt, pn_start) = seal_packet(crypto, hdr, body, pool);
     let mask = crypto
-        .compute_mask(&enc[pn_start + 4..pn_start + SAMPLE_SIZE + 4])
+        .compute_mask(&pkt[pn_start + 4..pn_start + SAMPLE_SIZE + 4])
         .unwrap();
-    enc[0] ^= mask[0]
-        & match hdr.tipe {
-            PacketType::Short => 0x1f,
-            _ => 0x0f,
+    protect_header(&mut pkt, pn_start, &mask);
+    pkt
+}
+
+/// Encrypt the body of a packet, but leave header protection for later.
+/// The buffer from `pool` is sized for the whole packet once, then the header
+/// is written at the start and the body encrypted after it.
+/// This returns the packet and the offset of its packet number.
+fn seal_packet(
+    crypto: &dyn CryptoCtx,
+    hdr: &PacketHdr,
+    body: &[u8],
+    pool: &mut BufferPool,
+) -> (Vec<u8>, usize) {
+    let mut pkt = pool.take();
+    pkt.resize(header_len_max(hdr) + body.len() + AUTH_TAG_LEN, 0);
+    let mut enc = SliceEncoder::new(&mut pkt);
+    if let PacketType::Short = hdr.tipe {
+        encode_short_header(hdr, &mut enc)
+    } else {
+        encode_long_header(hdr, body.len(), &mut enc)
+    }
+    .expect("header_len_max leaves room for the header");
+    let hdr_len = enc.len();
+    let (hdr_bytes, ct) = pkt.split_at_mut(hdr_len);
+    let ct_len = crypto
+        .aead_encrypt(hdr.pn, hdr_bytes, body, ct)
+        .unwrap()
+        .len();
+    pkt.truncate(hdr_len + ct_len);
+    (pkt, hdr_len - pn_length(hdr.pn))
+}
+
+/// Apply header protection to `pkt`, which has a packet number at `pn_start`.
+fn protect_header(pkt: &mut [u8], pn_start: usize, mask: &[u8]) {
+    let pn_len = decode_pnl(pkt[0] & 0x3);
+    qtrace!("mask hdr={}", hex(&pkt[0..pn_start + pn_len]));
+    pkt[0] ^= mask[0]
+        & if pkt[0] & PACKET_BIT_LONG == 0 {
+            0x1f
+        } else {
+            0x0f
         };
-    for i in 0..pn_length(hdr.pn) {
-        enc[pn_start + i] ^= mask[i + 1];
+    for i in 0..pn_len {
+        pkt[pn_start + i] ^= mask[i + 1];
+    }
+    qtrace!("masked hdr={}", hex(&pkt[0..pn_start + pn_len]));
+}
+
+/// Apply header protection to packets that were sealed with the same keys,
+/// all in one pass.  Each packet is paired with the offset of its packet number.
+pub fn protect_packets(crypto: &dyn CryptoCtx, packets: &mut [(&mut [u8], usize)]) -> Res<()> {
+    let mut samples = Vec::with_capacity(packets.len() * SAMPLE_SIZE);
+    for (pkt, pn_start) in packets.iter() {
+        samples.extend_from_slice(&pkt[pn_start + 4..pn_start + SAMPLE_SIZE + 4]);
+    }
+    let masks = crypto.compute_masks(&samples)?;
+    for ((pkt, pn_start), mask) in packets.iter_mut().zip(masks.chunks(MASK_LEN)) {
+        protect_header(pkt, *pn_start, mask);
     }
-    qtrace!("masked hdr={}", hex(&enc[0..hdr_len]));
-    enc.into()
+    Ok(())
 }
 
 // TODO(ekr@rtfm.com): Minimal packet number lengths.
@@ -536,7 +680,7 @@ fn pn_length(_pn: PacketNumber) -> usize {
 
 pub fn encode_retry(hdr: &PacketHdr) -> Vec<u8> {
     let mut rand_byte: [u8; 1] = [0; 1];
-    rand::thread_rng().fill(&mut rand_byte);
+    random_fill(&mut rand_byte);
     if let PacketType::Retry { odcid, token } = &hdr.tipe {
         let mut enc = Encoder::default();
         let b0 = PACKET_BIT_LONG
@@ -555,14 +699,21 @@ pub fn encode_retry(hdr: &PacketHdr) -> Vec<u8> {
     }
 }
 
-pub fn encode_packet(crypto: &dyn CryptoCtx, hdr: &PacketHdr, body: &[u8]) -> Vec<u8> {
+/// Build a protected packet.  Initial, 0-RTT, Handshake, and short header
+/// packets are built in a buffer from `pool`.
+pub fn encode_packet(
+    crypto: &dyn CryptoCtx,
+    hdr: &PacketHdr,
+    body: &[u8],
+    pool: &mut BufferPool,
+) -> Vec<u8> {
     match &hdr.tipe {
-        PacketType::Short => encode_packet_short(crypto, hdr, body),
         PacketType::VN(_) => encode_packet_vn(hdr),
         PacketType::Retry { .. } => encode_retry(hdr),
-        PacketType::Initial(..) | PacketType::ZeroRTT | PacketType::Handshake => {
-            encode_packet_long(crypto, hdr, body)
-        }
+        PacketType::Short
+        | PacketType::Initial(..)
+        | PacketType::ZeroRTT
+        | PacketType::Handshake => encrypt_packet(crypto, hdr, body, pool),
     }
 }
 
@@ -589,10 +740,17 @@ mod tests {
             Ok(vec![0xa5, 0xa5, 0xa5, 0xa5, 0xa5])
         }
 
-        fn aead_decrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
-            let mut pt = body.to_vec();
-
-            for i in &mut pt {
+        fn aead_decrypt<'a>(
+            &self,
+            pn: PacketNumber,
+            hdr: &[u8],
+            body: &[u8],
+            output: &'a mut [u8],
+        ) -> Res<&'a [u8]> {
+            let pt = &mut output[..body.len()];
+            pt.copy_from_slice(body);
+
+            for i in pt.iter_mut() {
                 *i ^= AEAD_MASK;
             }
             let pt_len = pt.len() - AUTH_TAG_LEN;
@@ -602,19 +760,25 @@ mod tests {
                     return Err(Error::DecryptError);
                 }
             }
-            Ok(pt[0..pt_len].to_vec())
+            Ok(&output[0..pt_len])
         }
 
-        fn aead_encrypt(&self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+        fn aead_encrypt<'a>(
+            &self,
+            pn: PacketNumber,
+            hdr: &[u8],
+            body: &[u8],
+            output: &'a mut [u8],
+        ) -> Res<&'a [u8]> {
             let tag = TestFixture::auth_tag(hdr, body);
-            let mut enc = Encoder::with_capacity(body.len() + tag.len());
-            enc.encode(body);
-            enc.encode(&tag);
-            for i in 0..enc.len() {
-                enc[i] ^= AEAD_MASK;
+            let ct = &mut output[..body.len() + tag.len()];
+            ct[..body.len()].copy_from_slice(body);
+            ct[body.len()..].copy_from_slice(&tag);
+            for i in ct.iter_mut() {
+                *i ^= AEAD_MASK;
             }
 
-            Ok(enc.into())
+            Ok(ct)
         }
     }
 
@@ -647,12 +811,18 @@ mod tests {
 
     fn test_decrypt_packet(f: &TestFixture, packet: Vec<u8>) -> Res<(PacketHdr, Vec<u8>)> {
         let mut phdr = decode_packet_hdr(f, &packet)?;
-        let body = decrypt_packet(f, PacketNumberDecoder::new(Some(0)), &mut phdr, &packet)?;
+        let body = decrypt_packet(
+            f,
+            PacketNumberDecoder::new(Some(0)),
+            &mut phdr,
+            &packet,
+            &mut BufferPool::default(),
+        )?;
         Ok((phdr, body))
     }
 
     fn test_encrypt_decrypt(f: &TestFixture, hdr: &mut PacketHdr, body: &[u8]) -> PacketHdr {
-        let packet = encode_packet(f, hdr, &TEST_BODY);
+        let packet = encode_packet(f, hdr, &TEST_BODY, &mut BufferPool::default());
         let res = test_decrypt_packet(&f, packet).unwrap();
         assert_headers_equal(&hdr, &res.0);
         assert_eq!(body.to_vec(), res.1);
@@ -666,16 +836,77 @@ mod tests {
         test_encrypt_decrypt(&f, &mut hdr, &TEST_BODY);
     }
 
+    #[test]
+    fn decode_pn() {
+        // The example from the spec.
+        let dec = PacketNumberDecoder::new(Some(0xa82f_30ea));
+        assert_eq!(dec.decode_pn(0x9b32, 2), 0xa82f_9b32);
+
+        let dec = PacketNumberDecoder::new(Some(0x1ff));
+        assert_eq!(dec.decode_pn(0x01, 1), 0x201); // Wraps forward.
+        assert_eq!(dec.decode_pn(0xff, 1), 0x1ff);
+        let dec = PacketNumberDecoder::new(Some(0x200));
+        assert_eq!(dec.decode_pn(0xf0, 1), 0x1f0); // Wraps back.
+        let dec = PacketNumberDecoder::new(None);
+        assert_eq!(dec.decode_pn(0xf0, 1), 0xf0); // Can't go below zero.
+    }
+
     #[test]
     fn test_short_packet_damaged() {
         let f = TestFixture {};
         let hdr = default_hdr();
-        let mut packet = encode_packet(&f, &hdr, &TEST_BODY);
+        let mut packet = encode_packet(&f, &hdr, &TEST_BODY, &mut BufferPool::default());
         let plen = packet.len();
         packet[plen - 1] ^= 0x7;
         assert!(test_decrypt_packet(&f, packet).is_err());
     }
 
+    #[test]
+    fn reuse_buffers() {
+        let f = TestFixture {};
+        let hdr = default_hdr();
+        let mut pool = BufferPool::default();
+        for _ in 0..3 {
+            let packet = encode_packet(&f, &hdr, &TEST_BODY, &mut pool);
+            let mut phdr = decode_packet_hdr(&f, &packet).unwrap();
+            let body = decrypt_packet(
+                &f,
+                PacketNumberDecoder::new(Some(0)),
+                &mut phdr,
+                &packet,
+                &mut pool,
+            )
+            .unwrap();
+            assert_eq!(&body[..], &TEST_BODY[..]);
+            pool.give(body);
+            pool.give(packet);
+        }
+        // Only the first round allocates.
+        assert_eq!(pool.stats().allocated, 3);
+    }
+
+    #[test]
+    fn protect_in_batch() {
+        let f = TestFixture {};
+        let mut pool = BufferPool::default();
+        let mut hdr = default_hdr();
+        let mut expected = Vec::new();
+        let mut sealed = Vec::new();
+        for pn in 0..3 {
+            hdr.pn = pn;
+            expected.push(encode_packet(&f, &hdr, &TEST_BODY, &mut pool));
+            sealed.push(seal_packet_short(&f, &hdr, &TEST_BODY, &mut pool));
+        }
+        let mut packets = sealed
+            .iter_mut()
+            .map(|(p, pn_start)| (&mut p[..], *pn_start))
+            .collect::<Vec<_>>();
+        protect_packets(&f, &mut packets).unwrap();
+        for ((p, _), e) in sealed.iter().zip(expected) {
+            assert_eq!(p, &e);
+        }
+    }
+
     #[test]
     fn test_handshake_packet() {
         let f = TestFixture {};
@@ -691,7 +922,7 @@ mod tests {
         let mut hdr = default_hdr();
         hdr.tipe = PacketType::Handshake;
         hdr.scid = Some(ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]));
-        let mut packet = encode_packet(&f, &hdr, &TEST_BODY);
+        let mut packet = encode_packet(&f, &hdr, &TEST_BODY, &mut BufferPool::default());
         let plen = packet.len();
         packet[plen - 1] ^= 0x7;
         assert!(test_decrypt_packet(&f, packet).is_err());
@@ -713,7 +944,7 @@ mod tests {
         let mut hdr = default_hdr();
         hdr.tipe = PacketType::Initial(vec![0x0, 0x0, 0x0, 0x0]);
         hdr.scid = Some(ConnectionId(vec![9, 8, 7, 6, 5, 4, 3, 2]));
-        let mut packet = encode_packet(&f, &hdr, &TEST_BODY);
+        let mut packet = encode_packet(&f, &hdr, &TEST_BODY, &mut BufferPool::default());
         let plen = packet.len();
         packet[plen - 1] ^= 0x7;
         assert!(test_decrypt_packet(&f, packet).is_err());
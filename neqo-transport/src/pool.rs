@@ -0,0 +1,142 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A pool of buffers for packet protection, so that steady-state operation
+// doesn't need to allocate for every packet.
+
+use std::mem;
+
+/// The number of buffers that a pool keeps by default.
+pub const DEFAULT_POOL_CAPACITY: usize = 16;
+/// The size of each buffer by default, with space for any packet we send
+/// and most that we receive.
+pub const DEFAULT_BUFFER_SIZE: usize = 2048;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Buffer pool statistics
+pub struct PoolStats {
+    /// Buffers that had to be allocated because the pool was empty
+    pub allocated: u64,
+    /// Buffers that were taken from the pool
+    pub reused: u64,
+    /// Buffers that were returned to the pool
+    pub returned: u64,
+    /// Buffers that were dropped because the pool was full
+    pub discarded: u64,
+}
+
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    capacity: usize,
+    buffer_size: usize,
+    stats: PoolStats,
+}
+
+impl BufferPool {
+    /// Make a pool that keeps up to `capacity` buffers of at least
+    /// `buffer_size` bytes.  Buffers are only allocated when they are needed.
+    pub fn new(capacity: usize, buffer_size: usize) -> Self {
+        Self {
+            buffers: Vec::with_capacity(capacity),
+            capacity,
+            buffer_size,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Change the limits of the pool.  Any buffers that no longer fit are dropped.
+    pub fn configure(&mut self, capacity: usize, buffer_size: usize) {
+        self.capacity = capacity;
+        self.buffer_size = buffer_size;
+        let old = mem::replace(&mut self.buffers, Vec::with_capacity(capacity));
+        for buf in old {
+            self.give(buf);
+        }
+    }
+
+    /// Take an empty buffer from the pool, or allocate one if it is empty.
+    pub fn take(&mut self) -> Vec<u8> {
+        if let Some(buf) = self.buffers.pop() {
+            self.stats.reused += 1;
+            buf
+        } else {
+            self.stats.allocated += 1;
+            Vec::with_capacity(self.buffer_size)
+        }
+    }
+
+    /// Return a buffer to the pool.  Buffers are dropped if the pool is full
+    /// or if they are too small to be useful.
+    pub fn give(&mut self, mut buf: Vec<u8>) {
+        if self.buffers.len() >= self.capacity || buf.capacity() < self.buffer_size {
+            self.stats.discarded += 1;
+            return;
+        }
+        buf.clear();
+        self.buffers.push(buf);
+        self.stats.returned += 1;
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.stats
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_CAPACITY, DEFAULT_BUFFER_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let mut pool = BufferPool::new(2, 100);
+        let mut a = pool.take();
+        assert!(a.capacity() >= 100);
+        a.extend_from_slice(&[1, 2, 3]);
+        let ptr = a.as_ptr();
+        pool.give(a);
+
+        let b = pool.take();
+        assert!(b.is_empty());
+        assert_eq!(b.as_ptr(), ptr);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                allocated: 1,
+                reused: 1,
+                returned: 1,
+                discarded: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let mut pool = BufferPool::new(1, 100);
+        let a = pool.take();
+        let b = pool.take();
+        pool.give(a);
+        pool.give(b);
+        // Too small.
+        pool.give(Vec::new());
+        let stats = pool.stats();
+        assert_eq!(stats.allocated, 2);
+        assert_eq!(stats.returned, 1);
+        assert_eq!(stats.discarded, 2);
+
+        pool.configure(0, 100);
+        assert_eq!(pool.stats().discarded, 3);
+        let _ = pool.take();
+        assert_eq!(pool.stats().allocated, 3);
+    }
+}
@@ -0,0 +1,54 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The source of the random choices that the transport makes: connection IDs
+// and the unprotected bits of Version Negotiation and Retry packets.  Tests
+// can seed this so that those choices are the same on every run.  This
+// doesn't reach the randomness that NSS uses for TLS.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+
+thread_local! {
+    static SEEDED: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Make the random choices on this thread repeatable, starting from `seed`.
+/// With `None`, go back to using the thread's own generator.
+pub fn seed_random(seed: Option<u64>) {
+    SEEDED.with(|s| *s.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+/// Fill `buf` with random bytes.
+pub(crate) fn random_fill(buf: &mut [u8]) {
+    SEEDED.with(|s| match &mut *s.borrow_mut() {
+        Some(rng) => rng.fill(buf),
+        None => rand::thread_rng().fill(buf),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw() -> [u8; 16] {
+        let mut buf = [0; 16];
+        random_fill(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn seeded() {
+        seed_random(Some(13));
+        let first = draw();
+        seed_random(Some(13));
+        assert_eq!(draw(), first);
+        seed_random(Some(14));
+        assert_ne!(draw(), first);
+        seed_random(None);
+    }
+}
@@ -9,6 +9,7 @@
 use std::cmp::{max, min};
 use std::collections::BTreeMap;
 use std::fmt::{self, Display};
+use std::mem;
 use std::ops::{Index, IndexMut};
 use std::time::{Duration, Instant};
 
@@ -19,6 +20,7 @@ use neqo_common::{const_max, const_min, qdebug, qinfo};
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
 use crate::send_stream::StreamRecoveryToken;
+use crate::stats::EcnCount;
 use crate::tracking::{AckToken, PNSpace};
 
 const GRANULARITY: Duration = Duration::from_millis(20);
@@ -44,11 +46,15 @@ pub enum RecoveryToken {
     Flow(FlowControlRecoveryToken),
 }
 
+/// The tokens for the frames in a packet.  Packets rarely carry more than a
+/// few frames that need tokens, so these are kept inline.
+pub type RecoveryTokens = SmallVec<[RecoveryToken; 4]>;
+
 #[derive(Debug, Clone)]
 pub struct SentPacket {
     ack_eliciting: bool,
     time_sent: Instant,
-    pub tokens: Vec<RecoveryToken>,
+    pub tokens: RecoveryTokens,
 
     time_declared_lost: Option<Instant>,
 
@@ -60,7 +66,7 @@ impl SentPacket {
     pub fn new(
         time_sent: Instant,
         ack_eliciting: bool,
-        tokens: Vec<RecoveryToken>,
+        tokens: RecoveryTokens,
         size: usize,
         in_flight: bool,
     ) -> SentPacket {
@@ -167,6 +173,8 @@ pub(crate) struct LossRecoverySpace {
     tx_pn: u64,
     largest_acked: Option<u64>,
     largest_acked_sent_time: Option<Instant>,
+    /// The largest count of ECN-CE marks that the peer has reported.
+    ecn_ce: u64,
     sent_packets: BTreeMap<u64, SentPacket>,
 }
 
@@ -273,7 +281,6 @@ impl Display for CongestionControl {
 }
 
 impl CongestionControl {
-    #[cfg(test)]
     pub fn cwnd(&self) -> usize {
         self.congestion_window
     }
@@ -354,6 +361,7 @@ impl CongestionControl {
         if in_persistent_congestion {
             qinfo!([self], "persistent congestion");
             self.congestion_window = MIN_CONG_WINDOW;
+            tracing_event!(info, cwnd = self.congestion_window, "persistent congestion");
         }
     }
 
@@ -393,6 +401,12 @@ impl CongestionControl {
                 self.congestion_window,
                 self.ssthresh
             );
+            tracing_event!(
+                debug,
+                cwnd = self.congestion_window,
+                ssthresh = self.ssthresh,
+                "congestion recovery"
+            );
         } else {
             qdebug!([self], "Cong event but already in recovery");
         }
@@ -430,11 +444,14 @@ impl LossRecovery {
         }
     }
 
-    #[cfg(test)]
     pub fn cwnd(&self) -> usize {
         self.cc.cwnd()
     }
 
+    pub fn rtt(&self) -> Duration {
+        self.rtt_vals.rtt()
+    }
+
     #[cfg(test)]
     pub fn ssthresh(&self) -> usize {
         self.cc.ssthresh()
@@ -492,6 +509,7 @@ impl LossRecovery {
         largest_acked: u64,
         acked_ranges: Vec<(u64, u64)>,
         ack_delay: Duration,
+        ecn_count: Option<EcnCount>,
         now: Instant,
     ) -> (Vec<SentPacket>, Vec<SentPacket>) {
         qdebug!(
@@ -523,7 +541,16 @@ impl LossRecovery {
             }
         }
 
-        // TODO Process ECN information if present.
+        // More CE marks are a congestion event. (-recovery 7.1)
+        if let Some(ecn_count) = ecn_count {
+            let space = &mut self.spaces[pn_space];
+            if ecn_count.ce > space.ecn_ce {
+                space.ecn_ce = ecn_count.ce;
+                let largest_acked_pkt = acked_packets.last().expect("must be there");
+                self.cc
+                    .on_congestion_event(now, largest_acked_pkt.time_sent);
+            }
+        }
 
         let lost_packets = self.detect_lost_packets(pn_space, now);
 
@@ -650,6 +677,22 @@ impl LossRecovery {
         lost_packets
     }
 
+    /// An estimate of the memory used by records of sent packets.
+    pub fn sent_packets_memory(&self) -> usize {
+        self.spaces
+            .iter()
+            .flat_map(|spc| spc.sent_packets.values())
+            .map(|sp| {
+                let spilled = if sp.tokens.spilled() {
+                    sp.tokens.capacity() * mem::size_of::<RecoveryToken>()
+                } else {
+                    0
+                };
+                mem::size_of::<(u64, SentPacket)>() + spilled
+            })
+            .sum()
+    }
+
     pub fn get_timer(&mut self) -> LossRecoveryState {
         qdebug!([self], "get_loss_detection_timer.");
 
@@ -808,7 +851,7 @@ mod tests {
             lr.on_packet_sent(
                 PNSpace::ApplicationData,
                 pn,
-                SentPacket::new(pn_time(pn), true, Vec::new(), ON_SENT_SIZE, true),
+                SentPacket::new(pn_time(pn), true, RecoveryTokens::new(), ON_SENT_SIZE, true),
             );
         }
     }
@@ -821,6 +864,7 @@ mod tests {
             pn,
             vec![(pn, pn)],
             ACK_DELAY,
+            None,
             pn_time(pn) + delay,
         );
     }
@@ -926,7 +970,7 @@ mod tests {
         lr.on_packet_sent(
             PNSpace::ApplicationData,
             0,
-            SentPacket::new(pn_time(0), true, Vec::new(), ON_SENT_SIZE, true),
+            SentPacket::new(pn_time(0), true, RecoveryTokens::new(), ON_SENT_SIZE, true),
         );
         lr.on_packet_sent(
             PNSpace::ApplicationData,
@@ -934,7 +978,7 @@ mod tests {
             SentPacket::new(
                 pn_time(0) + INITIAL_RTT / 4,
                 true,
-                Vec::new(),
+                RecoveryTokens::new(),
                 ON_SENT_SIZE,
                 true,
             ),
@@ -944,6 +988,7 @@ mod tests {
             1,
             vec![(1, 1)],
             ACK_DELAY,
+            None,
             pn_time(0) + (INITIAL_RTT * 5 / 4),
         );
         assert_eq!(lost.len(), 1);
@@ -966,6 +1011,7 @@ mod tests {
             2,
             vec![(2, 2)],
             ACK_DELAY,
+            None,
             pn_time(2) + INITIAL_RTT,
         );
         assert!(lost.is_empty());
@@ -997,8 +1043,43 @@ mod tests {
             4,
             vec![(4, 2)],
             ACK_DELAY,
+            None,
             pn_time(4),
         );
         assert_eq!(lost.len(), 1);
     }
+
+    #[test]
+    fn ecn_ce() {
+        let mut lr = setup_lr(4);
+        let cwnd = lr.cwnd();
+        let ack_ecn = |lr: &mut LossRecovery, pn, ce| {
+            let ecn_count = EcnCount {
+                ect0: pn + 1,
+                ect1: 0,
+                ce,
+            };
+            lr.on_ack_received(
+                PNSpace::ApplicationData,
+                pn,
+                vec![(pn, pn)],
+                ACK_DELAY,
+                Some(ecn_count),
+                pn_time(pn) + INITIAL_RTT,
+            );
+        };
+        // No CE marks, so no change.
+        ack_ecn(&mut lr, 1, 0);
+        let cwnd = cwnd + ON_SENT_SIZE;
+        assert_eq!(lr.cwnd(), cwnd);
+
+        // A CE mark halves the window.
+        ack_ecn(&mut lr, 2, 1);
+        assert_eq!(lr.cwnd(), cwnd / 2);
+        assert_eq!(lr.ssthresh(), cwnd / 2);
+
+        // The same count again isn't a new mark.
+        ack_ecn(&mut lr, 3, 1);
+        assert_eq!(lr.cwnd(), cwnd / 2);
+    }
 }
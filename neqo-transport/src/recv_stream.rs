@@ -8,9 +8,7 @@
 // incoming STREAM frames.
 
 use std::cell::RefCell;
-use std::cmp::{max, min};
 use std::collections::BTreeMap;
-use std::convert::TryInto;
 use std::mem;
 use std::ops::Bound::{Included, Unbounded};
 use std::rc::Rc;
@@ -21,7 +19,7 @@ use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
 use crate::stream_id::StreamId;
 use crate::{AppError, Error, Res};
-use neqo_common::{matches, qtrace};
+use neqo_common::{matches, qtrace, ChunkedBuffer};
 
 pub const RX_STREAM_DATA_WINDOW: u64 = 0xFFFF; // 64 KiB
 
@@ -29,10 +27,17 @@ pub(crate) type RecvStreams = BTreeMap<StreamId, RecvStream>;
 
 /// Holds data not yet read by application. Orders and dedupes data ranges
 /// from incoming STREAM frames.
+/// Data that is in order moves to a `ChunkedBuffer`, without being copied,
+/// and only data that is past a gap is held by offset.  A frame that repeats
+/// data that is already in order is trimmed, so the first copy is the one
+/// that is kept.
 #[derive(Debug, Default, PartialEq)]
 pub struct RxStreamOrderer {
-    data_ranges: BTreeMap<u64, Vec<u8>>, // (start_offset, data)
+    data_ranges: BTreeMap<u64, Vec<u8>>, // (start_offset, data), all past `contiguous`
+    ready: ChunkedBuffer,                // Data from `retired` to `contiguous`
     retired: u64,                        // Number of bytes the application has read
+    contiguous: u64,                     // End of the data that has no gaps
+    unordered: u64,                      // Number of bytes in `data_ranges`
 }
 
 impl RxStreamOrderer {
@@ -43,16 +48,14 @@ impl RxStreamOrderer {
     /// Process an incoming stream frame off the wire. This may result in data
     /// being available to upper layers if frame is not out of order (ooo) or
     /// if the frame fills a gap.
-    pub fn inbound_frame(&mut self, new_start: u64, mut new_data: Vec<u8>) -> Res<()> {
+    pub fn inbound_frame(&mut self, mut new_start: u64, mut new_data: Vec<u8>) -> Res<()> {
         qtrace!("Inbound data offset={} len={}", new_start, new_data.len());
 
-        // Get entry before where new entry would go, so we can see if we already
-        // have the new bytes.
-        // Avoid copies and duplicated data.
         let new_end = new_start + new_data.len() as u64;
 
-        if new_end <= self.retired {
-            // Range already read by application, this frame is very late and unneeded.
+        if new_end <= self.contiguous {
+            // Range already in order or read by application, this frame is
+            // very late and unneeded.
             return Ok(());
         }
 
@@ -61,6 +64,16 @@ impl RxStreamOrderer {
             return Ok(());
         }
 
+        if new_start < self.contiguous {
+            // Keep only what follows the data that is in order.
+            new_data.drain(..(self.contiguous - new_start) as usize);
+            new_start = self.contiguous;
+        }
+
+        // Get entry before where new entry would go, so we can see if we already
+        // have the new bytes.
+        // Avoid copies and duplicated data.
+        let mut dropped = 0;
         let (insert_new, remove_prev) = if let Some((&prev_start, prev_vec)) = self
             .data_ranges
             .range_mut((Unbounded, Included(new_start)))
@@ -76,8 +89,9 @@ impl RxStreamOrderer {
                     // (In-order frames will take this path, with no overlap)
                     let overlap = prev_end.saturating_sub(new_start);
                     if overlap != 0 {
-                        let truncate_to = new_data.len() - overlap as usize;
-                        prev_vec.truncate(truncate_to)
+                        let truncate_to = prev_vec.len() - overlap as usize;
+                        prev_vec.truncate(truncate_to);
+                        dropped += overlap;
                     }
                     qtrace!(
                         "New frame {}-{} received, overlap: {}",
@@ -102,6 +116,7 @@ impl RxStreamOrderer {
                     // PPPP      ->  NNNNNN
                     // NNNNNN
                     // Drop Prev, Insert New
+                    dropped += prev_end - prev_start;
                     qtrace!(
                         "New frame with {}-{} replaces existing {}-{}",
                         new_start,
@@ -159,6 +174,7 @@ impl RxStreamOrderer {
                         next_start,
                         next_end
                     );
+                    dropped += next_end - next_start;
                     to_remove.push(next_start);
                 }
             }
@@ -167,41 +183,33 @@ impl RxStreamOrderer {
                 self.data_ranges.remove(&start);
             }
 
+            self.unordered += new_data.len() as u64;
             self.data_ranges.insert(new_start, new_data);
         };
+        self.unordered -= dropped;
+        self.extend_contiguous();
 
         Ok(())
     }
 
+    /// Move any ranges that now touch the end of contiguous data to `ready`.
+    /// Ranges don't overlap, so the next one has to start right there.
+    fn extend_contiguous(&mut self) {
+        while let Some(data) = self.data_ranges.remove(&self.contiguous) {
+            self.contiguous += data.len() as u64;
+            self.unordered -= data.len() as u64;
+            self.ready.append(data);
+        }
+    }
+
     /// Are any bytes readable?
     pub fn data_ready(&self) -> bool {
-        self.data_ranges
-            .keys()
-            .next()
-            .map_or(false, |&start| start <= self.retired)
+        !self.ready.is_empty()
     }
 
     /// How many bytes are readable?
     fn bytes_ready(&self) -> usize {
-        let mut prev_end = self.retired;
-        self.data_ranges
-            .iter()
-            .map(|(start_offset, data)| {
-                // All ranges don't overlap but we could have partially
-                // retired some of the first entry's data.
-                let data_len = data.len() as u64 - self.retired.saturating_sub(*start_offset);
-                (start_offset, data_len)
-            })
-            .take_while(|(start_offset, data_len)| {
-                if **start_offset <= prev_end {
-                    prev_end += data_len;
-                    true
-                } else {
-                    false
-                }
-            })
-            .map(|(_, data_len)| data_len as usize)
-            .sum()
+        self.ready.len()
     }
 
     /// Bytes read by the application.
@@ -211,49 +219,16 @@ impl RxStreamOrderer {
 
     /// Data bytes buffered. Could be more than bytes_readable if there are
     /// ranges missing.
-    fn buffered(&self) -> u64 {
-        self.data_ranges
-            .iter()
-            .map(|(&start, data)| data.len() as u64 - (self.retired.saturating_sub(start)))
-            .sum()
+    pub fn buffered(&self) -> u64 {
+        self.ready.len() as u64 + self.unordered
     }
 
     /// Copy received data (if any) into the buffer. Returns bytes copied.
     fn read(&mut self, buf: &mut [u8]) -> Res<u64> {
         qtrace!("Reading {} bytes, {} available", buf.len(), self.buffered());
-        let mut buf_remaining = buf.len() as usize;
-        let mut copied = 0;
-
-        for (&range_start, range_data) in &mut self.data_ranges {
-            if self.retired >= range_start {
-                // Frame data has some new contig bytes after some old bytes
-
-                // Convert to offset into data vec and move past bytes we
-                // already have
-                let copy_offset = (max(range_start, self.retired) - range_start).try_into()?;
-                let copy_bytes = min(range_data.len() - copy_offset, buf_remaining);
-                let copy_slc = &mut range_data[copy_offset..copy_offset + copy_bytes];
-                buf[copied..copied + copy_bytes].copy_from_slice(copy_slc);
-                copied += copy_bytes;
-                buf_remaining -= copy_bytes;
-                self.retired += copy_bytes as u64;
-            } else {
-                break; // we're missing bytes
-            }
-        }
-
-        // Remove map items that are consumed
-        let to_remove = self
-            .data_ranges
-            .iter()
-            .take_while(|(start, data)| self.retired >= *start + data.len() as u64)
-            .map(|(k, _)| *k)
-            .collect::<Vec<_>>();
-        for key in to_remove {
-            self.data_ranges.remove(&key);
-        }
-
-        Ok(copied as u64)
+        let copied = self.ready.read(buf) as u64;
+        self.retired += copied;
+        Ok(copied)
     }
 
     /// Extend the given Vector with any available data.
@@ -269,7 +244,7 @@ impl RxStreamOrderer {
             .iter()
             .next_back()
             .map(|(start, data)| *start + data.len() as u64);
-        maybe_ooo_last.unwrap_or(self.retired)
+        maybe_ooo_last.unwrap_or(self.contiguous)
     }
 }
 
@@ -425,7 +400,18 @@ impl RecvStream {
         Ok(())
     }
 
-    pub fn reset(&mut self, application_error_code: AppError) {
+    pub fn reset(&mut self, application_error_code: AppError, final_size: u64) -> Res<()> {
+        // The final size can't change, or be less than what has been received
+        // (-transport 4.4).
+        let bad_size = match &self.state {
+            RecvStreamState::Recv { recv_buf, .. } => final_size < recv_buf.highest_seen_offset(),
+            RecvStreamState::SizeKnown { final_size: f, .. } => final_size != *f,
+            _ => false,
+        };
+        if bad_size {
+            return Err(Error::FinalSizeError);
+        }
+
         match self.state {
             RecvStreamState::Recv { .. } | RecvStreamState::SizeKnown { .. } => {
                 self.conn_events
@@ -436,10 +422,15 @@ impl RecvStream {
                 // Ignore reset if in DataRecvd, DataRead, or ResetRecvd
             }
         }
+        Ok(())
     }
 
     /// If we should tell the sender they have more credit, return an offset
     pub fn maybe_send_flowc_update(&mut self) {
+        if self.flow_mgr.borrow().rx_clamped() {
+            // Credit is held back until memory use goes down.
+            return;
+        }
         if let RecvStreamState::Recv {
             max_bytes,
             max_stream_data,
@@ -470,6 +461,11 @@ impl RecvStream {
         )
     }
 
+    /// Bytes that were received and not yet read.
+    pub fn buffered(&self) -> u64 {
+        self.state.recv_buf().map_or(0, RxStreamOrderer::buffered)
+    }
+
     // App got all data but did not get the fin signal.
     fn needs_to_inform_app_about_fin(&self) -> bool {
         matches!(self.state, RecvStreamState::DataRecvd { .. })
@@ -580,59 +576,62 @@ mod tests {
 
         let mut buf = vec![0u8; 100];
 
-        // test receiving a contig frame and reading it works
-        s.inbound_stream_frame(false, 0, vec![1; 6]).unwrap();
+        // Leave a gap at the start, so that the frames below stay out of order.
+        s.inbound_stream_frame(false, 10, vec![1; 6]).unwrap();
 
         // See inbound_frame(). Test (true, true) case
-        s.inbound_stream_frame(false, 2, vec![2; 6]).unwrap();
+        s.inbound_stream_frame(false, 12, vec![2; 6]).unwrap();
         {
             let mut i = s.state.recv_buf().unwrap().data_ranges.iter();
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 0);
+            assert_eq!(*item.0, 10);
             assert_eq!(item.1.len(), 2);
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 2);
+            assert_eq!(*item.0, 12);
             assert_eq!(item.1.len(), 6);
         }
 
         // Test (true, false) case
-        s.inbound_stream_frame(false, 4, vec![3; 4]).unwrap();
+        s.inbound_stream_frame(false, 14, vec![3; 4]).unwrap();
         {
             let mut i = s.state.recv_buf().unwrap().data_ranges.iter();
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 0);
+            assert_eq!(*item.0, 10);
             assert_eq!(item.1.len(), 2);
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 2);
+            assert_eq!(*item.0, 12);
             assert_eq!(item.1.len(), 6);
         }
 
         // Test (false, true) case
-        s.inbound_stream_frame(false, 2, vec![4; 8]).unwrap();
+        s.inbound_stream_frame(false, 12, vec![4; 8]).unwrap();
         {
             let mut i = s.state.recv_buf().unwrap().data_ranges.iter();
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 0);
+            assert_eq!(*item.0, 10);
             assert_eq!(item.1.len(), 2);
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 2);
+            assert_eq!(*item.0, 12);
             assert_eq!(item.1.len(), 8);
         }
 
         // Test (false, false) case
-        s.inbound_stream_frame(false, 2, vec![5; 2]).unwrap();
+        s.inbound_stream_frame(false, 12, vec![5; 2]).unwrap();
         {
             let mut i = s.state.recv_buf().unwrap().data_ranges.iter();
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 0);
+            assert_eq!(*item.0, 10);
             assert_eq!(item.1.len(), 2);
             let item = i.next().unwrap();
-            assert_eq!(*item.0, 2);
+            assert_eq!(*item.0, 12);
             assert_eq!(item.1.len(), 8);
         }
 
-        assert_eq!(s.read(&mut buf).unwrap(), (10, false));
-        assert_eq!(buf[..10], [1, 1, 4, 4, 4, 4, 4, 4, 4, 4]);
+        // Filling the gap puts everything in order.
+        s.inbound_stream_frame(false, 0, vec![0; 10]).unwrap();
+        assert!(s.state.recv_buf().unwrap().data_ranges.is_empty());
+        assert_eq!(s.read(&mut buf).unwrap(), (20, false));
+        assert_eq!(buf[10..20], [1, 1, 4, 4, 4, 4, 4, 4, 4, 4]);
 
         // Test truncation/span-drop on insert
         s.inbound_stream_frame(false, 100, vec![6; 6]).unwrap();
@@ -696,6 +695,31 @@ mod tests {
         assert_eq!(s.flow_mgr.borrow().peek(), None);
     }
 
+    #[test]
+    fn test_stream_flowc_clamped() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = RecvStream::new(
+            4.into(),
+            RX_STREAM_DATA_WINDOW,
+            Rc::clone(&flow_mgr),
+            ConnectionEvents::default(),
+        );
+
+        flow_mgr.borrow_mut().set_rx_clamped(true);
+        s.inbound_stream_frame(false, 0, vec![0; RX_STREAM_DATA_WINDOW as usize])
+            .unwrap();
+        assert_eq!(s.buffered(), RX_STREAM_DATA_WINDOW);
+        let mut buf = vec![0u8; RX_STREAM_DATA_WINDOW as usize];
+        assert_eq!(s.read(&mut buf).unwrap(), (RX_STREAM_DATA_WINDOW, false));
+        assert_eq!(s.buffered(), 0);
+        // No credit while clamped.
+        assert_eq!(flow_mgr.borrow().peek(), None);
+
+        flow_mgr.borrow_mut().set_rx_clamped(false);
+        s.maybe_send_flowc_update();
+        assert!(flow_mgr.borrow().peek().is_some());
+    }
+
     #[test]
     fn test_stream_max_stream_data() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
@@ -717,6 +741,25 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn test_stream_reset_final_size() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let conn_events = ConnectionEvents::default();
+
+        let mut s = RecvStream::new(4.into(), 1024, Rc::clone(&flow_mgr), conn_events);
+        s.inbound_stream_frame(false, 0, vec![1; 10]).unwrap();
+        // Less than has been received.
+        assert_eq!(s.reset(0, 5), Err(Error::FinalSizeError));
+        s.reset(0, 10).unwrap();
+        assert!(s.is_terminal());
+
+        let mut s = RecvStream::new(8.into(), 1024, flow_mgr, ConnectionEvents::default());
+        s.inbound_stream_frame(true, 5, vec![1; 5]).unwrap();
+        // Different from the size that the FIN set.
+        assert_eq!(s.reset(0, 11), Err(Error::FinalSizeError));
+        s.reset(0, 10).unwrap();
+    }
+
     #[test]
     fn test_stream_orderer_bytes_ready() {
         let mut rx_ord = RxStreamOrderer::new();
@@ -748,4 +791,54 @@ mod tests {
         assert_eq!(rx_ord.buffered(), 15);
         assert_eq!(rx_ord.retired(), 2);
     }
+
+    #[test]
+    fn test_stream_orderer_many_gaps() {
+        const COUNT: u64 = 10_000;
+        let mut rx_ord = RxStreamOrderer::new();
+
+        // Every other byte, arriving from the end backwards.
+        for i in (0..COUNT).rev() {
+            rx_ord.inbound_frame(i * 2 + 1, vec![1]).unwrap();
+        }
+        assert_eq!(rx_ord.bytes_ready(), 0);
+        assert_eq!(rx_ord.buffered(), COUNT);
+
+        // Fill the gaps, also backwards.
+        for i in (1..COUNT).rev() {
+            rx_ord.inbound_frame(i * 2, vec![0]).unwrap();
+        }
+        assert_eq!(rx_ord.bytes_ready(), 0);
+        rx_ord.inbound_frame(0, vec![0]).unwrap();
+        assert_eq!(rx_ord.bytes_ready(), COUNT as usize * 2);
+        assert_eq!(rx_ord.buffered(), COUNT * 2);
+
+        let mut buf = vec![0u8; COUNT as usize * 2];
+        assert_eq!(rx_ord.read(&mut buf).unwrap(), COUNT * 2);
+        assert!(buf.iter().enumerate().all(|(i, &v)| v == (i % 2) as u8));
+        assert_eq!(rx_ord.buffered(), 0);
+        assert!(!rx_ord.data_ready());
+    }
+
+    #[test]
+    fn test_stream_orderer_overlap_read() {
+        let mut rx_ord = RxStreamOrderer::new();
+        let mut buf = vec![0u8; 100];
+
+        rx_ord.inbound_frame(0, vec![1; 10]).unwrap();
+        // Overlapping the end of a longer frame only drops the overlap.
+        rx_ord.inbound_frame(8, vec![2; 4]).unwrap();
+        assert_eq!(rx_ord.bytes_ready(), 12);
+        assert_eq!(rx_ord.buffered(), 12);
+
+        assert_eq!(rx_ord.read(&mut buf[..9]).unwrap(), 9);
+        // Data that starts before what has been read.
+        rx_ord.inbound_frame(5, vec![3; 10]).unwrap();
+        assert_eq!(rx_ord.bytes_ready(), 6);
+        assert_eq!(rx_ord.buffered(), 6);
+        assert_eq!(rx_ord.read(&mut buf).unwrap(), 6);
+        // Bytes that were already in order aren't replaced.
+        assert_eq!(&buf[..6], &[1, 2, 2, 3, 3, 3]);
+        assert_eq!(rx_ord.retired(), 15);
+    }
 }
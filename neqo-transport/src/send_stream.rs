@@ -8,18 +8,18 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::collections::{hash_map::IterMut, BTreeMap, HashMap, VecDeque};
+use std::collections::{hash_map::IterMut, BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::mem;
 use std::rc::Rc;
 
 use smallvec::SmallVec;
 
-use neqo_common::{matches, qdebug, qerror, qinfo, qtrace};
+use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, ChunkedBuffer};
 
 use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
-use crate::frame::{Frame, TxMode};
+use crate::frame::{TxFrame, TxMode};
 use crate::recovery::RecoveryToken;
 use crate::stream_id::StreamId;
 use crate::{AppError, Error, Res};
@@ -271,29 +271,28 @@ impl RangeTracker {
 }
 
 /// Buffer to contain queued bytes and track their state.
+/// Bytes are held in a rope of chunks, so neither adding bytes nor dropping
+/// the ones that are acked moves the bytes that are already buffered.
 #[derive(Debug, Default, PartialEq)]
 pub struct TxBuffer {
-    retired: u64,           // contig acked bytes, no longer in buffer
-    send_buf: VecDeque<u8>, // buffer of not-acked bytes
-    ranges: RangeTracker,   // ranges in buffer that have been sent or acked
+    retired: u64,          // contig acked bytes, no longer in buffer
+    chunks: ChunkedBuffer, // buffer of not-acked bytes
+    ranges: RangeTracker,  // ranges in buffer that have been sent or acked
 }
 
 impl TxBuffer {
     const BUFFER_SIZE: usize = 0xFFFF; // 64 KiB
 
     pub fn new() -> Self {
-        Self {
-            send_buf: VecDeque::with_capacity(TxBuffer::BUFFER_SIZE),
-            ..Self::default()
-        }
+        Self::default()
     }
 
     /// Attempt to add some or all of the passed-in buffer to the TxBuffer.
     pub fn send(&mut self, buf: &[u8]) -> usize {
         let can_buffer = min(TxBuffer::BUFFER_SIZE - self.buffered(), buf.len());
         if can_buffer > 0 {
-            self.send_buf.extend(&buf[..can_buffer]);
-            assert!(self.send_buf.len() <= TxBuffer::BUFFER_SIZE);
+            self.chunks.extend_from_slice(&buf[..can_buffer]);
+            assert!(self.buffered() <= TxBuffer::BUFFER_SIZE);
         }
         can_buffer
     }
@@ -308,28 +307,26 @@ impl TxBuffer {
                 }
 
                 let buff_off = usize::try_from(start - self.retired).unwrap();
+                let bytes = self.chunks.chunk_at(buff_off);
                 match maybe_len {
                     Some(len) => {
-                        let len = min(len, self.send_buf.as_slices().0.len().try_into().unwrap());
-                        Some((
-                            start,
-                            &self.send_buf.as_slices().0
-                                [buff_off..buff_off + usize::try_from(len).unwrap()],
-                        ))
+                        let len = min(len, bytes.len().try_into().unwrap());
+                        Some((start, &bytes[..usize::try_from(len).unwrap()]))
                     }
-                    None => Some((start, &self.send_buf.as_slices().0[buff_off..])),
-                }
-            }
-            TxMode::Pto => {
-                if self.buffered() == 0 {
-                    None
-                } else {
-                    Some((self.retired, &self.send_buf.as_slices().0))
+                    None => Some((start, bytes)),
                 }
             }
+            TxMode::Pto => self.chunks.slices().next().map(|c| (self.retired, c)),
         }
     }
 
+    /// The `len` bytes at `offset`.  These have to be in a single chunk,
+    /// which is true of anything that `next_bytes` returns.
+    pub fn bytes(&self, offset: u64, len: usize) -> &[u8] {
+        let buff_off = usize::try_from(offset - self.retired).unwrap();
+        &self.chunks.chunk_at(buff_off)[..len]
+    }
+
     pub fn mark_as_sent(&mut self, offset: u64, len: usize) {
         self.ranges.mark_range(offset, len, RangeState::Sent)
     }
@@ -339,12 +336,8 @@ impl TxBuffer {
 
         // We can drop contig acked range from the buffer
         let new_retirable = self.ranges.acked_from_zero() - self.retired;
-        let keep_len =
-            self.buffered() - usize::try_from(new_retirable).expect("should fit in usize");
-
-        // Truncate front
-        self.send_buf.rotate_left(self.buffered() - keep_len);
-        self.send_buf.truncate(keep_len);
+        let retire = usize::try_from(new_retirable).expect("should fit in usize");
+        self.chunks.consume(retire);
 
         self.retired += new_retirable;
     }
@@ -357,8 +350,8 @@ impl TxBuffer {
         self.buffered() as u64 + self.retired
     }
 
-    fn buffered(&self) -> usize {
-        self.send_buf.len()
+    pub fn buffered(&self) -> usize {
+        self.chunks.len()
     }
 
     fn avail(&self) -> usize {
@@ -512,6 +505,13 @@ impl SendStream {
         }
     }
 
+    /// The `len` bytes at `offset`, from a range that `next_bytes` returned.
+    pub fn bytes(&self, offset: u64, len: usize) -> &[u8] {
+        self.state
+            .tx_buf()
+            .map_or(&[], |buf| buf.bytes(offset, len))
+    }
+
     pub fn mark_as_sent(&mut self, offset: u64, len: usize, fin: bool) {
         if let Some(buf) = self.state.tx_buf_mut() {
             buf.mark_as_sent(offset, len);
@@ -596,6 +596,11 @@ impl SendStream {
         self.max_stream_data
     }
 
+    /// Bytes held in the send buffer.
+    pub fn buffered(&self) -> usize {
+        self.state.tx_buf().map_or(0, TxBuffer::buffered)
+    }
+
     pub fn set_max_stream_data(&mut self, value: u64) {
         let stream_was_blocked = self.avail() == 0;
         self.max_stream_data = max(self.max_stream_data, value);
@@ -757,12 +762,17 @@ impl SendStreams {
         self.0.retain(|_, stream| !stream.is_terminal())
     }
 
+    /// Bytes held in the send buffers of all streams.
+    pub fn buffered(&self) -> usize {
+        self.0.values().map(SendStream::buffered).sum()
+    }
+
     pub(crate) fn get_frame(
         &mut self,
         epoch: u16,
         mode: TxMode,
         remaining: usize,
-    ) -> Option<(Frame, Option<RecoveryToken>)> {
+    ) -> Option<(TxFrame, Option<RecoveryToken>)> {
         if epoch != 3 && epoch != 1 {
             return None;
         }
@@ -771,7 +781,7 @@ impl SendStreams {
             let complete = stream.final_size().is_some();
             if let Some((offset, data)) = stream.next_bytes(mode) {
                 if let Some((frame, length)) =
-                    Frame::new_stream(stream_id.as_u64(), offset, data, complete, remaining)
+                    TxFrame::new_stream(stream_id.as_u64(), offset, data, complete, remaining)
                 {
                     qdebug!(
                         "Stream {} sending bytes {}-{}, epoch {}, mode {:?}",
@@ -782,11 +792,20 @@ impl SendStreams {
                         mode,
                     );
                     let fin = complete && length == data.len();
-                    debug_assert!(!fin || matches!(frame, Frame::Stream{fin: true, .. }));
+                    debug_assert!(!fin || matches!(frame, TxFrame::Stream{fin: true, .. }));
+                    let fill = matches!(frame, TxFrame::Stream { fill: true, .. });
                     stream.mark_as_sent(offset, length, fin);
 
+                    // Marking the data as sent needs the stream to be mutable,
+                    // so the frame takes the same bytes from the stream again.
                     return Some((
-                        frame,
+                        TxFrame::Stream {
+                            fin,
+                            stream_id: *stream_id,
+                            offset,
+                            data: stream.bytes(offset, length),
+                            fill,
+                        },
                         Some(RecoveryToken::Stream(StreamRecoveryToken {
                             id: *stream_id,
                             offset,
@@ -821,8 +840,9 @@ pub struct StreamRecoveryToken {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::VecDeque;
 
-    use neqo_common::matches;
+    use neqo_common::{matches, DEFAULT_CHUNK_SIZE};
 
     use crate::events::ConnectionEvent;
 
@@ -960,6 +980,38 @@ mod tests {
         assert_eq!(res, None);
     }
 
+    #[test]
+    fn tx_buffer_chunks() {
+        const CHUNK_SIZE: usize = DEFAULT_CHUNK_SIZE;
+        let mut tx = TxBuffer::new();
+        assert_eq!(tx.send(&[1; CHUNK_SIZE - 100]), CHUNK_SIZE - 100);
+        assert_eq!(tx.send(&[1; 100]), 100);
+        assert_eq!(tx.send(&[2; 100]), 100);
+        assert_eq!(tx.chunks.chunk_count(), 2);
+
+        // Data is sent from one chunk at a time.
+        let (offset, bytes) = tx.next_bytes(TxMode::Normal).unwrap();
+        assert_eq!((offset, bytes.len()), (0, CHUNK_SIZE));
+        tx.mark_as_sent(0, 1000);
+        let (offset, bytes) = tx.next_bytes(TxMode::Normal).unwrap();
+        assert_eq!((offset, bytes.len()), (1000, CHUNK_SIZE - 1000));
+        tx.mark_as_sent(1000, CHUNK_SIZE - 1000);
+        let (offset, bytes) = tx.next_bytes(TxMode::Normal).unwrap();
+        assert_eq!(offset, u64::try_from(CHUNK_SIZE).unwrap());
+        assert_eq!(bytes, &[2; 100][..]);
+
+        // Acking part of a chunk leaves the rest in place.
+        tx.mark_as_acked(0, 1000);
+        assert_eq!(tx.buffered(), CHUNK_SIZE - 1000 + 100);
+        assert_eq!(tx.chunks.chunk_at(0).len(), CHUNK_SIZE - 1000);
+        let (offset, bytes) = tx.next_bytes(TxMode::Pto).unwrap();
+        assert_eq!((offset, bytes.len()), (1000, CHUNK_SIZE - 1000));
+
+        tx.mark_as_acked(1000, CHUNK_SIZE - 1000);
+        assert_eq!(tx.chunks.chunk_count(), 1);
+        assert_eq!(tx.buffered(), 100);
+    }
+
     #[test]
     fn send_stream_writable_event_gen() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
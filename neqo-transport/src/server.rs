@@ -7,15 +7,19 @@
 // This file implements a server that can handle multiple connections.
 
 use neqo_common::{
-    hex, matches, qerror, qinfo, qtrace, qwarn, timer::Timer, Datagram, Decoder, Encoder,
+    hex, matches, qerror, qinfo, qtrace, qwarn,
+    timer::{Timer, TimerHandle},
+    Datagram, Decoder, Encoder,
 };
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
     selfencrypt::SelfEncrypt,
-    AntiReplay,
+    AlpnSelector, AntiReplay, CertificateSelector, KeyLog,
 };
 
-use crate::connection::{Connection, ConnectionIdManager, Output, State};
+use crate::connection::{
+    Connection, ConnectionIdManager, FixedConnectionIdManager, Output, State, TicketPolicy,
+};
 use crate::packet::{
     decode_packet_hdr, encode_packet_vn, encode_retry, ConnectionId, ConnectionIdDecoder,
     PacketHdr, PacketType, Version,
@@ -23,6 +27,7 @@ use crate::packet::{
 use crate::{Res, QUIC_VERSION};
 
 use std::cell::RefCell;
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::mem;
@@ -50,7 +55,12 @@ type ConnectionTableRef = Rc<RefCell<HashMap<ConnectionId, StateRef>>>;
 #[derive(Debug)]
 pub struct ServerConnectionState {
     c: Connection,
-    last_timer: Instant,
+    /// The timer that is set for the connection, if any.
+    timer: Option<TimerHandle>,
+    /// The connection ID that the client picked for its Initial packets,
+    /// until the handshake is done.  A ClientHello can take more than one
+    /// packet, and the client uses this until it hears from the server.
+    initial_cid: Option<ConnectionId>,
 }
 
 impl Deref for ServerConnectionState {
@@ -132,6 +142,12 @@ impl RetryToken {
         self.require_retry = retry;
     }
 
+    /// Use a new key for tokens.  Tokens made with the key before are still
+    /// accepted, until the key is rotated again.
+    pub fn rotate(&mut self) -> Res<()> {
+        Ok(self.self_encrypt.rotate()?)
+    }
+
     /// Decrypts `token` and returns the connection Id it contains.
     /// Returns `None` if the date is invalid in any way (such as it being expired or garbled).
     fn decrypt_token(
@@ -183,6 +199,24 @@ impl RetryToken {
     }
 }
 
+/// What a server is holding, for monitoring.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ServerStats {
+    /// Connections, including those that are closing.
+    pub connections: usize,
+    /// Connections with events that haven't been taken from
+    /// `active_connections`.
+    pub active: usize,
+    /// Connections that are waiting to send.
+    pub waiting: usize,
+    /// Timers that are set.
+    pub timers: usize,
+    /// Events that haven't been taken, on all connections.
+    pub events: usize,
+    /// The memory that all connections are using.
+    pub memory: usize,
+}
+
 pub struct Server {
     /// The version this server supports (currently just one).
     version: Version,
@@ -204,6 +238,15 @@ pub struct Server {
     /// Whether a Retry packet will be sent in response to new
     /// Initial packets.
     retry: RetryToken,
+    /// Where every connection logs its TLS secrets, if anywhere.
+    key_log: Option<Rc<RefCell<dyn KeyLog>>>,
+    /// How every connection sends session tickets.
+    ticket_policy: TicketPolicy,
+    /// What chooses the ALPN for every connection, if `protocols` doesn't.
+    alpn_selector: Option<Rc<dyn AlpnSelector>>,
+    /// What chooses the certificate for every connection, if the name that
+    /// the client asks for isn't served by `certs`.
+    certificate_selector: Option<Rc<dyn CertificateSelector>>,
 }
 
 impl Server {
@@ -232,6 +275,10 @@ impl Server {
             waiting: VecDeque::default(),
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
             retry: RetryToken::new(now)?,
+            key_log: None,
+            ticket_policy: TicketPolicy::default(),
+            alpn_selector: None,
+            certificate_selector: None,
         })
     }
 
@@ -253,9 +300,60 @@ impl Server {
         self.retry.set_retry_required(require_retry);
     }
 
+    /// The anti-replay context that new connections use, which can be
+    /// changed with `AntiReplay::reconfigure` while the server runs.
+    pub fn anti_replay_mut(&mut self) -> &mut AntiReplay {
+        &mut self.anti_replay
+    }
+
+    /// Rotate the keys that protect Retry tokens, without disturbing
+    /// connections.  Tokens from before this are still accepted until the
+    /// keys are rotated again.
+    pub fn rotate_keys(&mut self) -> Res<()> {
+        self.retry.rotate()
+    }
+
+    /// Have each new connection tell `log` about its TLS secrets.
+    pub fn set_key_log(&mut self, log: Rc<RefCell<dyn KeyLog>>) {
+        self.key_log = Some(log);
+    }
+
+    /// Have each new connection send session tickets according to `policy`.
+    pub fn set_ticket_policy(&mut self, policy: TicketPolicy) {
+        self.ticket_policy = policy;
+    }
+
+    /// Have `selector` choose the application protocol for each new
+    /// connection, instead of `protocols`, so that one server can speak
+    /// several.
+    pub fn set_alpn_selector(&mut self, selector: Rc<dyn AlpnSelector>) {
+        self.alpn_selector = Some(selector);
+    }
+
+    /// Have `selector` choose the certificate for each new connection from
+    /// the name that the client asks for, so that one server can have many
+    /// names.
+    pub fn set_certificate_selector(&mut self, selector: Rc<dyn CertificateSelector>) {
+        self.certificate_selector = Some(selector);
+    }
+
     fn remove_timer(&mut self, c: &StateRef) {
-        let last = c.borrow().last_timer;
-        self.timers.remove(last, |t| Rc::ptr_eq(t, c));
+        let timer = c.borrow_mut().timer.take();
+        if let Some(h) = timer {
+            self.timers.cancel(h);
+        }
+    }
+
+    fn set_timer(&mut self, c: &StateRef, next: Instant) {
+        let timer = c.borrow().timer;
+        let moved = match timer {
+            Some(h) if h.time() == next => return,
+            Some(h) => self.timers.reschedule(h, next),
+            None => None,
+        };
+        qtrace!([self], "Change timer to {:?}", next);
+        let h = moved.unwrap_or_else(|| self.timers.add(next, c.clone()));
+        c.borrow_mut().timer = Some(h);
     }
 
     fn process_connection(
@@ -271,15 +369,7 @@ impl Server {
                 qtrace!([self], "Sending packet, added to waiting connections");
                 self.waiting.push_back(c.clone());
             }
-            Output::Callback(delay) => {
-                let next = now + delay;
-                if next != c.borrow().last_timer {
-                    qtrace!([self], "Change timer to {:?}", next);
-                    self.remove_timer(&c);
-                    c.borrow_mut().last_timer = next;
-                    self.timers.add(next, c.clone());
-                }
-            }
+            Output::Callback(delay) => self.set_timer(&c, now + delay),
             _ => {
                 self.remove_timer(&c);
             }
@@ -292,6 +382,11 @@ impl Server {
             self.connections
                 .borrow_mut()
                 .retain(|_, v| !Rc::ptr_eq(v, &c));
+        } else if *c.borrow().state() == State::Connected {
+            let initial_cid = c.borrow_mut().initial_cid.take();
+            if let Some(cid) = initial_cid {
+                self.connections.borrow_mut().remove(&cid);
+            }
         }
         out.dgram()
     }
@@ -312,8 +407,10 @@ impl Server {
     ) -> Option<Datagram> {
         match self.retry.validate(&hdr, dgram.source(), now) {
             RetryTokenResult::Invalid => None,
-            RetryTokenResult::Pass => self.accept_connection(None, dgram, now),
-            RetryTokenResult::Valid(dcid) => self.accept_connection(Some(dcid), dgram, now),
+            RetryTokenResult::Pass => self.accept_connection(None, hdr.dcid, dgram, now),
+            RetryTokenResult::Valid(odcid) => {
+                self.accept_connection(Some(odcid), hdr.dcid, dgram, now)
+            }
             RetryTokenResult::Validate => {
                 qinfo!([self], "Send retry for {:?}", hdr.dcid);
 
@@ -345,6 +442,7 @@ impl Server {
     fn accept_connection(
         &mut self,
         odcid: Option<ConnectionId>,
+        initial_cid: ConnectionId,
         dgram: Datagram,
         now: Instant,
     ) -> Option<Datagram> {
@@ -366,8 +464,36 @@ impl Server {
             if let Some(odcid) = odcid {
                 c.original_connection_id(&odcid);
             }
-            let c = Rc::new(RefCell::new(ServerConnectionState { c, last_timer: now }));
+            if let Some(log) = &self.key_log {
+                c.set_key_log(Rc::clone(log));
+            }
+            if c.set_ticket_policy(self.ticket_policy).is_err() {
+                qwarn!([self], "Unable to set the ticket policy");
+            }
+            if let Some(selector) = &self.alpn_selector {
+                if c.set_alpn_selector(Box::new(Rc::clone(selector))).is_err() {
+                    qwarn!([self], "Unable to set the ALPN selector");
+                }
+            }
+            if let Some(selector) = &self.certificate_selector {
+                if c.set_certificate_selector(Box::new(Rc::clone(selector)))
+                    .is_err()
+                {
+                    qwarn!([self], "Unable to set the certificate selector");
+                }
+            }
+            let c = Rc::new(RefCell::new(ServerConnectionState {
+                c,
+                timer: None,
+                initial_cid: None,
+            }));
             cid_mgr.borrow_mut().c = Some(c.clone());
+            // Route the rest of the client's Initial packets here, unless the
+            // connection ID is taken already.
+            if let Entry::Vacant(e) = self.connections.borrow_mut().entry(initial_cid.clone()) {
+                e.insert(c.clone());
+                c.borrow_mut().initial_cid = Some(initial_cid);
+            }
             self.process_connection(c, Some(dgram), now)
         } else {
             qwarn!([self], "Unable to create connection");
@@ -423,6 +549,7 @@ impl Server {
         }
         qtrace!([self], "No packet to send still, run timers");
         while let Some(c) = self.timers.take_next(now) {
+            c.borrow_mut().timer = None;
             if let Some(d) = self.process_connection(c, None, now) {
                 return Some(d);
             }
@@ -474,6 +601,28 @@ impl Server {
     pub fn add_to_waiting(&mut self, c: ActiveConnectionRef) {
         self.waiting.push_back(c.connection());
     }
+
+    pub fn stats(&self) -> ServerStats {
+        // Each connection is in the table once for each connection ID.
+        let connections = self.connections.borrow();
+        let mut seen = HashSet::new();
+        let mut stats = ServerStats {
+            active: self.active.len(),
+            waiting: self.waiting.len(),
+            timers: self.timers.len(),
+            ..ServerStats::default()
+        };
+        for c in connections.values() {
+            let ptr: *const _ = c.as_ref();
+            if seen.insert(ptr) {
+                let c = c.borrow();
+                stats.connections += 1;
+                stats.events += c.pending_events();
+                stats.memory += c.memory_usage().total();
+            }
+        }
+        stats
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -539,6 +688,81 @@ impl ConnectionIdManager for ServerConnectionIdManager {
     }
 }
 
+/// Makes connection IDs that say which of a number of servers, or shards, has
+/// the connection.  The first byte of each connection ID identifies the shard.
+/// Give each shard a manager with a different shard number, and use a
+/// `ShardRouter` to find the shard for each datagram.  Shards don't share any
+/// state, so each can be run on its own thread.
+///
+/// Each shard needs its own anti-replay context, so a 0-RTT replay that is
+/// routed to a different shard will not be detected.
+#[derive(Debug)]
+pub struct ShardedConnectionIdManager {
+    len: usize,
+    shard: usize,
+    shards: usize,
+}
+
+impl ShardedConnectionIdManager {
+    /// Make connection IDs of `len` bytes for shard number `shard`, out of
+    /// `shards` shards.  There can be at most 256 shards.
+    pub fn new(len: usize, shard: usize, shards: usize) -> Self {
+        assert!(len > 0, "sharded connection IDs can't be empty");
+        assert!(shards <= 256, "too many shards");
+        assert!(shard < shards, "shard number out of range");
+        Self { len, shard, shards }
+    }
+}
+
+impl ConnectionIdDecoder for ShardedConnectionIdManager {
+    fn decode_cid(&self, dec: &mut Decoder) -> Option<ConnectionId> {
+        dec.decode(self.len).map(ConnectionId::from)
+    }
+}
+
+impl ConnectionIdManager for ShardedConnectionIdManager {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut cid = ConnectionId::generate(self.len);
+        // Keep as much of the random value as possible, in a byte that
+        // `ShardRouter` maps to this shard.
+        let range = 256 / self.shards;
+        let first = usize::from(cid.0[0]) % range * self.shards + self.shard;
+        cid.0[0] = u8::try_from(first).unwrap();
+        cid
+    }
+    fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
+        self
+    }
+}
+
+/// Finds which shard a datagram belongs to, for servers that use
+/// `ShardedConnectionIdManager`.  This only looks at the datagram, so it can
+/// be shared between threads freely.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardRouter {
+    len: usize,
+    shards: usize,
+}
+
+impl ShardRouter {
+    /// Route between `shards` shards that use connection IDs of `len` bytes.
+    pub fn new(len: usize, shards: usize) -> Self {
+        assert!(shards > 0 && shards <= 256, "bad number of shards");
+        Self { len, shards }
+    }
+
+    /// The shard for a datagram, or `None` if the datagram should be dropped.
+    /// A new connection goes to the shard that its first connection ID picks,
+    /// and that shard only gives out connection IDs that pick it too, so all
+    /// of the datagrams for a connection go the same way.
+    pub fn route(&self, dgram: &[u8]) -> Option<usize> {
+        let decoder = FixedConnectionIdManager::new(self.len);
+        let hdr = decode_packet_hdr(&decoder, dgram).ok()?;
+        let first = hdr.dcid.first().map_or(0, |b| usize::from(*b));
+        Some(first % self.shards)
+    }
+}
+
 impl ::std::fmt::Display for Server {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(f, "Server")
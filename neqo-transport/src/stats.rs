@@ -6,7 +6,13 @@
 
 // Tracking of some useful statistics.
 
+use neqo_common::metrics::{Counter, Registry};
+use neqo_common::Ecn;
+
+use std::time::Duration;
+
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Connection statistics
 pub struct Stats {
     /// Total packets received
@@ -15,4 +21,89 @@ pub struct Stats {
     pub packets_tx: u64,
     /// Duplicate packets received
     pub dups_rx: u64,
+    /// Packets that were declared lost
+    pub lost: u64,
+    /// The smoothed round trip time, as of the last acknowledgment
+    pub rtt: Duration,
+    /// The congestion window in bytes, as of the last acknowledgment or loss
+    pub cwnd: usize,
+    /// The ECN codepoints of packets received
+    pub ecn_rx: EcnCount,
+}
+
+#[derive(Default, Debug, Clone)]
+/// Counters that connections add to as they update their `Stats`, so that an
+/// embedder can collect totals over every connection from a `Registry`.
+pub struct ConnectionMetrics {
+    pub packets_rx: Counter,
+    pub packets_tx: Counter,
+    pub dups_rx: Counter,
+    pub lost: Counter,
+}
+
+impl ConnectionMetrics {
+    /// The counters in `registry`, which are shared with any connection that
+    /// uses the same registry.
+    pub fn register(registry: &Registry) -> Self {
+        Self {
+            packets_rx: registry.counter("neqo_packets_rx_total", "Packets received."),
+            packets_tx: registry.counter("neqo_packets_tx_total", "Packets sent."),
+            dups_rx: registry.counter("neqo_dups_rx_total", "Duplicate packets received."),
+            lost: registry.counter("neqo_lost_total", "Packets that were declared lost."),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// How many packets were received with each ECN-capable codepoint.  These are
+/// the counts that an ACK frame with ECN counts reports.
+pub struct EcnCount {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCount {
+    /// Count one packet.
+    pub fn add(&mut self, ecn: Ecn) {
+        match ecn {
+            Ecn::NotEct => {}
+            Ecn::Ect0 => self.ect0 += 1,
+            Ecn::Ect1 => self.ect1 += 1,
+            Ecn::Ce => self.ce += 1,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// An estimate of the memory that a connection is using, in bytes.
+pub struct MemoryUsage {
+    /// Data that is waiting to be sent or acknowledged, on all streams
+    pub send_buffers: usize,
+    /// Data that was received, but not yet read, on all streams
+    pub recv_buffers: usize,
+    /// Records of packets that are sent and not yet acknowledged or lost
+    pub sent_packets: usize,
+    /// Memory that the application protocol reports, like QPACK tables
+    pub app: usize,
+}
+
+impl MemoryUsage {
+    /// All of the memory in use.
+    pub fn total(&self) -> usize {
+        self.send_buffers + self.recv_buffers + self.sent_packets + self.app
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// What a connection does when it uses more memory than its budget.
+pub enum MemoryBudget {
+    /// Stop giving the peer more flow control credit until enough memory is
+    /// released.  The peer can still use the credit it already has.
+    ClampFlowControl(usize),
+    /// Close the connection.
+    Close(usize),
 }
@@ -43,6 +43,7 @@ pub mod tp_constants {
         MAX_ACK_DELAY = 11,
         DISABLE_MIGRATION = 12,
         PREFERRED_ADDRESS = 13,
+        MAX_DATAGRAM_FRAME_SIZE = 0x20,
     }
 }
 
@@ -99,7 +100,8 @@ impl TransportParameter {
             | INITIAL_MAX_STREAM_DATA_UNI
             | INITIAL_MAX_STREAMS_BIDI
             | INITIAL_MAX_STREAMS_UNI
-            | MAX_ACK_DELAY => match d.decode_varint() {
+            | MAX_ACK_DELAY
+            | MAX_DATAGRAM_FRAME_SIZE => match d.decode_varint() {
                 Some(v) => TransportParameter::Integer(v),
                 None => return Err(Error::TransportParameterError),
             },
@@ -182,7 +184,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
             | INITIAL_MAX_STREAMS_BIDI
-            | INITIAL_MAX_STREAMS_UNI => 0,
+            | INITIAL_MAX_STREAMS_UNI
+            | MAX_DATAGRAM_FRAME_SIZE => 0,
             MAX_PACKET_SIZE => 65527,
             ACK_DELAY_EXPONENT => 3,
             MAX_ACK_DELAY => 25,
@@ -207,7 +210,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAMS_UNI
             | MAX_PACKET_SIZE
             | ACK_DELAY_EXPONENT
-            | MAX_ACK_DELAY => {
+            | MAX_ACK_DELAY
+            | MAX_DATAGRAM_FRAME_SIZE => {
                 self.set(tipe, TransportParameter::Integer(value));
             }
             _ => panic!("Transport parameter not known"),
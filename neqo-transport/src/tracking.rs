@@ -6,17 +6,19 @@
 
 // Tracking of received packets and generating acks thereof.
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::ops::{Index, IndexMut};
 use std::time::{Duration, Instant};
 
-use neqo_common::{qdebug, qinfo, qtrace, qwarn};
+use neqo_common::{qdebug, qinfo, qtrace, qwarn, Ecn};
 use neqo_crypto::constants::Epoch;
+use smallvec::SmallVec;
 
-use crate::frame::{AckRange, Frame};
+use crate::frame::{AckRange, AckRanges, Frame};
 use crate::recovery::RecoveryToken;
+use crate::stats::EcnCount;
 
 // TODO(mt) look at enabling EnumMap for this: https://stackoverflow.com/a/44905797/1375574
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -134,7 +136,24 @@ const MAX_ACKS_PER_FRAME: usize = 32;
 #[derive(Debug, Clone)]
 pub struct AckToken {
     space: PNSpace,
-    ranges: Vec<PacketRange>,
+    ranges: SmallVec<[PacketRange; 4]>,
+}
+
+/// What the packets in a batch mean for when an ACK is sent.  This is only
+/// decided when the batch ends, so the order that the packets arrive in
+/// within the batch doesn't matter.
+#[derive(Debug)]
+struct AckBatch {
+    /// The packet number that was next in order when the batch started.
+    next_in_order_pn: u64,
+    /// The smallest packet number in the batch.
+    smallest: u64,
+    /// The largest packet number in the batch.
+    largest: u64,
+    /// The number of ack-eliciting packets in the batch.
+    ack_eliciting: usize,
+    /// Whether any packet in the batch was marked CE.
+    ce: bool,
 }
 
 /// A structure that tracks what packets have been received,
@@ -149,6 +168,10 @@ pub struct RecvdPackets {
     largest_pn_time: Option<Instant>,
     // The time that we should be sending an ACK.
     ack_time: Option<Instant>,
+    /// The ECN codepoints of the packets received, to report in ACK frames.
+    ecn_count: EcnCount,
+    /// The batch that packets are being received in, if any.
+    batch: Option<AckBatch>,
 }
 
 impl RecvdPackets {
@@ -160,6 +183,8 @@ impl RecvdPackets {
             min_tracked: 0,
             largest_pn_time: None,
             ack_time: None,
+            ecn_count: EcnCount::default(),
+            batch: None,
         }
     }
 
@@ -176,27 +201,43 @@ impl RecvdPackets {
         }
     }
 
-    // A simple addition of a packet number to the tracked set.
-    // This doesn't do a binary search on the assumption that
+    // Find the index of the first range that doesn't lie entirely above `pn`.
+    // Ranges are in descending order, so this is a binary search, except that
     // new packets will generally be added to the start of the list.
-    fn add(&mut self, pn: u64) -> usize {
-        for i in 0..self.ranges.len() {
-            if self.ranges[i].add(pn) {
-                // Maybe merge two ranges.
-                let nxt = i + 1;
-                if (nxt < self.ranges.len()) && (pn - 1 == self.ranges[nxt].largest) {
-                    let smaller = self.ranges.remove(nxt).unwrap();
-                    self.ranges[i].merge_smaller(&smaller);
-                }
-                return i;
+    fn find(&self, pn: u64) -> usize {
+        if self.ranges.front().map_or(true, |r| r.smallest <= pn) {
+            return 0;
+        }
+        let (mut low, mut high) = (1, self.ranges.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.ranges[mid].smallest > pn {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
-            if self.ranges[i].largest < pn {
-                self.ranges.insert(i, PacketRange::new(pn));
-                return i;
+        }
+        low
+    }
+
+    // Add a packet number to the tracked set, returning the index of the
+    // range that now holds it.
+    fn add(&mut self, pn: u64) -> usize {
+        let i = self.find(pn);
+        // The range before `i` is above `pn`, so `pn` can only extend it down.
+        if i > 0 && self.ranges[i - 1].add(pn) {
+            // Maybe merge two ranges.
+            if (i < self.ranges.len()) && (pn - 1 == self.ranges[i].largest) {
+                let smaller = self.ranges.remove(i).unwrap();
+                self.ranges[i - 1].merge_smaller(&smaller);
             }
+            return i - 1;
         }
-        self.ranges.push_back(PacketRange::new(pn));
-        self.ranges.len() - 1
+        if i < self.ranges.len() && self.ranges[i].add(pn) {
+            return i;
+        }
+        self.ranges.insert(i, PacketRange::new(pn));
+        i
     }
 
     /// Add the packet to the tracked set.
@@ -222,6 +263,15 @@ impl RecvdPackets {
             self.min_tracked = oldest.largest + 1;
         }
 
+        if let Some(batch) = &mut self.batch {
+            batch.smallest = min(batch.smallest, pn);
+            batch.largest = max(batch.largest, pn);
+            if ack_eliciting {
+                batch.ack_eliciting += 1;
+            }
+            return;
+        }
+
         if ack_eliciting {
             // Send ACK right away if out-of-order
             // On the first in-order ack-eliciting packet since sending an ACK,
@@ -236,18 +286,66 @@ impl RecvdPackets {
         }
     }
 
+    /// Count the ECN codepoint of a packet that `set_received` was given.  A
+    /// packet marked CE is acknowledged right away, if it needs one at all.
+    pub fn count_ecn(&mut self, now: Instant, ecn: Ecn) {
+        self.ecn_count.add(ecn);
+        if let Some(batch) = &mut self.batch {
+            batch.ce |= ecn == Ecn::Ce;
+        } else if ecn == Ecn::Ce && self.ack_time.is_some() {
+            self.ack_time = Some(now);
+        }
+    }
+
+    /// Start a batch.  Until `end_batch`, packets are tracked, but when to
+    /// send an ACK isn't decided.
+    fn start_batch(&mut self) {
+        self.batch = Some(AckBatch {
+            next_in_order_pn: self.ranges.front().map_or(0, |pr| pr.largest + 1),
+            smallest: u64::max_value(),
+            largest: 0,
+            ack_eliciting: 0,
+            ce: false,
+        });
+    }
+
+    /// Decide when to send an ACK for the packets in the batch.  The rules
+    /// are the ones that `set_received` uses, but for the batch as a whole:
+    /// it is in order if it follows on from the packets before it with no
+    /// gaps, whatever order its packets came in.
+    fn end_batch(&mut self, now: Instant) {
+        let batch = match self.batch.take() {
+            Some(b) => b,
+            None => return,
+        };
+        if batch.ack_eliciting > 0 {
+            let in_order = batch.smallest == batch.next_in_order_pn
+                && self.ranges.front().map_or(false, |pr| {
+                    pr.smallest <= batch.smallest && pr.largest == batch.largest
+                });
+            if in_order
+                && batch.ack_eliciting == 1
+                && self.ack_time.is_none()
+                && self.space == PNSpace::ApplicationData
+            {
+                self.ack_time = Some(now + ACK_DELAY);
+            } else {
+                self.ack_time = Some(now);
+            }
+        }
+        if batch.ce && self.ack_time.is_some() {
+            self.ack_time = Some(now);
+        }
+    }
+
     /// Check if the packet is a duplicate.
     pub fn is_duplicate(&self, pn: u64) -> bool {
         if pn < self.min_tracked {
             return true;
         }
-        // TODO(mt) consider a binary search or early exit.
-        for range in &self.ranges {
-            if range.contains(pn) {
-                return true;
-            }
-        }
-        false
+        self.ranges
+            .get(self.find(pn))
+            .map_or(false, |range| range.contains(pn))
     }
 
     /// Mark the given range as having been acknowledged.
@@ -286,6 +384,21 @@ impl AckTracker {
         }
     }
 
+    /// Start a batch of packets in every space.  See `RecvdPackets::end_batch`
+    /// for how that changes when ACKs are sent.
+    pub fn start_batch(&mut self) {
+        for space in &mut self.spaces {
+            space.start_batch();
+        }
+    }
+
+    /// End the batch, and decide when to send an ACK for it in each space.
+    pub fn end_batch(&mut self, now: Instant) {
+        for space in &mut self.spaces {
+            space.end_batch(now);
+        }
+    }
+
     pub fn acked(&mut self, token: &AckToken) {
         self.spaces[token.space as usize].acknowledged(&token.ranges);
     }
@@ -314,7 +427,7 @@ impl AckTracker {
 
         // Limit the number of ACK ranges we send so that we'll always
         // have space for data in packets.
-        let ranges: Vec<PacketRange> = space
+        let ranges: SmallVec<[PacketRange; 4]> = space
             .ranges
             .iter()
             .filter(|r| r.ack_needed())
@@ -327,7 +440,7 @@ impl AckTracker {
             Some(v) => v,
             _ => return None, // Nothing to send.
         };
-        let mut ack_ranges = Vec::new();
+        let mut ack_ranges = AckRanges::new();
         let mut last = first.smallest;
 
         for range in iter {
@@ -352,6 +465,11 @@ impl AckTracker {
                 ack_delay: delay,
                 first_ack_range: first.len() - 1,
                 ack_ranges,
+                ecn_count: if space.ecn_count == EcnCount::default() {
+                    None
+                } else {
+                    Some(space.ecn_count)
+                },
             };
             Some((
                 ack,
@@ -461,6 +579,43 @@ mod tests {
         test_ack_range(&[0, 1, 2, 5, 6, 7, 3, 4], 1);
     }
 
+    #[test]
+    fn reverse_order() {
+        test_ack_range(&[9, 8, 6, 5, 3, 1, 0], 4);
+    }
+
+    #[test]
+    fn fill_in_out_of_order() {
+        test_ack_range(&[20, 0, 10, 15, 5, 11, 9, 16, 14, 13, 12], 4);
+    }
+
+    #[test]
+    fn many_ranges_lookup() {
+        let mut rp = RecvdPackets::new(PNSpace::ApplicationData);
+        let mut pns = (0..MAX_TRACKED_RANGES as u64)
+            .map(|i| i * 3)
+            .collect::<Vec<_>>();
+        // Interleave the packet numbers so that most are added in the middle.
+        pns.sort_by_key(|pn| (pn % 7, *pn));
+        for pn in &pns {
+            rp.set_received(now(), *pn, true);
+        }
+        assert_eq!(rp.ranges.len(), MAX_TRACKED_RANGES);
+        for pn in 0..(MAX_TRACKED_RANGES as u64 * 3) {
+            assert_eq!(rp.is_duplicate(pn), pn % 3 == 0);
+        }
+
+        // Filling every gap leaves just one range.
+        for pn in 0..(MAX_TRACKED_RANGES as u64 * 3) {
+            if pn % 3 != 0 {
+                rp.set_received(now(), pn, true);
+            }
+        }
+        assert_eq!(rp.ranges.len(), 1);
+        assert_eq!(rp.ranges[0].smallest, 0);
+        assert_eq!(rp.ranges[0].largest, MAX_TRACKED_RANGES as u64 * 3 - 1);
+    }
+
     #[test]
     fn too_many_ranges() {
         let mut rp = RecvdPackets::new(PNSpace::Initial); // Any space will do.
@@ -498,6 +653,88 @@ mod tests {
         assert!(rp.ack_now(now()));
     }
 
+    #[test]
+    fn batch_ack_delay() {
+        let mut tracker = AckTracker::default();
+        let rp = &mut tracker[PNSpace::ApplicationData];
+        // Without a batch, 0 arriving after 1 is out of order.
+        rp.set_received(now(), 1, false);
+        rp.set_received(now(), 0, true);
+        assert_eq!(Some(now()), rp.ack_time());
+        assert!(tracker.get_frame(now(), 3).is_some());
+
+        // In a batch, the order doesn't matter, only that there are no gaps.
+        tracker.start_batch();
+        let rp = &mut tracker[PNSpace::ApplicationData];
+        rp.set_received(now(), 3, false);
+        rp.set_received(now(), 2, true);
+        assert_eq!(None, rp.ack_time());
+        tracker.end_batch(now());
+        assert_eq!(Some(now() + ACK_DELAY), tracker.ack_time());
+        assert!(tracker.get_frame(now() + ACK_DELAY, 3).is_some());
+
+        // A batch that leaves a gap is acknowledged straight away.
+        tracker.start_batch();
+        tracker[PNSpace::ApplicationData].set_received(now(), 6, true);
+        tracker[PNSpace::ApplicationData].set_received(now(), 4, false);
+        tracker.end_batch(now());
+        assert_eq!(Some(now()), tracker.ack_time());
+        assert!(tracker.get_frame(now(), 3).is_some());
+
+        // So is one with two ack-eliciting packets.
+        tracker.start_batch();
+        tracker[PNSpace::ApplicationData].set_received(now(), 7, true);
+        tracker[PNSpace::ApplicationData].set_received(now(), 8, true);
+        tracker.end_batch(now());
+        assert_eq!(Some(now()), tracker.ack_time());
+        assert!(tracker.get_frame(now(), 3).is_some());
+
+        // And one with a CE mark.
+        tracker.start_batch();
+        let rp = &mut tracker[PNSpace::ApplicationData];
+        rp.set_received(now(), 9, true);
+        rp.count_ecn(now(), Ecn::Ce);
+        tracker.end_batch(now());
+        assert_eq!(Some(now()), tracker.ack_time());
+        assert!(tracker.get_frame(now(), 3).is_some());
+
+        // A batch with nothing ack-eliciting doesn't need an ACK.
+        tracker.start_batch();
+        tracker[PNSpace::ApplicationData].set_received(now(), 10, false);
+        tracker.end_batch(now());
+        assert_eq!(None, tracker.ack_time());
+    }
+
+    #[test]
+    fn ecn_ack() {
+        let mut tracker = AckTracker::default();
+        let rp = &mut tracker[PNSpace::ApplicationData];
+        rp.set_received(now(), 0, true);
+        rp.count_ecn(now(), Ecn::NotEct);
+        assert_eq!(Some(now() + ACK_DELAY), rp.ack_time());
+        let (ack, _) = tracker.get_frame(now() + ACK_DELAY, 3).unwrap();
+        assert!(matches!(
+            ack,
+            Frame::Ack {
+                ecn_count: None,
+                ..
+            }
+        ));
+
+        // A CE mark isn't delayed, and is reported.
+        let rp = &mut tracker[PNSpace::ApplicationData];
+        rp.set_received(now(), 1, true);
+        rp.count_ecn(now(), Ecn::Ce);
+        assert_eq!(Some(now()), rp.ack_time());
+        let (ack, _) = tracker.get_frame(now(), 3).unwrap();
+        let expected = EcnCount {
+            ect0: 0,
+            ect1: 0,
+            ce: 1,
+        };
+        assert!(matches!(ack, Frame::Ack { ecn_count: Some(c), .. } if c == expected));
+    }
+
     #[test]
     fn no_ack_delay() {
         for space in &[PNSpace::Initial, PNSpace::Handshake] {
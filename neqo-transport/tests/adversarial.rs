@@ -0,0 +1,147 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Tests where the client breaks the protocol, and the server has to close
+// the connection with the right error.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_transport::{Error, State, StreamType};
+use test_fixture::adversary::{self, assert_closes};
+use test_fixture::pair::{Pair, Side};
+
+fn connected() -> Pair {
+    let mut pair = Pair::new();
+    pair.handshake();
+    pair
+}
+
+/// Inject `frames` into the next 1-RTT packet from the client, and check
+/// that the server closes with `error`.
+fn violation(frames: Vec<u8>, error: Error) {
+    let mut pair = connected();
+    pair.client().inject_frames(3, frames);
+    assert_closes(&mut pair, Side::Client, error);
+}
+
+#[test]
+fn stream_in_handshake() {
+    let mut pair = Pair::new();
+    pair.send(Side::Client).deliver(Side::Client);
+    pair.send(Side::Server).deliver(Side::Server);
+    pair.client()
+        .inject_frames(2, adversary::stream(0, 0, &[1, 2, 3], false));
+    assert_closes(&mut pair, Side::Client, Error::ProtocolViolation);
+}
+
+#[test]
+fn stream_flow_control() {
+    violation(
+        adversary::stream(0, 1 << 40, &[1], false),
+        Error::FlowControlError,
+    );
+}
+
+#[test]
+fn stream_limit() {
+    violation(
+        adversary::stream(400, 0, &[1], false),
+        Error::StreamLimitError,
+    );
+}
+
+#[test]
+fn stream_final_size() {
+    let mut frames = adversary::stream(0, 5, &[1; 5], true);
+    frames.extend(adversary::stream(0, 0, &[1; 20], true));
+    violation(frames, Error::FinalSizeError);
+}
+
+#[test]
+fn reset_final_size() {
+    let mut frames = adversary::stream(0, 0, &[1; 10], false);
+    frames.extend(adversary::reset_stream(0, 0, 5));
+    violation(frames, Error::FinalSizeError);
+}
+
+#[test]
+fn reset_send_only() {
+    // Stream 3 is one that the server opens and only sends on.
+    violation(adversary::reset_stream(3, 0, 0), Error::StreamStateError);
+}
+
+#[test]
+fn reset_stream_limit() {
+    violation(adversary::reset_stream(400, 0, 0), Error::StreamLimitError);
+}
+
+#[test]
+fn stop_sending_recv_only() {
+    // Stream 2 is one that the client opens and the server only receives on.
+    violation(adversary::stop_sending(2, 0), Error::StreamStateError);
+}
+
+#[test]
+fn new_connection_id_too_long() {
+    violation(
+        adversary::new_connection_id(1, 0, &[0xc1; 21]),
+        Error::ProtocolViolation,
+    );
+}
+
+#[test]
+fn new_connection_id_empty() {
+    violation(
+        adversary::new_connection_id(1, 0, &[]),
+        Error::ProtocolViolation,
+    );
+}
+
+#[test]
+fn new_connection_id_retire_prior() {
+    violation(
+        adversary::new_connection_id(1, 2, &[0xc1; 8]),
+        Error::FrameEncodingError,
+    );
+}
+
+#[test]
+fn unknown_frame() {
+    violation(adversary::unknown(0x21), Error::FrameEncodingError);
+}
+
+#[test]
+fn truncated_frame() {
+    violation(adversary::truncated_stream(0), Error::FrameEncodingError);
+}
+
+#[test]
+fn garbled_dcid() {
+    let mut pair = connected();
+    let id = pair.client().stream_create(StreamType::BiDi).unwrap();
+    pair.client().stream_send(id, &[1, 2, 3]).unwrap();
+    pair.send(Side::Client);
+    let d = pair.take(Side::Client, 0);
+    pair.inject(Side::Client, adversary::garble_dcid(&d))
+        .deliver(Side::Client);
+
+    // The server can't tell that the datagram is for it, so it drops it.
+    assert_eq!(*pair.server().state(), State::Connected);
+    let mut buf = [0; 16];
+    assert!(pair.server().stream_recv(id, &mut buf).is_err());
+
+    pair.inject(Side::Client, d).deliver(Side::Client);
+    assert_eq!(pair.server().stream_recv(id, &mut buf).unwrap(), (3, false));
+}
+
+#[test]
+fn padded_garbage() {
+    let mut pair = Pair::new();
+    pair.send(Side::Client);
+    let d = pair.take(Side::Client, 0);
+    pair.inject(Side::Client, adversary::pad_garbage(&d, 50));
+    pair.handshake();
+}
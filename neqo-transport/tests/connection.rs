@@ -6,8 +6,10 @@
 
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 
-use neqo_common::Datagram;
-use neqo_transport::State;
+use neqo_common::{matches, Datagram};
+use neqo_transport::{seed_random, State};
+use test_fixture::pair::{Pair, Side};
+use test_fixture::replay;
 use test_fixture::{self, default_client, default_server, now};
 
 #[test]
@@ -43,3 +45,91 @@ fn truncate_long_packet() {
     assert!(dgram.is_some());
     assert_eq!(*server.state(), State::Connected);
 }
+
+/// The part of a long header packet from the version to the end of the
+/// connection IDs, which doesn't depend on anything that TLS chooses.
+fn long_header_ids(d: &[u8]) -> &[u8] {
+    let dcil = usize::from(d[5]);
+    let scil = usize::from(d[6 + dcil]);
+    &d[1..7 + dcil + scil]
+}
+
+#[test]
+fn deterministic_connection_ids() {
+    let first_flight = |seed| {
+        let _ = test_fixture::deterministic(seed);
+        let mut client = default_client();
+        let mut server = default_server();
+        let c = client.process(None, now()).dgram().unwrap();
+        let s = server.process(Some(c.clone()), now()).dgram().unwrap();
+        (long_header_ids(&c).to_vec(), long_header_ids(&s).to_vec())
+    };
+    let first = first_flight(1);
+    assert_eq!(first_flight(1), first);
+    assert_ne!(first_flight(2), first);
+    seed_random(None);
+}
+
+#[test]
+fn deterministic_idle_timeout() {
+    // Let the client sit idle until it times out, noting each timer it sets.
+    let timers = || {
+        let (mut client, _server, clock) = test_fixture::deterministic_connect(3);
+        let mut timers = Vec::new();
+        let mut out = Vec::new();
+        while let Some(delay) = client.wakeup(None, &mut out) {
+            timers.push(delay);
+            clock.advance(delay);
+        }
+        assert!(matches!(client.state(), State::Closed(..)));
+        timers
+    };
+    assert_eq!(timers(), timers());
+    seed_random(None);
+}
+
+#[test]
+fn reordered_server_flight() {
+    let mut pair = Pair::new();
+    pair.send(Side::Client).deliver(Side::Client);
+    pair.send(Side::Server)
+        .reverse(Side::Server)
+        .deliver(Side::Server);
+    pair.handshake();
+}
+
+#[test]
+fn duplicated_client_initial() {
+    let mut pair = Pair::new();
+    pair.send(Side::Client)
+        .duplicate(Side::Client, 0)
+        .deliver(Side::Client);
+    pair.handshake();
+}
+
+#[test]
+fn lost_client_finished() {
+    let mut pair = Pair::new();
+    pair.send(Side::Client).deliver(Side::Client);
+    pair.send(Side::Server).deliver(Side::Server);
+    pair.send(Side::Client).lose_all(Side::Client);
+    assert_eq!(*pair.client().state(), State::Connected);
+    pair.handshake();
+}
+
+#[test]
+fn replay_client_initial() {
+    let mut pair = Pair::new();
+    let recorder = replay::record(pair.server(), None);
+    pair.handshake();
+    let corpus = replay::recording(&recorder);
+    assert!(corpus.received().count() > 1);
+    assert!(corpus.records().len() > corpus.received().count());
+
+    // Another server can read the client Initial, and answers it.
+    let mut server = default_server();
+    let sent = replay::replay(&mut server, &corpus);
+    assert!(!sent.is_empty());
+    assert_eq!(sent[0][0] & 0xf0, 0xc0);
+    assert_eq!(*server.state(), State::Handshaking);
+}
@@ -0,0 +1,24 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use proptest::prelude::*;
+use test_fixture::prop::{behavior, Invariants};
+
+proptest! {
+    // Each case runs a handshake, so don't run too many.
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn invariants(behavior in behavior()) {
+        let mut inv = Invariants::new();
+        for action in &behavior {
+            inv.step(action)?;
+        }
+        inv.finish()?;
+    }
+}
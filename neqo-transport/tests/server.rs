@@ -12,12 +12,12 @@ use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
     hkdf,
     hp::HpKey,
-    AuthenticationStatus,
+    AlpnSelector, AuthenticationStatus,
 };
 use neqo_transport::{
-    server::{ActiveConnectionRef, Server},
-    Connection, ConnectionError, Error, FixedConnectionIdManager, Output, State, StreamType,
-    QUIC_VERSION,
+    server::{ActiveConnectionRef, Server, ServerStats, ShardRouter, ShardedConnectionIdManager},
+    Connection, ConnectionError, ConnectionIdManager, Error, FixedConnectionIdManager, Output,
+    State, StreamType, TicketPolicy, TransportParameter, QUIC_VERSION,
 };
 use test_fixture::{self, assertions, default_client, now};
 
@@ -79,6 +79,86 @@ fn single_client() {
     connect(&mut client, &mut server);
 }
 
+#[test]
+fn stats() {
+    let mut server = default_server();
+    assert_eq!(server.stats(), ServerStats::default());
+    let mut client = default_client();
+    let server_conn = connect(&mut client, &mut server);
+
+    // Let the server finish sending, so that only a timer is left.
+    assert!(server.process(None, now()).dgram().is_none());
+    let stats = server.stats();
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.active, 0);
+    assert_eq!(stats.waiting, 0);
+    assert_eq!(stats.timers, 1);
+    assert_eq!(stats.events, server_conn.borrow().pending_events());
+}
+
+#[test]
+fn sharded_cids() {
+    const SHARDS: usize = 5;
+    let router = ShardRouter::new(9, SHARDS);
+    for shard in 0..SHARDS {
+        let mut cid_mgr = ShardedConnectionIdManager::new(9, shard, SHARDS);
+        for _ in 0..100 {
+            // A short header packet with this connection ID.
+            let mut dgram = vec![0x40];
+            dgram.extend_from_slice(&cid_mgr.generate_cid());
+            dgram.extend_from_slice(&[0; 20]);
+            assert_eq!(router.route(&dgram), Some(shard));
+        }
+    }
+}
+
+#[test]
+fn sharded_servers() {
+    const SHARDS: usize = 3;
+    let router = ShardRouter::new(9, SHARDS);
+    let mut servers = (0..SHARDS)
+        .map(|shard| {
+            Server::new(
+                now(),
+                test_fixture::DEFAULT_KEYS,
+                test_fixture::DEFAULT_ALPN,
+                test_fixture::anti_replay(),
+                Rc::new(RefCell::new(ShardedConnectionIdManager::new(
+                    9, shard, SHARDS,
+                ))),
+            )
+            .expect("should create a server")
+        })
+        .collect::<Vec<_>>();
+
+    // Every datagram from the client has to reach the same shard.
+    let mut shard = None;
+    let mut to_server = |dgram: Option<Datagram>| {
+        let dgram = dgram.expect("client should send");
+        let s = router.route(&dgram).expect("should route");
+        assert_eq!(*shard.get_or_insert(s), s);
+        servers[s].process(Some(dgram), now()).dgram()
+    };
+
+    let mut client = default_client();
+    let dgram = to_server(client.process(None, now()).dgram()); // ClientHello
+    assert!(dgram.is_some());
+    let dgram = to_server(client.process(dgram, now()).dgram()); // ACK
+    assert!(dgram.is_none());
+    client.authenticated(AuthenticationStatus::Ok, now());
+    let dgram = to_server(client.process(None, now()).dgram()); // Finished
+    assert!(dgram.is_some());
+    assert_eq!(*client.state(), State::Connected);
+
+    let shard = shard.unwrap();
+    connected_server(&mut servers[shard]);
+    for (i, server) in servers.iter_mut().enumerate() {
+        if i != shard {
+            assert!(server.active_connections().is_empty());
+        }
+    }
+}
+
 #[test]
 fn retry() {
     let mut server = default_server();
@@ -101,6 +181,7 @@ fn retry() {
     let dgram = client.process(None, now()).dgram(); // Send Finished
     assert!(dgram.is_some());
     assert_eq!(*client.state(), State::Connected);
+    assert!(client.retry_token().is_some());
     let dgram = server.process(dgram, now()).dgram(); // (done)
     assert!(dgram.is_some()); // Note that this packet will be dropped...
     connected_server(&mut server);
@@ -157,6 +238,124 @@ fn retry_0rtt() {
     assert!(client.tls_info().unwrap().resumed());
 }
 
+/// Connect with `policy`, and take the ticket that the server sends with the
+/// handshake.
+fn ticket_from_policy(policy: TicketPolicy) -> Vec<u8> {
+    let mut server = default_server();
+    server.set_ticket_policy(policy);
+    let mut client = default_client();
+    let dgram = client.process(None, now()).dgram(); // ClientHello
+    let dgram = server.process(dgram, now()).dgram(); // ServerHello...
+    let dgram = client.process(dgram, now()).dgram(); // ACK
+    let dgram = server.process(dgram, now()).dgram();
+    assert!(dgram.is_none());
+    client.authenticated(AuthenticationStatus::Ok, now());
+    let dgram = client.process(None, now()).dgram(); // Finished
+    let dgram = server.process(dgram, now()).dgram(); // ACK + NST
+    client.process_input(dgram.unwrap(), now());
+    client.resumption_token().expect("should get a ticket")
+}
+
+#[test]
+fn ticket_policy() {
+    let token = ticket_from_policy(TicketPolicy {
+        count: 2,
+        ..TicketPolicy::default()
+    });
+    let mut client = default_client();
+    client.set_resumption_token(now(), &token).unwrap();
+    assert!(client.stream_create(StreamType::UniDi).is_ok());
+}
+
+#[test]
+fn ticket_policy_no_0rtt() {
+    let token = ticket_from_policy(TicketPolicy {
+        count: 1,
+        allow_0rtt: false,
+        ..TicketPolicy::default()
+    });
+    let mut client = default_client();
+    client.set_resumption_token(now(), &token).unwrap();
+    // There is no 0-RTT, so streams have to wait for the handshake.
+    assert_eq!(
+        client.stream_create(StreamType::UniDi),
+        Err(Error::ConnectionState)
+    );
+}
+
+#[test]
+fn ticket_policy_max_early_data() {
+    let token = ticket_from_policy(TicketPolicy {
+        count: 1,
+        max_early_data: 100,
+        ..TicketPolicy::default()
+    });
+    let mut client = default_client();
+    client.set_resumption_token(now(), &token).unwrap();
+    assert_eq!(client.early_data_remaining(), 100);
+
+    // Only what the ticket allows goes in 0-RTT; the rest waits.
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[0; 300]).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    assertions::assert_coalesced_0rtt(dgram.as_ref().unwrap());
+    // Less than the header of another STREAM frame is left.
+    assert!(client.early_data_remaining() < 4);
+}
+
+/// Picks the last protocol that is offered, which no list in the server would.
+#[derive(Debug)]
+struct LastOffered;
+
+impl AlpnSelector for LastOffered {
+    fn select(&self, _server_name: Option<&str>, offered: &[&str]) -> Option<String> {
+        offered.last().map(|v| v.to_string())
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    let mut server = default_server();
+    server.set_alpn_selector(Rc::new(LastOffered));
+    let mut client = default_client();
+    client.set_alpn(&["alpn", "other"]).unwrap();
+
+    let sconn = connect(&mut client, &mut server);
+    let expected = Some(String::from("other"));
+    assert_eq!(client.handshake_info().unwrap().alpn, expected);
+    assert_eq!(sconn.borrow().handshake_info().unwrap().alpn, expected);
+}
+
+/// A post-quantum key share makes the ClientHello too big for one packet.
+/// Get the same effect with a big transport parameter that nobody knows.
+#[test]
+fn large_client_hello() {
+    let mut server = default_server();
+    let mut client = default_client();
+    client
+        .set_local_tparam(0xce16, TransportParameter::Bytes(vec![0; 1500]))
+        .unwrap();
+    let dgram1 = client.process(None, now()).dgram();
+    let dgram2 = client.process(None, now()).dgram();
+    assert!(dgram1.is_some());
+    assert!(dgram2.is_some());
+
+    // Both packets go to the same connection.
+    let _ = server.process(dgram1, now());
+    let dgram = server.process(dgram2, now()).dgram(); // ServerHello...
+    assert!(dgram.is_some());
+    assert_eq!(server.stats().connections, 1);
+
+    let dgram = client.process(dgram, now()).dgram(); // ACK
+    let _ = server.process(dgram, now());
+    client.authenticated(AuthenticationStatus::Ok, now());
+    let dgram = client.process(None, now()).dgram();
+    assert_eq!(*client.state(), State::Connected);
+    let _ = server.process(dgram, now());
+    connected_server(&mut server);
+    assert_eq!(server.stats().connections, 1);
+}
+
 #[test]
 fn retry_different_ip() {
     let mut server = default_server();
@@ -274,6 +473,31 @@ fn retry_bad_token() {
     assert!(dgram.is_none());
 }
 
+/// Get a Retry from `server`, rotate its keys `rotations` times, and then
+/// return what the server says to the Initial with the token.
+fn retry_after_rotation(rotations: usize) -> Option<Datagram> {
+    let mut client = default_client();
+    let mut server = default_server();
+    server.set_retry_required(true);
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+    for _ in 0..rotations {
+        server.rotate_keys().expect("should rotate keys");
+    }
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    assert!(dgram.is_some());
+    server.process(dgram, now()).dgram()
+}
+
+#[test]
+fn retry_rotate_keys() {
+    // The token is still good after one rotation, but not after two.
+    assert!(retry_after_rotation(1).is_some());
+    assert!(retry_after_rotation(2).is_none());
+}
+
 // Generate an AEAD and header protection object for a client Initial.
 fn client_initial_aead_and_hp(dcid: &[u8]) -> (Aead, HpKey) {
     const INITIAL_SALT: &[u8] = &[
@@ -484,6 +708,7 @@ fn version_negotiation() {
         }
         _ => panic!("Invalid client state"),
     }
+    assert!(client.vn_versions().unwrap().contains(&QUIC_VERSION));
 }
 
 #[test]
@@ -0,0 +1,464 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The UDP datapath for neqo tools.
+//!
+//! This sends and receives batches of datagrams with as few system calls as
+//! the platform allows.  On Linux, runs of equal-sized datagrams are sent with
+//! generic segmentation offload (GSO) and received with generic receive
+//! offload (GRO).  Where those aren't available, batches of datagrams are
+//! passed to `sendmmsg` and `recvmmsg`.  Other platforms handle each datagram
+//! on its own.
+//!
+//! `Socket` wraps all of this up with the socket options that QUIC wants.
+//! On Linux, the `io-uring` feature adds an `io_uring` datapath to `Socket`,
+//! which is chosen at runtime.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+#![cfg(unix)]
+
+mod socket;
+mod sys;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use socket::Socket;
+
+use neqo_common::{qdebug, Datagram, DatagramPool};
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+/// The most datagrams that the kernel will send in one GSO call.
+const MAX_SEGMENTS: usize = 64;
+/// The most data that can be passed to one GSO call.
+const MAX_SEND_SIZE: usize = 65_507;
+/// Enough space for any UDP datagram, or for datagrams that GRO coalesced.
+const RECV_BUFFER_SIZE: usize = 65_535;
+/// The space for each datagram when receiving without GRO.
+const RECV_SLOT_SIZE: usize = 2048;
+/// The number of datagrams sent or received in one call without GSO or GRO.
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// Sends datagrams on a UDP socket.
+#[derive(Debug)]
+pub struct Sender {
+    gso: bool,
+    batch_size: usize,
+    /// The DSCP for datagrams that don't have one.
+    dscp: u8,
+}
+
+impl Sender {
+    /// Make a sender for `socket`, using GSO if the kernel supports it.
+    pub fn new(socket: &impl AsRawFd) -> Self {
+        Self {
+            gso: sys::gso_supported(socket.as_raw_fd()),
+            batch_size: DEFAULT_BATCH_SIZE,
+            dscp: 0,
+        }
+    }
+
+    /// Whether GSO is in use.
+    #[must_use]
+    pub fn gso(&self) -> bool {
+        self.gso
+    }
+
+    /// Set how many datagrams to send at once when GSO isn't available.
+    /// A value of 1 sends each datagram with its own system call.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = max(batch_size, 1);
+    }
+
+    /// Send datagrams that don't have a DSCP with `dscp`.
+    /// # Panics
+    /// If `dscp` doesn't fit in six bits.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        assert!(dscp < 64, "DSCP is only six bits");
+        self.dscp = dscp;
+    }
+
+    /// Send `dgrams` in order.  A datagram with a segment size is sent with one
+    /// GSO call if it can be, and is split apart otherwise.
+    /// # Errors
+    /// When the socket can't send.
+    pub fn send(&mut self, socket: &impl AsRawFd, dgrams: &[Datagram]) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        let mut remaining = dgrams;
+        while let Some(first) = remaining.first() {
+            let tos = sys::tos(first, self.dscp);
+            if let Some(size) = first.segment_size() {
+                if self.gso && gso_fits(first, size) {
+                    let size = u16::try_from(size).unwrap();
+                    let (src, dst) = (first.source(), first.destination());
+                    match sys::send(fd, &src, &dst, tos, first, Some(size)) {
+                        Ok(()) => {
+                            remaining = &remaining[1..];
+                            continue;
+                        }
+                        Err(e) if e.raw_os_error() == Some(libc::EIO) => {
+                            qdebug!("GSO send failed, disabling GSO: {}", e);
+                            self.gso = false;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                self.send(socket, &first.segments().collect::<Vec<_>>())?;
+                remaining = &remaining[1..];
+                continue;
+            }
+            if !self.gso {
+                let n = min(self.batch_size, remaining.len());
+                let sent = sys::send_many(fd, &remaining[..n], self.dscp)?;
+                remaining = &remaining[sent..];
+                continue;
+            }
+            let n = gso_batch_len(remaining);
+            if n > 1 {
+                let buf = remaining[..n]
+                    .iter()
+                    .flat_map(|d| d.iter())
+                    .copied()
+                    .collect::<Vec<_>>();
+                let size = u16::try_from(first.len()).unwrap();
+                let (src, dst) = (first.source(), first.destination());
+                match sys::send(fd, &src, &dst, tos, &buf, Some(size)) {
+                    Ok(()) => {
+                        remaining = &remaining[n..];
+                        continue;
+                    }
+                    // The kernel allowed GSO, but the network device can't do it.
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) => {
+                        qdebug!("GSO send failed, disabling GSO: {}", e);
+                        self.gso = false;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            sys::send(fd, &first.source(), &first.destination(), tos, first, None)?;
+            remaining = &remaining[1..];
+        }
+        Ok(())
+    }
+}
+
+/// Receives datagrams from a UDP socket.
+pub struct Receiver {
+    gro: bool,
+    buf: Vec<u8>,
+    pool: Option<DatagramPool>,
+}
+
+impl Receiver {
+    /// Make a receiver for `socket`, enabling GRO if the kernel supports it.
+    pub fn new(socket: &impl AsRawFd) -> Self {
+        let gro = sys::enable_gro(socket.as_raw_fd());
+        let size = if gro {
+            RECV_BUFFER_SIZE
+        } else {
+            DEFAULT_BATCH_SIZE * RECV_SLOT_SIZE
+        };
+        Self {
+            gro,
+            buf: vec![0; size],
+            pool: None,
+        }
+    }
+
+    /// Whether GRO is in use.
+    #[must_use]
+    pub fn gro(&self) -> bool {
+        self.gro
+    }
+
+    /// Set how many datagrams to receive at once when GRO isn't available.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        if !self.gro {
+            self.buf.resize(max(batch_size, 1) * RECV_SLOT_SIZE, 0);
+        }
+    }
+
+    /// Put received datagrams in buffers from `pool`, rather than allocating
+    /// a buffer for each.
+    pub fn set_pool(&mut self, pool: Option<DatagramPool>) {
+        self.pool = pool;
+    }
+
+    fn datagram(
+        pool: Option<&DatagramPool>,
+        src: SocketAddr,
+        dst: SocketAddr,
+        tos: u8,
+        d: &[u8],
+    ) -> Datagram {
+        match pool {
+            Some(pool) => pool.datagram(src, dst, d),
+            None => Datagram::new(src, dst, d),
+        }
+        .with_tos(tos)
+    }
+
+    /// Receive from `socket`, which is bound to `local`.  Datagrams that the
+    /// kernel coalesced are split apart again, so this might return several.
+    /// Where the kernel reports the address that each datagram was sent to,
+    /// that replaces the address in `local`, which might be unspecified.
+    /// # Errors
+    /// When the socket can't receive, including when it would block.
+    pub fn recv(&mut self, socket: &impl AsRawFd, local: SocketAddr) -> io::Result<Vec<Datagram>> {
+        if !self.gro {
+            let received = sys::recv_many(socket.as_raw_fd(), &mut self.buf, RECV_SLOT_SIZE)?;
+            return Ok(received
+                .iter()
+                .zip(self.buf.chunks(RECV_SLOT_SIZE))
+                .map(|(r, slot)| {
+                    if r.truncated {
+                        qdebug!("Truncated datagram from {}", r.source);
+                    }
+                    let d = &slot[..r.len];
+                    Self::datagram(self.pool.as_ref(), r.source, r.local(local), r.tos, d)
+                })
+                .collect());
+        }
+
+        let r = sys::recv(socket.as_raw_fd(), &mut self.buf)?;
+        if r.truncated {
+            qdebug!("Truncated datagram from {}", r.source);
+        }
+        let data = &self.buf[..r.len];
+        let size = match r.segment_size {
+            Some(size) if size > 0 => size,
+            _ => {
+                return Ok(vec![Self::datagram(
+                    self.pool.as_ref(),
+                    r.source,
+                    r.local(local),
+                    r.tos,
+                    data,
+                )]);
+            }
+        };
+        Ok(data
+            .chunks(size)
+            .map(|d| Self::datagram(self.pool.as_ref(), r.source, r.local(local), r.tos, d))
+            .collect())
+    }
+}
+
+impl ::std::fmt::Debug for Receiver {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "Receiver gro={} buffer={} pool={:?}",
+            self.gro,
+            self.buf.len(),
+            self.pool
+        )
+    }
+}
+
+/// Whether `d`, which is made of `size` byte segments, can be sent with one
+/// GSO call.
+fn gso_fits(d: &Datagram, size: usize) -> bool {
+    size <= usize::from(u16::max_value())
+        && d.len() <= MAX_SEND_SIZE
+        && d.len() <= size * MAX_SEGMENTS
+}
+
+/// The number of datagrams from the start of `dgrams` that can be sent with
+/// one GSO call.  These all have the same addresses and size, except that
+/// the last can be shorter.
+fn gso_batch_len(dgrams: &[Datagram]) -> usize {
+    let first = &dgrams[0];
+    let size = first.len();
+    if size == 0 || size > usize::from(u16::max_value()) {
+        return 1;
+    }
+    let mut total = size;
+    let mut n = 1;
+    for d in &dgrams[1..] {
+        if n == MAX_SEGMENTS
+            || d.source() != first.source()
+            || d.destination() != first.destination()
+            || d.tos() != first.tos()
+            || d.segment_size().is_some()
+            || d.is_empty()
+            || d.len() > size
+            || total + d.len() > MAX_SEND_SIZE
+        {
+            break;
+        }
+        total += d.len();
+        n += 1;
+        if d.len() < size {
+            break;
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    fn socket() -> UdpSocket {
+        let s = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        s.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        s
+    }
+
+    fn dgram(src: SocketAddr, dst: SocketAddr, len: usize, fill: u8) -> Datagram {
+        Datagram::new(src, dst, vec![fill; len])
+    }
+
+    #[test]
+    fn batch_len() {
+        let a = "127.0.0.1:1".parse().unwrap();
+        let b = "127.0.0.1:2".parse().unwrap();
+        let d = |dst, len| dgram(a, dst, len, 0);
+        assert_eq!(gso_batch_len(&[d(b, 100), d(b, 100), d(b, 100)]), 3);
+        // A short datagram ends a batch.
+        assert_eq!(gso_batch_len(&[d(b, 100), d(b, 50), d(b, 100)]), 2);
+        // A longer one can't be included.
+        assert_eq!(gso_batch_len(&[d(b, 100), d(b, 101)]), 1);
+        // Nor can one for a different destination or with different marking.
+        assert_eq!(gso_batch_len(&[d(b, 100), d(a, 100)]), 1);
+        assert_eq!(gso_batch_len(&[d(b, 100), d(b, 100).with_dscp(10)]), 1);
+        let many = vec![d(b, 10); MAX_SEGMENTS + 1];
+        assert_eq!(gso_batch_len(&many), MAX_SEGMENTS);
+        let large = vec![d(b, 1500); MAX_SEGMENTS];
+        assert_eq!(gso_batch_len(&large), MAX_SEND_SIZE / 1500);
+    }
+
+    #[test]
+    fn send_batch() {
+        let tx = socket();
+        let rx = socket();
+        let src = tx.local_addr().unwrap();
+        let dst = rx.local_addr().unwrap();
+        let dgrams = vec![
+            dgram(src, dst, 1200, 1),
+            dgram(src, dst, 1200, 2),
+            dgram(src, dst, 1200, 3),
+            dgram(src, dst, 300, 4),
+            dgram(src, dst, 1200, 5),
+        ];
+
+        let mut sender = Sender::new(&tx);
+        sender.send(&tx, &dgrams).expect("send");
+
+        let mut buf = [0; 2048];
+        for d in &dgrams {
+            let (sz, from) = rx.recv_from(&mut buf).expect("recv");
+            assert_eq!(from, src);
+            assert_eq!(&buf[..sz], &d[..]);
+        }
+    }
+
+    #[test]
+    fn send_segmented() {
+        let tx = socket();
+        let rx = socket();
+        let src = tx.local_addr().unwrap();
+        let dst = rx.local_addr().unwrap();
+        let mut payload = Vec::new();
+        for (len, fill) in &[(1200, 1), (1200, 2), (300, 3)] {
+            payload.resize(payload.len() + len, *fill);
+        }
+        let d = Datagram::new(src, dst, payload).with_segment_size(1200);
+
+        // Without GSO, the segments are split apart.
+        let mut sender = Sender::new(&tx);
+        for gso in &[sender.gso, false] {
+            sender.gso = *gso;
+            sender.send(&tx, std::slice::from_ref(&d)).expect("send");
+            let mut buf = [0; 2048];
+            for s in d.segments() {
+                let (sz, from) = rx.recv_from(&mut buf).expect("recv");
+                assert_eq!(from, src);
+                assert_eq!(&buf[..sz], &s[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn mmsg_batch() {
+        let tx = socket();
+        let rx = socket();
+        let src = tx.local_addr().unwrap();
+        let dst = rx.local_addr().unwrap();
+        let dgrams = (0..10)
+            .map(|i| dgram(src, dst, 100 + i, u8::try_from(i).unwrap()))
+            .collect::<Vec<_>>();
+
+        let mut sender = Sender::new(&tx);
+        sender.gso = false;
+        sender.set_batch_size(4);
+        sender.send(&tx, &dgrams).expect("send");
+
+        let mut receiver = Receiver::new(&rx);
+        receiver.gro = false;
+        receiver.set_batch_size(3);
+        let mut all = Vec::new();
+        while all.len() < dgrams.len() {
+            let batch = receiver.recv(&rx, dst).expect("recv");
+            assert!(batch.len() <= 3);
+            all.extend(batch);
+        }
+        assert_eq!(all, dgrams);
+    }
+
+    #[test]
+    fn recv_batch() {
+        let tx = socket();
+        let rx = socket();
+        let src = tx.local_addr().unwrap();
+        let dst = rx.local_addr().unwrap();
+        let dgrams = vec![
+            dgram(src, dst, 1000, 1),
+            dgram(src, dst, 1000, 2),
+            dgram(src, dst, 1000, 3),
+            dgram(src, dst, 10, 4),
+        ];
+
+        let mut receiver = Receiver::new(&rx);
+        Sender::new(&tx).send(&tx, &dgrams).expect("send");
+
+        let mut all = Vec::new();
+        while all.len() < dgrams.len() {
+            all.extend(receiver.recv(&rx, dst).expect("recv"));
+        }
+        assert_eq!(all, dgrams);
+    }
+
+    #[test]
+    fn recv_pooled() {
+        let tx = socket();
+        let rx = socket();
+        let src = tx.local_addr().unwrap();
+        let dst = rx.local_addr().unwrap();
+        let pool = DatagramPool::new(4, 2048);
+        let mut receiver = Receiver::new(&rx);
+        receiver.set_pool(Some(pool.clone()));
+
+        let mut sender = Sender::new(&tx);
+        for i in 0..10 {
+            let d = dgram(src, dst, 1000, i);
+            sender.send(&tx, std::slice::from_ref(&d)).expect("send");
+            assert_eq!(receiver.recv(&rx, dst).expect("recv"), vec![d]);
+        }
+        // Each datagram was dropped before the next arrived, so one buffer is enough.
+        let stats = pool.stats();
+        assert_eq!(stats.allocated, 1);
+        assert_eq!(stats.reused, 9);
+    }
+}
@@ -0,0 +1,313 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A UDP socket set up for QUIC.
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::Uring;
+use crate::{sys, Receiver, Sender};
+use neqo_common::{Datagram, DatagramPool};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+/// A UDP socket that the neqo tools share.
+///
+/// This sets the "don't fragment" bit on everything it sends, learns the
+/// address that each datagram was sent to, and sends from the address that
+/// each datagram names as its source.  That makes sockets bound to an
+/// unspecified address usable with more than one local address.  Sending
+/// and receiving use segmentation offload or batches wherever possible.
+///
+/// With the `io-uring` feature, `use_io_uring` switches to `io_uring` instead.
+#[derive(Debug)]
+pub struct Socket {
+    inner: UdpSocket,
+    local: SocketAddr,
+    sender: Sender,
+    receiver: Receiver,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring: Option<Uring>,
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+    dscp: u8,
+}
+
+impl Socket {
+    /// Bind to `addr`.
+    /// # Errors
+    /// When the socket can't be bound or configured.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let inner = UdpSocket::bind(addr)?;
+        let local = inner.local_addr()?;
+        sys::configure(inner.as_raw_fd(), local.is_ipv6())?;
+        let sender = Sender::new(&inner);
+        let receiver = Receiver::new(&inner);
+        Ok(Self {
+            inner,
+            local,
+            sender,
+            receiver,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring: None,
+            nonblocking: false,
+            read_timeout: None,
+            dscp: 0,
+        })
+    }
+
+    /// Only exchange datagrams with `addr`.  This also fixes the local
+    /// address, which `local_addr` then reports.
+    /// # Errors
+    /// When `addr` can't be used.
+    pub fn connect(&mut self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        self.inner.connect(addr)?;
+        self.local = self.inner.local_addr()?;
+        Ok(())
+    }
+
+    /// The address that the socket is bound to.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Whether this IPv6 socket refuses IPv4.
+    /// # Errors
+    /// When the socket can't say.
+    pub fn only_v6(&self) -> io::Result<bool> {
+        sys::only_v6(self.inner.as_raw_fd())
+    }
+
+    /// # Errors
+    /// When the mode can't be set.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)?;
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Limit how long `recv` waits.
+    /// # Errors
+    /// When the timeout is zero or can't be set.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Set the TOS byte (IPv4) or traffic class (IPv6) of sent datagrams.
+    /// # Errors
+    /// When the value can't be set.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        sys::set_tos(self.inner.as_raw_fd(), self.local.is_ipv6(), tos)
+    }
+
+    /// Mark datagrams that don't have a DSCP with `dscp` as they are sent.
+    /// Unlike `set_tos`, this keeps the ECN codepoint of each datagram.
+    /// # Panics
+    /// If `dscp` doesn't fit in six bits.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.sender.set_dscp(dscp);
+        self.dscp = dscp;
+    }
+
+    /// Set how many datagrams to send or receive with one system call when
+    /// segmentation offload isn't available.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.sender.set_batch_size(batch_size);
+        self.receiver.set_batch_size(batch_size);
+    }
+
+    /// Put received datagrams in buffers from `pool`.  This doesn't apply to
+    /// `io_uring`, which has buffers of its own.
+    pub fn set_pool(&mut self, pool: Option<DatagramPool>) {
+        self.receiver.set_pool(pool);
+    }
+
+    /// Send and receive with `io_uring` from now on.
+    /// # Errors
+    /// When `io_uring` isn't available, including when this was built without
+    /// the `io-uring` feature.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn use_io_uring(&mut self) -> io::Result<()> {
+        if self.uring.is_none() {
+            // Coalesced datagrams don't fit in the buffers that io_uring uses.
+            if self.receiver.gro() {
+                sys::disable_gro(self.inner.as_raw_fd())?;
+            }
+            self.uring = Some(Uring::new(&self.inner)?);
+        }
+        Ok(())
+    }
+
+    /// Send and receive with `io_uring`, which is not available.
+    /// # Errors
+    /// Always.
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    #[allow(clippy::unused_self)]
+    pub fn use_io_uring(&mut self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "io_uring support is not enabled",
+        ))
+    }
+
+    /// Whether `io_uring` is in use.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[must_use]
+    pub fn io_uring(&self) -> bool {
+        self.uring.is_some()
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    #[must_use]
+    #[allow(clippy::unused_self)]
+    pub fn io_uring(&self) -> bool {
+        false
+    }
+
+    /// The descriptor to wait on before calling `recv` on a non-blocking
+    /// socket.  This is only the socket when `io_uring` isn't in use.
+    #[must_use]
+    pub fn poll_fd(&self) -> RawFd {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(uring) = &self.uring {
+                return uring.poll_fd();
+            }
+        }
+        self.inner.as_raw_fd()
+    }
+
+    /// Send `dgrams` in order.
+    /// # Errors
+    /// When the socket can't send.
+    pub fn send(&mut self, dgrams: &[Datagram]) -> io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(uring) = &mut self.uring {
+                return uring.send(dgrams, self.dscp);
+            }
+        }
+        self.sender.send(&self.inner, dgrams)
+    }
+
+    /// Receive whatever datagrams are available.  This waits if there are
+    /// none, unless the socket is non-blocking.
+    /// # Errors
+    /// When the socket can't receive, including when it would block or time out.
+    pub fn recv(&mut self) -> io::Result<Vec<Datagram>> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(uring) = &mut self.uring {
+                return uring.recv(self.local, self.nonblocking, self.read_timeout);
+            }
+        }
+        self.receiver.recv(&self.inner, self.local)
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neqo_common::Ecn;
+    use std::slice;
+
+    fn exchange(setup: fn(&mut Socket)) {
+        let mut server = Socket::bind("0.0.0.0:0").expect("bind");
+        setup(&mut server);
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let port = server.local_addr().port();
+        let mut client = Socket::bind("127.0.0.1:0").expect("bind");
+        client.connect(("127.0.0.1", port)).expect("connect");
+        setup(&mut client);
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client.set_tos(0x02).expect("set TOS");
+
+        let to_server = client.local_addr();
+        let to_server = Datagram::new(to_server, ([127, 0, 0, 1], port).into(), vec![1; 100]);
+        client.send(slice::from_ref(&to_server)).expect("send");
+        let received = server.recv().expect("recv");
+        // The server learns the address that was used, not the one it bound,
+        // and the marking that the socket set.
+        assert_eq!(received, vec![marked(to_server.clone(), 0x02)]);
+
+        // Answering from that address reaches the client.  Datagrams keep
+        // their own marking, and those without a DSCP get the socket's.
+        server.set_dscp(46);
+        let replies = (0..10)
+            .map(|i| {
+                let d = Datagram::new(to_server.destination(), to_server.source(), vec![i; 1000])
+                    .with_ecn(Ecn::Ect0);
+                if i % 2 == 0 {
+                    d.with_dscp(10)
+                } else {
+                    d
+                }
+            })
+            .collect::<Vec<_>>();
+        server.send(&replies).expect("send");
+        let mut all = Vec::new();
+        while all.len() < replies.len() {
+            all.extend(client.recv().expect("recv"));
+        }
+        let expected = replies
+            .iter()
+            .map(|d| {
+                let dscp = if d.dscp() == 0 { 46 } else { d.dscp() };
+                marked(d.clone(), dscp << 2 | u8::from(d.ecn()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(all, expected);
+    }
+
+    /// What a datagram sent with `tos` looks like when it is received.  Only
+    /// Linux reports the TOS byte.
+    fn marked(d: Datagram, tos: u8) -> Datagram {
+        if cfg!(target_os = "linux") {
+            d.with_tos(tos)
+        } else {
+            d.with_tos(0)
+        }
+    }
+
+    #[test]
+    fn syscalls() {
+        exchange(|_| {});
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn io_uring() {
+        exchange(|s| {
+            s.use_io_uring().expect("io_uring");
+            assert!(s.io_uring());
+        });
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn io_uring_nonblocking() {
+        let mut s = Socket::bind("127.0.0.1:0").expect("bind");
+        s.use_io_uring().expect("io_uring");
+        s.set_nonblocking(true).unwrap();
+        let err = s.recv().expect_err("nothing to receive");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_ne!(s.poll_fd(), s.as_raw_fd());
+    }
+}
@@ -0,0 +1,596 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The system calls that the datapath uses.  Everything unsafe lives here.
+
+use libc::{c_int, c_void, iovec, msghdr, sockaddr_storage, socklen_t};
+use neqo_common::Datagram;
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+pub const CONTROL_SIZE: usize = 128;
+
+/// Space for control messages, aligned as `cmsghdr` requires.
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+pub struct Control([u8; CONTROL_SIZE]);
+
+impl Default for Control {
+    fn default() -> Self {
+        Self([0; CONTROL_SIZE])
+    }
+}
+
+fn in_addr(a: Ipv4Addr) -> libc::in_addr {
+    libc::in_addr {
+        s_addr: u32::from_ne_bytes(a.octets()),
+    }
+}
+
+fn in6_addr(a: Ipv6Addr) -> libc::in6_addr {
+    libc::in6_addr {
+        s6_addr: a.octets(),
+    }
+}
+
+/// Convert an address into the form that the system calls use.
+pub fn sockaddr(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe {
+                &mut *(&mut storage as *mut sockaddr_storage).cast::<libc::sockaddr_in>()
+            };
+            sin.sin_family = libc::sa_family_t::try_from(libc::AF_INET).unwrap();
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr = in_addr(*a.ip());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe {
+                &mut *(&mut storage as *mut sockaddr_storage).cast::<libc::sockaddr_in6>()
+            };
+            sin6.sin6_family = libc::sa_family_t::try_from(libc::AF_INET6).unwrap();
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr = in6_addr(*a.ip());
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, socklen_t::try_from(len).unwrap())
+}
+
+/// Convert an address from the form that the system calls use.
+pub fn socket_addr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const sockaddr_storage).cast::<libc::sockaddr_in>() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 =
+                unsafe { &*(storage as *const sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn setsockopt_int(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const c_int).cast::<c_void>(),
+            socklen_t::try_from(mem::size_of::<c_int>()).unwrap(),
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Set the options that every socket needs: report the address that each
+/// datagram was sent to and the TOS byte or traffic class it had, and don't
+/// let datagrams be fragmented.
+#[cfg(target_os = "linux")]
+pub fn configure(fd: RawFd, v6: bool) -> io::Result<()> {
+    if v6 {
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )?;
+    }
+    // An IPv6 socket only needs this for IPv4 datagrams, so errors there
+    // don't matter.  An IPv6-only socket doesn't have any.
+    let res = setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)
+        .and_then(|()| setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1))
+        .and_then(|()| {
+            setsockopt_int(
+                fd,
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            )
+        });
+    if v6 {
+        Ok(())
+    } else {
+        res
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn configure(_fd: RawFd, _v6: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Set the TOS byte (IPv4) or traffic class (IPv6) of sent datagrams.
+pub fn set_tos(fd: RawFd, v6: bool, tos: u8) -> io::Result<()> {
+    if v6 {
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, c_int::from(tos))
+    } else {
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, c_int::from(tos))
+    }
+}
+
+/// Whether an IPv6 socket refuses IPv4.
+pub fn only_v6(fd: RawFd) -> io::Result<bool> {
+    let mut val: c_int = 0;
+    let mut len = socklen_t::try_from(mem::size_of::<c_int>()).unwrap();
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            (&mut val as *mut c_int).cast::<c_void>(),
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(val != 0)
+    }
+}
+
+/// Whether the kernel supports `UDP_SEGMENT` on this socket.
+#[cfg(target_os = "linux")]
+pub fn gso_supported(fd: RawFd) -> bool {
+    let mut val: c_int = 0;
+    let mut len = socklen_t::try_from(mem::size_of::<c_int>()).unwrap();
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            (&mut val as *mut c_int).cast::<c_void>(),
+            &mut len,
+        )
+    };
+    rc == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn gso_supported(_fd: RawFd) -> bool {
+    false
+}
+
+/// Ask the kernel to coalesce received datagrams.
+#[cfg(target_os = "linux")]
+pub fn enable_gro(fd: RawFd) -> bool {
+    setsockopt_int(fd, libc::SOL_UDP, libc::UDP_GRO, 1).is_ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_gro(_fd: RawFd) -> bool {
+    false
+}
+
+/// Stop the kernel from coalescing received datagrams.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub fn disable_gro(fd: RawFd) -> io::Result<()> {
+    setsockopt_int(fd, libc::SOL_UDP, libc::UDP_GRO, 0)
+}
+
+/// Append a control message to `msg`, which has a `Control` attached.
+/// Each control message takes a multiple of the alignment of `cmsghdr`.
+#[cfg(target_os = "linux")]
+#[allow(clippy::cast_ptr_alignment)]
+unsafe fn add_cmsg<T: Copy>(msg: &mut msghdr, level: c_int, ty: c_int, value: T) {
+    let used = msg.msg_controllen as usize;
+    let size = u32::try_from(mem::size_of::<T>()).unwrap();
+    let space = libc::CMSG_SPACE(size) as usize;
+    assert!(used + space <= CONTROL_SIZE);
+    let cmsg = msg
+        .msg_control
+        .cast::<u8>()
+        .add(used)
+        .cast::<libc::cmsghdr>();
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type = ty;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), value);
+    msg.msg_controllen = (used + space) as _;
+}
+
+/// Send from `src` rather than letting the kernel pick.
+#[cfg(target_os = "linux")]
+fn add_pktinfo(msg: &mut msghdr, src: IpAddr) {
+    unsafe {
+        match src {
+            IpAddr::V4(a) => add_cmsg(
+                msg,
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                libc::in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: in_addr(a),
+                    ipi_addr: in_addr(Ipv4Addr::UNSPECIFIED),
+                },
+            ),
+            IpAddr::V6(a) => add_cmsg(
+                msg,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                libc::in6_pktinfo {
+                    ipi6_addr: in6_addr(a),
+                    ipi6_ifindex: 0,
+                },
+            ),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_pktinfo(_msg: &mut msghdr, _src: IpAddr) {}
+
+/// Send with `tos` as the TOS byte (IPv4) or traffic class (IPv6), rather
+/// than what the socket has set.
+#[cfg(target_os = "linux")]
+fn add_tos(msg: &mut msghdr, dst: &SocketAddr, tos: u8) {
+    let tos = c_int::from(tos);
+    unsafe {
+        match dst {
+            SocketAddr::V4(_) => add_cmsg(msg, libc::IPPROTO_IP, libc::IP_TOS, tos),
+            SocketAddr::V6(_) => add_cmsg(msg, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_tos(_msg: &mut msghdr, _dst: &SocketAddr, _tos: u8) {}
+
+/// The TOS byte or traffic class to send `d` with: its own, with `dscp` as
+/// the DSCP if it doesn't have one.
+pub fn tos(d: &Datagram, dscp: u8) -> u8 {
+    if d.dscp() == 0 {
+        dscp << 2 | d.tos()
+    } else {
+        d.tos()
+    }
+}
+
+/// Have the kernel split what is sent into datagrams of `size`.
+#[cfg(target_os = "linux")]
+fn add_segment_size(msg: &mut msghdr, size: u16) {
+    unsafe { add_cmsg(msg, libc::SOL_UDP, libc::UDP_SEGMENT, size) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_segment_size(_msg: &mut msghdr, _size: u16) {
+    unreachable!("GSO is only used on Linux");
+}
+
+/// Fill out `msg` for sending `iov` from `src` to `dst`, which `addr` holds.
+/// A `tos` of zero leaves the TOS byte or traffic class to the socket.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_send(
+    msg: &mut msghdr,
+    addr: &mut (sockaddr_storage, socklen_t),
+    iov: &mut iovec,
+    control: &mut Control,
+    src: IpAddr,
+    dst: &SocketAddr,
+    tos: u8,
+    segment_size: Option<u16>,
+) {
+    msg.msg_name = (&mut addr.0 as *mut sockaddr_storage).cast::<c_void>();
+    msg.msg_namelen = addr.1;
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr().cast::<c_void>();
+    msg.msg_controllen = 0;
+    if let Some(size) = segment_size {
+        add_segment_size(msg, size);
+    }
+    if !src.is_unspecified() {
+        add_pktinfo(msg, src);
+    }
+    if tos != 0 {
+        add_tos(msg, dst, tos);
+    }
+    if msg.msg_controllen == 0 {
+        msg.msg_control = ptr::null_mut();
+    }
+}
+
+/// Send `buf` from `src` to `dst` with `tos`.  If `segment_size` is set, the
+/// kernel splits `buf` into datagrams of that size, except for the last,
+/// which can be smaller.
+pub fn send(
+    fd: RawFd,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    tos: u8,
+    buf: &[u8],
+    segment_size: Option<u16>,
+) -> io::Result<()> {
+    let mut addr = sockaddr(dst);
+    let mut iov = iovec {
+        iov_base: buf.as_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = Control::default();
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    prepare_send(
+        &mut msg,
+        &mut addr,
+        &mut iov,
+        &mut control,
+        src.ip(),
+        dst,
+        tos,
+        segment_size,
+    );
+    if unsafe { libc::sendmsg(fd, &msg, 0) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Send `dgrams` with one system call, returning how many were sent.  Those
+/// without a DSCP are sent with `dscp`.
+#[cfg(target_os = "linux")]
+pub fn send_many(fd: RawFd, dgrams: &[Datagram], dscp: u8) -> io::Result<usize> {
+    if dgrams.len() == 1 {
+        let d = &dgrams[0];
+        return send(fd, &d.source(), &d.destination(), tos(d, dscp), d, None).map(|()| 1);
+    }
+    let mut addrs = dgrams
+        .iter()
+        .map(|d| sockaddr(&d.destination()))
+        .collect::<Vec<_>>();
+    let mut iovs = dgrams
+        .iter()
+        .map(|d| iovec {
+            iov_base: d.as_ptr() as *mut c_void,
+            iov_len: d.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut controls = vec![Control::default(); dgrams.len()];
+    let mut msgs = dgrams
+        .iter()
+        .zip(addrs.iter_mut())
+        .zip(iovs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|(((d, addr), iov), control)| {
+            let mut m: libc::mmsghdr = unsafe { mem::zeroed() };
+            prepare_send(
+                &mut m.msg_hdr,
+                addr,
+                iov,
+                control,
+                d.source().ip(),
+                &d.destination(),
+                tos(d, dscp),
+                None,
+            );
+            m
+        })
+        .collect::<Vec<_>>();
+    let count = libc::c_uint::try_from(msgs.len()).unwrap();
+    let sent = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), count, 0) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(usize::try_from(sent).unwrap())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_many(fd: RawFd, dgrams: &[Datagram], dscp: u8) -> io::Result<usize> {
+    for (i, d) in dgrams.iter().enumerate() {
+        if let Err(e) = send(fd, &d.source(), &d.destination(), tos(d, dscp), d, None) {
+            return if i == 0 { Err(e) } else { Ok(i) };
+        }
+    }
+    Ok(dgrams.len())
+}
+
+/// What `recv` found.
+pub struct Received {
+    pub len: usize,
+    pub source: SocketAddr,
+    /// The address that the datagram was sent to, if the kernel said.
+    pub destination: Option<IpAddr>,
+    /// If the kernel coalesced datagrams, the size of each, except the last.
+    pub segment_size: Option<usize>,
+    /// The TOS byte or traffic class, or zero if the kernel didn't say.
+    pub tos: u8,
+    /// The buffer was too small for what was received.
+    pub truncated: bool,
+}
+
+impl Received {
+    /// The address that the datagram was sent to, on a socket bound to `local`.
+    pub fn local(&self, local: SocketAddr) -> SocketAddr {
+        SocketAddr::new(self.destination.unwrap_or_else(|| local.ip()), local.port())
+    }
+}
+
+/// Fill out `msg` for receiving into `iov`.
+fn prepare_recv(
+    msg: &mut msghdr,
+    addr: &mut sockaddr_storage,
+    iov: &mut iovec,
+    control: &mut Control,
+) {
+    msg.msg_name = (addr as *mut sockaddr_storage).cast::<c_void>();
+    msg.msg_namelen = socklen_t::try_from(mem::size_of::<sockaddr_storage>()).unwrap();
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr().cast::<c_void>();
+    msg.msg_controllen = CONTROL_SIZE as _;
+}
+
+/// Find the address that a datagram was sent to, the size of the datagrams
+/// that the kernel coalesced, and the TOS byte or traffic class, if the
+/// kernel said.
+#[cfg(target_os = "linux")]
+fn parse_control(msg: &msghdr) -> (Option<IpAddr>, Option<usize>, u8) {
+    let mut destination = None;
+    let mut segment_size = None;
+    let mut tos = 0;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = ptr::read_unaligned(data.cast::<libc::in_pktinfo>());
+                    let a = Ipv4Addr::from(info.ipi_addr.s_addr.to_ne_bytes());
+                    destination = Some(IpAddr::V4(a));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(data.cast::<libc::in6_pktinfo>());
+                    destination = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                }
+                (libc::SOL_UDP, libc::UDP_GRO) => {
+                    let size = ptr::read_unaligned(data.cast::<c_int>());
+                    segment_size = usize::try_from(size).ok();
+                }
+                // This is one byte, where the traffic class is an int.
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = ptr::read_unaligned(data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    let tclass = ptr::read_unaligned(data.cast::<c_int>());
+                    tos = u8::try_from(tclass & 0xff).unwrap();
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    (destination, segment_size, tos)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn parse_control(_msg: &msghdr) -> (Option<IpAddr>, Option<usize>, u8) {
+    (None, None, 0)
+}
+
+pub fn received(msg: &msghdr, addr: &sockaddr_storage, len: usize) -> io::Result<Received> {
+    let source = socket_addr(addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+    let (destination, segment_size, tos) = parse_control(msg);
+    Ok(Received {
+        len,
+        source,
+        destination,
+        segment_size,
+        tos,
+        truncated: (msg.msg_flags & libc::MSG_TRUNC) != 0,
+    })
+}
+
+/// Receive into `buf`.
+pub fn recv(fd: RawFd, buf: &mut [u8]) -> io::Result<Received> {
+    let mut addr: sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr().cast::<c_void>(),
+        iov_len: buf.len(),
+    };
+    let mut control = Control::default();
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    prepare_recv(&mut msg, &mut addr, &mut iov, &mut control);
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    received(&msg, &addr, usize::try_from(len).unwrap())
+}
+
+/// Receive as many datagrams as are available, up to one for each `slot`
+/// bytes of `buf`, waiting only for the first.
+#[cfg(target_os = "linux")]
+pub fn recv_many(fd: RawFd, buf: &mut [u8], slot: usize) -> io::Result<Vec<Received>> {
+    if buf.len() < 2 * slot {
+        return recv(fd, buf).map(|r| vec![r]);
+    }
+    let count = buf.len() / slot;
+    let mut addrs = vec![unsafe { mem::zeroed::<sockaddr_storage>() }; count];
+    let mut iovs = buf
+        .chunks_mut(slot)
+        .map(|b| iovec {
+            iov_base: b.as_mut_ptr().cast::<c_void>(),
+            iov_len: b.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut controls = vec![Control::default(); count];
+    let mut msgs = addrs
+        .iter_mut()
+        .zip(iovs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((addr, iov), control)| {
+            let mut m: libc::mmsghdr = unsafe { mem::zeroed() };
+            prepare_recv(&mut m.msg_hdr, addr, iov, control);
+            m
+        })
+        .collect::<Vec<_>>();
+    let n = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            libc::c_uint::try_from(count).unwrap(),
+            libc::MSG_WAITFORONE as _,
+            ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    msgs[..usize::try_from(n).unwrap()]
+        .iter()
+        .zip(addrs.iter())
+        .map(|(m, addr)| received(&m.msg_hdr, addr, usize::try_from(m.msg_len).unwrap()))
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_many(fd: RawFd, buf: &mut [u8], slot: usize) -> io::Result<Vec<Received>> {
+    recv(fd, &mut buf[..slot]).map(|r| vec![r])
+}
@@ -0,0 +1,380 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An io_uring datapath.  Datagrams are received with one multishot receive
+// into buffers that are registered with the kernel, and sent with one
+// submission for each batch.
+
+use crate::sys::{self, Control, CONTROL_SIZE};
+use io_uring::types::{BufRingEntry, Fd, RecvMsgOut, SubmitArgs, Timespec};
+use io_uring::{cqueue, opcode, IoUring};
+use libc::{c_void, iovec, msghdr, sockaddr_storage, socklen_t};
+use neqo_common::{qdebug, Datagram};
+use std::alloc::{self, Layout};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+/// The size of the submission queue.
+const RING_SIZE: u32 = 256;
+/// The most datagrams that are submitted for sending at once.
+const SEND_BATCH: usize = 64;
+/// The number of receive buffers.  This has to be a power of two.
+const RECV_BUFFERS: u16 = 256;
+/// The space for each received datagram, with its header, address and
+/// control messages.
+const RECV_BUFFER_SIZE: usize = 2048 + 512;
+/// The buffer ring has to start on a page.
+const PAGE_SIZE: usize = 4096;
+const BUFFER_GROUP: u16 = 0;
+
+const RECV: u64 = 0;
+const SEND: u64 = 1;
+const CANCEL: u64 = 2;
+
+pub struct Uring {
+    ring: IoUring,
+    fd: RawFd,
+    /// The ring that hands receive buffers to the kernel.
+    entries: NonNull<BufRingEntry>,
+    tail: u16,
+    bufs: Vec<u8>,
+    /// Describes how each receive buffer is laid out.  The kernel reads this
+    /// for as long as a receive is armed, so it doesn't move.
+    msg: Box<msghdr>,
+    armed: bool,
+    /// Receive completions that arrived while waiting for something else.
+    received: VecDeque<(i32, u32)>,
+}
+
+impl Uring {
+    #[allow(clippy::cast_ptr_alignment)] // The allocation is aligned to a page.
+    pub fn new(socket: &impl AsRawFd) -> io::Result<Self> {
+        let ring = IoUring::new(RING_SIZE)?;
+        let entries = unsafe { alloc::alloc_zeroed(Self::layout()) }.cast::<BufRingEntry>();
+        let entries = NonNull::new(entries)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no memory for buffers"))?;
+        let mut msg: Box<msghdr> = Box::new(unsafe { mem::zeroed() });
+        msg.msg_namelen = socklen_t::try_from(mem::size_of::<sockaddr_storage>()).unwrap();
+        msg.msg_controllen = CONTROL_SIZE as _;
+        let mut uring = Self {
+            ring,
+            fd: socket.as_raw_fd(),
+            entries,
+            tail: 0,
+            bufs: vec![0; usize::from(RECV_BUFFERS) * RECV_BUFFER_SIZE],
+            msg,
+            armed: false,
+            received: VecDeque::new(),
+        };
+        unsafe {
+            uring.ring.submitter().register_buf_ring_with_flags(
+                entries.as_ptr() as u64,
+                RECV_BUFFERS,
+                BUFFER_GROUP,
+                0,
+            )?;
+        }
+        for bid in 0..RECV_BUFFERS {
+            uring.provide(bid);
+        }
+        uring.publish();
+        uring.arm()?;
+        uring.ring.submit()?;
+        Ok(uring)
+    }
+
+    fn layout() -> Layout {
+        let size = mem::size_of::<BufRingEntry>() * usize::from(RECV_BUFFERS);
+        Layout::from_size_align(size, PAGE_SIZE).unwrap()
+    }
+
+    /// The descriptor that is readable when there is something to receive.
+    pub fn poll_fd(&self) -> RawFd {
+        self.ring.as_raw_fd()
+    }
+
+    /// Give buffer `bid` back to the kernel.  This needs `publish`.
+    fn provide(&mut self, bid: u16) {
+        let i = usize::from(self.tail & (RECV_BUFFERS - 1));
+        let buf = &mut self.bufs[usize::from(bid) * RECV_BUFFER_SIZE..][..RECV_BUFFER_SIZE];
+        let entry = unsafe { &mut *self.entries.as_ptr().add(i) };
+        entry.set_addr(buf.as_mut_ptr() as u64);
+        entry.set_len(u32::try_from(RECV_BUFFER_SIZE).unwrap());
+        entry.set_bid(bid);
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        let tail = unsafe { BufRingEntry::tail(self.entries.as_ptr()) };
+        unsafe { &*tail.cast::<AtomicU16>() }.store(self.tail, Ordering::Release);
+    }
+
+    fn arm(&mut self) -> io::Result<()> {
+        let recv = opcode::RecvMsgMulti::new(Fd(self.fd), &*self.msg, BUFFER_GROUP)
+            .build()
+            .user_data(RECV);
+        unsafe { self.ring.submission().push(&recv) }
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "submission queue is full"))?;
+        self.armed = true;
+        Ok(())
+    }
+
+    /// Take completions, keeping those for receives.  Returns how many sends
+    /// completed, and the first error that any of them had.
+    fn reap(&mut self) -> (usize, Option<io::Error>) {
+        let mut sent = 0;
+        let mut err = None;
+        for cqe in self.ring.completion() {
+            match cqe.user_data() {
+                RECV => {
+                    if !cqueue::more(cqe.flags()) {
+                        self.armed = false;
+                    }
+                    self.received.push_back((cqe.result(), cqe.flags()));
+                }
+                SEND => {
+                    sent += 1;
+                    if cqe.result() < 0 && err.is_none() {
+                        err = Some(io::Error::from_raw_os_error(-cqe.result()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        (sent, err)
+    }
+
+    /// Send `dgrams` in order, with `dscp` for those that don't have one.
+    pub fn send(&mut self, dgrams: &[Datagram], dscp: u8) -> io::Result<()> {
+        for batch in dgrams.chunks(SEND_BATCH) {
+            let mut addrs = batch
+                .iter()
+                .map(|d| sys::sockaddr(&d.destination()))
+                .collect::<Vec<_>>();
+            let mut iovs = batch
+                .iter()
+                .map(|d| iovec {
+                    iov_base: d.as_ptr() as *mut c_void,
+                    iov_len: d.len(),
+                })
+                .collect::<Vec<_>>();
+            let mut controls = vec![Control::default(); batch.len()];
+            let msgs = batch
+                .iter()
+                .zip(addrs.iter_mut())
+                .zip(iovs.iter_mut())
+                .zip(controls.iter_mut())
+                .map(|(((d, addr), iov), control)| {
+                    let mut msg: msghdr = unsafe { mem::zeroed() };
+                    sys::prepare_send(
+                        &mut msg,
+                        addr,
+                        iov,
+                        control,
+                        d.source().ip(),
+                        &d.destination(),
+                        sys::tos(d, dscp),
+                        None,
+                    );
+                    msg
+                })
+                .collect::<Vec<_>>();
+            let sends = msgs
+                .iter()
+                .map(|msg| {
+                    opcode::SendMsg::new(Fd(self.fd), msg)
+                        .build()
+                        .user_data(SEND)
+                })
+                .collect::<Vec<_>>();
+            // Queue all of the batch or none of it, so that nothing is left in
+            // the queue pointing at `msgs` if this fails.  Whatever is queued
+            // already is submitted first, to make room.
+            let free = {
+                let sq = self.ring.submission();
+                sq.capacity() - sq.len()
+            };
+            if free < sends.len() {
+                self.ring.submit()?;
+            }
+            unsafe { self.ring.submission().push_multiple(&sends) }
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "submission queue is full"))?;
+
+            // Everything that the kernel reads has to stay put until it is done.
+            let mut remaining = msgs.len();
+            let mut first_err = None;
+            while remaining > 0 {
+                match self.ring.submit_and_wait(1) {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+                let (sent, err) = self.reap();
+                remaining -= sent;
+                first_err = first_err.or(err);
+            }
+            if let Some(e) = first_err {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for something to be received, for up to `timeout`.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let res = match timeout {
+            Some(t) => {
+                let ts = Timespec::from(t);
+                self.ring
+                    .submitter()
+                    .submit_with_args(1, &SubmitArgs::new().timespec(&ts))
+            }
+            None => self.ring.submit_and_wait(1),
+        };
+        match res {
+            Err(ref e) if e.raw_os_error() == Some(libc::ETIME) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Receive on a socket that is bound to `local`.  This waits for up to
+    /// `timeout` unless `nonblocking` is set.
+    pub fn recv(
+        &mut self,
+        local: SocketAddr,
+        nonblocking: bool,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<Datagram>> {
+        if !self.armed {
+            self.arm()?;
+        }
+        if nonblocking {
+            self.ring.submit()?;
+        } else if self.received.is_empty() && self.ring.completion().is_empty() {
+            self.wait(timeout)?;
+        }
+        self.reap();
+        if self.received.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        let mut dgrams = Vec::with_capacity(self.received.len());
+        let mut first_err = None;
+        while let Some((res, flags)) = self.received.pop_front() {
+            if res < 0 {
+                // Running out of buffers stops the receive.  It is armed
+                // again once they are returned.
+                if res != -libc::ENOBUFS && first_err.is_none() {
+                    first_err = Some(io::Error::from_raw_os_error(-res));
+                }
+                continue;
+            }
+            let bid = cqueue::buffer_select(flags).expect("receive with a buffer");
+            let start = usize::from(bid) * RECV_BUFFER_SIZE;
+            let buf = &self.bufs[start..start + usize::try_from(res).unwrap()];
+            match Self::parse(buf, &self.msg, local) {
+                Ok(d) => dgrams.push(d),
+                Err(e) => qdebug!("Discarding received datagram: {}", e),
+            }
+            self.provide(bid);
+        }
+        self.publish();
+        if !self.armed {
+            self.arm()?;
+            self.ring.submit()?;
+        }
+        match first_err {
+            Some(e) if dgrams.is_empty() => Err(e),
+            _ => Ok(dgrams),
+        }
+    }
+
+    fn parse(buf: &[u8], template: &msghdr, local: SocketAddr) -> io::Result<Datagram> {
+        let out = RecvMsgOut::parse(buf, template)
+            .map_err(|()| io::Error::new(io::ErrorKind::InvalidData, "short receive"))?;
+        let name = out.name_data();
+        let mut addr: sockaddr_storage = unsafe { mem::zeroed() };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                name.as_ptr(),
+                (&mut addr as *mut sockaddr_storage).cast::<u8>(),
+                name.len().min(mem::size_of::<sockaddr_storage>()),
+            );
+        }
+        // The control messages are parsed in place.
+        let control = out.control_data();
+        let mut msg: msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as _;
+        msg.msg_flags = libc::c_int::from_ne_bytes(out.flags().to_ne_bytes());
+        let r = sys::received(&msg, &addr, out.payload_data().len())?;
+        if r.truncated {
+            qdebug!("Truncated datagram from {}", r.source);
+        }
+        Ok(Datagram::new(r.source, r.local(local), out.payload_data()).with_tos(r.tos))
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // The kernel can write to the buffers until the receive ends.
+        let cancel = opcode::AsyncCancel::new(RECV).build().user_data(CANCEL);
+        if self.armed && unsafe { self.ring.submission().push(&cancel) }.is_ok() {
+            while self.armed && self.ring.submit_and_wait(1).is_ok() {
+                self.reap();
+            }
+        }
+        let _ = self.ring.submitter().unregister_buf_ring(BUFFER_GROUP);
+        unsafe { alloc::dealloc(self.entries.as_ptr().cast::<u8>(), Self::layout()) };
+    }
+}
+
+impl ::std::fmt::Debug for Uring {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Uring fd={} armed={}", self.fd, self.armed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn send_with_queue_nearly_full() {
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        rx.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut uring = Uring::new(&tx).expect("io_uring");
+        // Leave less room than a batch needs.
+        let nop = opcode::Nop::new().build().user_data(CANCEL);
+        for _ in 0..RING_SIZE as usize - SEND_BATCH / 2 {
+            unsafe { uring.ring.submission().push(&nop) }.unwrap();
+        }
+
+        let dgrams = (0..SEND_BATCH)
+            .map(|i| {
+                let data = vec![u8::try_from(i).unwrap(); 10];
+                Datagram::new(tx.local_addr().unwrap(), rx.local_addr().unwrap(), data)
+            })
+            .collect::<Vec<_>>();
+        uring.send(&dgrams, 0).expect("send");
+        let mut buf = [0; 16];
+        for d in &dgrams {
+            let (n, _) = rx.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], &d[..]);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::driver::{self, Command, Driver, Requests};
+use crate::{ConnectionState, Header, NeqoError};
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle, ThreadId};
+
+/// What an app is told about a connection.  Every method is called on the
+/// connection's thread, which waits for it to return, so a method that has
+/// a lot to do should hand the work to another thread.  Methods can call
+/// `Client`, such as to send more of a body from `on_data_writable`.
+#[uniffi::export(callback_interface)]
+pub trait ClientListener: Send + Sync {
+    /// Decide whether to accept the server certificate.  `chain` has the
+    /// certificates that the server sent, in DER, starting with its own.
+    /// NSS has not checked them: this is where an app uses the platform
+    /// verifier.
+    fn verify_certificate(&self, chain: Vec<Vec<u8>>) -> bool;
+
+    fn on_state_change(&self, state: ConnectionState);
+
+    /// The response headers for a request.  If `fin` is set, there is no body.
+    fn on_headers(&self, stream_id: u64, headers: Vec<Header>, fin: bool);
+
+    /// Some of a response body.  If `fin` is set, the response is complete.
+    fn on_data(&self, stream_id: u64, data: Vec<u8>, fin: bool);
+
+    /// More of the request body can be sent on `stream_id`.
+    fn on_data_writable(&self, stream_id: u64);
+
+    /// The server reset the response.
+    fn on_reset(&self, stream_id: u64, error: u64);
+
+    /// The server doesn't want any more of the request body.
+    fn on_stop_sending(&self, stream_id: u64, error: u64);
+}
+
+/// An HTTP/3 client connection to one server.
+#[derive(uniffi::Object)]
+pub struct Client {
+    commands: Mutex<Sender<Command>>,
+    /// Writing to this wakes the connection thread, so that it looks at
+    /// `commands`.
+    wake: UnixDatagram,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    thread_id: ThreadId,
+}
+
+impl Client {
+    /// Have the connection thread run `f`, and wait for what it returns.
+    /// From the listener, which is on that thread, `f` runs right away.
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Requests) -> Result<T, NeqoError> + Send + 'static,
+    ) -> Result<T, NeqoError> {
+        if thread::current().id() == self.thread_id {
+            return driver::with_requests(f);
+        }
+        let (tx, rx) = channel();
+        let cmd: Command = Box::new(move |r: &mut Requests| {
+            let _ = tx.send(f(r));
+        });
+        self.commands
+            .lock()
+            .unwrap()
+            .send(cmd)
+            .map_err(|_| NeqoError::Closed)?;
+        // The thread only needs to see one byte to wake up, so if the buffer
+        // is full, there is nothing to do.
+        let _ = self.wake.send(&[0]);
+        rx.recv().map_err(|_| NeqoError::Closed)?
+    }
+}
+
+#[uniffi::export]
+impl Client {
+    /// Resolve `host`, bind a UDP socket, and start the handshake with
+    /// `alpn`, such as "h3-24".  `host` is also the server name that the
+    /// certificate is for and the authority of requests.
+    #[uniffi::constructor]
+    pub fn new(
+        host: String,
+        port: u16,
+        alpn: Vec<String>,
+        listener: Box<dyn ClientListener>,
+    ) -> Result<Arc<Self>, NeqoError> {
+        let remote = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| NeqoError::InvalidArgument {
+                reason: format!("can't resolve {}", host),
+            })?;
+        let local = if remote.is_ipv4() {
+            SocketAddr::from(([0; 4], 0))
+        } else {
+            SocketAddr::from(([0; 16], 0))
+        };
+        let (wake, wake_rx) = UnixDatagram::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+
+        // Neither the socket nor the connection can move between threads,
+        // so both are made on the connection thread.
+        let (commands, commands_rx) = channel();
+        let (ready, ready_rx) = channel();
+        let thread = thread::Builder::new()
+            .name(format!("neqo {}", host))
+            .spawn(move || {
+                match Driver::new(&host, &alpn, local, remote, listener, commands_rx, wake_rx) {
+                    Ok(mut d) => {
+                        let _ = ready.send(Ok(()));
+                        d.run();
+                    }
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                    }
+                }
+            })?;
+        ready_rx.recv().map_err(|_| NeqoError::Closed)??;
+        Ok(Arc::new(Client {
+            commands: Mutex::new(commands),
+            wake,
+            thread_id: thread.thread().id(),
+            thread: Mutex::new(Some(thread)),
+        }))
+    }
+
+    /// Make a request, and return the stream ID for it.  Send the body, if
+    /// there is one, with `send_body`, then call `close_send`.  The response
+    /// is given to the listener.
+    pub fn fetch(
+        &self,
+        method: String,
+        path: String,
+        headers: Vec<Header>,
+    ) -> Result<u64, NeqoError> {
+        self.call(move |d| d.fetch(&method, &path, &headers))
+    }
+
+    /// Send some of a request body, and return how much was taken.  When
+    /// not all of it is, wait for `on_data_writable` to send the rest.
+    pub fn send_body(&self, stream_id: u64, data: Vec<u8>) -> Result<u64, NeqoError> {
+        self.call(move |d| d.send_body(stream_id, &data))
+    }
+
+    /// Finish sending a request.
+    pub fn close_send(&self, stream_id: u64) -> Result<(), NeqoError> {
+        self.call(move |d| d.close_send(stream_id))
+    }
+
+    /// Cancel a request.
+    pub fn reset(&self, stream_id: u64, error: u64) -> Result<(), NeqoError> {
+        self.call(move |d| d.reset(stream_id, error))
+    }
+
+    /// Close the connection.  The listener sees it close, after which the
+    /// connection's thread stops.
+    pub fn close(&self, error: u64, reason: String) {
+        let _ = self.call(move |d| {
+            d.close(error, &reason);
+            Ok(())
+        });
+    }
+}
+
+impl Drop for Client {
+    /// Close the connection, and wait for the thread to stop, unless this
+    /// is that thread.
+    fn drop(&mut self) {
+        self.close(0, String::new());
+        if thread::current().id() == self.thread_id {
+            return;
+        }
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
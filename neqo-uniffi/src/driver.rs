@@ -0,0 +1,322 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The thread that runs a connection.  It waits on the UDP socket and on a
+// socket that `Client` writes to when it has queued a command, sends what
+// the connection produces, and calls the listener for each event.
+
+use crate::client::ClientListener;
+use crate::{Header, NeqoError};
+use neqo_common::{matches, qdebug, qwarn};
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Http3Client, Http3ClientEvent, Http3State, Output};
+use neqo_transport::FixedConnectionIdManager;
+use neqo_udp::Socket;
+
+use std::cell::{RefCell, RefMut};
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// How much is read from a stream at once.
+const READ_SIZE: usize = 0x1_0000;
+const MAX_TABLE_SIZE: u32 = 128;
+const MAX_BLOCKED_STREAMS: u16 = 128;
+
+pub(crate) type Command = Box<dyn FnOnce(&mut Requests) + Send>;
+
+/// The part of the connection that `Client` acts on.
+pub(crate) struct Requests {
+    client: Http3Client,
+    host: String,
+}
+
+impl Requests {
+    pub fn fetch(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Result<u64, NeqoError> {
+        let headers = headers
+            .iter()
+            .map(|h| (h.name.clone(), h.value.clone()))
+            .collect::<Vec<_>>();
+        Ok(self
+            .client
+            .fetch(method, "https", &self.host, path, &headers)?)
+    }
+
+    pub fn send_body(&mut self, stream_id: u64, data: &[u8]) -> Result<u64, NeqoError> {
+        let sent = self.client.send_request_body(stream_id, data)?;
+        Ok(u64::try_from(sent).unwrap())
+    }
+
+    pub fn close_send(&mut self, stream_id: u64) -> Result<(), NeqoError> {
+        Ok(self.client.stream_close_send(stream_id)?)
+    }
+
+    pub fn reset(&mut self, stream_id: u64, error: u64) -> Result<(), NeqoError> {
+        Ok(self.client.stream_reset(stream_id, error)?)
+    }
+
+    pub fn close(&mut self, error: u64, reason: &str) {
+        self.client.close(Instant::now(), error, reason);
+    }
+}
+
+thread_local! {
+    /// The connection that this thread runs, if it is a connection thread.
+    /// The listener runs on that thread, so when it calls `Client`, the
+    /// command is run here rather than queued for a thread that is waiting
+    /// for the listener to return.
+    static REQUESTS: RefCell<Option<Rc<RefCell<Requests>>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` on the connection that this thread runs.
+pub(crate) fn with_requests<T>(
+    f: impl FnOnce(&mut Requests) -> Result<T, NeqoError>,
+) -> Result<T, NeqoError> {
+    let requests = REQUESTS
+        .with(|r| r.borrow().clone())
+        .ok_or(NeqoError::Closed)?;
+    let mut requests = requests.borrow_mut();
+    f(&mut requests)
+}
+
+enum Dispatched {
+    None,
+    Some,
+    /// The connection is closed, so the thread can stop.
+    Closed,
+}
+
+pub(crate) struct Driver {
+    requests: Rc<RefCell<Requests>>,
+    socket: Socket,
+    listener: Box<dyn ClientListener>,
+    commands: Receiver<Command>,
+    wake: UnixDatagram,
+}
+
+impl Driver {
+    pub fn new(
+        host: &str,
+        alpn: &[String],
+        local: SocketAddr,
+        remote: SocketAddr,
+        listener: Box<dyn ClientListener>,
+        commands: Receiver<Command>,
+        wake: UnixDatagram,
+    ) -> Result<Self, NeqoError> {
+        let mut socket = Socket::bind(local)?;
+        socket.connect(remote)?;
+        socket.set_nonblocking(true)?;
+        let client = Http3Client::new(
+            host,
+            alpn,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            socket.local_addr(),
+            remote,
+            MAX_TABLE_SIZE,
+            MAX_BLOCKED_STREAMS,
+        )?;
+        Ok(Driver {
+            requests: Rc::new(RefCell::new(Requests {
+                client,
+                host: host.to_string(),
+            })),
+            socket,
+            listener,
+            commands,
+            wake,
+        })
+    }
+
+    /// Run the connection until it closes, or until the socket fails.
+    pub fn run(&mut self) {
+        REQUESTS.with(|r| *r.borrow_mut() = Some(Rc::clone(&self.requests)));
+        if let Err(e) = self.drive() {
+            qwarn!("Connection thread stopping: {}", e);
+        }
+        REQUESTS.with(|r| *r.borrow_mut() = None);
+    }
+
+    fn client(&self) -> RefMut<'_, Http3Client> {
+        RefMut::map(self.requests.borrow_mut(), |r| &mut r.client)
+    }
+
+    fn drive(&mut self) -> io::Result<()> {
+        loop {
+            while let Ok(cmd) = self.commands.try_recv() {
+                cmd(&mut self.requests.borrow_mut());
+            }
+            self.client().process_http3(Instant::now());
+            let timeout = self.send()?;
+            match self.dispatch() {
+                Dispatched::Closed => return Ok(()),
+                // The listener might have made requests, so send those
+                // before waiting.
+                Dispatched::Some => continue,
+                Dispatched::None => {}
+            }
+
+            if self.wait(timeout)? {
+                self.receive()?;
+            } else {
+                self.client().process_timer(Instant::now());
+            }
+        }
+    }
+
+    /// Send everything that there is to send, and return how long to wait
+    /// for, or `None` to wait until there is something to do.
+    fn send(&mut self) -> io::Result<Option<Duration>> {
+        loop {
+            let out = self.client().process_output(Instant::now());
+            match out {
+                Output::Datagram(d) => self.socket.send(&[d])?,
+                Output::Callback(t) => break Ok(Some(t)),
+                Output::None => break Ok(None),
+            }
+        }
+    }
+
+    /// Wait for a datagram or a command, or for `timeout` to pass.  This
+    /// returns whether the socket has anything to read.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.socket.poll_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wake.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // Round up, so that the timer has expired when this returns.
+        let ms = timeout.map_or(-1, |t| {
+            libc::c_int::try_from((t + Duration::from_micros(999)).as_millis())
+                .unwrap_or(libc::c_int::MAX)
+        });
+        let rv = unsafe { libc::poll(fds.as_mut_ptr(), 2, ms) };
+        if rv < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            };
+        }
+        if fds[1].revents != 0 {
+            let mut buf = [0; 16];
+            while self.wake.recv(&mut buf).is_ok() {}
+        }
+        Ok(fds[0].revents != 0)
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        loop {
+            match self.socket.recv() {
+                Ok(dgrams) => {
+                    for d in dgrams.into_iter().filter(|d| !d.is_empty()) {
+                        self.client().process_input(d, Instant::now());
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn verify_certificate(&mut self) {
+        let chain = self.client().peer_certificate_chain();
+        let status = if self.listener.verify_certificate(chain) {
+            AuthenticationStatus::Ok
+        } else {
+            AuthenticationStatus::Unknown
+        };
+        self.client().authenticated(status, Instant::now());
+    }
+
+    fn read_data(&mut self, stream_id: u64) {
+        loop {
+            let mut buf = vec![0; READ_SIZE];
+            let res = self
+                .client()
+                .read_response_data(Instant::now(), stream_id, &mut buf);
+            match res {
+                Ok((len, fin)) => {
+                    if len > 0 || fin {
+                        buf.truncate(len);
+                        self.listener.on_data(stream_id, buf, fin);
+                    }
+                    if len == 0 || fin {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    qdebug!("Unable to read stream {}: {:?}", stream_id, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tell the listener about each event.  No borrow of the connection is
+    /// held while the listener runs, so that it can call `Client`.
+    fn dispatch(&mut self) -> Dispatched {
+        let mut dispatched = Dispatched::None;
+        loop {
+            let event = self.client().next_event();
+            if event.is_some() {
+                dispatched = Dispatched::Some;
+            }
+            match event {
+                None => break,
+                Some(Http3ClientEvent::AuthenticationNeeded) => self.verify_certificate(),
+                Some(Http3ClientEvent::HeaderReady { stream_id }) => {
+                    let res = self.client().read_response_headers(stream_id);
+                    match res {
+                        Ok((headers, fin)) => self.listener.on_headers(
+                            stream_id,
+                            headers.into_iter().map(Header::from).collect(),
+                            fin,
+                        ),
+                        Err(e) => qdebug!("Unable to read headers {}: {:?}", stream_id, e),
+                    }
+                }
+                Some(Http3ClientEvent::DataReadable { stream_id }) => self.read_data(stream_id),
+                Some(Http3ClientEvent::DataWritable { stream_id }) => {
+                    self.listener.on_data_writable(stream_id)
+                }
+                Some(Http3ClientEvent::Reset { stream_id, error }) => {
+                    self.listener.on_reset(stream_id, error)
+                }
+                Some(Http3ClientEvent::StopSending { stream_id, error }) => {
+                    self.listener.on_stop_sending(stream_id, error)
+                }
+                Some(Http3ClientEvent::StateChange(state)) => {
+                    self.listener.on_state_change((&state).into())
+                }
+                Some(_) => {}
+            }
+        }
+        if matches!(self.client().state(), Http3State::Closed(_)) {
+            Dispatched::Closed
+        } else {
+            dispatched
+        }
+    }
+}
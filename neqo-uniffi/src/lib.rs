@@ -0,0 +1,136 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Kotlin and Swift bindings for the HTTP/3 client, made with uniffi.  A
+// `Client` owns a UDP socket and a thread that runs the connection to one
+// server.  Requests are made from any thread, and everything that happens on
+// the connection, including response headers and body data, is given to a
+// `ClientListener` on the connection's thread.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+mod client;
+mod driver;
+
+use neqo_http3::Http3State;
+use neqo_transport::CloseError;
+
+use std::fmt;
+use std::path::Path;
+
+pub use self::client::{Client, ClientListener};
+
+uniffi::setup_scaffolding!();
+
+#[derive(Debug, uniffi::Error)]
+pub enum NeqoError {
+    /// The socket failed.
+    Io { reason: String },
+    /// neqo refused the operation, or the connection failed.
+    Http3 { reason: String },
+    /// The connection is closed, so nothing more can be done with it.
+    Closed,
+    /// An argument isn't usable, such as a host that can't be resolved.
+    InvalidArgument { reason: String },
+}
+
+impl From<std::io::Error> for NeqoError {
+    fn from(err: std::io::Error) -> Self {
+        NeqoError::Io {
+            reason: err.to_string(),
+        }
+    }
+}
+
+impl From<neqo_http3::Error> for NeqoError {
+    fn from(err: neqo_http3::Error) -> Self {
+        NeqoError::Http3 {
+            reason: format!("{:?}", err),
+        }
+    }
+}
+
+impl fmt::Display for NeqoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NeqoError::Io { reason } => write!(f, "Socket error: {}", reason),
+            NeqoError::Http3 { reason } => write!(f, "{}", reason),
+            NeqoError::Closed => write!(f, "Connection closed"),
+            NeqoError::InvalidArgument { reason } => write!(f, "Invalid argument: {}", reason),
+        }
+    }
+}
+
+impl ::std::error::Error for NeqoError {}
+
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl From<neqo_http3::Header> for Header {
+    fn from((name, value): neqo_http3::Header) -> Self {
+        Header { name, value }
+    }
+}
+
+/// Why a connection closed, with the error code.
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum CloseReason {
+    Transport { code: u64 },
+    Application { code: u64 },
+}
+
+impl From<&CloseError> for CloseReason {
+    fn from(err: &CloseError) -> Self {
+        match err {
+            CloseError::Transport(code) => CloseReason::Transport { code: *code },
+            CloseError::Application(code) => CloseReason::Application { code: *code },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum ConnectionState {
+    Initializing,
+    ZeroRtt,
+    Connected,
+    GoingAway,
+    Closing { reason: CloseReason },
+    Closed { reason: CloseReason },
+}
+
+impl From<&Http3State> for ConnectionState {
+    fn from(state: &Http3State) -> Self {
+        match state {
+            Http3State::Initializing => ConnectionState::Initializing,
+            Http3State::ZeroRtt => ConnectionState::ZeroRtt,
+            Http3State::Connected => ConnectionState::Connected,
+            Http3State::GoingAway => ConnectionState::GoingAway,
+            Http3State::Closing(e) => ConnectionState::Closing { reason: e.into() },
+            Http3State::Closed(e) => ConnectionState::Closed { reason: e.into() },
+        }
+    }
+}
+
+/// Initialize NSS, with the database in `db` if it is given.  This has to be
+/// called once, before any client is made.
+#[uniffi::export]
+pub fn init(db: Option<String>) -> Result<(), NeqoError> {
+    match db {
+        None => neqo_crypto::init(),
+        Some(dir) => {
+            if !Path::new(&dir).is_dir() {
+                return Err(NeqoError::InvalidArgument {
+                    reason: format!("{} is not a directory", dir),
+                });
+            }
+            neqo_crypto::init_db(dir);
+        }
+    }
+    Ok(())
+}
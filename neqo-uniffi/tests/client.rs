@@ -0,0 +1,170 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+
+use neqo_http3::Http3ServerEvent;
+use neqo_transport::Output;
+use neqo_udp::Socket;
+use neqo_uniffi::{init, Client, ClientListener, ConnectionState, Header, NeqoError};
+use test_fixture::{default_http3_server, DEFAULT_ALPN, NSS_DB_PATH};
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const BODY: &[u8] = b"hello";
+
+/// Run an HTTP/3 server on a thread of its own until `stop` is set.  It
+/// answers every request with `BODY`.
+fn server(stop: Arc<AtomicBool>) -> (SocketAddr, JoinHandle<()>) {
+    let (addr, addr_rx) = channel();
+    let thread = thread::spawn(move || {
+        let mut socket = Socket::bind("127.0.0.1:0").expect("bind");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        addr.send(socket.local_addr()).unwrap();
+        let mut server = default_http3_server();
+        while !stop.load(Ordering::Relaxed) {
+            // This times out when there is nothing to receive.
+            let received = socket.recv().unwrap_or_default();
+            let mut out = Vec::new();
+            for d in received {
+                out.extend(server.process(Some(d), Instant::now()).dgram());
+            }
+            while let Some(event) = server.next_event() {
+                if let Http3ServerEvent::Headers { mut request, .. } = event {
+                    request
+                        .set_response(
+                            &[(String::from(":status"), String::from("200"))],
+                            BODY.to_vec(),
+                        )
+                        .unwrap();
+                }
+            }
+            while let Output::Datagram(d) = server.process(None, Instant::now()) {
+                out.push(d);
+            }
+            if !out.is_empty() {
+                socket.send(&out).expect("send");
+            }
+        }
+    });
+    (addr_rx.recv().unwrap(), thread)
+}
+
+#[derive(Debug)]
+enum Seen {
+    State(ConnectionState),
+    Headers(u64, Vec<Header>, bool),
+    Data(u64, Vec<u8>, bool),
+}
+
+/// Accepts any certificate, and passes on what it sees.
+struct Listener(Mutex<Sender<Seen>>);
+
+impl Listener {
+    fn seen(&self, s: Seen) {
+        let _ = self.0.lock().unwrap().send(s);
+    }
+}
+
+impl ClientListener for Listener {
+    fn verify_certificate(&self, chain: Vec<Vec<u8>>) -> bool {
+        assert!(!chain.is_empty());
+        true
+    }
+
+    fn on_state_change(&self, state: ConnectionState) {
+        self.seen(Seen::State(state));
+    }
+
+    fn on_headers(&self, stream_id: u64, headers: Vec<Header>, fin: bool) {
+        self.seen(Seen::Headers(stream_id, headers, fin));
+    }
+
+    fn on_data(&self, stream_id: u64, data: Vec<u8>, fin: bool) {
+        self.seen(Seen::Data(stream_id, data, fin));
+    }
+
+    fn on_data_writable(&self, _stream_id: u64) {}
+
+    fn on_reset(&self, stream_id: u64, error: u64) {
+        panic!("stream {} reset with {}", stream_id, error);
+    }
+
+    fn on_stop_sending(&self, stream_id: u64, error: u64) {
+        panic!("stream {} stopped with {}", stream_id, error);
+    }
+}
+
+#[test]
+fn fetch() {
+    init(Some(String::from(NSS_DB_PATH))).expect("initialize NSS");
+    let stop = Arc::new(AtomicBool::new(false));
+    let (addr, server) = server(Arc::clone(&stop));
+
+    let (seen, seen_rx) = channel();
+    let client = Client::new(
+        addr.ip().to_string(),
+        addr.port(),
+        DEFAULT_ALPN.iter().map(|a| a.to_string()).collect(),
+        Box::new(Listener(Mutex::new(seen))),
+    )
+    .expect("make a client");
+    let stream_id = client
+        .fetch(String::from("GET"), String::from("/"), Vec::new())
+        .expect("fetch");
+    client.close_send(stream_id).expect("close the request");
+
+    let mut connected = false;
+    let mut status = None;
+    let mut body = Vec::new();
+    loop {
+        match seen_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the response")
+        {
+            Seen::State(ConnectionState::Connected) => connected = true,
+            Seen::State(_) => {}
+            Seen::Headers(id, headers, fin) => {
+                assert_eq!(id, stream_id);
+                assert!(!fin);
+                status = headers
+                    .into_iter()
+                    .find(|h| h.name == ":status")
+                    .map(|h| h.value);
+            }
+            Seen::Data(id, data, fin) => {
+                assert_eq!(id, stream_id);
+                body.extend_from_slice(&data);
+                if fin {
+                    break;
+                }
+            }
+        }
+    }
+    assert!(connected);
+    assert_eq!(status, Some(String::from("200")));
+    assert_eq!(body, BODY);
+
+    // This closes the connection and waits for its thread.
+    drop(client);
+    stop.store(true, Ordering::Relaxed);
+    server.join().unwrap();
+}
+
+#[test]
+fn init_needs_a_directory() {
+    match init(Some(String::from("/nonexistent"))) {
+        Err(NeqoError::InvalidArgument { .. }) => {}
+        r => panic!("initialized without a database: {:?}", r),
+    }
+}
@@ -0,0 +1,126 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Tests in which one side of a `Pair` breaks the protocol, to check that its
+// peer closes the connection with the right error.  The frames here are
+// encoded without any checks and sent with `Connection::inject_frames`:
+//
+//     let mut pair = Pair::new();
+//     pair.handshake();
+//     pair.client().inject_frames(3, adversary::stream(0, 1 << 40, &[1], false));
+//     adversary::assert_closes(&mut pair, Side::Client, Error::FlowControlError);
+//
+// The functions that take a datagram change it after it has been protected,
+// for things like connection IDs that a peer can't check until later.
+
+use crate::pair::{Pair, Side};
+use neqo_common::{matches, Datagram, Encoder};
+use neqo_transport::{ConnectionError, Error, State};
+
+fn encode(frame_type: u64, body: impl FnOnce(&mut Encoder)) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode_varint(frame_type);
+    body(&mut enc);
+    enc.into()
+}
+
+/// A STREAM frame, with an offset and a length.
+pub fn stream(stream_id: u64, offset: u64, data: &[u8], fin: bool) -> Vec<u8> {
+    let frame_type = if fin { 0x0f } else { 0x0e };
+    encode(frame_type, |enc| {
+        enc.encode_varint(stream_id)
+            .encode_varint(offset)
+            .encode_vvec(data);
+    })
+}
+
+/// A STREAM frame that says it is longer than any packet.
+pub fn truncated_stream(stream_id: u64) -> Vec<u8> {
+    encode(0x0a, |enc| {
+        enc.encode_varint(stream_id).encode_varint(0x3fff_u64);
+    })
+}
+
+pub fn reset_stream(stream_id: u64, error: u64, final_size: u64) -> Vec<u8> {
+    encode(0x04, |enc| {
+        enc.encode_varint(stream_id)
+            .encode_varint(error)
+            .encode_varint(final_size);
+    })
+}
+
+pub fn stop_sending(stream_id: u64, error: u64) -> Vec<u8> {
+    encode(0x05, |enc| {
+        enc.encode_varint(stream_id).encode_varint(error);
+    })
+}
+
+pub fn new_connection_id(sequence: u64, retire_prior: u64, cid: &[u8]) -> Vec<u8> {
+    encode(0x18, |enc| {
+        enc.encode_varint(sequence)
+            .encode_varint(retire_prior)
+            .encode_vec(1, cid)
+            .encode(&[0; 16]);
+    })
+}
+
+/// A frame of a type that isn't defined, with some bytes after it.
+pub fn unknown(frame_type: u64) -> Vec<u8> {
+    encode(frame_type, |enc| {
+        enc.encode(&[0xff; 8]);
+    })
+}
+
+/// A copy of `d` with `len` bytes of garbage after it.
+pub fn pad_garbage(d: &Datagram, len: usize) -> Datagram {
+    let mut payload = d.to_vec();
+    payload.resize(d.len() + len, 0);
+    Datagram::new(d.source(), d.destination(), payload)
+}
+
+/// A copy of `d` where the destination connection ID of the first packet
+/// has been changed.  A short header is assumed to have a connection ID
+/// that is at least 4 bytes long.
+pub fn garble_dcid(d: &Datagram) -> Datagram {
+    let mut payload = d.to_vec();
+    let dcid = if payload[0] & 0x80 == 0 {
+        1..5
+    } else {
+        6..6 + usize::from(payload[5])
+    };
+    for b in &mut payload[dcid] {
+        *b ^= 0xff;
+    }
+    Datagram::new(d.source(), d.destination(), payload)
+}
+
+/// Deliver what `side` sends, and check that its peer closes the connection
+/// with `error`.  Then check that the peer tells `side` the right code.
+/// # Panics
+/// If either side doesn't close as it should.
+pub fn assert_closes(pair: &mut Pair, side: Side, error: Error) {
+    pair.send(side).deliver(side);
+    let peer = side.peer();
+    match pair.connection(peer).state() {
+        State::Closing {
+            error: ConnectionError::Transport(e),
+            ..
+        } => assert_eq!(*e, error),
+        s => panic!("{:?} should be closing with {:?}, not {:?}", peer, error, s),
+    }
+    pair.send(peer).deliver(peer);
+    let code = error.code();
+    assert!(
+        matches!(
+            pair.connection(side).state(),
+            State::Closed(ConnectionError::Transport(Error::PeerError(c))) if *c == code
+        ),
+        "{:?} should be told about error {}, not {:?}",
+        side,
+        code,
+        pair.connection(side).state()
+    );
+}
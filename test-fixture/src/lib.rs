@@ -8,9 +8,10 @@
 
 use neqo_common::matches;
 use neqo_common::once::OnceResult;
+use neqo_common::{Clock, VirtualClock};
 use neqo_crypto::{init_db, AntiReplay, AuthenticationStatus};
 use neqo_http3::{Http3Client, Http3Server};
-use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, State};
+use neqo_transport::{seed_random, Connection, ConnectionEvent, FixedConnectionIdManager, State};
 
 use std::cell::RefCell;
 use std::mem;
@@ -18,11 +19,27 @@ use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
+#[cfg(feature = "adversarial")]
+pub mod adversary;
 pub mod assertions;
+pub mod pair;
+#[cfg(feature = "proptest")]
+pub mod prop;
+pub mod replay;
 
 /// The path for the database used in tests.
 pub const NSS_DB_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/db");
 
+/// A certificate for "server.example" and its PKCS#8 private key, in PEM and
+/// in DER, for tests of servers that don't use the database.
+pub const PEM_CERTIFICATE: &str = include_str!("../server-cert/cert.pem");
+pub const PEM_PRIVATE_KEY: &str = include_str!("../server-cert/key.pem");
+pub const DER_CERTIFICATE: &[u8] = include_bytes!("../server-cert/cert.der");
+pub const DER_PRIVATE_KEY: &[u8] = include_bytes!("../server-cert/key.der");
+/// The PKCS#8 private key for a delegated credential.  That certificate has the
+/// DelegationUsage extension, so that it can delegate to this.
+pub const DC_PRIVATE_KEY: &[u8] = include_bytes!("../server-cert/dc-key.der");
+
 /// Initialize the test fixture.  Only call this if you aren't also calling a
 /// fixture function that depends on setup.  Other functions in the fixture
 /// that depend on this setup call the function for you.
@@ -91,27 +108,35 @@ pub fn default_server() -> Connection {
 /// If state is AuthenticationNeeded call authenticated(). This funstion will consume
 /// all outstanding events on the connection.
 pub fn maybe_authenticate(conn: &mut Connection) -> bool {
+    authenticate_at(conn, now())
+}
+
+fn authenticate_at(conn: &mut Connection, now: Instant) -> bool {
     let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
     if conn.events().any(authentication_needed) {
-        conn.authenticated(AuthenticationStatus::Ok, now());
+        conn.authenticated(AuthenticationStatus::Ok, now);
         return true;
     }
     false
 }
 
-pub fn handshake(client: &mut Connection, server: &mut Connection) {
+fn handshake_with(client: &mut Connection, server: &mut Connection, now: impl Fn() -> Instant) {
     let mut a = client;
     let mut b = server;
     let mut datagram = None;
     let is_done = |c: &Connection| matches!(c.state(), State::Connected | State::Closing { .. } | State::Closed(..));
     while !is_done(a) {
-        let _ = maybe_authenticate(a);
+        let _ = authenticate_at(a, now());
         let d = a.process(datagram, now());
         datagram = d.dgram();
         mem::swap(&mut a, &mut b);
     }
 }
 
+pub fn handshake(client: &mut Connection, server: &mut Connection) {
+    handshake_with(client, server, now);
+}
+
 pub fn connect() -> (Connection, Connection) {
     let mut client = default_client();
     let mut server = default_server();
@@ -121,6 +146,34 @@ pub fn connect() -> (Connection, Connection) {
     (client, server)
 }
 
+/// Make the random choices that the transport makes on this thread follow
+/// from `seed`, and return a clock that starts at `now()` and only moves when
+/// it is told to.  A test that uses both runs the same way every time, except
+/// for the choices that NSS makes in the TLS handshake.
+pub fn deterministic(seed: u64) -> VirtualClock {
+    seed_random(Some(seed));
+    VirtualClock::new(now())
+}
+
+/// Like `handshake`, but both connections take their time from `clock`.
+pub fn clocked_handshake(client: &mut Connection, server: &mut Connection, clock: &VirtualClock) {
+    client.set_clock(Rc::new(clock.clone()));
+    server.set_clock(Rc::new(clock.clone()));
+    handshake_with(client, server, || clock.now());
+}
+
+/// Like `connect`, but with the choices that `deterministic` makes repeatable.
+/// This returns the clock that the connections use.
+pub fn deterministic_connect(seed: u64) -> (Connection, Connection, VirtualClock) {
+    let clock = deterministic(seed);
+    let mut client = default_client();
+    let mut server = default_server();
+    clocked_handshake(&mut client, &mut server, &clock);
+    assert_eq!(*client.state(), State::Connected);
+    assert_eq!(*server.state(), State::Connected);
+    (client, server, clock)
+}
+
 /// Create a http3 client with default configuration.
 pub fn default_http3_client() -> Http3Client {
     fixture_init();
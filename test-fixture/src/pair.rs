@@ -0,0 +1,224 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A client and server with control over what gets delivered between them.
+// Each side sends a flight, which is held until the test says what to do with
+// it, so a test can script the losses and reordering that it needs:
+//
+//     let mut pair = Pair::new();
+//     pair.send(Side::Client).deliver(Side::Client);
+//     pair.send(Side::Server)
+//         .reverse(Side::Server)
+//         .deliver(Side::Server);
+//     pair.handshake();
+
+use crate::{authenticate_at, default_client, default_server, now};
+use neqo_common::{matches, qdebug, Datagram};
+use neqo_transport::{Connection, Output, State};
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// One end of a `Pair`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Self::Client => 0,
+            Self::Server => 1,
+        }
+    }
+
+    pub fn peer(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
+}
+
+/// How many times `handshake` lets both sides go quiet before it gives up.
+const MAX_HANDSHAKE_WAITS: usize = 20;
+
+/// A client and server, and what each has sent to the other.
+pub struct Pair {
+    connections: [Connection; 2],
+    now: Instant,
+    /// What each side has sent that hasn't been delivered, in order.
+    flights: [Vec<Datagram>; 2],
+    /// When each side next wants to run its timers.
+    wake: [Option<Instant>; 2],
+}
+
+impl Pair {
+    /// A default client and server.
+    pub fn new() -> Self {
+        Self::with(default_client(), default_server())
+    }
+
+    /// A pair made from the given client and server.
+    pub fn with(client: Connection, server: Connection) -> Self {
+        Self {
+            connections: [client, server],
+            now: now(),
+            flights: [Vec::new(), Vec::new()],
+            wake: [None, None],
+        }
+    }
+
+    pub fn client(&mut self) -> &mut Connection {
+        self.connection(Side::Client)
+    }
+
+    pub fn server(&mut self) -> &mut Connection {
+        self.connection(Side::Server)
+    }
+
+    pub fn connection(&mut self, side: Side) -> &mut Connection {
+        &mut self.connections[side.index()]
+    }
+
+    /// Take the client and server back.
+    pub fn into_connections(self) -> (Connection, Connection) {
+        let [client, server] = self.connections;
+        (client, server)
+    }
+
+    /// The time that the pair is using.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Move time forward.
+    pub fn advance(&mut self, d: Duration) -> &mut Self {
+        self.now += d;
+        self
+    }
+
+    /// Move time forward to when `side` next wants to run its timers.
+    /// # Panics
+    /// If `side` has no timer set.
+    pub fn wait(&mut self, side: Side) -> &mut Self {
+        let wake = self.wake[side.index()].expect("a timer is set");
+        if wake > self.now {
+            self.now = wake;
+        }
+        self
+    }
+
+    /// Have `side` send everything that it can now.  This is held until it
+    /// is delivered or lost.
+    pub fn send(&mut self, side: Side) -> &mut Self {
+        let i = side.index();
+        let c = &mut self.connections[i];
+        let _ = authenticate_at(c, self.now);
+        self.wake[i] = loop {
+            match c.process(None, self.now) {
+                Output::Datagram(d) => self.flights[i].push(d),
+                Output::Callback(delay) => break Some(self.now + delay),
+                Output::None => break None,
+            }
+        };
+        qdebug!("{:?} flight has {} datagrams", side, self.flights[i].len());
+        self
+    }
+
+    /// What `side` has sent that hasn't been delivered.
+    pub fn flight(&self, side: Side) -> &[Datagram] {
+        &self.flights[side.index()]
+    }
+
+    /// Deliver everything that `side` has sent to its peer, in order.
+    pub fn deliver(&mut self, side: Side) -> &mut Self {
+        let flight = mem::replace(&mut self.flights[side.index()], Vec::new());
+        let peer = &mut self.connections[side.peer().index()];
+        for d in flight {
+            peer.process_input(d, self.now);
+        }
+        self
+    }
+
+    /// Lose datagram `i` from what `side` has sent.
+    pub fn lose(&mut self, side: Side, i: usize) -> &mut Self {
+        let _ = self.take(side, i);
+        self
+    }
+
+    /// Lose everything that `side` has sent.
+    pub fn lose_all(&mut self, side: Side) -> &mut Self {
+        self.flights[side.index()].clear();
+        self
+    }
+
+    /// Send datagram `i` from what `side` has sent twice.
+    pub fn duplicate(&mut self, side: Side, i: usize) -> &mut Self {
+        let flight = &mut self.flights[side.index()];
+        let d = flight[i].clone();
+        flight.insert(i + 1, d);
+        self
+    }
+
+    /// Reverse the order of what `side` has sent.
+    pub fn reverse(&mut self, side: Side) -> &mut Self {
+        self.flights[side.index()].reverse();
+        self
+    }
+
+    /// Take datagram `i` from what `side` has sent, so that it can be
+    /// delivered later with `inject`.
+    pub fn take(&mut self, side: Side, i: usize) -> Datagram {
+        self.flights[side.index()].remove(i)
+    }
+
+    /// Add a datagram to the end of what `side` has sent.
+    pub fn inject(&mut self, side: Side, d: Datagram) -> &mut Self {
+        self.flights[side.index()].push(d);
+        self
+    }
+
+    /// Finish the handshake, delivering everything that is sent from now on
+    /// and letting time pass when neither side has anything to send.
+    /// # Panics
+    /// If the handshake doesn't finish.
+    pub fn handshake(&mut self) -> &mut Self {
+        let is_done = |c: &Connection| {
+            matches!(
+                c.state(),
+                State::Connected | State::Closing { .. } | State::Closed(..)
+            )
+        };
+        let mut waits = 0;
+        while !self.connections.iter().all(is_done) {
+            self.deliver(Side::Client).deliver(Side::Server);
+            self.send(Side::Client).send(Side::Server);
+            if self.flights.iter().all(Vec::is_empty) {
+                assert!(waits < MAX_HANDSHAKE_WAITS, "the handshake stalled");
+                waits += 1;
+                self.now = self
+                    .wake
+                    .iter()
+                    .flatten()
+                    .min()
+                    .copied()
+                    .expect("a timer is set");
+            }
+        }
+        assert_eq!(*self.connections[0].state(), State::Connected);
+        assert_eq!(*self.connections[1].state(), State::Connected);
+        self
+    }
+}
+
+impl Default for Pair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,406 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Generators for property-based tests, and checks for the invariants that
+// the transport needs to keep whatever a peer or the network does:
+//
+//     proptest! {
+//         #[test]
+//         fn invariants(behavior in behavior()) {
+//             let mut inv = Invariants::new();
+//             for action in &behavior {
+//                 inv.step(action)?;
+//             }
+//             inv.finish()?;
+//         }
+//     }
+
+use crate::pair::{Pair, Side};
+use neqo_common::{matches, Encoder};
+use neqo_transport::{ConnectionEvent, State, StreamType};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// A varint, with values of every encoded length.
+pub fn varint() -> impl Strategy<Value = u64> {
+    prop_oneof![
+        0..(1_u64 << 6),
+        0..(1_u64 << 14),
+        0..(1_u64 << 30),
+        0..(1_u64 << 62)
+    ]
+}
+
+fn encode(frame_type: u64, body: impl FnOnce(&mut Encoder)) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    enc.encode_varint(frame_type);
+    body(&mut enc);
+    enc.into()
+}
+
+fn bytes(max: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..max)
+}
+
+/// The encoding of a frame that decodes.  The encoding is the same as the
+/// one that neqo uses, so decoding and encoding again gives the same bytes.
+/// STREAM and DATAGRAM frames always have a length, so frames can be put
+/// one after another.
+pub fn frame() -> BoxedStrategy<Vec<u8>> {
+    prop_oneof![
+        Just(vec![0x00]),
+        Just(vec![0x01]),
+        (
+            varint(),
+            varint(),
+            varint(),
+            vec((varint(), varint()), 0..4),
+            proptest::option::of((varint(), varint(), varint()))
+        )
+            .prop_map(|(largest, delay, first, ranges, ecn)| {
+                let frame_type = if ecn.is_some() { 0x03 } else { 0x02 };
+                encode(frame_type, |enc| {
+                    enc.encode_varint(largest)
+                        .encode_varint(delay)
+                        .encode_varint(u64::try_from(ranges.len()).unwrap())
+                        .encode_varint(first);
+                    for (gap, range) in ranges {
+                        enc.encode_varint(gap).encode_varint(range);
+                    }
+                    if let Some((ect0, ect1, ce)) = ecn {
+                        enc.encode_varint(ect0)
+                            .encode_varint(ect1)
+                            .encode_varint(ce);
+                    }
+                })
+            }),
+        (varint(), varint(), varint()).prop_map(|(id, err, size)| encode(0x04, |enc| {
+            enc.encode_varint(id).encode_varint(err).encode_varint(size);
+        })),
+        (varint(), varint()).prop_map(|(id, err)| encode(0x05, |enc| {
+            enc.encode_varint(id).encode_varint(err);
+        })),
+        (varint(), bytes(64)).prop_map(|(offset, data)| encode(0x06, |enc| {
+            enc.encode_varint(offset).encode_vvec(&data);
+        })),
+        vec(any::<u8>(), 1..32).prop_map(|token| encode(0x07, |enc| {
+            enc.encode_vvec(&token);
+        })),
+        (varint(), varint(), any::<bool>(), bytes(64)).prop_map(|(id, offset, fin, data)| {
+            let mut frame_type = 0x0a;
+            if offset > 0 {
+                frame_type |= 0x04;
+            }
+            if fin {
+                frame_type |= 0x01;
+            }
+            encode(frame_type, |enc| {
+                enc.encode_varint(id);
+                if offset > 0 {
+                    enc.encode_varint(offset);
+                }
+                enc.encode_vvec(&data);
+            })
+        }),
+        varint().prop_map(|max| encode(0x10, |enc| {
+            enc.encode_varint(max);
+        })),
+        (varint(), varint()).prop_map(|(id, max)| encode(0x11, |enc| {
+            enc.encode_varint(id).encode_varint(max);
+        })),
+        (0x12_u64..=0x13, varint()).prop_map(|(frame_type, max)| encode(frame_type, |enc| {
+            enc.encode_varint(max);
+        })),
+        varint().prop_map(|limit| encode(0x14, |enc| {
+            enc.encode_varint(limit);
+        })),
+        (varint(), varint()).prop_map(|(id, limit)| encode(0x15, |enc| {
+            enc.encode_varint(id).encode_varint(limit);
+        })),
+        (0x16_u64..=0x17, varint()).prop_map(|(frame_type, limit)| encode(frame_type, |enc| {
+            enc.encode_varint(limit);
+        })),
+        (
+            varint(),
+            varint(),
+            vec(any::<u8>(), 1..=20),
+            any::<[u8; 16]>()
+        )
+            .prop_map(|(seq, retire, cid, srt)| encode(0x18, |enc| {
+                enc.encode_varint(seq)
+                    .encode_varint(retire)
+                    .encode_vec(1, &cid)
+                    .encode(&srt);
+            })),
+        varint().prop_map(|seq| encode(0x19, |enc| {
+            enc.encode_varint(seq);
+        })),
+        (0x1a_u64..=0x1b, any::<[u8; 8]>()).prop_map(|(frame_type, data)| encode(
+            frame_type,
+            |enc| {
+                enc.encode(&data);
+            }
+        )),
+        (0x1c_u64..=0x1d, varint(), varint(), bytes(32)).prop_map(
+            |(frame_type, err, ft, reason)| encode(frame_type, |enc| {
+                enc.encode_varint(err)
+                    .encode_varint(ft)
+                    .encode_vvec(&reason);
+            })
+        ),
+        bytes(64).prop_map(|data| encode(0x31, |enc| {
+            enc.encode_vvec(&data);
+        })),
+    ]
+    .boxed()
+}
+
+/// The encoding of a few frames, one after another.
+pub fn frames() -> impl Strategy<Value = Vec<u8>> {
+    vec(frame(), 1..8).prop_map(|frames| frames.concat())
+}
+
+/// What the network does to a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    Deliver,
+    Lose,
+    /// Deliver two copies.  Only datagrams with a short header are
+    /// duplicated, so that each duplicate holds one packet; the rest are
+    /// delivered once.
+    Duplicate,
+    /// Deliver after the rest of the flight.
+    Delay,
+}
+
+pub fn fate() -> impl Strategy<Value = Fate> {
+    prop_oneof![
+        4 => Just(Fate::Deliver),
+        1 => Just(Fate::Lose),
+        1 => Just(Fate::Duplicate),
+        1 => Just(Fate::Delay),
+    ]
+}
+
+/// The fates of a sequence of datagrams.  When a flight is longer, the
+/// fates repeat.
+pub fn fates() -> impl Strategy<Value = Vec<Fate>> {
+    vec(fate(), 1..16)
+}
+
+pub fn side() -> impl Strategy<Value = Side> {
+    prop_oneof![Just(Side::Client), Just(Side::Server)]
+}
+
+/// Something that happens to a connected pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// The client writes this many bytes to its stream, as much as it can.
+    Write(usize),
+    /// `Side` sends what it can, and each datagram meets its fate.
+    Send(Side, Vec<Fate>),
+    /// Time passes.
+    Advance(Duration),
+}
+
+pub fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        (1..20_000_usize).prop_map(Action::Write),
+        (side(), fates()).prop_map(|(side, fates)| Action::Send(side, fates)),
+        (1..200_u64).prop_map(|ms| Action::Advance(Duration::from_millis(ms))),
+    ]
+}
+
+/// What a peer and the network between them do.
+pub fn behavior() -> impl Strategy<Value = Vec<Action>> {
+    vec(action(), 1..32)
+}
+
+/// How many rounds `Invariants::finish` takes to deliver everything.
+const MAX_FINISH_ROUNDS: usize = 100;
+
+/// The byte at `offset` in what the client writes.
+fn pattern(offset: u64) -> u8 {
+    u8::try_from(offset % 251).unwrap()
+}
+
+/// A connected pair, where the client writes to a stream that the server
+/// reads, and checks that:
+///
+/// * the client never writes more than flow control allows, and neither
+///   side closes the connection;
+/// * packet numbers are never repeated, so the only packets that either side
+///   sees twice are those that the network duplicated;
+/// * the server reads what the client wrote, in order, and once everything
+///   is delivered, all of it.
+pub struct Invariants {
+    pair: Pair,
+    stream_id: u64,
+    written: u64,
+    read: u64,
+    fin: bool,
+    /// The datagrams that each side sent that were duplicated.
+    duplicates: [u64; 2],
+}
+
+impl Invariants {
+    pub fn new() -> Self {
+        Self::with(Pair::new())
+    }
+
+    /// Check the invariants on `pair`, after it finishes the handshake.
+    pub fn with(mut pair: Pair) -> Self {
+        pair.handshake();
+        let stream_id = pair.client().stream_create(StreamType::UniDi).unwrap();
+        Self {
+            pair,
+            stream_id,
+            written: 0,
+            read: 0,
+            fin: false,
+            duplicates: [0; 2],
+        }
+    }
+
+    pub fn pair(&mut self) -> &mut Pair {
+        &mut self.pair
+    }
+
+    fn write(&mut self, len: usize) -> Result<(), TestCaseError> {
+        let data = (self.written..).take(len).map(pattern).collect::<Vec<_>>();
+        let stream_id = self.stream_id;
+        let client = self.pair.client();
+        let avail = client.stream_avail_send_space(stream_id).unwrap();
+        let sent = client.stream_send(stream_id, &data).unwrap();
+        prop_assert!(
+            u64::try_from(sent).unwrap() <= avail,
+            "wrote {} with only {} of credit",
+            sent,
+            avail
+        );
+        self.written += u64::try_from(sent).unwrap();
+        Ok(())
+    }
+
+    /// Have `side` send, and deliver what it sent according to `fates`.
+    fn send(&mut self, side: Side, fates: &[Fate]) {
+        self.pair.send(side);
+        let mut delivered = Vec::new();
+        let mut delayed = Vec::new();
+        let count = self.pair.flight(side).len();
+        for i in 0..count {
+            let d = self.pair.take(side, 0);
+            match fates[i % fates.len()] {
+                Fate::Deliver => delivered.push(d),
+                Fate::Lose => (),
+                Fate::Duplicate if d[0] & 0x80 == 0 => {
+                    self.duplicates[side.index()] += 1;
+                    delivered.push(d.clone());
+                    delivered.push(d);
+                }
+                Fate::Duplicate => delivered.push(d),
+                Fate::Delay => delayed.push(d),
+            }
+        }
+        for d in delivered.into_iter().chain(delayed) {
+            self.pair.inject(side, d);
+        }
+        self.pair.deliver(side);
+    }
+
+    /// Read everything that the server can, checking that it is what the
+    /// client wrote.
+    fn read(&mut self) -> Result<(), TestCaseError> {
+        if self.fin {
+            return Ok(());
+        }
+        let stream_id = self.stream_id;
+        let server = self.pair.server();
+        let readable = |e| matches!(e, ConnectionEvent::RecvStreamReadable { .. });
+        if !server.events().any(readable) {
+            return Ok(());
+        }
+        let mut buf = vec![0; 4096];
+        loop {
+            let (n, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+            for (i, b) in buf[..n].iter().enumerate() {
+                let offset = self.read + u64::try_from(i).unwrap();
+                prop_assert_eq!(*b, pattern(offset), "data at {} is wrong", offset);
+            }
+            self.read += u64::try_from(n).unwrap();
+            self.fin |= fin;
+            if n == 0 || fin {
+                return Ok(());
+            }
+        }
+    }
+
+    fn check(&mut self) -> Result<(), TestCaseError> {
+        for side in &[Side::Client, Side::Server] {
+            let c = self.pair.connection(*side);
+            let closed = matches!(c.state(), State::Closing { .. } | State::Closed(..));
+            prop_assert!(!closed, "{:?} closed: {:?}", side, c.state());
+            // What one side receives twice, the other side sent.
+            let dups = c.stats().dups_rx;
+            let sent = self.duplicates[side.peer().index()];
+            prop_assert!(
+                dups <= sent,
+                "{:?} received {} duplicates, but only {} were sent",
+                side,
+                dups,
+                sent
+            );
+        }
+        prop_assert!(self.read <= self.written);
+        Ok(())
+    }
+
+    /// Apply `action` and check the invariants.
+    pub fn step(&mut self, action: &Action) -> Result<(), TestCaseError> {
+        match action {
+            Action::Write(len) => self.write(*len)?,
+            Action::Send(side, fates) => self.send(*side, fates),
+            Action::Advance(d) => {
+                self.pair.advance(*d);
+            }
+        }
+        self.read()?;
+        self.check()
+    }
+
+    /// Close the stream and deliver everything, then check that the server
+    /// read all that the client wrote.
+    pub fn finish(mut self) -> Result<(), TestCaseError> {
+        let stream_id = self.stream_id;
+        self.pair.client().stream_close_send(stream_id).unwrap();
+        for _ in 0..MAX_FINISH_ROUNDS {
+            self.send(Side::Client, &[Fate::Deliver]);
+            self.send(Side::Server, &[Fate::Deliver]);
+            self.read()?;
+            self.check()?;
+            if self.fin {
+                prop_assert_eq!(self.read, self.written);
+                return Ok(());
+            }
+            self.pair.wait(Side::Client);
+        }
+        Err(TestCaseError::fail(format!(
+            "only {} of {} bytes were delivered",
+            self.read, self.written
+        )))
+    }
+}
+
+impl Default for Invariants {
+    fn default() -> Self {
+        Self::new()
+    }
+}
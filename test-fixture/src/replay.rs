@@ -0,0 +1,56 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Recording connections to a corpus, and replaying a corpus, for regression
+// tests.  A replay feeds what was received to a new connection, at the same
+// times.  The new connection can always read Initial packets, because their
+// keys come from the packet.  Anything later needs the TLS secrets of the
+// recorded connection, which NSS won't export, so the new connection drops
+// those.  If the recording was made with a seed, call `seed_random` with
+// `Corpus::seed` first so that the new connection chooses the same
+// connection IDs.
+
+use crate::{authenticate_at, now};
+use neqo_common::{Capture, Corpus, Datagram, Recorder};
+use neqo_transport::{Connection, Output};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A recording that is kept in memory.
+pub type MemoryRecorder = Rc<RefCell<Recorder<Vec<u8>>>>;
+
+/// Record everything that `c` sends and receives.  `seed` is the one that the
+/// test gave to `seed_random`, if it did.  Read the recording back with
+/// `recording`.
+pub fn record(c: &mut Connection, seed: Option<u64>) -> MemoryRecorder {
+    let recorder = Rc::new(RefCell::new(
+        Recorder::new(Vec::new(), seed).expect("can write to memory"),
+    ));
+    let capture: Rc<RefCell<dyn Capture>> = Rc::clone(&recorder);
+    c.set_capture(Some(capture));
+    recorder
+}
+
+/// What has been recorded so far.
+pub fn recording(recorder: &MemoryRecorder) -> Corpus {
+    Corpus::decode(recorder.borrow().get_ref()).expect("the recording is valid")
+}
+
+/// Feed what was received in `corpus` to `c` through `process_input`, at the
+/// times it was received, relative to `now()`.  This returns what `c` sent.
+pub fn replay(c: &mut Connection, corpus: &Corpus) -> Vec<Datagram> {
+    let mut sent = Vec::new();
+    for r in corpus.received() {
+        let t = now() + r.time;
+        c.process_input(r.datagram.clone(), t);
+        let _ = authenticate_at(c, t);
+        while let Output::Datagram(d) = c.process(None, t) {
+            sent.push(d);
+        }
+    }
+    sent
+}